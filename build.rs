@@ -0,0 +1,91 @@
+/*
+    Turns the symbols of the previous link into the table src/ksym.rs searches. The Makefile
+    links the kernel, dumps its symbols with nm into target/ksyms.txt and links it again with
+    them. The table lives in .ksyms, at the end of the rodata segment, so its size changing
+    between the two links never moves any code.
+
+    Table layout (little endian):
+        u64 symbol count
+        count * { u64 address, u32 name offset, u32 name length }, sorted by address
+        the names, not null terminated
+*/
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+const SYMBOLS_FILE: &str = "target/ksyms.txt";
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", SYMBOLS_FILE);
+    println!("cargo:rerun-if-changed=linker.ld");
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let nm_output = fs::read_to_string(SYMBOLS_FILE).unwrap_or_default();
+
+    let mut symbols = parse_nm(&nm_output);
+    symbols.sort_by_key(|(address, _)| *address);
+    // aliases share an address, only the first name is kept
+    symbols.dedup_by_key(|(address, _)| *address);
+
+    let table = encode(&symbols);
+    fs::write(Path::new(&out_dir).join("ksyms.bin"), &table).unwrap();
+    fs::write(
+        Path::new(&out_dir).join("ksyms.rs"),
+        format!(
+            "#[used]\n\
+             #[link_section = \".ksyms\"]\n\
+             static KSYMS: [u8; {}] = *include_bytes!(\"ksyms.bin\");\n",
+            table.len()
+        ),
+    )
+    .unwrap();
+}
+
+// lines look like "ffffffff80001000 T griffin::main::h0123456789abcdef"
+fn parse_nm(output: &str) -> Vec<(u64, String)> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ' ');
+            let address = u64::from_str_radix(fields.next()?, 16).ok()?;
+            let kind = fields.next()?;
+            let name = fields.next()?;
+
+            // only code, the table is for turning return addresses into names
+            if !matches!(kind, "T" | "t" | "W" | "w") {
+                return None;
+            }
+
+            Some((address, strip_hash(name).to_string()))
+        })
+        .collect()
+}
+
+// rust's legacy mangling ends every path with ::h and 16 hex digits, which are just noise here
+fn strip_hash(name: &str) -> &str {
+    match name.rsplit_once("::h") {
+        Some((path, hash)) if hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()) => {
+            path
+        }
+        _ => name,
+    }
+}
+
+fn encode(symbols: &[(u64, String)]) -> Vec<u8> {
+    let mut entries = Vec::new();
+    let mut names = Vec::new();
+
+    for (address, name) in symbols {
+        entries.extend_from_slice(&address.to_le_bytes());
+        entries.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        entries.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+    }
+
+    let mut table = (symbols.len() as u64).to_le_bytes().to_vec();
+    table.append(&mut entries);
+    table.append(&mut names);
+    table
+}
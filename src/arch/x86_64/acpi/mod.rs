@@ -1,3 +1,4 @@
+use alloc::vec::Vec;
 use core::{intrinsics::size_of, ptr::null_mut};
 use stivale_boot::v2::StivaleRsdpTag;
 
@@ -37,6 +38,14 @@ impl Sdt {
 
 static mut RSDP: *mut Rsdp = null_mut();
 
+// the madt's entries start after the lapic address and the flags
+const MADT_ENTRIES_OFFSET: usize = 8;
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_SOURCE_OVERRIDE: u8 = 2;
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
 pub fn init(rsdp_tag: &StivaleRsdpTag) {
     let rsdp = rsdp_tag.rsdp as *mut Rsdp;
 
@@ -84,3 +93,81 @@ pub unsafe fn find_table(signature: [u8; 4]) -> Option<&'static Sdt> {
 
     None
 }
+
+/*
+    Calls f with the type and the bytes of every entry of the madt, the bytes include the
+    type and length at the start
+*/
+fn madt_entries(mut f: impl FnMut(u8, &[u8])) {
+    let madt = match unsafe { find_table(*b"APIC") } {
+        Some(madt) => madt,
+        None => return,
+    };
+
+    let length = madt.length as usize - size_of::<Sdt>();
+    let entries = unsafe { core::slice::from_raw_parts(madt.data_address() as *const u8, length) };
+    let mut offset = MADT_ENTRIES_OFFSET;
+
+    while offset + 2 <= length {
+        let (entry_type, entry_length) = (entries[offset], entries[offset + 1] as usize);
+        if entry_length < 2 || offset + entry_length > length {
+            break;
+        }
+
+        f(entry_type, &entries[offset..offset + entry_length]);
+        offset += entry_length;
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+// the lapic ids of every cpu the madt lists as usable, the bsp included
+pub fn lapic_ids() -> Vec<u32> {
+    let mut ids = Vec::new();
+
+    madt_entries(|entry_type, entry| {
+        // processor uid, apic id and the flags
+        if entry_type == MADT_LOCAL_APIC && entry.len() >= 8 {
+            let (apic_id, flags) = (entry[3], read_u32(entry, 4));
+
+            if flags & (LOCAL_APIC_ENABLED | LOCAL_APIC_ONLINE_CAPABLE) != 0 {
+                ids.push(apic_id as u32);
+            }
+        }
+    });
+
+    ids
+}
+
+// (id, physical address, first gsi) of every io apic
+pub fn io_apics() -> Vec<(u8, u32, u32)> {
+    let mut io_apics = Vec::new();
+
+    madt_entries(|entry_type, entry| {
+        if entry_type == MADT_IO_APIC && entry.len() >= 12 {
+            io_apics.push((entry[2], read_u32(entry, 4), read_u32(entry, 8)));
+        }
+    });
+
+    io_apics
+}
+
+/*
+    Where an isa irq is really wired to, as (gsi, mps inti flags). The firmware only lists
+    the ones that aren't identity mapped, edge triggered and active high
+*/
+pub fn irq_override(irq: u8) -> Option<(u32, u16)> {
+    let mut found = None;
+
+    madt_entries(|entry_type, entry| {
+        // bus, source irq, gsi and the flags
+        if entry_type == MADT_SOURCE_OVERRIDE && entry.len() >= 10 && entry[3] == irq {
+            let flags = u16::from_le_bytes([entry[8], entry[9]]);
+            found = Some((read_u32(entry, 4), flags));
+        }
+    });
+
+    found
+}
@@ -1,23 +1,69 @@
-use super::cpu;
+use super::{cpu, cpuid, interrupts};
 use super::io::outb;
 use super::mm::pmm;
-use crate::drivers::hpet;
-use crate::mm::vmm::{self, PageFlags};
-use crate::serial;
+use crate::drivers::timer_source;
+use crate::mm::vmm::{self, CacheMode};
+use crate::{log, serial};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const NS_IN_SECOND: u64 = 1_000_000_000;
+const CALIBRATION_MS: u64 = 10;
+// the timer counts at the base frequency divided by 2
+const TIMER_DIVIDE_BY_2: u32 = 0;
+const TIMER_ONESHOT: u32 = 0;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_MASKED: u32 = 1 << 16;
+// delivery modes and the level bit of the icr
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const SIVR_ENABLE: u32 = 1 << 8;
+// the lowest 4 bits of the spurious vector are hardwired to 1 on older cpus
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+pub const ERROR_VECTOR: u8 = 0xfe;
+// what each bit of the esr means, from bit 0 up
+const ESR_ERRORS: [&str; 8] = [
+    "send checksum error",
+    "receive checksum error",
+    "send accept error",
+    "receive accept error",
+    "redirectable ipi",
+    "send illegal vector",
+    "received illegal vector",
+    "illegal register address",
+];
 
 static mut LAPIC: Option<Xapic> = None;
+// ticks per second of the lapic timer, after the divider
+static TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static SPURIOUS_CNT: AtomicU64 = AtomicU64::new(0);
+static ERROR_CNT: AtomicU64 = AtomicU64::new(0);
 
 #[repr(u16)]
 #[derive(Clone, Copy)]
 pub enum LapicRegisters {
+    Id = 0x20,
     Eoi = 0xb0,
     Sivr = 0xf0,
+    Esr = 0x280,
     Dcr = 0x3e0,
+    IcrLow = 0x300,
+    IcrHigh = 0x310,
     LvtTimer = 0x320,
+    LvtError = 0x370,
     InitialCount = 0x380,
     CurrCount = 0x390,
 }
 
+#[repr(u32)]
+#[derive(Clone, Copy)]
+pub enum IpiDestination {
+    Single = 0b00,
+    Myself = 0b01,
+    All = 0b10,
+    AllButSelf = 0b11,
+}
+
 #[derive(Clone, Copy)]
 pub struct Xapic {
     address: u64,
@@ -30,11 +76,26 @@ impl Xapic {
         }
     }
 
+    // with errors delivered to ERROR_VECTOR, whatever was latched before is thrown away
     pub fn enable(&self) {
-        self.write(
-            LapicRegisters::Sivr,
-            self.read(LapicRegisters::Sivr) | 0x1ff,
-        );
+        let sivr = self.read(LapicRegisters::Sivr) & !0xff;
+        self.write(LapicRegisters::Sivr, sivr | SIVR_ENABLE | SPURIOUS_VECTOR as u32);
+
+        self.write(LapicRegisters::LvtError, ERROR_VECTOR as u32);
+        self.read_errors();
+    }
+
+    /*
+        The errors the lapic saw since the last call. The esr only shows them after a
+        write, which also clears what it showed before
+    */
+    pub fn read_errors(&self) -> u32 {
+        self.write(LapicRegisters::Esr, 0);
+        self.read(LapicRegisters::Esr)
+    }
+
+    pub fn id(&self) -> u32 {
+        self.read(LapicRegisters::Id) >> 24
     }
 
     pub fn read(&self, reg: LapicRegisters) -> u32 {
@@ -45,48 +106,210 @@ impl Xapic {
         unsafe { *((self.address + reg as u64) as *mut u32) = value }
     }
 
-    pub fn calibrate_timer(&self, ms: u64, vector: usize) {
-        self.write(LapicRegisters::Dcr, 0); // divide by two
-        self.write(LapicRegisters::InitialCount, u32::MAX);
+    /*
+        Finds out how fast the timer counts. The crystal or bus frequency reported by
+        cpuid (leaves 0x15 and 0x16) is what the timer runs at, when it's there.
+        Otherwise the timer is measured against the timer source
+    */
+    pub fn calibrate_timer(&self) -> u64 {
+        self.write(LapicRegisters::Dcr, TIMER_DIVIDE_BY_2);
+
+        let frequency = match cpuid_timer_frequency() {
+            Some(base_frequency) => base_frequency / 2,
+            None => {
+                self.write(LapicRegisters::LvtTimer, TIMER_MASKED);
+                self.write(LapicRegisters::InitialCount, u32::MAX);
+
+                timer_source::sleep(CALIBRATION_MS);
+
+                let count = u32::MAX - self.read(LapicRegisters::CurrCount);
+                self.write(LapicRegisters::InitialCount, 0);
+
+                count as u64 * 1000 / CALIBRATION_MS
+            }
+        };
+
+        TIMER_FREQUENCY.store(frequency, Ordering::Relaxed);
+        frequency
+    }
+
+    // the timer frequency in Hz, 0 if it hasn't been calibrated
+    pub fn timer_frequency(&self) -> u64 {
+        TIMER_FREQUENCY.load(Ordering::Relaxed)
+    }
+
+    fn ns_to_ticks(&self, ns: u64) -> u32 {
+        let frequency = self.timer_frequency();
+        assert!(frequency != 0, "The lapic timer hasn't been calibrated");
+
+        let ticks = ns as u128 * frequency as u128 / NS_IN_SECOND as u128;
+        ticks.clamp(1, u32::MAX as u128) as u32
+    }
 
-        hpet::sleep(ms);
+    // fires vector once, ns nanoseconds from now
+    pub fn oneshot(&self, vector: u8, ns: u64) {
+        let ticks = self.ns_to_ticks(ns);
 
-        let count = u32::MAX - self.read(LapicRegisters::CurrCount);
-        self.write(LapicRegisters::LvtTimer, vector as u32 | 1 << 17); // periodic mode
-        self.write(LapicRegisters::InitialCount, count);
+        self.write(LapicRegisters::LvtTimer, vector as u32 | TIMER_ONESHOT);
+        self.write(LapicRegisters::InitialCount, ticks);
+    }
+
+    // fires vector every ns nanoseconds
+    pub fn periodic(&self, vector: u8, ns: u64) {
+        let ticks = self.ns_to_ticks(ns);
+
+        self.write(LapicRegisters::LvtTimer, vector as u32 | TIMER_PERIODIC);
+        self.write(LapicRegisters::InitialCount, ticks);
+    }
+
+    pub fn stop_timer(&self) {
+        self.write(LapicRegisters::InitialCount, 0);
+        self.write(LapicRegisters::LvtTimer, TIMER_MASKED);
     }
 
     pub fn eoi(&self) {
         self.write(LapicRegisters::Eoi, 0);
     }
+
+    // the lapic id is only used when the destination is IpiDestination::Single
+    pub fn send_ipi(&self, lapic_id: u32, vector: u8, destination: IpiDestination) {
+        self.write(LapicRegisters::IcrHigh, lapic_id << 24);
+        self.write(
+            LapicRegisters::IcrLow,
+            vector as u32 | (destination as u32) << 18,
+        );
+
+        self.wait_for_delivery();
+    }
+
+    fn wait_for_delivery(&self) {
+        while self.read(LapicRegisters::IcrLow) & 1 << 12 != 0 {
+            core::hint::spin_loop();
+        }
+    }
+
+    // resets the cpu, it then waits for a startup ipi
+    pub fn send_init(&self, lapic_id: u32) {
+        self.write(LapicRegisters::IcrHigh, lapic_id << 24);
+        self.write(LapicRegisters::IcrLow, ICR_INIT | ICR_LEVEL_ASSERT);
+        self.wait_for_delivery();
+    }
+
+    // starts the cpu in real mode at page << 12, which has to be below 1MiB
+    pub fn send_startup(&self, lapic_id: u32, page: u8) {
+        self.write(LapicRegisters::IcrHigh, lapic_id << 24);
+        self.write(LapicRegisters::IcrLow, ICR_STARTUP | page as u32);
+        self.wait_for_delivery();
+    }
 }
 
 pub fn init() {
     unsafe {
         remap_pic();
+        interrupts::register_isr(SPURIOUS_VECTOR as usize, spurious as u64, 0, 0x8e, "spurious");
+        interrupts::register_isr(ERROR_VECTOR as usize, error as u64, 0, 0x8e, "apic error");
     }
     cpu::sti();
 
     let xapic = Xapic::new();
 
-    // vmm::get().map_page(
-    //     vmm::VirtAddr::new(xapic.address),
-    //     pmm::PhysAddr::new(xapic.address - pmm::PHYS_BASE),
-    //     PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::UNCACHEABLE,
-    //     true,
-    // );
+    vmm::get().map_mmio(
+        pmm::PhysAddr::new(xapic.address - pmm::PHYS_BASE),
+        pmm::PAGE_SIZE,
+        CacheMode::Uncacheable,
+    );
 
     xapic.enable();
 
+    // every cpu's timer runs at the same frequency, so this is only done once
+    if xapic.timer_frequency() == 0 {
+        let frequency = xapic.calibrate_timer();
+        log::info!("[APIC] Timer frequency: {} kHz\n", frequency / 1000);
+    }
+
     unsafe {
         LAPIC = Some(xapic);
     }
 }
 
+// the frequency the timer runs at before the divider, if cpuid reports it
+fn cpuid_timer_frequency() -> Option<u64> {
+    let max_leaf = cpuid::Cpuid::raw(0, 0).eax;
+
+    if max_leaf >= 0x15 {
+        // ecx is the core crystal clock in Hz
+        let crystal = cpuid::Cpuid::raw(0x15, 0).ecx;
+        if crystal != 0 {
+            return Some(crystal as u64);
+        }
+    }
+
+    if max_leaf >= 0x16 {
+        // ecx is the bus (reference) frequency in MHz
+        let bus = cpuid::Cpuid::raw(0x16, 0).ecx & 0xffff;
+        if bus != 0 {
+            return Some(bus as u64 * 1_000_000);
+        }
+    }
+
+    None
+}
+
+// the lapic is at the same address on every cpu, an ap only has to enable its own
+pub fn init_ap() {
+    Xapic::new().enable();
+}
+
 pub fn get() -> Xapic {
     unsafe { LAPIC.expect("The Lapic hasn't been initialized") }
 }
 
+// how many spurious interrupts and apic errors there were, on every cpu together
+pub fn spurious_count() -> u64 {
+    SPURIOUS_CNT.load(Ordering::Relaxed)
+}
+
+pub fn error_count() -> u64 {
+    ERROR_CNT.load(Ordering::Relaxed)
+}
+
+// logged at the 1st, 2nd, 4th, 8th... one, so a storm shows without flooding the log
+fn should_log(count: u64) -> bool {
+    count.is_power_of_two()
+}
+
+/*
+    An interrupt that went away between being signalled and the cpu taking it, e.g. a
+    level triggered one that was deasserted. Nothing is in service for it, so there's
+    no eoi: one would end whatever else is in service
+*/
+interrupts::isr!(spurious, |_stack| {
+    let count = SPURIOUS_CNT.fetch_add(1, Ordering::Relaxed) + 1;
+
+    if should_log(count) {
+        log::warning!("[APIC] Spurious interrupt on cpu {} ({} so far)\n", cpu::id(), count);
+    }
+});
+
+// it can come in before LAPIC is set
+interrupts::isr!(error, |_stack| {
+    let lapic = Xapic::new();
+    let errors = lapic.read_errors();
+    let count = ERROR_CNT.fetch_add(1, Ordering::Relaxed) + 1;
+
+    if should_log(count) {
+        log::error!("[APIC] Error on cpu {}: {:#x} ({} so far)\n", cpu::id(), errors, count);
+
+        for (bit, error) in ESR_ERRORS.iter().enumerate() {
+            if errors & 1 << bit != 0 {
+                log::error!("[APIC]   {}\n", error);
+            }
+        }
+    }
+
+    lapic.eoi();
+});
+
 pub unsafe fn remap_pic() {
     outb(0x20, 0x11);
     outb(0xA0, 0x11);
@@ -1,7 +1,22 @@
-use crate::arch::{gdt, mm::pmm};
+use crate::arch::cpuid::{self, Features};
+use crate::arch::{fpu, gdt, mce, mm::pmm, syscall, topology};
 use core::arch::asm;
+use crate::mm::vmm;
 use crate::serial;
 use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// rflags' trap flag, a #DB is raised after every instruction while it's set
+pub const TRAP_FLAG: u64 = 1 << 8;
+pub const INTERRUPT_FLAG: u64 = 1 << 9;
+pub const EFER_SCE: u64 = 1 << 0;
+pub const EFER_NXE: u64 = 1 << 11;
+const CR4_FSGSBASE: u64 = 1 << 16;
+
+// the bsp is always online
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(1);
+// whether the bsp's per-cpu data is there, before that gs:0 can't be read
+static LOCAL_READY: AtomicBool = AtomicBool::new(false);
 
 #[repr(C)]
 #[derive(Default, Clone, Copy)]
@@ -47,61 +62,14 @@ pub struct Tss {
     iobm: u32,
 }
 
-#[derive(Default, Debug)]
-pub struct Cpuid {
-    pub eax: u32,
-    pub ebx: u32,
-    pub ecx: u32,
-    pub edx: u32,
-}
-
-impl Cpuid {
-    pub fn raw(eax: u32, ecx: u32) -> Self {
-        let mut res = Cpuid::default();
-
-        unsafe {
-            asm!("cpuid", "mov edi, ebx", in("eax") eax, in("ecx") ecx, 
-                    lateout("eax") res.eax, lateout("edi") res.ebx, lateout("ecx") res.ecx, lateout("edx") res.edx);
-        }
-
-        res
-    }
-
-    pub fn has_smap() -> bool {
-        let res = Cpuid::raw(7, 0);
-        if res.ebx & 1 << 20 != 0 {
-            true
-        } else {
-            false
-        }
-    }
-
-    pub fn has_smep() -> bool {
-        let res = Cpuid::raw(7, 0);
-        if res.ebx & 1 << 7 != 0 {
-            true
-        } else {
-            false
-        }
-    }
-
-    pub fn has_fsgsbase() -> bool {
-        let res = Cpuid::raw(7, 0);
-        if res.ebx & 1 != 0 {
-            true
-        } else {
-            false
-        }
-    }
-
-    pub fn has_umip() -> bool {
-        let res = Cpuid::raw(7, 0);
-        if res.ecx & 1 << 2 != 0 {
-            true
-        } else {
-            false
-        }
-    }
+// per-cpu data, pointed to by the kernel gs base
+#[repr(C)]
+pub struct CpuLocal {
+    pub self_ptr: u64, // so that gs:0 gives us the address of the structure
+    pub cpu_id: usize,
+    pub user_rsp: u64,     // gs:16, scratch space for the syscall entry
+    pub kernel_stack: u64, // gs:24, stack used by the syscall entry, the running thread's
+    tss: *mut Tss,
 }
 
 #[repr(u8)]
@@ -109,36 +77,110 @@ impl Cpuid {
 pub enum Ists {
     PageFault = 0x1,
     Nmi = 0x2,
+    MachineCheck = 0x3,
 }
 
 pub fn start() {
+    cpuid::print_summary();
+    init_cpu(0);
+}
+
+// everything a cpu needs before it can take interrupts, the aps come here through smp
+pub fn init_cpu(cpu_id: usize) {
     init_features();
+    mce::init_cpu();
+    fpu::init();
+    topology::register(cpu_id);
 
+    /*
+        stacks grow down, so the tss needs the top of each allocation. rsp0 is only used
+        until the scheduler starts, then it's always the running thread's kernel stack
+    */
     let mut tss = Box::new(Tss::default());
     tss.rsp0 = pmm::get()
         .calloc(2)
-        .expect("Could not allocate the pages for rsp0")
+        .expect("Could not allocate the pages for the boot kernel stack")
         .higher_half()
-        .as_u64();
+        .as_u64()
+        + 2 * pmm::PAGE_SIZE;
 
     // page fault's ist
     tss.ist1 = pmm::get()
         .calloc(2)
-        .expect("Could not allocate the pages for rsp0")
+        .expect("Could not allocate the pages for the page fault ist")
         .higher_half()
-        .as_u64();
+        .as_u64()
+        + 2 * pmm::PAGE_SIZE;
 
     // NMI's ist
     tss.ist2 = pmm::get()
         .calloc(2)
-        .expect("Could not allocate the pages for rsp0")
+        .expect("Could not allocate the pages for the NMI ist")
         .higher_half()
-        .as_u64();
+        .as_u64()
+        + 2 * pmm::PAGE_SIZE;
+
+    // a machine check can come in on top of anything, even a bad stack
+    tss.ist3 = pmm::get()
+        .calloc(2)
+        .expect("Could not allocate the pages for the machine check ist")
+        .higher_half()
+        .as_u64()
+        + 2 * pmm::PAGE_SIZE;
 
     let leaked_tss = Box::leak(tss);
     unsafe {
         gdt::load_tss(leaked_tss as *mut Tss as u64);
     }
+
+    init_cpu_local(cpu_id, leaked_tss);
+}
+
+fn init_cpu_local(cpu_id: usize, tss: &'static mut Tss) {
+    let cpu_local = Box::leak(Box::new(CpuLocal {
+        self_ptr: 0,
+        cpu_id,
+        user_rsp: 0,
+        kernel_stack: tss.rsp0,
+        tss,
+    }));
+    cpu_local.self_ptr = cpu_local as *mut CpuLocal as u64;
+
+    // while in the kernel, the user's gs base lives in the kernel gs base msr
+    wrmsr(MsrList::GsBase, cpu_local.self_ptr);
+    wrmsr(MsrList::KernelGsBase, 0);
+    LOCAL_READY.store(true, Ordering::Release);
+}
+
+pub fn local() -> &'static mut CpuLocal {
+    let cpu_local: u64;
+    unsafe {
+        asm!("mov {}, qword ptr gs:[0]", out(reg) cpu_local);
+        &mut *(cpu_local as *mut CpuLocal)
+    }
+}
+
+// the running cpu's, 0 for anything that runs before the bsp has its per-cpu data
+pub fn id() -> usize {
+    if !LOCAL_READY.load(Ordering::Acquire) {
+        return 0;
+    }
+
+    local().cpu_id
+}
+
+/*
+    Makes top the stack this cpu enters the kernel on, both for interrupts coming from
+    userspace and for syscalls. Set on every context switch, so each thread has its own
+*/
+pub fn set_kernel_stack(top: u64) {
+    let cpu_local = local();
+    cpu_local.kernel_stack = top;
+
+    // the tss is packed, so rsp0 isn't aligned
+    unsafe {
+        core::ptr::addr_of_mut!((*cpu_local.tss).rsp0).write_unaligned(top);
+    }
 }
 
 pub fn init_features() {
@@ -147,48 +189,159 @@ pub fn init_features() {
         asm!("mov {}, cr4", out(reg) cr4);
     }
 
-    if Cpuid::has_smap() {
+    if cpuid::enabled(Features::SMAP) {
         cr4 |= 1 << 21;
     }
 
-    if Cpuid::has_smep() {
+    if cpuid::enabled(Features::SMEP) {
         cr4 |= 1 << 20;
     }
 
-    if Cpuid::has_umip() {
+    if cpuid::enabled(Features::UMIP) {
         cr4 |= 1 << 11;
     }
 
-    if Cpuid::has_fsgsbase() {
-        cr4 |= 1 << 16;
-    }
-
     unsafe {
         asm!("mov cr4, {}", in(reg) cr4);
     }
+
+    // the same on every cpu, or a page could be cached differently depending on who touches it
+    if cpuid::has(Features::PAT) {
+        wrmsr(MsrList::Pat, vmm::PAT);
+
+        // nothing cached or in the tlb can still be under the old types
+        unsafe {
+            asm!("wbinvd");
+            asm!("mov {0}, cr3", "mov cr3, {0}", out(reg) _);
+        }
+    }
+
+    let spec_ctrl = cpuid::spec_ctrl();
+    if spec_ctrl != 0 {
+        wrmsr(MsrList::SpecCtrl, spec_ctrl);
+    }
+}
+
+/*
+    What every cpu needs set before it loads the kernel's page tables, which use NX, or
+    runs user code: NXE (without it the NX bit is reserved and faults), SCE and the syscall
+    msrs, and rdfsbase and friends when the cpu has them. The bsp runs it before vmm::init,
+    the aps in ap_main
+*/
+pub fn init_msrs() {
+    let mut efer = rdmsr(MsrList::Efer) | EFER_SCE;
+    if cpuid::has(Features::NX) {
+        efer |= EFER_NXE;
+    }
+    wrmsr(MsrList::Efer, efer);
+
+    if cpuid::enabled(Features::FSGSBASE) {
+        unsafe {
+            asm!(
+                "mov {tmp}, cr4",
+                "or {tmp}, {bit}",
+                "mov cr4, {tmp}",
+                tmp = out(reg) _,
+                bit = in(reg) CR4_FSGSBASE
+            );
+        }
+    }
+
+    syscall::init();
 }
 
 #[repr(u32)]
 pub enum MsrList {
     ApicBase = 0x1b,
+    SpecCtrl = 0x48,
+    McgCap = 0x179,
+    Pat = 0x277,
+    McgStatus = 0x17a,
+    McgCtl = 0x17b,
+    Efer = 0xc0000080,
+    Star = 0xc0000081,
+    Lstar = 0xc0000082,
+    Sfmask = 0xc0000084,
+    FsBase = 0xc0000100,
     GsBase = 0xc0000101,
+    KernelGsBase = 0xc0000102,
 }
 
 pub fn rdmsr(msr: MsrList) -> u64 {
+    rdmsr_raw(msr as u32)
+}
+
+pub fn wrmsr(msr: MsrList, value: u64) {
+    wrmsr_raw(msr as u32, value);
+}
+
+// for msrs that come in arrays, like the machine check banks
+pub fn rdmsr_raw(msr: u32) -> u64 {
     let mut low: u32;
     let mut high: u32;
 
     unsafe {
-        asm!("rdmsr", in("ecx") msr as u32, out("eax") low, out("edx") high);
+        asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high);
     }
 
     low as u64 | (high as u64) << 32
 }
 
-pub fn wrmsr(msr: MsrList, value: u64) {
+pub fn wrmsr_raw(msr: u32, value: u64) {
+    unsafe {
+        asm!("wrmsr", in("ecx") msr, in("eax") value as u32, in("edx") (value >> 32) as u32);
+    }
+}
+
+pub fn get_fs_base() -> u64 {
+    if cpuid::enabled(Features::FSGSBASE) {
+        let fs_base: u64;
+        unsafe {
+            asm!("rdfsbase {}", out(reg) fs_base);
+        }
+
+        fs_base
+    } else {
+        rdmsr(MsrList::FsBase)
+    }
+}
+
+pub fn set_fs_base(fs_base: u64) {
+    if cpuid::enabled(Features::FSGSBASE) {
+        unsafe {
+            asm!("wrfsbase {}", in(reg) fs_base);
+        }
+    } else {
+        wrmsr(MsrList::FsBase, fs_base);
+    }
+}
+
+// the user gs base, only valid while running in the kernel (i.e. after swapgs)
+pub fn get_user_gs_base() -> u64 {
+    rdmsr(MsrList::KernelGsBase)
+}
+
+pub fn set_user_gs_base(gs_base: u64) {
+    wrmsr(MsrList::KernelGsBase, gs_base);
+}
+
+pub fn online_cpus() -> usize {
+    ONLINE_CPUS.load(Ordering::SeqCst)
+}
+
+pub fn set_online(count: usize) {
+    ONLINE_CPUS.store(count, Ordering::SeqCst);
+}
+
+pub fn rdtsc() -> u64 {
+    let low: u32;
+    let high: u32;
+
     unsafe {
-        asm!("wrmsr", in("ecx") msr as u32, in("eax") value as u32, in("edx") (value >> 32) as u32);
+        asm!("rdtsc", out("eax") low, out("edx") high);
     }
+
+    (high as u64) << 32 | low as u64
 }
 
 pub fn halt() -> ! {
@@ -199,8 +352,53 @@ pub fn halt() -> ! {
     }
 }
 
+// allow supervisor accesses to user pages (only matters when smap is enabled)
+pub fn stac() {
+    if cpuid::enabled(Features::SMAP) {
+        unsafe {
+            asm!("stac");
+        }
+    }
+}
+
+pub fn clac() {
+    if cpuid::enabled(Features::SMAP) {
+        unsafe {
+            asm!("clac");
+        }
+    }
+}
+
+pub fn cli() {
+    unsafe {
+        asm!("cli");
+    }
+}
+
 pub fn sti() {
     unsafe {
         asm!("sti");
     }
 }
+
+pub fn interrupts_enabled() -> bool {
+    let rflags: u64;
+    unsafe {
+        asm!("pushfq", "pop {}", out(reg) rflags);
+    }
+
+    rflags & INTERRUPT_FLAG != 0
+}
+
+// runs f with interrupts disabled, and puts them back the way they were
+pub fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+    let enabled = interrupts_enabled();
+    cli();
+
+    let result = f();
+
+    if enabled {
+        sti();
+    }
+    result
+}
@@ -0,0 +1,273 @@
+/*
+    What the cpu supports, read once from cpuid. The features the kernel makes use of can
+    be turned off from the command line with the same names linux uses (nosmap, nosmep,
+    noumip, nofsgsbase, noxsave), the rest of the kernel asks enabled() before using them.
+
+    Speculative execution mitigations are applied through IA32_SPEC_CTRL when the cpu has
+    it, mitigations=off skips all of them, nospectre_v2 and nospec_store_bypass_disable
+    skip one each
+*/
+
+use crate::{cmdline, log};
+use alloc::string::String;
+use core::arch::asm;
+
+#[derive(Default, Debug)]
+pub struct Cpuid {
+    pub eax: u32,
+    pub ebx: u32,
+    pub ecx: u32,
+    pub edx: u32,
+}
+
+impl Cpuid {
+    pub fn raw(eax: u32, ecx: u32) -> Self {
+        let mut res = Cpuid::default();
+
+        unsafe {
+            asm!("cpuid", "mov edi, ebx", in("eax") eax, in("ecx") ecx, 
+                    lateout("eax") res.eax, lateout("edi") res.ebx, lateout("ecx") res.ecx, lateout("edx") res.edx);
+        }
+
+        res
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Register {
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+macro_rules! features {
+    ($($name:ident = ($leaf:expr, $register:ident, $bit:expr, $display:expr)),* $(,)?) => {
+        bitflags::bitflags! {
+            pub struct Features: u64 {
+                $(const $name = 1 << Bit::$name as u64;)*
+            }
+        }
+
+        #[allow(non_camel_case_types)]
+        enum Bit {
+            $($name),*
+        }
+
+        // leaf, register, bit and the name shown in the summary, in the order of Features
+        static FEATURE_BITS: &[(Features, u32, Register, u32, &str)] = &[
+            $((Features::$name, $leaf, Register::$register, $bit, $display)),*
+        ];
+    };
+}
+
+features! {
+    NX = (0x8000_0001, Edx, 20, "nx"),
+    PAGES_1G = (0x8000_0001, Edx, 26, "pages1g"),
+    TOPOEXT = (0x8000_0001, Ecx, 22, "topoext"),
+    MCE = (1, Edx, 7, "mce"),
+    MCA = (1, Edx, 14, "mca"),
+    PAT = (1, Edx, 16, "pat"),
+    HTT = (1, Edx, 28, "htt"),
+    X2APIC = (1, Ecx, 21, "x2apic"),
+    TSC_DEADLINE = (1, Ecx, 24, "tsc_deadline"),
+    XSAVE = (1, Ecx, 26, "xsave"),
+    AVX = (1, Ecx, 28, "avx"),
+    RDRAND = (1, Ecx, 30, "rdrand"),
+    PCID = (1, Ecx, 17, "pcid"),
+    FSGSBASE = (7, Ebx, 0, "fsgsbase"),
+    SMEP = (7, Ebx, 7, "smep"),
+    AVX2 = (7, Ebx, 5, "avx2"),
+    INVPCID = (7, Ebx, 10, "invpcid"),
+    RDSEED = (7, Ebx, 18, "rdseed"),
+    SMAP = (7, Ebx, 20, "smap"),
+    UMIP = (7, Ecx, 2, "umip"),
+    MD_CLEAR = (7, Edx, 10, "md_clear"),
+    SPEC_CTRL = (7, Edx, 26, "spec_ctrl"),
+    STIBP = (7, Edx, 27, "stibp"),
+    SSBD = (7, Edx, 31, "ssbd"),
+    INVARIANT_TSC = (0x8000_0007, Edx, 8, "invariant_tsc"),
+}
+
+// the command line options that turn a feature off
+static OVERRIDES: &[(&str, Features)] = &[
+    ("nosmap", Features::SMAP),
+    ("nosmep", Features::SMEP),
+    ("noumip", Features::UMIP),
+    ("nofsgsbase", Features::FSGSBASE),
+    ("noxsave", Features::XSAVE.union(Features::AVX)),
+];
+
+// IA32_SPEC_CTRL bits
+const SPEC_CTRL_STIBP: u64 = 1 << 1;
+const SPEC_CTRL_SSBD: u64 = 1 << 2;
+
+pub struct CpuInfo {
+    pub vendor: String,
+    pub brand: String,
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    pub supported: Features,
+    pub enabled: Features, // supported, minus what the command line turned off
+}
+
+static mut INFO: Option<CpuInfo> = None;
+
+// the brand string is spread over leaves 0x80000002 to 0x80000004
+fn brand() -> String {
+    if Cpuid::raw(0x8000_0000, 0).eax < 0x8000_0004 {
+        return String::new();
+    }
+
+    let mut bytes = alloc::vec::Vec::new();
+    for leaf in 0x8000_0002..=0x8000_0004 {
+        let res = Cpuid::raw(leaf, 0);
+        for register in [res.eax, res.ebx, res.ecx, res.edx] {
+            bytes.extend_from_slice(&register.to_le_bytes());
+        }
+    }
+
+    let brand = String::from_utf8_lossy(&bytes);
+    String::from(brand.trim_matches(|c: char| c == '\0' || c.is_whitespace()))
+}
+
+fn detect() -> CpuInfo {
+    let vendor_leaf = Cpuid::raw(0, 0);
+    let max_leaf = vendor_leaf.eax;
+    let max_extended_leaf = Cpuid::raw(0x8000_0000, 0).eax;
+
+    let mut vendor = alloc::vec::Vec::new();
+    for register in [vendor_leaf.ebx, vendor_leaf.edx, vendor_leaf.ecx] {
+        vendor.extend_from_slice(&register.to_le_bytes());
+    }
+
+    // the extended family and model only count for some base families
+    let signature = Cpuid::raw(1, 0).eax;
+    let mut family = (signature >> 8) & 0xf;
+    let mut model = (signature >> 4) & 0xf;
+    if family == 0xf {
+        family += (signature >> 20) & 0xff;
+    }
+    if family == 0x6 || family >= 0xf {
+        model |= ((signature >> 16) & 0xf) << 4;
+    }
+
+    let mut supported = Features::empty();
+    for (feature, leaf, register, bit, _) in FEATURE_BITS {
+        let available = if *leaf >= 0x8000_0000 {
+            *leaf <= max_extended_leaf
+        } else {
+            *leaf <= max_leaf
+        };
+
+        if !available {
+            continue;
+        }
+
+        let res = Cpuid::raw(*leaf, 0);
+        let value = match register {
+            Register::Ebx => res.ebx,
+            Register::Ecx => res.ecx,
+            Register::Edx => res.edx,
+        };
+
+        if value & 1 << bit != 0 {
+            supported |= *feature;
+        }
+    }
+
+    let mut enabled = supported;
+    for (option, features) in OVERRIDES {
+        if cmdline::option(option).is_some() {
+            enabled.remove(*features);
+        }
+    }
+
+    CpuInfo {
+        vendor: String::from_utf8_lossy(&vendor).into_owned(),
+        brand: brand(),
+        family,
+        model,
+        stepping: signature & 0xf,
+        supported,
+        enabled,
+    }
+}
+
+// detected on first use, the command line has to be parsed by then
+pub fn info() -> &'static CpuInfo {
+    unsafe {
+        if INFO.is_none() {
+            INFO = Some(detect());
+        }
+
+        INFO.as_ref().unwrap()
+    }
+}
+
+pub fn has(features: Features) -> bool {
+    info().supported.contains(features)
+}
+
+// supported and not turned off, what the kernel checks before using something
+pub fn enabled(features: Features) -> bool {
+    info().enabled.contains(features)
+}
+
+pub fn names(features: Features) -> String {
+    let mut names = String::new();
+
+    for (feature, _, _, _, name) in FEATURE_BITS {
+        if features.contains(*feature) {
+            if !names.is_empty() {
+                names.push(' ');
+            }
+            names.push_str(name);
+        }
+    }
+
+    names
+}
+
+// the value for IA32_SPEC_CTRL, 0 if there's nothing to set
+pub fn spec_ctrl() -> u64 {
+    if !has(Features::SPEC_CTRL) || cmdline::option("mitigations") == Some("off") {
+        return 0;
+    }
+
+    let mut value = 0;
+    if has(Features::STIBP) && cmdline::option("nospectre_v2").is_none() {
+        value |= SPEC_CTRL_STIBP;
+    }
+    if has(Features::SSBD) && cmdline::option("nospec_store_bypass_disable").is_none() {
+        value |= SPEC_CTRL_SSBD;
+    }
+
+    value
+}
+
+pub fn print_summary() {
+    let info = info();
+
+    log::info!(
+        "[CPU] {} family {:#x} model {:#x} stepping {}: {}\n",
+        info.vendor,
+        info.family,
+        info.model,
+        info.stepping,
+        info.brand
+    );
+    log::info!("[CPU] Features: {}\n", names(info.supported));
+
+    let disabled = info.supported - info.enabled;
+    if !disabled.is_empty() {
+        log::info!("[CPU] Turned off from the command line: {}\n", names(disabled));
+    }
+
+    let spec_ctrl = spec_ctrl();
+    log::info!(
+        "[CPU] Mitigations: stibp {}, ssbd {}\n",
+        if spec_ctrl & SPEC_CTRL_STIBP != 0 { "on" } else { "off" },
+        if spec_ctrl & SPEC_CTRL_SSBD != 0 { "on" } else { "off" }
+    );
+}
@@ -0,0 +1,107 @@
+use super::cpuid::{self, Cpuid, Features};
+use super::mm::pmm::PmmBox;
+use core::arch::asm;
+
+// legacy fxsave area size, used when xsave is not available
+const FXSAVE_AREA_SIZE: usize = 512;
+
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+
+const DEFAULT_FCW: u16 = 0x37f;
+const DEFAULT_MXCSR: u32 = 0x1f80;
+
+static mut SAVE_AREA_SIZE: usize = FXSAVE_AREA_SIZE;
+static mut USE_XSAVE: bool = false;
+
+// extended (x87/SSE/AVX) state of a thread, saved and restored eagerly on every context switch
+pub struct FpuState {
+    area: PmmBox<u8>,
+}
+
+impl FpuState {
+    pub fn new() -> Self {
+        let area = PmmBox::<u8>::new(unsafe { SAVE_AREA_SIZE });
+
+        // the area is zeroed, which would leave every exception unmasked
+        unsafe {
+            (area.as_mut_ptr() as *mut u16).write(DEFAULT_FCW);
+            (area.as_mut_ptr().offset(24) as *mut u32).write(DEFAULT_MXCSR);
+        }
+
+        FpuState { area }
+    }
+
+    pub fn save(&mut self) {
+        unsafe {
+            if USE_XSAVE {
+                asm!("xsave64 [{}]", in(reg) self.area.as_mut_ptr(), in("eax") u32::MAX, in("edx") u32::MAX);
+            } else {
+                asm!("fxsave64 [{}]", in(reg) self.area.as_mut_ptr());
+            }
+        }
+    }
+
+    pub fn restore(&self) {
+        unsafe {
+            if USE_XSAVE {
+                asm!("xrstor64 [{}]", in(reg) self.area.as_ptr(), in("eax") u32::MAX, in("edx") u32::MAX);
+            } else {
+                asm!("fxrstor64 [{}]", in(reg) self.area.as_ptr());
+            }
+        }
+    }
+}
+
+fn xsetbv(xcr: u32, value: u64) {
+    unsafe {
+        asm!("xsetbv", in("ecx") xcr, in("eax") value as u32, in("edx") (value >> 32) as u32);
+    }
+}
+
+pub fn init() {
+    let mut cr0: u64;
+    let mut cr4: u64;
+
+    unsafe {
+        asm!("mov {}, cr0", out(reg) cr0);
+        asm!("mov {}, cr4", out(reg) cr4);
+    }
+
+    cr0 &= !(1 << 2); // clear EM, there is an fpu
+    cr0 &= !(1 << 3); // clear TS, the state is switched eagerly
+    cr0 |= 1 << 1; // MP
+    cr0 |= 1 << 5; // NE, report fpu errors through #MF
+
+    cr4 |= 1 << 9; // OSFXSR
+    cr4 |= 1 << 10; // OSXMMEXCPT
+
+    if cpuid::enabled(Features::XSAVE) {
+        cr4 |= 1 << 18; // OSXSAVE
+    }
+
+    unsafe {
+        asm!("mov cr0, {}", in(reg) cr0);
+        asm!("mov cr4, {}", in(reg) cr4);
+    }
+
+    if cpuid::enabled(Features::XSAVE) {
+        let mut xcr0 = XCR0_X87 | XCR0_SSE;
+        if cpuid::enabled(Features::AVX) {
+            xcr0 |= XCR0_AVX;
+        }
+
+        xsetbv(0, xcr0);
+
+        unsafe {
+            // ebx holds the size needed for the features currently enabled in xcr0
+            SAVE_AREA_SIZE = Cpuid::raw(0xd, 0).ebx as usize;
+            USE_XSAVE = true;
+        }
+    }
+
+    unsafe {
+        asm!("fninit");
+    }
+}
@@ -0,0 +1,511 @@
+/*
+    A gdb remote serial protocol stub, so the kernel can be debugged over COM1 with
+    "target remote" on machines without qemu's gdbserver. It's enabled with gdb on the
+    command line (gdb=wait also stops right after init, for gdb to attach), and is entered
+    from int3, from #DB after a single step, or when gdb sends a ^C, which the timer tick
+    looks for.
+
+    The stub runs inside the exception handler with interrupts off and can't allocate, the
+    interrupted code may be holding the heap's lock. The other cpus are not stopped.
+*/
+
+use super::cpu::{self, InterruptContext};
+use crate::cmdline;
+use crate::log;
+use crate::mm::vmm::{self, VirtAddr};
+use crate::serial::SerialWriter;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const PACKET_SIZE: usize = 4096;
+const MAX_BREAKPOINTS: usize = 32;
+
+const INT3: u8 = 0xcc;
+
+// what gdb is told stopped the kernel, as unix signals
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+// rax..r15 and rip are 8 bytes wide in the g packet, eflags and the segments 4
+const GDB_REGISTERS: usize = 24;
+const RIP: usize = 16;
+const EFLAGS: usize = 17;
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+static mut BREAKPOINTS: [Breakpoint; MAX_BREAKPOINTS] = [Breakpoint::EMPTY; MAX_BREAKPOINTS];
+static mut INPUT: [u8; PACKET_SIZE] = [0; PACKET_SIZE];
+static mut OUTPUT: Reply = Reply {
+    data: [0; PACKET_SIZE],
+    len: 0,
+};
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    address: u64, // 0 if the slot is unused
+    original: u8, // the byte the int3 replaced
+}
+
+impl Breakpoint {
+    const EMPTY: Breakpoint = Breakpoint {
+        address: 0,
+        original: 0,
+    };
+}
+
+#[derive(PartialEq)]
+enum Reason {
+    Breakpoint,
+    Step,
+    Interrupt,
+}
+
+struct Reply {
+    data: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let len = bytes.len().min(PACKET_SIZE - self.len);
+        self.data[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+    }
+
+    fn push_byte(&mut self, byte: u8) {
+        self.push(&[hex_digit(byte >> 4), hex_digit(byte & 0xf)]);
+    }
+
+    // registers go over the wire in target byte order
+    fn push_le(&mut self, value: u64, bytes: usize) {
+        for i in 0..bytes {
+            self.push_byte((value >> (i * 8)) as u8);
+        }
+    }
+}
+
+fn hex_digit(value: u8) -> u8 {
+    b"0123456789abcdef"[value as usize & 0xf]
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|value| value as u8)
+}
+
+fn parse_hex(digits: &[u8]) -> Option<u64> {
+    if digits.is_empty() || digits.len() > 16 {
+        return None;
+    }
+
+    digits
+        .iter()
+        .try_fold(0u64, |value, digit| Some(value << 4 | hex_value(*digit)? as u64))
+}
+
+// little endian hex, like the values of the G and P packets
+fn parse_le(digits: &[u8]) -> Option<u64> {
+    digits.chunks(2).enumerate().try_fold(0u64, |value, (i, byte)| {
+        Some(value | parse_hex(byte)? << (i * 8))
+    })
+}
+
+fn read_byte() -> u8 {
+    loop {
+        if let Some(c) = SerialWriter::read_char() {
+            return c as u8;
+        }
+
+        core::hint::spin_loop();
+    }
+}
+
+// waits for a packet with a good checksum and returns its data
+fn receive() -> &'static [u8] {
+    let input = unsafe { &mut INPUT };
+
+    loop {
+        while read_byte() != b'$' {}
+
+        let mut len = 0;
+        let mut checksum = 0u8;
+        loop {
+            let byte = read_byte();
+            if byte == b'#' {
+                break;
+            }
+
+            if len < PACKET_SIZE {
+                input[len] = byte;
+                len += 1;
+            }
+            checksum = checksum.wrapping_add(byte);
+        }
+
+        let expected = [read_byte(), read_byte()];
+        if parse_hex(&expected) == Some(checksum as u64) {
+            SerialWriter::send_char('+');
+            return &input[..len];
+        }
+
+        SerialWriter::send_char('-');
+    }
+}
+
+fn send(data: &[u8]) {
+    let checksum = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+
+    loop {
+        SerialWriter::send_char('$');
+        for byte in data {
+            SerialWriter::send_char(*byte as char);
+        }
+        SerialWriter::send_char('#');
+        SerialWriter::send_char(hex_digit(checksum >> 4) as char);
+        SerialWriter::send_char(hex_digit(checksum & 0xf) as char);
+
+        // anything but an ack means it has to be sent again
+        if read_byte() == b'+' {
+            return;
+        }
+    }
+}
+
+// in the gdb numbering, None for the registers the interrupt frame doesn't have
+fn register(regs: &mut InterruptContext, index: usize) -> Option<&mut u64> {
+    Some(match index {
+        0 => &mut regs.rax,
+        1 => &mut regs.rbx,
+        2 => &mut regs.rcx,
+        3 => &mut regs.rdx,
+        4 => &mut regs.rsi,
+        5 => &mut regs.rdi,
+        6 => &mut regs.rbp,
+        7 => &mut regs.rsp,
+        8 => &mut regs.r8,
+        9 => &mut regs.r9,
+        10 => &mut regs.r10,
+        11 => &mut regs.r11,
+        12 => &mut regs.r12,
+        13 => &mut regs.r13,
+        14 => &mut regs.r14,
+        15 => &mut regs.r15,
+        RIP => &mut regs.rip,
+        EFLAGS => &mut regs.rflags,
+        18 => &mut regs.cs,
+        19 => &mut regs.ss,
+        _ => return None,
+    })
+}
+
+fn register_size(index: usize) -> usize {
+    if index <= RIP {
+        8
+    } else {
+        4
+    }
+}
+
+// the segments can't be changed from here, iretq would fault on a bad one
+fn is_writable(index: usize) -> bool {
+    index <= EFLAGS
+}
+
+fn read_memory(address: u64) -> Option<u8> {
+    vmm::translate_active(VirtAddr::new(address))
+        .map(|phys| unsafe { phys.higher_half().as_ptr::<u8>().read_volatile() })
+}
+
+// through the direct map, so breakpoints can be put in the read only kernel text
+fn write_memory(address: u64, value: u8) -> bool {
+    vmm::patch_byte(VirtAddr::new(address), value)
+}
+
+fn breakpoints() -> &'static mut [Breakpoint; MAX_BREAKPOINTS] {
+    unsafe { &mut BREAKPOINTS }
+}
+
+fn insert_breakpoint(address: u64) -> bool {
+    if breakpoints().iter().any(|breakpoint| breakpoint.address == address) {
+        return true;
+    }
+
+    let original = match read_memory(address) {
+        Some(original) => original,
+        None => return false,
+    };
+
+    match breakpoints().iter_mut().find(|breakpoint| breakpoint.address == 0) {
+        Some(slot) if write_memory(address, INT3) => {
+            *slot = Breakpoint { address, original };
+            true
+        }
+        _ => false,
+    }
+}
+
+fn remove_breakpoint(address: u64) -> bool {
+    match breakpoints().iter_mut().find(|breakpoint| breakpoint.address == address) {
+        Some(breakpoint) => {
+            write_memory(address, breakpoint.original);
+            *breakpoint = Breakpoint::EMPTY;
+            true
+        }
+        None => false,
+    }
+}
+
+fn remove_all_breakpoints() {
+    for breakpoint in breakpoints().iter_mut().filter(|breakpoint| breakpoint.address != 0) {
+        write_memory(breakpoint.address, breakpoint.original);
+        *breakpoint = Breakpoint::EMPTY;
+    }
+}
+
+fn read_registers(regs: &mut InterruptContext, reply: &mut Reply) {
+    for index in 0..GDB_REGISTERS {
+        let value = register(regs, index).map(|value| *value).unwrap_or(0);
+        reply.push_le(value, register_size(index));
+    }
+}
+
+fn write_registers(regs: &mut InterruptContext, data: &[u8]) -> bool {
+    let mut offset = 0;
+
+    for index in 0..GDB_REGISTERS {
+        let digits = register_size(index) * 2;
+        let value = match data.get(offset..offset + digits).and_then(parse_le) {
+            Some(value) => value,
+            None => break,
+        };
+        offset += digits;
+
+        if !is_writable(index) {
+            continue;
+        }
+
+        if let Some(register) = register(regs, index) {
+            // eflags is only 4 bytes wide in the packet
+            let mask = if digits == 16 { u64::MAX } else { 0xffffffff };
+            *register = (*register & !mask) | value;
+        }
+    }
+
+    offset > 0
+}
+
+// "addr,len" for m, z and Z, with M's data following a ':'
+fn parse_range(args: &[u8]) -> Option<(u64, usize, &[u8])> {
+    let (range, data) = match args.iter().position(|byte| *byte == b':') {
+        Some(colon) => (&args[..colon], &args[colon + 1..]),
+        None => (args, &[][..]),
+    };
+
+    let comma = range.iter().position(|byte| *byte == b',')?;
+    let address = parse_hex(&range[..comma])?;
+    let len = parse_hex(&range[comma + 1..])? as usize;
+
+    Some((address, len, data))
+}
+
+// handles packets until gdb lets the kernel run again
+fn serve(regs: &mut InterruptContext, signal: u8) {
+    let reply = unsafe { &mut OUTPUT };
+
+    reply.clear();
+    reply.push(b"S");
+    reply.push_byte(signal);
+    send(&reply.data[..reply.len]);
+
+    loop {
+        let packet = receive();
+        let (command, args) = match packet.split_first() {
+            Some((command, args)) => (*command, args),
+            None => continue,
+        };
+
+        reply.clear();
+
+        match command {
+            b'?' => {
+                reply.push(b"S");
+                reply.push_byte(signal);
+            }
+            b'g' => read_registers(regs, reply),
+            b'G' => reply.push(if write_registers(regs, args) { b"OK" } else { b"E01" }),
+            b'p' => {
+                let index = parse_hex(args).unwrap_or(u64::MAX) as usize;
+
+                if index < GDB_REGISTERS {
+                    let value = register(regs, index).map(|value| *value).unwrap_or(0);
+                    reply.push_le(value, register_size(index));
+                } else {
+                    reply.push(b"E01");
+                }
+            }
+            b'P' => {
+                let equals = args.iter().position(|byte| *byte == b'=').unwrap_or(0);
+                let index = parse_hex(&args[..equals]).unwrap_or(u64::MAX) as usize;
+                let value = parse_le(&args[equals + 1..]);
+
+                match (is_writable(index), value) {
+                    (true, Some(value)) => {
+                        *register(regs, index).unwrap() = value;
+                        reply.push(b"OK");
+                    }
+                    _ => reply.push(b"E01"),
+                }
+            }
+            b'm' => match parse_range(args) {
+                Some((address, len, _)) => {
+                    for i in 0..len.min(PACKET_SIZE / 2) as u64 {
+                        match read_memory(address + i) {
+                            Some(byte) => reply.push_byte(byte),
+                            None => break,
+                        }
+                    }
+
+                    // a partial read is fine, but not an empty one
+                    if reply.len == 0 && len != 0 {
+                        reply.push(b"E14");
+                    }
+                }
+                None => reply.push(b"E01"),
+            },
+            b'M' => match parse_range(args) {
+                Some((address, len, data)) if data.len() >= len * 2 => {
+                    let written = (0..len).all(|i| match parse_hex(&data[i * 2..i * 2 + 2]) {
+                        Some(byte) => write_memory(address + i as u64, byte as u8),
+                        None => false,
+                    });
+
+                    reply.push(if written { b"OK" } else { b"E14" });
+                }
+                _ => reply.push(b"E01"),
+            },
+            // only software breakpoints, the kind is the instruction length which int3 ignores
+            b'Z' | b'z' => match (args.first(), args.get(1..).and_then(parse_range)) {
+                (Some(b'0'), Some((address, _, _))) if command == b'Z' => {
+                    reply.push(if insert_breakpoint(address) { b"OK" } else { b"E14" });
+                }
+                (Some(b'0'), Some((address, _, _))) => {
+                    reply.push(if remove_breakpoint(address) { b"OK" } else { b"E01" });
+                }
+                _ => {}
+            },
+            b'c' | b's' => {
+                if let Some(address) = parse_hex(args) {
+                    regs.rip = address;
+                }
+
+                if command == b's' {
+                    regs.rflags |= cpu::TRAP_FLAG;
+                } else {
+                    regs.rflags &= !cpu::TRAP_FLAG;
+                }
+
+                return;
+            }
+            // gdb went away, let the kernel run as if nothing happened
+            b'D' | b'k' => {
+                remove_all_breakpoints();
+                regs.rflags &= !cpu::TRAP_FLAG;
+
+                if command == b'D' {
+                    send(b"OK");
+                }
+                return;
+            }
+            b'q' if args.starts_with(b"Supported") => {
+                reply.push(b"PacketSize=");
+                push_hex(reply, PACKET_SIZE as u64);
+            }
+            b'q' if args.starts_with(b"Attached") => reply.push(b"1"),
+            b'q' if args.starts_with(b"C") => reply.push(b"QC1"),
+            _ => {}
+        }
+
+        send(&reply.data[..reply.len]);
+    }
+}
+
+// a number as big endian hex without leading zeroes, for the q replies
+fn push_hex(reply: &mut Reply, value: u64) {
+    let digits = (64 - value.leading_zeros() as usize + 3) / 4;
+
+    for i in (0..digits.max(1)).rev() {
+        reply.push(&[hex_digit((value >> (i * 4)) as u8)]);
+    }
+}
+
+// the frame is written back to the cpu by iretq, so gdb can change any of it
+fn enter(regs: &mut InterruptContext, reason: Reason) {
+    // rip is past the int3, gdb wants it on the breakpoint's address
+    let planted = breakpoints()
+        .iter()
+        .any(|breakpoint| breakpoint.address != 0 && breakpoint.address == regs.rip.wrapping_sub(1));
+    if reason == Reason::Breakpoint && planted {
+        regs.rip -= 1;
+    }
+
+    let signal = match reason {
+        Reason::Interrupt => SIGINT,
+        Reason::Breakpoint | Reason::Step => SIGTRAP,
+    };
+
+    serve(regs, signal);
+}
+
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+// called by the int3 handler, returns false if nobody is debugging the kernel
+pub fn on_breakpoint(regs: &mut InterruptContext) -> bool {
+    if !is_active() {
+        return false;
+    }
+
+    enter(regs, Reason::Breakpoint);
+    true
+}
+
+// called by the #DB handler after a single step gdb asked for
+pub fn on_single_step(regs: &mut InterruptContext) -> bool {
+    if !is_active() {
+        return false;
+    }
+
+    enter(regs, Reason::Step);
+    true
+}
+
+// called on every timer tick, ^C is how gdb asks to stop the kernel
+pub fn check_interrupt(regs: &mut InterruptContext) {
+    if is_active() && SerialWriter::read_char() == Some('\x03') {
+        enter(regs, Reason::Interrupt);
+    }
+}
+
+pub fn breakpoint() {
+    unsafe {
+        asm!("int3");
+    }
+}
+
+pub fn init() {
+    let option = match cmdline::option("gdb") {
+        Some(option) => option,
+        None => return,
+    };
+
+    ACTIVE.store(true, Ordering::SeqCst);
+
+    if option == "wait" {
+        log::info!("[GDB] Waiting for a debugger on the serial port\n");
+        breakpoint();
+    }
+}
@@ -1,5 +1,8 @@
 use core::arch::asm;
 
+// present 64 bit tss, not busy
+const TSS_AVAILABLE: u8 = 0x89;
+
 #[repr(C, packed)]
 struct GdtDescriptor {
     limit: u16,
@@ -33,8 +36,9 @@ struct Gdt {
     null: GdtEntry,
     kernel_code: GdtEntry,
     kernel_data: GdtEntry,
-    user_code: GdtEntry,
+    // sysret expects the user data segment right before the user code segment
     user_data: GdtEntry,
+    user_code: GdtEntry,
     tss: TssEntry,
 }
 
@@ -77,9 +81,9 @@ static mut GDT: Gdt = Gdt {
     null: GdtEntry::new(0, 0),
     kernel_code: GdtEntry::new(0x9A, 0x20),
     kernel_data: GdtEntry::new(0x92, 0),
-    user_code: GdtEntry::new(0xFA, 0x20),
     user_data: GdtEntry::new(0xF2, 0),
-    tss: TssEntry::new(104, 0x89),
+    user_code: GdtEntry::new(0xFA, 0x20),
+    tss: TssEntry::new(104, TSS_AVAILABLE),
 };
 
 static mut GDT_DESCRIPTOR: GdtDescriptor = GdtDescriptor {
@@ -108,8 +112,14 @@ pub unsafe fn init() {
     );
 }
 
+/*
+    Every cpu loads its tss through the same descriptor, ltr caches the base so it can be
+    changed for the next one. ltr also marks the descriptor busy, which has to be undone
+    or the next ltr faults, so cpus can't do this at the same time
+*/
 pub unsafe fn load_tss(tss_addr: u64) {
     let tss_selector = 0x28;
     GDT.tss.set_base(tss_addr);
+    GDT.tss.flags1 = TSS_AVAILABLE;
     asm!("ltr {:x}", in(reg) tss_selector);
 }
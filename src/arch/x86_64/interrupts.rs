@@ -1,6 +1,14 @@
-use super::cpu;
-use crate::serial;
+use super::{cpu, gdbstub};
+use crate::{crashdump, kprobe, log};
+use alloc::{format, vec::Vec};
 use core::arch::asm;
+use core::fmt::{self, Write};
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+
+// cpus past the last one are counted with it
+const MAX_CPUS: usize = 16;
+const MAX_HANDLERS: usize = 64;
 
 #[repr(C, packed)]
 struct IdtDescriptor {
@@ -32,17 +40,94 @@ impl IdtGate {
             zero: 0,
         }
     }
+
+    fn offset(&self) -> u64 {
+        self.offset1 as u64 | (self.offset2 as u64) << 16 | (self.offset3 as u64) << 32
+    }
+}
+
+/*
+    How many times a handler ran on each cpu. Every handler made with isr! or isr_err! has
+    its own, which it adds to the registry the first time it runs, so reporting goes from a
+    vector's gate to its handler's counts. Nothing here takes a lock, a handler can run on
+    top of anything. A handler registered on several vectors shows the same counts on each
+*/
+pub struct Hits {
+    counts: [AtomicU64; MAX_CPUS],
+    registered: AtomicBool,
+}
+
+// the handler of every slot, 0 until its counts are in HANDLER_HITS
+static HANDLERS: [AtomicU64; MAX_HANDLERS] = [Hits::ZERO; MAX_HANDLERS];
+static HANDLER_HITS: [AtomicPtr<Hits>; MAX_HANDLERS] = [Hits::NULL; MAX_HANDLERS];
+static HANDLER_CNT: AtomicUsize = AtomicUsize::new(0);
+
+impl Hits {
+    const ZERO: AtomicU64 = AtomicU64::new(0);
+    const NULL: AtomicPtr<Hits> = AtomicPtr::new(null_mut());
+
+    pub const fn new() -> Self {
+        Hits {
+            counts: [Self::ZERO; MAX_CPUS],
+            registered: AtomicBool::new(false),
+        }
+    }
+
+    pub fn hit(&'static self, handler: u64) {
+        let cpu = cpu::id().min(MAX_CPUS - 1);
+        self.counts[cpu].fetch_add(1, Ordering::Relaxed);
+
+        if self.registered.load(Ordering::Relaxed) || self.registered.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        // past MAX_HANDLERS the counts just aren't reported
+        let slot = HANDLER_CNT.fetch_add(1, Ordering::AcqRel);
+        if slot < MAX_HANDLERS {
+            HANDLER_HITS[slot].store(self as *const Hits as *mut Hits, Ordering::Release);
+            HANDLERS[slot].store(handler, Ordering::Release);
+        }
+    }
+
+    pub fn count(&self, cpu: usize) -> u64 {
+        self.counts.get(cpu).map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().map(|count| count.load(Ordering::Relaxed)).sum()
+    }
+}
+
+// None if the handler never ran
+fn handler_hits(handler: u64) -> Option<&'static Hits> {
+    if handler == 0 {
+        return None;
+    }
+
+    let cnt = HANDLER_CNT.load(Ordering::Acquire).min(MAX_HANDLERS);
+
+    (0..cnt)
+        .find(|&slot| HANDLERS[slot].load(Ordering::Acquire) == handler)
+        .map(|slot| unsafe { &*HANDLER_HITS[slot].load(Ordering::Acquire) })
 }
 
 macro_rules! isr {
     ($name:ident, |$stack: ident| $code:block) => {
         #[naked]
         unsafe extern "C" fn $name() {
-            unsafe extern "C" fn inner_isr($stack: &crate::arch::cpu::InterruptContext) {
+            unsafe extern "C" fn inner_isr($stack: &mut crate::arch::cpu::InterruptContext) {
+                static HITS: crate::arch::interrupts::Hits = crate::arch::interrupts::Hits::new();
+                HITS.hit($name as u64);
                 $code
             }
 
             core::arch::asm!(
+                // only swap the gs base if the interrupt came from userspace
+                "test qword ptr [rsp + 8], 3",
+                "jz 2f",
+                "swapgs",
+                "2:",
+
                 "push r15",
                 "push r14",
                 "push r13",
@@ -78,6 +163,11 @@ macro_rules! isr {
                 "pop r13",
                 "pop r14",
                 "pop r15",
+
+                "test qword ptr [rsp + 8], 3",
+                "jz 3f",
+                "swapgs",
+                "3:",
                 "iretq",
                 isr = sym inner_isr,
                 options(noreturn)
@@ -90,11 +180,22 @@ macro_rules! isr_err {
     ($name:ident, |$stack: ident, $error: ident| $code:block) => {
         #[naked]
         unsafe extern "C" fn $name() {
-            unsafe extern "C" fn inner_isr($stack: &crate::arch::cpu::InterruptContext, $error: u64) {
+            unsafe extern "C" fn inner_isr(
+                $stack: &mut crate::arch::cpu::InterruptContext,
+                $error: u64,
+            ) {
+                static HITS: crate::arch::interrupts::Hits = crate::arch::interrupts::Hits::new();
+                HITS.hit($name as u64);
                 $code
             }
 
             core::arch::asm!(
+                // the error code is on top of the stack, so the saved cs is one slot further
+                "test qword ptr [rsp + 16], 3",
+                "jz 2f",
+                "swapgs",
+                "2:",
+
                 "xchg [rsp], r15", // put the error code in r15 and r15 right after the rip
                 "push r14",
                 "push r13",
@@ -132,6 +233,11 @@ macro_rules! isr_err {
                 "pop r13",
                 "pop r14",
                 "pop r15",
+
+                "test qword ptr [rsp + 8], 3",
+                "jz 3f",
+                "swapgs",
+                "3:",
                 "iretq",
                 isr = sym inner_isr,
                 options(noreturn)
@@ -148,9 +254,12 @@ static mut IDT_DESCRIPTOR: IdtDescriptor = IdtDescriptor {
     limit: 16 * 256,
     offset: 0,
 };
+// what each vector is shown as in /proc/interrupts
+static mut NAMES: [&str; 256] = [""; 256];
 
-pub unsafe fn register_isr(vector: usize, addr: u64, ist: u8, gate_type: u8) {
+pub unsafe fn register_isr(vector: usize, addr: u64, ist: u8, gate_type: u8, name: &'static str) {
     IDT[vector] = IdtGate::new(addr, ist, gate_type, 0x8);
+    NAMES[vector] = name;
 }
 
 pub fn alloc_vector() -> Option<usize> {
@@ -163,11 +272,63 @@ pub fn alloc_vector() -> Option<usize> {
     None
 }
 
+// (vector, ist, gate type) of every registered handler
+pub fn used_vectors() -> Vec<(usize, u8, u8)> {
+    let mut used = alloc::vec![];
+
+    for i in 0..256 {
+        let gate = unsafe { IDT[i] };
+        if gate.gate_type != 0 {
+            used.push((i, gate.ist, gate.gate_type));
+        }
+    }
+
+    used
+}
+
+// how many times the handler of vector ran, on every cpu together
+pub fn hits(vector: usize) -> u64 {
+    let handler = unsafe { IDT[vector].offset() };
+    handler_hits(handler).map_or(0, |hits| hits.total())
+}
+
+/*
+    Like linux's /proc/interrupts: a column per online cpu, then a line for every vector
+    with a handler, with how often it ran on each cpu and its name
+*/
+pub fn write_stats(w: &mut impl Write) -> fmt::Result {
+    let cpus = cpu::online_cpus().min(MAX_CPUS);
+
+    write!(w, "    ")?;
+    for cpu in 0..cpus {
+        write!(w, " {:>10}", format!("CPU{}", cpu))?;
+    }
+    writeln!(w)?;
+
+    for (vector, _, _) in used_vectors() {
+        let hits = handler_hits(unsafe { IDT[vector].offset() });
+
+        write!(w, "{:>3}:", vector)?;
+        for cpu in 0..cpus {
+            write!(w, " {:>10}", hits.map_or(0, |hits| hits.count(cpu)))?;
+        }
+        writeln!(w, "   {}", unsafe { NAMES[vector] })?;
+    }
+
+    Ok(())
+}
+
 pub unsafe fn init() {
-    register_isr(0x3, int3 as u64, 0, 0x8e);
-    register_isr(0x6, invalid_opcode as u64, 0, 0x8e);
+    register_isr(0x1, debug_exception as u64, 0, 0x8e, "debug");
+    register_isr(0x3, int3 as u64, 0, 0x8e, "breakpoint");
+    register_isr(0x6, invalid_opcode as u64, 0, 0x8e, "invalid opcode");
 
     IDT_DESCRIPTOR.offset = &IDT as *const IdtGate as u64;
+    load();
+}
+
+// every cpu shares the same idt, the aps just load it
+pub unsafe fn load() {
     asm!("lidt [{}]", in(reg) &IDT_DESCRIPTOR);
 }
 
@@ -183,12 +344,31 @@ pub fn disable() {
     }
 }
 
-isr!(int3, |_stack| {
-    serial::print!("Breakpoint yeeee\n");
-    cpu::halt();
+// single steps, asked for by a kprobe stepping over its int3 or by gdb
+isr!(debug_exception, |stack| {
+    // dr6 has to be cleared by hand, or the next #DB looks like another single step
+    asm!("mov dr6, {}", in(reg) 0u64);
+
+    stack.rflags &= !cpu::TRAP_FLAG;
+
+    if kprobe::on_single_step(stack) || gdbstub::on_single_step(stack) {
+        return;
+    }
+
+    log::warning!("Unexpected debug exception at {:#x}\n", stack.rip);
 });
 
-isr!(invalid_opcode, |_stack| {
-    serial::print!("INVALID OPCODE\n");
+isr!(int3, |stack| {
+    if kprobe::on_breakpoint(stack) || gdbstub::on_breakpoint(stack) {
+        return;
+    }
+
+    log::warning!("Unexpected breakpoint at {:#x}\n", stack.rip);
     cpu::halt();
 });
+
+isr!(invalid_opcode, |stack| {
+    log::error!("INVALID OPCODE\n");
+    crashdump::set_context(stack);
+    panic!("Invalid opcode at {:#x}", stack.rip);
+});
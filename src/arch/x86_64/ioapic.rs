@@ -0,0 +1,138 @@
+/*
+    The io apics, which deliver the interrupts of devices that don't do msi (the legacy isa
+    ones, like the ps/2 controller) to a lapic. Each one covers a range of gsis starting at
+    its base. An isa irq is the gsi of the same number unless the madt overrides it.
+
+    Every entry starts masked, a driver routes its irq to a vector it allocated
+*/
+
+use super::acpi;
+use super::apic;
+use super::mm::pmm::PhysAddr;
+use crate::error::{KError, KResult};
+use crate::log;
+use crate::mm::vmm::{self, CacheMode};
+use alloc::vec::Vec;
+
+const IOREGSEL: usize = 0x0;
+const IOWIN: usize = 0x10;
+
+const REG_VERSION: u32 = 0x1;
+const REG_REDIRECTION: u32 = 0x10; // two registers per entry, the low half first
+
+const ACTIVE_LOW: u64 = 1 << 13;
+const LEVEL_TRIGGERED: u64 = 1 << 15;
+const MASKED: u64 = 1 << 16;
+
+// mps inti flags, 0 in either field means the bus' default, which is what isa irqs use
+const POLARITY_MASK: u16 = 0b11;
+const POLARITY_ACTIVE_LOW: u16 = 0b11;
+const TRIGGER_MASK: u16 = 0b11 << 2;
+const TRIGGER_LEVEL: u16 = 0b11 << 2;
+
+static IO_APICS: spin::Mutex<Vec<IoApic>> = spin::Mutex::new(Vec::new());
+
+struct IoApic {
+    id: u8,
+    address: u64, // in the higher half
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            ((self.address as usize + IOREGSEL) as *mut u32).write_volatile(reg);
+            ((self.address as usize + IOWIN) as *const u32).read_volatile()
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            ((self.address as usize + IOREGSEL) as *mut u32).write_volatile(reg);
+            ((self.address as usize + IOWIN) as *mut u32).write_volatile(value);
+        }
+    }
+
+    fn set_entry(&self, index: u32, entry: u64) {
+        // masked while it's half written
+        self.write(REG_REDIRECTION + index * 2, MASKED as u32);
+        self.write(REG_REDIRECTION + index * 2 + 1, (entry >> 32) as u32);
+        self.write(REG_REDIRECTION + index * 2, entry as u32);
+    }
+
+    fn covers(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+}
+
+pub fn init() {
+    let mut io_apics = IO_APICS.lock();
+
+    for (id, address, gsi_base) in acpi::io_apics() {
+        let registers = vmm::get().map_mmio(
+            PhysAddr::new(address as u64),
+            IOWIN as u64 + 4,
+            CacheMode::Uncacheable,
+        );
+
+        let mut io_apic = IoApic {
+            id,
+            address: registers as u64,
+            gsi_base,
+            entries: 0,
+        };
+        io_apic.entries = (io_apic.read(REG_VERSION) >> 16 & 0xff) + 1;
+
+        for index in 0..io_apic.entries {
+            io_apic.set_entry(index, MASKED);
+        }
+
+        log::info!(
+            "[IOAPIC] {} at {:#x}, gsis {} to {}\n",
+            id,
+            address,
+            gsi_base,
+            gsi_base + io_apic.entries - 1
+        );
+        io_apics.push(io_apic);
+    }
+}
+
+// the gsi an isa irq ends up on, and the redirection entry's polarity and trigger bits
+fn resolve(irq: u8) -> (u32, u64) {
+    let (gsi, flags) = acpi::irq_override(irq).unwrap_or((irq as u32, 0));
+
+    let mut bits = 0;
+    if flags & POLARITY_MASK == POLARITY_ACTIVE_LOW {
+        bits |= ACTIVE_LOW;
+    }
+    if flags & TRIGGER_MASK == TRIGGER_LEVEL {
+        bits |= LEVEL_TRIGGERED;
+    }
+
+    (gsi, bits)
+}
+
+// delivers an isa irq to vector on the bsp, the handler has to send the lapic an eoi
+pub fn route_irq(irq: u8, vector: u8) -> KResult<()> {
+    let (gsi, bits) = resolve(irq);
+    let io_apics = IO_APICS.lock();
+    let io_apic = io_apics.iter().find(|io_apic| io_apic.covers(gsi)).ok_or(KError::ENODEV)?;
+
+    // fixed delivery, physical destination
+    let destination = (apic::get().id() as u64) << 56;
+    io_apic.set_entry(gsi - io_apic.gsi_base, destination | bits | vector as u64);
+
+    log::debug!("[IOAPIC] irq {} (gsi {} on {}) -> vector {}\n", irq, gsi, io_apic.id, vector);
+    Ok(())
+}
+
+pub fn mask_irq(irq: u8) {
+    let (gsi, _) = resolve(irq);
+    let io_apics = IO_APICS.lock();
+
+    if let Some(io_apic) = io_apics.iter().find(|io_apic| io_apic.covers(gsi)) {
+        io_apic.set_entry(gsi - io_apic.gsi_base, MASKED);
+    }
+}
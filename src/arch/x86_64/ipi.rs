@@ -0,0 +1,234 @@
+use super::apic::{self, IpiDestination};
+use super::{cpu, interrupts};
+use crate::mm::vmm::VirtAddr;
+use crate::arch::mm::pmm;
+use crate::proc::scheduler;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+
+// fixed vectors, right below the spurious interrupt vector
+#[repr(u8)]
+#[derive(Clone, Copy)]
+pub enum IpiVector {
+    Reschedule = 0xf0,
+    TlbShootdown = 0xf1,
+    CallFunction = 0xf2,
+}
+
+/*
+    Only one shootdown/function call can be in flight at a time, the
+    locks are held by the sender until every target has acknowledged it
+*/
+static SHOOTDOWN_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+static SHOOTDOWN_START: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_PAGES: AtomicU64 = AtomicU64::new(0);
+// the cpus, by id, that haven't flushed the range in flight yet
+static SHOOTDOWN_TARGETS: AtomicU64 = AtomicU64::new(0);
+
+// the cpus, by id, whose idt and lapic are up, an ipi sent to any other one never arrives
+static READY_CPUS: AtomicU64 = AtomicU64::new(0);
+
+static CALL_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+// the lapic id of the cpu the call in flight is for
+static CALL_TARGET: AtomicU32 = AtomicU32::new(0);
+// 0 once the target has picked the call up
+static CALL_FUNCTION: AtomicUsize = AtomicUsize::new(0);
+static CALL_ARGUMENT: AtomicU64 = AtomicU64::new(0);
+static CALL_DONE: AtomicBool = AtomicBool::new(false);
+
+pub fn init() {
+    unsafe {
+        interrupts::register_isr(
+            IpiVector::Reschedule as usize,
+            reschedule_ipi as u64,
+            0,
+            0x8e,
+            "reschedule ipi",
+        );
+        interrupts::register_isr(
+            IpiVector::TlbShootdown as usize,
+            tlb_shootdown_ipi as u64,
+            0,
+            0x8e,
+            "tlb shootdown ipi",
+        );
+        interrupts::register_isr(
+            IpiVector::CallFunction as usize,
+            call_function_ipi as u64,
+            0,
+            0x8e,
+            "call function ipi",
+        );
+    }
+
+    set_ready();
+}
+
+// called by every cpu once it can take ipis, the bsp does it in init
+pub fn set_ready() {
+    let bit = cpu_bit(cpu::id());
+    READY_CPUS.fetch_or(bit, Ordering::SeqCst);
+}
+
+// ids past 63 don't fit, those cpus are never sent a shootdown
+fn cpu_bit(cpu_id: usize) -> u64 {
+    1u64.checked_shl(cpu_id as u32).unwrap_or(0)
+}
+
+// asks the cpu with cpu_id to reschedule, if it can take ipis yet
+pub fn send_reschedule(cpu_id: usize) {
+    if READY_CPUS.load(Ordering::SeqCst) & cpu_bit(cpu_id) == 0 {
+        return;
+    }
+
+    #[cfg(feature = "smp")]
+    if let Some(lapic_id) = super::smp::lapic_id(cpu_id) {
+        apic::get().send_ipi(lapic_id, IpiVector::Reschedule as u8, IpiDestination::Single);
+    }
+}
+
+fn invalidate_range(start: u64, pages: u64) {
+    for page in 0..pages {
+        unsafe {
+            asm!("invlpg [{}]", in(reg) start + page * pmm::PAGE_SIZE);
+        }
+    }
+}
+
+// flushes the range in flight if this cpu is one of its targets, and acknowledges it
+fn flush_pending() {
+    let bit = cpu_bit(cpu::id());
+    if SHOOTDOWN_TARGETS.load(Ordering::SeqCst) & bit == 0 {
+        return;
+    }
+
+    invalidate_range(
+        SHOOTDOWN_START.load(Ordering::SeqCst),
+        SHOOTDOWN_PAGES.load(Ordering::SeqCst),
+    );
+    SHOOTDOWN_TARGETS.fetch_and(!bit, Ordering::SeqCst);
+}
+
+/*
+    Invalidates the range on this cpu and on every other cpu that can take ipis.
+    The sender may have interrupts disabled, so while it waits for the lock or for
+    the acknowledgements it flushes whatever another sender asked of it itself,
+    otherwise two cpus shooting down at once would wait on each other forever
+*/
+pub fn tlb_shootdown(start: VirtAddr, pages: usize) {
+    invalidate_range(start.as_u64(), pages as u64);
+
+    let targets = READY_CPUS.load(Ordering::SeqCst) & !cpu_bit(cpu::id());
+    if targets == 0 {
+        return;
+    }
+
+    let _guard = loop {
+        if let Some(guard) = SHOOTDOWN_LOCK.try_lock() {
+            break guard;
+        }
+
+        flush_pending();
+        core::hint::spin_loop();
+    };
+
+    SHOOTDOWN_START.store(start.as_u64(), Ordering::SeqCst);
+    SHOOTDOWN_PAGES.store(pages as u64, Ordering::SeqCst);
+    SHOOTDOWN_TARGETS.store(targets, Ordering::SeqCst);
+
+    apic::get().send_ipi(0, IpiVector::TlbShootdown as u8, IpiDestination::AllButSelf);
+
+    while SHOOTDOWN_TARGETS.load(Ordering::SeqCst) != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/*
+    Runs the call in flight if it's for this cpu. Whichever gets to it first, its ipi or
+    the cpu itself while it waits on a call of its own, picks it up
+*/
+fn run_pending_call() {
+    if CALL_FUNCTION.load(Ordering::SeqCst) == 0
+        || CALL_TARGET.load(Ordering::SeqCst) != apic::get().id()
+    {
+        return;
+    }
+
+    // the sender only moves on to another call once the function is taken
+    let argument = CALL_ARGUMENT.load(Ordering::SeqCst);
+    let function = match CALL_FUNCTION.swap(0, Ordering::SeqCst) {
+        0 => return,
+        function => unsafe { core::mem::transmute::<usize, fn(u64)>(function) },
+    };
+
+    function(argument);
+    CALL_DONE.store(true, Ordering::SeqCst);
+}
+
+// what a cpu that waits on another one with interrupts maybe off does meanwhile
+fn drain() {
+    flush_pending();
+    run_pending_call();
+    core::hint::spin_loop();
+}
+
+/*
+    Runs `function(argument)` on the cpu with the given lapic id. Like tlb_shootdown, the
+    caller runs whatever is asked of its own cpu while it waits, so two cpus calling each
+    other don't wait on each other forever
+*/
+pub fn call_on_cpu(lapic_id: u32, function: fn(u64), argument: u64, wait: bool) {
+    let lapic = apic::get();
+
+    if lapic.id() == lapic_id {
+        function(argument);
+        return;
+    }
+
+    let _guard = loop {
+        if let Some(guard) = CALL_LOCK.try_lock() {
+            break guard;
+        }
+
+        drain();
+    };
+
+    // the function goes last, it's what the target looks for
+    CALL_TARGET.store(lapic_id, Ordering::SeqCst);
+    CALL_ARGUMENT.store(argument, Ordering::SeqCst);
+    CALL_DONE.store(false, Ordering::SeqCst);
+    CALL_FUNCTION.store(function as usize, Ordering::SeqCst);
+
+    lapic.send_ipi(lapic_id, IpiVector::CallFunction as u8, IpiDestination::Single);
+
+    /*
+        Even if the caller doesn't want to wait for the function to return,
+        we still need to wait for the target to pick up the function and its argument
+        before releasing the lock
+    */
+    while CALL_FUNCTION.load(Ordering::SeqCst) != 0 {
+        drain();
+    }
+
+    if wait {
+        while !CALL_DONE.load(Ordering::SeqCst) {
+            drain();
+        }
+    }
+}
+
+interrupts::isr!(reschedule_ipi, |stack| {
+    scheduler::reschedule_from(stack, false);
+});
+
+// a late one, for a range that was already flushed while its target waited, flushes nothing
+interrupts::isr!(tlb_shootdown_ipi, |_stack| {
+    flush_pending();
+    apic::get().eoi();
+});
+
+// a late one, for a call its target already ran while waiting, runs nothing
+interrupts::isr!(call_function_ipi, |_stack| {
+    apic::get().eoi();
+    run_pending_call();
+});
@@ -0,0 +1,174 @@
+/*
+    Machine checks, the cpu reporting a hardware error: bad memory, a cache or tlb parity
+    error, a bus timeout. Each cpu has a number of banks, each with a status msr that says
+    what went wrong and, when it's valid, the address it went wrong at.
+
+    #MC is taken on its own stack. Corrected errors only get logged, anything the cpu
+    couldn't recover from panics with what the banks said, instead of the triple fault the
+    cpu goes for when there's no handler or CR4.MCE is off
+*/
+
+use super::cpu::{self, MsrList};
+use super::cpuid::{self, Features};
+use super::interrupts;
+use crate::{crashdump, log};
+use core::arch::asm;
+
+const CR4_MCE: u64 = 1 << 6;
+
+// IA32_MCG_CAP
+const BANK_COUNT_MASK: u64 = 0xff;
+const MCG_CTL_PRESENT: u64 = 1 << 8;
+
+// IA32_MCG_STATUS
+const RESTART_IP_VALID: u64 = 1 << 0;
+const IN_PROGRESS: u64 = 1 << 2;
+
+// the bank msrs, 4 of them per bank starting at IA32_MC0_CTL
+const MC0_CTL: u32 = 0x400;
+const MC_CTL: u32 = 0;
+const MC_STATUS: u32 = 1;
+const MC_ADDR: u32 = 2;
+
+// IA32_MCi_STATUS
+const VALID: u64 = 1 << 63;
+const OVERFLOW: u64 = 1 << 62;
+const UNCORRECTED: u64 = 1 << 61;
+const ADDR_VALID: u64 = 1 << 58;
+const CONTEXT_CORRUPT: u64 = 1 << 57;
+
+fn bank_msr(bank: u64, register: u32) -> u32 {
+    MC0_CTL + bank as u32 * 4 + register
+}
+
+fn bank_count() -> u64 {
+    cpu::rdmsr(MsrList::McgCap) & BANK_COUNT_MASK
+}
+
+// the kind of error in the low 16 bits of a bank's status, the "compound" encodings
+pub fn decode(status: u64) -> &'static str {
+    let code = status as u16;
+
+    match code {
+        0x0000 => "no error",
+        0x0001 => "unclassified error",
+        0x0002 => "microcode rom parity error",
+        0x0003 => "external error",
+        0x0004 => "frc error",
+        0x0005 => "internal parity error",
+        _ if code & 0xfc00 == 0x0400 => "internal error",
+        _ if code & 0xe800 == 0x0800 => "bus or interconnect error",
+        _ if code & 0xef00 == 0x0100 => "cache error",
+        _ if code & 0xef80 == 0x0080 => "memory controller error",
+        _ if code & 0xeff0 == 0x0010 => "tlb error",
+        _ if code & 0xeffc == 0x000c => "cache hierarchy error",
+        _ => "unknown error",
+    }
+}
+
+// whether an error in this bank means the kernel can't go on
+fn is_fatal(status: u64) -> bool {
+    status & (UNCORRECTED | CONTEXT_CORRUPT) != 0
+}
+
+fn log_bank(bank: u64, status: u64) {
+    log::error!(
+        "[MCE] Bank {}: {} ({:#x}){}{}{}\n",
+        bank,
+        decode(status),
+        status,
+        if status & UNCORRECTED != 0 { ", uncorrected" } else { ", corrected" },
+        if status & CONTEXT_CORRUPT != 0 { ", context corrupt" } else { "" },
+        if status & OVERFLOW != 0 { ", more were lost" } else { "" }
+    );
+
+    if status & ADDR_VALID != 0 {
+        let address = cpu::rdmsr_raw(bank_msr(bank, MC_ADDR));
+        log::error!("[MCE] Bank {} address: {:#x}\n", bank, address);
+    }
+}
+
+pub fn init() {
+    unsafe {
+        interrupts::register_isr(
+            0x12,
+            machine_check as u64,
+            cpu::Ists::MachineCheck as u8,
+            0x8e,
+            "machine check",
+        );
+    }
+}
+
+/*
+    Turns on every error source of every bank, and CR4.MCE last. The banks are cleared
+    first, whatever is still in them was logged before this boot
+*/
+pub fn init_cpu() {
+    if !cpuid::enabled(Features::MCE) {
+        return;
+    }
+
+    if cpuid::enabled(Features::MCA) {
+        let cap = cpu::rdmsr(MsrList::McgCap);
+        if cap & MCG_CTL_PRESENT != 0 {
+            cpu::wrmsr(MsrList::McgCtl, u64::MAX);
+        }
+
+        for bank in 0..bank_count() {
+            let status = cpu::rdmsr_raw(bank_msr(bank, MC_STATUS));
+            if status & VALID != 0 {
+                log::warning!("[MCE] Bank {} has an error from before this boot:\n", bank);
+                log_bank(bank, status);
+            }
+
+            cpu::wrmsr_raw(bank_msr(bank, MC_CTL), u64::MAX);
+            cpu::wrmsr_raw(bank_msr(bank, MC_STATUS), 0);
+        }
+    }
+
+    unsafe {
+        let mut cr4: u64;
+        asm!("mov {}, cr4", out(reg) cr4);
+        asm!("mov cr4, {}", in(reg) cr4 | CR4_MCE);
+    }
+}
+
+interrupts::isr!(machine_check, |stack| {
+    let mcg_status = cpu::rdmsr(MsrList::McgStatus);
+    let mut fatal = None;
+
+    if cpuid::enabled(Features::MCA) {
+        for bank in 0..bank_count() {
+            let status = cpu::rdmsr_raw(bank_msr(bank, MC_STATUS));
+            if status & VALID == 0 {
+                continue;
+            }
+
+            log_bank(bank, status);
+            if is_fatal(status) && fatal.is_none() {
+                fatal = Some((bank, status));
+            }
+            cpu::wrmsr_raw(bank_msr(bank, MC_STATUS), 0);
+        }
+    }
+
+    // without a valid rip there's nowhere to go back to, even if every error was corrected
+    if fatal.is_some() || mcg_status & RESTART_IP_VALID == 0 {
+        crashdump::set_context(stack);
+
+        match fatal {
+            Some((bank, status)) => panic!(
+                "Machine check at {:#x}: {} in bank {} (status {:#x})",
+                stack.rip,
+                decode(status),
+                bank,
+                status
+            ),
+            None => panic!("Machine check at {:#x} with no way to restart", stack.rip),
+        }
+    }
+
+    // another #MC while this is set shuts the cpu down
+    cpu::wrmsr(MsrList::McgStatus, mcg_status & !IN_PROGRESS);
+});
@@ -1,7 +1,10 @@
-use crate::serial;
-use crate::utils::{bitmap, math::div_ceil};
+use crate::error::{KError, KResult};
+use crate::log;
+use crate::spinlock::Spinlock;
+use crate::utils::{bitmap, math::{div_ceil, round_up}};
 use core::ops::{Deref, DerefMut};
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use stivale_boot::v2::{StivaleMemoryMapEntry, StivaleMemoryMapEntryType};
 
 //TODO: eventually switch to a buddy allocator?
@@ -11,6 +14,17 @@ pub const PHYS_BASE: u64 = 0xffff800000000000;
 
 pub static mut PAGE_ALLOCATOR: Option<Pmm> = None;
 
+// pages of usable memory handed to the allocator, free or not
+static TOTAL_PAGES: AtomicUsize = AtomicUsize::new(0);
+
+// the bootloader reclaimable regions, given to the allocator once we're done with the bootloader's data
+const MAX_RECLAIMABLE: usize = 32;
+static mut RECLAIMABLE: [(u64, u64); MAX_RECLAIMABLE] = [(0, 0); MAX_RECLAIMABLE];
+
+// memory that must never be handed out, even if the memory map said it was reclaimable
+const MAX_RESERVED: usize = 32;
+static mut RESERVED: [(u64, u64); MAX_RESERVED] = [(0, 0); MAX_RESERVED];
+
 #[derive(Debug, Clone, Copy)]
 #[repr(transparent)]
 pub struct PhysAddr(u64);
@@ -53,7 +67,7 @@ pub struct PmmBox<T> {
 
 impl<T> PmmBox<T> {
     pub fn new(size: usize) -> Self {
-        serial::print!("creating PmmBox\n");
+        log::debug!("[PMM] creating PmmBox\n");
         let alloc_size = div_ceil(size, PAGE_SIZE as usize);
         let mem: *mut T = get()
             .calloc(alloc_size)
@@ -92,20 +106,21 @@ impl<T> DerefMut for PmmBox<T> {
 
 impl<T> Drop for PmmBox<T> {
     fn drop(&mut self) {
-        serial::print!("dropping PmmBox\n");
+        log::debug!("[PMM] dropping PmmBox\n");
         get().free(self.data as *mut u8, self.page_cnt);
     }
 }
 
-pub struct Pmm(spin::Mutex<bitmap::Bitmap>);
+// page faults and isrs allocate too, so the bitmap is only ever locked with interrupts off
+pub struct Pmm(Spinlock<bitmap::Bitmap>);
 
 impl Pmm {
     fn new(bitmap: bitmap::Bitmap) -> Self {
-        Pmm(spin::Mutex::new(bitmap))
+        Pmm(Spinlock::new(bitmap))
     }
 
-    pub fn alloc(&mut self, pages: usize) -> Option<PhysAddr> {
-        let mut bitmap = self.0.lock();
+    pub fn alloc(&mut self, pages: usize) -> KResult<PhysAddr> {
+        let mut bitmap = self.0.lock_irqsave();
         let mut count = 0;
 
         for i in 0..bitmap.size() * 8 {
@@ -118,8 +133,8 @@ impl Pmm {
                     for p in page..page + pages {
                         bitmap.clear(p);
                     }
-                    serial::print!("address: {:#x}\n", page as u64 * PAGE_SIZE);
-                    return Some(PhysAddr::new(page as u64 * PAGE_SIZE));
+                    log::debug!("[PMM] address: {:#x}\n", page as u64 * PAGE_SIZE);
+                    return Ok(PhysAddr::new(page as u64 * PAGE_SIZE));
                 }
 
                 continue;
@@ -128,24 +143,65 @@ impl Pmm {
             count = 0;
         }
 
-        None
+        Err(KError::ENOMEM)
     }
 
-    pub fn calloc(&mut self, pages: usize) -> Option<PhysAddr> {
-        if let Some(mem) = self.alloc(pages) {
-            unsafe {
-                mem.as_mut_ptr::<u8>()
-                    .write_bytes(0, pages * PAGE_SIZE as usize);
+    // like alloc, but the first page is aligned to align pages (used for huge pages)
+    pub fn alloc_aligned(&mut self, pages: usize, align: usize) -> KResult<PhysAddr> {
+        self.alloc_below(pages, align, u64::MAX)
+    }
+
+    // like alloc_aligned, with every page below limit (for devices that can't address it all)
+    pub fn alloc_below(&mut self, pages: usize, align: usize, limit: u64) -> KResult<PhysAddr> {
+        let mut bitmap = self.0.lock_irqsave();
+        let mut page = 0;
+        let last_page = (bitmap.size() * 8).min((limit / PAGE_SIZE) as usize);
+
+        while page + pages <= last_page {
+            if let Some(used) = (page..page + pages).rev().find(|p| !bitmap.is_set(*p)) {
+                page = round_up(used + 1, align);
+                continue;
+            }
+
+            for p in page..page + pages {
+                bitmap.clear(p);
             }
-            Some(mem)
-        } else {
-            None
+
+            return Ok(PhysAddr::new(page as u64 * PAGE_SIZE));
+        }
+
+        Err(KError::ENOMEM)
+    }
+
+    pub fn calloc(&mut self, pages: usize) -> KResult<PhysAddr> {
+        let mem = self.alloc(pages)?;
+
+        unsafe {
+            mem.higher_half()
+                .as_mut_ptr::<u8>()
+                .write_bytes(0, pages * PAGE_SIZE as usize);
         }
+
+        Ok(mem)
+    }
+
+    // one past the highest frame number the allocator knows, usable or not
+    pub fn max_pages(&self) -> usize {
+        self.0.lock_irqsave().size() * 8
+    }
+
+    pub fn total_pages(&self) -> usize {
+        TOTAL_PAGES.load(Ordering::Relaxed)
+    }
+
+    pub fn free_pages(&self) -> usize {
+        let bitmap = self.0.lock_irqsave();
+        (0..bitmap.size() * 8).filter(|page| bitmap.is_set(*page)).count()
     }
 
     pub fn free(&mut self, ptr: *mut u8, pages_amnt: usize) {
         let page = (ptr as u64 & !PHYS_BASE) / PAGE_SIZE;
-        let mut bitmap = self.0.lock();
+        let mut bitmap = self.0.lock_irqsave();
 
         for i in page..(page + pages_amnt as u64) {
             bitmap.set(i as usize);
@@ -216,11 +272,87 @@ pub unsafe fn init(entries: *const StivaleMemoryMapEntry, entries_num: u64) {
         for p in page..page + length {
             bitmap.set(p as usize);
         }
+
+        TOTAL_PAGES.fetch_add(length as usize, Ordering::Relaxed);
+    }
+
+    let mut reclaimable = 0;
+    for i in 0..entries_num {
+        let entry = &*(entries.offset(i as isize));
+
+        if matches!(entry.entry_type, StivaleMemoryMapEntryType::BootloaderReclaimable) {
+            if reclaimable == MAX_RECLAIMABLE {
+                log::warning!("[PMM] Too many bootloader reclaimable regions, some will be lost\n");
+                break;
+            }
+
+            RECLAIMABLE[reclaimable] = (entry.base, entry.length);
+            reclaimable += 1;
+        }
     }
 
     PAGE_ALLOCATOR = Some(Pmm::new(bitmap));
 }
 
+/*
+    Gives the bootloader reclaimable memory to the allocator. Nothing the bootloader
+    left there (stivale2 tags, its page tables, the memory map) can be used afterwards
+*/
+pub fn reclaim_bootloader_memory() {
+    let mut reclaimed = 0;
+
+    unsafe {
+        for (base, length) in RECLAIMABLE.iter_mut() {
+            if *length == 0 {
+                continue;
+            }
+
+            for page in (*base..*base + *length).step_by(PAGE_SIZE as usize) {
+                if is_reserved(page) {
+                    continue;
+                }
+
+                get().free((page + PHYS_BASE) as *mut u8, 1);
+                TOTAL_PAGES.fetch_add(1, Ordering::Relaxed);
+                reclaimed += PAGE_SIZE;
+            }
+
+            *length = 0;
+        }
+    }
+
+    log::info!("[PMM] Reclaimed {} KiB of bootloader memory\n", reclaimed / 1024);
+}
+
+fn is_reserved(page: u64) -> bool {
+    unsafe { RESERVED.iter() }.any(|(base, length)| page >= *base && page < base + length)
+}
+
+/*
+    Keeps the pages of a physical range away from the allocator for good, e.g. boot modules.
+    Has to be called before anything could have allocated them
+*/
+pub fn reserve(base: u64, length: u64) {
+    let start = base / PAGE_SIZE * PAGE_SIZE;
+    let end = round_up((base + length) as usize, PAGE_SIZE as usize) as u64;
+
+    unsafe {
+        match RESERVED.iter_mut().find(|(_, length)| *length == 0) {
+            Some(slot) => *slot = (start, end - start),
+            None => log::warning!("[PMM] Too many reserved regions, {:#x} isn't kept\n", base),
+        }
+
+        // the memory map may have listed it as usable
+        let mut bitmap = get().0.lock_irqsave();
+        for page in start / PAGE_SIZE..end / PAGE_SIZE {
+            if (page as usize) < bitmap.size() * 8 && bitmap.is_set(page as usize) {
+                bitmap.clear(page as usize);
+                TOTAL_PAGES.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
 pub fn get() -> &'static mut Pmm {
     unsafe {
         PAGE_ALLOCATOR
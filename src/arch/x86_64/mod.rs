@@ -1,8 +1,19 @@
 pub mod acpi;
 pub mod apic;
 pub mod cpu;
+pub mod cpuid;
+pub mod fpu;
+pub mod gdbstub;
 pub mod gdt;
 pub mod interrupts;
 pub mod io;
+pub mod ioapic;
+pub mod ipi;
+pub mod mce;
 pub mod mm;
 pub mod pci;
+pub mod power;
+#[cfg(feature = "smp")]
+pub mod smp;
+pub mod syscall;
+pub mod topology;
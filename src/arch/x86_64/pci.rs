@@ -1,6 +1,12 @@
 use super::io::{inl, outl};
 use crate::arch::mm::pmm::PhysAddr;
+#[cfg(feature = "audio")]
+use crate::drivers::ac97;
+#[cfg(feature = "ahci")]
 use crate::drivers::ahci;
+#[cfg(feature = "graphics")]
+use crate::drivers::{bochs, virtio};
+use crate::rcu::{Rcu, RcuGuard};
 use crate::serial;
 use alloc::vec::Vec;
 
@@ -8,7 +14,8 @@ const CONFIG_ADDR: u16 = 0xCF8;
 const CONFIG_DATA: u16 = 0xCFC;
 const MSI_CAPABILITY_ID: u8 = 0x5;
 
-pub static mut PCI_DEVICES: Vec<PciDevice> = alloc::vec![];
+// found once at boot, replaced as a whole if it's ever rescanned
+static PCI_DEVICES: Rcu<Vec<PciDevice>> = Rcu::new(Vec::new());
 
 #[derive(Debug)]
 pub struct PciDevice {
@@ -42,22 +49,36 @@ impl PciDevice {
             msi_offset: 0,
         };
 
-        if device.has_capabilities() {
-            let mut cap_offset = device.read(0x34) as u8;
+        if let Some((_, offset)) = device
+            .capabilities()
+            .into_iter()
+            .find(|(id, _)| *id == MSI_CAPABILITY_ID)
+        {
+            device.msi_offset = offset;
+        }
 
-            while cap_offset != 0 {
-                let capability = device.read(cap_offset);
-                if capability as u8 == MSI_CAPABILITY_ID {
-                    device.msi_offset = cap_offset;
-                    break;
-                }
+        device
+    }
 
-                // get the pointer to the next capability
-                cap_offset = (capability >> 8) as u8;
-            }
+    // the id and config space offset of every capability in the list
+    pub fn capabilities(&self) -> Vec<(u8, u8)> {
+        let mut capabilities = Vec::new();
+        if !self.has_capabilities() {
+            return capabilities;
         }
 
-        device
+        let mut cap_offset = self.read(0x34) as u8 & 0xfc;
+
+        // a broken list could loop, there's only room for 48 of them
+        while cap_offset != 0 && capabilities.len() < 48 {
+            let capability = self.read(cap_offset);
+            capabilities.push((capability as u8, cap_offset));
+
+            // get the pointer to the next capability
+            cap_offset = (capability >> 8) as u8 & 0xfc;
+        }
+
+        capabilities
     }
 
     pub fn read(&self, offset: u8) -> u32 {
@@ -68,6 +89,14 @@ impl PciDevice {
         write(data, self.bus, self.device, self.function, offset);
     }
 
+    pub fn vendor_id(&self) -> u16 {
+        self.vendor_id
+    }
+
+    pub fn device_id(&self) -> u16 {
+        self.device_id
+    }
+
     pub fn has_capabilities(&self) -> bool {
         (self.read(0x4) >> 16) & 1 << 4 != 0
     }
@@ -89,6 +118,22 @@ impl PciDevice {
         PhysAddr::new((bar & 0xfffffff0) as u64)
     }
 
+    // how much a memory bar decodes, found by writing all ones and seeing what sticks
+    pub fn bar_size(&self, bar_num: u8) -> u64 {
+        let offset = 0x10 + bar_num * 4;
+        let bar = self.read(offset);
+
+        if bar & 1 == 1 {
+            return 0;
+        }
+
+        self.write(u32::MAX, offset);
+        let mask = self.read(offset) & 0xfffffff0;
+        self.write(bar, offset);
+
+        (!mask).wrapping_add(1) as u64
+    }
+
     pub fn bus_master(&self) {
         let mut command_reg = self.read(0x4);
         command_reg |= 4;
@@ -101,6 +146,17 @@ impl PciDevice {
         self.write(command_reg, 0x4);
     }
 
+    // for devices whose registers are in i/o space
+    pub fn enable_io(&self) {
+        let mut command_reg = self.read(0x4);
+        command_reg |= 1;
+        self.write(command_reg, 0x4);
+    }
+
+    pub fn has_msi(&self) -> bool {
+        self.msi_offset != 0
+    }
+
     pub fn set_msi(&self, vector: usize) {
         if self.msi_offset == 0 {
             panic!("This device does not support MSIs");
@@ -124,6 +180,25 @@ impl PciDevice {
     }
 }
 
+// bb:dd.f vendor:device class.subclass.prog_if rev
+impl core::fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}.{} {:04x}:{:04x} class {:02x}.{:02x}.{:02x} rev {:02x}",
+            self.bus,
+            self.device,
+            self.function,
+            self.vendor_id,
+            self.device_id,
+            self.class,
+            self.subclass,
+            self.prog_if,
+            self.revision
+        )
+    }
+}
+
 fn get_header_type(bus: u8, device: u8, function: u8) -> u8 {
     let res = read(bus, device, function, 0xc);
     (res >> 16) as u8
@@ -131,6 +206,8 @@ fn get_header_type(bus: u8, device: u8, function: u8) -> u8 {
 
 // good old bruteforce
 pub fn enumerate_devices() {
+    let mut devices = Vec::new();
+
     for bus in 0..=255 {
         for device in 0..=31 {
             for function in 0..=7 {
@@ -139,21 +216,48 @@ pub fn enumerate_devices() {
                     continue;
                 }
 
-                unsafe {
-                    PCI_DEVICES.push(PciDevice::new(bus, device, function));
-                }
+                devices.push(PciDevice::new(bus, device, function));
             }
         }
     }
 
-    unsafe {
-        for dev in PCI_DEVICES.iter() {
+    PCI_DEVICES.publish(devices);
+
+    // the drivers that were left out of the build just don't claim their devices
+    #[cfg(feature = "ahci")]
+    {
+        for dev in PCI_DEVICES.read().iter() {
             if dev.class == 0x1 && dev.subclass == 0x6 && dev.prog_if == 0x1 {
                 // ahci controller
                 ahci::init(dev);
             }
         }
     }
+
+    #[cfg(feature = "graphics")]
+    {
+        for dev in PCI_DEVICES.read().iter() {
+            if dev.vendor_id == bochs::VENDOR_ID && dev.device_id == bochs::DEVICE_ID {
+                bochs::init(dev);
+            }
+            if dev.vendor_id == virtio::VENDOR_ID && dev.device_id == virtio::gpu::DEVICE_ID {
+                virtio::gpu::init(dev);
+            }
+        }
+    }
+
+    #[cfg(feature = "audio")]
+    {
+        for dev in PCI_DEVICES.read().iter() {
+            if dev.vendor_id == ac97::VENDOR_ID && dev.device_id == ac97::DEVICE_ID {
+                ac97::init(dev);
+            }
+        }
+    }
+}
+
+pub fn devices() -> RcuGuard<'static, Vec<PciDevice>> {
+    PCI_DEVICES.read()
 }
 
 pub fn read(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
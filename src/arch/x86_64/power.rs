@@ -0,0 +1,74 @@
+/*
+    Rebooting and powering off, both sync and unmount the filesystems first. Rebooting
+    pulses the reset line through the keyboard controller, and if that doesn't work, triple
+    faults.
+
+    Powering off properly needs the \_S5 package out of the dsdt's aml, which we can't
+    run. So it uses the ports qemu, bochs and virtualbox have for it, and on anything else
+    just halts once it's safe to turn the machine off
+*/
+
+use super::io::{inb, outb, outw};
+use crate::fs::{vfs, writeback};
+use crate::log;
+use core::arch::asm;
+
+const KBD_STATUS_PORT: u16 = 0x64;
+const KBD_COMMAND_PORT: u16 = 0x64;
+const KBD_INPUT_FULL: u8 = 1 << 1;
+const KBD_PULSE_RESET: u8 = 0xfe;
+
+// (port, value) written to power off some emulator
+const POWER_OFF_PORTS: [(u16, u16); 3] = [
+    (0x604, 0x2000),  // qemu
+    (0xb004, 0x2000), // bochs and older qemu
+    (0x4004, 0x3400), // virtualbox
+];
+
+fn sync() {
+    log::info!("[POWER] Syncing the filesystems\n");
+    if writeback::sync().is_err() {
+        log::error!("[POWER] Some changes could not be written back\n");
+    }
+
+    // so they're clean on the disk, nothing is going to write to them anymore
+    vfs::unmount_all();
+}
+
+fn halt() -> ! {
+    loop {
+        unsafe {
+            asm!("cli", "hlt");
+        }
+    }
+}
+
+pub fn reboot() -> ! {
+    sync();
+    log::info!("[POWER] Rebooting\n");
+
+    unsafe {
+        while inb(KBD_STATUS_PORT) & KBD_INPUT_FULL != 0 {
+            core::hint::spin_loop();
+        }
+        outb(KBD_COMMAND_PORT, KBD_PULSE_RESET);
+
+        // an idt that can't hold any vector, so the exception turns into a triple fault
+        let null_idt = [0u16; 5];
+        asm!("lidt [{}]", "int3", in(reg) &null_idt);
+    }
+
+    halt()
+}
+
+pub fn power_off() -> ! {
+    sync();
+    log::info!("[POWER] Powering off\n");
+
+    for (port, value) in POWER_OFF_PORTS {
+        unsafe { outw(port, value) };
+    }
+
+    log::info!("[POWER] It's now safe to turn off the machine\n");
+    halt()
+}
@@ -0,0 +1,376 @@
+/*
+    Bringing up the other cpus, the aps. The bsp sends each one an INIT and startup ipis,
+    which start it in real mode at a page below 1MiB. The trampoline below is copied there
+    and takes it straight to long mode, then jumps to ap_main with what the bsp left in the
+    handoff structure at the trampoline's end.
+
+    A bootloader that was asked to start the aps (the stivale2 smp tag) leaves them spinning
+    on goto_address in its own memory. Those are moved into the kernel by park before that
+    memory is reclaimed, and released by init like the others would be started.
+
+    Either way the aps come up one at a time, since they share the tss descriptor
+*/
+
+use super::mm::pmm::{self, PhysAddr};
+use super::{acpi, apic, cpu, gdt, interrupts, ipi};
+use crate::drivers::timer_source;
+use crate::log;
+use crate::mm::vmm::{self, PageFlags, VirtAddr, VirtualMemManager};
+use alloc::{boxed::Box, vec::Vec};
+use core::arch::{asm, global_asm};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use stivale_boot::v2::{StivaleSmpInfo, StivaleSmpTag};
+
+const AP_STACK_PAGES: usize = 4;
+// the startup ipi takes a page number below 1MiB
+const LOW_MEMORY_END: u64 = 0x100000;
+// the trampoline loads cr3 while still in real mode, so only 32 bits of it
+const LOW_4G_END: u64 = 1 << 32;
+const EFER_LME: u64 = 1 << 8;
+const CR0_WP: u64 = 1 << 16;
+const INIT_DELAY_MS: u64 = 10;
+const STARTUP_TIMEOUT_MS: u64 = 100;
+// the bootloader's aps are parked before the clock is up, so they get a number of spins
+const PARK_SPINS: usize = 100_000_000;
+
+// set by an ap once it can take interrupts
+static AP_READY: AtomicBool = AtomicBool::new(false);
+// the lapic id of every online cpu, by cpu id
+static mut LAPIC_IDS: Vec<u32> = Vec::new();
+static mut PARKED: Vec<&'static Parked> = Vec::new();
+
+/*
+    Real mode to long mode in one go: with PAE, LME and the page tables set up, turning on
+    protection and paging together is allowed. Everything in 16 bit code is addressed
+    relative to cs, which the startup ipi sets to the page the trampoline was copied to.
+    The far jump's target and the gdt's address are absolute, so they're patched after
+    the copy. The page has to be identity mapped by the page tables in the handoff
+*/
+global_asm!(
+    ".global smp_trampoline_start",
+    ".global smp_trampoline_jump",
+    ".global smp_trampoline_64",
+    ".global smp_trampoline_gdt",
+    ".global smp_trampoline_gdtr",
+    ".global smp_trampoline_handoff",
+    ".global smp_trampoline_end",
+    ".code16",
+    "smp_trampoline_start:",
+    "cli",
+    "cld",
+    "mov ax, cs",
+    "mov ds, ax",
+    "lgdt [smp_gdtr_offset]",
+    // PAE
+    "mov eax, cr4",
+    "or eax, 1 << 5",
+    "mov cr4, eax",
+    "mov eax, dword ptr [smp_handoff_offset]",
+    "mov cr3, eax",
+    "mov ecx, 0xc0000080",
+    "rdmsr",
+    "or eax, dword ptr [smp_handoff_offset + 8]",
+    "wrmsr",
+    // protection and paging
+    "mov eax, cr0",
+    "or eax, 0x80000001",
+    "mov cr0, eax",
+    // jmp far dword 0x8:smp_trampoline_64, the offset is patched
+    "smp_trampoline_jump:",
+    ".byte 0x66, 0xea",
+    ".long 0",
+    ".word 0x8",
+    ".code64",
+    "smp_trampoline_64:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "mov rsp, qword ptr [rip + smp_trampoline_handoff + 16]",
+    "mov rdi, qword ptr [rip + smp_trampoline_handoff + 32]",
+    "call qword ptr [rip + smp_trampoline_handoff + 24]",
+    "ud2",
+    ".balign 8",
+    "smp_trampoline_gdt:",
+    ".quad 0",
+    ".quad 0x00af9a000000ffff", // 64 bit code
+    ".quad 0x00cf92000000ffff", // data
+    "smp_trampoline_gdtr:",
+    ".word 23",
+    ".long 0", // patched
+    ".balign 8",
+    "smp_trampoline_handoff:",
+    ".zero 40",
+    "smp_trampoline_end:",
+    // the assembler only takes one symbol in a memory operand
+    ".set smp_gdtr_offset, smp_trampoline_gdtr - smp_trampoline_start",
+    ".set smp_handoff_offset, smp_trampoline_handoff - smp_trampoline_start",
+);
+
+extern "C" {
+    static smp_trampoline_start: u8;
+    static smp_trampoline_jump: u8;
+    static smp_trampoline_64: u8;
+    static smp_trampoline_gdt: u8;
+    static smp_trampoline_gdtr: u8;
+    static smp_trampoline_handoff: u8;
+    static smp_trampoline_end: u8;
+}
+
+// what an ap needs to get to ap_main, the trampoline has the field offsets hardcoded
+#[repr(C)]
+struct Handoff {
+    cr3: u64,
+    efer: u64, // or'ed into efer before paging is turned on
+    stack: u64,
+    entry: u64, // ap_main
+    cpu_id: u64,
+}
+
+impl Handoff {
+    fn new(cr3: u64, cpu_id: usize) -> Self {
+        let stack = pmm::get()
+            .calloc(AP_STACK_PAGES)
+            .expect("Could not allocate the stack of an ap")
+            .higher_half()
+            .as_u64();
+
+        Handoff {
+            cr3,
+            efer: EFER_LME | cpu::rdmsr(cpu::MsrList::Efer) & cpu::EFER_NXE,
+            stack: stack + (AP_STACK_PAGES as u64) * pmm::PAGE_SIZE,
+            entry: ap_main as u64,
+            cpu_id: cpu_id as u64,
+        }
+    }
+}
+
+// an ap the bootloader started, spinning in the kernel until init lets it go
+struct Parked {
+    lapic_id: u32,
+    handoff: Handoff,
+    cpu_id: AtomicU64, // given out when it's released, so ids have no holes
+    parked: AtomicBool,
+    released: AtomicBool,
+}
+
+extern "C" fn ap_main(cpu_id: u64) -> ! {
+    unsafe {
+        gdt::init();
+        interrupts::load();
+        // read only pages are read only for the kernel too, like vmm::init does on the bsp
+        asm!(
+            "mov {tmp}, cr0",
+            "or {tmp}, {wp}",
+            "mov cr0, {tmp}",
+            tmp = out(reg) _,
+            wp = in(reg) CR0_WP
+        );
+    }
+
+    cpu::init_msrs();
+    vmm::get().switch_pagemap();
+    cpu::init_cpu(cpu_id as usize);
+    apic::init_ap();
+    ipi::set_ready();
+
+    AP_READY.store(true, Ordering::SeqCst);
+    cpu::sti();
+    cpu::halt()
+}
+
+// where the bootloader's aps jump to, on the stack park gave them
+unsafe extern "C" fn stivale_entry(info: *const StivaleSmpInfo) -> ! {
+    let parked = &*((*info).extra_argument as *const Parked);
+
+    // neither the bootloader's gdt nor its page tables survive its memory being reclaimed
+    gdt::init();
+    cpu::wrmsr(cpu::MsrList::Efer, cpu::rdmsr(cpu::MsrList::Efer) | parked.handoff.efer);
+    asm!("mov cr3, {}", in(reg) parked.handoff.cr3);
+    parked.parked.store(true, Ordering::SeqCst);
+
+    while !parked.released.load(Ordering::SeqCst) {
+        core::hint::spin_loop();
+    }
+
+    ap_main(parked.cpu_id.load(Ordering::SeqCst))
+}
+
+/*
+    Moves the aps the bootloader started into the kernel. Has to run before the bootloader's
+    memory is reclaimed, but after the vmm, since they switch to the kernel's page tables
+*/
+pub fn park(tag: Option<&StivaleSmpTag>) {
+    let tag = match tag {
+        Some(tag) => tag,
+        None => return,
+    };
+
+    let cr3 = vmm::get().pagemap.as_u64();
+
+    for info in tag.as_slice().iter().filter(|info| info.lapic_id != tag.bsp_lapic_id) {
+        let lapic_id = info.lapic_id;
+        let parked: &'static Parked = Box::leak(Box::new(Parked {
+            lapic_id,
+            handoff: Handoff::new(cr3, 0),
+            cpu_id: AtomicU64::new(0),
+            parked: AtomicBool::new(false),
+            released: AtomicBool::new(false),
+        }));
+
+        let info = info as *const StivaleSmpInfo as *mut StivaleSmpInfo;
+        unsafe {
+            (*info).target_stack = parked.handoff.stack;
+            (*info).extra_argument = parked as *const Parked as u64;
+            // the ap jumps as soon as it sees goto_address change, so it's written last
+            core::sync::atomic::fence(Ordering::SeqCst);
+            core::ptr::addr_of_mut!((*info).goto_address).write_volatile(stivale_entry as u64);
+        }
+
+        let mut spins = 0;
+        while !parked.parked.load(Ordering::SeqCst) && spins < PARK_SPINS {
+            core::hint::spin_loop();
+            spins += 1;
+        }
+
+        if parked.parked.load(Ordering::SeqCst) {
+            unsafe { PARKED.push(parked) };
+        } else {
+            log::warning!("[SMP] The cpu with lapic id {} never left the bootloader\n", lapic_id);
+        }
+    }
+}
+
+// waits for the ap that was just started, and counts it as online if it came up
+fn wait_ready(lapic_id: u32) -> bool {
+    let deadline = timer_source::current_ms() + STARTUP_TIMEOUT_MS;
+
+    while !AP_READY.load(Ordering::SeqCst) {
+        if timer_source::current_ms() >= deadline {
+            log::warning!("[SMP] The cpu with lapic id {} didn't come up\n", lapic_id);
+            return false;
+        }
+
+        core::hint::spin_loop();
+    }
+
+    unsafe { LAPIC_IDS.push(lapic_id) };
+    cpu::set_online(cpu::online_cpus() + 1);
+    true
+}
+
+fn release_parked() {
+    for parked in unsafe { core::mem::take(&mut PARKED) } {
+        AP_READY.store(false, Ordering::SeqCst);
+        parked.cpu_id.store(cpu::online_cpus() as u64, Ordering::SeqCst);
+        parked.released.store(true, Ordering::SeqCst);
+
+        wait_ready(parked.lapic_id);
+    }
+}
+
+// a pml4 below 4GiB with the kernel's higher half and the trampoline identity mapped
+fn trampoline_pagemap(trampoline: PhysAddr) -> Option<VirtualMemManager> {
+    let pml4 = pmm::get().alloc_below(1, 1, LOW_4G_END).ok()?;
+
+    unsafe {
+        let entries = pml4.higher_half().as_mut_ptr::<u64>();
+        let kernel_entries = vmm::get().pagemap.higher_half().as_mut_ptr::<u64>();
+
+        entries.write_bytes(0, 256);
+        entries.add(256).copy_from(kernel_entries.add(256), 256);
+    }
+
+    let mut pagemap = VirtualMemManager::new(false);
+    pagemap.pagemap = pml4;
+    pagemap.map_page(VirtAddr::new(trampoline.as_u64()), trampoline, PageFlags::PRESENT, false);
+
+    Some(pagemap)
+}
+
+// copies the trampoline to a page below 1MiB, returns it and where its handoff ended up
+fn install_trampoline() -> Option<(PhysAddr, *mut Handoff)> {
+    let trampoline = pmm::get().alloc_below(1, 1, LOW_MEMORY_END).ok()?;
+
+    unsafe {
+        let start = &smp_trampoline_start as *const u8;
+        let offset = |symbol: &u8| symbol as *const u8 as usize - start as usize;
+        let size = offset(&smp_trampoline_end);
+        let copy = trampoline.higher_half().as_mut_ptr::<u8>();
+
+        copy.copy_from(start, size);
+
+        let base = trampoline.as_u64() as u32;
+        let jump_target = copy.add(offset(&smp_trampoline_jump) + 2) as *mut u32;
+        jump_target.write_unaligned(base + offset(&smp_trampoline_64) as u32);
+        let gdt_base = copy.add(offset(&smp_trampoline_gdtr) + 2) as *mut u32;
+        gdt_base.write_unaligned(base + offset(&smp_trampoline_gdt) as u32);
+
+        let handoff = copy.add(offset(&smp_trampoline_handoff)) as *mut Handoff;
+        Some((trampoline, handoff))
+    }
+}
+
+// INIT, then up to two startup ipis, like the multiprocessor spec says
+fn start_with_ipis(lapic_ids: &[u32]) {
+    let (trampoline, handoff) = match install_trampoline() {
+        Some(trampoline) => trampoline,
+        None => {
+            log::warning!("[SMP] No free page below 1MiB for the trampoline\n");
+            return;
+        }
+    };
+
+    let mut pagemap = match trampoline_pagemap(trampoline) {
+        Some(pagemap) => pagemap,
+        None => {
+            log::warning!("[SMP] No free page below 4GiB for the trampoline's page tables\n");
+            pmm::get().free(trampoline.higher_half().as_mut_ptr(), 1);
+            return;
+        }
+    };
+
+    let lapic = apic::get();
+    let page = (trampoline.as_u64() / pmm::PAGE_SIZE) as u8;
+
+    for lapic_id in lapic_ids {
+        let cpu_id = cpu::online_cpus();
+        unsafe { handoff.write(Handoff::new(pagemap.pagemap.as_u64(), cpu_id)) };
+        AP_READY.store(false, Ordering::SeqCst);
+
+        lapic.send_init(*lapic_id);
+        timer_source::sleep(INIT_DELAY_MS);
+        lapic.send_startup(*lapic_id, page);
+
+        // the second one is only needed if the first one was missed
+        timer_source::sleep(1);
+        if !AP_READY.load(Ordering::SeqCst) {
+            lapic.send_startup(*lapic_id, page);
+        }
+
+        wait_ready(*lapic_id);
+    }
+
+    // every ap that came up is on the kernel's page tables by now
+    pagemap.destroy();
+    pmm::get().free(trampoline.higher_half().as_mut_ptr(), 1);
+}
+
+// needs the lapic and the clock, and has to run before anything sizes itself by online_cpus
+pub fn init() {
+    let bsp = apic::get().id();
+    unsafe { LAPIC_IDS.push(bsp) };
+
+    if unsafe { !PARKED.is_empty() } {
+        release_parked();
+    } else {
+        let aps: Vec<u32> = acpi::lapic_ids().into_iter().filter(|id| *id != bsp).collect();
+        start_with_ipis(&aps);
+    }
+
+    log::info!("[SMP] {} cpus online\n", cpu::online_cpus());
+}
+
+pub fn lapic_id(cpu_id: usize) -> Option<u32> {
+    unsafe { LAPIC_IDS.get(cpu_id).copied() }
+}
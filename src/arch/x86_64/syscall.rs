@@ -0,0 +1,88 @@
+use super::cpu::{self, MsrList};
+use crate::proc::process::SelectorValues;
+use crate::syscall;
+
+// the msrs syscall/sysret use, EFER.SCE is set with them in cpu::init_msrs
+pub fn init() {
+    /*
+        sysret loads cs with STAR[63:48] + 16 and ss with STAR[63:48] + 8,
+        which is why the user data segment comes before the user code segment
+    */
+    let star = (SelectorValues::KernelCs as u64) << 32 | (SelectorValues::KernelDs as u64 | 0x3) << 48;
+
+    cpu::wrmsr(MsrList::Star, star);
+    cpu::wrmsr(MsrList::Lstar, syscall_entry as u64);
+    // clear IF, TF, DF and AC on entry
+    cpu::wrmsr(MsrList::Sfmask, 1 << 9 | 1 << 8 | 1 << 10 | 1 << 18);
+}
+
+unsafe extern "C" fn dispatch(regs: &mut cpu::InterruptContext) {
+    regs.rax = syscall::dispatch(regs.rax, regs.rdi, regs.rsi, regs.rdx, regs.r10, regs.r8, regs.r9);
+}
+
+/*
+    Builds an InterruptContext on the per-cpu kernel stack, so the syscall
+    handlers see the same layout as the interrupt handlers. SFMASK clears IF
+    on entry, the handlers run with interrupts enabled again
+*/
+#[naked]
+unsafe extern "C" fn syscall_entry() {
+    core::arch::asm!(
+        "swapgs",
+        "mov qword ptr gs:[16], rsp",
+        "mov rsp, qword ptr gs:[24]",
+
+        "push 0x1b", // user ds
+        "push qword ptr gs:[16]",
+        "push r11", // rflags
+        "push 0x23", // user cs
+        "push rcx", // rip
+
+        "push r15",
+        "push r14",
+        "push r13",
+        "push r12",
+        "push r11",
+        "push r10",
+        "push r9",
+        "push r8",
+        "push rbp",
+        "push rdi",
+        "push rsi",
+        "push rdx",
+        "push rcx",
+        "push rbx",
+        "push rax",
+        "cld",
+
+        // the user rsp is in the frame now, so a syscall can block or be preempted
+        "sti",
+        "mov rdi, rsp",
+        "call {dispatch}",
+        "cli", // until sysret, which brings the user's rflags back
+
+        "pop rax",
+        "pop rbx",
+        "pop rcx",
+        "pop rdx",
+        "pop rsi",
+        "pop rdi",
+        "pop rbp",
+        "pop r8",
+        "pop r9",
+        "pop r10",
+        "pop r11",
+        "pop r12",
+        "pop r13",
+        "pop r14",
+        "pop r15",
+
+        "mov rcx, [rsp]",
+        "mov r11, [rsp + 16]",
+        "mov rsp, [rsp + 24]",
+        "swapgs",
+        "sysretq",
+        dispatch = sym dispatch,
+        options(noreturn)
+    );
+}
@@ -0,0 +1,322 @@
+/*
+    How the cpus are laid out: which package, core and hardware thread each one is, and
+    the caches they have. Every cpu reads its own apic id and splits it with the shifts
+    from cpuid leaf 0x1f (or 0xb on older cpus), the low bits number the threads of a
+    core, the ones above them the cores of a package, and the rest is the package.
+
+    The caches come from leaf 4, or 0x8000001d on amd, and are the same on every cpu so
+    they're only read once. The scheduler uses the layout to keep threads close to the cpu
+    they last ran on
+*/
+
+use super::cpuid::{self, Cpuid, Features};
+use super::{acpi, cpu};
+use crate::log;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+// leaf 0xb/0x1f level types
+const LEVEL_SMT: u32 = 1;
+const LEVEL_INVALID: u32 = 0;
+
+static CPUS: spin::Mutex<Vec<Option<CpuTopology>>> = spin::Mutex::new(Vec::new());
+static mut LAYOUT: Option<Layout> = None;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CpuTopology {
+    pub apic_id: u32,
+    pub package: u32,
+    pub core: u32,   // within the package
+    pub thread: u32, // within the core
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CacheType {
+    Data,
+    Instruction,
+    Unified,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Cache {
+    pub level: u32,
+    pub cache_type: CacheType,
+    pub size: usize, // in bytes
+    pub line_size: usize,
+    pub ways: usize,
+    pub shared_by: u32, // how many hardware threads share it at most
+}
+
+// how far apart two cpus are, the scheduler prefers the closest one
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Distance {
+    Same,
+    Core,    // smt siblings, they share every cache
+    Package, // they share the last level cache
+    Remote,
+}
+
+struct Layout {
+    smt_shift: u32,     // apic id bits for the thread in a core
+    package_shift: u32, // apic id bits for everything below the package
+    caches: Vec<Cache>,
+}
+
+fn ceil_log2(value: u32) -> u32 {
+    if value <= 1 {
+        0
+    } else {
+        32 - (value - 1).leading_zeros()
+    }
+}
+
+fn max_leaf() -> u32 {
+    Cpuid::raw(0, 0).eax
+}
+
+fn is_amd() -> bool {
+    cpuid::info().vendor == "AuthenticAMD"
+}
+
+// the shifts from leaf 0x1f or 0xb, None if the cpu has neither
+fn extended_shifts() -> Option<(u32, u32)> {
+    let max_leaf = max_leaf();
+    let leaf = if max_leaf >= 0x1f && Cpuid::raw(0x1f, 0).ebx != 0 {
+        0x1f
+    } else if max_leaf >= 0xb && Cpuid::raw(0xb, 0).ebx != 0 {
+        0xb
+    } else {
+        return None;
+    };
+
+    let mut smt_shift = 0;
+    let mut package_shift = 0;
+
+    // every level gives the shift to the level above it, the last one the package's
+    for subleaf in 0.. {
+        let res = Cpuid::raw(leaf, subleaf);
+        let level_type = (res.ecx >> 8) & 0xff;
+        if level_type == LEVEL_INVALID {
+            break;
+        }
+
+        let shift = res.eax & 0x1f;
+        if level_type == LEVEL_SMT {
+            smt_shift = shift;
+        }
+        package_shift = shift;
+    }
+
+    Some((smt_shift, package_shift))
+}
+
+// what leaf 1 and the core count leaves say, for cpus without leaf 0xb
+fn legacy_shifts() -> (u32, u32) {
+    if !cpuid::has(Features::HTT) {
+        return (0, 0);
+    }
+
+    let logical = (Cpuid::raw(1, 0).ebx >> 16) & 0xff;
+    let cores = if is_amd() && Cpuid::raw(0x8000_0000, 0).eax >= 0x8000_0008 {
+        (Cpuid::raw(0x8000_0008, 0).ecx & 0xff) + 1
+    } else if max_leaf() >= 4 {
+        (Cpuid::raw(4, 0).eax >> 26) + 1
+    } else {
+        1
+    };
+
+    let package_shift = ceil_log2(logical);
+    (ceil_log2(logical / cores.max(1)), package_shift)
+}
+
+fn detect_caches() -> Vec<Cache> {
+    let mut caches = Vec::new();
+
+    let leaf = if is_amd() {
+        if !cpuid::has(Features::TOPOEXT) {
+            return caches;
+        }
+        0x8000_001d
+    } else if max_leaf() >= 4 {
+        4
+    } else {
+        return caches;
+    };
+
+    for subleaf in 0.. {
+        let res = Cpuid::raw(leaf, subleaf);
+        let cache_type = match res.eax & 0x1f {
+            1 => CacheType::Data,
+            2 => CacheType::Instruction,
+            3 => CacheType::Unified,
+            _ => break,
+        };
+
+        let line_size = (res.ebx & 0xfff) as usize + 1;
+        let partitions = ((res.ebx >> 12) & 0x3ff) as usize + 1;
+        let ways = ((res.ebx >> 22) & 0x3ff) as usize + 1;
+        let sets = res.ecx as usize + 1;
+
+        caches.push(Cache {
+            level: (res.eax >> 5) & 0x7,
+            cache_type,
+            size: ways * partitions * line_size * sets,
+            line_size,
+            ways,
+            shared_by: ((res.eax >> 14) & 0xfff) + 1,
+        });
+    }
+
+    caches
+}
+
+fn layout() -> &'static Layout {
+    unsafe {
+        if LAYOUT.is_none() {
+            let (smt_shift, package_shift) = extended_shifts().unwrap_or_else(legacy_shifts);
+
+            LAYOUT = Some(Layout {
+                smt_shift,
+                package_shift,
+                caches: detect_caches(),
+            });
+        }
+
+        LAYOUT.as_ref().unwrap()
+    }
+}
+
+// the x2apic id when there is one, the 8 bit xapic id otherwise
+fn current_apic_id() -> u32 {
+    if max_leaf() >= 0xb && Cpuid::raw(0xb, 0).ebx != 0 {
+        Cpuid::raw(0xb, 0).edx
+    } else {
+        Cpuid::raw(1, 0).ebx >> 24
+    }
+}
+
+pub fn split(apic_id: u32) -> CpuTopology {
+    let layout = layout();
+    let core_bits = layout.package_shift.saturating_sub(layout.smt_shift);
+
+    CpuTopology {
+        apic_id,
+        package: apic_id.checked_shr(layout.package_shift).unwrap_or(0),
+        core: (apic_id >> layout.smt_shift) & ((1u64 << core_bits) - 1) as u32,
+        thread: apic_id & ((1u64 << layout.smt_shift) - 1) as u32,
+    }
+}
+
+// records where the calling cpu is, every cpu runs it once while it's brought up
+pub fn register(cpu_id: usize) {
+    let topology = split(current_apic_id());
+    let mut cpus = CPUS.lock();
+
+    if cpus.len() <= cpu_id {
+        cpus.resize(cpu_id + 1, None);
+    }
+    cpus[cpu_id] = Some(topology);
+}
+
+pub fn get(cpu_id: usize) -> Option<CpuTopology> {
+    CPUS.lock().get(cpu_id).copied().flatten()
+}
+
+pub fn caches() -> &'static [Cache] {
+    &layout().caches
+}
+
+/*
+    How many cpus there can be, for sizing per-cpu structures. The madt lists every cpu
+    the firmware knows about, even ones that failed to start
+*/
+pub fn possible_cpus() -> usize {
+    acpi::lapic_ids().len().max(cpu::online_cpus())
+}
+
+pub fn distance(a: usize, b: usize) -> Distance {
+    if a == b {
+        return Distance::Same;
+    }
+
+    match (get(a), get(b)) {
+        (Some(a), Some(b)) if a.package == b.package && a.core == b.core => Distance::Core,
+        (Some(a), Some(b)) if a.package == b.package => Distance::Package,
+        _ => Distance::Remote,
+    }
+}
+
+// the cpus no further than distance from cpu, as an affinity style mask
+pub fn domain(cpu_id: usize, distance: Distance) -> u64 {
+    let cpus = CPUS.lock().len().min(64);
+
+    (0..cpus)
+        .filter(|other| self::distance(cpu_id, *other) <= distance)
+        .fold(0, |mask, other| mask | 1 << other)
+}
+
+fn format_size(size: usize) -> String {
+    let mut formatted = String::new();
+
+    if size >= 1024 * 1024 && size % (1024 * 1024) == 0 {
+        write!(formatted, "{}M", size / (1024 * 1024)).ok();
+    } else {
+        write!(formatted, "{}K", size / 1024).ok();
+    }
+
+    formatted
+}
+
+// after the aps came up, so every cpu is counted
+pub fn print_summary() {
+    let (packages, cores, threads) = {
+        let cpus = CPUS.lock();
+        let mut cores: Vec<(u32, u32)> = cpus
+            .iter()
+            .flatten()
+            .map(|cpu| (cpu.package, cpu.core))
+            .collect();
+        cores.sort_unstable();
+        cores.dedup();
+
+        let mut packages: Vec<u32> = cores.iter().map(|(package, _)| *package).collect();
+        packages.dedup();
+
+        (packages.len(), cores.len(), cpus.iter().flatten().count())
+    };
+
+    log::info!(
+        "[CPU] {} package(s), {} core(s), {} thread(s)\n",
+        packages,
+        cores,
+        threads
+    );
+
+    let mut summary = String::new();
+    for cache in caches() {
+        let kind = match cache.cache_type {
+            CacheType::Data => "d",
+            CacheType::Instruction => "i",
+            CacheType::Unified => "",
+        };
+
+        if !summary.is_empty() {
+            summary.push_str(", ");
+        }
+        write!(
+            summary,
+            "L{}{} {} {}-way (shared by {})",
+            cache.level,
+            kind,
+            format_size(cache.size),
+            cache.ways,
+            cache.shared_by
+        )
+        .ok();
+    }
+
+    if !summary.is_empty() {
+        log::info!("[CPU] Caches: {}\n", summary);
+    }
+}
@@ -0,0 +1,62 @@
+/*
+    The modules the bootloader loaded along with the kernel. Their names are copied out of
+    the stivale2 tag, and their memory is reserved in the pmm, so both stay valid after the
+    bootloader's memory is reclaimed
+*/
+
+use crate::arch::mm::pmm::{self, PHYS_BASE};
+use crate::log;
+use alloc::{string::String, vec::Vec};
+use stivale_boot::v2::StivaleModuleTag;
+
+static mut MODULES: Vec<Module> = Vec::new();
+
+pub struct Module {
+    pub name: String, // the whole module string from the bootloader's config
+    pub address: u64, // physical
+    pub size: usize,
+}
+
+impl Module {
+    pub fn data(&self) -> &'static [u8] {
+        unsafe { core::slice::from_raw_parts(self.as_mut_ptr(), self.size) }
+    }
+
+    // the memory is never given back, so it's fine to write to it
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        (self.address | PHYS_BASE) as *mut u8
+    }
+}
+
+// needs the heap, has to run before reclaim_bootloader_memory
+pub fn init(tag: Option<&StivaleModuleTag>) {
+    let tag = match tag {
+        Some(tag) => tag,
+        None => return,
+    };
+
+    for module in tag.iter() {
+        // works whether the bootloader gave us physical or higher half addresses
+        let address = module.start & !PHYS_BASE;
+        let size = module.size() as usize;
+
+        pmm::reserve(address, size as u64);
+        log::info!("[BOOT] Module {} at {:#x}, {} KiB\n", module.as_str(), address, size / 1024);
+
+        unsafe {
+            MODULES.push(Module {
+                name: String::from(module.as_str()),
+                address,
+                size,
+            });
+        }
+    }
+}
+
+pub fn modules() -> &'static [Module] {
+    unsafe { MODULES.as_slice() }
+}
+
+pub fn find(prefix: &str) -> Option<&'static Module> {
+    modules().iter().find(|module| module.name.starts_with(prefix))
+}
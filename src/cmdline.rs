@@ -0,0 +1,47 @@
+use stivale_boot::v2::StivaleCommandLineTag;
+
+const COMMAND_LINE_MAX: usize = 512;
+
+// copied out of the bootloader's memory, so it survives it being reclaimed
+static mut COMMAND_LINE_BUFFER: [u8; COMMAND_LINE_MAX] = [0; COMMAND_LINE_MAX];
+static mut COMMAND_LINE: &str = "";
+
+// the command line is a list of key=value pairs (or lone keys) separated by spaces
+pub fn init(tag: Option<&StivaleCommandLineTag>) {
+    let tag = match tag {
+        Some(tag) if tag.command_line != 0 => tag,
+        _ => return,
+    };
+
+    let start = tag.command_line as *const u8;
+    let mut len = 0;
+
+    unsafe {
+        while *start.add(len) != 0 && len < COMMAND_LINE_MAX {
+            len += 1;
+        }
+
+        COMMAND_LINE_BUFFER[..len].copy_from_slice(core::slice::from_raw_parts(start, len));
+
+        if let Ok(command_line) = core::str::from_utf8(&COMMAND_LINE_BUFFER[..len]) {
+            COMMAND_LINE = command_line;
+        }
+    }
+}
+
+pub fn get() -> &'static str {
+    unsafe { COMMAND_LINE }
+}
+
+// returns the value of key, or an empty string if it was passed without one
+pub fn option(key: &str) -> Option<&'static str> {
+    for arg in get().split_whitespace() {
+        let (name, value) = arg.split_once('=').unwrap_or((arg, ""));
+
+        if name == key {
+            return Some(value);
+        }
+    }
+
+    None
+}
@@ -0,0 +1,254 @@
+/*
+    With "crashdump" on the command line, a panic leaves a report of what the kernel was
+    doing behind: the registers, the backtrace, memory stats and the kernel's message
+    buffer. "crashdump=serial" prints it base64 encoded between two markers, so nothing on
+    the way mangles it, and "crashdump=<partition>" (e.g. ahci0p3) writes it to the start of
+    that partition behind a header with its length and crc32, to be read after a reboot.
+
+    Nothing is allocated while dumping, the heap might be what broke
+*/
+
+use crate::arch::cpu::InterruptContext;
+use crate::arch::mm::pmm;
+use crate::fs::partitions::{self, Partition};
+use crate::utils::{base64, crc32::crc32};
+use crate::{cmdline, ksym, log, serial};
+use alloc::sync::Arc;
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const VERSION: usize = 1;
+const MAGIC: &[u8; 8] = b"GRIFDUMP";
+const HEADER_SIZE: usize = 16; // the magic, the report's length and its crc32
+const DUMP_SIZE: usize = 0x8000; // the kmsg buffer is half of it
+const SECTOR_SIZE: usize = 512;
+const LINE_LENGTH: usize = 76;
+
+static mut TARGET: Option<Target> = None;
+// what the cpu was doing when an exception turned into a panic
+static mut CONTEXT: Option<InterruptContext> = None;
+static mut DUMP: Dump = Dump {
+    buffer: [0; DUMP_SIZE],
+    len: HEADER_SIZE,
+};
+static DUMPING: AtomicBool = AtomicBool::new(false);
+
+enum Target {
+    Serial,
+    Partition(Arc<Partition>),
+}
+
+// the header and then the report, what doesn't fit is cut off
+struct Dump {
+    buffer: [u8; DUMP_SIZE],
+    len: usize,
+}
+
+impl Dump {
+    fn report(&self) -> &[u8] {
+        &self.buffer[HEADER_SIZE..self.len]
+    }
+
+    fn finish(&mut self) {
+        let len = (self.len - HEADER_SIZE) as u32;
+        let crc = crc32(self.report());
+
+        self.buffer[..8].copy_from_slice(MAGIC);
+        self.buffer[8..12].copy_from_slice(&len.to_le_bytes());
+        self.buffer[12..16].copy_from_slice(&crc.to_le_bytes());
+    }
+}
+
+impl Write for Dump {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let cnt = s.len().min(DUMP_SIZE - self.len);
+        self.buffer[self.len..self.len + cnt].copy_from_slice(&s.as_bytes()[..cnt]);
+        self.len += cnt;
+        Ok(())
+    }
+}
+
+// the report behind the header, if it's one of ours and intact
+fn parse(dump: &[u8]) -> Option<&[u8]> {
+    if dump.len() < HEADER_SIZE || &dump[..8] != MAGIC {
+        return None;
+    }
+
+    let len = u32::from_le_bytes([dump[8], dump[9], dump[10], dump[11]]) as usize;
+    let crc = u32::from_le_bytes([dump[12], dump[13], dump[14], dump[15]]);
+    let report = dump.get(HEADER_SIZE..HEADER_SIZE + len)?;
+
+    (crc32(report) == crc).then(|| report)
+}
+
+// after the partitions were scanned
+pub fn init() {
+    let target = match cmdline::option("crashdump") {
+        None => return,
+        Some("") | Some("serial") => Target::Serial,
+        Some(name) => match partitions::find(name) {
+            Some(partition) if partition.size() >= DUMP_SIZE as u64 => {
+                Target::Partition(partition)
+            }
+            Some(_) => {
+                log::warning!("[CRASHDUMP] {} is smaller than {} bytes\n", name, DUMP_SIZE);
+                return;
+            }
+            None => {
+                log::warning!("[CRASHDUMP] No partition named {}\n", name);
+                return;
+            }
+        },
+    };
+
+    match &target {
+        Target::Serial => log::info!("[CRASHDUMP] Dumping to serial on panic\n"),
+        Target::Partition(partition) => {
+            log::info!("[CRASHDUMP] Dumping to {} on panic\n", partition.name);
+            check_previous(partition);
+        }
+    }
+
+    unsafe { TARGET = Some(target) };
+}
+
+// says so if the last boot left a dump, it stays there until the next one replaces it
+fn check_previous(partition: &Partition) {
+    let mut dump = alloc::vec![0u8; DUMP_SIZE];
+    let offset = partition.start_lba * SECTOR_SIZE as u64;
+    if partition.device.read(offset, dump.len(), dump.as_mut_ptr()).is_err() {
+        return;
+    }
+
+    if let Some(report) = parse(&dump) {
+        log::warning!(
+            "[CRASHDUMP] {} holds a dump of {} bytes from an earlier boot\n",
+            partition.name,
+            report.len()
+        );
+    }
+}
+
+pub fn set_context(context: &InterruptContext) {
+    unsafe { CONTEXT = Some(*context) };
+}
+
+fn write_registers(dump: &mut Dump) {
+    if let Some(c) = unsafe { CONTEXT } {
+        writeln!(dump, "rip {:#018x} rsp {:#018x} rflags {:#x}", c.rip, c.rsp, c.rflags).ok();
+        writeln!(dump, "rax {:#018x} rbx {:#018x} rcx {:#018x}", c.rax, c.rbx, c.rcx).ok();
+        writeln!(dump, "rdx {:#018x} rsi {:#018x} rdi {:#018x}", c.rdx, c.rsi, c.rdi).ok();
+        writeln!(dump, "rbp {:#018x} r8  {:#018x} r9  {:#018x}", c.rbp, c.r8, c.r9).ok();
+        writeln!(dump, "r10 {:#018x} r11 {:#018x} r12 {:#018x}", c.r10, c.r11, c.r12).ok();
+        writeln!(dump, "r13 {:#018x} r14 {:#018x} r15 {:#018x}", c.r13, c.r14, c.r15).ok();
+        writeln!(dump, "cs {:#x} ss {:#x}", c.cs, c.ss).ok();
+    } else {
+        // the panic handler's own, the backtrace says how it got there
+        let (rsp, rbp, rflags): (u64, u64, u64);
+        unsafe {
+            asm!("mov {}, rsp", out(reg) rsp);
+            asm!("mov {}, rbp", out(reg) rbp);
+            asm!("pushfq; pop {}", out(reg) rflags);
+        }
+        writeln!(dump, "rsp {:#018x} rbp {:#018x} rflags {:#x}", rsp, rbp, rflags).ok();
+    }
+
+    let (cr0, cr2, cr3, cr4): (u64, u64, u64, u64);
+    unsafe {
+        asm!("mov {}, cr0", out(reg) cr0);
+        asm!("mov {}, cr2", out(reg) cr2);
+        asm!("mov {}, cr3", out(reg) cr3);
+        asm!("mov {}, cr4", out(reg) cr4);
+    }
+    writeln!(dump, "cr0 {:#x} cr2 {:#x} cr3 {:#x} cr4 {:#x}", cr0, cr2, cr3, cr4).ok();
+}
+
+fn build(dump: &mut Dump, info: &PanicInfo) {
+    writeln!(dump, "griffin crash dump v{}", VERSION).ok();
+
+    writeln!(dump, "[panic]").ok();
+    if let Some(location) = info.location() {
+        writeln!(dump, "at {}:{}", location.file(), location.line()).ok();
+    }
+    if let Some(message) = info.message() {
+        writeln!(dump, "{}", message).ok();
+    }
+
+    writeln!(dump, "[registers]").ok();
+    write_registers(dump);
+
+    writeln!(dump, "[backtrace]").ok();
+    ksym::backtrace(|address| {
+        writeln!(dump, "{}", ksym::Symbolized(address)).ok();
+    });
+
+    writeln!(dump, "[memory]").ok();
+    writeln!(dump, "total_pages {}", pmm::get().total_pages()).ok();
+    writeln!(dump, "free_pages {}", pmm::get().free_pages()).ok();
+
+    // last, it's the biggest and the rest matters more
+    writeln!(dump, "[log]").ok();
+    match log::try_kmsg() {
+        Some(kmsg) => kmsg.for_each(|record| record.write_dmesg(dump).is_ok()),
+        None => {
+            writeln!(dump, "(the log was locked)").ok();
+        }
+    }
+}
+
+fn to_serial(report: &[u8]) {
+    let mut line = [0u8; LINE_LENGTH];
+    let mut len = 0;
+    // base64 is all ascii
+    let print_line = |line: &[u8]| {
+        serial::print!("{}\n", core::str::from_utf8(line).unwrap_or(""));
+    };
+
+    serial::print!("=== CRASH DUMP BEGIN ({} bytes) ===\n", report.len());
+    base64::encode(report, |c| {
+        line[len] = c;
+        len += 1;
+
+        if len == LINE_LENGTH {
+            print_line(&line);
+            len = 0;
+        }
+    });
+    if len > 0 {
+        print_line(&line[..len]);
+    }
+    serial::print!("=== CRASH DUMP END ===\n");
+}
+
+// from the panic handler, only the first panic gets dumped
+pub fn dump(info: &PanicInfo) {
+    let target = match unsafe { TARGET.as_ref() } {
+        Some(target) => target,
+        None => return,
+    };
+    if DUMPING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let dump = unsafe { &mut DUMP };
+    build(dump, info);
+    dump.finish();
+
+    match target {
+        Target::Serial => to_serial(dump.report()),
+        Target::Partition(partition) => {
+            // whole sectors, the rest of the buffer is zeroed
+            let len = (dump.len + SECTOR_SIZE - 1) / SECTOR_SIZE * SECTOR_SIZE;
+            let offset = partition.start_lba * SECTOR_SIZE as u64;
+
+            // straight to the disk, the request queue could be what's stuck
+            let device = partition.device.unqueued().unwrap_or(&*partition.device);
+            match device.write(offset, len, dump.buffer.as_ptr()) {
+                Ok(_) => serial::print!("crash dump written to {}\n", partition.name),
+                Err(err) => serial::print!("could not write the crash dump: {}\n", err),
+            }
+        }
+    }
+}
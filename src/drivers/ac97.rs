@@ -0,0 +1,238 @@
+/*
+    Intel's ac'97 controller (the ich one qemu has as -device AC97). It has two i/o bars:
+    the mixer of the codec behind it, and the bus master that moves samples to it.
+
+    The pcm out channel plays from a ring of 32 buffer descriptors, each pointing at a
+    chunk of 16 bit stereo frames. The controller plays from the current index (civ) up to
+    the last valid one (lvi) and halts there, writing a new lvi makes it go on. Nothing is
+    waited on with interrupts, a writer sleeps until there's room
+*/
+
+use super::audio::{self, Frame};
+use super::timer_source;
+use crate::arch::io::{inb, inl, inw, outb, outl, outw};
+use crate::arch::pci::PciDevice;
+use crate::error::{KError, KResult};
+use crate::log;
+use crate::mm::dma::{DmaBuffer, DmaConstraints};
+use alloc::boxed::Box;
+use core::mem::size_of;
+
+pub const VENDOR_ID: u16 = 0x8086;
+pub const DEVICE_ID: u16 = 0x2415;
+
+// mixer registers, in the first bar
+const RESET: u16 = 0x00;
+const MASTER_VOLUME: u16 = 0x02;
+const PCM_OUT_VOLUME: u16 = 0x18;
+const EXT_AUDIO_ID: u16 = 0x28;
+const EXT_AUDIO_CTRL: u16 = 0x2a;
+const FRONT_DAC_RATE: u16 = 0x2c;
+
+const VARIABLE_RATE: u16 = 1 << 0; // in EXT_AUDIO_ID and EXT_AUDIO_CTRL
+const UNITY_GAIN: u16 = 0x0808; // pcm out, 0 is loudest for the master
+
+// bus master registers, in the second bar
+const PO_BDBAR: u16 = 0x10;
+const PO_CIV: u16 = 0x14;
+const PO_LVI: u16 = 0x15;
+const PO_SR: u16 = 0x16;
+const PO_CR: u16 = 0x1b;
+const GLOB_CNT: u16 = 0x2c;
+const GLOB_STA: u16 = 0x30;
+
+const HALTED: u16 = 1 << 0; // in PO_SR
+const RUN: u8 = 1 << 0; // in PO_CR
+const RESET_REGISTERS: u8 = 1 << 1;
+const COLD_RESET_DONE: u32 = 1 << 1; // in GLOB_CNT, the reset is active while it's clear
+const CODEC_READY: u32 = 1 << 8; // in GLOB_STA
+
+const DEFAULT_RATE: u32 = 48000; // what every codec plays
+const DESCRIPTORS: usize = 32;
+const CHUNK_FRAMES: usize = 1024;
+const TIMEOUT_MS: u64 = 100;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    address: u32,
+    samples: u16, // not frames, a stereo frame is 2
+    flags: u16,
+}
+
+struct Ac97 {
+    mixer: u16,
+    bus_master: u16,
+    variable_rate: bool,
+    descriptors: DmaBuffer,
+    chunks: DmaBuffer,
+    tail: usize, // the descriptor filled next
+}
+
+fn wait_for(done: impl Fn() -> bool) -> KResult<()> {
+    let deadline = timer_source::current_ms() + TIMEOUT_MS;
+
+    while !done() {
+        if timer_source::current_ms() > deadline {
+            return Err(KError::ETIMEDOUT);
+        }
+        core::hint::spin_loop();
+    }
+
+    Ok(())
+}
+
+impl Ac97 {
+    fn mixer_read(&self, reg: u16) -> u16 {
+        unsafe { inw(self.mixer + reg) }
+    }
+
+    fn mixer_write(&self, reg: u16, value: u16) {
+        unsafe { outw(self.mixer + reg, value) }
+    }
+
+    fn running(&self) -> bool {
+        unsafe { inb(self.bus_master + PO_CR) & RUN != 0 }
+    }
+
+    // how many descriptors the controller hasn't finished, the current one included
+    fn queued(&self) -> usize {
+        if unsafe { inw(self.bus_master + PO_SR) } & HALTED != 0 {
+            return 0;
+        }
+
+        let current = unsafe { inb(self.bus_master + PO_CIV) } as usize;
+        (self.tail + DESCRIPTORS - current) % DESCRIPTORS
+    }
+
+    fn setup(&mut self) -> KResult<()> {
+        unsafe {
+            outl(self.bus_master + GLOB_CNT, COLD_RESET_DONE);
+        }
+        wait_for(|| unsafe { inl(self.bus_master + GLOB_STA) } & CODEC_READY != 0)?;
+
+        // any write resets the mixer to its defaults, which are muted
+        self.mixer_write(RESET, 0);
+        self.mixer_write(MASTER_VOLUME, 0);
+        self.mixer_write(PCM_OUT_VOLUME, UNITY_GAIN);
+
+        if self.mixer_read(EXT_AUDIO_ID) & VARIABLE_RATE != 0 {
+            let ctrl = self.mixer_read(EXT_AUDIO_CTRL);
+            self.mixer_write(EXT_AUDIO_CTRL, ctrl | VARIABLE_RATE);
+            self.variable_rate = true;
+        }
+
+        let bus_master = self.bus_master;
+        unsafe {
+            outb(bus_master + PO_CR, RESET_REGISTERS);
+        }
+        wait_for(|| unsafe { inb(bus_master + PO_CR) } & RESET_REGISTERS == 0)?;
+
+        unsafe {
+            outl(bus_master + PO_BDBAR, self.descriptors.phys() as u32);
+        }
+        Ok(())
+    }
+}
+
+impl audio::Output for Ac97 {
+    fn name(&self) -> &str {
+        "ac97"
+    }
+
+    fn set_rate(&mut self, rate: u32) -> KResult<()> {
+        if rate == DEFAULT_RATE {
+            return Ok(());
+        }
+        if !self.variable_rate || rate > u16::MAX as u32 {
+            return Err(KError::EINVAL);
+        }
+
+        // the codec rounds to what it can do
+        self.mixer_write(FRONT_DAC_RATE, rate as u16);
+        if self.mixer_read(FRONT_DAC_RATE) as u32 != rate {
+            self.mixer_write(FRONT_DAC_RATE, DEFAULT_RATE as u16);
+            return Err(KError::EINVAL);
+        }
+
+        Ok(())
+    }
+
+    fn queue(&mut self, frames: &[Frame]) -> usize {
+        // one is kept free, a full ring would look like an empty one
+        let free = DESCRIPTORS - 1 - self.queued();
+        let descriptors = self.descriptors.as_mut_ptr::<Descriptor>();
+        let mut queued = 0;
+
+        for chunk in frames.chunks(CHUNK_FRAMES).take(free) {
+            let offset = self.tail * CHUNK_FRAMES * size_of::<Frame>();
+
+            unsafe {
+                let data = self.chunks.as_mut_ptr::<u8>().add(offset) as *mut Frame;
+                core::ptr::copy_nonoverlapping(chunk.as_ptr(), data, chunk.len());
+
+                descriptors.add(self.tail).write_volatile(Descriptor {
+                    address: (self.chunks.phys() + offset as u64) as u32,
+                    samples: (chunk.len() * 2) as u16,
+                    flags: 0,
+                });
+            }
+
+            self.tail = (self.tail + 1) % DESCRIPTORS;
+            queued += chunk.len();
+        }
+
+        if queued == 0 {
+            return 0;
+        }
+
+        // a halted controller goes on from here
+        let last = (self.tail + DESCRIPTORS - 1) % DESCRIPTORS;
+        unsafe {
+            outb(self.bus_master + PO_LVI, last as u8);
+            if !self.running() {
+                outb(self.bus_master + PO_CR, RUN);
+            }
+        }
+
+        queued
+    }
+}
+
+fn create(dev: &PciDevice) -> KResult<Ac97> {
+    let mut ac97 = Ac97 {
+        mixer: dev.get_bar(0).as_u64() as u16,
+        bus_master: dev.get_bar(1).as_u64() as u16,
+        variable_rate: false,
+        descriptors: DmaBuffer::new(
+            DESCRIPTORS * size_of::<Descriptor>(),
+            DmaConstraints::BELOW_4G,
+        )?,
+        chunks: DmaBuffer::new(
+            DESCRIPTORS * CHUNK_FRAMES * size_of::<Frame>(),
+            DmaConstraints::BELOW_4G,
+        )?,
+        tail: 0,
+    };
+
+    ac97.setup()?;
+    Ok(ac97)
+}
+
+pub fn init(dev: &PciDevice) {
+    dev.enable_io();
+    dev.bus_master();
+
+    match create(dev) {
+        Ok(ac97) => {
+            log::info!(
+                "[AC97] Mixer at {:#x}, bus master at {:#x}{}\n",
+                ac97.mixer,
+                ac97.bus_master,
+                if ac97.variable_rate { ", variable rate" } else { "" }
+            );
+            audio::register(Box::new(ac97));
+        }
+        Err(err) => log::error!("[AC97] Could not set up the controller: {}\n", err),
+    }
+}
@@ -1,11 +1,16 @@
 use core::intrinsics::size_of;
 
-use crate::arch::mm::pmm::{self, PhysAddr, PmmBox};
-use crate::arch::{interrupts, io::Mmio, pci};
-use crate::mm::vmm::{self, PageFlags, VirtAddr};
-use crate::serial;
+use crate::arch::mm::pmm;
+use super::{block, timer_source};
+use crate::arch::{apic, interrupts, io::Mmio, pci};
+use crate::mm::dma::{DmaBuffer, DmaConstraints};
+use crate::mm::vmm::{self, CacheMode};
+use crate::error::{KError, KResult};
+use crate::proc::wait::WaitQueue;
+use crate::{log, trace};
 use crate::utils::math::div_ceil;
-use alloc::vec::Vec;
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 const SATA_ATA: u32 = 0x101;
 const FIS_TYPE_REG_H2D: u8 = 0x27;
@@ -14,7 +19,42 @@ const ATA_READ_DMA: u8 = 0x25;
 const ATA_WRITE_DMA: u8 = 0x35;
 const ATA_IDENTIFY: u8 = 0xec;
 
+// port interrupt status bits
+const PORT_IS_DHRS: u32 = 1 << 0; // a device to host register fis, sent when a command ends
+const PORT_IS_PCS: u32 = 1 << 6; // port connect change
+const PORT_IS_PRCS: u32 = 1 << 22; // phy ready change
+const PORT_IS_IFS: u32 = 1 << 27;
+const PORT_IS_HBDS: u32 = 1 << 28;
+const PORT_IS_HBFS: u32 = 1 << 29;
+const PORT_IS_TFES: u32 = 1 << 30;
+const PORT_IS_FATAL: u32 = PORT_IS_IFS | PORT_IS_HBDS | PORT_IS_HBFS | PORT_IS_TFES;
+const PORT_IS_CONNECTION: u32 = PORT_IS_PCS | PORT_IS_PRCS;
+
+// port command bits
+const PORT_CMD_ST: u32 = 1 << 0;
+const PORT_CMD_FRE: u32 = 1 << 4;
+const PORT_CMD_FR: u32 = 1 << 14;
+const PORT_CMD_CR: u32 = 1 << 15;
+
+const SSTS_DET_MASK: u32 = 0xf;
+const SSTS_DET_PRESENT: u32 = 3; // device detected and phy communication established
+const SCTL_DET_COMRESET: u32 = 1;
+
+const TFD_BSY: u32 = 1 << 7;
+const TFD_DRQ: u32 = 1 << 3;
+
+const COMMAND_TIMEOUT_MS: u64 = 5000;
+const RESET_TIMEOUT_MS: u64 = 1000;
+const COMMAND_RETRIES: usize = 3;
+// read-ahead commands don't wait for their completion, so they get a slot no one else takes
+const READAHEAD_SLOT: u8 = 31;
+
 static mut AHCI_DEVICES: Vec<AhciDevice> = alloc::vec![];
+static mut HBA: Option<&'static ControllerRegisters> = None;
+// notified by the interrupt handler whenever any port finishes a command
+static COMPLETION: WaitQueue = WaitQueue::new();
+// set once the controller's msi is hooked up, until then commands are polled
+static INTERRUPTS: AtomicBool = AtomicBool::new(false);
 
 #[repr(C, packed)]
 struct FisRegH2D {
@@ -156,7 +196,7 @@ impl PortRegisters {
     }
 
     fn get_slot(&self) -> Option<u8> {
-        for i in 0..32 {
+        for i in 0..READAHEAD_SLOT {
             if ((self.sact.get() | self.ci.get()) & (1 << i)) == 0 {
                 return Some(i);
             }
@@ -165,6 +205,61 @@ impl PortRegisters {
         None
     }
 
+    fn device_present(&self) -> bool {
+        self.ssts.get() & SSTS_DET_MASK == SSTS_DET_PRESENT
+    }
+
+    // stops the command list and fis receive engines
+    fn stop(&self) -> KResult<()> {
+        self.cmd.set(self.cmd.get() & !PORT_CMD_ST);
+        if !wait_until(RESET_TIMEOUT_MS, || self.cmd.get() & PORT_CMD_CR == 0) {
+            return Err(KError::ETIMEDOUT);
+        }
+
+        self.cmd.set(self.cmd.get() & !PORT_CMD_FRE);
+        if !wait_until(RESET_TIMEOUT_MS, || self.cmd.get() & PORT_CMD_FR == 0) {
+            return Err(KError::ETIMEDOUT);
+        }
+
+        Ok(())
+    }
+
+    fn start(&self) -> KResult<()> {
+        if !wait_until(RESET_TIMEOUT_MS, || self.tfd.get() & (TFD_BSY | TFD_DRQ) == 0) {
+            return Err(KError::ETIMEDOUT);
+        }
+
+        self.cmd.set(self.cmd.get() | PORT_CMD_FRE);
+        self.cmd.set(self.cmd.get() | PORT_CMD_ST);
+        Ok(())
+    }
+
+    /*
+        COMRESET, for when a command failed in a way that left the port in an error state
+        or never completed. Every command that was in flight is lost
+    */
+    fn reset(&self) -> KResult<()> {
+        // if the engines don't stop, the reset below is the only way out anyway
+        if let Err(err) = self.stop() {
+            log::warning!("[AHCI] Could not stop the port before resetting it: {}\n", err);
+        }
+
+        self.sctl.set((self.sctl.get() & !SSTS_DET_MASK) | SCTL_DET_COMRESET);
+        // the reset has to be held for at least 1ms
+        timer_source::sleep(1);
+        self.sctl.set(self.sctl.get() & !SSTS_DET_MASK);
+
+        if !wait_until(RESET_TIMEOUT_MS, || self.device_present()) {
+            return Err(KError::ENODEV);
+        }
+
+        // both are write 1 to clear
+        self.serr.set(u32::MAX);
+        self.interrupt_status.set(u32::MAX);
+
+        self.start()
+    }
+
     // TODO: zero structs
     // if it succeeds, it will return the number of bytes read/written
     // max number of bytes that can be read/written with one command is 4MB (only 1 prdt is used)
@@ -172,96 +267,346 @@ impl PortRegisters {
         &self,
         lba: u64,
         sectors: u16,
-        buffer: *mut u8,
+        buffer: &DmaBuffer,
+        write: bool,
+    ) -> KResult<usize> {
+        let command = if write { ATA_WRITE_DMA } else { ATA_READ_DMA };
+        trace::trace!(ahci_command, lba, sectors as u64 | (write as u64) << 16);
+        self.issue(command, lba, sectors, buffer, write)
+    }
+
+    // failed commands are retried after resetting the port
+    fn issue(
+        &self,
+        command: u8,
+        lba: u64,
+        sectors: u16,
+        buffer: &DmaBuffer,
         write: bool,
-    ) -> Result<usize, ()> {
-        let slot = self
-            .get_slot()
-            .expect("Could not get a slot fot the AHCI command");
+    ) -> KResult<usize> {
+        for attempt in 1..=COMMAND_RETRIES {
+            let result = self.try_issue(command, lba, sectors, buffer, write);
+
+            match result {
+                Ok(_) | Err(KError::ENODEV) | Err(KError::EBUSY) => return result,
+                Err(err) => log::warning!(
+                    "[AHCI] Command {:#x} failed: {} (LBA: {}, sectors: {}, attempt {})\n",
+                    command,
+                    err,
+                    lba,
+                    sectors,
+                    attempt
+                ),
+            }
+
+            if let Err(err) = self.reset() {
+                log::error!("[AHCI] Could not reset the port: {}\n", err);
+                return Err(err);
+            }
+        }
+
+        // the last try, whatever happens here is final
+        self.try_issue(command, lba, sectors, buffer, write)
+    }
 
+    // fills in the command header, table and fis of slot, without issuing it
+    fn prepare(
+        &self,
+        slot: u8,
+        command: u8,
+        lba: u64,
+        sectors: u16,
+        buffer: &DmaBuffer,
+        write: bool,
+    ) -> &mut CommandHeader {
         let cmd_header = self.get_command_header(slot);
         cmd_header.cfl_awp.set((size_of::<FisRegH2D>() / 4) as u8);
         if write {
             cmd_header.cfl_awp.set(cmd_header.cfl_awp.get() | 1 << 6);
         }
         cmd_header.prdtl.set(1);
+        cmd_header.prdbc.set(0);
 
         let cmd_table = cmd_header.get_command_table();
 
-        let buffer_addr = buffer as u64 & !pmm::PHYS_BASE;
-        cmd_table.prdt_entries[0].set_buffer(buffer_addr, sectors);
+        cmd_table.prdt_entries[0].set_buffer(buffer.phys(), sectors);
 
         let fis = unsafe { &mut *(cmd_table.cmd_fis.as_mut_ptr() as *mut FisRegH2D) };
         fis.fis_type.set(FIS_TYPE_REG_H2D);
         fis.mul_cmd.set(1 << 7); // specifies that it is a command
-        fis.command
-            .set(if write { ATA_WRITE_DMA } else { ATA_READ_DMA });
+        fis.command.set(command);
 
         fis.set_lba(lba); // this will also set the lba addressing
         fis.set_count(sectors as u16);
 
+        cmd_header
+    }
+
+    // issues a read in the read-ahead slot and returns right away
+    fn start_readahead(&self, lba: u64, sectors: u16, buffer: &DmaBuffer) -> KResult<()> {
+        if sectors as usize * 512 > buffer.len() {
+            return Err(KError::EINVAL);
+        }
+
+        if self.ci.get() & (1 << READAHEAD_SLOT) != 0 {
+            return Err(KError::EBUSY);
+        }
+
+        trace::trace!(ahci_command, lba, sectors);
+        self.prepare(READAHEAD_SLOT, ATA_READ_DMA, lba, sectors, buffer, false);
+        self.ci.set(1 << READAHEAD_SLOT);
+        Ok(())
+    }
+
+    fn try_issue(
+        &self,
+        command: u8,
+        lba: u64,
+        sectors: u16,
+        buffer: &DmaBuffer,
+        write: bool,
+    ) -> KResult<usize> {
+        if sectors as usize * 512 > buffer.len() {
+            return Err(KError::EINVAL);
+        }
+
+        if !self.device_present() {
+            return Err(KError::ENODEV);
+        }
+
+        let slot = self.get_slot().ok_or(KError::EBUSY)?;
+        let cmd_header = self.prepare(slot, command, lba, sectors, buffer, write);
+
+        // errors left over from an earlier command would be blamed on this one
+        self.interrupt_status.set(PORT_IS_FATAL);
         self.ci.set(1 << slot);
 
-        while self.ci.get() & (1 << slot) != 0 {
-            if self.interrupt_status.get() & (1 << 30) != 0 {
-                serial::print!("[AHCI] error while executing a command\n");
-                serial::print!("1\n");
-                serial::print!("LBA: {}, sectors: {}, buffer: {:?}\n", lba, sectors, buffer);
-                return Err(());
-            }
+        // a task file error still ends with a register fis, so it wakes us up too
+        let completed = io_request(COMMAND_TIMEOUT_MS).wait(|| {
+            self.ci.get() & (1 << slot) == 0 || self.interrupt_status.get() & PORT_IS_FATAL != 0
+        });
+
+        if self.interrupt_status.get() & PORT_IS_FATAL != 0 {
+            return Err(KError::EIO);
         }
 
-        if self.interrupt_status.get() & (1 << 30) != 0 {
-            serial::print!("[AHCI] error while executing a command\n");
-            serial::print!("2\n");
-            serial::print!("LBA: {}, sectors: {}, buffer: {:?}\n", lba, sectors, buffer);
-            return Err(());
+        if !completed {
+            return Err(KError::ETIMEDOUT);
         }
 
-        serial::print!("bytes read: {}\n", cmd_header.prdbc.get());
+        log::debug!("[AHCI] bytes read: {}\n", cmd_header.prdbc.get());
         Ok(cmd_header.prdbc.get() as usize)
     }
 }
 
+// sleeps until the interrupt handler reports a completion, or polls without one
+fn io_request(timeout_ms: u64) -> block::IoRequest<'static> {
+    let completion = INTERRUPTS.load(Ordering::SeqCst).then(|| &COMPLETION);
+    block::IoRequest::new(completion, timeout_ms)
+}
+
+// polls condition until it's true, false if it wasn't before the timeout
+fn wait_until(timeout_ms: u64, condition: impl Fn() -> bool) -> bool {
+    let deadline = timer_source::current_ms() + timeout_ms;
+
+    while !condition() {
+        if timer_source::current_ms() >= deadline {
+            return false;
+        }
+
+        core::hint::spin_loop();
+    }
+
+    true
+}
+
+// a read started before anyone asked for it, the buffer is kept until the next one
+struct ReadAhead {
+    offset: u64, // in bytes, sector aligned
+    bytes: usize,
+    buffer: DmaBuffer,
+    done: bool,
+}
+
+impl ReadAhead {
+    fn covers(&self, offset: u64, bytes: usize) -> bool {
+        offset >= self.offset && offset + bytes as u64 <= self.offset + self.bytes as u64
+    }
+}
+
 struct AhciDevice {
     pub regs: &'static mut PortRegisters,
+    pub sectors: AtomicU64,
+    pub present: AtomicBool,
+    // what the controller can address, every buffer it's given must fit in it
+    dma: DmaConstraints,
+    _command_tables: Vec<DmaBuffer>,
+    readahead: spin::Mutex<Option<ReadAhead>>,
+}
+
+// the view of an ahci disk that the rest of the kernel gets
+pub struct AhciBlockDevice {
+    index: usize,
+    name: String,
+}
+
+impl block::BlockDevice for AhciBlockDevice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn size(&self) -> u64 {
+        unsafe { AHCI_DEVICES[self.index].sectors.load(Ordering::SeqCst) * 512 }
+    }
+
+    fn read(&self, offset: u64, bytes: usize, buffer: *mut u8) -> KResult<usize> {
+        read(self.index, offset, bytes, buffer)
+    }
+
+    fn write(&self, offset: u64, bytes: usize, buffer: *const u8) -> KResult<usize> {
+        write(self.index, offset, bytes, buffer)
+    }
+
+    fn prefetch(&self, offset: u64, bytes: usize) {
+        prefetch(self.index, offset, bytes)
+    }
 }
 
 impl AhciDevice {
     // we use the clb and fb provided by the firmware
-    unsafe fn new(regs: &'static mut PortRegisters) -> Self {
+    unsafe fn new(regs: &'static mut PortRegisters, dma: DmaConstraints) -> Self {
         /*
             get an interrupt once we receive a device to host FIS,
             which should indicate that a transfer has been completed
         */
         regs.interrupt_enable.set(regs.interrupt_enable.get() | 1);
 
+        let mut command_tables = Vec::new();
         for i in 0..32 {
             let cmd_header = regs.get_command_header(i);
 
-            let cmd_table_pages = div_ceil(size_of::<CommandTable>(), pmm::PAGE_SIZE as usize);
-            let cmd_table = pmm::get()
-                .calloc(cmd_table_pages)
-                .expect("Could not allocate the pages needed for the command list (AHCI)")
-                .as_u64();
-
-            for i in (0..cmd_table_pages * pmm::PAGE_SIZE as usize).step_by(pmm::PAGE_SIZE as usize)
-            {
-                vmm::get().map_page(
-                    VirtAddr::new(cmd_table + pmm::PHYS_BASE + i as u64),
-                    PhysAddr::new(cmd_table + i as u64),
-                    PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::UNCACHEABLE,
-                    true,
-                );
-            }
+            // command tables have to be 128 bytes aligned
+            let cmd_table = DmaBuffer::new(size_of::<CommandTable>(), dma.aligned(128))
+                .expect("Could not allocate the pages needed for the command list (AHCI)");
 
-            cmd_header.ctaddr_lower.set(cmd_table as u32);
-            cmd_header.ctaddr_upper.set((cmd_table >> 32) as u32);
+            cmd_header.ctaddr_lower.set(cmd_table.phys() as u32);
+            cmd_header.ctaddr_upper.set((cmd_table.phys() >> 32) as u32);
+            command_tables.push(cmd_table);
         }
 
-        let device = AhciDevice { regs };
+        let present = regs.device_present();
+        let device = AhciDevice {
+            regs,
+            sectors: AtomicU64::new(0),
+            present: AtomicBool::new(present),
+            dma,
+            _command_tables: command_tables,
+            readahead: spin::Mutex::new(None),
+        };
+
+        device.sectors.store(device.identify().unwrap_or(0), Ordering::SeqCst);
         device
     }
+
+    /*
+        Until the controller's interrupt is hooked up, a device being plugged in or removed
+        is noticed on the next access to the port. Returns whether there's a usable device
+    */
+    fn check_connection(&self) -> bool {
+        let status = self.regs.interrupt_status.get() & PORT_IS_CONNECTION;
+
+        if status != 0 {
+            // the connect change bit only clears along with the diagnostic bits in serr
+            self.regs.serr.set(u32::MAX);
+            self.regs.interrupt_status.set(status);
+
+            if self.regs.device_present() {
+                log::info!("[AHCI] A device was connected\n");
+
+                let sectors = match self.regs.reset() {
+                    Ok(()) => self.identify().unwrap_or(0),
+                    Err(err) => {
+                        log::error!("[AHCI] Could not bring up the new device: {}\n", err);
+                        0
+                    }
+                };
+
+                self.sectors.store(sectors, Ordering::SeqCst);
+                self.present.store(sectors != 0, Ordering::SeqCst);
+            } else {
+                log::info!("[AHCI] A device was removed\n");
+                self.present.store(false, Ordering::SeqCst);
+            }
+        }
+
+        self.present.load(Ordering::SeqCst)
+    }
+
+    /*
+        Waits for the read-ahead to land, if it's still in flight. A failed or lost one
+        (a reset stops every command) is thrown away, returns whether there's data to use
+    */
+    fn wait_readahead(&self, readahead: &mut Option<ReadAhead>) -> bool {
+        let pending = match readahead.as_mut() {
+            Some(pending) => pending,
+            None => return false,
+        };
+
+        if !pending.done {
+            let slot = 1 << READAHEAD_SLOT;
+            let completed = io_request(COMMAND_TIMEOUT_MS).wait(|| self.regs.ci.get() & slot == 0);
+
+            // the controller could still write to the buffer later
+            if !completed {
+                log::warning!("[AHCI] A read-ahead timed out, resetting the port\n");
+                if let Err(err) = self.regs.reset() {
+                    log::error!("[AHCI] Could not reset the port: {}\n", err);
+                }
+            }
+
+            let transferred = self.regs.get_command_header(READAHEAD_SLOT).prdbc.get();
+            if !completed || transferred as usize != pending.bytes {
+                *readahead = None;
+                return false;
+            }
+
+            pending.done = true;
+        }
+
+        true
+    }
+
+    // serves a read from the read-ahead buffer if it has all of it
+    fn read_ahead_copy(&self, offset: u64, bytes: usize, buffer: *mut u8) -> bool {
+        let mut readahead = self.readahead.lock();
+
+        if !matches!(readahead.as_ref(), Some(pending) if pending.covers(offset, bytes)) {
+            return false;
+        }
+
+        if !self.wait_readahead(&mut readahead) {
+            return false;
+        }
+
+        let pending = readahead.as_ref().unwrap();
+        unsafe {
+            let start = pending.buffer.as_ptr::<u8>().add((offset - pending.offset) as usize);
+            buffer.copy_from(start, bytes);
+        }
+
+        true
+    }
+
+    // returns the amount of sectors the disk has
+    fn identify(&self) -> Option<u64> {
+        let identify_data = DmaBuffer::new(512, self.dma).ok()?;
+
+        self.regs.issue(ATA_IDENTIFY, 0, 1, &identify_data, false).ok()?;
+
+        // words 100 to 103 hold the number of sectors addressable with lba48
+        let words = unsafe { core::slice::from_raw_parts(identify_data.as_ptr::<u16>(), 256) };
+        Some((100..104).fold(0u64, |sectors, word| sectors | (words[word] as u64) << ((word - 100) * 16)))
+    }
 }
 
 pub fn init(hba: &pci::PciDevice) {
@@ -270,45 +615,64 @@ pub fn init(hba: &pci::PciDevice) {
     hba.bus_master();
     hba.enable_mmio();
 
-    let hba_mem = unsafe { &mut *bar5.higher_half().as_mut_ptr::<ControllerRegisters>() };
-
-    vmm::get().map_page(
-        VirtAddr::new(bar5.higher_half().as_u64()),
+    let registers = vmm::get().map_mmio(
         bar5,
-        PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::UNCACHEABLE,
-        true,
+        size_of::<ControllerRegisters>() as u64,
+        CacheMode::Uncacheable,
     );
+    let hba_mem = unsafe { &mut *(registers as *mut ControllerRegisters) };
 
-    if hba_mem.capabilities.get() & (1 << 31) == 0 {
-        serial::print!("The AHCI controller does not support 64 bits addressing\n");
-        return;
+    let dma = if hba_mem.capabilities.get() & (1 << 31) == 0 {
+        log::info!("[AHCI] The controller only supports 32 bits addressing\n");
+        DmaConstraints::BELOW_4G
+    } else {
+        DmaConstraints::ANY
+    };
+
+    unsafe {
+        HBA = Some(&*(hba_mem as *const ControllerRegisters));
     }
 
-    hba_mem.ghc.set(hba_mem.ghc.get() | 2); // enable interrupts
+    match interrupts::alloc_vector() {
+        Some(vector) if hba.has_msi() => {
+            unsafe {
+                interrupts::register_isr(vector, ahci_isr as u64, 0, 0x8e, "ahci");
+            }
+            hba.set_msi(vector);
 
-    // let vector = interrupts::alloc_vector().expect("[AHCI] Could not allocate an interrupt vector");
-    // unsafe {
-    //     interrupts::register_isr(vector, ahci_isr as u64, 0, 0x8e);
-    // }
-    // hba.set_msi(vector);
+            hba_mem.ghc.set(hba_mem.ghc.get() | 2); // enable interrupts
+            INTERRUPTS.store(true, Ordering::SeqCst);
+        }
+        _ => log::warning!("[AHCI] No interrupt for the controller, commands will be polled\n"),
+    }
 
     for (i, port) in hba_mem.ports.iter_mut().enumerate() {
         if hba_mem.port_implemented.get() & (1 << i) != 0 {
             if port.signature.get() == SATA_ATA {
                 unsafe {
-                    let device = AhciDevice::new(port);
-                    serial::print!("Initialized ahci driver\n");
+                    let device = AhciDevice::new(port, dma);
+                    log::info!(
+                        "[AHCI] Initialized ahci driver, {} sectors\n",
+                        device.sectors.load(Ordering::SeqCst)
+                    );
                     AHCI_DEVICES.push(device);
+
+                    let index = AHCI_DEVICES.len() - 1;
+                    block::register(Arc::new(AhciBlockDevice {
+                        index,
+                        name: alloc::format!("ahci{}", index),
+                    }));
                 }
             }
         }
     }
 }
 
-pub fn read(device_index: usize, offset: u64, bytes: usize, buffer: *mut u8) -> Result<usize, ()> {
+pub fn read(device_index: usize, offset: u64, bytes: usize, buffer: *mut u8) -> KResult<usize> {
     let device = unsafe { &AHCI_DEVICES[device_index] };
-    let tmp_buffer = PmmBox::<u8>::new(bytes);
-    let tmp_buffer_ptr = tmp_buffer.as_mut_ptr();
+    if !device.check_connection() {
+        return Err(KError::ENODEV);
+    }
 
     /*
         bytes + (offset % 512) will make sure than unaligned reads that span more than one sector
@@ -317,21 +681,20 @@ pub fn read(device_index: usize, offset: u64, bytes: usize, buffer: *mut u8) ->
         E.g. a read from offset 510 and with byte count of 4 needs to get the contents of 2 sectors
         in order to retrieve those 4 bytes
     */
-    let sectors = div_ceil(bytes + (offset % 512) as usize, 512) as u16;
+    if device.read_ahead_copy(offset, bytes, buffer) {
+        return Ok(bytes);
+    }
 
-    let access_result = device
-        .regs
-        .send_command(offset / 512, sectors, tmp_buffer_ptr, false);
+    let sectors = div_ceil(bytes + (offset % 512) as usize, 512) as u16;
+    let bounce_buffer = DmaBuffer::new(sectors as usize * 512, device.dma)?;
 
-    if let Ok(bc) = access_result {
-        unsafe {
-            buffer.copy_from(tmp_buffer_ptr.offset((offset % 512) as isize), bytes);
-        }
+    let bc = device.regs.send_command(offset / 512, sectors, &bounce_buffer, false)?;
 
-        Ok(bc)
-    } else {
-        access_result
+    unsafe {
+        buffer.copy_from(bounce_buffer.as_ptr::<u8>().add((offset % 512) as usize), bytes);
     }
+
+    Ok(bc)
 }
 
 pub fn write(
@@ -339,34 +702,93 @@ pub fn write(
     offset: u64,
     bytes: usize,
     buffer: *const u8,
-) -> Result<usize, ()> {
+) -> KResult<usize> {
     let device = unsafe { &AHCI_DEVICES[device_index] };
-    let tmp_buffer = PmmBox::<u8>::new(bytes);
-    let tmp_buffer_ptr = tmp_buffer.as_mut_ptr();
+    if !device.check_connection() {
+        return Err(KError::ENODEV);
+    }
+
+    // the read-ahead buffer would hand out what was there before this write
+    let mut readahead = device.readahead.lock();
+    if let Some(pending) = readahead.as_ref() {
+        let end = pending.offset + pending.bytes as u64;
+        if offset < end && offset + bytes as u64 > pending.offset {
+            device.wait_readahead(&mut readahead);
+            *readahead = None;
+        }
+    }
+    drop(readahead);
 
     let sectors = div_ceil(bytes + (offset % 512) as usize, 512) as u16;
+    let bounce_buffer = DmaBuffer::new(sectors as usize * 512, device.dma)?;
 
-    let mut access_result = device
-        .regs
-        .send_command(offset / 512, sectors, tmp_buffer_ptr, false);
+    // the sectors are only partially overwritten, so the rest of them has to be read first
+    device.regs.send_command(offset / 512, sectors, &bounce_buffer, false)?;
 
-    if let Ok(_) = access_result {
-        unsafe {
-            tmp_buffer_ptr
-                .offset((offset % 512) as isize)
-                .copy_from(buffer, bytes);
-        }
+    unsafe {
+        bounce_buffer
+            .as_mut_ptr::<u8>()
+            .add((offset % 512) as usize)
+            .copy_from(buffer, bytes);
+    }
 
-        access_result = device
-            .regs
-            .send_command(offset / 512, sectors, tmp_buffer_ptr, true);
+    device.regs.send_command(offset / 512, sectors, &bounce_buffer, true)
+}
 
-        access_result
-    } else {
-        access_result
+/*
+    Starts reading the range into the device's read-ahead buffer without waiting for it, a
+    later read that falls inside of it is copied from there. Only one read-ahead is kept
+    per device, starting another drops the previous one
+*/
+pub fn prefetch(device_index: usize, offset: u64, bytes: usize) {
+    let device = unsafe { &AHCI_DEVICES[device_index] };
+    if bytes == 0 || !device.check_connection() {
+        return;
+    }
+
+    let mut readahead = device.readahead.lock();
+    if matches!(readahead.as_ref(), Some(pending) if pending.covers(offset, bytes)) {
+        return;
+    }
+
+    // the old buffer can only be freed once the controller is done with it
+    device.wait_readahead(&mut readahead);
+    *readahead = None;
+
+    let sectors = div_ceil(bytes + (offset % 512) as usize, 512).min(u16::MAX as usize) as u16;
+    let buffer = match DmaBuffer::new(sectors as usize * 512, device.dma) {
+        Ok(buffer) => buffer,
+        Err(_) => return,
+    };
+
+    let lba = offset / 512;
+    if device.regs.start_readahead(lba, sectors, &buffer).is_ok() {
+        *readahead = Some(ReadAhead {
+            offset: lba * 512,
+            bytes: sectors as usize * 512,
+            buffer,
+            done: false,
+        });
     }
 }
 
+/*
+    Only the end of a command is acked here. Errors and connection changes are left in the
+    ports' status for whoever issued the command and check_connection
+*/
 interrupts::isr!(ahci_isr, |_stack| {
-    serial::print!("=== Disk transfer completed ===\n");
+    if let Some(hba) = HBA {
+        let pending = hba.interrupt_status.get();
+
+        for (i, port) in hba.ports.iter().enumerate() {
+            if pending & (1 << i) != 0 {
+                port.interrupt_status.set(PORT_IS_DHRS);
+            }
+        }
+
+        hba.interrupt_status.set(pending);
+    }
+
+    COMPLETION.notify();
+    apic::get().eoi();
 });
@@ -0,0 +1,211 @@
+/*
+    Block i/o that doesn't make the caller wait for it. submit hands the request to the
+    kaiod threads and returns a handle right away. A kaiod thread does the request through
+    the device's queue like anyone else, sleeping on the device's interrupt meanwhile, and
+    then completes it: the callback runs, if there is one, and whoever waits on the handle
+    wakes up. There are a few of them, so a few requests are at the queue at once and it can
+    sort and merge them.
+
+    A request owns its buffer, a read fills it and it comes back with the result. Without the
+    scheduler nothing can run in the background, so requests are done right as they come in
+*/
+
+use super::block::{BlockDevice, Priority};
+use crate::error::KResult;
+use crate::log;
+use crate::proc::process::{Process, SelectorValues, Thread};
+use crate::proc::{scheduler, wait::WaitQueue};
+use alloc::collections::VecDeque;
+use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const KAIOD_THREADS: usize = 4;
+
+static PENDING: spin::Mutex<VecDeque<Pending>> = spin::Mutex::new(VecDeque::new());
+// notified when a request comes in
+static WORK: WaitQueue = WaitQueue::new();
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Op {
+    Read,
+    Write,
+}
+
+pub struct Request {
+    pub device: Arc<dyn BlockDevice>,
+    pub op: Op,
+    pub offset: u64,
+    pub buffer: Vec<u8>, // all of it is read or written
+    pub priority: Priority,
+}
+
+impl Request {
+    pub fn read(
+        device: Arc<dyn BlockDevice>,
+        offset: u64,
+        bytes: usize,
+        priority: Priority,
+    ) -> Self {
+        Request {
+            device,
+            op: Op::Read,
+            offset,
+            buffer: alloc::vec![0u8; bytes],
+            priority,
+        }
+    }
+
+    pub fn write(
+        device: Arc<dyn BlockDevice>,
+        offset: u64,
+        data: Vec<u8>,
+        priority: Priority,
+    ) -> Self {
+        Request {
+            device,
+            op: Op::Write,
+            offset,
+            buffer: data,
+            priority,
+        }
+    }
+
+    fn run(self) -> Completion {
+        let mut buffer = self.buffer;
+        let result = match self.op {
+            Op::Read => {
+                self.device
+                    .read_with(self.offset, buffer.len(), buffer.as_mut_ptr(), self.priority)
+            }
+            Op::Write => {
+                self.device
+                    .write_with(self.offset, buffer.len(), buffer.as_ptr(), self.priority)
+            }
+        };
+
+        Completion { result, buffer }
+    }
+}
+
+pub struct Completion {
+    pub result: KResult<usize>,
+    pub buffer: Vec<u8>,
+}
+
+type Callback = Box<dyn FnOnce(&Completion)>;
+
+struct Shared {
+    done: spin::Mutex<Option<Completion>>,
+    completion: WaitQueue,
+}
+
+struct Pending {
+    request: Request,
+    callback: Option<Callback>,
+    shared: Arc<Shared>,
+}
+
+// the kaiod threads take the requests of everyone
+unsafe impl Send for Pending {}
+
+impl Pending {
+    fn complete(self) {
+        let completion = self.request.run();
+        if let Some(callback) = self.callback {
+            callback(&completion);
+        }
+
+        *self.shared.done.lock() = Some(completion);
+        self.shared.completion.notify();
+    }
+}
+
+pub struct Handle {
+    shared: Arc<Shared>,
+}
+
+impl Handle {
+    pub fn is_done(&self) -> bool {
+        self.shared.done.lock().is_some()
+    }
+
+    // sleeps until the request is done
+    pub fn wait(self) -> Completion {
+        loop {
+            let generation = self.shared.completion.generation();
+            if let Some(completion) = self.shared.done.lock().take() {
+                return completion;
+            }
+
+            self.shared.completion.wait(generation, None);
+        }
+    }
+}
+
+pub fn submit(request: Request) -> Handle {
+    queue(request, None)
+}
+
+// callback runs on the kaiod thread that did the request, before waiters wake up
+pub fn submit_with(request: Request, callback: impl FnOnce(&Completion) + 'static) -> Handle {
+    queue(request, Some(Box::new(callback)))
+}
+
+// submits them all and then waits for each, so they're at the device's queue together
+pub fn run_all(requests: Vec<Request>) -> Vec<Completion> {
+    let handles: Vec<Handle> = requests.into_iter().map(submit).collect();
+    handles.into_iter().map(Handle::wait).collect()
+}
+
+fn queue(request: Request, callback: Option<Callback>) -> Handle {
+    let shared = Arc::new(Shared {
+        done: spin::Mutex::new(None),
+        completion: WaitQueue::new(),
+    });
+    let pending = Pending {
+        request,
+        callback,
+        shared: shared.clone(),
+    };
+
+    if STARTED.load(Ordering::Acquire) {
+        PENDING.lock().push_back(pending);
+        WORK.notify();
+    } else {
+        pending.complete();
+    }
+
+    Handle { shared }
+}
+
+extern "C" fn kaiod() -> ! {
+    loop {
+        let generation = WORK.generation();
+        let pending = PENDING.lock().pop_front();
+
+        match pending {
+            Some(pending) => pending.complete(),
+            None => {
+                WORK.wait(generation, None);
+            }
+        }
+    }
+}
+
+// starts the kaiod threads, until then every request is done by whoever submits it
+pub fn init() {
+    if scheduler::try_get().is_none() {
+        log::debug!("[AIO] No scheduler, requests are done as they're submitted\n");
+        return;
+    }
+
+    let process = Process::new(String::from("kaiod"), 0, String::from("/"));
+    for _ in 0..KAIOD_THREADS {
+        let thread = Thread::new(kaiod as u64, SelectorValues::KernelCs, process.clone());
+        process.borrow_mut().threads.push(thread.clone());
+        scheduler::get().enqueue(thread);
+    }
+
+    STARTED.store(true, Ordering::Release);
+}
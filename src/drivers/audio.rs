@@ -0,0 +1,161 @@
+/*
+    Sound output. Samples come in whatever format was set last, and are converted to what
+    every card plays: 16 bit signed stereo. The card has a ring of buffers that it plays on
+    its own, a writer waits while it's full.
+
+    /dev/dsp takes raw samples in the same format, like oss' does
+*/
+
+use super::timer_source;
+use crate::error::{KError, KResult};
+use crate::fs::devfs;
+use crate::log;
+use crate::proc::wait;
+use alloc::boxed::Box;
+
+// a stereo frame, left then right
+pub type Frame = [i16; 2];
+
+// how long a writer sleeps while the card is busy with what it has
+const POLL_MS: u64 = 10;
+const BATCH_FRAMES: usize = 256;
+const TONE_VOLUME: i16 = 8000;
+
+static OUTPUT: spin::Mutex<Option<Box<dyn Output>>> = spin::Mutex::new(None);
+static FORMAT: spin::Mutex<Format> = spin::Mutex::new(Format::DEFAULT);
+static DSP: Dsp = Dsp;
+
+pub trait Output: Send {
+    fn name(&self) -> &str;
+    fn set_rate(&mut self, rate: u32) -> KResult<()>;
+    // takes as many frames as it has room for, 0 if it's full
+    fn queue(&mut self, frames: &[Frame]) -> usize;
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Format {
+    pub rate: u32, // frames per second
+    pub channels: u8, // 1 or 2
+    pub bits: u8, // 8 (unsigned) or 16 (signed, little endian)
+}
+
+impl Format {
+    pub const DEFAULT: Format = Format {
+        rate: 48000,
+        channels: 2,
+        bits: 16,
+    };
+
+    pub fn frame_size(&self) -> usize {
+        self.channels as usize * self.bits as usize / 8
+    }
+
+    // the frame that starts at bytes, which has to hold frame_size of them
+    pub fn convert(&self, bytes: &[u8]) -> Frame {
+        let sample = |i: usize| match self.bits {
+            8 => (bytes[i] as i16 - 128) << 8,
+            _ => i16::from_le_bytes([bytes[2 * i], bytes[2 * i + 1]]),
+        };
+
+        match self.channels {
+            1 => [sample(0); 2],
+            _ => [sample(0), sample(1)],
+        }
+    }
+}
+
+pub fn register(output: Box<dyn Output>) {
+    log::info!("[AUDIO] Output on {}\n", output.name());
+    *OUTPUT.lock() = Some(output);
+
+    if let Err(err) = set_format(Format::DEFAULT) {
+        log::warning!("[AUDIO] The card doesn't take the default format: {}\n", err);
+    }
+    if let Err(err) = devfs::register("dsp", &DSP) {
+        log::warning!("[AUDIO] Could not register dsp: {}\n", err);
+    }
+}
+
+pub fn format() -> Format {
+    *FORMAT.lock()
+}
+
+pub fn set_format(format: Format) -> KResult<()> {
+    if !matches!(format.channels, 1 | 2) || !matches!(format.bits, 8 | 16) {
+        return Err(KError::EINVAL);
+    }
+
+    OUTPUT.lock().as_mut().ok_or(KError::ENODEV)?.set_rate(format.rate)?;
+    *FORMAT.lock() = format;
+    Ok(())
+}
+
+// waits until every frame was handed to the card
+pub fn write_frames(mut frames: &[Frame]) -> KResult<()> {
+    while !frames.is_empty() {
+        let queued = OUTPUT.lock().as_mut().ok_or(KError::ENODEV)?.queue(frames);
+        frames = &frames[queued..];
+
+        if queued == 0 {
+            // nothing notifies, so this only sleeps
+            wait::wait_any(&[], Some(timer_source::current_ns() + POLL_MS * 1_000_000));
+        }
+    }
+
+    Ok(())
+}
+
+/*
+    Plays data, which is in the current format. Returns how many bytes were taken, a
+    partial frame at the end is left out
+*/
+pub fn write_pcm(data: &[u8]) -> KResult<usize> {
+    let format = format();
+    let frame_size = format.frame_size();
+    let mut batch = [[0; 2]; BATCH_FRAMES];
+
+    for chunk in data.chunks(frame_size * BATCH_FRAMES) {
+        let frames = chunk.len() / frame_size;
+        for (i, frame) in batch.iter_mut().enumerate().take(frames) {
+            *frame = format.convert(&chunk[i * frame_size..]);
+        }
+
+        write_frames(&batch[..frames])?;
+    }
+
+    Ok(data.len() / frame_size * frame_size)
+}
+
+// a square wave, for a beep
+pub fn tone(frequency: u32, ms: u64) -> KResult<()> {
+    let rate = format().rate as u64;
+    let total = rate * ms / 1000;
+    let half_period = (rate / (2 * frequency.max(1) as u64)).max(1);
+    let mut batch = [[0; 2]; BATCH_FRAMES];
+    let mut done = 0;
+
+    while done < total {
+        let frames = (total - done).min(BATCH_FRAMES as u64) as usize;
+        for (i, frame) in batch.iter_mut().enumerate().take(frames) {
+            let high = (done + i as u64) / half_period % 2 == 0;
+            *frame = [if high { TONE_VOLUME } else { -TONE_VOLUME }; 2];
+        }
+
+        write_frames(&batch[..frames])?;
+        done += frames as u64;
+    }
+
+    Ok(())
+}
+
+struct Dsp;
+
+impl devfs::Device for Dsp {
+    fn read(&self, _buffer: *mut u8, _cnt: usize, _offset: usize) -> KResult<usize> {
+        Err(KError::EINVAL)
+    }
+
+    fn write(&self, buffer: *const u8, cnt: usize, _offset: usize) -> KResult<usize> {
+        write_pcm(unsafe { core::slice::from_raw_parts(buffer, cnt) })
+    }
+}
@@ -0,0 +1,122 @@
+use super::{iosched, timer_source};
+use crate::error::KResult;
+use crate::log;
+use crate::proc::wait::WaitQueue;
+use alloc::{sync::Arc, vec::Vec};
+
+static mut BLOCK_DEVICES: Vec<Arc<dyn BlockDevice>> = alloc::vec![];
+
+// what the i/o scheduler serves first, filesystem metadata is what most waiting is on
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Metadata,
+    Data,
+}
+
+// offsets and sizes are in bytes, the device takes care of splitting them into sectors
+pub trait BlockDevice {
+    fn name(&self) -> &str;
+    fn size(&self) -> u64;
+    fn read(&self, offset: u64, bytes: usize, buffer: *mut u8) -> KResult<usize>;
+    fn write(&self, offset: u64, bytes: usize, buffer: *const u8) -> KResult<usize>;
+
+    // a hint that the range is about to be read, devices that can read asynchronously start on it
+    fn prefetch(&self, _offset: u64, _bytes: usize) {}
+
+    // read and write with a priority, only the queue a device is registered behind uses it
+    fn read_with(
+        &self,
+        offset: u64,
+        bytes: usize,
+        buffer: *mut u8,
+        _priority: Priority,
+    ) -> KResult<usize> {
+        self.read(offset, bytes, buffer)
+    }
+
+    fn write_with(
+        &self,
+        offset: u64,
+        bytes: usize,
+        buffer: *const u8,
+        _priority: Priority,
+    ) -> KResult<usize> {
+        self.write(offset, bytes, buffer)
+    }
+
+    // the device behind the queue, for the panic handler which can't wait in it
+    fn unqueued(&self) -> Option<&dyn BlockDevice> {
+        None
+    }
+}
+
+/*
+    Waiting for a command a device works on by itself, e.g. a dma transfer. The caller
+    sleeps on the queue the device's interrupt handler notifies, so other threads run in the
+    meantime. A device without a working interrupt passes no queue and gets polled, as does
+    everything before the scheduler is up
+*/
+pub struct IoRequest<'a> {
+    completion: Option<&'a WaitQueue>,
+    deadline: u64, // timer source ns
+}
+
+impl<'a> IoRequest<'a> {
+    pub fn new(completion: Option<&'a WaitQueue>, timeout_ms: u64) -> Self {
+        IoRequest {
+            completion,
+            deadline: timer_source::current_ns() + timeout_ms * 1_000_000,
+        }
+    }
+
+    // returns whether done became true before the timeout
+    pub fn wait(&self, done: impl Fn() -> bool) -> bool {
+        loop {
+            // read before checking, so a completion in between still wakes us up
+            let generation = self.completion.map(|queue| queue.generation());
+
+            if done() {
+                return true;
+            }
+
+            if timer_source::current_ns() >= self.deadline {
+                return false;
+            }
+
+            match (self.completion, generation) {
+                (Some(queue), Some(generation)) => {
+                    queue.wait(generation, Some(self.deadline));
+                }
+                _ => core::hint::spin_loop(),
+            }
+        }
+    }
+}
+
+// returns the index of the new device, which is put behind a request queue
+pub fn register(device: Arc<dyn BlockDevice>) -> usize {
+    let device: Arc<dyn BlockDevice> = Arc::new(iosched::Queue::new(device));
+
+    log::info!(
+        "[BLOCK] Registered {} ({} KiB)\n",
+        device.name(),
+        device.size() / 1024
+    );
+
+    unsafe {
+        BLOCK_DEVICES.push(device);
+        BLOCK_DEVICES.len() - 1
+    }
+}
+
+pub fn get(index: usize) -> Option<Arc<dyn BlockDevice>> {
+    unsafe { BLOCK_DEVICES.get(index).cloned() }
+}
+
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    devices().iter().find(|device| device.name() == name).cloned()
+}
+
+pub fn devices() -> &'static [Arc<dyn BlockDevice>] {
+    unsafe { BLOCK_DEVICES.as_slice() }
+}
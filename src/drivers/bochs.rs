@@ -0,0 +1,161 @@
+/*
+    The display interface of bochs' and qemu's standard vga (the dispi registers), which
+    lets the resolution be changed at runtime instead of living with the bootloader's.
+    The linear framebuffer is the pci device's bar 0, and a new mode is handed to the
+    console, which redraws itself for it. video=<width>x<height> on the command line sets a
+    mode at boot
+*/
+
+use crate::arch::io::{inw, outw};
+use crate::arch::mm::pmm::PhysAddr;
+use crate::arch::pci::PciDevice;
+use crate::error::{KError, KResult};
+use crate::mm::vmm::{self, CacheMode};
+use crate::video::{self, gfx::Framebuffer};
+use crate::{cmdline, log};
+
+pub const VENDOR_ID: u16 = 0x1234;
+pub const DEVICE_ID: u16 = 0x1111;
+
+const INDEX_PORT: u16 = 0x1ce;
+const DATA_PORT: u16 = 0x1cf;
+
+// dispi registers
+const INDEX_ID: u16 = 0x0;
+const INDEX_XRES: u16 = 0x1;
+const INDEX_YRES: u16 = 0x2;
+const INDEX_BPP: u16 = 0x3;
+const INDEX_ENABLE: u16 = 0x4;
+const INDEX_VIRT_WIDTH: u16 = 0x6;
+const INDEX_X_OFFSET: u16 = 0x8;
+const INDEX_Y_OFFSET: u16 = 0x9;
+
+const ENABLED: u16 = 0x01;
+const GET_CAPS: u16 = 0x02; // xres and yres read as the maximum while it's set
+const LFB_ENABLED: u16 = 0x40;
+
+// the first version with a linear framebuffer and 32 bits per pixel
+const ID_MIN: u16 = 0xb0c2;
+const ID_MAX: u16 = 0xb0c5;
+const BPP: u16 = 32;
+
+static BOCHS: spin::Mutex<Option<Bochs>> = spin::Mutex::new(None);
+
+struct Bochs {
+    framebuffer: PhysAddr,
+    vram_size: u64,
+    max_width: u16,
+    max_height: u16,
+}
+
+fn read(index: u16) -> u16 {
+    unsafe {
+        outw(INDEX_PORT, index);
+        inw(DATA_PORT)
+    }
+}
+
+fn write(index: u16, value: u16) {
+    unsafe {
+        outw(INDEX_PORT, index);
+        outw(DATA_PORT, value);
+    }
+}
+
+// the current mode, (width, height)
+pub fn mode() -> Option<(u16, u16)> {
+    BOCHS.lock().as_ref()?;
+    Some((read(INDEX_XRES), read(INDEX_YRES)))
+}
+
+pub fn set_mode(width: u16, height: u16) -> KResult<()> {
+    let bochs = BOCHS.lock();
+    let bochs = bochs.as_ref().ok_or(KError::ENODEV)?;
+
+    if width == 0 || height == 0 || width > bochs.max_width || height > bochs.max_height {
+        return Err(KError::EINVAL);
+    }
+    if width as u64 * height as u64 * (BPP as u64 / 8) > bochs.vram_size {
+        return Err(KError::ENOMEM);
+    }
+
+    // the registers can only be changed with the display off
+    write(INDEX_ENABLE, 0);
+    write(INDEX_XRES, width);
+    write(INDEX_YRES, height);
+    write(INDEX_BPP, BPP);
+    write(INDEX_X_OFFSET, 0);
+    write(INDEX_Y_OFFSET, 0);
+    write(INDEX_ENABLE, ENABLED | LFB_ENABLED);
+
+    if read(INDEX_XRES) != width || read(INDEX_YRES) != height {
+        log::warning!("[BOCHS] The adapter didn't take {}x{}\n", width, height);
+        return Err(KError::EINVAL);
+    }
+
+    // the adapter can pad lines, the virtual width is what they really take
+    let stride = read(INDEX_VIRT_WIDTH).max(width) as usize;
+
+    video::set_framebuffer(Framebuffer::from_raw(
+        bochs.framebuffer.higher_half().as_mut_ptr::<u32>(),
+        width as usize,
+        height as usize,
+        stride,
+    ));
+
+    log::info!("[BOCHS] Mode set to {}x{}x{}\n", width, height, BPP);
+    Ok(())
+}
+
+// e.g. 1024x768
+fn parse_mode(mode: &str) -> Option<(u16, u16)> {
+    let (width, height) = mode.split_once('x')?;
+    Some((width.parse().ok()?, height.parse().ok()?))
+}
+
+pub fn init(dev: &PciDevice) {
+    let id = read(INDEX_ID);
+    if !(ID_MIN..=ID_MAX).contains(&id) {
+        log::warning!("[BOCHS] Unsupported dispi version {:#x}\n", id);
+        return;
+    }
+
+    dev.enable_mmio();
+    let framebuffer = dev.get_bar(0);
+    let vram_size = dev.bar_size(0);
+
+    // also where a 64 bit bar is above what the direct map covers
+    vmm::get().map_mmio(framebuffer, vram_size, CacheMode::WriteCombining);
+
+    // the maximum resolution is only readable while GET_CAPS is set
+    let enable = read(INDEX_ENABLE);
+    write(INDEX_ENABLE, GET_CAPS);
+    let (max_width, max_height) = (read(INDEX_XRES), read(INDEX_YRES));
+    write(INDEX_ENABLE, enable);
+
+    *BOCHS.lock() = Some(Bochs {
+        framebuffer,
+        vram_size,
+        max_width,
+        max_height,
+    });
+
+    log::info!(
+        "[BOCHS] dispi {:#x}, {} KiB of vram, up to {}x{}\n",
+        id,
+        vram_size / 1024,
+        max_width,
+        max_height
+    );
+
+    if let Some(mode) = cmdline::option("video") {
+        match parse_mode(mode) {
+            Some((width, height)) => {
+                if let Err(err) = set_mode(width, height) {
+                    log::warning!("[BOCHS] Could not set {}: {}\n", mode, err);
+                }
+            }
+            None => log::warning!("[BOCHS] Invalid mode {}, expected <width>x<height>\n", mode),
+        }
+    }
+}
@@ -1,7 +1,9 @@
 use crate::arch::{acpi, mm::pmm};
-use crate::mm::vmm::{self, PageFlags};
+use crate::error::{KError, KResult};
+use crate::mm::vmm::{self, CacheMode};
+use core::mem::size_of;
 
-const MS_IN_FEMTOSECONDS: u64 = 1000000000000;
+const NS_IN_FEMTOSECONDS: u64 = 1000000;
 
 static mut HPET: Option<&HpetMem> = None;
 
@@ -32,31 +34,31 @@ struct HpetMem {
     main_counter_value: u64,
 }
 
-pub fn init() {
+// ENODEV if acpi doesn't have one, the pit is used instead then
+pub fn init() -> KResult<()> {
     let hpet_table = unsafe {
-        &mut *(acpi::find_table(*b"HPET").expect("Could not find the HPET table")
-            as *const acpi::Sdt as *mut HpetTable)
+        &*(acpi::find_table(*b"HPET").ok_or(KError::ENODEV)? as *const acpi::Sdt
+            as *const HpetTable)
     };
 
-    vmm::get().map_page(
-        vmm::VirtAddr::new(hpet_table.address + pmm::PHYS_BASE),
+    let registers = vmm::get().map_mmio(
         pmm::PhysAddr::new(hpet_table.address),
-        PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::UNCACHEABLE,
-        true,
+        size_of::<HpetMem>() as u64,
+        CacheMode::Uncacheable,
     );
 
-    let hpet = unsafe { &mut *(hpet_table.address as *mut HpetMem) };
+    let hpet = unsafe { &mut *(registers as *mut HpetMem) };
     hpet.general_config = 1;
 
     unsafe { HPET = Some(hpet) }
+    Ok(())
 }
 
-pub fn sleep(ms: u64) {
+// nanoseconds since the hpet has been enabled, this is our monotonic clock
+pub fn current_ns() -> u64 {
     let hpet = unsafe { HPET.expect("The HPET hasn't been initialized") };
     let clock = (hpet.general_capabilities >> 32) as u32;
 
-    let target = { hpet.main_counter_value } + (ms * MS_IN_FEMTOSECONDS) / clock as u64;
-    while hpet.main_counter_value < target {
-        core::hint::spin_loop();
-    }
+    // u128 so that it doesn't overflow after a few hours of uptime
+    (({ hpet.main_counter_value } as u128 * clock as u128) / NS_IN_FEMTOSECONDS as u128) as u64
 }
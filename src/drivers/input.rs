@@ -0,0 +1,181 @@
+/*
+    Input events, in a queue per device that readers of its device file drain. The events
+    are like linux's evdev ones: a device reports each thing that changed, a relative
+    motion or a button going down or up, then a SYN_REPORT to say they all happened at once.
+
+    Drivers push from their isrs, so the queue is a fixed ring that never allocates. When
+    nobody reads it the oldest events are dropped
+*/
+
+use crate::drivers::timer_source;
+use crate::error::{KError, KResult};
+use crate::fs::devfs;
+use crate::fs::vfs::PollEvents;
+use crate::proc::wait::WaitQueue;
+use crate::spinlock::Spinlock;
+use core::mem::size_of;
+
+// event kinds
+pub const EV_SYN: u16 = 0x0;
+pub const EV_KEY: u16 = 0x1;
+pub const EV_REL: u16 = 0x2;
+pub const EV_ABS: u16 = 0x3;
+
+pub const SYN_REPORT: u16 = 0x0;
+
+// codes of EV_REL and EV_ABS
+pub const REL_X: u16 = 0x0;
+pub const REL_Y: u16 = 0x1;
+pub const REL_WHEEL: u16 = 0x8;
+pub const ABS_X: u16 = 0x0;
+pub const ABS_Y: u16 = 0x1;
+
+// codes of EV_KEY, the value is 1 for down and 0 for up
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_RIGHT: u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
+
+const QUEUE_SIZE: usize = 256;
+
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct InputEvent {
+    pub time: u64, // timer source ns
+    pub kind: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+impl InputEvent {
+    const EMPTY: InputEvent = InputEvent {
+        time: 0,
+        kind: 0,
+        code: 0,
+        value: 0,
+    };
+
+    pub fn new(kind: u16, code: u16, value: i32) -> Self {
+        InputEvent {
+            time: timer_source::current_ns(),
+            kind,
+            code,
+            value,
+        }
+    }
+}
+
+struct Ring {
+    events: [InputEvent; QUEUE_SIZE],
+    head: usize, // the oldest event
+    len: usize,
+    dropped: u64,
+}
+
+pub struct InputQueue {
+    ring: Spinlock<Ring>,
+    queue: WaitQueue,
+}
+
+impl InputQueue {
+    pub const fn new() -> Self {
+        InputQueue {
+            ring: Spinlock::new(Ring {
+                events: [InputEvent::EMPTY; QUEUE_SIZE],
+                head: 0,
+                len: 0,
+                dropped: 0,
+            }),
+            queue: WaitQueue::new(),
+        }
+    }
+
+    // can be called from an isr
+    pub fn push(&self, events: &[InputEvent]) {
+        let mut ring = self.ring.lock_irqsave();
+
+        for event in events {
+            if ring.len == QUEUE_SIZE {
+                ring.head = (ring.head + 1) % QUEUE_SIZE;
+                ring.len -= 1;
+                ring.dropped += 1;
+            }
+
+            let tail = (ring.head + ring.len) % QUEUE_SIZE;
+            ring.events[tail] = *event;
+            ring.len += 1;
+        }
+
+        drop(ring);
+        self.queue.notify();
+    }
+
+    pub fn pop(&self) -> Option<InputEvent> {
+        let mut ring = self.ring.lock_irqsave();
+        if ring.len == 0 {
+            return None;
+        }
+
+        let event = ring.events[ring.head];
+        ring.head = (ring.head + 1) % QUEUE_SIZE;
+        ring.len -= 1;
+        Some(event)
+    }
+
+    pub fn len(&self) -> usize {
+        self.ring.lock_irqsave().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // how many events were pushed out by newer ones before anyone read them
+    pub fn dropped(&self) -> u64 {
+        self.ring.lock_irqsave().dropped
+    }
+}
+
+impl devfs::Device for InputQueue {
+    // whole events, waiting for the first one if there's none yet
+    fn read(&self, buffer: *mut u8, cnt: usize, _offset: usize) -> KResult<usize> {
+        let size = size_of::<InputEvent>();
+        if cnt < size {
+            return Err(KError::EINVAL);
+        }
+
+        loop {
+            let generation = self.queue.generation();
+            if !self.is_empty() {
+                break;
+            }
+            self.queue.wait(generation, None);
+        }
+
+        let mut read = 0;
+        while read + size <= cnt {
+            let event = match self.pop() {
+                Some(event) => event,
+                None => break,
+            };
+
+            unsafe {
+                (buffer.add(read) as *mut InputEvent).write_unaligned(event);
+            }
+            read += size;
+        }
+
+        Ok(read)
+    }
+
+    fn poll(&self) -> PollEvents {
+        if self.is_empty() {
+            PollEvents::empty()
+        } else {
+            PollEvents::POLLIN
+        }
+    }
+
+    fn wait_queue(&self) -> Option<&WaitQueue> {
+        Some(&self.queue)
+    }
+}
@@ -0,0 +1,270 @@
+/*
+    The request queue every block device is registered behind, a simple elevator. While
+    the device is busy requests pile up, and whoever finds it idle dispatches them in
+    batches: metadata first, then everything else in one sweep up the disk from where the
+    last batch ended, wrapping around to the lowest offset once (c-look). Neighbouring
+    requests in the same direction become one transfer through a bounce buffer.
+
+    Each caller still waits for its own request, the queue only decides the order. The
+    buffers are kernel memory, so the thread dispatching can copy to and from anyone's
+*/
+
+use super::block::{BlockDevice, Priority};
+use crate::error::KResult;
+use crate::proc::wait::WaitQueue;
+use alloc::{sync::Arc, vec::Vec};
+
+const MAX_MERGE: usize = 128 * 1024; // bytes in one transfer
+const MAX_BATCH: usize = 64; // later requests wait for the next sweep, so none starves
+
+#[derive(Clone, Copy)]
+pub struct Extent {
+    pub write: bool,
+    pub priority: Priority,
+    pub offset: u64,
+    pub bytes: usize,
+}
+
+impl Extent {
+    fn end(&self) -> u64 {
+        self.offset + self.bytes as u64
+    }
+}
+
+struct Request {
+    id: u64,
+    extent: Extent,
+    buffer: *mut u8, // only read from for writes
+}
+
+// the dispatching thread takes the requests of sleeping ones
+unsafe impl Send for Request {}
+
+struct State {
+    pending: Vec<Request>,
+    finished: Vec<(u64, KResult<usize>)>,
+    dispatching: bool,
+    head: u64, // where the last batch ended
+    next_id: u64,
+}
+
+pub struct Queue {
+    device: Arc<dyn BlockDevice>,
+    state: spin::Mutex<State>,
+    completion: WaitQueue,
+}
+
+/*
+    The order a batch goes to the device in, as runs of indexes into extents, each run
+    being merged into one transfer. Requests for the same place keep the order they came in
+*/
+pub fn plan(extents: &[Extent], head: u64) -> Vec<Vec<usize>> {
+    let mut order: Vec<usize> = (0..extents.len()).collect();
+    order.sort_by_key(|&i| (extents[i].priority, extents[i].offset < head, extents[i].offset));
+
+    let mut runs: Vec<Vec<usize>> = Vec::new();
+    for i in order {
+        let extent = extents[i];
+
+        if let Some(run) = runs.last_mut() {
+            let first = extents[run[0]];
+            let last = extents[*run.last().unwrap()];
+
+            if last.write == extent.write
+                && last.end() == extent.offset
+                && (extent.end() - first.offset) as usize <= MAX_MERGE
+            {
+                run.push(i);
+                continue;
+            }
+        }
+
+        runs.push(alloc::vec![i]);
+    }
+
+    runs
+}
+
+impl Queue {
+    pub fn new(device: Arc<dyn BlockDevice>) -> Self {
+        Queue {
+            device,
+            state: spin::Mutex::new(State {
+                pending: Vec::new(),
+                finished: Vec::new(),
+                dispatching: false,
+                head: 0,
+                next_id: 0,
+            }),
+            completion: WaitQueue::new(),
+        }
+    }
+
+    fn submit(&self, extent: Extent, buffer: *mut u8) -> KResult<usize> {
+        let mut state = self.state.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.pending.push(Request { id, extent, buffer });
+
+        if !state.dispatching {
+            state.dispatching = true;
+            drop(state);
+            self.dispatch();
+        } else {
+            drop(state);
+        }
+
+        self.wait_for(id)
+    }
+
+    fn wait_for(&self, id: u64) -> KResult<usize> {
+        loop {
+            let generation = self.completion.generation();
+
+            let mut state = self.state.lock();
+            if let Some(position) = state.finished.iter().position(|(done, _)| *done == id) {
+                return state.finished.swap_remove(position).1;
+            }
+            drop(state);
+
+            self.completion.wait(generation, None);
+        }
+    }
+
+    // until there's nothing left, including what came in while the device was busy
+    fn dispatch(&self) {
+        loop {
+            let mut state = self.state.lock();
+            if state.pending.is_empty() {
+                state.dispatching = false;
+                return;
+            }
+
+            let cnt = state.pending.len().min(MAX_BATCH);
+            let batch: Vec<Request> = state.pending.drain(..cnt).collect();
+            let head = state.head;
+            drop(state);
+
+            let extents: Vec<Extent> = batch.iter().map(|request| request.extent).collect();
+            let mut results = Vec::with_capacity(batch.len());
+            let mut end = head;
+
+            for run in plan(&extents, head) {
+                let requests: Vec<&Request> = run.iter().map(|&i| &batch[i]).collect();
+                let result = self.transfer(&requests);
+                end = requests.last().unwrap().extent.end();
+
+                for request in requests {
+                    // a merged transfer's count is the whole run's
+                    let result = match result {
+                        Ok(_) if run.len() > 1 => Ok(request.extent.bytes),
+                        _ => result,
+                    };
+                    results.push((request.id, result));
+                }
+            }
+
+            let mut state = self.state.lock();
+            state.head = end;
+            state.finished.extend(results);
+            drop(state);
+
+            self.completion.notify();
+        }
+    }
+
+    // one run of requests, contiguous and all in the same direction
+    fn transfer(&self, requests: &[&Request]) -> KResult<usize> {
+        let first = requests[0].extent;
+        if requests.len() == 1 {
+            return if first.write {
+                self.device.write(first.offset, first.bytes, requests[0].buffer)
+            } else {
+                self.device.read(first.offset, first.bytes, requests[0].buffer)
+            };
+        }
+
+        let total = (requests.last().unwrap().extent.end() - first.offset) as usize;
+        let mut bounce = alloc::vec![0u8; total];
+
+        if first.write {
+            for request in requests {
+                let start = (request.extent.offset - first.offset) as usize;
+                unsafe {
+                    let destination = bounce.as_mut_ptr().add(start);
+                    destination.copy_from(request.buffer, request.extent.bytes);
+                }
+            }
+
+            return self.device.write(first.offset, total, bounce.as_ptr());
+        }
+
+        let read = self.device.read(first.offset, total, bounce.as_mut_ptr())?;
+        for request in requests {
+            let start = (request.extent.offset - first.offset) as usize;
+            unsafe {
+                request.buffer.copy_from(bounce.as_ptr().add(start), request.extent.bytes);
+            }
+        }
+
+        Ok(read)
+    }
+}
+
+impl BlockDevice for Queue {
+    fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    fn size(&self) -> u64 {
+        self.device.size()
+    }
+
+    fn read(&self, offset: u64, bytes: usize, buffer: *mut u8) -> KResult<usize> {
+        self.read_with(offset, bytes, buffer, Priority::Data)
+    }
+
+    fn write(&self, offset: u64, bytes: usize, buffer: *const u8) -> KResult<usize> {
+        self.write_with(offset, bytes, buffer, Priority::Data)
+    }
+
+    fn read_with(
+        &self,
+        offset: u64,
+        bytes: usize,
+        buffer: *mut u8,
+        priority: Priority,
+    ) -> KResult<usize> {
+        let extent = Extent {
+            write: false,
+            priority,
+            offset,
+            bytes,
+        };
+        self.submit(extent, buffer)
+    }
+
+    fn write_with(
+        &self,
+        offset: u64,
+        bytes: usize,
+        buffer: *const u8,
+        priority: Priority,
+    ) -> KResult<usize> {
+        let extent = Extent {
+            write: true,
+            priority,
+            offset,
+            bytes,
+        };
+        self.submit(extent, buffer as *mut u8)
+    }
+
+    fn prefetch(&self, offset: u64, bytes: usize) {
+        self.device.prefetch(offset, bytes)
+    }
+
+    fn unqueued(&self) -> Option<&dyn BlockDevice> {
+        Some(&*self.device)
+    }
+}
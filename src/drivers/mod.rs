@@ -1,2 +1,21 @@
+#[cfg(feature = "audio")]
+pub mod ac97;
+#[cfg(feature = "ahci")]
 pub mod ahci;
+#[cfg(feature = "audio")]
+pub mod audio;
+pub mod aio;
+pub mod block;
+#[cfg(feature = "graphics")]
+pub mod bochs;
 pub mod hpet;
+pub mod input;
+pub mod iosched;
+pub mod pit;
+pub mod ps2;
+pub mod ramdisk;
+pub mod timer;
+pub mod timer_source;
+// only the gpu uses it so far
+#[cfg(feature = "graphics")]
+pub mod virtio;
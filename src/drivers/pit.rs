@@ -0,0 +1,87 @@
+/*
+    The 8254 pit, the clock when there's no hpet. Channel 0 counts down from 65536 over
+    and over at 1.193182MHz, and the time is the ticks counted so far: every read adds
+    what went by since the last one. That only works if no two reads are a whole period
+    (~55ms) apart, so once the ioapic is up the pit's irq reads it at every wrap
+*/
+
+use crate::arch::io::{inb, outb};
+use crate::arch::{apic, interrupts, ioapic};
+use crate::log;
+use crate::spinlock::Spinlock;
+
+const FREQUENCY: u64 = 1_193_182;
+const NS_IN_SECOND: u128 = 1_000_000_000;
+const CHANNEL0: u16 = 0x40;
+const COMMAND: u16 = 0x43;
+// channel 0, low then high byte, mode 2 (rate generator), binary
+const RATE_GENERATOR: u8 = 0b00_11_010_0;
+const LATCH_CHANNEL0: u8 = 0;
+const PERIOD: u32 = 0x10000; // a reload value of 0
+const IRQ_LINE: u8 = 0;
+
+static CLOCK: Spinlock<Clock> = Spinlock::new(Clock { last: 0, ticks: 0 });
+
+struct Clock {
+    last: u16, // the count at the last read
+    ticks: u64,
+}
+
+// what channel 0 is at right now, counting down
+fn read_count() -> u16 {
+    unsafe {
+        outb(COMMAND, LATCH_CHANNEL0);
+        let low = inb(CHANNEL0);
+        let high = inb(CHANNEL0);
+        u16::from_le_bytes([low, high])
+    }
+}
+
+// the ticks since init, the irq takes the lock too
+fn ticks() -> u64 {
+    let mut clock = CLOCK.lock_irqsave();
+    let count = read_count();
+
+    // a count of 0 is really 65536, so a full period is never mistaken for nothing
+    let elapsed = (clock.last as u32 + PERIOD - count as u32) % PERIOD;
+    clock.ticks += elapsed as u64;
+    clock.last = count;
+    clock.ticks
+}
+
+pub fn init() {
+    unsafe {
+        outb(COMMAND, RATE_GENERATOR);
+        outb(CHANNEL0, 0);
+        outb(CHANNEL0, 0);
+    }
+
+    CLOCK.lock_irqsave().last = read_count();
+}
+
+// after the ioapic, without it the clock only keeps up while it's read often enough
+pub fn route_irq() {
+    let vector = match interrupts::alloc_vector() {
+        Some(vector) => vector,
+        None => {
+            log::warning!("[PIT] No vector for the pit, it can fall behind\n");
+            return;
+        }
+    };
+    unsafe {
+        interrupts::register_isr(vector, pit_irq as u64, 0, 0x8e, "pit");
+    }
+
+    if let Err(err) = ioapic::route_irq(IRQ_LINE, vector as u8) {
+        log::warning!("[PIT] Could not route irq {}: {}, it can fall behind\n", IRQ_LINE, err);
+    }
+}
+
+pub fn current_ns() -> u64 {
+    (ticks() as u128 * NS_IN_SECOND / FREQUENCY as u128) as u64
+}
+
+interrupts::isr!(pit_irq, |_stack| {
+    ticks();
+    apic::get().eoi();
+});
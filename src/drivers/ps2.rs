@@ -0,0 +1,332 @@
+/*
+    The mouse on the ps/2 controller's aux port. It sends a packet for every change:
+    the buttons and the x and y motion, plus the wheel for mice that speak the intellimouse
+    protocol, which is turned on by a magic sequence of sample rates.
+
+    Every packet becomes input events in EVENTS, read through /dev/input/mouse0, and moves
+    the pointer, whose position is clamped to the screen
+*/
+
+use super::timer_source;
+use super::input::{self, InputEvent, InputQueue};
+use crate::arch::io::{inb, outb};
+use crate::arch::{apic, interrupts, ioapic};
+use crate::error::{KError, KResult};
+use crate::fs::devfs;
+use crate::log;
+use crate::spinlock::Spinlock;
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64; // read, it's the command port when written
+const COMMAND_PORT: u16 = 0x64;
+
+// status
+const OUTPUT_FULL: u8 = 1 << 0;
+const INPUT_FULL: u8 = 1 << 1;
+const AUX_DATA: u8 = 1 << 5; // what's in the output buffer came from the mouse
+
+// controller commands
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_AUX: u8 = 0xa7;
+const ENABLE_AUX: u8 = 0xa8;
+const DISABLE_KEYBOARD: u8 = 0xad;
+const WRITE_AUX: u8 = 0xd4; // the next byte written goes to the mouse
+
+// controller configuration
+const KEYBOARD_IRQ: u8 = 1 << 0;
+const AUX_IRQ: u8 = 1 << 1;
+const AUX_CLOCK_DISABLED: u8 = 1 << 5;
+
+// mouse commands
+const GET_ID: u8 = 0xf2;
+const SET_SAMPLE_RATE: u8 = 0xf3;
+const ENABLE_REPORTING: u8 = 0xf4;
+const SET_DEFAULTS: u8 = 0xf6;
+const ACK: u8 = 0xfa;
+
+// the first byte of a packet
+const LEFT: u8 = 1 << 0;
+const RIGHT: u8 = 1 << 1;
+const MIDDLE: u8 = 1 << 2;
+const ALWAYS_SET: u8 = 1 << 3; // how a lost byte is noticed
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+const WHEEL_MOUSE_ID: u8 = 3;
+const AUX_IRQ_LINE: u8 = 12;
+const TIMEOUT_MS: u64 = 100;
+
+pub static EVENTS: InputQueue = InputQueue::new();
+static MOUSE: Spinlock<Option<Mouse>> = Spinlock::new(None);
+
+struct Mouse {
+    packet: [u8; 4],
+    received: usize,
+    packet_len: usize, // 4 with a wheel
+    buttons: u8,
+    position: (usize, usize),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Packet {
+    pub buttons: u8, // LEFT, RIGHT and MIDDLE
+    pub dx: i32,
+    pub dy: i32, // down is positive, like on the screen
+    pub wheel: i32,
+}
+
+/*
+    A whole packet, 3 or 4 bytes. The motion is 9 bit two's complement, with the sign in
+    the first byte. None if it overflowed, its motion is garbage then
+*/
+pub fn decode(bytes: &[u8]) -> Option<Packet> {
+    let flags = bytes[0];
+    if flags & ALWAYS_SET == 0 || flags & (X_OVERFLOW | Y_OVERFLOW) != 0 {
+        return None;
+    }
+
+    let extend = |value: u8, negative: bool| value as i32 - if negative { 0x100 } else { 0 };
+    let dx = extend(bytes[1], flags & X_SIGN != 0);
+    let dy = extend(bytes[2], flags & Y_SIGN != 0);
+
+    // the low 4 bits of the fourth byte, two's complement
+    let wheel = bytes.get(3).map_or(0, |&byte| ((byte << 4) as i8 >> 4) as i32);
+
+    Some(Packet {
+        buttons: flags & (LEFT | RIGHT | MIDDLE),
+        dx,
+        dy: -dy,
+        wheel,
+    })
+}
+
+fn wait(ready: impl Fn(u8) -> bool) -> KResult<()> {
+    let deadline = timer_source::current_ms() + TIMEOUT_MS;
+
+    while !ready(unsafe { inb(STATUS_PORT) }) {
+        if timer_source::current_ms() > deadline {
+            return Err(KError::ETIMEDOUT);
+        }
+        core::hint::spin_loop();
+    }
+
+    Ok(())
+}
+
+fn command(command: u8) -> KResult<()> {
+    wait(|status| status & INPUT_FULL == 0)?;
+    unsafe { outb(COMMAND_PORT, command) };
+    Ok(())
+}
+
+fn write_data(data: u8) -> KResult<()> {
+    wait(|status| status & INPUT_FULL == 0)?;
+    unsafe { outb(DATA_PORT, data) };
+    Ok(())
+}
+
+fn read_data() -> KResult<u8> {
+    wait(|status| status & OUTPUT_FULL != 0)?;
+    Ok(unsafe { inb(DATA_PORT) })
+}
+
+// sends a byte to the mouse, which acknowledges everything
+fn mouse_command(byte: u8) -> KResult<()> {
+    command(WRITE_AUX)?;
+    write_data(byte)?;
+
+    match read_data()? {
+        ACK => Ok(()),
+        _ => Err(KError::EIO),
+    }
+}
+
+fn set_sample_rate(rate: u8) -> KResult<()> {
+    mouse_command(SET_SAMPLE_RATE)?;
+    mouse_command(rate)
+}
+
+// a wheel mouse only says it is after this knock
+fn enable_wheel() -> KResult<bool> {
+    for rate in [200, 100, 80] {
+        set_sample_rate(rate)?;
+    }
+
+    mouse_command(GET_ID)?;
+    Ok(read_data()? == WHEEL_MOUSE_ID)
+}
+
+// what the pointer is clamped to, there's no pointer without a framebuffer
+fn screen_size() -> (usize, usize) {
+    #[cfg(feature = "graphics")]
+    return crate::video::screen_size().unwrap_or((0, 0));
+    #[cfg(not(feature = "graphics"))]
+    return (0, 0);
+}
+
+fn clamp(position: (usize, usize), dx: i32, dy: i32) -> (usize, usize) {
+    let (width, height) = screen_size();
+
+    let x = (position.0 as i64 + dx as i64).clamp(0, width.saturating_sub(1) as i64);
+    let y = (position.1 as i64 + dy as i64).clamp(0, height.saturating_sub(1) as i64);
+    (x as usize, y as usize)
+}
+
+impl Mouse {
+    // returns a whole packet once it has one
+    fn receive(&mut self, byte: u8) -> Option<[u8; 4]> {
+        // out of sync, wait for something that can start a packet
+        if self.received == 0 && byte & ALWAYS_SET == 0 {
+            return None;
+        }
+
+        self.packet[self.received] = byte;
+        self.received += 1;
+
+        if self.received < self.packet_len {
+            return None;
+        }
+
+        self.received = 0;
+        Some(self.packet)
+    }
+
+    fn report(&mut self, packet: Packet) {
+        // at most 3 motions, 3 buttons, 2 positions and the report
+        let mut events = [InputEvent::new(input::EV_SYN, input::SYN_REPORT, 0); 9];
+        let mut count = 0;
+        let mut add = |kind, code, value| {
+            events[count] = InputEvent::new(kind, code, value);
+            count += 1;
+        };
+
+        for (code, value) in [
+            (input::REL_X, packet.dx),
+            (input::REL_Y, packet.dy),
+            (input::REL_WHEEL, packet.wheel),
+        ] {
+            if value != 0 {
+                add(input::EV_REL, code, value);
+            }
+        }
+
+        for (button, code) in [
+            (LEFT, input::BTN_LEFT),
+            (RIGHT, input::BTN_RIGHT),
+            (MIDDLE, input::BTN_MIDDLE),
+        ] {
+            if (self.buttons ^ packet.buttons) & button != 0 {
+                add(input::EV_KEY, code, (packet.buttons & button != 0) as i32);
+            }
+        }
+        self.buttons = packet.buttons;
+
+        let position = clamp(self.position, packet.dx, packet.dy);
+        if position != self.position {
+            add(input::EV_ABS, input::ABS_X, position.0 as i32);
+            add(input::EV_ABS, input::ABS_Y, position.1 as i32);
+            self.position = position;
+
+            #[cfg(feature = "graphics")]
+            crate::video::move_pointer(position.0, position.1);
+        }
+
+        add(input::EV_SYN, input::SYN_REPORT, 0);
+        EVENTS.push(&events[..count]);
+    }
+}
+
+interrupts::isr!(aux_isr, |_stack| {
+    if let Some(mouse) = MOUSE.lock_irqsave().as_mut() {
+        loop {
+            let status = unsafe { inb(STATUS_PORT) };
+            if status & OUTPUT_FULL == 0 {
+                break;
+            }
+
+            let byte = unsafe { inb(DATA_PORT) };
+            if status & AUX_DATA == 0 {
+                continue;
+            }
+
+            if let Some(bytes) = mouse.receive(byte) {
+                if let Some(packet) = decode(&bytes[..mouse.packet_len]) {
+                    mouse.report(packet);
+                }
+            }
+        }
+    }
+
+    apic::get().eoi();
+});
+
+fn setup() -> KResult<bool> {
+    // quiet while it's being set up, whatever is in the output buffer is stale
+    command(DISABLE_AUX)?;
+    command(DISABLE_KEYBOARD)?;
+    while unsafe { inb(STATUS_PORT) } & OUTPUT_FULL != 0 {
+        unsafe { inb(DATA_PORT) };
+    }
+
+    // nothing reads the keyboard yet, its bytes would sit in front of the mouse's
+    command(READ_CONFIG)?;
+    let config = read_data()?;
+    command(WRITE_CONFIG)?;
+    write_data((config | AUX_IRQ) & !(AUX_CLOCK_DISABLED | KEYBOARD_IRQ))?;
+    command(ENABLE_AUX)?;
+
+    mouse_command(SET_DEFAULTS)?;
+    let wheel = enable_wheel()?;
+    mouse_command(ENABLE_REPORTING)?;
+
+    Ok(wheel)
+}
+
+pub fn init() {
+    let wheel = match setup() {
+        Ok(wheel) => wheel,
+        Err(err) => {
+            log::info!("[PS2] No mouse on the aux port: {}\n", err);
+            return;
+        }
+    };
+
+    // starts in the middle of the screen
+    let (width, height) = screen_size();
+    let start = (width / 2, height / 2);
+
+    *MOUSE.lock_irqsave() = Some(Mouse {
+        packet: [0; 4],
+        received: 0,
+        packet_len: if wheel { 4 } else { 3 },
+        buttons: 0,
+        position: start,
+    });
+
+    let vector = match interrupts::alloc_vector() {
+        Some(vector) => vector,
+        None => {
+            log::warning!("[PS2] No vector for the mouse\n");
+            return;
+        }
+    };
+    unsafe {
+        interrupts::register_isr(vector, aux_isr as u64, 0, 0x8e, "ps2 aux");
+    }
+    if let Err(err) = ioapic::route_irq(AUX_IRQ_LINE, vector as u8) {
+        log::warning!("[PS2] Could not route irq {}: {}\n", AUX_IRQ_LINE, err);
+        return;
+    }
+
+    if let Err(err) = devfs::register("input/mouse0", &EVENTS) {
+        log::warning!("[PS2] Could not register input/mouse0: {}\n", err);
+    }
+
+    #[cfg(feature = "graphics")]
+    crate::video::move_pointer(start.0, start.1);
+
+    log::info!("[PS2] Mouse{} on the aux port\n", if wheel { " with a wheel" } else { "" });
+}
@@ -0,0 +1,96 @@
+/*
+    A block device backed by memory, either a stivale2 module called "ramdisk"
+    or an empty buffer allocated on demand
+*/
+
+use super::block;
+use crate::arch::mm::pmm::PmmBox;
+use crate::error::{KError, KResult};
+use crate::{boot, log};
+use alloc::{string::String, sync::Arc};
+
+pub struct Ramdisk {
+    name: String,
+    data: *mut u8,
+    size: u64,
+    _buffer: Option<PmmBox<u8>>, // None if the memory came from a module
+}
+
+impl Ramdisk {
+    // a zero filled ramdisk
+    pub fn new(name: String, size: u64) -> Self {
+        let buffer = PmmBox::<u8>::new(size as usize);
+
+        Ramdisk {
+            name,
+            data: buffer.as_mut_ptr(),
+            size,
+            _buffer: Some(buffer),
+        }
+    }
+
+    // the memory has to stay valid for as long as the ramdisk exists
+    pub unsafe fn from_raw(name: String, data: *mut u8, size: u64) -> Self {
+        Ramdisk {
+            name,
+            data,
+            size,
+            _buffer: None,
+        }
+    }
+
+    fn check_range(&self, offset: u64, bytes: usize) -> KResult<()> {
+        if offset > self.size || bytes as u64 > self.size - offset {
+            return Err(KError::EINVAL);
+        }
+
+        Ok(())
+    }
+}
+
+impl block::BlockDevice for Ramdisk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read(&self, offset: u64, bytes: usize, buffer: *mut u8) -> KResult<usize> {
+        self.check_range(offset, bytes)?;
+
+        unsafe {
+            buffer.copy_from(self.data.add(offset as usize), bytes);
+        }
+
+        Ok(bytes)
+    }
+
+    fn write(&self, offset: u64, bytes: usize, buffer: *const u8) -> KResult<usize> {
+        self.check_range(offset, bytes)?;
+
+        unsafe {
+            self.data.add(offset as usize).copy_from(buffer, bytes);
+        }
+
+        Ok(bytes)
+    }
+}
+
+// registers every module whose string starts with "ramdisk" as a block device
+pub fn init() {
+    for module in boot::modules() {
+        if !module.name.starts_with("ramdisk") {
+            continue;
+        }
+
+        // module memory is reserved for good
+        let ramdisk = unsafe {
+            Ramdisk::from_raw(module.name.clone(), module.as_mut_ptr(), module.size as u64)
+        };
+
+        log::info!("[RAMDISK] Found module {}\n", module.name);
+        block::register(Arc::new(ramdisk));
+    }
+}
@@ -0,0 +1,66 @@
+/*
+    A periodic tick from the lapic timer. Callbacks registered with every() run from the
+    tick's interrupt handler, so they must not block and can only try_lock anything that
+    is also locked outside of interrupts. After them the tick is what preempts the running
+    thread, the scheduler counts timeslices in ticks
+*/
+
+use crate::arch::{apic, gdbstub, interrupts};
+use crate::proc::scheduler;
+use crate::{log, trace};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+pub const TICK_MS: u64 = 10;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static CALLBACKS: spin::Mutex<Vec<Callback>> = spin::Mutex::new(Vec::new());
+
+struct Callback {
+    period: u64, // in ticks
+    next: u64,
+    func: fn(),
+}
+
+interrupts::isr!(tick, |regs| {
+    gdbstub::check_interrupt(regs);
+
+    let now = TICKS.fetch_add(1, Ordering::SeqCst) + 1;
+    trace::trace!(timer_tick, now);
+
+    // someone is registering a callback, they'll run on the next tick
+    if let Some(mut callbacks) = CALLBACKS.try_lock() {
+        for callback in callbacks.iter_mut().filter(|callback| now >= callback.next) {
+            callback.next = now + callback.period;
+            (callback.func)();
+        }
+    }
+
+    // acknowledges the tick, and the running thread may not get the cpu back from it
+    scheduler::reschedule_from(regs, true);
+});
+
+// runs func every ms milliseconds, rounded up to whole ticks
+pub fn every(ms: u64, func: fn()) {
+    let period = ((ms + TICK_MS - 1) / TICK_MS).max(1);
+
+    CALLBACKS.lock().push(Callback {
+        period,
+        next: ticks() + period,
+        func,
+    });
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst)
+}
+
+pub fn init() {
+    let vector = interrupts::alloc_vector().expect("Could not allocate a vector for the timer");
+    unsafe {
+        interrupts::register_isr(vector, tick as u64, 0, 0x8e, "timer");
+    }
+
+    apic::get().periodic(vector as u8, TICK_MS * 1_000_000);
+    log::info!("[TIMER] Ticking every {}ms\n", TICK_MS);
+}
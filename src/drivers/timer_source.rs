@@ -0,0 +1,90 @@
+/*
+    The monotonic clock everything reads the time from, and the busy waits built on it.
+    It's the hpet's main counter when acpi has an HPET table and the pit's ticks when it
+    doesn't, which are slower to read and only as fine as their ~838ns.
+    "timer_source=pit" on the command line picks the pit even if there's an hpet
+*/
+
+use super::{hpet, pit};
+use crate::{cmdline, log};
+use core::sync::atomic::{AtomicU8, Ordering};
+
+const NS_IN_MS: u64 = 1_000_000;
+
+// 0 until init, then a Source
+static SOURCE: AtomicU8 = AtomicU8::new(0);
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Source {
+    Hpet = 1,
+    Pit = 2,
+}
+
+impl Source {
+    pub fn name(self) -> &'static str {
+        match self {
+            Source::Hpet => "hpet",
+            Source::Pit => "pit",
+        }
+    }
+}
+
+// after acpi and the vmm
+pub fn init() {
+    let forced_pit = cmdline::option("timer_source") == Some("pit");
+
+    let source = if !forced_pit && hpet::init().is_ok() {
+        Source::Hpet
+    } else {
+        if !forced_pit {
+            log::warning!("[TIMER SOURCE] No hpet, falling back to the pit\n");
+        }
+        pit::init();
+        Source::Pit
+    };
+
+    SOURCE.store(source as u8, Ordering::SeqCst);
+    log::info!("[TIMER SOURCE] Using the {}\n", source.name());
+}
+
+// after the ioapic, the pit needs its irq to keep up
+pub fn init_irq() {
+    if source() == Some(Source::Pit) {
+        pit::route_irq();
+    }
+}
+
+pub fn source() -> Option<Source> {
+    match SOURCE.load(Ordering::Relaxed) {
+        1 => Some(Source::Hpet),
+        2 => Some(Source::Pit),
+        _ => None,
+    }
+}
+
+// the log asks before timestamping its messages
+pub fn is_initialized() -> bool {
+    source().is_some()
+}
+
+// nanoseconds since the timer source has been started, this is our monotonic clock
+pub fn current_ns() -> u64 {
+    match source() {
+        Some(Source::Hpet) => hpet::current_ns(),
+        Some(Source::Pit) => pit::current_ns(),
+        None => panic!("The timer source hasn't been initialized"),
+    }
+}
+
+pub fn current_ms() -> u64 {
+    current_ns() / NS_IN_MS
+}
+
+pub fn sleep(ms: u64) {
+    let target = current_ns() + ms * NS_IN_MS;
+
+    while current_ns() < target {
+        core::hint::spin_loop();
+    }
+}
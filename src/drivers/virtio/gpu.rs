@@ -0,0 +1,538 @@
+/*
+    virtio-gpu, 2d only. The screen is a resource on the host backed by guest memory, which
+    is where the console draws. The host only sees what it's told changed: a flush copies a
+    rectangle of the backing to the resource and shows it.
+
+    The host's window can be resized, which the device says with a display event. The
+    console then gets a new resource of the new size, so it follows the window.
+
+    Nothing is logged with GPU's lock held, logging prints on the console, which flushes
+*/
+
+use super::virtqueue::Buffer;
+use super::{Transport, Virtqueue};
+use crate::arch::cpu;
+use crate::arch::io::Mmio;
+use crate::arch::pci::PciDevice;
+use crate::drivers::timer_source;
+use crate::error::{KError, KResult};
+use crate::log;
+use crate::mm::dma::{DmaBuffer, DmaConstraints};
+use crate::spinlock::Spinlock;
+use crate::video::{
+    self,
+    gfx::{Framebuffer, Rect},
+};
+
+pub const DEVICE_ID: u16 = 0x1050;
+
+const CONTROL_QUEUE: u16 = 0;
+
+// commands
+const GET_DISPLAY_INFO: u32 = 0x100;
+const RESOURCE_CREATE_2D: u32 = 0x101;
+const RESOURCE_UNREF: u32 = 0x102;
+const SET_SCANOUT: u32 = 0x103;
+const RESOURCE_FLUSH: u32 = 0x104;
+const TRANSFER_TO_HOST_2D: u32 = 0x105;
+const RESOURCE_ATTACH_BACKING: u32 = 0x106;
+const RESOURCE_DETACH_BACKING: u32 = 0x107;
+
+// responses
+const OK_NODATA: u32 = 0x1100;
+const OK_DISPLAY_INFO: u32 = 0x1101;
+
+const EVENT_DISPLAY: u32 = 1;
+const FORMAT_B8G8R8X8: u32 = 2; // what the console draws, 0xrrggbb in little endian
+const MAX_SCANOUTS: usize = 16;
+const DEFAULT_MODE: (u32, u32) = (1024, 768); // for a display that doesn't say
+const COMMAND_TIMEOUT_MS: u64 = 1000;
+const RESPONSE_OFFSET: usize = 2048; // in the command buffer, the request goes before
+
+static GPU: Spinlock<Option<Gpu>> = Spinlock::new(None);
+
+#[repr(C)]
+struct Config {
+    events_read: Mmio<u32>,
+    events_clear: Mmio<u32>, // writing a bit clears it in events_read
+    num_scanouts: Mmio<u32>,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct Header {
+    kind: u32,
+    flags: u32,
+    fence_id: u64,
+    ctx_id: u32,
+    ring_idx: u8,
+    padding: [u8; 3],
+}
+
+impl Header {
+    fn new(kind: u32) -> Self {
+        Header {
+            kind,
+            ..Default::default()
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GpuRect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DisplayOne {
+    rect: GpuRect,
+    enabled: u32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DisplayInfo {
+    header: Header,
+    modes: [DisplayOne; MAX_SCANOUTS],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ResourceCreate2d {
+    header: Header,
+    resource_id: u32,
+    format: u32,
+    width: u32,
+    height: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ResourceUnref {
+    header: Header,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct SetScanout {
+    header: Header,
+    rect: GpuRect,
+    scanout_id: u32,
+    resource_id: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ResourceFlush {
+    header: Header,
+    rect: GpuRect,
+    resource_id: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct TransferToHost2d {
+    header: Header,
+    rect: GpuRect,
+    offset: u64, // of the rectangle in the backing
+    resource_id: u32,
+    padding: u32,
+}
+
+// the backing is contiguous, so it's always a single entry
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct AttachBacking {
+    header: Header,
+    resource_id: u32,
+    nr_entries: u32,
+    addr: u64,
+    length: u32,
+    padding: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct DetachBacking {
+    header: Header,
+    resource_id: u32,
+    padding: u32,
+}
+
+// a resource shown on scanout 0
+struct Scanout {
+    resource_id: u32,
+    width: u32,
+    height: u32,
+    backing: DmaBuffer,
+}
+
+struct Gpu {
+    transport: Transport,
+    config: &'static Config,
+    control: Virtqueue,
+    commands: DmaBuffer,
+    scanout: Option<Scanout>,
+    next_resource: u32, // 0 means no resource to the device
+    failed: bool,       // a command timed out, the device is given up on
+}
+
+impl Gpu {
+    /*
+        Sends request and waits for the response, which must be of kind expected. The
+        device is polled, it answers right away for everything we ask
+    */
+    fn command<Req: Copy, Resp: Copy>(&mut self, request: Req, expected: u32) -> KResult<Resp> {
+        if self.failed {
+            return Err(KError::EIO);
+        }
+
+        let request_len = core::mem::size_of::<Req>();
+        let response_len = core::mem::size_of::<Resp>();
+        let phys = self.commands.phys();
+
+        unsafe {
+            core::ptr::write_volatile(self.commands.as_mut_ptr::<Req>(), request);
+        }
+
+        let head = self.control.submit(&[
+            Buffer {
+                phys,
+                len: request_len as u32,
+                device_writes: false,
+            },
+            Buffer {
+                phys: phys + RESPONSE_OFFSET as u64,
+                len: response_len as u32,
+                device_writes: true,
+            },
+        ])?;
+
+        let deadline = timer_source::current_ms() + COMMAND_TIMEOUT_MS;
+        while self.control.poll().map(|(done, _)| done) != Some(head) {
+            // every flush would wait as long, with interrupts off
+            if timer_source::current_ms() > deadline {
+                self.failed = true;
+                self.transport.fail();
+                return Err(KError::ETIMEDOUT);
+            }
+            core::hint::spin_loop();
+        }
+
+        let response = self.commands.as_ptr::<u8>().wrapping_add(RESPONSE_OFFSET);
+        let response = unsafe { core::ptr::read_volatile(response as *const Resp) };
+
+        // every response starts with a header, whose first field is the kind
+        let kind = unsafe { *(&response as *const Resp as *const u32) };
+        if kind != expected {
+            return Err(KError::EIO);
+        }
+
+        Ok(response)
+    }
+
+    // scanout 0's size as the host would like it, if it's enabled
+    fn preferred_mode(&mut self) -> KResult<Option<(u32, u32)>> {
+        let info: DisplayInfo = self.command(Header::new(GET_DISPLAY_INFO), OK_DISPLAY_INFO)?;
+        let mode = info.modes[0];
+
+        if mode.enabled == 0 || mode.rect.width == 0 || mode.rect.height == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some((mode.rect.width, mode.rect.height)))
+    }
+
+    /*
+        Shows a new resource of width by height on scanout 0 and returns what the console
+        draws on. The old one is given back too, it has to stay around until the console
+        moved off its backing
+    */
+    fn create_scanout(
+        &mut self,
+        width: u32,
+        height: u32,
+    ) -> KResult<(Framebuffer, Option<Scanout>)> {
+        let backing = DmaBuffer::new(width as usize * height as usize * 4, DmaConstraints::ANY)?;
+        let resource_id = self.next_resource;
+        self.next_resource += 1;
+
+        let _: Header = self.command(
+            ResourceCreate2d {
+                header: Header::new(RESOURCE_CREATE_2D),
+                resource_id,
+                format: FORMAT_B8G8R8X8,
+                width,
+                height,
+            },
+            OK_NODATA,
+        )?;
+
+        let scanout = Scanout {
+            resource_id,
+            width,
+            height,
+            backing,
+        };
+
+        let attached = self.command::<_, Header>(
+            AttachBacking {
+                header: Header::new(RESOURCE_ATTACH_BACKING),
+                resource_id,
+                nr_entries: 1,
+                addr: scanout.backing.phys(),
+                length: scanout.backing.len() as u32,
+                padding: 0,
+            },
+            OK_NODATA,
+        );
+        let shown = attached.and_then(|_| {
+            self.command::<_, Header>(
+                SetScanout {
+                    header: Header::new(SET_SCANOUT),
+                    rect: GpuRect {
+                        x: 0,
+                        y: 0,
+                        width,
+                        height,
+                    },
+                    scanout_id: 0,
+                    resource_id,
+                },
+                OK_NODATA,
+            )
+        });
+
+        if let Err(err) = shown {
+            self.destroy(scanout);
+            return Err(err);
+        }
+
+        let fb = Framebuffer::from_raw(
+            scanout.backing.as_mut_ptr::<u32>(),
+            width as usize,
+            height as usize,
+            width as usize,
+        );
+
+        Ok((fb, self.scanout.replace(scanout)))
+    }
+
+    // the host forgets about the resource, then its backing is freed
+    fn destroy(&mut self, scanout: Scanout) {
+        let detached = self.command::<_, Header>(
+            DetachBacking {
+                header: Header::new(RESOURCE_DETACH_BACKING),
+                resource_id: scanout.resource_id,
+                padding: 0,
+            },
+            OK_NODATA,
+        );
+        let unref = self.command::<_, Header>(
+            ResourceUnref {
+                header: Header::new(RESOURCE_UNREF),
+                resource_id: scanout.resource_id,
+                padding: 0,
+            },
+            OK_NODATA,
+        );
+
+        // the host may still be reading from the backing, it's better to leak it
+        if detached.is_err() || unref.is_err() {
+            core::mem::forget(scanout);
+        }
+    }
+
+    // copies rect from the backing to the host and shows it
+    fn flush(&mut self, rect: Rect) -> KResult<()> {
+        let scanout = self.scanout.as_ref().ok_or(KError::ENODEV)?;
+        let resource_id = scanout.resource_id;
+
+        // the console could have drawn on the old framebuffer right before a resize
+        let x = (rect.x as u32).min(scanout.width);
+        let y = (rect.y as u32).min(scanout.height);
+        let rect = GpuRect {
+            x,
+            y,
+            width: (rect.width as u32).min(scanout.width - x),
+            height: (rect.height as u32).min(scanout.height - y),
+        };
+        if rect.width == 0 || rect.height == 0 {
+            return Ok(());
+        }
+
+        let offset = (y as u64 * scanout.width as u64 + x as u64) * 4;
+
+        let _: Header = self.command(
+            TransferToHost2d {
+                header: Header::new(TRANSFER_TO_HOST_2D),
+                rect,
+                offset,
+                resource_id,
+                padding: 0,
+            },
+            OK_NODATA,
+        )?;
+        let _: Header = self.command(
+            ResourceFlush {
+                header: Header::new(RESOURCE_FLUSH),
+                rect,
+                resource_id,
+                padding: 0,
+            },
+            OK_NODATA,
+        )?;
+
+        Ok(())
+    }
+
+    // whether the host changed the display since the last call
+    fn display_changed(&self) -> bool {
+        if self.config.events_read.get() & EVENT_DISPLAY == 0 {
+            return false;
+        }
+
+        self.config.events_clear.set(EVENT_DISPLAY);
+        true
+    }
+}
+
+// the current mode, (width, height)
+pub fn mode() -> Option<(u32, u32)> {
+    let gpu = GPU.lock_irqsave();
+    let scanout = gpu.as_ref()?.scanout.as_ref()?;
+
+    Some((scanout.width, scanout.height))
+}
+
+pub fn set_mode(width: u32, height: u32) -> KResult<()> {
+    if width == 0 || height == 0 {
+        return Err(KError::EINVAL);
+    }
+
+    let (fb, old) = GPU
+        .lock_irqsave()
+        .as_mut()
+        .ok_or(KError::ENODEV)?
+        .create_scanout(width, height)?;
+
+    // flushes the whole new screen, the gpu's lock can't be held meanwhile
+    video::set_framebuffer(fb);
+
+    if let Some(old) = old {
+        if let Some(gpu) = GPU.lock_irqsave().as_mut() {
+            gpu.destroy(old);
+        }
+    }
+
+    log::info!("[VIRTIO-GPU] Mode set to {}x{}\n", width, height);
+    Ok(())
+}
+
+// follows the host's display to its new size, if it has one
+pub fn update_display() {
+    let preferred = match GPU.lock_irqsave().as_mut() {
+        Some(gpu) => gpu.preferred_mode(),
+        None => return,
+    };
+
+    match preferred {
+        Ok(Some((width, height))) if mode() != Some((width, height)) => {
+            if let Err(err) = set_mode(width, height) {
+                log::warning!("[VIRTIO-GPU] Could not resize to {}x{}: {}\n", width, height, err);
+            }
+        }
+        Ok(_) => {}
+        Err(err) => log::warning!("[VIRTIO-GPU] Could not get the display info: {}\n", err),
+    }
+}
+
+// the console's flush hook, it can run in the cursor's timer interrupt
+fn flush(rect: Rect) {
+    // a resize allocates, so display events are left for when interrupts are on
+    let can_resize = cpu::interrupts_enabled();
+
+    // a failure isn't logged, that would flush again
+    let resized = match GPU.lock_irqsave().as_mut() {
+        Some(gpu) => {
+            let _ = gpu.flush(rect);
+            can_resize && gpu.display_changed()
+        }
+        None => false,
+    };
+
+    if resized {
+        update_display();
+    }
+}
+
+pub fn init(dev: &PciDevice) {
+    let transport = match super::Transport::new(dev) {
+        Some(transport) => transport,
+        None => {
+            log::warning!("[VIRTIO-GPU] Only the modern interface is supported\n");
+            return;
+        }
+    };
+
+    dev.enable_mmio();
+    dev.bus_master();
+
+    let setup = transport.negotiate(0).and_then(|_| {
+        let config = transport.device_config::<Config>().ok_or(KError::ENODEV)?;
+        let control = transport.setup_queue(CONTROL_QUEUE)?;
+        let commands = DmaBuffer::new(RESPONSE_OFFSET * 2, DmaConstraints::ANY)?;
+        Ok((config, control, commands))
+    });
+
+    let (config, control, commands) = match setup {
+        Ok(setup) => setup,
+        Err(err) => {
+            log::warning!("[VIRTIO-GPU] Could not set the device up: {}\n", err);
+            transport.fail();
+            return;
+        }
+    };
+
+    transport.driver_ok();
+    log::info!(
+        "[VIRTIO-GPU] {} scanouts, {} queues\n",
+        config.num_scanouts.get(),
+        transport.num_queues()
+    );
+
+    *GPU.lock_irqsave() = Some(Gpu {
+        transport,
+        config,
+        control,
+        commands,
+        scanout: None,
+        next_resource: 1,
+        failed: false,
+    });
+
+    let preferred = GPU.lock_irqsave().as_mut().unwrap().preferred_mode();
+    let (width, height) = match preferred {
+        Ok(Some(mode)) => mode,
+        Ok(None) => DEFAULT_MODE,
+        Err(err) => {
+            log::warning!("[VIRTIO-GPU] Could not get the display info: {}\n", err);
+            DEFAULT_MODE
+        }
+    };
+
+    // the flush hook has to be there before the console draws on the resource
+    video::set_flush(flush);
+    if let Err(err) = set_mode(width, height) {
+        log::warning!("[VIRTIO-GPU] Could not set {}x{}: {}\n", width, height, err);
+    }
+}
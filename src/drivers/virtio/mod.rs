@@ -0,0 +1,222 @@
+/*
+    Virtio devices over pci, with the modern (1.0) interface. The device describes where its
+    registers are with vendor capabilities: the common configuration, which is the same for
+    every kind of device, the doorbells for the queues, the interrupt status and the
+    configuration specific to the device. Each one is a window into one of its bars.
+
+    Everything is polled for now, no interrupt vectors are set up
+*/
+
+use crate::arch::io::Mmio;
+use crate::arch::mm::pmm::PhysAddr;
+use crate::arch::pci::PciDevice;
+use crate::error::{KError, KResult};
+use crate::mm::vmm::{self, CacheMode};
+
+pub mod gpu;
+pub mod virtqueue;
+
+pub use virtqueue::Virtqueue;
+
+pub const VENDOR_ID: u16 = 0x1af4;
+
+const VENDOR_CAPABILITY: u8 = 0x09;
+
+// what a vendor capability's cfg_type says the window is
+const COMMON_CFG: u8 = 1;
+const NOTIFY_CFG: u8 = 2;
+const ISR_CFG: u8 = 3;
+const DEVICE_CFG: u8 = 4;
+
+// device status
+const ACKNOWLEDGE: u8 = 1;
+const DRIVER: u8 = 2;
+const DRIVER_OK: u8 = 4;
+const FEATURES_OK: u8 = 8;
+const FAILED: u8 = 128;
+
+// the only interface we speak, devices without it are legacy ones
+const VERSION_1: u64 = 1 << 32;
+const NO_VECTOR: u16 = 0xffff;
+
+#[repr(C)]
+struct CommonCfg {
+    device_feature_select: Mmio<u32>,
+    device_feature: Mmio<u32>,
+    driver_feature_select: Mmio<u32>,
+    driver_feature: Mmio<u32>,
+    msix_config: Mmio<u16>,
+    num_queues: Mmio<u16>,
+    device_status: Mmio<u8>,
+    config_generation: Mmio<u8>,
+    queue_select: Mmio<u16>,
+    queue_size: Mmio<u16>,
+    queue_msix_vector: Mmio<u16>,
+    queue_enable: Mmio<u16>,
+    queue_notify_off: Mmio<u16>,
+    // the 64 bit addresses are written as two halves, low first
+    queue_desc: [Mmio<u32>; 2],
+    queue_driver: [Mmio<u32>; 2],
+    queue_device: [Mmio<u32>; 2],
+}
+
+pub struct Transport {
+    common: &'static CommonCfg,
+    notify: *mut u8,
+    notify_multiplier: u32, // bytes between two notify_off
+    isr: &'static Mmio<u8>,
+    device: Option<*mut u8>,
+}
+
+// the registers are only touched through their owner's lock
+unsafe impl Send for Transport {}
+
+fn set_u64(register: &[Mmio<u32>; 2], value: u64) {
+    register[0].set(value as u32);
+    register[1].set((value >> 32) as u32);
+}
+
+// the window a capability points to, mapped uncached
+fn map_window(dev: &PciDevice, cap: u8) -> *mut u8 {
+    let bar = dev.read(cap + 4) as u8;
+    let offset = dev.read(cap + 8) as u64;
+    let length = dev.read(cap + 12) as u64;
+
+    let start = PhysAddr::new(dev.get_bar(bar).as_u64() + offset);
+    vmm::get().map_mmio(start, length, CacheMode::Uncacheable)
+}
+
+impl Transport {
+    // None if the device doesn't have the modern interface
+    pub fn new(dev: &PciDevice) -> Option<Transport> {
+        let mut common = None;
+        let mut notify = None;
+        let mut isr = None;
+        let mut device = None;
+
+        for (id, cap) in dev.capabilities() {
+            if id != VENDOR_CAPABILITY {
+                continue;
+            }
+
+            // a type can show up more than once, the first one is the preferred
+            match (dev.read(cap) >> 24) as u8 {
+                COMMON_CFG if common.is_none() => common = Some(map_window(dev, cap)),
+                NOTIFY_CFG if notify.is_none() => {
+                    notify = Some((map_window(dev, cap), dev.read(cap + 16)));
+                }
+                ISR_CFG if isr.is_none() => isr = Some(map_window(dev, cap)),
+                DEVICE_CFG if device.is_none() => device = Some(map_window(dev, cap)),
+                _ => {}
+            }
+        }
+
+        let (notify, notify_multiplier) = notify?;
+
+        Some(Transport {
+            common: unsafe { &*(common? as *const CommonCfg) },
+            notify,
+            notify_multiplier,
+            isr: unsafe { &*(isr? as *const Mmio<u8>) },
+            device,
+        })
+    }
+
+    fn add_status(&self, status: u8) {
+        let common = self.common;
+        common.device_status.set(common.device_status.get() | status);
+    }
+
+    /*
+        Resets the device and agrees on the features, which are the ones in wanted that the
+        device offers. Queues can be set up afterwards, until driver_ok
+    */
+    pub fn negotiate(&self, wanted: u64) -> KResult<u64> {
+        let common = self.common;
+
+        common.device_status.set(0);
+        while common.device_status.get() != 0 {
+            core::hint::spin_loop();
+        }
+        self.add_status(ACKNOWLEDGE | DRIVER);
+
+        common.device_feature_select.set(0);
+        let mut offered = common.device_feature.get() as u64;
+        common.device_feature_select.set(1);
+        offered |= (common.device_feature.get() as u64) << 32;
+
+        if offered & VERSION_1 == 0 {
+            self.fail();
+            return Err(KError::ENODEV);
+        }
+
+        let features = offered & (wanted | VERSION_1);
+        common.driver_feature_select.set(0);
+        common.driver_feature.set(features as u32);
+        common.driver_feature_select.set(1);
+        common.driver_feature.set((features >> 32) as u32);
+
+        self.add_status(FEATURES_OK);
+        if common.device_status.get() & FEATURES_OK == 0 {
+            self.fail();
+            return Err(KError::ENODEV);
+        }
+
+        common.msix_config.set(NO_VECTOR);
+        Ok(features)
+    }
+
+    pub fn num_queues(&self) -> u16 {
+        self.common.num_queues.get()
+    }
+
+    pub fn setup_queue(&self, index: u16) -> KResult<Virtqueue> {
+        let common = self.common;
+
+        common.queue_select.set(index);
+        let max_size = common.queue_size.get();
+        if index >= self.num_queues() || max_size == 0 {
+            return Err(KError::EINVAL);
+        }
+
+        let notify_off = common.queue_notify_off.get() as usize;
+        let doorbell = unsafe { self.notify.add(notify_off * self.notify_multiplier as usize) };
+        let queue = Virtqueue::new(index, max_size, doorbell as *const Mmio<u16>)?;
+
+        common.queue_size.set(queue.size());
+        common.queue_msix_vector.set(NO_VECTOR);
+        set_u64(&common.queue_desc, queue.desc_phys());
+        set_u64(&common.queue_driver, queue.avail_phys());
+        set_u64(&common.queue_device, queue.used_phys());
+        common.queue_enable.set(1);
+
+        Ok(queue)
+    }
+
+    // the device can be used from now on
+    pub fn driver_ok(&self) {
+        self.add_status(DRIVER_OK);
+    }
+
+    // tells the device we gave up on it
+    pub fn fail(&self) {
+        self.add_status(FAILED);
+    }
+
+    // reading it acknowledges the interrupt
+    pub fn isr_status(&self) -> u8 {
+        self.isr.get()
+    }
+
+    /*
+        The configuration specific to the device, laid out as T. It changes under us, a read
+        of more than one field is consistent if config_generation is the same before and after
+    */
+    pub fn device_config<T>(&self) -> Option<&'static T> {
+        self.device.map(|device| unsafe { &*(device as *const T) })
+    }
+
+    pub fn config_generation(&self) -> u8 {
+        self.common.config_generation.get()
+    }
+}
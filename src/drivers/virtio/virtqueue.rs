@@ -0,0 +1,185 @@
+/*
+    A split virtqueue. The driver hands the device chains of buffers through the available
+    ring, each chain is a linked list in the descriptor table, and the device gives back the
+    head of each chain it's done with through the used ring, in whatever order it finishes.
+
+    All three live in dma memory, which is uncached, but the compiler still has to be kept
+    from reordering the writes, so they're volatile and fenced before the index is published
+*/
+
+use crate::arch::io::Mmio;
+use crate::error::{KError, KResult};
+use crate::mm::dma::{DmaBuffer, DmaConstraints};
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+
+// more than enough for the few requests in flight of a polled driver
+const MAX_SIZE: u16 = 64;
+
+// descriptor flags
+const NEXT: u16 = 1;
+const WRITE: u16 = 2; // the device writes to the buffer instead of reading it
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UsedElement {
+    id: u32, // the head of the chain
+    len: u32, // how much the device wrote
+}
+
+// the rings' headers, flags then idx
+const RING_HEADER: usize = 4;
+
+pub struct Buffer {
+    pub phys: u64,
+    pub len: u32,
+    pub device_writes: bool,
+}
+
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    desc: DmaBuffer,
+    avail: DmaBuffer,
+    used: DmaBuffer,
+    free: Vec<u16>, // descriptors that aren't in any chain
+    avail_idx: u16,
+    last_used: u16,
+    doorbell: *const Mmio<u16>,
+}
+
+impl Virtqueue {
+    // the device can take up to max_size entries, it has to be a power of two
+    pub fn new(index: u16, max_size: u16, doorbell: *const Mmio<u16>) -> KResult<Virtqueue> {
+        let size = max_size.min(MAX_SIZE);
+        if !size.is_power_of_two() {
+            return Err(KError::EINVAL);
+        }
+
+        let entries = size as usize;
+        let desc_len = core::mem::size_of::<Descriptor>() * entries;
+        let avail_len = RING_HEADER + 2 * entries + 2;
+        let used_len = RING_HEADER + core::mem::size_of::<UsedElement>() * entries + 2;
+
+        Ok(Virtqueue {
+            index,
+            size,
+            desc: DmaBuffer::new(desc_len, DmaConstraints::ANY)?,
+            avail: DmaBuffer::new(avail_len, DmaConstraints::ANY)?,
+            used: DmaBuffer::new(used_len, DmaConstraints::ANY)?,
+            free: (0..size).rev().collect(),
+            avail_idx: 0,
+            last_used: 0,
+            doorbell,
+        })
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub fn desc_phys(&self) -> u64 {
+        self.desc.phys()
+    }
+
+    pub fn avail_phys(&self) -> u64 {
+        self.avail.phys()
+    }
+
+    pub fn used_phys(&self) -> u64 {
+        self.used.phys()
+    }
+
+    fn descriptor(&self, index: u16) -> *mut Descriptor {
+        unsafe { self.desc.as_mut_ptr::<Descriptor>().add(index as usize) }
+    }
+
+    /*
+        Gives the device a chain of buffers, the ones it reads have to come before the ones
+        it writes. Returns the chain's head, which is what poll gives back once it's done
+    */
+    pub fn submit(&mut self, buffers: &[Buffer]) -> KResult<u16> {
+        if buffers.is_empty() {
+            return Err(KError::EINVAL);
+        }
+        if buffers.len() > self.free.len() {
+            return Err(KError::EBUSY);
+        }
+
+        let chain: Vec<u16> = (0..buffers.len()).map(|_| self.free.pop().unwrap()).collect();
+
+        for (i, buffer) in buffers.iter().enumerate() {
+            let mut flags = if buffer.device_writes { WRITE } else { 0 };
+            let next = chain.get(i + 1).copied().unwrap_or(0);
+            if i + 1 < chain.len() {
+                flags |= NEXT;
+            }
+
+            let descriptor = Descriptor {
+                addr: buffer.phys,
+                len: buffer.len,
+                flags,
+                next,
+            };
+            unsafe { write_volatile(self.descriptor(chain[i]), descriptor) };
+        }
+
+        let head = chain[0];
+        unsafe {
+            let ring = self.avail.as_mut_ptr::<u16>().add(RING_HEADER / 2);
+            write_volatile(ring.add((self.avail_idx % self.size) as usize), head);
+        }
+
+        // the device mustn't see the new index before the entry
+        fence(Ordering::SeqCst);
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+        unsafe { write_volatile(self.avail.as_mut_ptr::<u16>().add(1), self.avail_idx) };
+        fence(Ordering::SeqCst);
+
+        unsafe { (*self.doorbell).set(self.index) };
+        Ok(head)
+    }
+
+    // a chain the device is done with, its head and how many bytes it wrote
+    pub fn poll(&mut self) -> Option<(u16, u32)> {
+        let used_idx = unsafe { read_volatile(self.used.as_ptr::<u16>().add(1)) };
+        if used_idx == self.last_used {
+            return None;
+        }
+
+        // the index is read before the entry it covers
+        fence(Ordering::SeqCst);
+        let element = unsafe {
+            let ring = self.used.as_ptr::<u8>().add(RING_HEADER) as *const UsedElement;
+            read_volatile(ring.add((self.last_used % self.size) as usize))
+        };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        // the chain's descriptors can be reused
+        let mut index = element.id as u16;
+        loop {
+            let descriptor = unsafe { read_volatile(self.descriptor(index)) };
+            self.free.push(index);
+
+            if descriptor.flags & NEXT == 0 {
+                break;
+            }
+            index = descriptor.next;
+        }
+
+        Some((element.id as u16, element.len))
+    }
+}
+
+// only used through its owner's lock
+unsafe impl Send for Virtqueue {}
@@ -0,0 +1,113 @@
+use core::fmt;
+
+pub type KResult<T> = Result<T, KError>;
+
+// the same numbers as linux, so syscalls can hand them to userspace as they are
+#[repr(i64)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KError {
+    EPERM = 1,
+    ENOENT = 2,
+    EIO = 5,
+    EBADF = 9,
+    EAGAIN = 11,
+    ENOMEM = 12,
+    EACCES = 13,
+    EFAULT = 14,
+    EBUSY = 16,
+    EEXIST = 17,
+    EXDEV = 18,
+    ENODEV = 19,
+    ENOTDIR = 20,
+    EISDIR = 21,
+    EINVAL = 22,
+    ENFILE = 23,
+    EMFILE = 24,
+    ENOTTY = 25,
+    EFBIG = 27,
+    ENOSPC = 28,
+    EROFS = 30,
+    EMLINK = 31,
+    EPIPE = 32,
+    ERANGE = 34,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38,
+    ENOTEMPTY = 39,
+    ELOOP = 40,
+    ENOTSOCK = 88,
+    EDESTADDRREQ = 89,
+    EMSGSIZE = 90,
+    EPROTONOSUPPORT = 93,
+    EOPNOTSUPP = 95,
+    EADDRINUSE = 98,
+    EADDRNOTAVAIL = 99,
+    ENETUNREACH = 101,
+    ECONNRESET = 104,
+    EISCONN = 106,
+    ENOTCONN = 107,
+    ETIMEDOUT = 110,
+    ECONNREFUSED = 111,
+}
+
+impl KError {
+    pub fn errno(self) -> i64 {
+        self as i64
+    }
+
+    // what a syscall returns on failure: -errno, like linux
+    pub fn as_syscall_return(self) -> u64 {
+        (-self.errno()) as u64
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            KError::EPERM => "operation not permitted",
+            KError::ENOENT => "no such file or directory",
+            KError::EIO => "input/output error",
+            KError::EBADF => "bad file descriptor",
+            KError::EAGAIN => "resource temporarily unavailable",
+            KError::ENOMEM => "out of memory",
+            KError::EACCES => "permission denied",
+            KError::EFAULT => "bad address",
+            KError::EBUSY => "device or resource busy",
+            KError::EEXIST => "file exists",
+            KError::EXDEV => "invalid cross-device link",
+            KError::ENODEV => "no such device",
+            KError::ENOTDIR => "not a directory",
+            KError::EISDIR => "is a directory",
+            KError::EINVAL => "invalid argument",
+            KError::ENFILE => "too many open files in system",
+            KError::EMFILE => "too many open files",
+            KError::ENOTTY => "inappropriate ioctl for device",
+            KError::EFBIG => "file too large",
+            KError::ENOSPC => "no space left on device",
+            KError::EROFS => "read-only file system",
+            KError::EMLINK => "too many links",
+            KError::EPIPE => "broken pipe",
+            KError::ERANGE => "result out of range",
+            KError::ENAMETOOLONG => "file name too long",
+            KError::ENOSYS => "function not implemented",
+            KError::ENOTEMPTY => "directory not empty",
+            KError::ELOOP => "too many levels of symbolic links",
+            KError::ENOTSOCK => "socket operation on non-socket",
+            KError::EDESTADDRREQ => "destination address required",
+            KError::EMSGSIZE => "message too long",
+            KError::EPROTONOSUPPORT => "protocol not supported",
+            KError::EOPNOTSUPP => "operation not supported",
+            KError::EADDRINUSE => "address already in use",
+            KError::EADDRNOTAVAIL => "cannot assign requested address",
+            KError::ENETUNREACH => "network is unreachable",
+            KError::ECONNRESET => "connection reset by peer",
+            KError::EISCONN => "transport endpoint is already connected",
+            KError::ENOTCONN => "transport endpoint is not connected",
+            KError::ETIMEDOUT => "connection timed out",
+            KError::ECONNREFUSED => "connection refused",
+        }
+    }
+}
+
+impl fmt::Display for KError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} ({})", self, self.description())
+    }
+}
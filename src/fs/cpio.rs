@@ -0,0 +1,160 @@
+/*
+    The "newc" cpio format, what linux's tooling produces for an initramfs. Every entry is
+    a 110 byte header of ascii hex fields, followed by the name and then the data, both
+    padded to 4 bytes. The archive ends with an entry called TRAILER!!!
+
+    A module whose string starts with "initramfs" is unpacked into a ramfs at boot, which
+    can then be mounted as the root with root=initramfs
+*/
+
+use super::ramfs::Ramfs;
+use super::vfs::FileType;
+use crate::error::{KError, KResult};
+use crate::{boot, log};
+use alloc::{boxed::Box, collections::BTreeMap};
+
+const HEADER_SIZE: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+const FILE_TYPE_MASK: u32 = 0o170000;
+
+static mut INITRAMFS: Option<&'static Ramfs> = None;
+
+// the fields after the magic, in order
+#[derive(Clone, Copy)]
+enum Field {
+    Ino = 0,
+    Mode = 1,
+    Nlink = 4,
+    FileSize = 6,
+    NameSize = 11,
+}
+
+struct Header<'a> {
+    fields: &'a [u8],
+}
+
+impl<'a> Header<'a> {
+    // 070702 is the same format, with a checksum of the data in the last field
+    fn new(bytes: &'a [u8]) -> KResult<Self> {
+        if bytes.len() < HEADER_SIZE || !matches!(&bytes[..6], b"070701" | b"070702") {
+            return Err(KError::EINVAL);
+        }
+
+        Ok(Header {
+            fields: &bytes[6..HEADER_SIZE],
+        })
+    }
+
+    fn get(&self, field: Field) -> KResult<u32> {
+        let start = field as usize * 8;
+        let digits = core::str::from_utf8(&self.fields[start..start + 8])
+            .map_err(|_| KError::EINVAL)?;
+
+        u32::from_str_radix(digits, 16).map_err(|_| KError::EINVAL)
+    }
+}
+
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/*
+    Adds every entry of archive to fs, returns how many were added. Hard links share one
+    node, newc only stores their data with the last name
+*/
+pub fn unpack(archive: &[u8], fs: &Ramfs) -> KResult<usize> {
+    let mut offset = 0;
+    let mut added = 0;
+    // inode numbers of the archive to ramfs nodes
+    let mut links: BTreeMap<u32, usize> = BTreeMap::new();
+
+    loop {
+        let header = Header::new(archive.get(offset..).ok_or(KError::EINVAL)?)?;
+        let name_size = header.get(Field::NameSize)? as usize;
+        let file_size = header.get(Field::FileSize)? as usize;
+
+        let name_start = offset + HEADER_SIZE;
+        let data_start = align4(name_start + name_size);
+        let data_end = data_start + file_size;
+        if name_size == 0 || data_end > archive.len() {
+            return Err(KError::EINVAL);
+        }
+
+        // the name size counts the terminating null
+        let name = core::str::from_utf8(&archive[name_start..name_start + name_size - 1])
+            .map_err(|_| KError::EINVAL)?;
+        let data = &archive[data_start..data_end];
+        offset = align4(data_end);
+
+        if name == TRAILER {
+            return Ok(added);
+        }
+
+        let path = name.trim_start_matches("./").trim_start_matches('/');
+        if path.is_empty() || path == "." {
+            continue;
+        }
+
+        let mode = header.get(Field::Mode)?;
+        // archives don't have to list the directories before what's in them
+        if let Some((parent, _)) = path.rsplit_once('/') {
+            fs.create_dirs(parent)?;
+        }
+
+        match mode & FILE_TYPE_MASK {
+            file_type if file_type == FileType::DIRECTORY.bits() as u32 => {
+                match fs.create_dir(path, mode as u16) {
+                    // create_dirs may have already made it for an earlier entry
+                    Ok(_) | Err(KError::EEXIST) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            file_type if file_type == FileType::SYMLINK.bits() as u32 => {
+                let target = core::str::from_utf8(data).map_err(|_| KError::EINVAL)?;
+                fs.symlink(path, target)?;
+            }
+            file_type if file_type == FileType::NORMAL.bits() as u32 => {
+                let ino = header.get(Field::Ino)?;
+
+                match links.get(&ino) {
+                    Some(node) if header.get(Field::Nlink)? > 1 => {
+                        fs.link(path, *node)?;
+                        if !data.is_empty() {
+                            fs.set_data(*node, data)?;
+                        }
+                    }
+                    _ => {
+                        let node = fs.create(path, mode as u16, data)?;
+                        links.insert(ino, node);
+                    }
+                }
+            }
+            _ => {
+                log::debug!("[CPIO] Skipping {}, devices and fifos aren't supported\n", path);
+                continue;
+            }
+        }
+
+        added += 1;
+    }
+}
+
+pub fn init() {
+    let module = match boot::find("initramfs") {
+        Some(module) => module,
+        None => return,
+    };
+
+    let fs = Ramfs::new();
+    match unpack(module.data(), &fs) {
+        Ok(entries) => {
+            log::info!("[CPIO] Unpacked {} entries from the initramfs\n", entries);
+            unsafe { INITRAMFS = Some(Box::leak(Box::new(fs))) };
+        }
+        Err(err) => log::error!("[CPIO] The initramfs is not a valid newc archive: {}\n", err),
+    }
+}
+
+pub fn initramfs() -> Option<&'static Ramfs> {
+    unsafe { INITRAMFS }
+}
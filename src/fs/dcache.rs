@@ -0,0 +1,136 @@
+/*
+    Path lookups without reading directories: what inode a name in a directory was last
+    found to be, by the directory's inode number and the name. The filesystem keeps it in
+    step with its directories, every entry it adds, removes or renames is updated here too.
+
+    When it's full the entry used longest ago goes. Every use stamps an entry with the next
+    tick of a counter, and the entries are also kept by their stamp, oldest first
+*/
+
+use crate::error::KResult;
+use alloc::{collections::BTreeMap, string::String};
+
+struct Entries {
+    dirs: BTreeMap<u64, BTreeMap<String, (u64, u64)>>, // name -> (inode, stamp)
+    by_use: BTreeMap<u64, (u64, String)>,               // stamp -> (dir, name)
+    len: usize,
+    clock: u64,
+    generation: u64, // bumped by every change the filesystem makes
+}
+
+impl Entries {
+    fn get(&mut self, dir: u64, name: &str) -> Option<u64> {
+        let (inode, stamp) = self.dirs.get_mut(&dir)?.get_mut(name)?;
+
+        let old = *stamp;
+        self.clock += 1;
+        *stamp = self.clock;
+        let key = self.by_use.remove(&old).unwrap();
+        self.by_use.insert(self.clock, key);
+
+        Some(*inode)
+    }
+
+    fn remove(&mut self, dir: u64, name: &str) -> Option<u64> {
+        let names = self.dirs.get_mut(&dir)?;
+        let (inode, stamp) = names.remove(name)?;
+        if names.is_empty() {
+            self.dirs.remove(&dir);
+        }
+
+        self.by_use.remove(&stamp);
+        self.len -= 1;
+        Some(inode)
+    }
+
+    fn insert(&mut self, capacity: usize, dir: u64, name: &str, inode: u64) {
+        self.remove(dir, name);
+        if self.len == capacity {
+            let oldest = *self.by_use.keys().next().unwrap();
+            let (oldest_dir, oldest_name) = self.by_use[&oldest].clone();
+            self.remove(oldest_dir, &oldest_name);
+        }
+
+        self.clock += 1;
+        self.dirs
+            .entry(dir)
+            .or_insert_with(BTreeMap::new)
+            .insert(String::from(name), (inode, self.clock));
+        self.by_use.insert(self.clock, (dir, String::from(name)));
+        self.len += 1;
+    }
+}
+
+pub struct DentryCache {
+    capacity: usize,
+    entries: spin::Mutex<Entries>,
+}
+
+impl DentryCache {
+    pub fn new(capacity: usize) -> Self {
+        DentryCache {
+            capacity,
+            entries: spin::Mutex::new(Entries {
+                dirs: BTreeMap::new(),
+                by_use: BTreeMap::new(),
+                len: 0,
+                clock: 0,
+                generation: 0,
+            }),
+        }
+    }
+
+    /*
+        What name in dir is, from the cache or else from find, which reads the directory.
+        The answer is only kept if nothing changed while find ran, it could have read the
+        directory before the change
+    */
+    pub fn lookup(
+        &self,
+        dir: u64,
+        name: &str,
+        find: impl FnOnce() -> KResult<u64>,
+    ) -> KResult<u64> {
+        let generation = {
+            let mut entries = self.entries.lock();
+            if let Some(inode) = entries.get(dir, name) {
+                return Ok(inode);
+            }
+            entries.generation
+        };
+
+        let inode = find()?;
+
+        let mut entries = self.entries.lock();
+        if entries.generation == generation {
+            entries.insert(self.capacity, dir, name, inode);
+        }
+
+        Ok(inode)
+    }
+
+    // name was added to dir, or now points to another inode
+    pub fn insert(&self, dir: u64, name: &str, inode: u64) {
+        let mut entries = self.entries.lock();
+        entries.generation += 1;
+        entries.insert(self.capacity, dir, name, inode);
+    }
+
+    pub fn remove(&self, dir: u64, name: &str) {
+        let mut entries = self.entries.lock();
+        entries.generation += 1;
+        entries.remove(dir, name);
+    }
+
+    pub fn clear(&self) {
+        let mut entries = self.entries.lock();
+        entries.dirs.clear();
+        entries.by_use.clear();
+        entries.len = 0;
+        entries.generation += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().len
+    }
+}
@@ -0,0 +1,199 @@
+/*
+    Device files, mounted on /dev. A driver registers its device under a path like
+    input/mouse0, and the directories on the way there are made up from the registered
+    paths. File index 0 is the root, node i is file i + 1
+*/
+
+use super::vfs::{self, DirEntry, DirEntryType, PollEvents};
+use crate::error::{KError, KResult};
+use crate::log;
+use crate::proc::wait::WaitQueue;
+use crate::rcu::Rcu;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+const MOUNT_POINT: &str = "/dev";
+const ROOT: usize = 0;
+
+// what's behind a device file, the offset is only meaningful for some devices
+pub trait Device: Sync {
+    fn read(&self, buffer: *mut u8, cnt: usize, offset: usize) -> KResult<usize>;
+
+    fn write(&self, _buffer: *const u8, _cnt: usize, _offset: usize) -> KResult<usize> {
+        Err(KError::EINVAL)
+    }
+
+    fn poll(&self) -> PollEvents {
+        PollEvents::POLLIN | PollEvents::POLLOUT
+    }
+
+    fn wait_queue(&self) -> Option<&WaitQueue> {
+        None
+    }
+}
+
+#[derive(Clone)]
+struct Node {
+    path: String,                        // from /dev, without the leading slash
+    device: Option<&'static dyn Device>, // None for a directory
+}
+
+pub struct Devfs;
+
+static DEVFS: Devfs = Devfs;
+// registering is rare, looking up happens on every open
+static NODES: Rcu<Vec<Node>> = Rcu::new(Vec::new());
+
+// "" for what's in the root
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+fn node(index: usize) -> KResult<Node> {
+    index
+        .checked_sub(1)
+        .and_then(|i| NODES.read().get(i).cloned())
+        .ok_or(KError::EBADF)
+}
+
+fn device(index: usize) -> KResult<&'static dyn Device> {
+    if index == ROOT {
+        return Err(KError::EISDIR);
+    }
+
+    node(index)?.device.ok_or(KError::EISDIR)
+}
+
+// the directory's path, "" for the root
+fn directory(index: usize) -> KResult<String> {
+    if index == ROOT {
+        return Ok(String::new());
+    }
+
+    let node = node(index)?;
+    match node.device {
+        Some(_) => Err(KError::ENOTDIR),
+        None => Ok(node.path),
+    }
+}
+
+pub fn register(path: &str, device: &'static dyn Device) -> KResult<()> {
+    let path = path.trim_matches('/');
+    if path.is_empty() || path.split('/').any(|component| component.is_empty()) {
+        return Err(KError::EINVAL);
+    }
+
+    NODES.update(|nodes| {
+        if nodes.iter().any(|node| node.path == path) {
+            return Err(KError::EEXIST);
+        }
+
+        // every directory on the way, shortest first
+        let mut end = 0;
+        while let Some(slash) = path[end..].find('/') {
+            end += slash;
+            let dir = &path[..end];
+
+            match nodes.iter().find(|node| node.path == dir) {
+                Some(node) if node.device.is_some() => return Err(KError::ENOTDIR),
+                Some(_) => {}
+                None => nodes.push(Node {
+                    path: String::from(dir),
+                    device: None,
+                }),
+            }
+            end += 1;
+        }
+
+        nodes.push(Node {
+            path: String::from(path),
+            device: Some(device),
+        });
+        Ok(())
+    })
+}
+
+impl vfs::Filesystem for Devfs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn open(
+        &self,
+        path: &str,
+        flags: vfs::Flags,
+        _mode: vfs::Mode,
+    ) -> KResult<vfs::FileDescription> {
+        let path = path.trim_matches('/');
+        let index = if path.is_empty() {
+            ROOT
+        } else {
+            NODES
+                .read()
+                .iter()
+                .position(|node| node.path == path)
+                .map(|i| i + 1)
+                .ok_or(KError::ENOENT)?
+        };
+
+        let writing = flags.intersects(vfs::Flags::O_WRONLY | vfs::Flags::O_RDWR);
+        if writing && device(index).is_err() {
+            return Err(KError::EISDIR);
+        }
+
+        Ok(vfs::FileDescription::new(index, flags, &DEVFS))
+    }
+
+    fn mkdir(&self, _path: &str, _mode: vfs::Mode) -> KResult<vfs::FileDescription> {
+        Err(KError::EPERM)
+    }
+
+    fn read(&self, index: usize, buffer: *mut u8, cnt: usize, offset: usize) -> KResult<usize> {
+        device(index)?.read(buffer, cnt, offset)
+    }
+
+    fn write(&self, index: usize, buffer: *const u8, cnt: usize, offset: usize) -> KResult<usize> {
+        device(index)?.write(buffer, cnt, offset)
+    }
+
+    fn readdir(&self, index: usize, offset: usize) -> KResult<Option<DirEntry>> {
+        let dir = directory(index)?;
+        let nodes = NODES.read();
+
+        let found = nodes
+            .iter()
+            .enumerate()
+            .skip(offset)
+            .find(|(_, node)| parent(&node.path) == dir);
+
+        Ok(found.map(|(i, node)| DirEntry {
+            inode: i as u64 + 1,
+            next_offset: i + 1,
+            entry_type: match node.device {
+                Some(_) => DirEntryType::CharDevice,
+                None => DirEntryType::Directory,
+            },
+            name: String::from(node.path.rsplit('/').next().unwrap_or(&node.path)),
+        }))
+    }
+
+    fn poll(&self, index: usize) -> KResult<PollEvents> {
+        Ok(device(index)?.poll())
+    }
+
+    fn wait_queue(&self, index: usize) -> Option<&WaitQueue> {
+        device(index).ok()?.wait_queue()
+    }
+}
+
+pub fn init() {
+    match vfs::mount(&DEVFS, MOUNT_POINT) {
+        Ok(()) => log::info!("[DEVFS] Mounted on {}\n", MOUNT_POINT),
+        Err(err) => log::error!("[DEVFS] Could not mount {}: {}\n", MOUNT_POINT, err),
+    }
+
+    // the kernel's own, drivers register theirs when they find their device
+    if let Err(err) = register("kmsg", &log::KMSG_DEVICE) {
+        log::warning!("[DEVFS] Could not register kmsg: {}\n", err);
+    }
+}
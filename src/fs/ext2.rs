@@ -1,19 +1,48 @@
+use super::dcache::DentryCache;
 use super::vfs;
 use crate::arch::mm::pmm::PmmBox;
+use crate::error::{KError, KResult};
 use crate::utils::math::{div_ceil, round_up};
-use crate::{drivers::ahci, serial, utils::bitmap};
-use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use crate::drivers::aio;
+use crate::drivers::block::{BlockDevice, Priority};
+use crate::{log, utils::bitmap, vdso};
+use alloc::{boxed::Box, collections::BTreeMap, string::String, sync::Arc, vec::Vec};
 use core::intrinsics::size_of;
-use core::ops::Deref;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
 const EXT2_SIGNATURE: u16 = 0xef53;
 const ROOT_DIR_INODE: u32 = 0x2;
-const MAX_OPEN_FILE_CNT: usize = 1024;
-const INODE_TABLE_INIT: Option<Box<Inode>> = None;
-
-static mut EXT2_FS: Option<Arc<Ext2Filesystem>> = None;
-static mut INODE_TABLE: [Option<Box<Inode>>; MAX_OPEN_FILE_CNT] =
-    [INODE_TABLE_INIT; MAX_OPEN_FILE_CNT];
+// per open file, 64 pointer blocks cover at least 16MiB of it
+const MAX_CACHED_INDIRECT_BLOCKS: usize = 64;
+// names in directories, per filesystem
+const DENTRY_CACHE_SIZE: usize = 1024;
+// the most an open file reads ahead at once
+const MAX_READAHEAD: usize = 128 * 1024;
+// what rev 0 has, later ones can have bigger inodes, but we only use the first 128 bytes
+const GOOD_OLD_INODE_SIZE: usize = 128;
+// the first inode files can have, rev 0 reserves the ones before it
+const GOOD_OLD_FIRST_INODE: u32 = 11;
+// the most a regular file can hold without LARGE_FILE, old drivers read the size as signed
+const MAX_SMALL_FILE_SIZE: usize = 0x7fff_ffff;
+// the direct and indirect block pointers, where a fast symlink's target is
+const FAST_SYMLINK_MAX: usize = 60;
+
+/*
+    The feature flags of the extended superblock (revision 1 and later). A filesystem with
+    an incompat feature we don't know can't be mounted, one with an unknown ro_compat
+    feature can only be read
+*/
+const INCOMPAT_FILETYPE: u32 = 0x2; // directory entries have a type
+const SUPPORTED_INCOMPAT: u32 = INCOMPAT_FILETYPE;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1; // only some groups have a backup superblock
+const RO_COMPAT_LARGE_FILE: u32 = 0x2; // regular files have the high 32 bits of their size
+const SUPPORTED_RO_COMPAT: u32 = RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE;
+// where the superblock's unallocated block and inode counts are, one after the other
+const SUPERBLOCK_COUNTS_OFFSET: usize = 12;
+// the mount and write times, the mount count, the max mount count, the signature and the state
+const SUPERBLOCK_STATE_OFFSET: usize = 44;
+const STATE_VALID: u16 = 1; // cleanly unmounted, it's cleared while mounted
+const STATE_ERRORS: u16 = 2;
 
 #[repr(C, packed)]
 pub struct Superblock {
@@ -42,20 +71,67 @@ pub struct Superblock {
     maj_version: u32,
     user_id: u16,
     group_id: u16,
+    // the extended superblock, all zeroes on revision 0
+    first_inode: u32,
+    inode_size: u16,
+    superblock_group: u16,
+    features_compat: u32,
+    features_incompat: u32,
+    features_ro_compat: u32,
 }
 
 impl Superblock {
-    pub fn flush(&self) {
-        let fs = unsafe { EXT2_FS.clone().unwrap() };
+    pub fn inode_size(&self) -> usize {
+        if self.maj_version >= 1 {
+            self.inode_size as usize
+        } else {
+            GOOD_OLD_INODE_SIZE
+        }
+    }
+
+    fn unsupported_incompat(&self) -> u32 {
+        if self.maj_version >= 1 {
+            self.features_incompat & !SUPPORTED_INCOMPAT
+        } else {
+            0
+        }
+    }
+
+    fn unsupported_ro_compat(&self) -> u32 {
+        if self.maj_version >= 1 {
+            self.features_ro_compat & !SUPPORTED_RO_COMPAT
+        } else {
+            0
+        }
+    }
+
+    fn has_file_types(&self) -> bool {
+        self.maj_version >= 1 && self.features_incompat & INCOMPAT_FILETYPE != 0
+    }
+
+    fn has_large_files(&self) -> bool {
+        self.maj_version >= 1 && self.features_ro_compat & RO_COMPAT_LARGE_FILE != 0
+    }
+
+    fn first_inode(&self) -> u32 {
+        if self.maj_version >= 1 {
+            self.first_inode
+        } else {
+            GOOD_OLD_FIRST_INODE
+        }
+    }
+
+    pub fn flush(&self, fs: &Ext2Filesystem) -> KResult<()> {
         let starting_lba = fs.starting_lba;
 
-        ahci::write(
-            0,
+        fs.device.write_with(
             (starting_lba as u64 + 2) * 512,
             size_of::<Superblock>(),
             self as *const Superblock as *const u8,
-        )
-        .unwrap();
+            Priority::Metadata,
+        )?;
+
+        Ok(())
     }
 }
 
@@ -71,170 +147,384 @@ struct BlockGroupDescriptor {
     unused: [u8; 14],
 }
 
-#[repr(C)]
-struct BlockGroup {
-    raw: BlockGroupDescriptor,
-    index: usize,
+/*
+    The block group descriptor table, read whole on first use and kept in memory along
+    with the groups' bitmaps. Changes to it stay in memory until sync writes the table back
+    in one go, which kflushd does every few seconds
+*/
+struct Groups {
+    descriptors: Vec<BlockGroupDescriptor>, // empty until the table is read
+    dirty: bool,
+    bitmaps: Vec<GroupBitmaps>,
 }
 
-impl BlockGroup {
-    pub fn get(block_group_index: usize) -> Box<BlockGroup> {
-        let fs = unsafe { EXT2_FS.clone().unwrap() };
-        let starting_lba = fs.starting_lba;
-        let block_size = fs.block_size;
-
-        let bgdt_block = if block_size > 1024 { 1 } else { 2 };
-        let block_group = unsafe {
-            alloc::alloc::alloc(alloc::alloc::Layout::new::<BlockGroup>()) as *mut BlockGroup
-        };
+impl Groups {
+    fn table_offset(fs: &Ext2Filesystem) -> u64 {
+        let bgdt_block = if fs.block_size > 1024 { 1 } else { 2 };
+        (fs.starting_lba * 512 + bgdt_block * fs.block_size) as u64
+    }
 
-        ahci::read(
-            0,
-            (starting_lba * 512
-                + bgdt_block * block_size
-                + block_group_index * size_of::<BlockGroupDescriptor>()) as u64,
-            size_of::<BlockGroupDescriptor>(),
-            block_group as *mut u8,
-        )
-        .unwrap();
+    fn load(&mut self, fs: &Ext2Filesystem) -> KResult<()> {
+        if !self.descriptors.is_empty() {
+            return Ok(());
+        }
 
-        let mut block_group = unsafe { Box::from_raw(block_group) };
-        block_group.index = block_group_index;
-        block_group
+        let mut descriptors: Vec<BlockGroupDescriptor> = Vec::with_capacity(fs.block_group_cnt);
+        fs.device.read_with(
+            Groups::table_offset(fs),
+            fs.block_group_cnt * size_of::<BlockGroupDescriptor>(),
+            descriptors.as_mut_ptr() as *mut u8,
+            Priority::Metadata,
+        )?;
+
+        // the descriptors are plain data, all of them were just read
+        unsafe { descriptors.set_len(fs.block_group_cnt) };
+        self.descriptors = descriptors;
+        Ok(())
     }
 
-    // writes all the changes made to this block group descriptor back to the disk
-    pub fn flush(&self) {
-        let fs = unsafe { EXT2_FS.clone().unwrap() };
-        let starting_lba = fs.starting_lba;
-        let block_size = fs.block_size;
+    /*
+        Writes the dirty bitmaps and, if anything in it changed, the whole table. They're all
+        submitted at once, so the device's queue gets to sort them
+    */
+    fn flush(&mut self, fs: &Ext2Filesystem) -> KResult<()> {
+        let mut requests: Vec<aio::Request> = self
+            .bitmaps
+            .iter()
+            .flat_map(|group| [group.block.as_ref(), group.inode.as_ref()])
+            .flatten()
+            .filter(|bitmap| bitmap.dirty)
+            .map(|bitmap| bitmap.write_request(fs))
+            .collect();
+
+        if self.dirty {
+            let table = unsafe {
+                core::slice::from_raw_parts(
+                    self.descriptors.as_ptr() as *const u8,
+                    self.descriptors.len() * size_of::<BlockGroupDescriptor>(),
+                )
+            };
+            requests.push(aio::Request::write(
+                fs.device.clone(),
+                Groups::table_offset(fs),
+                table.to_vec(),
+                Priority::Metadata,
+            ));
+        }
+
+        for completion in aio::run_all(requests) {
+            completion.result?;
+        }
 
-        let bgdt_block = if block_size > 1024 { 1 } else { 2 };
+        for group in self.bitmaps.iter_mut() {
+            for bitmap in [group.block.as_mut(), group.inode.as_mut()].into_iter().flatten() {
+                bitmap.dirty = false;
+            }
+        }
+        self.dirty = false;
+        Ok(())
+    }
 
-        ahci::write(
-            0,
-            (starting_lba * 512
-                + bgdt_block * block_size
-                + self.index * size_of::<BlockGroupDescriptor>()) as u64,
-            size_of::<BlockGroupDescriptor>(),
-            self as *const BlockGroup as *const u8,
-        )
-        .unwrap();
+    fn inode_table(&self, group: usize) -> u32 {
+        self.descriptors[group].inode_table
     }
 
-    pub fn get_inode(&self, inode_addr: u32) -> Box<Inode> {
-        let fs = unsafe { EXT2_FS.clone().unwrap() };
-        let starting_lba = fs.starting_lba;
-        let block_size = fs.block_size;
+    fn add_directory(&mut self, group: usize) {
+        self.descriptors[group].directories_cnt += 1;
+        self.dirty = true;
+    }
 
-        let inode_index = Inode::get_table_index(inode_addr as usize);
+    pub fn alloc_block(
+        &mut self,
+        fs: &Ext2Filesystem,
+        group: usize,
+        block_cnt: usize,
+    ) -> KResult<Vec<u32>> {
+        let descriptor = &mut self.descriptors[group];
+        if (descriptor.unallocated_blocks as usize) < block_cnt {
+            return Err(KError::ENOSPC);
+        }
 
-        let inode =
-            unsafe { alloc::alloc::alloc(alloc::alloc::Layout::new::<Inode>()) as *mut Inode };
+        let block_bitmap =
+            CachedBitmap::cached(&mut self.bitmaps[group].block, fs, descriptor.block_bitmap)?;
 
-        ahci::read(
-            0,
-            (starting_lba * 512
-                + self.raw.inode_table as usize * block_size
-                + inode_index * size_of::<Inode>()) as u64,
-            size_of::<Inode>(),
-            inode as *mut u8,
-        )
-        .unwrap();
+        // the free bits are found first, so nothing is taken if there aren't enough of them
+        let free: Vec<usize> = (0..fs.block_size * 8)
+            .filter(|i| !block_bitmap.bitmap.is_set(*i))
+            .take(block_cnt)
+            .collect();
+        if free.len() != block_cnt {
+            return Err(KError::ENOSPC);
+        }
 
-        let mut inode = unsafe { Box::from_raw(inode) };
-        // might already be set to the inode addr, but just in case
-        inode.inode_number = inode_addr;
-        inode
-    }
+        let mut blocks = Vec::with_capacity(block_cnt);
+        for i in free {
+            block_bitmap.bitmap.set(i);
+            blocks.push(i as u32 + group as u32 * fs.superblock.blocks_per_group);
+
+            descriptor.unallocated_blocks -= 1;
+            fs.count_blocks(-1);
+        }
 
-    pub fn alloc_block(&mut self, block_cnt: usize) -> Option<Vec<u32>> {
-        if (self.raw.unallocated_blocks as usize) < block_cnt {
-            return None;
+        if block_cnt != 0 {
+            block_bitmap.dirty = true;
+            self.dirty = true;
         }
 
-        let fs = unsafe { EXT2_FS.clone().unwrap() };
+        Ok(blocks)
+    }
 
-        let mut block_bitmap = bitmap::Bitmap::new(fs.block_size);
+    pub fn alloc_inode(&mut self, fs: &Ext2Filesystem, group: usize) -> KResult<u32> {
+        let descriptor = &mut self.descriptors[group];
+        if descriptor.unallocated_inodes == 0 {
+            return Err(KError::ENOSPC);
+        }
 
-        ahci::read(
-            0,
-            (fs.starting_lba * 512 + self.raw.block_bitmap as usize * fs.block_size) as u64,
-            fs.block_size,
-            block_bitmap.as_mut_ptr(),
-        )
-        .unwrap();
+        let inode_bitmap =
+            CachedBitmap::cached(&mut self.bitmaps[group].inode, fs, descriptor.inode_bitmap)?;
 
-        let mut allocated = 0;
-        let mut blocks = Vec::new();
         for i in 0..fs.block_size * 8 {
-            if !block_bitmap.is_set(i) {
-                block_bitmap.set(i);
-                blocks.push(i as u32 + self.index as u32 * fs.superblock.blocks_per_group);
-                allocated += 1;
+            if !inode_bitmap.bitmap.is_set(i) {
+                inode_bitmap.bitmap.set(i);
+                inode_bitmap.dirty = true;
+                descriptor.unallocated_inodes -= 1;
+                fs.count_inodes(-1);
+                self.dirty = true;
+
+                return Ok((i + 1 + group * fs.superblock.inodes_per_group as usize) as u32);
+            }
+        }
 
-                self.raw.unallocated_blocks -= 1;
+        Err(KError::ENOSPC)
+    }
 
-                if allocated == block_cnt {
-                    break;
-                }
-            }
+    // the numbers of the group's allocated inodes
+    fn used_inodes(&mut self, fs: &Ext2Filesystem, group: usize) -> KResult<Vec<u32>> {
+        let descriptor = &self.descriptors[group];
+        let inode_bitmap =
+            CachedBitmap::cached(&mut self.bitmaps[group].inode, fs, descriptor.inode_bitmap)?;
+        let first = group * fs.superblock.inodes_per_group as usize + 1;
+
+        Ok((0..fs.superblock.inodes_per_group as usize)
+            .filter(|&i| inode_bitmap.bitmap.is_set(i))
+            .map(|i| (first + i) as u32)
+            .collect())
+    }
+
+    // clears bit in the group's block or inode bitmap, false if it was clear already
+    fn release(
+        &mut self,
+        fs: &Ext2Filesystem,
+        group: usize,
+        inode_bitmap: bool,
+        bit: usize,
+    ) -> KResult<bool> {
+        let descriptor = &self.descriptors[group];
+        let bitmaps = &mut self.bitmaps[group];
+        let cached = if inode_bitmap {
+            CachedBitmap::cached(&mut bitmaps.inode, fs, descriptor.inode_bitmap)?
+        } else {
+            CachedBitmap::cached(&mut bitmaps.block, fs, descriptor.block_bitmap)?
+        };
+
+        if !cached.bitmap.is_set(bit) {
+            return Ok(false);
+        }
+
+        cached.bitmap.clear(bit);
+        cached.dirty = true;
+        Ok(true)
+    }
+
+    pub fn free_block(&mut self, fs: &Ext2Filesystem, group: usize, block: u32) -> KResult<()> {
+        // the inverse of how alloc_block numbers them
+        let bit = (block - group as u32 * fs.superblock.blocks_per_group) as usize;
+
+        if self.release(fs, group, false, bit)? {
+            self.descriptors[group].unallocated_blocks += 1;
+            fs.count_blocks(1);
+            self.dirty = true;
         }
 
-        if allocated != block_cnt {
-            return None;
+        Ok(())
+    }
+
+    pub fn free_inode(
+        &mut self,
+        fs: &Ext2Filesystem,
+        group: usize,
+        inode: u32,
+        directory: bool,
+    ) -> KResult<()> {
+        let bit = Inode::get_table_index(fs, inode as usize);
+
+        if self.release(fs, group, true, bit)? {
+            let descriptor = &mut self.descriptors[group];
+            descriptor.unallocated_inodes += 1;
+            if directory {
+                descriptor.directories_cnt -= 1;
+            }
+            fs.count_inodes(1);
+            self.dirty = true;
         }
 
-        ahci::write(
-            0,
-            (fs.starting_lba * 512 + self.raw.block_bitmap as usize * fs.block_size) as u64,
+        Ok(())
+    }
+}
+
+// in-memory copy of a block group's block or inode bitmap, only written back on sync
+struct CachedBitmap {
+    block: u32,
+    bitmap: bitmap::Bitmap,
+    dirty: bool,
+}
+
+impl CachedBitmap {
+    fn load(fs: &Ext2Filesystem, block: u32) -> KResult<Self> {
+        let mut bitmap = bitmap::Bitmap::new(fs.block_size);
+
+        fs.device.read_with(
+            (fs.starting_lba * 512 + block as usize * fs.block_size) as u64,
             fs.block_size,
-            block_bitmap.as_ptr(),
+            bitmap.as_mut_ptr(),
+            Priority::Metadata,
+        )?;
+
+        Ok(CachedBitmap {
+            block,
+            bitmap,
+            dirty: false,
+        })
+    }
+
+    // the bitmap in slot, read from block first if it isn't cached yet
+    fn cached<'a>(
+        slot: &'a mut Option<CachedBitmap>,
+        fs: &Ext2Filesystem,
+        block: u32,
+    ) -> KResult<&'a mut CachedBitmap> {
+        if slot.is_none() {
+            *slot = Some(CachedBitmap::load(fs, block)?);
+        }
+
+        Ok(slot.as_mut().unwrap())
+    }
+
+    fn write_request(&self, fs: &Ext2Filesystem) -> aio::Request {
+        let bitmap = unsafe { core::slice::from_raw_parts(self.bitmap.as_ptr(), fs.block_size) };
+
+        aio::Request::write(
+            fs.device.clone(),
+            (fs.starting_lba * 512 + self.block as usize * fs.block_size) as u64,
+            bitmap.to_vec(),
+            Priority::Metadata,
         )
-        .unwrap();
+    }
+}
+
+struct GroupBitmaps {
+    block: Option<CachedBitmap>,
+    inode: Option<CachedBitmap>,
+}
+
+/*
+    The indirect blocks of a file that were already read, keyed by their block number. Open
+    files keep theirs around between reads, anything that changes the pointers of the file
+    has to invalidate it
+*/
+// a run of the file's blocks being read in the background, by where they are on the disk
+struct ReadAhead {
+    offset: u64,
+    bytes: usize,
+    pending: Option<aio::Handle>,
+    data: Vec<u8>, // once it's done
+}
 
-        self.flush();
+pub struct BlockMap {
+    blocks: BTreeMap<u32, Box<[u32]>>,
+    readahead: Option<ReadAhead>,
+}
 
-        Some(blocks)
+impl BlockMap {
+    pub fn new() -> Self {
+        BlockMap {
+            blocks: BTreeMap::new(),
+            readahead: None,
+        }
     }
 
-    pub fn alloc_inode(&mut self) -> Option<u32> {
-        if self.raw.unallocated_inodes == 0 {
-            return None;
+    fn start_readahead(&mut self, fs: &Ext2Filesystem, offset: u64, bytes: usize) {
+        if let Some(readahead) = self.readahead.as_ref() {
+            if offset >= readahead.offset
+                && offset + bytes as u64 <= readahead.offset + readahead.bytes as u64
+            {
+                return;
+            }
         }
 
-        let fs = unsafe { EXT2_FS.clone().unwrap() };
+        let request = aio::Request::read(fs.device.clone(), offset, bytes, Priority::Data);
+        self.readahead = Some(ReadAhead {
+            offset,
+            bytes,
+            pending: Some(aio::submit(request)),
+            data: Vec::new(),
+        });
+    }
 
-        let mut inode_bitmap = bitmap::Bitmap::new(fs.block_size);
+    // copies the range out of what was read ahead, false if it isn't all there
+    fn read_ahead(&mut self, offset: u64, bytes: usize, buffer: *mut u8) -> bool {
+        let readahead = match self.readahead.as_mut() {
+            Some(readahead)
+                if offset >= readahead.offset
+                    && offset + bytes as u64 <= readahead.offset + readahead.bytes as u64 =>
+            {
+                readahead
+            }
+            _ => return false,
+        };
 
-        ahci::read(
-            0,
-            (fs.starting_lba * 512 + self.raw.inode_bitmap as usize * fs.block_size) as u64,
-            fs.block_size,
-            inode_bitmap.as_mut_ptr(),
-        )
-        .unwrap();
+        if let Some(pending) = readahead.pending.take() {
+            let completion = pending.wait();
+            if completion.result.is_err() {
+                self.readahead = None;
+                return false;
+            }
+            readahead.data = completion.buffer;
+        }
 
-        for i in 0..fs.block_size * 8 {
-            if !inode_bitmap.is_set(i) {
-                inode_bitmap.set(i);
-                self.raw.unallocated_inodes -= 1;
-
-                ahci::write(
-                    0,
-                    (fs.starting_lba * 512 + self.raw.inode_bitmap as usize * fs.block_size) as u64,
-                    fs.block_size,
-                    inode_bitmap.as_ptr(),
-                )
-                .unwrap();
+        let start = (offset - readahead.offset) as usize;
+        unsafe { buffer.copy_from(readahead.data[start..].as_ptr(), bytes) };
+        true
+    }
 
-                self.flush();
+    // the index-th pointer of the indirect block, a hole if the indirect block is one too
+    fn entry(&mut self, fs: &Ext2Filesystem, block: u32, index: usize) -> KResult<u32> {
+        if block == 0 {
+            return Ok(0);
+        }
 
-                return Some((i + 1 + self.index * fs.superblock.inodes_per_group as usize) as u32);
+        if !self.blocks.contains_key(&block) {
+            if self.blocks.len() == MAX_CACHED_INDIRECT_BLOCKS {
+                let oldest = *self.blocks.keys().next().unwrap();
+                self.blocks.remove(&oldest);
             }
+
+            let mut pointers = alloc::vec![0u32; fs.block_size / 4].into_boxed_slice();
+            fs.device.read_with(
+                (fs.starting_lba * 512 + block as usize * fs.block_size) as u64,
+                fs.block_size,
+                pointers.as_mut_ptr() as *mut u8,
+                Priority::Metadata,
+            )?;
+
+            self.blocks.insert(block, pointers);
         }
 
-        None
+        Ok(self.blocks[&block][index])
+    }
+
+    pub fn invalidate(&mut self) {
+        self.blocks.clear();
     }
 }
 
@@ -265,248 +555,380 @@ pub struct Inode {
 }
 
 impl Inode {
-    pub fn get_block_group(inode: usize) -> usize {
-        let fs = unsafe { EXT2_FS.clone().unwrap() };
+    // a zeroed inode, whatever a deleted one left in the table mustn't show through
+    fn new(inode_number: u32, type_and_permissions: u16) -> Box<Inode> {
+        let mut inode = unsafe {
+            Box::from_raw(
+                alloc::alloc::alloc_zeroed(alloc::alloc::Layout::new::<Inode>()) as *mut Inode,
+            )
+        };
+
+        inode.type_and_permissions = type_and_permissions;
+        inode.inode_number = inode_number;
+        inode
+    }
+
+    pub fn get_block_group(fs: &Ext2Filesystem, inode: usize) -> usize {
         (inode - 1) / fs.superblock.inodes_per_group as usize
     }
 
-    pub fn get_table_index(inode: usize) -> usize {
-        let fs = unsafe { EXT2_FS.clone().unwrap() };
+    pub fn get_table_index(fs: &Ext2Filesystem, inode: usize) -> usize {
         (inode - 1) % fs.superblock.inodes_per_group as usize
     }
 
+    pub fn attributes(&self) -> vfs::Attributes {
+        vfs::Attributes {
+            permissions: vfs::FilePermissions::from_bits_truncate(self.type_and_permissions),
+            uid: self.user_id as u32,
+            gid: self.group_id as u32,
+        }
+    }
+
+    // a new file belongs to whoever creates it
+    fn set_owner_to_caller(&mut self) {
+        let (uid, gid) = vfs::credentials();
+        self.user_id = uid as u16;
+        self.group_id = gid as u16;
+    }
+
+    // the type is a number in the top 4 bits, not flags, a symlink has NORMAL's bit too
+    fn file_type(&self) -> u16 {
+        self.type_and_permissions & 0xf000
+    }
+
     pub fn is_directory(&self) -> bool {
-        self.type_and_permissions & vfs::FileType::DIRECTORY.bits() != 0
+        self.file_type() == vfs::FileType::DIRECTORY.bits()
     }
 
     pub fn is_regular_file(&self) -> bool {
-        self.type_and_permissions & vfs::FileType::NORMAL.bits() != 0
+        self.file_type() == vfs::FileType::NORMAL.bits()
     }
 
     pub fn is_symlink(&self) -> bool {
-        self.type_and_permissions & vfs::FileType::SYMLINK.bits() != 0
+        self.file_type() == vfs::FileType::SYMLINK.bits()
+    }
+
+    /*
+        A target shorter than the 60 bytes of block pointers is kept in them (a fast
+        symlink), a longer one in a data block. Fast ones have no blocks at all
+    */
+    fn is_fast_symlink(&self) -> bool {
+        self.is_symlink() && self.sectors_used == 0
+    }
+
+    fn inline_data(&self) -> &[u8; FAST_SYMLINK_MAX] {
+        unsafe { &*(core::ptr::addr_of!(self.direct_pointer) as *const [u8; FAST_SYMLINK_MAX]) }
+    }
+
+    fn inline_data_mut(&mut self) -> &mut [u8; FAST_SYMLINK_MAX] {
+        let pointers = core::ptr::addr_of_mut!(self.direct_pointer);
+        unsafe { &mut *(pointers as *mut [u8; FAST_SYMLINK_MAX]) }
+    }
+
+    // what a directory entry pointing to it has as its type, 0 without INCOMPAT_FILETYPE
+    fn entry_type(&self, fs: &Ext2Filesystem) -> u8 {
+        if !fs.superblock.has_file_types() {
+            return 0;
+        }
+
+        match self.file_type() {
+            0x8000 => 1, // regular file
+            0x4000 => 2, // directory
+            0x2000 => 3, // character device
+            0x6000 => 4, // block device
+            0x1000 => 5, // fifo
+            0xc000 => 6, // socket
+            0xa000 => 7, // symlink
+            _ => 0,
+        }
     }
 
-    pub fn flush(&self) {
-        let fs = unsafe { EXT2_FS.clone().unwrap() };
+    // only regular files have the high half, for the rest it's the directory acl
+    pub fn size(&self) -> usize {
+        let high = if self.is_regular_file() { self.sizeh_dir_acl } else { 0 };
+        (high as usize) << 32 | self.sizel as usize
+    }
+
+    fn set_size(&mut self, size: usize) {
+        self.sizel = size as u32;
+        if self.is_regular_file() {
+            self.sizeh_dir_acl = (size >> 32) as u32;
+        }
+    }
+
+    fn max_size(&self, fs: &Ext2Filesystem) -> usize {
+        match self.is_regular_file() {
+            true if fs.superblock.has_large_files() => usize::MAX,
+            true => MAX_SMALL_FILE_SIZE,
+            false => u32::MAX as usize,
+        }
+    }
+
+    pub fn flush(&self, fs: &Ext2Filesystem) -> KResult<()> {
         let starting_lba = fs.starting_lba;
         let block_size = fs.block_size;
 
-        let inode_table = BlockGroup::get(Inode::get_block_group(self.inode_number as usize))
-            .raw
-            .inode_table;
-        let inode_index = Inode::get_table_index(self.inode_number as usize);
+        let block_group = Inode::get_block_group(fs, self.inode_number as usize);
+        let inode_table = fs.groups()?.inode_table(block_group);
+        let inode_index = Inode::get_table_index(fs, self.inode_number as usize);
 
-        ahci::write(
-            0,
+        fs.device.write_with(
             (starting_lba * 512
                 + inode_table as usize * block_size
-                + inode_index as usize * size_of::<Inode>()) as u64,
+                + inode_index as usize * fs.inode_size) as u64,
             size_of::<Inode>(),
             self as *const Inode as *const u8,
-        )
-        .unwrap();
+            Priority::Metadata,
+        )?;
+
+        Ok(())
     }
 
-    // TODO: test it
-    pub fn resize(&mut self, new_size: usize) {
-        if new_size == self.sizel as usize {
-            return;
+    // frees every data block and the indirect blocks pointing to them, the inode is left empty
+    fn free_blocks(&mut self, fs: &Ext2Filesystem) -> KResult<()> {
+        // the pointers are the target's bytes
+        if self.is_fast_symlink() {
+            self.inline_data_mut().fill(0);
+            self.set_size(0);
+            return Ok(());
         }
 
-        let fs = unsafe { EXT2_FS.clone().unwrap() };
+        let direct = self.direct_pointer;
+        for block in direct.iter().copied().filter(|block| *block != 0) {
+            fs.free_block(block)?;
+        }
 
-        let new_block_cnt = div_ceil(new_size, fs.block_size);
-        let old_block_cnt = div_ceil(self.sizel as usize, fs.block_size);
+        fs.free_indirect(self.singly_ip, 1)?;
+        fs.free_indirect(self.doubly_ip, 2)?;
+        fs.free_indirect(self.triply_ip, 3)?;
+
+        self.direct_pointer = [0; 12];
+        self.singly_ip = 0;
+        self.doubly_ip = 0;
+        self.triply_ip = 0;
+        self.set_size(0);
+        self.sectors_used = 0;
+        Ok(())
+    }
 
-        if new_block_cnt == old_block_cnt {
-            return;
+    // growing the file leaves a hole, its blocks are only allocated once they're written
+    pub fn resize(&mut self, fs: &Ext2Filesystem, new_size: usize) -> KResult<()> {
+        if new_size == self.size() {
+            return Ok(());
         }
 
-        if new_block_cnt > old_block_cnt {
-            for i in old_block_cnt..new_block_cnt {
-                let new_block = fs
-                    .alloc_block()
-                    .expect("[EXT2] Could not allocate a new block");
+        if new_size > self.max_size(fs) {
+            return Err(KError::EFBIG);
+        }
 
-                self.set_block_address(i, new_block);
-            }
-        } else {
-            // TODO: free the blocks
+        // TODO: free the blocks past the end when it shrinks
+        self.set_size(new_size);
+        self.flush(fs)
+    }
+
+    // allocates the block for the hole at block_index, zeroed unless it's written whole
+    fn fill_hole(
+        &mut self,
+        fs: &Ext2Filesystem,
+        map: &mut BlockMap,
+        block_index: usize,
+        whole: bool,
+    ) -> KResult<u32> {
+        let block = if whole { fs.alloc_block()? } else { fs.alloc_zeroed_block()? };
+        self.set_block_address(fs, block_index, block)?;
+        self.sectors_used += (fs.block_size / 512) as u32;
+
+        // the pointer went into an indirect block behind the map's back
+        if block_index >= 12 {
+            map.invalidate();
         }
 
-        self.sizel = new_size as u32;
-        self.sectors_used = ((new_block_cnt * fs.block_size) / 512) as u32;
-        self.flush();
+        Ok(block)
+    }
+
+    // for a one-off read, the block map only lives as long as the call
+    pub fn read(
+        &self,
+        fs: &Ext2Filesystem,
+        offset: usize,
+        bytes: usize,
+        buffer: *mut u8,
+    ) -> KResult<usize> {
+        self.read_mapped(fs, &mut BlockMap::new(), offset, bytes, buffer)
     }
 
-    pub fn read(&self, offset: usize, bytes: usize, buffer: *mut u8) -> Result<usize, ()> {
-        let fs = unsafe { EXT2_FS.clone().unwrap() };
+    pub fn read_mapped(
+        &self,
+        fs: &Ext2Filesystem,
+        map: &mut BlockMap,
+        offset: usize,
+        bytes: usize,
+        buffer: *mut u8,
+    ) -> KResult<usize> {
         let block_size = fs.block_size;
         let starting_lba = fs.starting_lba;
 
+        if self.is_fast_symlink() {
+            let data = self.inline_data().get(offset..offset + bytes).ok_or(KError::EINVAL)?;
+            unsafe { buffer.copy_from(data.as_ptr(), bytes) };
+            return Ok(bytes);
+        }
+
         let mut bytes_read = 0;
-        let mut blocks_read = 0;
 
+        // the first and last blocks may only be partially read
         while bytes_read < bytes {
-            let block_address = self.get_block_address(offset / block_size + blocks_read);
-            serial::print!("block address: {}\n", block_address);
-            let count = if bytes_read + block_size <= bytes {
-                block_size
-            } else {
-                bytes % block_size
-            };
+            let position = offset + bytes_read;
+            let block_address = self.get_block_address(fs, map, position / block_size)?;
+            log::debug!("[EXT2] block address: {}\n", block_address);
+            let block_offset = position % block_size;
+            let count = (block_size - block_offset).min(bytes - bytes_read);
+
+            // a hole, block 0 is the boot block and the superblock
+            if block_address == 0 {
+                unsafe { buffer.add(bytes_read).write_bytes(0, count) };
+                bytes_read += count;
+                continue;
+            }
 
-            ahci::read(
-                0,
-                (starting_lba * 512 + block_address as usize * block_size + offset) as u64,
-                count,
-                buffer,
-            )?;
+            let disk_offset =
+                (starting_lba * 512 + block_address as usize * block_size + block_offset) as u64;
+            let destination = unsafe { buffer.add(bytes_read) };
+            if !map.read_ahead(disk_offset, count, destination) {
+                fs.device.read(disk_offset, count, destination)?;
+            }
 
-            blocks_read += 1;
             bytes_read += count;
         }
 
         Ok(bytes_read)
     }
 
-    pub fn write(&mut self, offset: usize, bytes: usize, buffer: *const u8) -> Result<usize, ()> {
-        let fs = unsafe { EXT2_FS.clone().unwrap() };
+    pub fn write(
+        &mut self,
+        fs: &Ext2Filesystem,
+        offset: usize,
+        bytes: usize,
+        buffer: *const u8,
+    ) -> KResult<usize> {
+        self.write_mapped(fs, &mut BlockMap::new(), offset, bytes, buffer)
+    }
+
+    pub fn write_mapped(
+        &mut self,
+        fs: &Ext2Filesystem,
+        map: &mut BlockMap,
+        offset: usize,
+        bytes: usize,
+        buffer: *const u8,
+    ) -> KResult<usize> {
         let block_size = fs.block_size;
         let starting_lba = fs.starting_lba;
 
+        // there are no blocks to write to, the pointers hold the target
+        if self.is_fast_symlink() {
+            return Err(KError::EINVAL);
+        }
+
         let mut bytes_written = 0;
-        let mut blocks_written = 0;
+        let mut filled = false;
+        // it could be reading what's about to be overwritten
+        map.readahead = None;
 
-        self.resize(offset + bytes);
+        if offset + bytes > self.size() {
+            self.resize(fs, offset + bytes)?;
+        }
 
         while bytes_written < bytes {
-            let block_address = self.get_block_address(offset / block_size + blocks_written);
-            serial::print!("block address: {}\n", block_address);
-            let count = if bytes_written + block_size <= bytes {
-                block_size
-            } else {
-                bytes % block_size
-            };
+            let position = offset + bytes_written;
+            let block_index = position / block_size;
+            let block_offset = position % block_size;
+            let count = (block_size - block_offset).min(bytes - bytes_written);
+
+            let mut block_address = self.get_block_address(fs, map, block_index)?;
+            if block_address == 0 {
+                block_address = self.fill_hole(fs, map, block_index, count == block_size)?;
+                filled = true;
+            }
+            log::debug!("[EXT2] block address: {}\n", block_address);
 
-            ahci::write(
-                0,
-                (starting_lba * 512 + block_address as usize * block_size + offset) as u64,
+            fs.device.write(
+                (starting_lba * 512 + block_address as usize * block_size + block_offset) as u64,
                 count,
-                buffer,
+                unsafe { buffer.add(bytes_written) },
             )?;
 
-            blocks_written += 1;
             bytes_written += count;
         }
 
+        // sectors_used went up
+        if filled {
+            self.flush(fs)?;
+        }
+
         Ok(bytes_written)
     }
 
-    pub fn get_block_address(&self, mut block_index: usize) -> u32 {
-        let fs = unsafe { EXT2_FS.clone().unwrap() };
-        let block_size = fs.block_size;
-        let starting_lba = fs.starting_lba;
-
+    /*
+        Indirect blocks are read whole through map, so translating the blocks of a file one
+        after the other only goes to the disk when a new indirect block is needed
+    */
+    pub fn get_block_address(
+        &self,
+        fs: &Ext2Filesystem,
+        map: &mut BlockMap,
+        mut block_index: usize,
+    ) -> KResult<u32> {
         if block_index < 12 {
-            return self.direct_pointer[block_index];
+            return Ok(self.direct_pointer[block_index]);
         }
 
-        let addresses_per_block = block_size / 4;
-        let mut block_address: u32 = 0;
+        let addresses_per_block = fs.block_size / 4;
         block_index -= 12;
 
         if block_index < addresses_per_block {
             // singly indirect
-            ahci::read(
-                0,
-                (starting_lba * 512 + self.singly_ip as usize * block_size + block_index * 4)
-                    as u64,
-                4,
-                &mut block_address as *mut u32 as *mut u8,
-            )
-            .unwrap(); // TODO: handle the error like a MAN
-
-            return block_address;
+            return map.entry(fs, self.singly_ip, block_index);
         }
 
         block_index -= addresses_per_block;
 
         if block_index < addresses_per_block * addresses_per_block {
             // doubly indirect
-            let mut indirect: u32 = 0;
-
-            ahci::read(
-                0,
-                (starting_lba * 512
-                    + self.doubly_ip as usize * block_size
-                    + (block_index / addresses_per_block) * 4) as u64,
-                4,
-                &mut indirect as *mut u32 as *mut u8,
-            )
-            .unwrap(); // TODO: handle the error like a MAN
-
-            ahci::read(
-                0,
-                (starting_lba * 512
-                    + indirect as usize * block_size
-                    + (block_index % addresses_per_block) * 4) as u64,
-                4,
-                &mut block_address as *mut u32 as *mut u8,
-            )
-            .unwrap(); // TODO: handle the error like a MAN
-
-            return block_address;
+            let indirect = map.entry(fs, self.doubly_ip, block_index / addresses_per_block)?;
+            return map.entry(fs, indirect, block_index % addresses_per_block);
         }
 
         block_index -= addresses_per_block * addresses_per_block;
 
         // triply indirect
-
-        let base = block_index % (addresses_per_block * addresses_per_block);
-        let mut indirect1: u32 = 0;
-        let mut indirect2: u32 = 0;
-
-        ahci::read(
-            0,
-            (starting_lba * 512
-                + self.triply_ip as usize * block_size
-                + (block_index / (addresses_per_block * addresses_per_block)) * 4)
-                as u64,
-            4,
-            &mut indirect1 as *mut u32 as *mut u8,
-        )
-        .unwrap(); // TODO: handle the error like a MAN
-
-        ahci::read(
-            0,
-            (starting_lba * 512 + indirect1 as usize * block_size + (base / 1024) * 4) as u64,
-            4,
-            &mut indirect2 as *mut u32 as *mut u8,
-        )
-        .unwrap(); // TODO: handle the error like a MAN
-
-        ahci::read(
-            0,
-            (starting_lba * 512 + indirect2 as usize * block_size + (base % 1024) * 4) as u64,
-            4,
-            &mut block_address as *mut u32 as *mut u8,
-        )
-        .unwrap(); // TODO: handle the error like a MAN
-
-        block_address
+        let indirect1 = map.entry(
+            fs,
+            self.triply_ip,
+            block_index / (addresses_per_block * addresses_per_block),
+        )?;
+        let indirect2 = map.entry(
+            fs,
+            indirect1,
+            (block_index / addresses_per_block) % addresses_per_block,
+        )?;
+
+        map.entry(fs, indirect2, block_index % addresses_per_block)
     }
 
-    pub fn set_block_address(&mut self, mut block_index: usize, block_address: u32) {
-        let fs = unsafe { EXT2_FS.clone().unwrap() };
+    pub fn set_block_address(
+        &mut self,
+        fs: &Ext2Filesystem,
+        mut block_index: usize,
+        block_address: u32,
+    ) -> KResult<()> {
         let block_size = fs.block_size;
         let starting_lba = fs.starting_lba;
 
         if block_index < 12 {
             self.direct_pointer[block_index] = block_address;
-            self.flush();
-            return;
+            self.flush(fs)?;
+            return Ok(());
         }
 
         let addresses_per_block = block_size / 4;
@@ -515,24 +937,20 @@ impl Inode {
         if block_index < addresses_per_block {
             // singly indirect
             if self.singly_ip == 0 {
-                // TODO: zero the new block?
-                self.singly_ip = fs
-                    .alloc_block()
-                    .expect("[EXT2] Could not allocate a new block");
+                self.singly_ip = fs.alloc_zeroed_block()?;
 
-                self.flush();
+                self.flush(fs)?;
             }
 
-            ahci::write(
-                0,
+            fs.device.write_with(
                 (starting_lba * 512 + self.singly_ip as usize * block_size + block_index * 4)
                     as u64,
                 4,
                 &block_address as *const u32 as *const u8,
-            )
-            .unwrap(); // TODO: handle the error like a MAN
+                Priority::Metadata,
+            )?;
 
-            return;
+            return Ok(());
         }
 
         block_index -= addresses_per_block;
@@ -541,55 +959,45 @@ impl Inode {
             // doubly indirect
             let mut indirect: u32 = 0;
 
-            /*
-                The doubly indirect pointer hasn't been allocated yet,
-                so we allocate it along with the new singly indirect pointer
-                entry
-            */
             if self.doubly_ip == 0 {
-                // TODO: zero the new block?
-                self.doubly_ip = fs
-                    .alloc_block()
-                    .expect("[EXT2] Could not allocate a new block");
-
-                self.flush();
-
-                indirect = fs
-                    .alloc_block()
-                    .expect("[EXT2] Could not allocate a new block");
-
-                ahci::write(
-                    0,
-                    (starting_lba * 512
-                        + self.doubly_ip as usize * block_size
-                        + (block_index / addresses_per_block) * 4) as u64,
-                    4,
-                    &mut indirect as *mut u32 as *mut u8,
-                )
-                .unwrap(); // TODO: handle the error like a MAN
-            } else {
-                ahci::read(
-                    0,
-                    (starting_lba * 512
-                        + self.doubly_ip as usize * block_size
-                        + (block_index / addresses_per_block) * 4) as u64,
+                self.doubly_ip = fs.alloc_zeroed_block()?;
+
+                self.flush(fs)?;
+            }
+
+            let indirect_entry = (starting_lba * 512
+                + self.doubly_ip as usize * block_size
+                + (block_index / addresses_per_block) * 4) as u64;
+
+            fs.device.read_with(
+                indirect_entry,
+                4,
+                &mut indirect as *mut u32 as *mut u8,
+                Priority::Metadata,
+            )?;
+
+            // the first block that goes through this singly indirect block
+            if indirect == 0 {
+                indirect = fs.alloc_zeroed_block()?;
+
+                fs.device.write_with(
+                    indirect_entry,
                     4,
-                    &mut indirect as *mut u32 as *mut u8,
-                )
-                .unwrap(); // TODO: handle the error like a MAN
+                    &indirect as *const u32 as *const u8,
+                    Priority::Metadata,
+                )?;
             }
 
-            ahci::write(
-                0,
+            fs.device.write_with(
                 (starting_lba * 512
                     + indirect as usize * block_size
                     + (block_index % addresses_per_block) * 4) as u64,
                 4,
                 &block_address as *const u32 as *const u8,
-            )
-            .unwrap(); // TODO: handle the error like a MAN
+                Priority::Metadata,
+            )?;
 
-            return;
+            return Ok(());
         }
 
         block_index -= addresses_per_block * addresses_per_block;
@@ -627,13 +1035,45 @@ impl Inode {
         //     &mut block_address as *mut u32 as *mut u8,
         // )
         // .unwrap(); // TODO: handle the error like a MAN
+
+        Err(KError::EFBIG)
     }
 
-    pub fn get(inode_addr: u32) -> Box<Inode> {
-        let inode_block_group = Inode::get_block_group(inode_addr as usize);
+    pub fn get(fs: &Ext2Filesystem, inode_addr: u32) -> KResult<Box<Inode>> {
+        let block_group = Inode::get_block_group(fs, inode_addr as usize);
+        let inode_table = fs.groups()?.inode_table(block_group);
+        let inode_index = Inode::get_table_index(fs, inode_addr as usize);
+
+        let mut inode = unsafe {
+            Box::from_raw(alloc::alloc::alloc(alloc::alloc::Layout::new::<Inode>()) as *mut Inode)
+        };
+
+        fs.device.read_with(
+            (fs.starting_lba * 512
+                + inode_table as usize * fs.block_size
+                + inode_index * fs.inode_size) as u64,
+            size_of::<Inode>(),
+            inode.as_mut() as *mut Inode as *mut u8,
+            Priority::Metadata,
+        )?;
+
+        // might already be set to the inode addr, but just in case
+        inode.inode_number = inode_addr;
+        Ok(inode)
+    }
+}
 
-        let block_group = BlockGroup::get(inode_block_group);
-        block_group.get_inode(inode_addr)
+// the inverse of Inode::entry_type
+fn dir_entry_type(code: u8) -> vfs::DirEntryType {
+    match code {
+        1 => vfs::DirEntryType::Normal,
+        2 => vfs::DirEntryType::Directory,
+        3 => vfs::DirEntryType::CharDevice,
+        4 => vfs::DirEntryType::BlockDevice,
+        5 => vfs::DirEntryType::Fifo,
+        6 => vfs::DirEntryType::Socket,
+        7 => vfs::DirEntryType::Symlink,
+        _ => vfs::DirEntryType::Unknown,
     }
 }
 
@@ -648,21 +1088,27 @@ struct DirectoryEntry {
 }
 
 impl DirectoryEntry {
-    pub fn search(inode: &Inode, name: &str) -> Option<u32> {
+    // through the dentry cache, only a miss reads the directory
+    pub fn search(fs: &Ext2Filesystem, inode: &Inode, name: &str) -> KResult<u32> {
         if !inode.is_directory() {
-            return None;
+            return Err(KError::ENOTDIR);
         }
 
+        let found = fs.dentries.lookup(inode.inode_number as u64, name, || {
+            DirectoryEntry::scan(fs, inode, name).map(|found| found as u64)
+        })?;
+        Ok(found as u32)
+    }
+
+    fn scan(fs: &Ext2Filesystem, inode: &Inode, name: &str) -> KResult<u32> {
         // just try to search a big directory and we will have some serious troubles
-        let entries_buffer = PmmBox::<u8>::new(inode.sizel as usize);
+        let entries_buffer = PmmBox::<u8>::new(inode.size());
         let entries_buffer_ptr = entries_buffer.as_mut_ptr();
 
-        inode
-            .read(0, inode.sizel as usize, entries_buffer_ptr)
-            .unwrap();
+        inode.read(fs, 0, inode.size(), entries_buffer_ptr)?;
 
         let mut i = 0;
-        while i < inode.sizel {
+        while (i as usize) < inode.size() {
             let curr_entry =
                 unsafe { &*(entries_buffer_ptr.offset(i as isize) as *mut DirectoryEntry) };
 
@@ -680,25 +1126,30 @@ impl DirectoryEntry {
             };
 
             if entry_name == name.as_bytes() {
-                return Some(curr_entry.inode);
+                return Ok(curr_entry.inode);
             }
         }
 
-        None
+        Err(KError::ENOENT)
     }
 
-    pub fn add_entry(dir: &mut Inode, inode: u32, name: &str) -> Result<(), ()> {
+    pub fn add_entry(
+        fs: &Ext2Filesystem,
+        dir: &mut Inode,
+        inode: &Inode,
+        name: &str,
+    ) -> KResult<()> {
         if !dir.is_directory() {
-            return Err(());
+            return Err(KError::ENOTDIR);
         }
 
-        let entries_buffer = PmmBox::<u8>::new(dir.sizel as usize);
+        let entries_buffer = PmmBox::<u8>::new(dir.size());
         let entries_buffer_ptr = entries_buffer.as_mut_ptr();
 
-        dir.read(0, dir.sizel as usize, entries_buffer_ptr).unwrap();
+        dir.read(fs, 0, dir.size(), entries_buffer_ptr)?;
 
         let mut i = 0;
-        while i < dir.sizel {
+        while (i as usize) < dir.size() {
             let curr_entry =
                 unsafe { &mut *(entries_buffer_ptr.offset(i as isize) as *mut DirectoryEntry) };
 
@@ -730,9 +1181,9 @@ impl DirectoryEntry {
 
                 curr_entry.entry_size = true_size as u16;
                 new_entry.name_length = name.len() as u8;
-                new_entry.inode = inode;
+                new_entry.inode = inode.inode_number;
                 new_entry.entry_size = empty_space as u16;
-                new_entry.ti_or_length = 1;
+                new_entry.ti_or_length = inode.entry_type(fs);
 
                 unsafe {
                     new_entry
@@ -741,8 +1192,8 @@ impl DirectoryEntry {
                         .copy_from(name.as_ptr(), name.len());
                 }
 
-                dir.write(0, dir.sizel as usize, entries_buffer_ptr)
-                    .unwrap();
+                dir.write(fs, 0, dir.size(), entries_buffer_ptr)?;
+                fs.dentries.insert(dir.inode_number as u64, name, inode.inode_number as u64);
 
                 return Ok(());
             }
@@ -750,94 +1201,800 @@ impl DirectoryEntry {
             i += curr_entry.entry_size as u32;
         }
 
-        Err(())
+        // TODO: grow the directory
+        Err(KError::ENOSPC)
     }
-}
 
-pub struct Ext2Filesystem {
-    superblock: Box<Superblock>,
-    block_size: usize,
-    block_group_cnt: usize,
-    starting_lba: usize,
+    /*
+        Takes name out of dir and returns the inode it pointed to. Its space goes to the
+        entry before it, or if it's the first of its block, it's only marked as unused
+    */
+    pub fn remove_entry(fs: &Ext2Filesystem, dir: &mut Inode, name: &str) -> KResult<u32> {
+        if !dir.is_directory() {
+            return Err(KError::ENOTDIR);
+        }
+
+        let size = dir.size();
+        let entries_buffer = PmmBox::<u8>::new(size);
+        let entries_buffer_ptr = entries_buffer.as_mut_ptr();
+
+        dir.read(fs, 0, size, entries_buffer_ptr)?;
+
+        let mut previous = None;
+        let mut i = 0;
+        while i < size {
+            let curr_entry = unsafe { &mut *(entries_buffer_ptr.add(i) as *mut DirectoryEntry) };
+            if curr_entry.entry_size == 0 {
+                return Err(KError::EIO);
+            }
+
+            // entries never cross a block boundary
+            if i % fs.block_size == 0 {
+                previous = None;
+            }
+
+            let entry_name = unsafe {
+                core::slice::from_raw_parts(
+                    curr_entry.entry_name.as_ptr(),
+                    curr_entry.name_length as usize,
+                )
+            };
+
+            if curr_entry.inode != 0 && entry_name == name.as_bytes() {
+                let inode = curr_entry.inode;
+
+                match previous {
+                    Some(previous) => unsafe {
+                        let previous_entry =
+                            &mut *(entries_buffer_ptr.add(previous) as *mut DirectoryEntry);
+                        previous_entry.entry_size += curr_entry.entry_size;
+                    },
+                    None => curr_entry.inode = 0,
+                }
+
+                dir.write(fs, 0, size, entries_buffer_ptr)?;
+                fs.dentries.remove(dir.inode_number as u64, name);
+                return Ok(inode);
+            }
+
+            previous = Some(i);
+            i += curr_entry.entry_size as usize;
+        }
+
+        Err(KError::ENOENT)
+    }
+
+    // the offset of the used entry called name in a directory's entries
+    fn locate(entries: &[u8], name: &str) -> KResult<Option<usize>> {
+        let mut i = 0;
+        while i + size_of::<DirectoryEntry>() <= entries.len() {
+            let curr_entry = unsafe { &*(entries.as_ptr().add(i) as *const DirectoryEntry) };
+            if curr_entry.entry_size == 0 {
+                return Err(KError::EIO);
+            }
+
+            let entry_name = unsafe {
+                core::slice::from_raw_parts(
+                    curr_entry.entry_name.as_ptr(),
+                    curr_entry.name_length as usize,
+                )
+            };
+
+            if curr_entry.inode != 0 && entry_name == name.as_bytes() {
+                return Ok(Some(i));
+            }
+
+            i += curr_entry.entry_size as usize;
+        }
+
+        Ok(None)
+    }
+
+    /*
+        Gives the entry called old the name new, where it is, if its record is big enough.
+        Returns false without touching anything if it isn't
+    */
+    pub fn rename_entry(
+        fs: &Ext2Filesystem,
+        dir: &mut Inode,
+        old: &str,
+        new: &str,
+    ) -> KResult<bool> {
+        if !dir.is_directory() {
+            return Err(KError::ENOTDIR);
+        }
+
+        let mut entries = alloc::vec![0u8; dir.size()];
+        dir.read(fs, 0, entries.len(), entries.as_mut_ptr())?;
+
+        let offset = DirectoryEntry::locate(&entries, old)?.ok_or(KError::ENOENT)?;
+        let curr_entry = unsafe { &mut *(entries.as_mut_ptr().add(offset) as *mut DirectoryEntry) };
+
+        if (curr_entry.entry_size as usize) < round_up(size_of::<DirectoryEntry>() + new.len(), 4) {
+            return Ok(false);
+        }
+
+        curr_entry.name_length = new.len() as u8;
+        unsafe {
+            curr_entry
+                .entry_name
+                .as_mut_ptr()
+                .copy_from(new.as_ptr(), new.len());
+        }
+        let inode = curr_entry.inode;
+
+        dir.write(fs, 0, entries.len(), entries.as_ptr())?;
+        fs.dentries.remove(dir.inode_number as u64, old);
+        fs.dentries.insert(dir.inode_number as u64, new, inode as u64);
+        Ok(true)
+    }
+
+    // points the entry called name to inode instead, returns the inode it pointed to
+    pub fn replace_entry(
+        fs: &Ext2Filesystem,
+        dir: &mut Inode,
+        name: &str,
+        inode: &Inode,
+    ) -> KResult<u32> {
+        if !dir.is_directory() {
+            return Err(KError::ENOTDIR);
+        }
+
+        let mut entries = alloc::vec![0u8; dir.size()];
+        dir.read(fs, 0, entries.len(), entries.as_mut_ptr())?;
+
+        let offset = DirectoryEntry::locate(&entries, name)?.ok_or(KError::ENOENT)?;
+        let curr_entry = unsafe { &mut *(entries.as_mut_ptr().add(offset) as *mut DirectoryEntry) };
+        let replaced = curr_entry.inode;
+
+        curr_entry.inode = inode.inode_number;
+        curr_entry.ti_or_length = inode.entry_type(fs);
+
+        dir.write(fs, 0, entries.len(), entries.as_ptr())?;
+        fs.dentries.insert(dir.inode_number as u64, name, inode.inode_number as u64);
+        Ok(replaced)
+    }
+
+    // returns the first used entry at or after offset, or None at the end of the directory
+    pub fn read_entry(
+        fs: &Ext2Filesystem,
+        dir: &Inode,
+        map: &mut BlockMap,
+        mut offset: usize,
+    ) -> KResult<Option<vfs::DirEntry>> {
+        if !dir.is_directory() {
+            return Err(KError::ENOTDIR);
+        }
+
+        while offset + size_of::<DirectoryEntry>() <= dir.size() {
+            let mut header = DirectoryEntry {
+                inode: 0,
+                entry_size: 0,
+                name_length: 0,
+                ti_or_length: 0,
+                entry_name: [],
+            };
+
+            dir.read_mapped(
+                fs,
+                map,
+                offset,
+                size_of::<DirectoryEntry>(),
+                &mut header as *mut DirectoryEntry as *mut u8,
+            )?;
+
+            // a corrupted entry would make us loop forever
+            if header.entry_size == 0 {
+                return Err(KError::EIO);
+            }
+
+            let next_offset = offset + header.entry_size as usize;
+
+            if header.inode == 0 {
+                offset = next_offset;
+                continue;
+            }
+
+            let mut name = alloc::vec![0u8; header.name_length as usize];
+            dir.read_mapped(
+                fs,
+                map,
+                offset + size_of::<DirectoryEntry>(),
+                name.len(),
+                name.as_mut_ptr(),
+            )?;
+
+            // without the filetype feature the byte is the high half of the name's length
+            let entry_type = if fs.superblock.has_file_types() {
+                dir_entry_type(header.ti_or_length)
+            } else {
+                vfs::DirEntryType::Unknown
+            };
+
+            return Ok(Some(vfs::DirEntry {
+                inode: header.inode as u64,
+                next_offset,
+                entry_type,
+                name: String::from_utf8_lossy(&name).into_owned(),
+            }));
+        }
+
+        Ok(None)
+    }
+}
+
+/*
+    An inode that's open, every open of it shares the one copy. Opens counts the opens the
+    vfs hasn't closed yet, the last close writes it back if it's dirty and frees it if it
+    was unlinked in the meantime
+*/
+struct OpenFile {
+    inode: Box<Inode>,
+    map: BlockMap,
+    opens: usize,
+    dirty: bool, // changed since it was last written to the disk
+}
+
+impl OpenFile {
+    fn flush(&mut self, fs: &Ext2Filesystem) -> KResult<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        self.inode.flush(fs)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+// the part of the superblock that changes with every mount and unmount
+#[derive(Clone, Copy)]
+struct MountState {
+    mounted: bool,
+    last_mt: u32,
+    last_wt: u32,
+    mount_cnt: u16,
+    fs_state: u16,
+}
+
+impl MountState {
+    // the 16 bytes at SUPERBLOCK_STATE_OFFSET
+    fn bytes(&self, superblock: &Superblock) -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        bytes[..4].copy_from_slice(&self.last_mt.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.last_wt.to_le_bytes());
+        bytes[8..10].copy_from_slice(&self.mount_cnt.to_le_bytes());
+        bytes[10..12].copy_from_slice(&{ superblock.mounts_bfc }.to_le_bytes());
+        bytes[12..14].copy_from_slice(&{ superblock.signature }.to_le_bytes());
+        bytes[14..].copy_from_slice(&self.fs_state.to_le_bytes());
+        bytes
+    }
+}
+
+pub struct Ext2Filesystem {
+    device: Arc<dyn BlockDevice>,
+    superblock: Box<Superblock>,
+    block_size: usize,
+    inode_size: usize, // the stride of the inode tables
+    block_group_cnt: usize,
+    starting_lba: usize,
+    read_only: bool, // it has ro_compat features we don't know
+    groups: spin::Mutex<Groups>,
+    // the superblock's counts, kept up to date and written back to it on sync
+    free_blocks: AtomicU32,
+    free_inodes: AtomicU32,
+    counts_dirty: AtomicBool,
+    mount_state: spin::Mutex<MountState>,
+    dentries: DentryCache,
+    /*
+        Indexed by the file_index of the file descriptions this filesystem hands out, it
+        grows as needed and a closed file's slot is reused. Reads and writes take their file
+        out and let go of the table
+    */
+    open_files: spin::Mutex<Vec<Option<Arc<spin::Mutex<OpenFile>>>>>,
 }
 
 impl Ext2Filesystem {
-    pub fn new(starting_lba: u64, superblock: Box<Superblock>) -> Self {
+    pub fn new(
+        device: Arc<dyn BlockDevice>,
+        starting_lba: u64,
+        superblock: Box<Superblock>,
+    ) -> Self {
+        let block_group_cnt = div_ceil(
+            superblock.block_cnt as usize,
+            superblock.blocks_per_group as usize,
+        );
+
+        let bitmaps = (0..block_group_cnt)
+            .map(|_| GroupBitmaps {
+                block: None,
+                inode: None,
+            })
+            .collect();
+
         Ext2Filesystem {
+            device,
             block_size: 1024 << superblock.block_size,
-            block_group_cnt: div_ceil(
-                superblock.block_cnt as usize,
-                superblock.blocks_per_group as usize,
-            ),
+            inode_size: superblock.inode_size(),
+            block_group_cnt,
+            read_only: superblock.unsupported_ro_compat() != 0,
+            free_blocks: AtomicU32::new(superblock.unallocated_blocks),
+            free_inodes: AtomicU32::new(superblock.unallocated_inodes),
+            counts_dirty: AtomicBool::new(false),
+            mount_state: spin::Mutex::new(MountState {
+                mounted: false,
+                last_mt: superblock.last_mt,
+                last_wt: superblock.last_wt,
+                mount_cnt: superblock.mount_cnt,
+                fs_state: superblock.fs_state,
+            }),
             superblock,
             starting_lba: starting_lba as usize,
+            dentries: DentryCache::new(DENTRY_CACHE_SIZE),
+            groups: spin::Mutex::new(Groups {
+                descriptors: Vec::new(),
+                dirty: false,
+                bitmaps,
+            }),
+            open_files: spin::Mutex::new(Vec::new()),
+        }
+    }
+
+    // writes every dirty open inode, cached bitmap and group descriptor back to the disk
+    pub fn sync(&self) -> KResult<()> {
+        for file in self.open_files.lock().iter().flatten() {
+            file.lock().flush(self)?;
+        }
+
+        self.groups.lock().flush(self)?;
+        self.flush_counts()
+    }
+
+    // the group descriptor table, read the first time it's needed
+    fn groups(&self) -> KResult<spin::MutexGuard<Groups>> {
+        let mut groups = self.groups.lock();
+        groups.load(self)?;
+        Ok(groups)
+    }
+
+    fn count_blocks(&self, change: i32) {
+        self.free_blocks.fetch_add(change as u32, Ordering::Relaxed);
+        self.counts_dirty.store(true, Ordering::Relaxed);
+    }
+
+    fn count_inodes(&self, change: i32) {
+        self.free_inodes.fetch_add(change as u32, Ordering::Relaxed);
+        self.counts_dirty.store(true, Ordering::Relaxed);
+    }
+
+    // the superblock is only ever written a few fields at a time, the rest never changes
+    fn write_superblock(&self, offset: usize, bytes: &[u8]) -> KResult<()> {
+        let offset = (self.starting_lba + 2) * 512 + offset;
+        self.device
+            .write_with(offset as u64, bytes.len(), bytes.as_ptr(), Priority::Metadata)
+            .map(|_| ())
+    }
+
+    fn flush_counts(&self) -> KResult<()> {
+        if !self.counts_dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let mut counts = [0u8; 8];
+        counts[..4].copy_from_slice(&self.free_blocks.load(Ordering::Relaxed).to_le_bytes());
+        counts[4..].copy_from_slice(&self.free_inodes.load(Ordering::Relaxed).to_le_bytes());
+
+        let result = self.write_superblock(SUPERBLOCK_COUNTS_OFFSET, &counts);
+        if result.is_err() {
+            self.counts_dirty.store(true, Ordering::Relaxed);
+        }
+
+        result
+    }
+
+    /*
+        Marks the filesystem as mounted on the disk, so a crash leaves it not clean, and
+        says so when it should be checked with e2fsck first. After a crash, files that were
+        unlinked while open are still allocated, those are freed here
+    */
+    pub fn mount(&self) -> KResult<()> {
+        let mut state = self.mount_state.lock();
+        if state.mounted {
+            return Err(KError::EBUSY);
+        }
+
+        let clean = state.fs_state & STATE_VALID != 0;
+        if state.fs_state & STATE_ERRORS != 0 {
+            log::warning!("[EXT2] The filesystem has errors, it should be checked\n");
+        } else if !clean {
+            log::warning!("[EXT2] The filesystem wasn't unmounted cleanly, it should be checked\n");
+        }
+
+        // a negative maximum turns the check off
+        let max_mounts = self.superblock.mounts_bfc as i16;
+        if max_mounts > 0 && state.mount_cnt >= max_mounts as u16 {
+            log::warning!(
+                "[EXT2] Mounted {} times without a check, it should be checked\n",
+                state.mount_cnt
+            );
+        }
+
+        if self.read_only {
+            state.mounted = true;
+            return Ok(());
         }
+
+        let mut mounted = *state;
+        mounted.mounted = true;
+        mounted.mount_cnt = mounted.mount_cnt.wrapping_add(1);
+        mounted.last_mt = vdso::unix_time() as u32;
+        mounted.fs_state &= !STATE_VALID;
+        self.write_superblock(SUPERBLOCK_STATE_OFFSET, &mounted.bytes(&self.superblock))?;
+        *state = mounted;
+        drop(state);
+
+        if !clean {
+            if let Err(err) = self.free_orphans() {
+                log::error!("[EXT2] Could not free the orphaned inodes: {}\n", err);
+            }
+        }
+
+        Ok(())
+    }
+
+    // every allocated inode with no links left, only the last close of a file frees it
+    fn free_orphans(&self) -> KResult<()> {
+        let mut freed = 0;
+
+        for group in 0..self.block_group_cnt {
+            let used = self.groups()?.used_inodes(self, group)?;
+
+            for inode_number in used {
+                if inode_number < self.superblock.first_inode() {
+                    continue;
+                }
+
+                let inode = Inode::get(self, inode_number)?;
+                if inode.ref_cnt == 0 {
+                    self.free_unlinked(inode)?;
+                    freed += 1;
+                }
+            }
+        }
+
+        if freed > 0 {
+            log::info!("[EXT2] Freed {} orphaned inodes\n", freed);
+        }
+
+        Ok(())
+    }
+
+    /*
+        Must be called before the filesystem goes away, otherwise the cached changes are
+        lost. EBUSY while files are open, unless it's forced because the machine is going
+        down. Then unlinked files that are still open stay allocated and the filesystem is
+        left not clean, so the next mount frees them
+    */
+    pub fn unmount(&self, force: bool) -> KResult<()> {
+        let (open, orphans) = {
+            let open_files = self.open_files.lock();
+            let open = open_files.iter().flatten().count();
+            let orphans = open_files
+                .iter()
+                .flatten()
+                .filter(|file| file.lock().inode.ref_cnt == 0)
+                .count();
+            (open, orphans)
+        };
+        if open > 0 && !force {
+            return Err(KError::EBUSY);
+        }
+
+        self.sync()?;
+
+        let mut state = self.mount_state.lock();
+        if !self.read_only && orphans == 0 {
+            let mut unmounted = *state;
+            unmounted.last_wt = vdso::unix_time() as u32;
+            unmounted.fs_state |= STATE_VALID;
+            self.write_superblock(SUPERBLOCK_STATE_OFFSET, &unmounted.bytes(&self.superblock))?;
+            *state = unmounted;
+        }
+        state.mounted = false;
+        drop(state);
+
+        self.dentries.clear();
+
+        let mut groups = self.groups.lock();
+        groups.descriptors.clear();
+        for group in groups.bitmaps.iter_mut() {
+            group.block = None;
+            group.inode = None;
+        }
+
+        Ok(())
     }
 
     // TODO: allocate multiple blocks at the same time
-    pub fn alloc_block(&self) -> Option<u32> {
-        if self.superblock.unallocated_blocks == 0 {
-            return None;
+    pub fn alloc_block(&self) -> KResult<u32> {
+        if self.free_blocks.load(Ordering::Relaxed) == 0 {
+            return Err(KError::ENOSPC);
         }
 
+        let mut groups = self.groups()?;
         for bg in 0..self.block_group_cnt {
-            let mut block_group = BlockGroup::get(bg);
-
-            if let Some(block_addr) = block_group.alloc_block(1) {
-                // TODO: make this possible
-                // self.superblock.unallocated_blocks -= 1;
-                // self.superblock.flush();
-                return Some(block_addr[0]);
+            match groups.alloc_block(self, bg, 1) {
+                Ok(block_addr) => return Ok(block_addr[0]),
+                Err(KError::ENOSPC) => continue,
+                Err(err) => return Err(err),
             }
         }
 
-        None
+        Err(KError::ENOSPC)
+    }
+
+    /*
+        Metadata blocks (indirect blocks for now) must not be handed out with whatever was
+        on the disk before, the stale data would be read back as block pointers
+    */
+    pub fn alloc_zeroed_block(&self) -> KResult<u32> {
+        let block = self.alloc_block()?;
+        self.zero_block(block)?;
+        Ok(block)
+    }
+
+    fn zero_block(&self, block: u32) -> KResult<()> {
+        let zeroes = alloc::vec![0u8; self.block_size];
+
+        self.device.write_with(
+            (self.starting_lba * 512 + block as usize * self.block_size) as u64,
+            self.block_size,
+            zeroes.as_ptr(),
+            Priority::Metadata,
+        )?;
+
+        Ok(())
     }
 
-    pub fn alloc_inode(&self) -> Option<u32> {
-        if self.superblock.unallocated_inodes == 0 {
-            return None;
+    pub fn alloc_inode(&self) -> KResult<u32> {
+        if self.free_inodes.load(Ordering::Relaxed) == 0 {
+            return Err(KError::ENOSPC);
         }
 
+        let mut groups = self.groups()?;
         for bg in 0..self.block_group_cnt {
-            let mut block_group = BlockGroup::get(bg);
+            match groups.alloc_inode(self, bg) {
+                Ok(inode_addr) => return Ok(inode_addr),
+                Err(KError::ENOSPC) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+
+        Err(KError::ENOSPC)
+    }
+
+    pub fn free_block(&self, block: u32) -> KResult<()> {
+        let block_group = (block / self.superblock.blocks_per_group) as usize;
+        self.groups()?.free_block(self, block_group, block)
+    }
+
+    pub fn free_inode(&self, inode: u32, directory: bool) -> KResult<()> {
+        let block_group = Inode::get_block_group(self, inode as usize);
+        self.groups()?.free_inode(self, block_group, inode, directory)
+    }
+
+    // block holds pointers depth levels above the data blocks, all of them are freed
+    fn free_indirect(&self, block: u32, depth: usize) -> KResult<()> {
+        if block == 0 {
+            return Ok(());
+        }
 
-            if let Some(inode_addr) = block_group.alloc_inode() {
-                // TODO: make this possible
-                // self.superblock.unallocated_inodes -= 1;
-                // self.superblock.flush();
-                return Some(inode_addr);
+        let mut pointers = alloc::vec![0u32; self.block_size / 4];
+        self.device.read_with(
+            (self.starting_lba * 512 + block as usize * self.block_size) as u64,
+            self.block_size,
+            pointers.as_mut_ptr() as *mut u8,
+            Priority::Metadata,
+        )?;
+
+        for pointer in pointers.into_iter().filter(|pointer| *pointer != 0) {
+            if depth == 1 {
+                self.free_block(pointer)?;
+            } else {
+                self.free_indirect(pointer, depth - 1)?;
             }
         }
 
-        None
+        self.free_block(block)
     }
 
-    pub fn new_fd(&self, inode: Box<Inode>, flags: vfs::Flags) -> Option<vfs::FileDescription> {
-        for (i, slot) in unsafe { INODE_TABLE.iter().enumerate() } {
-            match slot {
-                Some(_) => {
-                    continue;
-                }
-                None => unsafe {
-                    INODE_TABLE[i] = Some(inode);
-                    let fd = vfs::FileDescription::new(i, flags, EXT2_FS.as_ref().unwrap().deref());
-                    return Some(fd);
-                },
+    fn lookup(&self, path: &str) -> KResult<Box<Inode>> {
+        let mut inode = Inode::get(self, ROOT_DIR_INODE)?;
+
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            let next = DirectoryEntry::search(self, &inode, name)?;
+            inode = Inode::get(self, next)?;
+        }
+
+        Ok(inode)
+    }
+
+    // the directory the last component of path is in, and that component
+    fn lookup_parent<'a>(&self, path: &'a str) -> KResult<(Box<Inode>, &'a str)> {
+        let path = path.trim_end_matches('/');
+        let (dir, name) = match path.rfind('/') {
+            Some(i) => (&path[..i], &path[i + 1..]),
+            None => ("", path),
+        };
+
+        if name.is_empty() || name == "." || name == ".." {
+            return Err(KError::EINVAL);
+        }
+        if name.len() > u8::MAX as usize {
+            return Err(KError::ENAMETOOLONG);
+        }
+
+        let dir = self.lookup(dir)?;
+        if !dir.is_directory() {
+            return Err(KError::ENOTDIR);
+        }
+
+        Ok((dir, name))
+    }
+
+    // whether dir is ancestor or somewhere under it, going up through the .. entries
+    fn is_ancestor(&self, ancestor: u32, mut dir: u32) -> KResult<bool> {
+        loop {
+            if dir == ancestor {
+                return Ok(true);
             }
+            if dir == ROOT_DIR_INODE {
+                return Ok(false);
+            }
+
+            let inode = Inode::get(self, dir)?;
+            dir = DirectoryEntry::search(self, &inode, "..")?;
         }
+    }
 
-        None
+    fn add_links(&self, inode: &mut Inode, links: i32) -> KResult<()> {
+        let ref_cnt = (inode.ref_cnt as i32 + links).max(0) as u16;
+        inode.ref_cnt = ref_cnt;
+        self.update_open(inode.inode_number, |file| file.ref_cnt = ref_cnt);
+        inode.flush(self)
+    }
+
+    /*
+        One entry less points to inode, which is freed along with its blocks once none does.
+        If it's open the last close frees it instead. The open copy is updated last, under
+        the table's lock, so exactly one of this and the close sees it unlinked and closed
+    */
+    fn drop_link(&self, mut inode: Box<Inode>) -> KResult<()> {
+        let ref_cnt = inode.ref_cnt.saturating_sub(1);
+        inode.ref_cnt = ref_cnt;
+        inode.flush(self)?;
+
+        if self.update_open(inode.inode_number, |file| file.ref_cnt = ref_cnt) || ref_cnt > 0 {
+            return Ok(());
+        }
+
+        self.free_unlinked(inode)
+    }
+
+    fn free_unlinked(&self, mut inode: Box<Inode>) -> KResult<()> {
+        inode.free_blocks(self)?;
+        inode.flush(self)?;
+        self.free_inode(inode.inode_number, false)
+    }
+
+    /*
+        An open file has the inode's copy its writes go through, which is flushed whenever
+        it grows, so a change made through another copy has to be made to it too
+    */
+    fn update_open(&self, inode_number: u32, update: impl Fn(&mut Inode)) -> bool {
+        let open_files = self.open_files.lock();
+        let file = open_files
+            .iter()
+            .flatten()
+            .find(|file| file.lock().inode.inode_number == inode_number);
+
+        match file {
+            Some(file) => {
+                update(&mut file.lock().inode);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn open_file(&self, index: usize) -> KResult<Arc<spin::Mutex<OpenFile>>> {
+        let open_files = self.open_files.lock();
+        open_files.get(index).and_then(|slot| slot.clone()).ok_or(KError::EBADF)
+    }
+
+    // inode was just read from the disk, if it's open already the open copy is used instead
+    pub fn new_fd(&self, inode: Box<Inode>, flags: vfs::Flags) -> KResult<vfs::FileDescription> {
+        let mut open_files = self.open_files.lock();
+        let open = open_files.iter().position(|slot| {
+            slot.as_ref()
+                .map_or(false, |file| file.lock().inode.inode_number == inode.inode_number)
+        });
+
+        let index = match open {
+            Some(index) => {
+                open_files[index].as_ref().unwrap().lock().opens += 1;
+                index
+            }
+            None => {
+                let file = Arc::new(spin::Mutex::new(OpenFile {
+                    inode,
+                    map: BlockMap::new(),
+                    opens: 1,
+                    dirty: false,
+                }));
+
+                match open_files.iter().position(|slot| slot.is_none()) {
+                    Some(index) => {
+                        open_files[index] = Some(file);
+                        index
+                    }
+                    None => {
+                        open_files.push(Some(file));
+                        open_files.len() - 1
+                    }
+                }
+            }
+        };
+
+        // filesystems are never freed once probed (see probe.rs), so this is really static
+        let fs: &'static Ext2Filesystem = unsafe { &*(self as *const Ext2Filesystem) };
+        Ok(vfs::FileDescription::new(index, flags, fs))
     }
 }
 
 impl vfs::Filesystem for Ext2Filesystem {
-    fn open(&self, path: &str, flags: vfs::Flags, mode: vfs::Mode) -> Option<vfs::FileDescription> {
-        serial::print!("open path: {}\n", path);
-        let root_dir = Inode::get(ROOT_DIR_INODE);
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn sync(&self) -> KResult<()> {
+        Ext2Filesystem::sync(self)
+    }
+
+    fn mount(&self) -> KResult<()> {
+        Ext2Filesystem::mount(self)
+    }
+
+    fn unmount(&self, force: bool) -> KResult<()> {
+        Ext2Filesystem::unmount(self, force)
+    }
+
+    fn statfs(&self) -> KResult<vfs::StatFs> {
+        Ok(vfs::StatFs {
+            block_size: self.block_size,
+            blocks: self.superblock.block_cnt as u64,
+            free_blocks: self.free_blocks.load(Ordering::Relaxed) as u64,
+            inodes: self.superblock.inode_cnt as u64,
+            free_inodes: self.free_inodes.load(Ordering::Relaxed) as u64,
+        })
+    }
+
+    fn open(
+        &self,
+        path: &str,
+        flags: vfs::Flags,
+        mode: vfs::Mode,
+    ) -> KResult<vfs::FileDescription> {
+        log::debug!("[EXT2] open path: {}\n", path);
+        let writes = vfs::Flags::O_WRONLY | vfs::Flags::O_RDWR | vfs::Flags::O_TRUNC;
+        if self.read_only && flags.intersects(writes | vfs::Flags::O_CREAT) {
+            return Err(KError::EROFS);
+        }
+
+        let root_dir = Inode::get(self, ROOT_DIR_INODE)?;
         let mut current_dir = root_dir;
         let path: Vec<&str> = path.split('/').collect();
-        serial::print!("path vector: {:?}\n", path);
+        log::debug!("[EXT2] path vector: {:?}\n", path);
 
         // TODO: some more testing
         for (i, path_fragment) in path.iter().enumerate() {
@@ -845,103 +2002,483 @@ impl vfs::Filesystem for Ext2Filesystem {
                 continue;
             }
 
-            if let Some(inode_addr) = DirectoryEntry::search(&current_dir, path_fragment) {
-                let entry_inode = Inode::get(inode_addr);
+            match DirectoryEntry::search(self, &current_dir, path_fragment) {
+                Ok(inode_addr) => {
+                    let entry_inode = Inode::get(self, inode_addr)?;
 
-                if i + 1 == path.len() {
-                    return self.new_fd(entry_inode, flags);
-                }
+                    if i + 1 == path.len() {
+                        return self.new_fd(entry_inode, flags);
+                    }
 
-                if !entry_inode.is_directory() {
-                    return None;
-                }
+                    if !entry_inode.is_directory() {
+                        return Err(KError::ENOTDIR);
+                    }
 
-                current_dir = entry_inode;
-            } else {
-                if i + 1 == path.len() && flags.contains(vfs::Flags::O_CREAT) {
-                    let new_inode_addr = self
-                        .alloc_inode()
-                        .expect("[EXT2] Could not allocate a new inode");
+                    current_dir = entry_inode;
+                }
+                Err(KError::ENOENT)
+                    if i + 1 == path.len() && flags.contains(vfs::Flags::O_CREAT) =>
+                {
+                    vfs::check_access(&current_dir.attributes(), vfs::Access::WRITE)?;
+                    let new_inode_addr = self.alloc_inode()?;
 
-                    let mut new_inode = Inode::get(new_inode_addr);
+                    let mut new_inode = Inode::get(self, new_inode_addr)?;
                     new_inode.type_and_permissions = 0x81ed;
                     new_inode.ref_cnt = 1;
-                    new_inode.flush();
+                    new_inode.set_owner_to_caller();
+                    new_inode.flush(self)?;
 
-                    DirectoryEntry::add_entry(&mut current_dir, new_inode_addr, path_fragment)
-                        .unwrap();
+                    DirectoryEntry::add_entry(self, &mut current_dir, &new_inode, path_fragment)?;
 
                     return self.new_fd(new_inode, flags);
                 }
+                Err(err) => return Err(err),
+            }
+        }
+
+        // the path ended with a slash (or it's the root), so it names a directory
+        self.new_fd(current_dir, flags)
+    }
 
-                return None;
+    /*
+        A directory starts out with a block holding . and .., so it has 2 links (its entry in
+        the parent and its own .), and the parent gets one more for the new ..
+    */
+    fn mkdir(&self, path: &str, _mode: vfs::Mode) -> KResult<vfs::FileDescription> {
+        if self.read_only {
+            return Err(KError::EROFS);
+        }
+
+        let (mut parent, name) = self.lookup_parent(path)?;
+        match DirectoryEntry::search(self, &parent, name) {
+            Ok(_) => return Err(KError::EEXIST),
+            Err(KError::ENOENT) => {}
+            Err(err) => return Err(err),
+        }
+        if parent.ref_cnt == u16::MAX {
+            return Err(KError::EMLINK);
+        }
+        vfs::check_access(&parent.attributes(), vfs::Access::WRITE)?;
+
+        let inode_number = self.alloc_inode()?;
+        let block = self.alloc_block()?;
+
+        let mut dir = Inode::new(inode_number, 0x41ed);
+        dir.ref_cnt = 2;
+        dir.set_owner_to_caller();
+        dir.direct_pointer = [block, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        dir.set_size(self.block_size);
+        dir.sectors_used = (self.block_size / 512) as u32;
+
+        let mut entries = alloc::vec![0u8; self.block_size];
+        let entry_type = dir.entry_type(self);
+        let dot_size = round_up(size_of::<DirectoryEntry>() + 1, 4);
+        for (offset, inode, name, entry_size) in [
+            (0, inode_number, ".", dot_size),
+            (dot_size, parent.inode_number, "..", self.block_size - dot_size),
+        ] {
+            let entry = DirectoryEntry {
+                inode,
+                entry_size: entry_size as u16,
+                name_length: name.len() as u8,
+                ti_or_length: entry_type,
+                entry_name: [],
+            };
+
+            unsafe {
+                let start = entries.as_mut_ptr().add(offset);
+                (start as *mut DirectoryEntry).write_unaligned(entry);
+                start.add(size_of::<DirectoryEntry>()).copy_from(name.as_ptr(), name.len());
             }
         }
 
-        None
+        self.device.write_with(
+            (self.starting_lba * 512 + block as usize * self.block_size) as u64,
+            self.block_size,
+            entries.as_ptr(),
+            Priority::Metadata,
+        )?;
+        dir.flush(self)?;
+
+        let group = Inode::get_block_group(self, inode_number as usize);
+        self.groups()?.add_directory(group);
+
+        DirectoryEntry::add_entry(self, &mut parent, &dir, name)?;
+        self.add_links(&mut parent, 1)?;
+
+        self.new_fd(dir, vfs::Flags::O_RDONLY)
     }
 
-    fn mkdir(&self, path: &str, mode: vfs::Mode) -> Option<vfs::FileDescription> {
-        todo!()
+    fn unlink(&self, path: &str) -> KResult<()> {
+        if self.read_only {
+            return Err(KError::EROFS);
+        }
+
+        let (mut dir, name) = self.lookup_parent(path)?;
+        let inode = Inode::get(self, DirectoryEntry::search(self, &dir, name)?)?;
+        if inode.is_directory() {
+            return Err(KError::EISDIR);
+        }
+
+        DirectoryEntry::remove_entry(self, &mut dir, name)?;
+        self.drop_link(inode)
     }
 
-    fn read(&self, index: usize, buffer: *mut u8, cnt: usize, offset: usize) -> usize {
-        let inode_option = unsafe { INODE_TABLE[index].as_ref() };
+    fn link(&self, old: &str, new: &str) -> KResult<()> {
+        if self.read_only {
+            return Err(KError::EROFS);
+        }
 
-        if let Some(inode) = inode_option {
-            inode.read(offset, cnt, buffer).unwrap()
-        } else {
-            //TODO: report the error somehow
-            0
+        let mut inode = self.lookup(old)?;
+        // a directory with two parents would leave .. pointing at only one of them
+        if inode.is_directory() {
+            return Err(KError::EPERM);
+        }
+        if inode.ref_cnt == u16::MAX {
+            return Err(KError::EMLINK);
+        }
+
+        let (mut dir, name) = self.lookup_parent(new)?;
+        match DirectoryEntry::search(self, &dir, name) {
+            Ok(_) => return Err(KError::EEXIST),
+            Err(KError::ENOENT) => {}
+            Err(err) => return Err(err),
         }
+
+        DirectoryEntry::add_entry(self, &mut dir, &inode, name)?;
+        self.add_links(&mut inode, 1)
     }
 
-    fn write(&self, index: usize, buffer: *const u8, cnt: usize, offset: usize) -> usize {
-        let inode_option = unsafe { INODE_TABLE[index].as_mut() };
+    fn symlink(&self, target: &str, path: &str) -> KResult<()> {
+        if self.read_only {
+            return Err(KError::EROFS);
+        }
+        if target.is_empty() {
+            return Err(KError::ENOENT);
+        }
+        if target.len() >= self.block_size {
+            return Err(KError::ENAMETOOLONG);
+        }
+
+        let (mut parent, name) = self.lookup_parent(path)?;
+        match DirectoryEntry::search(self, &parent, name) {
+            Ok(_) => return Err(KError::EEXIST),
+            Err(KError::ENOENT) => {}
+            Err(err) => return Err(err),
+        }
+        vfs::check_access(&parent.attributes(), vfs::Access::WRITE)?;
 
-        if let Some(inode) = inode_option {
-            inode.write(offset, cnt, buffer).unwrap()
+        let mut link = Inode::new(self.alloc_inode()?, vfs::FileType::SYMLINK.bits() | 0o777);
+        link.ref_cnt = 1;
+        link.set_owner_to_caller();
+        link.set_size(target.len());
+
+        if target.len() < FAST_SYMLINK_MAX {
+            link.inline_data_mut()[..target.len()].copy_from_slice(target.as_bytes());
         } else {
-            //TODO: report the error somehow
-            0
+            let block = self.alloc_block()?;
+            let mut data = alloc::vec![0u8; self.block_size];
+            data[..target.len()].copy_from_slice(target.as_bytes());
+
+            self.device.write(
+                (self.starting_lba * 512 + block as usize * self.block_size) as u64,
+                self.block_size,
+                data.as_ptr(),
+            )?;
+            link.direct_pointer[0] = block;
+            link.sectors_used = (self.block_size / 512) as u32;
+        }
+
+        link.flush(self)?;
+        DirectoryEntry::add_entry(self, &mut parent, &link, name)
+    }
+
+    fn readlink(&self, path: &str) -> KResult<String> {
+        let link = self.lookup(path)?;
+        if !link.is_symlink() {
+            return Err(KError::EINVAL);
+        }
+
+        let mut target = alloc::vec![0u8; link.size()];
+        link.read(self, 0, target.len(), target.as_mut_ptr())?;
+        String::from_utf8(target).map_err(|_| KError::EINVAL)
+    }
+
+    /*
+        A rename within a directory just rewrites the name if it fits. Otherwise the writes
+        are ordered so that a crash at any point leaves the file reachable by one of its
+        names: the link count goes up before the new entry is written and only comes back
+        down once the old one is gone, so at worst it's one too high, which leaks the inode
+        instead of freeing it while an entry still points to it
+    */
+    fn rename(&self, old: &str, new: &str) -> KResult<()> {
+        if self.read_only {
+            return Err(KError::EROFS);
+        }
+
+        let (mut old_dir, old_name) = self.lookup_parent(old)?;
+        let (mut new_dir, new_name) = self.lookup_parent(new)?;
+        let mut inode = Inode::get(self, DirectoryEntry::search(self, &old_dir, old_name)?)?;
+        let same_dir = old_dir.inode_number == new_dir.inode_number;
+
+        let target = match DirectoryEntry::search(self, &new_dir, new_name) {
+            Ok(target) => Some(Inode::get(self, target)?),
+            Err(KError::ENOENT) => None,
+            Err(err) => return Err(err),
+        };
+
+        if let Some(target) = &target {
+            // both names are already the same file
+            if target.inode_number == inode.inode_number {
+                return Ok(());
+            }
+            // there's no removing directories yet, so none is ever replaced
+            if target.is_directory() {
+                return Err(if inode.is_directory() { KError::EEXIST } else { KError::EISDIR });
+            }
+            if inode.is_directory() {
+                return Err(KError::ENOTDIR);
+            }
+        }
+
+        if inode.is_directory() && !same_dir {
+            if self.is_ancestor(inode.inode_number, new_dir.inode_number)? {
+                return Err(KError::EINVAL);
+            }
+            if new_dir.ref_cnt == u16::MAX {
+                return Err(KError::EMLINK);
+            }
+        }
+
+        if same_dir
+            && target.is_none()
+            && DirectoryEntry::rename_entry(self, &mut old_dir, old_name, new_name)?
+        {
+            return Ok(());
+        }
+
+        if inode.ref_cnt == u16::MAX {
+            return Err(KError::EMLINK);
+        }
+        self.add_links(&mut inode, 1)?;
+
+        let added = match &target {
+            Some(_) => {
+                DirectoryEntry::replace_entry(self, &mut new_dir, new_name, &inode).map(|_| ())
+            }
+            None => DirectoryEntry::add_entry(self, &mut new_dir, &inode, new_name),
+        };
+        if let Err(err) = added {
+            self.add_links(&mut inode, -1)?;
+            return Err(err);
+        }
+        if let Some(target) = target {
+            self.drop_link(target)?;
+        }
+
+        DirectoryEntry::remove_entry(self, &mut old_dir, old_name)?;
+        self.add_links(&mut inode, -1)?;
+
+        // .. goes to the new parent, which gets the link the old one loses
+        if inode.is_directory() && !same_dir {
+            self.add_links(&mut new_dir, 1)?;
+            DirectoryEntry::replace_entry(self, &mut inode, "..", &new_dir)?;
+            self.add_links(&mut old_dir, -1)?;
+        }
+
+        Ok(())
+    }
+
+    fn attributes(&self, index: usize) -> Option<vfs::Attributes> {
+        let file = self.open_file(index).ok()?;
+        let attributes = file.lock().inode.attributes();
+        Some(attributes)
+    }
+
+    fn close(&self, index: usize) {
+        let mut open_files = self.open_files.lock();
+        let file = match open_files.get(index).and_then(|slot| slot.clone()) {
+            Some(file) => file,
+            None => return,
+        };
+
+        let mut file = file.lock();
+        file.opens -= 1;
+        if file.opens > 0 {
+            return;
+        }
+
+        // still in the table, so an open in the meantime can't read the inode before this
+        if let Err(err) = file.flush(self) {
+            let inode_number = file.inode.inode_number;
+            log::error!("[EXT2] Could not write back inode {}: {}\n", inode_number, err);
+        }
+        open_files[index] = None;
+        drop(open_files);
+
+        if file.inode.ref_cnt == 0 {
+            let inode = core::mem::replace(&mut file.inode, Inode::new(0, 0));
+            if let Err(err) = self.free_unlinked(inode) {
+                log::error!("[EXT2] Could not free an unlinked inode: {}\n", err);
+            }
         }
     }
+
+    fn chmod(&self, path: &str, permissions: vfs::FilePermissions) -> KResult<()> {
+        if self.read_only {
+            return Err(KError::EROFS);
+        }
+
+        let mut inode = self.lookup(path)?;
+        vfs::check_chmod(&inode.attributes())?;
+
+        let type_and_permissions = inode.type_and_permissions & 0xf000 | permissions.bits();
+        inode.type_and_permissions = type_and_permissions;
+        self.update_open(inode.inode_number, |file| {
+            file.type_and_permissions = type_and_permissions;
+        });
+        inode.flush(self)
+    }
+
+    // only the low 16 bits of the ids are kept, so bigger ones can't be stored
+    fn chown(&self, path: &str, uid: Option<u32>, gid: Option<u32>) -> KResult<()> {
+        if self.read_only {
+            return Err(KError::EROFS);
+        }
+        if uid.max(gid).map_or(false, |id| id > u16::MAX as u32) {
+            return Err(KError::EINVAL);
+        }
+
+        let mut inode = self.lookup(path)?;
+        let attributes = inode.attributes();
+        vfs::check_chown(&attributes, uid, gid)?;
+
+        let uid = uid.unwrap_or(attributes.uid) as u16;
+        let gid = gid.unwrap_or(attributes.gid) as u16;
+        let mut type_and_permissions = inode.type_and_permissions;
+        // a program given to someone else mustn't keep running as its old owner
+        if uid as u32 != attributes.uid || gid as u32 != attributes.gid {
+            let set_ids = vfs::FilePermissions::SET_UID | vfs::FilePermissions::SET_GID;
+            type_and_permissions &= !set_ids.bits();
+        }
+
+        inode.user_id = uid;
+        inode.group_id = gid;
+        inode.type_and_permissions = type_and_permissions;
+        self.update_open(inode.inode_number, |file| {
+            file.user_id = uid;
+            file.group_id = gid;
+            file.type_and_permissions = type_and_permissions;
+        });
+        inode.flush(self)
+    }
+
+    fn read(&self, index: usize, buffer: *mut u8, cnt: usize, offset: usize) -> KResult<usize> {
+        let file = self.open_file(index)?;
+        let file = &mut *file.lock();
+        file.inode.read_mapped(self, &mut file.map, offset, cnt, buffer)
+    }
+
+    fn readdir(&self, index: usize, offset: usize) -> KResult<Option<vfs::DirEntry>> {
+        let dir = self.open_file(index)?;
+        let dir = &mut *dir.lock();
+        DirectoryEntry::read_entry(self, &dir.inode, &mut dir.map, offset)
+    }
+
+    fn write(&self, index: usize, buffer: *const u8, cnt: usize, offset: usize) -> KResult<usize> {
+        if self.read_only {
+            return Err(KError::EROFS);
+        }
+
+        let file = self.open_file(index)?;
+        let file = &mut *file.lock();
+        let written = file.inode.write_mapped(self, &mut file.map, offset, cnt, buffer)?;
+
+        // written back with the next sync or the last close
+        file.inode.last_mod_time = vdso::unix_time() as u32;
+        file.dirty = true;
+        Ok(written)
+    }
+
+    // one read-ahead per open file, so only the first contiguous run of blocks is read ahead
+    fn readahead(&self, index: usize, offset: usize, cnt: usize) {
+        let file = match self.open_file(index) {
+            Ok(file) => file,
+            Err(_) => return,
+        };
+        let file = &mut *file.lock();
+
+        let end = (offset + cnt).min(file.inode.size());
+        if offset >= end {
+            return;
+        }
+
+        let first_block = offset / self.block_size;
+        let last_block =
+            div_ceil(end, self.block_size).min(first_block + MAX_READAHEAD / self.block_size);
+
+        let start = match file.inode.get_block_address(self, &mut file.map, first_block) {
+            Ok(block) if block != 0 => block,
+            _ => return,
+        };
+
+        let mut run = 1;
+        for i in first_block + 1..last_block {
+            match file.inode.get_block_address(self, &mut file.map, i) {
+                Ok(block) if block == start + run as u32 => run += 1,
+                _ => break,
+            }
+        }
+
+        let disk_offset = (self.starting_lba * 512 + start as usize * self.block_size) as u64;
+        file.map.start_readahead(self, disk_offset, run * self.block_size);
+    }
 }
 
-pub fn try_and_init(starting_lba: u64) -> Result<(), ()> {
+// the probe for the filesystem registry, see probe.rs
+pub fn probe(device: Arc<dyn BlockDevice>, starting_lba: u64) -> Option<Box<dyn vfs::Filesystem>> {
     let superblock = unsafe {
         alloc::alloc::alloc(alloc::alloc::Layout::new::<Superblock>()) as *mut Superblock
     };
 
     // superblock is always located at LBA 2 of the volume
-    ahci::read(
-        0,
-        (starting_lba + 2) * 512,
-        size_of::<Superblock>(),
-        superblock as *mut u8,
-    )?;
+    device
+        .read(
+            (starting_lba + 2) * 512,
+            size_of::<Superblock>(),
+            superblock as *mut u8,
+        )
+        .ok()?;
 
     let superblock = unsafe { Box::from_raw(superblock) };
 
     if superblock.signature != EXT2_SIGNATURE {
-        serial::print!("not ext2\n");
-        serial::print!("signature: {:#x}\n", superblock.signature);
-        return Err(());
+        log::debug!("[EXT2] not ext2, signature: {:#x}\n", superblock.signature);
+        return None;
+    }
+
+    let incompat = superblock.unsupported_incompat();
+    if incompat != 0 {
+        log::error!("[EXT2] Not mounting, unsupported incompat features: {:#x}\n", incompat);
+        return None;
+    }
+
+    let ro_compat = superblock.unsupported_ro_compat();
+    if ro_compat != 0 {
+        log::warning!("[EXT2] Read-only, unsupported ro_compat features: {:#x}\n", ro_compat);
     }
 
-    serial::print!("Found an ext2 filesystem!\n");
-    serial::print!(
-        "Block size: {}, Inode count: {}\n",
+    // a smaller stride would read the tables wrong
+    if superblock.inode_size() < size_of::<Inode>() {
+        log::error!("[EXT2] Not mounting, inodes of {} bytes\n", superblock.inode_size());
+        return None;
+    }
+
+    log::info!(
+        "[EXT2] Found an ext2 filesystem, block size: {}, inode count: {}\n",
         1024 << superblock.block_size,
         superblock.inode_cnt
     );
 
-    unsafe { EXT2_FS = Some(Arc::new(Ext2Filesystem::new(starting_lba, superblock))) };
-    Ok(())
-}
-
-pub fn get() -> &'static mut Ext2Filesystem {
-    unsafe {
-        &mut *(EXT2_FS.as_ref().unwrap().deref() as *const Ext2Filesystem as *mut Ext2Filesystem)
-    }
+    Some(Box::new(Ext2Filesystem::new(device, starting_lba, superblock)))
 }
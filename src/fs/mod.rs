@@ -1,3 +1,13 @@
+pub mod cpio;
+pub mod dcache;
+pub mod devfs;
 pub mod ext2;
+pub mod modfs;
 pub mod partitions;
+pub mod probe;
+pub mod procfs;
+pub mod ramfs;
+pub mod shmfs;
+pub mod uring;
 pub mod vfs;
+pub mod writeback;
@@ -0,0 +1,108 @@
+/*
+    The boot modules as read-only files, mounted on /boot/modules. A module's file is named
+    after the first word of its string, so "initramfs.cpio root" shows up as initramfs.cpio.
+    File index 0 is the directory, module i is file i + 1
+*/
+
+use super::vfs::{self, DirEntry, DirEntryType};
+use crate::boot::{self, Module};
+use crate::error::{KError, KResult};
+use crate::log;
+use alloc::string::String;
+
+const MOUNT_POINT: &str = "/boot/modules";
+const ROOT: usize = 0;
+
+pub struct Modfs;
+
+static MODFS: Modfs = Modfs;
+
+fn file_name(module: &Module) -> &str {
+    let path = module.name.split_whitespace().next().unwrap_or("");
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+fn module(index: usize) -> KResult<&'static Module> {
+    index
+        .checked_sub(1)
+        .and_then(|i| boot::modules().get(i))
+        .ok_or(KError::EBADF)
+}
+
+impl vfs::Filesystem for Modfs {
+    fn name(&self) -> &'static str {
+        "modfs"
+    }
+
+    fn open(
+        &self,
+        path: &str,
+        flags: vfs::Flags,
+        _mode: vfs::Mode,
+    ) -> KResult<vfs::FileDescription> {
+        if flags.intersects(vfs::Flags::O_WRONLY | vfs::Flags::O_RDWR | vfs::Flags::O_TRUNC) {
+            return Err(KError::EROFS);
+        }
+
+        let name = path.trim_matches('/');
+        let index = if name.is_empty() {
+            ROOT
+        } else {
+            boot::modules()
+                .iter()
+                .position(|module| file_name(module) == name)
+                .map(|i| i + 1)
+                .ok_or(KError::ENOENT)?
+        };
+
+        Ok(vfs::FileDescription::new(index, flags, &MODFS))
+    }
+
+    fn mkdir(&self, _path: &str, _mode: vfs::Mode) -> KResult<vfs::FileDescription> {
+        Err(KError::EROFS)
+    }
+
+    fn read(&self, index: usize, buffer: *mut u8, cnt: usize, offset: usize) -> KResult<usize> {
+        if index == ROOT {
+            return Err(KError::EISDIR);
+        }
+
+        let data = module(index)?.data();
+        let start = offset.min(data.len());
+        let read = cnt.min(data.len() - start);
+        unsafe {
+            buffer.copy_from(data[start..].as_ptr(), read);
+        }
+
+        Ok(read)
+    }
+
+    fn write(&self, _index: usize, _buffer: *const u8, _cnt: usize, _off: usize) -> KResult<usize> {
+        Err(KError::EROFS)
+    }
+
+    fn readdir(&self, index: usize, offset: usize) -> KResult<Option<DirEntry>> {
+        if index != ROOT {
+            return Err(KError::ENOTDIR);
+        }
+
+        Ok(boot::modules().get(offset).map(|module| DirEntry {
+            inode: offset as u64 + 1,
+            next_offset: offset + 1,
+            entry_type: DirEntryType::Normal,
+            name: String::from(file_name(module)),
+        }))
+    }
+}
+
+// after the root is mounted, there's nothing to show without modules
+pub fn init() {
+    if boot::modules().is_empty() {
+        return;
+    }
+
+    match vfs::mount(&MODFS, MOUNT_POINT) {
+        Ok(()) => log::info!("[MODFS] {} modules in {}\n", boot::modules().len(), MOUNT_POINT),
+        Err(err) => log::error!("[MODFS] Could not mount {}: {}\n", MOUNT_POINT, err),
+    }
+}
@@ -1,12 +1,18 @@
-use super::ext2;
-use crate::arch::mm::pmm::{self, PmmBox};
-use crate::drivers::ahci;
-use crate::serial;
-use crate::utils::math::div_ceil;
-use alloc::alloc::{alloc, dealloc, Layout};
+use crate::drivers::block::BlockDevice;
+use crate::error::{KError, KResult};
+use crate::log;
+use crate::utils::crc32::crc32;
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use core::fmt;
 use core::intrinsics::size_of;
 
+const SECTOR_SIZE: u64 = 512;
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+
+static mut PARTITIONS: Vec<Arc<Partition>> = alloc::vec![];
+
 #[repr(C, packed)]
+#[derive(Clone, Copy)]
 struct GptHeader {
     signature: [u8; 8],
     revision: u32,
@@ -25,75 +31,257 @@ struct GptHeader {
 }
 
 #[repr(C, packed)]
-#[derive(Debug)]
+#[derive(Clone, Copy)]
 struct GptPartitionEntry {
-    pt_guid: [u64; 2],
-    unique_guid: [u64; 2],
+    pt_guid: [u8; 16],
+    unique_guid: [u8; 16],
     start_lba: u64,
     end_lba: u64,
     attributes: u64,
-    name: [u8; 72],
+    name: [u16; 36],
 }
 
-pub fn scan() -> Result<(), ()> {
-    let gpt_header_layout = Layout::new::<GptHeader>();
-    let gpt_header = unsafe { &mut *(alloc(gpt_header_layout) as *mut GptHeader) };
-    ahci::read(
-        0,
-        512,
-        size_of::<GptHeader>(),
-        gpt_header as *mut GptHeader as *mut u8,
-    )?;
-
-    if gpt_header
-        .signature
-        .iter()
-        .zip(b"EFI PART".iter())
-        .all(|(a, b)| a != b)
-    {
-        return scan_mbr();
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    // the raw bytes of the well known type guids, in their on-disk mixed endian layout
+    pub const UNUSED: Guid = Guid([0; 16]);
+    pub const EFI_SYSTEM: Guid = Guid([
+        0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9,
+        0x3b,
+    ]);
+    pub const BIOS_BOOT: Guid = Guid([
+        0x48, 0x61, 0x68, 0x21, 0x49, 0x64, 0x6f, 0x6e, 0x74, 0x4e, 0x65, 0x65, 0x64, 0x45, 0x46,
+        0x49,
+    ]);
+    pub const LINUX_FILESYSTEM: Guid = Guid([
+        0xaf, 0x3d, 0xc6, 0x0f, 0x83, 0x84, 0x72, 0x47, 0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d,
+        0xe4,
+    ]);
+    pub const LINUX_SWAP: Guid = Guid([
+        0x6d, 0xfd, 0x57, 0x06, 0xab, 0xa4, 0xc4, 0x43, 0x84, 0xe5, 0x09, 0x33, 0xc8, 0x4b, 0x4f,
+        0x4f,
+    ]);
+
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Guid::UNUSED => "none",
+            Guid::EFI_SYSTEM => "EFI system",
+            Guid::BIOS_BOOT => "BIOS boot",
+            Guid::LINUX_FILESYSTEM => "Linux filesystem",
+            Guid::LINUX_SWAP => "Linux swap",
+            _ => "unknown",
+        }
     }
+}
 
-    serial::print!(
-        "revision: {}, starting lba: {}, partitions: {}, first and last block: {} and {}\n",
-        gpt_header.revision,
-        gpt_header.start_lba,
-        gpt_header.partition_entries,
-        gpt_header.first_usable,
-        gpt_header.last_usable
-    );
+impl fmt::Display for Guid {
+    // the first three groups are little endian, the last two big endian
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-",
+            b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9]
+        )?;
+
+        for byte in &b[10..] {
+            write!(f, "{:02X}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct Partition {
+    pub device: Arc<dyn BlockDevice>,
+    // e.g. ahci0p1, partitions are numbered from 1 like on linux
+    pub name: String,
+    pub label: String,
+    pub type_guid: Guid,
+    pub unique_guid: Guid,
+    pub start_lba: u64,
+    pub end_lba: u64,
+}
 
-    let gpt_entries = PmmBox::<GptPartitionEntry>::new(
-        gpt_header.partition_entries as usize * size_of::<GptPartitionEntry>(),
+impl Partition {
+    pub fn size(&self) -> u64 {
+        (self.end_lba - self.start_lba + 1) * SECTOR_SIZE
+    }
+}
+
+fn register(partition: Partition) {
+    log::info!(
+        "[PARTITIONS] {}: \"{}\" ({}), LBA {}-{}\n",
+        partition.name,
+        partition.label,
+        partition.type_guid.type_name(),
+        partition.start_lba,
+        partition.end_lba
     );
-    let gpt_entries_ptr = gpt_entries.as_mut_ptr();
 
-    ahci::read(
-        0,
-        gpt_header.start_lba * 512,
-        gpt_header.partition_entries as usize * size_of::<GptPartitionEntry>(),
-        gpt_entries_ptr as *mut u8,
-    )?;
+    unsafe { PARTITIONS.push(Arc::new(partition)) };
+}
+
+pub fn partitions() -> &'static [Arc<Partition>] {
+    unsafe { PARTITIONS.as_slice() }
+}
 
-    for i in 0..gpt_header.partition_entries {
-        let entry = unsafe { &*gpt_entries_ptr.offset(i as isize) };
+pub fn find(name: &str) -> Option<Arc<Partition>> {
+    partitions()
+        .iter()
+        .find(|partition| partition.name == name)
+        .cloned()
+}
+
+// the partitions of a device, in the order they appear in its table
+pub fn of_device(device_name: &str) -> Vec<Arc<Partition>> {
+    partitions()
+        .iter()
+        .filter(|partition| partition.device.name() == device_name)
+        .cloned()
+        .collect()
+}
+
+// reads the header at lba and checks its signature and checksum
+fn read_gpt_header(device: &Arc<dyn BlockDevice>, lba: u64) -> Option<GptHeader> {
+    let mut sector = [0u8; SECTOR_SIZE as usize];
+    device
+        .read(lba * SECTOR_SIZE, sector.len(), sector.as_mut_ptr())
+        .ok()?;
+
+    let header = unsafe { (sector.as_ptr() as *const GptHeader).read_unaligned() };
+
+    if &header.signature != GPT_SIGNATURE {
+        return None;
+    }
 
-        if entry.pt_guid[0] == 0 {
-            // unused entry
+    let hdr_size = header.hdr_size as usize;
+    if hdr_size < size_of::<GptHeader>() || hdr_size > sector.len() {
+        log::warning!("[GPT] Header at LBA {} has a bad size ({})\n", lba, hdr_size);
+        return None;
+    }
+
+    // the checksum is calculated with the checksum field zeroed
+    let checksum = header.checksum;
+    sector[16..20].fill(0);
+
+    if crc32(&sector[..hdr_size]) != checksum || header.hdr_lba != lba {
+        log::warning!("[GPT] Header at LBA {} is corrupted\n", lba);
+        return None;
+    }
+
+    Some(header)
+}
+
+fn read_gpt_entries(device: &Arc<dyn BlockDevice>, header: &GptHeader) -> Option<Vec<u8>> {
+    let entry_size = header.entry_size as usize;
+    if entry_size < size_of::<GptPartitionEntry>() {
+        log::warning!("[GPT] Bad partition entry size ({})\n", entry_size);
+        return None;
+    }
+
+    let mut entries = alloc::vec![0u8; header.partition_entries as usize * entry_size];
+    device
+        .read(
+            header.start_lba * SECTOR_SIZE,
+            entries.len(),
+            entries.as_mut_ptr(),
+        )
+        .ok()?;
+
+    if crc32(&entries) != header.pea_checksum {
+        log::warning!(
+            "[GPT] Partition entry array at LBA {} is corrupted\n",
+            { header.start_lba }
+        );
+        return None;
+    }
+
+    Some(entries)
+}
+
+fn decode_name(name: &[u16; 36]) -> String {
+    let len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+
+    char::decode_utf16(name[..len].iter().cloned())
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+// finds the partitions of device and adds them to the registry, without probing them
+pub fn scan(device: Arc<dyn BlockDevice>) -> KResult<()> {
+    let last_lba = (device.size() / SECTOR_SIZE)
+        .checked_sub(1)
+        .ok_or(KError::EINVAL)?;
+
+    // a corrupted primary header or entry array makes us fall back to the backup copy
+    // at the end of the disk
+    let primary = read_gpt_header(&device, 1);
+    let table = primary
+        .and_then(|header| Some((header, read_gpt_entries(&device, &header)?)))
+        .or_else(|| {
+            let alt_lba = primary.map_or(last_lba, |header| header.alt_hdr_lba);
+            let header = read_gpt_header(&device, alt_lba)?;
+            log::warning!("[GPT] Using the backup GPT of {}\n", device.name());
+            Some((header, read_gpt_entries(&device, &header)?))
+        });
+
+    let (header, entries) = match table {
+        Some(table) => table,
+        None => return scan_mbr(device),
+    };
+
+    log::debug!(
+        "[GPT] revision: {}, starting lba: {}, partitions: {}, first and last block: {} and {}\n",
+        { header.revision },
+        { header.start_lba },
+        { header.partition_entries },
+        { header.first_usable },
+        { header.last_usable }
+    );
+
+    for (i, raw_entry) in entries.chunks_exact(header.entry_size as usize).enumerate() {
+        let entry = unsafe { (raw_entry.as_ptr() as *const GptPartitionEntry).read_unaligned() };
+        let type_guid = Guid(entry.pt_guid);
+
+        if type_guid == Guid::UNUSED {
             continue;
         }
 
-        serial::print!("Found a partition at LBA {}\n", entry.start_lba);
-        ext2::try_and_init(entry.start_lba);
-    }
+        if entry.start_lba > entry.end_lba || entry.end_lba > last_lba {
+            log::warning!("[GPT] Partition {} of {} is out of bounds\n", i + 1, device.name());
+            continue;
+        }
 
-    unsafe {
-        dealloc(gpt_header as *mut GptHeader as *mut u8, gpt_header_layout);
+        register(Partition {
+            device: device.clone(),
+            name: format!("{}p{}", device.name(), i + 1),
+            label: decode_name(&{ entry.name }),
+            type_guid,
+            unique_guid: Guid(entry.unique_guid),
+            start_lba: entry.start_lba,
+            end_lba: entry.end_lba,
+        });
     }
 
     Ok(())
 }
 
-fn scan_mbr() -> Result<(), ()> {
-    todo!()
+fn scan_mbr(device: Arc<dyn BlockDevice>) -> KResult<()> {
+    // TODO: parse the partition table, for now the whole device is treated as one partition
+    let sectors = device.size() / SECTOR_SIZE;
+
+    register(Partition {
+        name: String::from(device.name()),
+        device,
+        label: String::new(),
+        type_guid: Guid::UNUSED,
+        unique_guid: Guid::UNUSED,
+        start_lba: 0,
+        end_lba: sectors - 1,
+    });
+
+    Ok(())
 }
@@ -0,0 +1,52 @@
+use super::partitions::Partition;
+use super::{ext2, vfs::Filesystem};
+use crate::drivers::block::BlockDevice;
+use crate::log;
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+
+static mut FS_DRIVERS: Vec<FilesystemDriver> = alloc::vec![];
+
+// a probe looks for its filesystem at start_lba, and returns it ready to be mounted
+pub struct FilesystemDriver {
+    pub name: &'static str,
+    pub probe: fn(device: Arc<dyn BlockDevice>, start_lba: u64) -> Option<Box<dyn Filesystem>>,
+}
+
+pub fn register(driver: FilesystemDriver) {
+    log::debug!("[FS] Registered the {} driver\n", driver.name);
+    unsafe { FS_DRIVERS.push(driver) };
+}
+
+pub fn drivers() -> &'static [FilesystemDriver] {
+    unsafe { FS_DRIVERS.as_slice() }
+}
+
+// registers the builtin filesystems
+pub fn init() {
+    register(FilesystemDriver {
+        name: "ext2",
+        probe: ext2::probe,
+    });
+}
+
+// tries every registered driver in order, the first one to recognize the volume wins.
+// filesystems live as long as the kernel, since the vfs only deals with static references
+pub fn probe(device: Arc<dyn BlockDevice>, start_lba: u64) -> Option<&'static dyn Filesystem> {
+    for driver in drivers() {
+        if let Some(fs) = (driver.probe)(device.clone(), start_lba) {
+            log::info!(
+                "[FS] Found {} at LBA {} of {}\n",
+                driver.name,
+                start_lba,
+                device.name()
+            );
+            return Some(Box::leak(fs));
+        }
+    }
+
+    None
+}
+
+pub fn probe_partition(partition: &Partition) -> Option<&'static dyn Filesystem> {
+    probe(partition.device.clone(), partition.start_lba)
+}
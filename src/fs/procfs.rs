@@ -0,0 +1,304 @@
+/*
+    Kernel state as read-only text files, mounted on /proc. Nothing is stored, a file's
+    content is generated again on every read and the offset is into that.
+
+    File index 0 is the directory, 1 to 3 are meminfo, uptime and interrupts. A process
+    gets the indexes from (pid + 1) * 4 up: its directory, then its status and maps files
+*/
+
+use super::vfs::{self, DirEntry, DirEntryType};
+use crate::arch::interrupts;
+use crate::arch::mm::pmm;
+use crate::drivers::timer_source;
+use crate::error::{KError, KResult};
+use crate::log;
+use crate::mm::{vmalloc, vmm::MapProt};
+use crate::proc::process::{Process, Status};
+use crate::proc::scheduler;
+use alloc::{format, rc::Rc, string::String, vec::Vec};
+use core::cell::RefCell;
+use core::fmt::Write;
+
+const MOUNT_POINT: &str = "/proc";
+const ROOT: usize = 0;
+const MEMINFO: usize = 1;
+const UPTIME: usize = 2;
+const INTERRUPTS: usize = 3;
+
+// a process's files, the low bits of their index
+const KINDS: usize = 4;
+const DIRECTORY: usize = 0;
+const STATUS: usize = 1;
+const MAPS: usize = 2;
+
+const FILES: [(&str, usize); 3] = [
+    ("meminfo", MEMINFO),
+    ("uptime", UPTIME),
+    ("interrupts", INTERRUPTS),
+];
+const PROCESS_FILES: [(&str, usize); 2] = [("status", STATUS), ("maps", MAPS)];
+
+pub struct Procfs;
+
+static PROCFS: Procfs = Procfs;
+
+fn process_index(pid: usize, kind: usize) -> usize {
+    (pid + 1) * KINDS + kind
+}
+
+// the pid and kind of a process's file
+fn split_index(index: usize) -> Option<(usize, usize)> {
+    (index >= KINDS).then(|| (index / KINDS - 1, index % KINDS))
+}
+
+/*
+    The processes that can be looked at right now, by pid. One that's borrowed, like the
+    one in the middle of a getdents64 on /proc, is left out. None before the scheduler
+*/
+fn processes() -> Vec<Rc<RefCell<Process>>> {
+    let mut processes = match scheduler::try_get() {
+        Some(scheduler) => scheduler.processes(),
+        None => return Vec::new(),
+    };
+    processes.retain(|process| process.try_borrow().is_ok());
+    processes.sort_by_key(|process| process.borrow().pid);
+    processes
+}
+
+fn find_process(pid: usize) -> KResult<Rc<RefCell<Process>>> {
+    processes()
+        .into_iter()
+        .find(|process| process.borrow().pid == pid)
+        .ok_or(KError::ENOENT)
+}
+
+fn meminfo() -> String {
+    let kb = |pages: usize| pages as u64 * pmm::PAGE_SIZE / 1024;
+    let total = pmm::get().total_pages();
+    let free = pmm::get().free_pages();
+    let mut content = String::new();
+
+    writeln!(content, "MemTotal: {:>12} kB", kb(total)).ok();
+    writeln!(content, "MemFree: {:>13} kB", kb(free)).ok();
+    writeln!(content, "MemUsed: {:>13} kB", kb(total - free)).ok();
+    writeln!(content, "VmallocUsed: {:>9} kB", kb(vmalloc::used_pages())).ok();
+    content
+}
+
+// like linux's: seconds since boot and seconds the idle threads ran, with 2 decimals
+fn uptime() -> String {
+    let idle_ns: u64 = scheduler::try_get()
+        .map(|scheduler| {
+            scheduler
+                .idle_threads
+                .iter()
+                .filter_map(|thread| thread.try_borrow().ok().map(|idle| idle.stats.cpu_time_ns))
+                .sum()
+        })
+        .unwrap_or(0);
+    let cs = |ns: u64| ns / 10_000_000;
+
+    let now = cs(timer_source::current_ns());
+    let idle = cs(idle_ns);
+    format!("{}.{:02} {}.{:02}\n", now / 100, now % 100, idle / 100, idle % 100)
+}
+
+fn interrupts() -> String {
+    let mut content = String::new();
+    interrupts::write_stats(&mut content).ok();
+    content
+}
+
+fn status(process: &Process) -> String {
+    let state = match process.status {
+        Status::Running => "R (running)",
+        Status::Waiting => "S (sleeping)",
+        Status::Dying => "X (dying)",
+    };
+    let mut content = String::new();
+
+    writeln!(content, "Name:\t{}", process.name).ok();
+    writeln!(content, "State:\t{}", state).ok();
+    writeln!(content, "Pid:\t{}", process.pid).ok();
+    writeln!(content, "Pgid:\t{}", process.pgid).ok();
+    writeln!(content, "Sid:\t{}", process.sid).ok();
+    writeln!(content, "Threads:\t{}", process.threads.len()).ok();
+    if let Some(pagemap) = &process.pagemap {
+        let resident = pagemap.resident_pages() as u64 * pmm::PAGE_SIZE / 1024;
+        writeln!(content, "VmRSS:\t{} kB", resident).ok();
+    }
+    content
+}
+
+/*
+    One line per mapped range: "start-end perms offset file". No paths are kept, so a file
+    mapping is named by its filesystem and file index, e.g. ext2:12
+*/
+fn maps(process: &Process) -> String {
+    let mut content = String::new();
+    let pagemap = match &process.pagemap {
+        Some(pagemap) => pagemap,
+        None => return content,
+    };
+
+    for range in pagemap.ranges() {
+        let prot = range.prot();
+        let perm = |bit: MapProt, c: char| if prot.contains(bit) { c } else { '-' };
+        let file = match range.fd() {
+            Some(fd) => format!("{}:{}", fd.fs.name(), fd.file_index),
+            None => String::new(),
+        };
+
+        writeln!(
+            content,
+            "{:012x}-{:012x} {}{}{}{} {:08x} {}",
+            range.start(),
+            range.end(),
+            perm(MapProt::READ, 'r'),
+            perm(MapProt::WRITE, 'w'),
+            perm(MapProt::EXEC, 'x'),
+            if range.is_shared_map() { 's' } else { 'p' },
+            range.offset(),
+            file
+        )
+        .ok();
+    }
+    content
+}
+
+fn generate(index: usize) -> KResult<String> {
+    match index {
+        ROOT => Err(KError::EISDIR),
+        MEMINFO => Ok(meminfo()),
+        UPTIME => Ok(uptime()),
+        INTERRUPTS => Ok(interrupts()),
+        _ => {
+            let (pid, kind) = split_index(index).ok_or(KError::EBADF)?;
+            let process = find_process(pid)?;
+            let process = process.borrow();
+
+            match kind {
+                DIRECTORY => Err(KError::EISDIR),
+                STATUS => Ok(status(&process)),
+                MAPS => Ok(maps(&process)),
+                _ => Err(KError::EBADF),
+            }
+        }
+    }
+}
+
+// the index of a path relative to /proc
+fn lookup(path: &str) -> KResult<usize> {
+    let mut components = path.split('/').filter(|component| !component.is_empty());
+
+    let first = match components.next() {
+        Some(first) => first,
+        None => return Ok(ROOT),
+    };
+
+    if let Some((_, index)) = FILES.iter().find(|(name, _)| *name == first) {
+        return match components.next() {
+            Some(_) => Err(KError::ENOTDIR),
+            None => Ok(*index),
+        };
+    }
+
+    let pid: usize = first.parse().map_err(|_| KError::ENOENT)?;
+    find_process(pid)?;
+
+    let kind = match components.next() {
+        None => DIRECTORY,
+        Some(name) => PROCESS_FILES
+            .iter()
+            .find(|(file, _)| *file == name)
+            .map(|(_, kind)| *kind)
+            .ok_or(KError::ENOENT)?,
+    };
+    if components.next().is_some() {
+        return Err(if kind == DIRECTORY { KError::ENOENT } else { KError::ENOTDIR });
+    }
+
+    Ok(process_index(pid, kind))
+}
+
+impl vfs::Filesystem for Procfs {
+    fn name(&self) -> &'static str {
+        "procfs"
+    }
+
+    fn open(
+        &self,
+        path: &str,
+        flags: vfs::Flags,
+        _mode: vfs::Mode,
+    ) -> KResult<vfs::FileDescription> {
+        if flags.intersects(vfs::Flags::O_WRONLY | vfs::Flags::O_RDWR | vfs::Flags::O_TRUNC) {
+            return Err(KError::EROFS);
+        }
+
+        Ok(vfs::FileDescription::new(lookup(path)?, flags, &PROCFS))
+    }
+
+    fn mkdir(&self, _path: &str, _mode: vfs::Mode) -> KResult<vfs::FileDescription> {
+        Err(KError::EROFS)
+    }
+
+    fn read(&self, index: usize, buffer: *mut u8, cnt: usize, offset: usize) -> KResult<usize> {
+        let content = generate(index)?;
+        let data = content.as_bytes();
+        let start = offset.min(data.len());
+        let read = cnt.min(data.len() - start);
+        unsafe {
+            buffer.copy_from(data[start..].as_ptr(), read);
+        }
+
+        Ok(read)
+    }
+
+    fn write(&self, _index: usize, _buffer: *const u8, _cnt: usize, _off: usize) -> KResult<usize> {
+        Err(KError::EROFS)
+    }
+
+    fn readdir(&self, index: usize, offset: usize) -> KResult<Option<DirEntry>> {
+        if index == ROOT {
+            if let Some((name, index)) = FILES.get(offset) {
+                return Ok(Some(DirEntry {
+                    inode: *index as u64,
+                    next_offset: offset + 1,
+                    entry_type: DirEntryType::Normal,
+                    name: String::from(*name),
+                }));
+            }
+
+            // the pids in order, a process that exits between two calls is just skipped
+            let pid = processes().get(offset - FILES.len()).map(|process| process.borrow().pid);
+            return Ok(pid.map(|pid| DirEntry {
+                inode: process_index(pid, DIRECTORY) as u64,
+                next_offset: offset + 1,
+                entry_type: DirEntryType::Directory,
+                name: format!("{}", pid),
+            }));
+        }
+
+        match split_index(index) {
+            Some((pid, DIRECTORY)) => {
+                find_process(pid)?;
+                Ok(PROCESS_FILES.get(offset).map(|(name, kind)| DirEntry {
+                    inode: process_index(pid, *kind) as u64,
+                    next_offset: offset + 1,
+                    entry_type: DirEntryType::Normal,
+                    name: String::from(*name),
+                }))
+            }
+            _ => Err(KError::ENOTDIR),
+        }
+    }
+}
+
+// after the root is mounted
+pub fn init() {
+    match vfs::mount(&PROCFS, MOUNT_POINT) {
+        Ok(()) => log::info!("[PROCFS] Mounted on {}\n", MOUNT_POINT),
+        Err(err) => log::error!("[PROCFS] Could not mount {}: {}\n", MOUNT_POINT, err),
+    }
+}
@@ -0,0 +1,431 @@
+/*
+    A filesystem that only lives in memory, what an initramfs is unpacked into. Nodes are
+    kept in a table and a file index is just the position of a node in it. Directories
+    map names to nodes, so a node can be linked from more than one of them.
+
+    One is also mounted on /tmp, like linux's tmpfs, for what's only needed until a reboot
+*/
+
+use super::vfs::{self, DirEntry, DirEntryType, FileType};
+use crate::error::{KError, KResult};
+use crate::log;
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+const ROOT: usize = 0;
+const TMP_MOUNT_POINT: &str = "/tmp";
+// how many symlinks can be followed while resolving one path
+const MAX_SYMLINK_DEPTH: usize = 8;
+const FILE_TYPE_MASK: u16 = 0xf000;
+
+pub const DEFAULT_DIR_MODE: u16 = FileType::DIRECTORY.bits() | 0o755;
+pub const DEFAULT_FILE_MODE: u16 = FileType::NORMAL.bits() | 0o644;
+
+struct Node {
+    mode: u16, // file type and permissions, like an ext2 inode's
+    data: Vec<u8>, // the contents of a file, or the target of a symlink
+    children: Vec<(String, usize)>,
+    parent: usize, // the directory it was created in
+}
+
+impl Node {
+    fn new(mode: u16, parent: usize) -> Self {
+        Node {
+            mode,
+            data: Vec::new(),
+            children: Vec::new(),
+            parent,
+        }
+    }
+
+    fn file_type(&self) -> u16 {
+        self.mode & FILE_TYPE_MASK
+    }
+
+    fn is_directory(&self) -> bool {
+        self.file_type() == FileType::DIRECTORY.bits()
+    }
+
+    fn is_symlink(&self) -> bool {
+        self.file_type() == FileType::SYMLINK.bits()
+    }
+
+    fn child(&self, name: &str) -> Option<usize> {
+        self.children
+            .iter()
+            .find(|(child, _)| child == name)
+            .map(|(_, index)| *index)
+    }
+}
+
+pub struct Metadata {
+    pub mode: u16,
+    pub size: usize,
+}
+
+pub struct Ramfs {
+    nodes: spin::Mutex<Vec<Node>>,
+}
+
+// splits a path into its parent and the last component, ignoring repeated and trailing slashes
+fn split_last(path: &str) -> Option<(&str, &str)> {
+    let path = path.trim_end_matches('/');
+
+    match path.rsplit_once('/') {
+        Some((parent, name)) if !name.is_empty() => Some((parent, name)),
+        None if !path.is_empty() => Some(("", path)),
+        _ => None,
+    }
+}
+
+fn entry_type(mode: u16) -> DirEntryType {
+    match FileType::from_bits_truncate(mode & FILE_TYPE_MASK) {
+        FileType::FIFO => DirEntryType::Fifo,
+        FileType::CHAR_DEVICE => DirEntryType::CharDevice,
+        FileType::DIRECTORY => DirEntryType::Directory,
+        FileType::BLOCK_DEVICE => DirEntryType::BlockDevice,
+        FileType::NORMAL => DirEntryType::Normal,
+        FileType::SYMLINK => DirEntryType::Symlink,
+        FileType::SOCKET => DirEntryType::Socket,
+        _ => DirEntryType::Unknown,
+    }
+}
+
+impl Ramfs {
+    pub fn new() -> Self {
+        Ramfs {
+            nodes: spin::Mutex::new(alloc::vec![Node::new(DEFAULT_DIR_MODE, ROOT)]),
+        }
+    }
+
+    /*
+        Follows path from the directory start. Symlinks are followed on the way, and also
+        at the end if follow is set. Absolute targets start over from the root of the ramfs
+    */
+    fn walk(
+        nodes: &[Node],
+        start: usize,
+        path: &str,
+        follow: bool,
+        depth: usize,
+    ) -> KResult<usize> {
+        let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        let mut current = if path.starts_with('/') { ROOT } else { start };
+
+        for (i, component) in components.iter().enumerate() {
+            let directory = current;
+            if !nodes[directory].is_directory() {
+                return Err(KError::ENOTDIR);
+            }
+
+            current = match *component {
+                "." => directory,
+                ".." => nodes[directory].parent,
+                name => nodes[directory].child(name).ok_or(KError::ENOENT)?,
+            };
+
+            let last = i + 1 == components.len();
+            if nodes[current].is_symlink() && (follow || !last) {
+                if depth == MAX_SYMLINK_DEPTH {
+                    return Err(KError::ELOOP);
+                }
+
+                let target = core::str::from_utf8(&nodes[current].data)
+                    .map_err(|_| KError::EINVAL)?;
+                current = Ramfs::walk(nodes, directory, target, true, depth + 1)?;
+            }
+        }
+
+        Ok(current)
+    }
+
+    fn lookup(&self, path: &str, follow: bool) -> KResult<usize> {
+        Ramfs::walk(&self.nodes.lock(), ROOT, path, follow, 0)
+    }
+
+    // adds a node named after the last component of path, whose parent has to exist
+    fn insert(&self, path: &str, node: Node) -> KResult<usize> {
+        let (parent_path, name) = split_last(path).ok_or(KError::EEXIST)?;
+        let parent = self.lookup(parent_path, true)?;
+
+        let mut nodes = self.nodes.lock();
+        if !nodes[parent].is_directory() {
+            return Err(KError::ENOTDIR);
+        }
+        if nodes[parent].child(name).is_some() {
+            return Err(KError::EEXIST);
+        }
+
+        let index = nodes.len();
+        nodes.push(Node { parent, ..node });
+        nodes[parent].children.push((String::from(name), index));
+
+        Ok(index)
+    }
+
+    pub fn create(&self, path: &str, mode: u16, data: &[u8]) -> KResult<usize> {
+        let mut node = Node::new(mode, ROOT);
+        node.data.extend_from_slice(data);
+        self.insert(path, node)
+    }
+
+    pub fn create_dir(&self, path: &str, mode: u16) -> KResult<usize> {
+        self.insert(path, Node::new(FileType::DIRECTORY.bits() | mode & 0o7777, ROOT))
+    }
+
+    pub fn symlink(&self, path: &str, target: &str) -> KResult<usize> {
+        self.create(path, FileType::SYMLINK.bits() | 0o777, target.as_bytes())
+    }
+
+    // makes path another name for the node at index
+    pub fn link(&self, path: &str, index: usize) -> KResult<()> {
+        let (parent_path, name) = split_last(path).ok_or(KError::EEXIST)?;
+        let parent = self.lookup(parent_path, true)?;
+
+        let mut nodes = self.nodes.lock();
+        if index >= nodes.len() || nodes[index].is_directory() {
+            return Err(KError::EPERM);
+        }
+        if nodes[parent].child(name).is_some() {
+            return Err(KError::EEXIST);
+        }
+
+        nodes[parent].children.push((String::from(name), index));
+        Ok(())
+    }
+
+    // takes the name away, the node stays in the table since nodes are never reused
+    pub fn unlink(&self, path: &str) -> KResult<()> {
+        let (parent_path, name) = split_last(path).ok_or(KError::EISDIR)?;
+        let parent = self.lookup(parent_path, true)?;
+
+        let mut nodes = self.nodes.lock();
+        let index = nodes[parent].child(name).ok_or(KError::ENOENT)?;
+        if nodes[index].is_directory() {
+            return Err(KError::EISDIR);
+        }
+
+        nodes[parent].children.retain(|(child, _)| child != name);
+        Ok(())
+    }
+
+    // moves the node named old to new, replacing a file or an empty directory that's there
+    pub fn rename(&self, old: &str, new: &str) -> KResult<()> {
+        let (old_parent_path, old_name) = split_last(old).ok_or(KError::EBUSY)?;
+        let (new_parent_path, new_name) = split_last(new).ok_or(KError::EBUSY)?;
+        let old_parent = self.lookup(old_parent_path, true)?;
+        let new_parent = self.lookup(new_parent_path, true)?;
+
+        let mut nodes = self.nodes.lock();
+        if !nodes[new_parent].is_directory() {
+            return Err(KError::ENOTDIR);
+        }
+        let index = nodes[old_parent].child(old_name).ok_or(KError::ENOENT)?;
+        let is_directory = nodes[index].is_directory();
+
+        // a directory can't go under itself
+        if is_directory {
+            let mut ancestor = new_parent;
+            while ancestor != ROOT {
+                if ancestor == index {
+                    return Err(KError::EINVAL);
+                }
+                ancestor = nodes[ancestor].parent;
+            }
+        }
+
+        if let Some(existing) = nodes[new_parent].child(new_name) {
+            if existing == index {
+                return Ok(());
+            }
+
+            match (is_directory, nodes[existing].is_directory()) {
+                (false, true) => return Err(KError::EISDIR),
+                (true, false) => return Err(KError::ENOTDIR),
+                (true, true) if !nodes[existing].children.is_empty() => {
+                    return Err(KError::ENOTEMPTY)
+                }
+                _ => {}
+            }
+            nodes[new_parent].children.retain(|(child, _)| child != new_name);
+        }
+
+        nodes[old_parent].children.retain(|(child, _)| child != old_name);
+        nodes[new_parent].children.push((String::from(new_name), index));
+        if is_directory {
+            nodes[index].parent = new_parent;
+        }
+
+        Ok(())
+    }
+
+    // creates every missing directory along path
+    pub fn create_dirs(&self, path: &str) -> KResult<()> {
+        let mut current = String::new();
+
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            current.push('/');
+            current.push_str(component);
+
+            match self.create_dir(&current, 0o755) {
+                Ok(_) | Err(KError::EEXIST) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn set_data(&self, index: usize, data: &[u8]) -> KResult<()> {
+        let mut nodes = self.nodes.lock();
+        let node = nodes.get_mut(index).ok_or(KError::EBADF)?;
+
+        node.data.clear();
+        node.data.extend_from_slice(data);
+        Ok(())
+    }
+
+    pub fn readlink(&self, path: &str) -> KResult<String> {
+        let index = self.lookup(path, false)?;
+        let nodes = self.nodes.lock();
+
+        if !nodes[index].is_symlink() {
+            return Err(KError::EINVAL);
+        }
+
+        String::from_utf8(nodes[index].data.clone()).map_err(|_| KError::EINVAL)
+    }
+
+    fn new_fd(&self, index: usize, flags: vfs::Flags) -> vfs::FileDescription {
+        // mounted filesystems are never freed, so this is really static
+        let fs: &'static Ramfs = unsafe { &*(self as *const Ramfs) };
+        vfs::FileDescription::new(index, flags, fs)
+    }
+
+    pub fn metadata(&self, index: usize) -> KResult<Metadata> {
+        let nodes = self.nodes.lock();
+        let node = nodes.get(index).ok_or(KError::EBADF)?;
+
+        Ok(Metadata {
+            mode: node.mode,
+            size: node.data.len(),
+        })
+    }
+}
+
+impl vfs::Filesystem for Ramfs {
+    fn name(&self) -> &'static str {
+        "ramfs"
+    }
+
+    fn open(
+        &self,
+        path: &str,
+        flags: vfs::Flags,
+        mode: vfs::Mode,
+    ) -> KResult<vfs::FileDescription> {
+        let index = match self.lookup(path, true) {
+            Ok(index) => index,
+            Err(KError::ENOENT) if flags.contains(vfs::Flags::O_CREAT) => {
+                // no mode at all is the default, not a file nobody can use
+                let mode = match mode.bits() as u16 {
+                    0 => DEFAULT_FILE_MODE,
+                    permissions => FileType::NORMAL.bits() | permissions,
+                };
+                self.create(path, mode, &[])?
+            }
+            Err(err) => return Err(err),
+        };
+
+        if flags.contains(vfs::Flags::O_TRUNC) {
+            let mut nodes = self.nodes.lock();
+            if !nodes[index].is_directory() {
+                nodes[index].data.clear();
+            }
+        }
+
+        Ok(self.new_fd(index, flags))
+    }
+
+    fn mkdir(&self, path: &str, _mode: vfs::Mode) -> KResult<vfs::FileDescription> {
+        let index = self.create_dir(path, 0o755)?;
+        Ok(self.new_fd(index, vfs::Flags::O_RDONLY))
+    }
+
+    fn symlink(&self, target: &str, path: &str) -> KResult<()> {
+        Ramfs::symlink(self, path, target).map(|_| ())
+    }
+
+    fn readlink(&self, path: &str) -> KResult<String> {
+        Ramfs::readlink(self, path)
+    }
+
+    fn unlink(&self, path: &str) -> KResult<()> {
+        Ramfs::unlink(self, path)
+    }
+
+    fn rename(&self, old: &str, new: &str) -> KResult<()> {
+        Ramfs::rename(self, old, new)
+    }
+
+    fn read(&self, index: usize, buffer: *mut u8, cnt: usize, offset: usize) -> KResult<usize> {
+        let nodes = self.nodes.lock();
+        let node = nodes.get(index).ok_or(KError::EBADF)?;
+
+        if node.is_directory() {
+            return Err(KError::EISDIR);
+        }
+
+        let start = offset.min(node.data.len());
+        let read = cnt.min(node.data.len() - start);
+        unsafe {
+            buffer.copy_from(node.data[start..].as_ptr(), read);
+        }
+
+        Ok(read)
+    }
+
+    fn write(&self, index: usize, buffer: *const u8, cnt: usize, offset: usize) -> KResult<usize> {
+        let mut nodes = self.nodes.lock();
+        let node = nodes.get_mut(index).ok_or(KError::EBADF)?;
+
+        if node.is_directory() {
+            return Err(KError::EISDIR);
+        }
+
+        // writing past the end leaves a zero filled gap
+        if node.data.len() < offset + cnt {
+            node.data.resize(offset + cnt, 0);
+        }
+        unsafe {
+            node.data[offset..].as_mut_ptr().copy_from(buffer, cnt);
+        }
+
+        Ok(cnt)
+    }
+
+    fn readdir(&self, index: usize, offset: usize) -> KResult<Option<DirEntry>> {
+        let nodes = self.nodes.lock();
+        let node = nodes.get(index).ok_or(KError::EBADF)?;
+
+        if !node.is_directory() {
+            return Err(KError::ENOTDIR);
+        }
+
+        Ok(node.children.get(offset).map(|(name, child)| DirEntry {
+            inode: *child as u64,
+            next_offset: offset + 1,
+            entry_type: entry_type(nodes[*child].mode),
+            name: name.clone(),
+        }))
+    }
+}
+
+// after the root is mounted
+pub fn init() {
+    let tmp: &'static Ramfs = Box::leak(Box::new(Ramfs::new()));
+
+    match vfs::mount(tmp, TMP_MOUNT_POINT) {
+        Ok(()) => log::info!("[RAMFS] Mounted on {}\n", TMP_MOUNT_POINT),
+        Err(err) => log::error!("[RAMFS] Could not mount {}: {}\n", TMP_MOUNT_POINT, err),
+    }
+}
@@ -0,0 +1,279 @@
+/*
+    Named shared memory objects, the kernel side of shm_open. Every object is a file in a
+    flat directory mounted on /dev/shm, its pages are allocated the first time they're
+    written or mapped. A MAP_SHARED mapping of one maps its frames directly, so every
+    process that maps the same object sees the same memory.
+
+    The frames are counted (see mm::frame), the object holds a reference to each of its
+    pages and every mapping of one another. Unlinking (or shrinking) an object drops its
+    references, so a page that's still mapped stays until the last mapping goes. File
+    index 0 is the directory, object i is file i + 1
+*/
+
+use super::vfs::{self, DirEntry, DirEntryType};
+use crate::arch::mm::pmm::{self, PhysAddr};
+use crate::error::{KError, KResult};
+use crate::log;
+use crate::mm::frame::{self, FrameFlags};
+use crate::utils::math::div_ceil;
+use alloc::{string::String, vec::Vec};
+
+const MOUNT_POINT: &str = "/dev/shm";
+const ROOT: usize = 0;
+const PAGE_SIZE: usize = pmm::PAGE_SIZE as usize;
+
+struct Object {
+    name: String,
+    size: usize,
+    pages: Vec<Option<PhysAddr>>, // allocated on first use
+}
+
+impl Object {
+    // the frame of page n, allocating it if it's not there yet
+    fn page(&mut self, n: usize) -> KResult<PhysAddr> {
+        if self.pages.len() <= n {
+            self.pages.resize(n + 1, None);
+        }
+
+        match self.pages[n] {
+            Some(page) => Ok(page),
+            None => {
+                let page = pmm::get().calloc(1).map_err(|_| KError::ENOMEM)?;
+                frame::track(page, FrameFlags::SHMEM);
+                self.pages[n] = Some(page);
+                Ok(page)
+            }
+        }
+    }
+
+    // the pages past size are let go, a mapping that still has one keeps it
+    fn resize(&mut self, size: usize) {
+        if size < self.size {
+            let first_gone = div_ceil(size, PAGE_SIZE);
+
+            if let Some(Some(page)) = self.pages.get(size / PAGE_SIZE) {
+                let tail = size % PAGE_SIZE;
+                if tail != 0 {
+                    let start = unsafe { page.higher_half().as_mut_ptr::<u8>().add(tail) };
+                    unsafe { start.write_bytes(0, PAGE_SIZE - tail) };
+                }
+            }
+
+            for page in self.pages.iter_mut().skip(first_gone) {
+                if let Some(page) = page.take() {
+                    frame::put(page);
+                }
+            }
+        }
+
+        self.size = size;
+    }
+}
+
+pub struct Shmfs {
+    objects: spin::Mutex<Vec<Option<Object>>>,
+}
+
+static SHMFS: Shmfs = Shmfs {
+    objects: spin::Mutex::new(Vec::new()),
+};
+
+fn object_name(path: &str) -> KResult<&str> {
+    let name = path.trim_start_matches('/');
+
+    if name.is_empty() || name.contains('/') {
+        return Err(KError::EINVAL);
+    }
+
+    Ok(name)
+}
+
+impl Shmfs {
+    fn find(objects: &[Option<Object>], name: &str) -> Option<usize> {
+        objects
+            .iter()
+            .position(|object| matches!(object, Some(object) if object.name == name))
+    }
+
+    fn object<T>(&self, index: usize, f: impl FnOnce(&mut Object) -> KResult<T>) -> KResult<T> {
+        if index == ROOT {
+            return Err(KError::EISDIR);
+        }
+
+        let mut objects = self.objects.lock();
+        match objects.get_mut(index - 1) {
+            Some(Some(object)) => f(object),
+            _ => Err(KError::EBADF),
+        }
+    }
+}
+
+impl vfs::Filesystem for Shmfs {
+    fn name(&self) -> &'static str {
+        "shmfs"
+    }
+
+    fn open(
+        &self,
+        path: &str,
+        flags: vfs::Flags,
+        _mode: vfs::Mode,
+    ) -> KResult<vfs::FileDescription> {
+        if path.trim_matches('/').is_empty() {
+            return Ok(vfs::FileDescription::new(ROOT, flags, &SHMFS));
+        }
+
+        let name = object_name(path)?;
+        let mut objects = self.objects.lock();
+
+        let index = match Shmfs::find(&objects, name) {
+            Some(index) => index,
+            None if flags.contains(vfs::Flags::O_CREAT) => {
+                let object = Object {
+                    name: String::from(name),
+                    size: 0,
+                    pages: Vec::new(),
+                };
+
+                // reuse the slot of an unlinked object
+                match objects.iter().position(|object| object.is_none()) {
+                    Some(index) => {
+                        objects[index] = Some(object);
+                        index
+                    }
+                    None => {
+                        objects.push(Some(object));
+                        objects.len() - 1
+                    }
+                }
+            }
+            None => return Err(KError::ENOENT),
+        };
+
+        if flags.contains(vfs::Flags::O_TRUNC) {
+            objects[index].as_mut().unwrap().resize(0);
+        }
+
+        Ok(vfs::FileDescription::new(index + 1, flags, &SHMFS))
+    }
+
+    fn mkdir(&self, _path: &str, _mode: vfs::Mode) -> KResult<vfs::FileDescription> {
+        Err(KError::EPERM)
+    }
+
+    fn read(&self, index: usize, buffer: *mut u8, cnt: usize, offset: usize) -> KResult<usize> {
+        self.object(index, |object| {
+            let end = (offset + cnt).min(object.size);
+            let mut position = offset;
+
+            while position < end {
+                let in_page = position % PAGE_SIZE;
+                let chunk = (PAGE_SIZE - in_page).min(end - position);
+                let dest = unsafe { buffer.add(position - offset) };
+
+                // a page that was never written is all zeroes
+                match object.pages.get(position / PAGE_SIZE) {
+                    Some(Some(page)) => unsafe {
+                        dest.copy_from(page.higher_half().as_ptr::<u8>().add(in_page), chunk);
+                    },
+                    _ => unsafe { dest.write_bytes(0, chunk) },
+                }
+
+                position += chunk;
+            }
+
+            Ok(end.saturating_sub(offset))
+        })
+    }
+
+    fn write(&self, index: usize, buffer: *const u8, cnt: usize, offset: usize) -> KResult<usize> {
+        self.object(index, |object| {
+            let mut position = offset;
+
+            while position < offset + cnt {
+                let in_page = position % PAGE_SIZE;
+                let chunk = (PAGE_SIZE - in_page).min(offset + cnt - position);
+                let page = object.page(position / PAGE_SIZE)?;
+
+                unsafe {
+                    page.higher_half()
+                        .as_mut_ptr::<u8>()
+                        .add(in_page)
+                        .copy_from(buffer.add(position - offset), chunk);
+                }
+
+                position += chunk;
+            }
+
+            object.size = object.size.max(offset + cnt);
+            Ok(cnt)
+        })
+    }
+
+    fn readdir(&self, index: usize, offset: usize) -> KResult<Option<DirEntry>> {
+        if index != ROOT {
+            return Err(KError::ENOTDIR);
+        }
+
+        let objects = self.objects.lock();
+        let found = objects.iter().enumerate().skip(offset).find_map(|(i, object)| {
+            object.as_ref().map(|object| (i, object))
+        });
+
+        Ok(found.map(|(i, object)| DirEntry {
+            inode: i as u64 + 1,
+            next_offset: i + 1,
+            entry_type: DirEntryType::Normal,
+            name: object.name.clone(),
+        }))
+    }
+
+    // descriptors that are still open get EBADF afterwards
+    fn unlink(&self, path: &str) -> KResult<()> {
+        let name = object_name(path)?;
+        let mut objects = self.objects.lock();
+        let index = Shmfs::find(&objects, name).ok_or(KError::ENOENT)?;
+
+        if let Some(mut object) = objects[index].take() {
+            object.resize(0);
+        }
+
+        Ok(())
+    }
+
+    fn truncate(&self, index: usize, size: usize) -> KResult<()> {
+        self.object(index, |object| {
+            object.resize(size);
+            Ok(())
+        })
+    }
+
+    fn page(&self, index: usize, offset: usize) -> KResult<Option<PhysAddr>> {
+        self.object(index, |object| {
+            if offset >= object.size {
+                return Err(KError::EINVAL);
+            }
+
+            let page = object.page(offset / PAGE_SIZE)?;
+            frame::get(page);
+            Ok(Some(page))
+        })
+    }
+}
+
+// shm_open, name is what the object is called in /dev/shm, a leading slash is optional
+pub fn open(name: &str, flags: vfs::Flags, mode: vfs::Mode) -> KResult<vfs::FileDescription> {
+    vfs::Filesystem::open(&SHMFS, object_name(name)?, flags, mode)
+}
+
+// shm_unlink, the object stays usable through the mappings it already has
+pub fn unlink(name: &str) -> KResult<()> {
+    vfs::Filesystem::unlink(&SHMFS, object_name(name)?)
+}
+
+pub fn init() {
+    match vfs::mount(&SHMFS, MOUNT_POINT) {
+        Ok(()) => log::debug!("[SHMFS] Mounted on {}\n", MOUNT_POINT),
+        Err(err) => log::error!("[SHMFS] Could not mount {}: {}\n", MOUNT_POINT, err),
+    }
+}
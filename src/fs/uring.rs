@@ -0,0 +1,400 @@
+/*
+    Rings shared between a process and the kernel to batch i/o through, the kernel side of
+    io_uring. A ring is one region (like IORING_FEAT_SINGLE_MMAP): the submission ring's
+    header and its array of entry indexes, the completion ring's header and its entries,
+    and then the submission entries themselves. Userspace fills in entries, puts their
+    indexes on the submission ring and moves its tail, and the ring's worker does them in
+    order and puts a completion for each on the completion ring (see syscall/uring.rs).
+
+    Every ring is a file of its own here, that's what its descriptor and mappings hold on
+    to. The region's frames are counted (see mm::frame), the ring holds a reference to each
+    and so does every mapping, the ring goes away with the last descriptor
+*/
+
+use super::vfs;
+use crate::arch::mm::pmm::{self, PhysAddr};
+use crate::error::{KError, KResult};
+use crate::mm::frame::{self, FrameFlags};
+use crate::proc::wait::WaitQueue;
+use crate::utils::math::round_up;
+use alloc::{sync::Arc, vec::Vec};
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+pub const IORING_OP_NOP: u8 = 0;
+pub const IORING_OP_OPENAT: u8 = 18;
+pub const IORING_OP_READ: u8 = 22;
+pub const IORING_OP_WRITE: u8 = 23;
+
+pub const MAX_ENTRIES: u32 = 4096;
+const PAGE_SIZE: usize = pmm::PAGE_SIZE as usize;
+// every header gets a cache line, the fields are u32s at these offsets into it
+const HEADER_SIZE: usize = 64;
+const HEAD: usize = 0;
+const TAIL: usize = 4;
+const RING_MASK: usize = 8;
+const RING_ENTRIES: usize = 12;
+const FLAGS_OR_OVERFLOW: usize = 16; // the sq's flags, the cq's overflow count
+const DROPPED: usize = 20;
+
+static URINGFS: UringFs = UringFs;
+static RINGS: spin::Mutex<Vec<Option<Arc<Ring>>>> = spin::Mutex::new(Vec::new());
+
+// what's asked for, laid out like linux's io_uring_sqe
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct Sqe {
+    pub opcode: u8,
+    pub flags: u8,
+    pub ioprio: u16,
+    pub fd: i32,
+    pub off: u64,  // u64::MAX for the descriptor's own offset
+    pub addr: u64, // the buffer, or the path for openat
+    pub len: u32,  // or the mode for openat
+    pub op_flags: u32,
+    pub user_data: u64, // handed back in the completion
+    pub pad: [u64; 3],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Cqe {
+    pub user_data: u64,
+    pub res: i32, // what the syscall would have returned, -errno on failure
+    pub flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct SqOffsets {
+    pub head: u32,
+    pub tail: u32,
+    pub ring_mask: u32,
+    pub ring_entries: u32,
+    pub flags: u32,
+    pub dropped: u32,
+    pub array: u32,
+    pub sqes: u32, // linux has the sqes in a mapping of their own
+    pub resv: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct CqOffsets {
+    pub head: u32,
+    pub tail: u32,
+    pub ring_mask: u32,
+    pub ring_entries: u32,
+    pub overflow: u32,
+    pub cqes: u32,
+    pub flags: u32,
+    pub resv1: u32,
+    pub resv2: u64,
+}
+
+// io_uring_params, with where the ring was mapped since there's no mmap to ask for it with
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct Params {
+    pub sq_entries: u32,
+    pub cq_entries: u32,
+    pub flags: u32,
+    pub sq_thread_cpu: u32,
+    pub sq_thread_idle: u32,
+    pub features: u32,
+    pub wq_fd: u32,
+    pub resv: [u32; 3],
+    pub sq_off: SqOffsets,
+    pub cq_off: CqOffsets,
+    pub ring_address: u64,
+    pub ring_size: u64,
+}
+
+pub struct Ring {
+    base: PhysAddr, // the region, contiguous
+    size: usize,
+    entries: u32,    // submission entries, the completion ring has twice as many
+    sq_array: usize, // offsets into the region
+    cq: usize,
+    cqes: usize,
+    sqes: usize,
+    // wakes the worker, which ends once the ring is closed
+    pub submitted: WaitQueue,
+    completed: WaitQueue,
+    closed: AtomicBool,
+}
+
+impl Ring {
+    // entries is rounded up to a power of 2
+    pub fn new(entries: u32) -> KResult<Ring> {
+        if entries == 0 || entries > MAX_ENTRIES {
+            return Err(KError::EINVAL);
+        }
+
+        let entries = entries.next_power_of_two();
+        let sq_array = HEADER_SIZE;
+        let cq = round_up(sq_array + entries as usize * size_of::<u32>(), HEADER_SIZE);
+        let cqes = cq + HEADER_SIZE;
+        let sqes = round_up(cqes + 2 * entries as usize * size_of::<Cqe>(), HEADER_SIZE);
+        let size = round_up(sqes + entries as usize * size_of::<Sqe>(), PAGE_SIZE);
+
+        let base = pmm::get().calloc(size / PAGE_SIZE).map_err(|_| KError::ENOMEM)?;
+        for page in 0..size / PAGE_SIZE {
+            frame::track(nth_page(base, page), FrameFlags::SHMEM);
+        }
+
+        let ring = Ring {
+            base,
+            size,
+            entries,
+            sq_array,
+            cq,
+            cqes,
+            sqes,
+            submitted: WaitQueue::new(),
+            completed: WaitQueue::new(),
+            closed: AtomicBool::new(false),
+        };
+
+        ring.word(RING_MASK).store(entries - 1, Ordering::Relaxed);
+        ring.word(RING_ENTRIES).store(entries, Ordering::Relaxed);
+        ring.word(ring.cq + RING_MASK).store(2 * entries - 1, Ordering::Relaxed);
+        ring.word(ring.cq + RING_ENTRIES).store(2 * entries, Ordering::Relaxed);
+        Ok(ring)
+    }
+
+    // the region through the direct map
+    pub fn region(&self) -> *mut u8 {
+        self.base.higher_half().as_mut_ptr()
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn word(&self, offset: usize) -> &AtomicU32 {
+        unsafe { &*(self.region().add(offset) as *const AtomicU32) }
+    }
+
+    pub fn params(&self, ring_address: u64) -> Params {
+        Params {
+            sq_entries: self.entries,
+            cq_entries: 2 * self.entries,
+            sq_off: SqOffsets {
+                head: HEAD as u32,
+                tail: TAIL as u32,
+                ring_mask: RING_MASK as u32,
+                ring_entries: RING_ENTRIES as u32,
+                flags: FLAGS_OR_OVERFLOW as u32,
+                dropped: DROPPED as u32,
+                array: self.sq_array as u32,
+                sqes: self.sqes as u32,
+                resv: 0,
+            },
+            cq_off: CqOffsets {
+                head: (self.cq + HEAD) as u32,
+                tail: (self.cq + TAIL) as u32,
+                ring_mask: (self.cq + RING_MASK) as u32,
+                ring_entries: (self.cq + RING_ENTRIES) as u32,
+                overflow: (self.cq + FLAGS_OR_OVERFLOW) as u32,
+                cqes: self.cqes as u32,
+                ..CqOffsets::default()
+            },
+            ring_address,
+            ring_size: self.size as u64,
+            ..Params::default()
+        }
+    }
+
+    /*
+        Does what's on the submission ring in order, execute gives each entry's result.
+        An entry is copied out first, so userspace can't change it while it's being done.
+        Returns how many were done
+    */
+    pub fn consume(&self, mut execute: impl FnMut(&Sqe) -> i32) -> usize {
+        let mask = self.entries - 1;
+        let tail = self.word(TAIL).load(Ordering::Acquire);
+        let mut head = self.word(HEAD).load(Ordering::Relaxed);
+        let mut done = 0;
+
+        while head != tail {
+            let slot = self.sq_array + (head & mask) as usize * size_of::<u32>();
+            let index = self.word(slot).load(Ordering::Relaxed);
+
+            if index < self.entries {
+                let sqe = unsafe {
+                    (self.region().add(self.sqes) as *const Sqe)
+                        .add(index as usize)
+                        .read_volatile()
+                };
+                let res = execute(&sqe);
+                self.complete(sqe.user_data, res);
+                done += 1;
+            } else {
+                self.word(DROPPED).fetch_add(1, Ordering::Relaxed);
+            }
+
+            head = head.wrapping_add(1);
+            self.word(HEAD).store(head, Ordering::Release);
+        }
+
+        if done > 0 {
+            self.completed.notify();
+        }
+        done
+    }
+
+    // a full completion ring loses it, it's counted in overflow
+    fn complete(&self, user_data: u64, res: i32) {
+        let head = self.word(self.cq + HEAD).load(Ordering::Acquire);
+        let tail = self.word(self.cq + TAIL).load(Ordering::Relaxed);
+        if tail.wrapping_sub(head) == 2 * self.entries {
+            self.word(self.cq + FLAGS_OR_OVERFLOW).fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let cqe = Cqe {
+            user_data,
+            res,
+            flags: 0,
+        };
+        unsafe {
+            let cqes = self.region().add(self.cqes) as *mut Cqe;
+            cqes.add((tail & (2 * self.entries - 1)) as usize).write_volatile(cqe);
+        }
+        self.word(self.cq + TAIL).store(tail.wrapping_add(1), Ordering::Release);
+    }
+
+    // completions userspace hasn't taken off the ring yet
+    pub fn ready(&self) -> u32 {
+        let tail = self.word(self.cq + TAIL).load(Ordering::Acquire);
+        tail.wrapping_sub(self.word(self.cq + HEAD).load(Ordering::Relaxed))
+    }
+
+    // false if the ring was closed before there were cnt completions
+    pub fn wait_completions(&self, cnt: u32) -> bool {
+        loop {
+            let generation = self.completed.generation();
+            if self.ready() >= cnt {
+                return true;
+            }
+            if self.is_closed() {
+                return false;
+            }
+
+            self.completed.wait(generation, None);
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.submitted.notify();
+        self.completed.notify();
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        for page in 0..self.size / PAGE_SIZE {
+            frame::put(nth_page(self.base, page));
+        }
+    }
+}
+
+fn nth_page(base: PhysAddr, n: usize) -> PhysAddr {
+    PhysAddr::new(base.as_u64() + (n * PAGE_SIZE) as u64)
+}
+
+// a new ring with a description for it, whoever sets it up starts its worker
+pub fn create(entries: u32) -> KResult<(vfs::FileDescription, Arc<Ring>)> {
+    let ring = Arc::new(Ring::new(entries)?);
+
+    let mut rings = RINGS.lock();
+    let index = match rings.iter().position(|slot| slot.is_none()) {
+        Some(index) => index,
+        None => {
+            rings.push(None);
+            rings.len() - 1
+        }
+    };
+    rings[index] = Some(ring.clone());
+
+    let flags = vfs::Flags::O_RDWR;
+    Ok((vfs::FileDescription::new(index, flags, &URINGFS), ring))
+}
+
+// the ring a description is for, None if it isn't one
+pub fn get(description: &vfs::FileDescription) -> Option<Arc<Ring>> {
+    if description.fs.name() != vfs::Filesystem::name(&URINGFS) {
+        return None;
+    }
+
+    RINGS.lock().get(description.file_index).cloned().flatten()
+}
+
+struct UringFs;
+
+impl vfs::Filesystem for UringFs {
+    fn name(&self) -> &'static str {
+        "io_uring"
+    }
+
+    // rings are only made by io_uring_setup, they have no names
+    fn open(
+        &self,
+        _path: &str,
+        _flags: vfs::Flags,
+        _mode: vfs::Mode,
+    ) -> KResult<vfs::FileDescription> {
+        Err(KError::ENOENT)
+    }
+
+    fn mkdir(&self, _path: &str, _mode: vfs::Mode) -> KResult<vfs::FileDescription> {
+        Err(KError::EPERM)
+    }
+
+    fn read(&self, _index: usize, _buffer: *mut u8, _cnt: usize, _offset: usize) -> KResult<usize> {
+        Err(KError::EINVAL)
+    }
+
+    fn write(
+        &self,
+        _index: usize,
+        _buffer: *const u8,
+        _cnt: usize,
+        _offset: usize,
+    ) -> KResult<usize> {
+        Err(KError::EINVAL)
+    }
+
+    fn readdir(&self, _index: usize, _offset: usize) -> KResult<Option<vfs::DirEntry>> {
+        Err(KError::ENOTDIR)
+    }
+
+    fn page(&self, index: usize, offset: usize) -> KResult<Option<PhysAddr>> {
+        let rings = RINGS.lock();
+        let ring = rings.get(index).and_then(|slot| slot.as_ref()).ok_or(KError::EBADF)?;
+        if offset >= ring.size {
+            return Err(KError::EINVAL);
+        }
+
+        let page = nth_page(ring.base, offset / PAGE_SIZE);
+        frame::get(page);
+        Ok(Some(page))
+    }
+
+    /*
+        The worker ends, and the region goes once it and every mapping let go of it.
+        create makes the only open of a ring, and the vfs only calls this once the last
+        clone of it is gone (dup, fork and mmap all clone it), so it's the ring's last close
+    */
+    fn close(&self, index: usize) {
+        if let Some(ring) = RINGS.lock().get_mut(index).and_then(|slot| slot.take()) {
+            ring.close();
+        }
+    }
+}
@@ -1,6 +1,20 @@
-use alloc::{string::String, vec::Vec};
+use crate::arch::mm::pmm::PhysAddr;
+use crate::drivers::timer_source;
+use crate::error::{KError, KResult};
+use crate::log;
+use crate::proc::scheduler;
+use crate::proc::wait::{self, WaitQueue};
+use crate::rcu::{Rcu, RcuGuard};
+use alloc::boxed::Box;
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::cell::Cell;
 
-static mut MOUNT_POINTS: Vec<MountPoint> = alloc::vec![];
+// how much is read ahead of a sequential reader, the window grows from the first to the second
+const READAHEAD_MIN: usize = 16 * 1024;
+const READAHEAD_MAX: usize = 128 * 1024;
+
+// looked up on every open, mounting is rare. A mount point is never freed once it's in
+static MOUNT_POINTS: Rcu<Vec<&'static MountPoint>> = Rcu::new(Vec::new());
 
 bitflags::bitflags! {
     pub struct Flags: u32 {
@@ -12,7 +26,9 @@ bitflags::bitflags! {
         const O_APPEND = 2000;
     }
 
+    // what a new file's permissions are, the same bits as FilePermissions
     pub struct Mode: u32 {
+        const PERMISSIONS = 0o7777;
     }
 
     pub struct FileType: u16 {
@@ -26,17 +42,172 @@ bitflags::bitflags! {
     }
 
     pub struct FilePermissions: u16 {
+        const SET_UID = 1 << 11;
+        const SET_GID = 1 << 10;
+        const STICKY = 1 << 9;
         const USER_READ = 1 << 8;
         const USER_WRITE = 1 << 7;
         const USER_EXEC = 1 << 6;
+        const GROUP_READ = 1 << 5;
+        const GROUP_WRITE = 1 << 4;
+        const GROUP_EXEC = 1 << 3;
+        const OTHER_READ = 1 << 2;
+        const OTHER_WRITE = 1 << 1;
+        const OTHER_EXEC = 1 << 0;
+    }
+
+    // what's being done to a file, the same bits as each rwx triple of FilePermissions
+    pub struct Access: u16 {
+        const READ = 1 << 2;
+        const WRITE = 1 << 1;
+        const EXEC = 1 << 0;
+    }
+
+    // the same bits as linux's poll
+    pub struct PollEvents: u16 {
+        const POLLIN = 0x1;
+        const POLLOUT = 0x4;
+        const POLLERR = 0x8;
+        const POLLHUP = 0x10;
+        const POLLNVAL = 0x20;
+    }
+}
+
+// same values as the d_type of getdents64
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DirEntryType {
+    Unknown = 0,
+    Fifo = 1,
+    CharDevice = 2,
+    Directory = 4,
+    BlockDevice = 6,
+    Normal = 8,
+    Symlink = 10,
+    Socket = 12,
+}
+
+// who a file belongs to and what they and everyone else may do with it
+#[derive(Clone, Copy)]
+pub struct Attributes {
+    pub permissions: FilePermissions,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+// how full a filesystem is, everything is 0 for the ones that don't keep track
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
+pub struct StatFs {
+    pub block_size: usize,
+    pub blocks: u64,
+    pub free_blocks: u64,
+    pub inodes: u64,
+    pub free_inodes: u64,
+}
+
+pub struct DirEntry {
+    pub inode: u64,
+    pub next_offset: usize, // where the entry after this one is searched from
+    pub entry_type: DirEntryType,
+    pub name: String,
+}
+
+// iterates over the entries of a directory, starting at the description's offset
+pub struct ReadDir<'a> {
+    fd: &'a FileDescription,
+    offset: usize,
+}
+
+impl<'a> ReadDir<'a> {
+    // the offset to store in the description once the entries returned so far were consumed
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl<'a> Iterator for ReadDir<'a> {
+    type Item = KResult<DirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.fd.fs.readdir(self.fd.file_index, self.offset) {
+            Ok(Some(entry)) => {
+                self.offset = entry.next_offset;
+                Some(Ok(entry))
+            }
+            Ok(None) => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+/*
+    Notices when a file is read from start to end and asks the filesystem to fetch what
+    comes next ahead of time. The next window is only requested once the reader got to the
+    end of the last one, and it doubles every time up to READAHEAD_MAX
+*/
+#[derive(Clone)]
+pub struct ReadAhead {
+    next: Cell<usize>, // where a sequential read would start
+    end: Cell<usize>,  // the end of the last window that was requested
+    window: Cell<usize>,
+}
+
+impl ReadAhead {
+    fn new() -> Self {
+        ReadAhead {
+            next: Cell::new(0),
+            end: Cell::new(0),
+            window: Cell::new(0),
+        }
+    }
+
+    // returns the range to read ahead after a read of cnt bytes at offset, if any
+    fn access(&self, offset: usize, cnt: usize) -> Option<(usize, usize)> {
+        let sequential = offset == self.next.get() && cnt > 0;
+        self.next.set(offset + cnt);
+
+        if !sequential {
+            self.window.set(0);
+            self.end.set(0);
+            return None;
+        }
+
+        if offset + cnt < self.end.get() {
+            return None;
+        }
+
+        let window = (self.window.get() * 2).clamp(READAHEAD_MIN, READAHEAD_MAX);
+        self.window.set(window);
+        self.end.set(offset + cnt + window);
+
+        Some((offset + cnt, window))
+    }
+}
+
+// one open of a file, the filesystem is told it's closed when the last clone of it goes
+struct OpenFile {
+    fs: &'static dyn Filesystem,
+    index: usize,
+}
+
+impl Drop for OpenFile {
+    fn drop(&mut self) {
+        self.fs.close(self.index);
     }
 }
 
+/*
+    Cloned for a duplicated descriptor or a forked process, the clones have an offset of
+    their own but keep the file open together
+*/
+#[derive(Clone)]
 pub struct FileDescription {
     pub flags: Flags,
     pub offset: usize,
     pub fs: &'static dyn Filesystem,
     pub file_index: usize, // an index for the filesystem-specific table of open files
+    readahead: ReadAhead,
+    open: Arc<OpenFile>,
 }
 
 impl FileDescription {
@@ -46,8 +217,15 @@ impl FileDescription {
             offset: 0,
             fs,
             file_index: index,
+            readahead: ReadAhead::new(),
+            open: Arc::new(OpenFile { fs, index }),
         }
     }
+
+    // how many descriptions share this open of the file
+    pub fn open_count(&self) -> usize {
+        Arc::strong_count(&self.open)
+    }
 }
 
 pub struct MountPoint {
@@ -62,39 +240,294 @@ impl MountPoint {
             fs: None,
         }
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn fs(&self) -> Option<&'static dyn Filesystem> {
+        self.fs
+    }
 }
 
 pub trait Filesystem {
-    fn open(&self, path: &str, flags: Flags, mode: Mode) -> Option<FileDescription>;
-    fn mkdir(&self, path: &str, mode: Mode) -> Option<FileDescription>;
-    fn read(&self, index: usize, buffer: *mut u8, cnt: usize, offset: usize) -> usize;
-    fn write(&self, index: usize, buffer: *const u8, cnt: usize, offset: usize) -> usize;
+    fn name(&self) -> &'static str;
+    fn open(&self, path: &str, flags: Flags, mode: Mode) -> KResult<FileDescription>;
+    fn mkdir(&self, path: &str, mode: Mode) -> KResult<FileDescription>;
+    fn read(&self, index: usize, buffer: *mut u8, cnt: usize, offset: usize) -> KResult<usize>;
+    fn write(&self, index: usize, buffer: *const u8, cnt: usize, offset: usize) -> KResult<usize>;
+    // Ok(None) at the end of the directory
+    fn readdir(&self, index: usize, offset: usize) -> KResult<Option<DirEntry>>;
+
+    // what the file is ready for right now, files on disk never make anyone wait
+    fn poll(&self, _index: usize) -> KResult<PollEvents> {
+        Ok(PollEvents::POLLIN | PollEvents::POLLOUT)
+    }
+
+    // notified whenever poll's answer may have changed, None if it never does
+    fn wait_queue(&self, _index: usize) -> Option<&WaitQueue> {
+        None
+    }
+
+    // cnt bytes at offset are likely to be read next, the filesystem can start fetching them
+    fn readahead(&self, _index: usize, _offset: usize, _cnt: usize) {}
+
+    fn unlink(&self, _path: &str) -> KResult<()> {
+        Err(KError::EPERM)
+    }
+
+    // another name for the file at old, both paths are on this filesystem
+    fn link(&self, _old: &str, _new: &str) -> KResult<()> {
+        Err(KError::EPERM)
+    }
+
+    // a symlink at path pointing to target, which is stored as it is and not looked at
+    fn symlink(&self, _target: &str, _path: &str) -> KResult<()> {
+        Err(KError::EPERM)
+    }
+
+    // what the symlink at path points to, EINVAL if it isn't one
+    fn readlink(&self, _path: &str) -> KResult<String> {
+        Err(KError::EINVAL)
+    }
+
+    // moves the file at old to new, replacing what's there, both paths are on this filesystem
+    fn rename(&self, _old: &str, _new: &str) -> KResult<()> {
+        Err(KError::EPERM)
+    }
+
+    // the last description of the file at index went away, its slot can be reused
+    fn close(&self, _index: usize) {}
+
+    // sets the size of the file, growing it with zeroes
+    fn truncate(&self, _index: usize, _size: usize) -> KResult<()> {
+        Err(KError::EINVAL)
+    }
+
+    /*
+        The frame that holds the page at offset, for files that live in memory, so shared
+        mappings of them can map it directly. Ok(None) if it has to be read into a new frame.
+        A counted frame comes with a reference for the mapping, put when it's unmapped
+    */
+    fn page(&self, _index: usize, _offset: usize) -> KResult<Option<PhysAddr>> {
+        Ok(None)
+    }
+
+    // writes back whatever the filesystem keeps cached in memory
+    fn sync(&self) -> KResult<()> {
+        Ok(())
+    }
+
+    // before it's reachable through a mount point, EBUSY if it's mounted somewhere already
+    fn mount(&self) -> KResult<()> {
+        Ok(())
+    }
+
+    /*
+        Once it's not reachable anymore, everything cached has to be written back. EBUSY if
+        files are still open, unless it's forced, which is only done before a reboot
+    */
+    fn unmount(&self, _force: bool) -> KResult<()> {
+        self.sync()
+    }
+
+    fn statfs(&self) -> KResult<StatFs> {
+        Ok(StatFs::default())
+    }
+
+    // None if the filesystem doesn't keep owners, then everyone may do anything
+    fn attributes(&self, _index: usize) -> Option<Attributes> {
+        None
+    }
+
+    // the caller has to pass check_chmod and check_chown first, against the file's attributes
+    fn chmod(&self, _path: &str, _permissions: FilePermissions) -> KResult<()> {
+        Err(KError::EPERM)
+    }
+
+    // None leaves that id as it is
+    fn chown(&self, _path: &str, _uid: Option<u32>, _gid: Option<u32>) -> KResult<()> {
+        Err(KError::EPERM)
+    }
 }
 
-pub fn mount(fs: &'static dyn Filesystem, target: &str) -> bool {
-    if target.chars().nth(0) != Some('/') {
-        return false;
+pub struct PollFd<'a> {
+    pub fd: &'a FileDescription,
+    pub events: PollEvents, // what the caller is interested in
+    pub revents: PollEvents, // filled in by poll
+}
+
+impl<'a> PollFd<'a> {
+    pub fn new(fd: &'a FileDescription, events: PollEvents) -> Self {
+        PollFd {
+            fd,
+            events,
+            revents: PollEvents::empty(),
+        }
     }
+}
+
+/*
+    Waits until at least one of fds is ready for what it asked for, or until timeout_ms
+    passed (forever if it's None, not at all if it's 0). Errors and hangups are always
+    reported. Returns how many descriptors have revents set
+*/
+pub fn poll(fds: &mut [PollFd], timeout_ms: Option<u64>) -> KResult<usize> {
+    let deadline = timeout_ms.map(|timeout| timer_source::current_ns() + timeout * 1_000_000);
+
+    loop {
+        // read before checking, so a notify that comes after the check wakes us up
+        let queues: Vec<(&WaitQueue, u64)> = fds
+            .iter()
+            .filter_map(|pollfd| pollfd.fd.fs.wait_queue(pollfd.fd.file_index))
+            .map(|queue| (queue, queue.generation()))
+            .collect();
+
+        let mut ready = 0;
+        for pollfd in fds.iter_mut() {
+            let always = PollEvents::POLLERR | PollEvents::POLLHUP | PollEvents::POLLNVAL;
+            let events = pollfd.fd.fs.poll(pollfd.fd.file_index)?;
+
+            pollfd.revents = events & (pollfd.events | always);
+            if !pollfd.revents.is_empty() {
+                ready += 1;
+            }
+        }
+
+        if ready > 0 || timeout_ms == Some(0) {
+            return Ok(ready);
+        }
+
+        // nothing can ever change
+        if queues.is_empty() && deadline.is_none() {
+            return Err(KError::EINVAL);
+        }
 
-    for mount_point in unsafe { MOUNT_POINTS.iter() } {
-        if mount_point.name == target {
-            return false;
+        if !wait::wait_any(&queues, deadline) {
+            return Ok(0);
         }
     }
+}
+
+pub fn mount(fs: &'static dyn Filesystem, target: &str) -> KResult<()> {
+    if target.chars().nth(0) != Some('/') {
+        return Err(KError::EINVAL);
+    }
+    if MOUNT_POINTS.read().iter().any(|mount_point| mount_point.name == target) {
+        return Err(KError::EBUSY);
+    }
+
+    fs.mount()?;
+
+    let result = MOUNT_POINTS.update(|mount_points| {
+        if mount_points.iter().any(|mount_point| mount_point.name == target) {
+            return Err(KError::EBUSY);
+        }
 
-    unsafe {
         let mut new_mp = MountPoint::new();
         new_mp.fs = Some(fs);
         new_mp.name = String::from(target);
-        MOUNT_POINTS.push(new_mp);
+        mount_points.push(Box::leak(Box::new(new_mp)));
+
+        Ok(())
+    });
+
+    // someone else mounted something there in the meantime
+    if result.is_err() {
+        fs.unmount(false).ok();
+    }
+
+    result
+}
+
+/*
+    Takes the mount point away first, so nothing new can be opened through it, and puts it
+    back if the filesystem can't be unmounted. The mount point itself is leaked, readers may
+    still be looking at it
+*/
+pub fn unmount(target: &str) -> KResult<()> {
+    if target == "/" {
+        return Err(KError::EBUSY);
     }
 
-    true
+    let mut removed: Option<&'static MountPoint> = None;
+    MOUNT_POINTS.update(|mount_points| {
+        let index = mount_points
+            .iter()
+            .position(|mount_point| mount_point.name == target)
+            .ok_or(KError::EINVAL)?;
+
+        // something is mounted under it
+        let prefix = alloc::format!("{}/", target.trim_end_matches('/'));
+        if mount_points.iter().any(|mount_point| mount_point.name.starts_with(&prefix)) {
+            return Err(KError::EBUSY);
+        }
+
+        removed = Some(mount_points.remove(index));
+        Ok(())
+    })?;
+
+    let mount_point = removed.unwrap();
+    let fs = match mount_point.fs {
+        Some(fs) => fs,
+        None => return Ok(()),
+    };
+
+    if let Err(err) = fs.unmount(false) {
+        MOUNT_POINTS
+            .update(|mount_points| {
+                mount_points.push(mount_point);
+                Ok::<(), KError>(())
+            })
+            .ok();
+        return Err(err);
+    }
+
+    Ok(())
 }
 
-pub fn get_mount_point(path: &str) -> Option<&MountPoint> {
-    let mut curr_mp: Option<&MountPoint> = None;
-    for mount_point in unsafe { MOUNT_POINTS.iter() } {
+// before a reboot, files may still be open but nothing's going to use them anymore
+pub fn unmount_all() {
+    let mounted: Vec<(String, &'static dyn Filesystem)> = MOUNT_POINTS
+        .read()
+        .iter()
+        .filter_map(|mount_point| Some((mount_point.name.clone(), mount_point.fs?)))
+        .collect();
+
+    for (name, fs) in mounted {
+        if let Err(err) = fs.unmount(true) {
+            log::error!("[VFS] Could not unmount {}: {}\n", name, err);
+        }
+    }
+}
+
+// every mounted filesystem, the first error is returned but the rest are synced anyway
+pub fn sync() -> KResult<()> {
+    // syncing sleeps on the disk, which can't be done inside of the read side
+    let mounted: Vec<(String, &'static dyn Filesystem)> = MOUNT_POINTS
+        .read()
+        .iter()
+        .filter_map(|mount_point| Some((mount_point.name.clone(), mount_point.fs?)))
+        .collect();
+    let mut result = Ok(());
+
+    for (name, fs) in mounted {
+        if let Err(err) = fs.sync() {
+            log::error!("[VFS] Could not sync {}: {}\n", name, err);
+            result = result.and(Err(err));
+        }
+    }
+
+    result
+}
+
+pub fn mount_points() -> RcuGuard<'static, Vec<&'static MountPoint>> {
+    MOUNT_POINTS.read()
+}
+
+pub fn get_mount_point(path: &str) -> Option<&'static MountPoint> {
+    let mut curr_mp: Option<&'static MountPoint> = None;
+    for mount_point in MOUNT_POINTS.read().iter().copied() {
         if path.contains(mount_point.name.as_str()) {
             if let Some(mp) = curr_mp {
                 if mount_point.name.len() > mp.name.len() {
@@ -109,53 +542,306 @@ pub fn get_mount_point(path: &str) -> Option<&MountPoint> {
     curr_mp
 }
 
-pub fn open(path: &str, flags: Flags, mode: Mode) -> Option<FileDescription> {
-    if path.chars().nth(0) != Some('/') {
-        // relative path, not supported atm
-        return None;
+// the working directory of the running process, or / if there's none
+pub fn getcwd() -> String {
+    match scheduler::running_thread() {
+        Some(thread) => thread.borrow().parent.borrow().working_dir.clone(),
+        None => String::from("/"),
+    }
+}
+
+/*
+    Turns path into an absolute path without any "." or ".." components or repeated
+    slashes. Relative paths are resolved against cwd, and ".." at the root stays there
+*/
+pub fn normalize_path(cwd: &str, path: &str) -> String {
+    let mut components: Vec<&str> = Vec::new();
+
+    let base = if path.starts_with('/') { "" } else { cwd };
+
+    for component in base.split('/').chain(path.split('/')) {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            _ => components.push(component),
+        }
+    }
+
+    let mut normalized = String::new();
+    for component in components {
+        normalized.push('/');
+        normalized.push_str(component);
+    }
+
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+
+    normalized
+}
+
+pub fn resolve_path(path: &str) -> String {
+    normalize_path(&getcwd(), path)
+}
+
+pub fn chdir(path: &str) -> KResult<()> {
+    let thread = scheduler::running_thread().ok_or(KError::EINVAL)?;
+    let path = resolve_path(path);
+
+    // only directories can be read as one
+    let fd = open(&path, Flags::O_RDONLY, Mode::empty())?;
+    fd.fs.readdir(fd.file_index, 0)?;
+
+    thread.borrow().parent.borrow_mut().working_dir = path;
+    Ok(())
+}
+
+// the uid and gid files are accessed as, the running process's or root's before there's one
+pub fn credentials() -> (u32, u32) {
+    match scheduler::running_thread() {
+        Some(thread) => {
+            let process = thread.borrow().parent.clone();
+            let process = process.borrow();
+            (process.uid, process.gid)
+        }
+        None => (0, 0),
     }
+}
+
+/*
+    Whether uid and gid may access a file the way access says. The owner gets the user
+    bits and the group the group bits, each one only theirs, so an owner can be denied
+    what everyone else is allowed. Root reads and writes anything, but only runs a file
+    anyone can run
+*/
+pub fn permitted(attributes: &Attributes, uid: u32, gid: u32, access: Access) -> bool {
+    let bits = attributes.permissions.bits();
+    let exec_bits = (FilePermissions::USER_EXEC
+        | FilePermissions::GROUP_EXEC
+        | FilePermissions::OTHER_EXEC)
+        .bits();
 
-    if let Some(mount_point) = get_mount_point(path) {
-        mount_point
-            .fs
-            .as_ref()
-            .unwrap()
-            .open(&path[mount_point.name.len()..], flags, mode)
+    let granted = if uid == 0 {
+        let exec = if bits & exec_bits != 0 { Access::EXEC } else { Access::empty() };
+        Access::READ | Access::WRITE | exec
+    } else if uid == attributes.uid {
+        Access::from_bits_truncate(bits >> 6 & 0x7)
+    } else if gid == attributes.gid {
+        Access::from_bits_truncate(bits >> 3 & 0x7)
     } else {
-        // TODO: report the error
-        None
+        Access::from_bits_truncate(bits & 0x7)
+    };
+
+    granted.contains(access)
+}
+
+pub fn check_access(attributes: &Attributes, access: Access) -> KResult<()> {
+    let (uid, gid) = credentials();
+    if permitted(attributes, uid, gid, access) {
+        Ok(())
+    } else {
+        Err(KError::EACCES)
     }
 }
 
-pub fn mkdir(path: &str, mode: Mode) -> Option<FileDescription> {
-    if let Some(mount_point) = get_mount_point(path) {
-        mount_point
-            .fs
-            .as_ref()
-            .unwrap()
-            .mkdir(&path[mount_point.name.len()..], mode)
+// only the owner and root change a file's permissions
+pub fn check_chmod(attributes: &Attributes) -> KResult<()> {
+    let (uid, _) = credentials();
+    if uid == 0 || uid == attributes.uid {
+        Ok(())
     } else {
-        // TODO: report the error
-        None
+        Err(KError::EPERM)
     }
 }
 
-pub fn read(
-    fs: &dyn Filesystem,
-    file_index: usize,
-    buffer: *mut u8,
-    cnt: usize,
-    offset: usize,
-) -> usize {
-    fs.read(file_index, buffer, cnt, offset)
+// only root gives a file away, an owner can only hand it to their own group
+pub fn check_chown(attributes: &Attributes, uid: Option<u32>, gid: Option<u32>) -> KResult<()> {
+    let (new_uid, new_gid) = (uid, gid);
+    let (uid, gid) = credentials();
+    if uid == 0 {
+        return Ok(());
+    }
+
+    let keeps_owner = new_uid.map_or(true, |new_uid| new_uid == attributes.uid);
+    let own_group = new_gid.map_or(true, |new_gid| new_gid == gid || new_gid == attributes.gid);
+    if uid == attributes.uid && keeps_owner && own_group {
+        Ok(())
+    } else {
+        Err(KError::EPERM)
+    }
 }
 
-pub fn write(
-    fs: &dyn Filesystem,
-    file_index: usize,
+fn access_for(flags: Flags) -> Access {
+    let mut access = if flags.contains(Flags::O_RDWR) {
+        Access::READ | Access::WRITE
+    } else if flags.contains(Flags::O_WRONLY) {
+        Access::WRITE
+    } else {
+        Access::READ
+    };
+
+    if flags.contains(Flags::O_TRUNC) {
+        access |= Access::WRITE;
+    }
+    access
+}
+
+pub fn open(path: &str, flags: Flags, mode: Mode) -> KResult<FileDescription> {
+    let path = resolve_path(path);
+    let path = path.as_str();
+
+    let mount_point = get_mount_point(path).ok_or(KError::ENOENT)?;
+    let fs = mount_point.fs.ok_or(KError::ENODEV)?;
+    let fd = fs.open(&path[mount_point.name.len()..], flags, mode)?;
+
+    if let Some(attributes) = fs.attributes(fd.file_index) {
+        check_access(&attributes, access_for(flags))?;
+    }
+    Ok(fd)
+}
+
+// opens a program to be loaded, which needs to be executable by the caller
+pub fn open_exec(path: &str) -> KResult<FileDescription> {
+    let fd = open(path, Flags::O_RDONLY, Mode::empty())?;
+
+    if let Some(attributes) = fd.fs.attributes(fd.file_index) {
+        check_access(&attributes, Access::EXEC)?;
+    }
+    Ok(fd)
+}
+
+pub fn chmod(path: &str, permissions: FilePermissions) -> KResult<()> {
+    let path = resolve_path(path);
+    let path = path.as_str();
+
+    let mount_point = get_mount_point(path).ok_or(KError::ENOENT)?;
+    let fs = mount_point.fs.ok_or(KError::ENODEV)?;
+    fs.chmod(&path[mount_point.name.len()..], permissions)
+}
+
+pub fn symlink(target: &str, path: &str) -> KResult<()> {
+    let path = resolve_path(path);
+
+    let mount_point = get_mount_point(&path).ok_or(KError::ENOENT)?;
+    let fs = mount_point.fs.ok_or(KError::ENODEV)?;
+    fs.symlink(target, &path[mount_point.name.len()..])
+}
+
+pub fn readlink(path: &str) -> KResult<String> {
+    let path = resolve_path(path);
+
+    let mount_point = get_mount_point(&path).ok_or(KError::ENOENT)?;
+    let fs = mount_point.fs.ok_or(KError::ENODEV)?;
+    fs.readlink(&path[mount_point.name.len()..])
+}
+
+// about the filesystem path is on
+pub fn statfs(path: &str) -> KResult<StatFs> {
+    let path = resolve_path(path);
+
+    let mount_point = get_mount_point(&path).ok_or(KError::ENOENT)?;
+    mount_point.fs.ok_or(KError::ENODEV)?.statfs()
+}
+
+pub fn chown(path: &str, uid: Option<u32>, gid: Option<u32>) -> KResult<()> {
+    let path = resolve_path(path);
+    let path = path.as_str();
+
+    let mount_point = get_mount_point(path).ok_or(KError::ENOENT)?;
+    let fs = mount_point.fs.ok_or(KError::ENODEV)?;
+    fs.chown(&path[mount_point.name.len()..], uid, gid)
+}
+
+pub fn unlink(path: &str) -> KResult<()> {
+    let path = resolve_path(path);
+    let path = path.as_str();
+
+    let mount_point = get_mount_point(path).ok_or(KError::ENOENT)?;
+    let fs = mount_point.fs.ok_or(KError::ENODEV)?;
+    fs.unlink(&path[mount_point.name.len()..])
+}
+
+pub fn link(old: &str, new: &str) -> KResult<()> {
+    let old = resolve_path(old);
+    let new = resolve_path(new);
+
+    let mount_point = get_mount_point(&old).ok_or(KError::ENOENT)?;
+    let new_mount_point = get_mount_point(&new).ok_or(KError::ENOENT)?;
+    if !core::ptr::eq(mount_point, new_mount_point) {
+        return Err(KError::EXDEV);
+    }
+
+    let fs = mount_point.fs.ok_or(KError::ENODEV)?;
+    let prefix = mount_point.name.len();
+    fs.link(&old[prefix..], &new[prefix..])
+}
+
+pub fn rename(old: &str, new: &str) -> KResult<()> {
+    let old = resolve_path(old);
+    let new = resolve_path(new);
+
+    let mount_point = get_mount_point(&old).ok_or(KError::ENOENT)?;
+    let new_mount_point = get_mount_point(&new).ok_or(KError::ENOENT)?;
+    if !core::ptr::eq(mount_point, new_mount_point) {
+        return Err(KError::EXDEV);
+    }
+
+    let fs = mount_point.fs.ok_or(KError::ENODEV)?;
+    let prefix = mount_point.name.len();
+    fs.rename(&old[prefix..], &new[prefix..])
+}
+
+pub fn truncate(fd: &FileDescription, size: usize) -> KResult<()> {
+    fd.fs.truncate(fd.file_index, size)
+}
+
+pub fn mkdir(path: &str, mode: Mode) -> KResult<FileDescription> {
+    let path = resolve_path(path);
+    let path = path.as_str();
+
+    let mount_point = get_mount_point(path).ok_or(KError::ENOENT)?;
+    let fs = mount_point.fs.ok_or(KError::ENODEV)?;
+    fs.mkdir(&path[mount_point.name.len()..], mode)
+}
+
+// reads at the description's offset and moves it past what was read
+pub fn read(fd: &mut FileDescription, buffer: *mut u8, cnt: usize) -> KResult<usize> {
+    let read = pread(fd, buffer, cnt, fd.offset)?;
+    fd.offset += read;
+    Ok(read)
+}
+
+pub fn write(fd: &mut FileDescription, buffer: *const u8, cnt: usize) -> KResult<usize> {
+    let written = pwrite(fd, buffer, cnt, fd.offset)?;
+    fd.offset += written;
+    Ok(written)
+}
+
+// like read and write, but at an explicit offset and without touching the description's
+pub fn pread(fd: &FileDescription, buffer: *mut u8, cnt: usize, offset: usize) -> KResult<usize> {
+    let read = fd.fs.read(fd.file_index, buffer, cnt, offset)?;
+
+    if let Some((offset, cnt)) = fd.readahead.access(offset, read) {
+        fd.fs.readahead(fd.file_index, offset, cnt);
+    }
+
+    Ok(read)
+}
+
+pub fn pwrite(
+    fd: &FileDescription,
     buffer: *const u8,
     cnt: usize,
     offset: usize,
-) -> usize {
-    fs.write(file_index, buffer, cnt, offset)
+) -> KResult<usize> {
+    fd.fs.write(fd.file_index, buffer, cnt, offset)
+}
+
+pub fn read_dir(fd: &FileDescription) -> ReadDir {
+    ReadDir {
+        fd,
+        offset: fd.offset,
+    }
 }
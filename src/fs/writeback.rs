@@ -0,0 +1,75 @@
+/*
+    What's only in memory reaches the disk here: the dirty pages of shared file mappings
+    and whatever the mounted filesystems cache. kflushd syncs everything every
+    WRITEBACK_INTERVAL_MS, so a crash loses at most that much, and sync() does it right
+    away, e.g. before a reboot
+*/
+
+use super::vfs;
+use crate::drivers::timer_source;
+use crate::error::KResult;
+use crate::log;
+use crate::mm::vmm::VirtAddr;
+use crate::proc::process::{Process, SelectorValues, Thread};
+use crate::proc::{scheduler, wait::WaitQueue};
+use alloc::string::String;
+
+const WRITEBACK_INTERVAL_MS: u64 = 5000;
+
+// notified by sync(), so kflushd's next round doesn't also find everything clean
+static WAKE: WaitQueue = WaitQueue::new();
+
+// every process's shared file mappings, the ones in the middle of something are skipped
+fn sync_mappings() {
+    let processes = match scheduler::try_get() {
+        Some(scheduler) => scheduler.processes(),
+        None => return,
+    };
+
+    for process in processes {
+        let process = match process.try_borrow() {
+            Ok(process) => process,
+            Err(_) => continue,
+        };
+
+        if let Some(pagemap) = process.pagemap.as_ref() {
+            for range in pagemap.ranges() {
+                let length = (range.end() - range.start()) as usize;
+                pagemap.msync(VirtAddr::new(range.start()), length);
+            }
+        }
+    }
+}
+
+pub fn sync() -> KResult<()> {
+    sync_mappings();
+    let result = vfs::sync();
+    WAKE.notify();
+    result
+}
+
+extern "C" fn kflushd() -> ! {
+    loop {
+        let generation = WAKE.generation();
+        let deadline = timer_source::current_ns() + WRITEBACK_INTERVAL_MS * 1_000_000;
+
+        // a sync in the meantime pushes the next round back
+        if !WAKE.wait(generation, Some(deadline)) {
+            sync_mappings();
+            vfs::sync().ok();
+        }
+    }
+}
+
+// starts kflushd, without the scheduler only sync() writes anything back
+pub fn init() {
+    if scheduler::try_get().is_none() {
+        log::debug!("[WRITEBACK] No scheduler, writing back only on sync\n");
+        return;
+    }
+
+    let process = Process::new(String::from("kflushd"), 0, String::from("/"));
+    let thread = Thread::new(kflushd as u64, SelectorValues::KernelCs, process.clone());
+    process.borrow_mut().threads.push(thread.clone());
+    scheduler::get().enqueue(thread);
+}
@@ -0,0 +1,170 @@
+/*
+    Runtime tracing of kernel functions. Arming a probe patches an int3 over the first byte
+    of a function, every hit dumps the registers over serial and bumps a counter. To let the
+    function run, the original byte is put back and the instruction is single stepped, then
+    the #DB handler puts the int3 back.
+
+    The hits are printed straight to serial because the logger takes locks, and a probed
+    function could be holding them. Another cpu running through a probe while it's being
+    stepped over isn't traced
+*/
+
+use crate::arch::cpu::{self, InterruptContext};
+use crate::error::{KError, KResult};
+use crate::ksym;
+use crate::mm::vmm::{self, VirtAddr};
+use crate::serial;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+const MAX_PROBES: usize = 16;
+const INT3: u8 = 0xcc;
+
+// the handlers look probes up without locking, so every field is an atomic
+struct Probe {
+    address: AtomicU64, // 0 if the slot is unused
+    original: AtomicU8,
+    hits: AtomicU64,
+}
+
+impl Probe {
+    const EMPTY: Probe = Probe {
+        address: AtomicU64::new(0),
+        original: AtomicU8::new(0),
+        hits: AtomicU64::new(0),
+    };
+}
+
+static PROBES: [Probe; MAX_PROBES] = [Probe::EMPTY; MAX_PROBES];
+// only serializes arm and disarm
+static ARMING: spin::Mutex<()> = spin::Mutex::new(());
+// the probe whose instruction is being single stepped, 0 if none
+static STEPPING: AtomicU64 = AtomicU64::new(0);
+
+fn find(address: u64) -> Option<&'static Probe> {
+    PROBES.iter().find(|probe| probe.address.load(Ordering::SeqCst) == address)
+}
+
+// only the start of a function is sure to be the start of an instruction
+pub fn arm(address: u64) -> KResult<()> {
+    match ksym::resolve(address) {
+        Some((_, 0)) => {}
+        _ => return Err(KError::EINVAL),
+    }
+
+    let _arming = ARMING.lock();
+
+    if find(address).is_some() {
+        return Err(KError::EEXIST);
+    }
+
+    let probe = find(0).ok_or(KError::ENOSPC)?;
+    let phys = vmm::translate_active(VirtAddr::new(address)).ok_or(KError::EFAULT)?;
+    let original = unsafe { phys.higher_half().as_ptr::<u8>().read_volatile() };
+
+    // the probe has to be findable before the int3 can be hit
+    probe.original.store(original, Ordering::SeqCst);
+    probe.hits.store(0, Ordering::SeqCst);
+    probe.address.store(address, Ordering::SeqCst);
+    vmm::patch_byte(VirtAddr::new(address), INT3);
+
+    Ok(())
+}
+
+pub fn disarm(address: u64) -> KResult<()> {
+    let _arming = ARMING.lock();
+    let probe = find(address).filter(|_| address != 0).ok_or(KError::ENOENT)?;
+
+    vmm::patch_byte(VirtAddr::new(address), probe.original.load(Ordering::SeqCst));
+    probe.address.store(0, Ordering::SeqCst);
+
+    Ok(())
+}
+
+// (address, hits) of every armed probe
+pub fn probes() -> Vec<(u64, u64)> {
+    PROBES
+        .iter()
+        .map(|probe| (probe.address.load(Ordering::SeqCst), probe.hits.load(Ordering::SeqCst)))
+        .filter(|(address, _)| *address != 0)
+        .collect()
+}
+
+fn dump(address: u64, hits: u64, regs: &InterruptContext) {
+    serial::print!("[KPROBE] {} hit {}\n", ksym::Symbolized(address), hits);
+    serial::print!(
+        "[KPROBE]     rax={:#018x} rbx={:#018x} rcx={:#018x} rdx={:#018x}\n",
+        regs.rax,
+        regs.rbx,
+        regs.rcx,
+        regs.rdx
+    );
+    serial::print!(
+        "[KPROBE]     rsi={:#018x} rdi={:#018x} rbp={:#018x} rsp={:#018x}\n",
+        regs.rsi,
+        regs.rdi,
+        regs.rbp,
+        regs.rsp
+    );
+    serial::print!(
+        "[KPROBE]     r8={:#018x} r9={:#018x} r10={:#018x} r11={:#018x}\n",
+        regs.r8,
+        regs.r9,
+        regs.r10,
+        regs.r11
+    );
+    serial::print!(
+        "[KPROBE]     r12={:#018x} r13={:#018x} r14={:#018x} r15={:#018x}\n",
+        regs.r12,
+        regs.r13,
+        regs.r14,
+        regs.r15
+    );
+    serial::print!(
+        "[KPROBE]     rflags={:#x} cs={:#x} ss={:#x}\n",
+        regs.rflags,
+        regs.cs,
+        regs.ss
+    );
+    if let Some(caller) = unsafe { (regs.rsp as *const u64).as_ref() } {
+        // at the first instruction the return address is still on top of the stack
+        serial::print!("[KPROBE]     called from {}\n", ksym::Symbolized(*caller));
+    }
+}
+
+// called by the int3 handler, returns false if the int3 isn't one of ours
+pub fn on_breakpoint(regs: &mut InterruptContext) -> bool {
+    // rip is past the int3
+    let address = regs.rip.wrapping_sub(1);
+    let probe = match find(address) {
+        Some(probe) if address != 0 => probe,
+        _ => return false,
+    };
+
+    let hits = probe.hits.fetch_add(1, Ordering::SeqCst) + 1;
+    dump(address, hits, regs);
+
+    // run the real instruction once, on_single_step puts the int3 back
+    vmm::patch_byte(VirtAddr::new(address), probe.original.load(Ordering::SeqCst));
+    STEPPING.store(address, Ordering::SeqCst);
+
+    regs.rip = address;
+    regs.rflags |= cpu::TRAP_FLAG;
+
+    true
+}
+
+// called by the #DB handler, which already cleared the trap flag
+pub fn on_single_step(_regs: &InterruptContext) -> bool {
+    let address = STEPPING.swap(0, Ordering::SeqCst);
+    if address == 0 {
+        return false;
+    }
+
+    // it may have been disarmed while we were stepping
+    if find(address).is_some() {
+        vmm::patch_byte(VirtAddr::new(address), INT3);
+    }
+
+    true
+}
@@ -0,0 +1,136 @@
+/*
+    The kernel's own symbol table, generated by build.rs and linked into .ksyms. It only
+    has the code symbols, which is enough to turn return addresses into function names.
+    A kernel built with plain cargo build has an empty table and nothing resolves
+*/
+
+use crate::arch::mm::pmm::PHYS_BASE;
+use core::arch::asm;
+use core::fmt;
+
+const HEADER_SIZE: usize = 8;
+const ENTRY_SIZE: usize = 16;
+// the panic backtrace gives up after this many frames, the chain may be corrupted
+const MAX_FRAMES: usize = 32;
+
+include!(concat!(env!("OUT_DIR"), "/ksyms.rs"));
+
+// defined in linker.ld
+#[allow(non_upper_case_globals)]
+extern "C" {
+    static ksyms_start: u8;
+    static ksyms_end: u8;
+    static text_start: u8;
+    static text_end: u8;
+}
+
+fn table() -> &'static [u8] {
+    unsafe {
+        let start = &ksyms_start as *const u8;
+        let end = &ksyms_end as *const u8;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn count() -> usize {
+    let table = table();
+
+    if table.len() < HEADER_SIZE {
+        return 0;
+    }
+
+    read_u64(table, 0) as usize
+}
+
+fn entry(index: usize) -> (u64, &'static str) {
+    let table = table();
+    let offset = HEADER_SIZE + index * ENTRY_SIZE;
+    let names = HEADER_SIZE + count() * ENTRY_SIZE;
+
+    let address = read_u64(table, offset);
+    let name_start = names + read_u32(table, offset + 8) as usize;
+    let name_len = read_u32(table, offset + 12) as usize;
+    let name = core::str::from_utf8(&table[name_start..name_start + name_len]).unwrap_or("?");
+
+    (address, name)
+}
+
+fn is_text(address: u64) -> bool {
+    unsafe {
+        address >= &text_start as *const u8 as u64 && address < &text_end as *const u8 as u64
+    }
+}
+
+// the function address is in, and how far into it
+pub fn resolve(address: u64) -> Option<(&'static str, u64)> {
+    if !is_text(address) {
+        return None;
+    }
+
+    // the number of symbols at or below address
+    let (mut low, mut high) = (0, count());
+    while low < high {
+        let middle = (low + high) / 2;
+
+        if entry(middle).0 <= address {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+
+    if low == 0 {
+        return None;
+    }
+
+    let (start, name) = entry(low - 1);
+    Some((name, address - start))
+}
+
+// the other way around, for the shell
+pub fn lookup(name: &str) -> Option<u64> {
+    (0..count()).map(entry).find(|(_, other)| *other == name).map(|(address, _)| address)
+}
+
+// formats an address as "0xffffffff80001234 <griffin::main+0x34>", nothing is allocated
+pub struct Symbolized(pub u64);
+
+impl fmt::Display for Symbolized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match resolve(self.0) {
+            Some((name, offset)) => write!(f, "{:#x} <{}+{:#x}>", self.0, name, offset),
+            None => write!(f, "{:#x}", self.0),
+        }
+    }
+}
+
+// walks the frame pointers of the current stack, calling f with every return address
+pub fn backtrace(mut f: impl FnMut(u64)) {
+    let mut frame: u64;
+
+    unsafe {
+        asm!("mov {}, rbp", out(reg) frame);
+    }
+
+    for _ in 0..MAX_FRAMES {
+        if frame < PHYS_BASE || frame % 8 != 0 {
+            break;
+        }
+
+        let return_address = unsafe { *((frame + 8) as *const u64) };
+        if !is_text(return_address) {
+            break;
+        }
+
+        f(return_address);
+        frame = unsafe { *(frame as *const u64) };
+    }
+}
@@ -0,0 +1,161 @@
+use super::{kassert, kassert_eq, ktest};
+use crate::arch::cpuid::{self, Features};
+use crate::arch::cpu::{self, MsrList};
+use crate::arch::{apic, interrupts};
+use crate::drivers::pit;
+use crate::drivers::timer_source::{self, Source};
+use alloc::string::String;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+static TIMER_FIRED: AtomicBool = AtomicBool::new(false);
+
+interrupts::isr!(ktest_timer, |_regs| {
+    TIMER_FIRED.store(true, Ordering::SeqCst);
+    apic::get().eoi();
+});
+
+ktest!(apic_timer_calibrated, {
+    // anything below 1MHz or above 10GHz means the calibration went wrong
+    let frequency = apic::get().timer_frequency();
+    kassert!(frequency > 1_000_000 && frequency < 10_000_000_000);
+});
+
+ktest!(apic_oneshot_fires, {
+    let vector = interrupts::alloc_vector().ok_or("no free vector")?;
+    unsafe { interrupts::register_isr(vector, ktest_timer as u64, 0, 0x8e, "ktest") };
+
+    TIMER_FIRED.store(false, Ordering::SeqCst);
+    apic::get().oneshot(vector as u8, 1_000_000);
+
+    let deadline = timer_source::current_ms() + 100;
+    while !TIMER_FIRED.load(Ordering::SeqCst) && timer_source::current_ms() < deadline {
+        core::hint::spin_loop();
+    }
+
+    apic::get().stop_timer();
+    let hits = interrupts::hits(vector);
+    // a gate type of 0 gives the vector back
+    unsafe { interrupts::register_isr(vector, 0, 0, 0, "") };
+
+    kassert!(TIMER_FIRED.load(Ordering::SeqCst));
+    kassert!(hits >= 1);
+});
+
+ktest!(apic_spurious_and_error_vectors, {
+    // both are taken, so nothing else is given them
+    let used = interrupts::used_vectors();
+    for vector in [apic::SPURIOUS_VECTOR, apic::ERROR_VECTOR] {
+        kassert!(used.iter().any(|(used, _, _)| *used == vector as usize));
+    }
+
+    // enabled, with the spurious vector in the sivr, and no errors latched
+    let sivr = apic::get().read(apic::LapicRegisters::Sivr);
+    kassert_eq!(sivr & 0x1ff, 0x100 | apic::SPURIOUS_VECTOR as u32);
+    kassert_eq!(apic::get().read_errors(), 0);
+});
+
+ktest!(interrupt_stats, {
+    let mut stats = String::new();
+    interrupts::write_stats(&mut stats).map_err(|_| "write_stats failed")?;
+
+    // a header with the cpus, then the registered vectors with their names
+    let mut lines = stats.lines();
+    kassert!(lines.next().map_or(false, |header| header.trim_start().starts_with("CPU0")));
+    kassert!(lines.any(|line| line.starts_with(" 14:") && line.ends_with("page fault")));
+    let free = interrupts::alloc_vector().ok_or("no free vector")?;
+    kassert_eq!(interrupts::hits(free), 0);
+});
+
+ktest!(timer_source_sleeps, {
+    kassert!(timer_source::is_initialized());
+
+    let start = timer_source::current_ns();
+    timer_source::sleep(5);
+    let elapsed = timer_source::current_ns() - start;
+    kassert!(elapsed >= 5_000_000 && elapsed < 1_000_000_000);
+});
+
+ktest!(pit_keeps_time, {
+    // the pit counts on its own, whichever source is used
+    if timer_source::source() != Some(Source::Pit) {
+        pit::init();
+    }
+
+    let start = pit::current_ns();
+    timer_source::sleep(2);
+    let elapsed = pit::current_ns() - start;
+    kassert!(elapsed >= 1_000_000 && elapsed < 50_000_000);
+});
+
+ktest!(cpuid_features, {
+    let info = cpuid::info();
+
+    kassert!(info.supported.contains(info.enabled));
+    // every x86_64 cpu qemu emulates has these
+    kassert!(cpuid::has(Features::NX));
+    kassert!(info.vendor.len() == 12);
+
+    let names = cpuid::names(Features::NX | Features::SMAP);
+    kassert!(names.as_str() == "nx smap");
+});
+
+ktest!(msrs_enabled, {
+    let efer = cpu::rdmsr(MsrList::Efer);
+    kassert!(efer & cpu::EFER_SCE != 0);
+    kassert_eq!(efer & cpu::EFER_NXE != 0, cpuid::has(Features::NX));
+
+    // through rdfsbase when the cpu has it, which faults unless cr4 allows it
+    let base = cpu::get_fs_base();
+    kassert_eq!(base, cpu::rdmsr(MsrList::FsBase));
+});
+
+ktest!(topology_of_the_bsp, {
+    use crate::arch::topology::{self, Distance};
+
+    let bsp = topology::get(0).ok_or("the bsp has no topology")?;
+    kassert!(topology::split(bsp.apic_id) == bsp);
+    kassert!(topology::distance(0, 0) == Distance::Same);
+    kassert!(topology::domain(0, Distance::Same) == 1);
+    kassert!(topology::possible_cpus() >= 1);
+
+    // whatever the cpu reports has to describe a real cache
+    for cache in topology::caches() {
+        kassert!(cache.size > 0 && cache.line_size.is_power_of_two());
+    }
+});
+
+#[cfg(feature = "smp")]
+static AP_CALLS: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
+#[cfg(feature = "smp")]
+fn count_ap_call(_: u64) {
+    AP_CALLS.fetch_add(1, Ordering::SeqCst);
+}
+
+// every ap that came up has to answer ipis
+#[cfg(feature = "smp")]
+ktest!(smp_aps_take_ipis, {
+    use crate::arch::{cpu, ipi, smp};
+
+    kassert!(smp::lapic_id(0) == Some(apic::get().id()));
+
+    AP_CALLS.store(0, Ordering::SeqCst);
+    for cpu_id in 1..cpu::online_cpus() {
+        let lapic_id = smp::lapic_id(cpu_id).ok_or("an online cpu has no lapic id")?;
+        ipi::call_on_cpu(lapic_id, count_ap_call, 0, true);
+    }
+
+    kassert!(AP_CALLS.load(Ordering::SeqCst) == cpu::online_cpus() - 1);
+});
+
+ktest!(mce_decodes_error_codes, {
+    use crate::arch::mce::decode;
+
+    kassert_eq!(decode(0), "no error");
+    // a data cache read error at level 1, and a memory controller read on channel 2
+    kassert_eq!(decode(0x0000_0000_0000_0135), "cache error");
+    kassert_eq!(decode(0x0000_0000_0000_00a2), "memory controller error");
+    // a tlb error on level 0, only the low 16 bits of the status count
+    kassert_eq!(decode(0xb200_0000_0000_0010), "tlb error");
+    kassert_eq!(decode(0x0000_0000_0000_0e0f), "bus or interconnect error");
+});
@@ -0,0 +1,118 @@
+use super::{kassert, kassert_eq, ktest};
+use crate::{kprobe, ksym, trace};
+
+ktest!(ksym_resolves_functions, {
+    let address = ksym::lookup as u64;
+    let (name, offset) = ksym::resolve(address).ok_or("ksym::lookup has no symbol")?;
+    kassert!(name.ends_with("ksym::lookup"));
+    kassert_eq!(offset, 0);
+
+    let (name, offset) = ksym::resolve(address + 1).ok_or("no symbol past the start")?;
+    kassert!(name.ends_with("ksym::lookup"));
+    kassert_eq!(offset, 1);
+
+    kassert_eq!(ksym::lookup(name), Some(address));
+    // the direct map is not code
+    kassert!(ksym::resolve(0xffff800000001000).is_none());
+});
+
+#[cfg(feature = "shell")]
+ktest!(shell_sym_command, {
+    let output = crate::shell::execute(&alloc::format!("sym {:#x}", ksym::lookup as u64));
+    kassert!(output.contains("ksym::lookup+0x0"));
+    kassert!(crate::shell::execute("nonsense").starts_with("unknown command"));
+});
+
+#[inline(never)]
+fn kprobe_target(value: u64) -> u64 {
+    core::hint::black_box(value) + 1
+}
+
+ktest!(kprobe_counts_hits, {
+    let address = kprobe_target as u64;
+    let first_byte = unsafe { *(address as *const u8) };
+
+    kprobe::arm(address).map_err(|err| alloc::format!("arm failed: {}", err))?;
+    kassert!(kprobe::arm(address).is_err());
+    kassert_eq!(kprobe_target(1), 2);
+    kassert_eq!(kprobe_target(2), 3);
+    kassert_eq!(kprobe::probes(), alloc::vec![(address, 2)]);
+
+    kprobe::disarm(address).map_err(|err| alloc::format!("disarm failed: {}", err))?;
+    kassert_eq!(kprobe_target(3), 4);
+    kassert!(kprobe::probes().is_empty());
+    kassert_eq!(unsafe { *(address as *const u8) }, first_byte);
+    // only function starts can be probed
+    kassert!(kprobe::arm(address + 1).is_err());
+});
+
+ktest!(trace_records_in_order, {
+    let was_enabled = trace::is_enabled();
+    trace::enable(true);
+    trace::clear();
+
+    trace::trace!(sched_wake, 1);
+    trace::trace!(sched_switch, 1, 2);
+    let records = trace::records();
+    trace::enable(was_enabled);
+
+    kassert_eq!(records.len(), 2);
+    kassert_eq!(records[0].event.name(), "sched_wake");
+    kassert_eq!(records[1].args, [1, 2]);
+    kassert!(records[0].timestamp <= records[1].timestamp);
+    kassert!(trace::dump().contains("sched_switch"));
+});
+
+ktest!(base64_encoding, {
+    use crate::utils::base64;
+
+    let encode = |data: &[u8]| {
+        let mut encoded = alloc::string::String::new();
+        base64::encode(data, |c| encoded.push(c as char));
+        encoded
+    };
+
+    // the rfc's test vectors, every amount of padding
+    kassert_eq!(encode(b""), "");
+    kassert_eq!(encode(b"f"), "Zg==");
+    kassert_eq!(encode(b"fo"), "Zm8=");
+    kassert_eq!(encode(b"foo"), "Zm9v");
+    kassert_eq!(encode(b"foobar"), "Zm9vYmFy");
+    kassert_eq!(encode(&[0xfb, 0xff]), "+/8=");
+});
+
+ktest!(kmsg_drops_oldest_records, {
+    use crate::log::{Kmsg, Level};
+    use alloc::{boxed::Box, format, string::String};
+
+    let mut kmsg = Box::new(Kmsg::new());
+    for i in 0..1000 {
+        kmsg.push(Level::Info, i * 1000, format!("message {}", i).as_bytes());
+    }
+
+    // the ring is smaller than a thousand records, the oldest ones went first
+    kassert!(kmsg.first_seq() > 0);
+    kassert_eq!(kmsg.next_seq(), 1000);
+
+    let mut expected = kmsg.first_seq();
+    let mut last = String::new();
+    kmsg.for_each(|record| {
+        if record.seq != expected || record.time_ns != record.seq * 1000 {
+            return false;
+        }
+        expected += 1;
+        last = String::from(record.text);
+        true
+    });
+    kassert_eq!(expected, 1000);
+    kassert_eq!(last, "message 999");
+
+    let mut line = String::new();
+    kmsg.for_each(|record| {
+        record.write_kmsg(&mut line).ok();
+        false
+    });
+    // the time is in us, pushed as seq * 1000 ns
+    let first = kmsg.first_seq();
+    kassert_eq!(line, format!("6,{},{},-;message {}\n", first, first, first));
+});
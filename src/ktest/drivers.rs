@@ -0,0 +1,244 @@
+use super::{kassert, kassert_eq, ktest};
+use crate::drivers::aio;
+use crate::drivers::block::{BlockDevice, IoRequest, Priority};
+use crate::drivers::iosched::{self, Extent, Queue};
+use crate::drivers::ramdisk::Ramdisk;
+use crate::proc::wait::WaitQueue;
+use crate::rng;
+use crate::serial::{SerialConfig, SerialWriter};
+use crate::vdso::VdsoData;
+use core::cell::Cell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+ktest!(ramdisk_read_write, {
+    let ramdisk = Ramdisk::new(String::from("ktest"), 8192);
+
+    let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+    kassert_eq!(ramdisk.write(4000, data.len(), data.as_ptr()), Ok(data.len()));
+
+    let mut read_back = alloc::vec![0u8; data.len()];
+    kassert_eq!(ramdisk.read(4000, read_back.len(), read_back.as_mut_ptr()), Ok(data.len()));
+    kassert!(read_back == data);
+
+    // a new ramdisk is zeroed
+    let mut zeroes = [0xffu8; 16];
+    ramdisk.read(0, zeroes.len(), zeroes.as_mut_ptr()).ok();
+    kassert!(zeroes.iter().all(|byte| *byte == 0));
+});
+
+ktest!(ramdisk_out_of_range, {
+    let ramdisk = Ramdisk::new(String::from("ktest"), 4096);
+    let mut buffer = [0u8; 16];
+
+    kassert!(ramdisk.read(4090, buffer.len(), buffer.as_mut_ptr()).is_err());
+    kassert!(ramdisk.write(u64::MAX, buffer.len(), buffer.as_ptr()).is_err());
+});
+
+ktest!(iosched_sorts_and_merges, {
+    let extent = |write, priority, offset, bytes| Extent {
+        write,
+        priority,
+        offset,
+        bytes,
+    };
+    let extents = [
+        extent(false, Priority::Data, 4096, 512),
+        extent(false, Priority::Data, 512, 512),
+        extent(false, Priority::Data, 4608, 512),
+        extent(true, Priority::Metadata, 8192, 4),
+        extent(true, Priority::Data, 5120, 512),
+        extent(false, Priority::Data, 0, 512),
+    ];
+
+    // metadata first, then up from 1024, then the rest from the start of the disk
+    let runs = iosched::plan(&extents, 1024);
+    kassert!(runs == [alloc::vec![3], alloc::vec![0, 2], alloc::vec![4], alloc::vec![5, 1]]);
+
+    // a queued ramdisk reads and writes like the ramdisk itself
+    let queue = Queue::new(Arc::new(Ramdisk::new(String::from("ktest"), 8192)));
+    let data = [0x5au8; 600];
+    kassert_eq!(queue.write(1000, data.len(), data.as_ptr()), Ok(data.len()));
+
+    let mut read_back = [0u8; 600];
+    let read = queue.read_with(1000, read_back.len(), read_back.as_mut_ptr(), Priority::Metadata);
+    kassert_eq!(read, Ok(data.len()));
+    kassert!(read_back == data);
+});
+
+ktest!(aio_submit_and_complete, {
+    let ramdisk: Arc<dyn BlockDevice> = Arc::new(Ramdisk::new(String::from("ktest-aio"), 8192));
+    let data = alloc::vec![0x3cu8; 1024];
+
+    // the callback sees the completion before the handle does
+    let called = Arc::new(AtomicBool::new(false));
+    let flag = called.clone();
+    let write = aio::Request::write(ramdisk.clone(), 2048, data.clone(), Priority::Data);
+    let handle = aio::submit_with(write, move |completion| {
+        flag.store(completion.result == Ok(1024), Ordering::SeqCst);
+    });
+    kassert_eq!(handle.wait().result, Ok(1024));
+    kassert!(called.load(Ordering::SeqCst));
+
+    let completions = aio::run_all(alloc::vec![
+        aio::Request::read(ramdisk.clone(), 2048, 1024, Priority::Data),
+        aio::Request::read(ramdisk.clone(), 0, 16, Priority::Metadata),
+        aio::Request::read(ramdisk, 8000, 1024, Priority::Data),
+    ]);
+    kassert!(completions[0].buffer == data);
+    kassert!(completions[1].buffer.iter().all(|byte| *byte == 0));
+    kassert!(completions[2].result.is_err());
+});
+
+ktest!(io_request_completion, {
+    kassert!(IoRequest::new(None, 10).wait(|| true));
+    kassert!(!IoRequest::new(None, 10).wait(|| false));
+
+    // stands in for a device whose interrupt handler notifies the queue
+    let queue = WaitQueue::new();
+    let checks = Cell::new(0);
+    let done = IoRequest::new(Some(&queue), 1000).wait(|| {
+        checks.set(checks.get() + 1);
+        queue.notify();
+        checks.get() == 3
+    });
+    kassert!(done);
+    kassert_eq!(checks.get(), 3);
+});
+
+ktest!(rng_chacha_block, {
+    // rfc 8439, section 2.3.2
+    let key: [u32; 8] = core::array::from_fn(|i| {
+        u32::from_le_bytes([4 * i as u8, 4 * i as u8 + 1, 4 * i as u8 + 2, 4 * i as u8 + 3])
+    });
+    let output = rng::block(&key, 1, &[0x09000000, 0x4a000000, 0]);
+
+    kassert_eq!(output[0], 0xe4e7f110);
+    kassert_eq!(output[1], 0x15593bd1);
+    kassert_eq!(output[15], 0x4e3c50a2);
+});
+
+ktest!(rng_fill, {
+    let mut first = [0u8; 100];
+    let mut second = [0u8; 100];
+    rng::fill(&mut first);
+    rng::fill(&mut second);
+
+    // the key changes after every fill
+    kassert!(first != second);
+    kassert!(first.iter().any(|byte| *byte != 0));
+    kassert!(rng::below(10) < 10);
+});
+
+ktest!(serial_rejects_bad_baud_rates, {
+    // all of them are refused before the port is touched
+    let refused = |baud_rate| !SerialWriter::init(SerialConfig { baud_rate, flow_control: false });
+
+    kassert!(refused(0));
+    // a divisor of 115200 doesn't fit in the latch
+    kassert!(refused(1));
+    // 115200 / 7 isn't a whole divisor
+    kassert!(refused(7));
+    kassert!(refused(230400));
+});
+
+ktest!(vdso_clock, {
+    let data = VdsoData::new(1_000_000_000, 1_600_000_000 * 1_000_000_000);
+
+    kassert!(data.update(5000, 100));
+    // at 1GHz every cycle is a ns
+    kassert_eq!(data.monotonic_ns(5000), 100);
+    kassert_eq!(data.monotonic_ns(6500), 1600);
+    kassert_eq!(data.realtime_ns(5000), 1_600_000_000 * 1_000_000_000 + 100);
+    // a tsc read before the update doesn't go back in time
+    kassert_eq!(data.monotonic_ns(4000), 100);
+
+    // someone else is updating
+    data.sequence.fetch_add(1, Ordering::SeqCst);
+    kassert!(!data.update(7000, 2100));
+});
+
+#[cfg(feature = "graphics")]
+ktest!(gfx_ppm_images, {
+    use crate::video::gfx::Image;
+
+    let image = Image::from_ppm(b"P6\n2 1\n255\n\xff\x00\x00\x00\x00\xff")
+        .ok_or("a valid ppm wasn't parsed")?;
+    kassert_eq!((image.width, image.height), (2, 1));
+
+    // too few pixels, then an ascii ppm
+    kassert!(Image::from_ppm(b"P6\n2 2\n255\n\xff\x00\x00").is_none());
+    kassert!(Image::from_ppm(b"P3\n1 1\n255\n255 0 0\n").is_none());
+    kassert!(Image::from_ppm(include_bytes!("../video/logo.ppm")).is_some());
+});
+
+#[cfg(feature = "graphics")]
+ktest!(gfx_dirty_tracking, {
+    use crate::video::gfx::{Framebuffer, Rect};
+
+    let mut pixels = [0u32; 16 * 8];
+    let mut fb = Framebuffer::from_raw(pixels.as_mut_ptr(), 16, 8, 16);
+    kassert!(fb.take_dirty().is_none());
+
+    // two pixels make one rectangle around both, and a fill is clipped to the screen
+    fb.put_pixel(2, 1, 0xffffff);
+    fb.put_pixel(5, 3, 0xffffff);
+    kassert_eq!(fb.take_dirty(), Some(Rect { x: 2, y: 1, width: 4, height: 3 }));
+    kassert!(fb.take_dirty().is_none());
+
+    fb.fill_rect(12, 6, 10, 10, 0xff0000);
+    kassert_eq!(fb.take_dirty(), Some(Rect { x: 12, y: 6, width: 4, height: 2 }));
+
+    // nothing was drawn off the screen
+    fb.put_pixel(16, 0, 0xffffff);
+    kassert!(fb.take_dirty().is_none());
+});
+
+ktest!(ps2_mouse_packets, {
+    use crate::drivers::ps2::{decode, Packet};
+
+    // left button, one right and one up, which is down on the screen
+    kassert_eq!(
+        decode(&[0x09, 1, 1]),
+        Some(Packet { buttons: 1, dx: 1, dy: -1, wheel: 0 })
+    );
+    // both negative through the sign bits, and a wheel step down
+    kassert_eq!(
+        decode(&[0x38, 0xfe, 0xfb, 0x0f]),
+        Some(Packet { buttons: 0, dx: -2, dy: 5, wheel: -1 })
+    );
+
+    // out of sync, then an overflow
+    kassert!(decode(&[0x01, 1, 1]).is_none());
+    kassert!(decode(&[0x48, 0xff, 0]).is_none());
+});
+
+ktest!(input_queue_drops_oldest, {
+    use crate::drivers::input::{self, InputEvent, InputQueue};
+
+    let queue = InputQueue::new();
+    for i in 0..300 {
+        queue.push(&[InputEvent::new(input::EV_REL, input::REL_X, i)]);
+    }
+
+    // the ring holds 256, the first ones were pushed out
+    kassert_eq!(queue.len(), 256);
+    kassert_eq!(queue.dropped(), 44);
+    kassert_eq!(queue.pop().map(|event| event.value), Some(44));
+});
+
+#[cfg(feature = "audio")]
+ktest!(audio_format_conversion, {
+    use crate::drivers::audio::Format;
+
+    let stereo = Format { rate: 48000, channels: 2, bits: 16 };
+    kassert_eq!(stereo.frame_size(), 4);
+    kassert_eq!(stereo.convert(&[0x34, 0x12, 0x00, 0x80]), [0x1234, i16::MIN]);
+
+    // unsigned 8 bit mono, copied to both sides
+    let mono = Format { rate: 8000, channels: 1, bits: 8 };
+    kassert_eq!(mono.frame_size(), 1);
+    kassert_eq!(mono.convert(&[0x80]), [0, 0]);
+    kassert_eq!(mono.convert(&[0xff]), [0x7f00, 0x7f00]);
+    kassert_eq!(mono.convert(&[0x00]), [i16::MIN, i16::MIN]);
+});
@@ -0,0 +1,1109 @@
+use super::{kassert, kassert_eq, ktest};
+use crate::drivers::{block::BlockDevice, ramdisk::Ramdisk};
+use crate::fs::partitions::{self, Guid};
+use crate::fs::dcache::DentryCache;
+use crate::fs::probe;
+use crate::boot;
+use crate::fs::{cpio, devfs, modfs, procfs, ramfs::Ramfs, shmfs, uring, writeback};
+use crate::drivers::timer_source;
+use crate::error::{KError, KResult};
+use crate::fs::vfs;
+use crate::mm::frame;
+use crate::proc::wait::WaitQueue;
+use crate::utils::crc32::crc32;
+use alloc::{format, string::String, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// the disk image built by the Makefile has a copy of limine.cfg in /home
+const LIMINE_CFG: &[u8] = include_bytes!("../../limine.cfg");
+
+ktest!(vfs_mount_point_lookup, {
+    let mount_point = vfs::get_mount_point("/home/limine.cfg").ok_or("no mount point for /")?;
+    kassert_eq!(mount_point.name(), "/");
+});
+
+ktest!(vfs_relative_path, {
+    // there's no running process, so the working directory is /
+    kassert_eq!(vfs::getcwd().as_str(), "/");
+    kassert!(vfs::open("home/limine.cfg", vfs::Flags::O_RDONLY, vfs::Mode::empty()).is_ok());
+    kassert!(vfs::open("./home/../home/limine.cfg", vfs::Flags::O_RDONLY, vfs::Mode::empty())
+        .is_ok());
+});
+
+ktest!(vfs_normalize_path, {
+    kassert_eq!(vfs::normalize_path("/", "home/limine.cfg").as_str(), "/home/limine.cfg");
+    kassert_eq!(vfs::normalize_path("/home", "./limine.cfg").as_str(), "/home/limine.cfg");
+    kassert_eq!(vfs::normalize_path("/home", "../etc//passwd").as_str(), "/etc/passwd");
+    kassert_eq!(vfs::normalize_path("/home", "/usr/./bin/").as_str(), "/usr/bin");
+    kassert_eq!(vfs::normalize_path("/", "../../..").as_str(), "/");
+    kassert_eq!(vfs::normalize_path("/a/b", "..").as_str(), "/a");
+});
+
+ktest!(vfs_sync_every_mount, {
+    kassert_eq!(vfs::sync(), Ok(()));
+    // nothing was left dirty by the first one
+    kassert_eq!(writeback::sync(), Ok(()));
+});
+
+ktest!(vfs_missing_file, {
+    let missing = vfs::open("/home/does_not_exist", vfs::Flags::O_RDONLY, vfs::Mode::empty());
+    kassert_eq!(missing.err(), Some(KError::ENOENT));
+
+    let not_dir = vfs::open("/home/limine.cfg/nope", vfs::Flags::O_RDONLY, vfs::Mode::empty());
+    kassert_eq!(not_dir.err(), Some(KError::ENOTDIR));
+});
+
+ktest!(ext2_read_file, {
+    let mut fd = vfs::open("/home/limine.cfg", vfs::Flags::O_RDONLY, vfs::Mode::empty())
+        .map_err(|_| "could not open /home/limine.cfg")?;
+
+    let mut content = alloc::vec![0u8; LIMINE_CFG.len()];
+    let read = vfs::read(&mut fd, content.as_mut_ptr(), content.len());
+
+    kassert_eq!(read, Ok(LIMINE_CFG.len()));
+    kassert_eq!(fd.offset, LIMINE_CFG.len());
+    kassert!(content.as_slice() == LIMINE_CFG);
+});
+
+ktest!(ext2_read_at_offset, {
+    let mut fd = vfs::open("/home/limine.cfg", vfs::Flags::O_RDONLY, vfs::Mode::empty())
+        .map_err(|_| "could not open /home/limine.cfg")?;
+
+    let mut content = alloc::vec![0u8; 8];
+    vfs::pread(&fd, content.as_mut_ptr(), content.len(), 4)
+        .map_err(|err| format!("read failed: {}", err))?;
+    kassert!(content.as_slice() == &LIMINE_CFG[4..12]);
+    kassert_eq!(fd.offset, 0);
+
+    // consecutive reads carry on where the last one stopped
+    for expected in LIMINE_CFG[..16].chunks(8) {
+        vfs::read(&mut fd, content.as_mut_ptr(), content.len())
+            .map_err(|err| format!("read failed: {}", err))?;
+        kassert!(content.as_slice() == expected);
+    }
+});
+
+ktest!(ext2_readdir, {
+    let fd = vfs::open("/home", vfs::Flags::O_RDONLY, vfs::Mode::empty())
+        .map_err(|_| "could not open /home")?;
+
+    let names = vfs::read_dir(&fd)
+        .map(|entry| entry.map(|entry| entry.name))
+        .collect::<Result<Vec<String>, KError>>()
+        .map_err(|err| format!("readdir failed: {}", err))?;
+
+    kassert!(names.iter().any(|name| name == "."));
+    kassert!(names.iter().any(|name| name == ".."));
+    kassert!(names.iter().any(|name| name == "limine.cfg"));
+});
+
+ktest!(ext2_create_write_read, {
+    let flags = vfs::Flags::O_CREAT | vfs::Flags::O_RDWR;
+    let mut fd = vfs::open("/ktest_file", flags, vfs::Mode::empty())
+        .map_err(|_| "could not create /ktest_file")?;
+
+    let data: Vec<u8> = (0..3000u32).map(|i| (i % 251) as u8).collect();
+    let written = vfs::write(&mut fd, data.as_ptr(), data.len());
+    kassert_eq!(written, Ok(data.len()));
+
+    let mut read_back = alloc::vec![0u8; data.len()];
+    vfs::pread(&fd, read_back.as_mut_ptr(), read_back.len(), 0)
+        .map_err(|err| format!("read failed: {}", err))?;
+    kassert!(read_back == data);
+});
+
+// a 64 sector disk with one partition, laid out the way a real gpt disk is
+fn build_gpt_disk(ramdisk: &Ramdisk) {
+    fn put(buffer: &mut [u8], offset: usize, bytes: &[u8]) {
+        buffer[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+
+    let mut entries = [0u8; 512];
+    put(&mut entries, 0, &Guid::LINUX_FILESYSTEM.0);
+    put(&mut entries, 16, &[0x42; 16]);
+    put(&mut entries, 32, &34u64.to_le_bytes());
+    put(&mut entries, 40, &61u64.to_le_bytes());
+    for (i, c) in "root".encode_utf16().enumerate() {
+        put(&mut entries, 56 + i * 2, &c.to_le_bytes());
+    }
+
+    for (hdr_lba, alt_lba, entries_lba) in [(1u64, 63u64, 2u64), (63, 1, 62)] {
+        let mut header = [0u8; 512];
+        put(&mut header, 0, b"EFI PART");
+        put(&mut header, 8, &0x10000u32.to_le_bytes());
+        put(&mut header, 12, &92u32.to_le_bytes());
+        put(&mut header, 24, &hdr_lba.to_le_bytes());
+        put(&mut header, 32, &alt_lba.to_le_bytes());
+        put(&mut header, 40, &34u64.to_le_bytes());
+        put(&mut header, 48, &61u64.to_le_bytes());
+        put(&mut header, 72, &entries_lba.to_le_bytes());
+        put(&mut header, 80, &4u32.to_le_bytes());
+        put(&mut header, 84, &128u32.to_le_bytes());
+        put(&mut header, 88, &crc32(&entries).to_le_bytes());
+        let checksum = crc32(&header[..92]);
+        put(&mut header, 16, &checksum.to_le_bytes());
+
+        ramdisk.write(hdr_lba * 512, 512, header.as_ptr()).ok();
+        ramdisk.write(entries_lba * 512, 512, entries.as_ptr()).ok();
+    }
+}
+
+ktest!(crc32_check_value, {
+    kassert_eq!(crc32(b"123456789"), 0xcbf43926);
+    kassert_eq!(crc32(b""), 0);
+});
+
+ktest!(gpt_scan, {
+    let ramdisk = Ramdisk::new(String::from("ktest-gpt"), 64 * 512);
+    build_gpt_disk(&ramdisk);
+    partitions::scan(Arc::new(ramdisk)).map_err(|_| "scan failed")?;
+
+    let partition = partitions::find("ktest-gptp1").ok_or("partition not registered")?;
+    kassert_eq!(partition.label.as_str(), "root");
+    kassert!(partition.type_guid == Guid::LINUX_FILESYSTEM);
+    kassert_eq!((partition.start_lba, partition.end_lba), (34, 61));
+    kassert_eq!(
+        format!("{}", Guid::LINUX_FILESYSTEM).as_str(),
+        "0FC63DAF-8483-4772-8E79-3D69D8477DE4"
+    );
+});
+
+ktest!(gpt_backup_header_fallback, {
+    let ramdisk = Ramdisk::new(String::from("ktest-gpt-backup"), 64 * 512);
+    build_gpt_disk(&ramdisk);
+
+    // corrupt the primary header's revision, so only its checksum is wrong
+    ramdisk.write(512 + 8, 1, [0xffu8].as_ptr()).ok();
+    partitions::scan(Arc::new(ramdisk)).map_err(|_| "scan failed")?;
+
+    let partition = partitions::find("ktest-gpt-backupp1").ok_or("backup gpt was not used")?;
+    kassert_eq!(partition.label.as_str(), "root");
+});
+
+ktest!(dentry_cache_lru, {
+    let cache = DentryCache::new(2);
+    let reads = core::cell::Cell::new(0);
+    let find = |inode: u64| {
+        reads.set(reads.get() + 1);
+        move || Ok(inode)
+    };
+
+    kassert_eq!(cache.lookup(2, "a", find(10)), Ok(10));
+    kassert_eq!(cache.lookup(2, "b", find(11)), Ok(11));
+    kassert_eq!(cache.lookup(2, "a", || Err(KError::EIO)), Ok(10));
+
+    // b was used longest ago
+    kassert_eq!(cache.lookup(3, "a", find(12)), Ok(12));
+    kassert_eq!(cache.len(), 2);
+    kassert_eq!(cache.lookup(2, "b", find(13)), Ok(13));
+    kassert_eq!(reads.get(), 4);
+
+    cache.insert(2, "b", 14);
+    kassert_eq!(cache.lookup(2, "b", || Err(KError::EIO)), Ok(14));
+    cache.remove(2, "b");
+    kassert_eq!(cache.lookup(2, "b", || Err(KError::ENOENT)), Err(KError::ENOENT));
+
+    // a change while the directory was read keeps what was found out of the cache
+    let stale = cache.lookup(4, "c", || {
+        cache.remove(4, "c");
+        Ok(15)
+    });
+    kassert_eq!(stale, Ok(15));
+    kassert_eq!(cache.lookup(4, "c", find(16)), Ok(16));
+});
+
+ktest!(ext2_two_instances, {
+    // a second instance of the root volume, with its own caches and open file table
+    let fs = partitions::partitions()
+        .iter()
+        .filter_map(|partition| probe::probe_partition(partition))
+        .find(|fs| fs.name() == "ext2")
+        .ok_or("no ext2 partition")?;
+    kassert!(vfs::mount(fs, "/ktest-ext2").is_ok());
+    kassert_eq!(vfs::mount(fs, "/ktest-ext2"), Err(KError::EBUSY));
+
+    let root_fd = vfs::open("/home/limine.cfg", vfs::Flags::O_RDONLY, vfs::Mode::empty())
+        .map_err(|_| "could not open /home/limine.cfg")?;
+    let second_fd = vfs::open(
+        "/ktest-ext2/home/limine.cfg",
+        vfs::Flags::O_RDONLY,
+        vfs::Mode::empty(),
+    )
+    .map_err(|_| "could not open /ktest-ext2/home/limine.cfg")?;
+
+    let root_fs = root_fd.fs as *const dyn vfs::Filesystem as *const u8;
+    let second_fs = second_fd.fs as *const dyn vfs::Filesystem as *const u8;
+    kassert!(root_fs != second_fs);
+
+    let mut content = alloc::vec![0u8; LIMINE_CFG.len()];
+    vfs::pread(&second_fd, content.as_mut_ptr(), content.len(), 0)
+        .map_err(|err| format!("read failed: {}", err))?;
+    kassert!(content.as_slice() == LIMINE_CFG);
+});
+
+fn put(image: &mut [u8], offset: usize, bytes: &[u8]) {
+    image[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+/*
+    A 2MiB ext2 volume with 1KiB blocks, one block group and an empty root directory.
+    Only the metadata is written, every free block is left full of garbage
+*/
+fn build_dirty_ext2(ramdisk: &Ramdisk) -> Result<(), String> {
+    const BLOCK: usize = 1024;
+    const BLOCKS: usize = 2048;
+    const INODES: usize = 32;
+    const USED_BLOCKS: usize = 10;
+    const USED_INODES: usize = 10;
+
+    let mut image = alloc::vec![0xa5u8; BLOCKS * BLOCK];
+
+    // superblock
+    let sb = BLOCK;
+    put(&mut image, sb, &[0; BLOCK]);
+    put(&mut image, sb, &(INODES as u32).to_le_bytes());
+    put(&mut image, sb + 4, &(BLOCKS as u32).to_le_bytes());
+    put(&mut image, sb + 12, &((BLOCKS - USED_BLOCKS) as u32).to_le_bytes());
+    put(&mut image, sb + 16, &((INODES - USED_INODES) as u32).to_le_bytes());
+    put(&mut image, sb + 20, &1u32.to_le_bytes());
+    put(&mut image, sb + 32, &8192u32.to_le_bytes());
+    put(&mut image, sb + 36, &8192u32.to_le_bytes());
+    put(&mut image, sb + 40, &(INODES as u32).to_le_bytes());
+    put(&mut image, sb + 56, &0xef53u16.to_le_bytes());
+    put(&mut image, sb + 58, &1u16.to_le_bytes());
+
+    // block group descriptor, bitmaps at blocks 3 and 4, inode table at 5-8
+    let bgd = 2 * BLOCK;
+    put(&mut image, bgd, &[0; BLOCK]);
+    put(&mut image, bgd, &3u32.to_le_bytes());
+    put(&mut image, bgd + 4, &4u32.to_le_bytes());
+    put(&mut image, bgd + 8, &5u32.to_le_bytes());
+    put(&mut image, bgd + 12, &((BLOCKS - USED_BLOCKS) as u16).to_le_bytes());
+    put(&mut image, bgd + 14, &((INODES - USED_INODES) as u16).to_le_bytes());
+    put(&mut image, bgd + 16, &1u16.to_le_bytes());
+
+    // the bits past the end of the volume are set, so they're never handed out
+    let mut block_bitmap = [0xffu8; BLOCK];
+    block_bitmap[1..BLOCKS / 8].fill(0);
+    block_bitmap[1] = (1 << (USED_BLOCKS - 8)) - 1;
+    put(&mut image, 3 * BLOCK, &block_bitmap);
+
+    let mut inode_bitmap = [0xffu8; BLOCK];
+    inode_bitmap[1..INODES / 8].fill(0);
+    inode_bitmap[1] = (1 << (USED_INODES - 8)) - 1;
+    put(&mut image, 4 * BLOCK, &inode_bitmap);
+
+    // mke2fs zeroes the inode table
+    put(&mut image, 5 * BLOCK, &[0; INODES * 128]);
+
+    // root inode, its only block is 9
+    let root = 5 * BLOCK + 128;
+    put(&mut image, root, &0x41edu16.to_le_bytes());
+    put(&mut image, root + 4, &(BLOCK as u32).to_le_bytes());
+    put(&mut image, root + 26, &2u16.to_le_bytes());
+    put(&mut image, root + 28, &2u32.to_le_bytes());
+    put(&mut image, root + 40, &9u32.to_le_bytes());
+
+    let dir = 9 * BLOCK;
+    put(&mut image, dir, &[0; BLOCK]);
+    put(&mut image, dir, &2u32.to_le_bytes());
+    put(&mut image, dir + 4, &12u16.to_le_bytes());
+    put(&mut image, dir + 6, &[1, 2, b'.']);
+    put(&mut image, dir + 12, &2u32.to_le_bytes());
+    put(&mut image, dir + 16, &((BLOCK - 12) as u16).to_le_bytes());
+    put(&mut image, dir + 18, &[2, 2, b'.', b'.']);
+
+    ramdisk
+        .write(0, image.len(), image.as_ptr())
+        .map_err(|err| format!("could not write the image: {}", err))?;
+    Ok(())
+}
+
+/*
+    A volume from build_dirty_ext2 mounted on /ktest-<name>. It's unmounted when this is
+    dropped, so it has to be made before any file is opened on it
+*/
+struct ScratchExt2 {
+    ramdisk: Arc<Ramdisk>,
+    fs: &'static dyn vfs::Filesystem,
+    mount_point: String,
+}
+
+impl ScratchExt2 {
+    // creates path, relative to the mount point, and opens it for reading and writing
+    fn create(&self, path: &str) -> Result<vfs::FileDescription, String> {
+        let path = format!("{}/{}", self.mount_point, path);
+        let flags = vfs::Flags::O_CREAT | vfs::Flags::O_RDWR;
+        vfs::open(&path, flags, vfs::Mode::empty())
+            .map_err(|err| format!("could not create {}: {}", path, err))
+    }
+}
+
+impl Drop for ScratchExt2 {
+    fn drop(&mut self) {
+        // the test may have unmounted it itself
+        vfs::unmount(&self.mount_point).ok();
+    }
+}
+
+fn dirty_ramdisk(name: &str) -> Result<Arc<Ramdisk>, String> {
+    let ramdisk = Arc::new(Ramdisk::new(format!("ktest-ext2-{}", name), 2048 * 1024));
+    build_dirty_ext2(&ramdisk)?;
+    Ok(ramdisk)
+}
+
+fn mount_scratch(ramdisk: Arc<Ramdisk>, name: &str) -> Result<ScratchExt2, String> {
+    let fs = probe::probe(ramdisk.clone(), 0).ok_or("the dirty volume wasn't probed as ext2")?;
+    let mount_point = format!("/ktest-{}", name);
+    vfs::mount(fs, &mount_point).map_err(|err| format!("mount failed: {}", err))?;
+
+    Ok(ScratchExt2 {
+        ramdisk,
+        fs,
+        mount_point,
+    })
+}
+
+fn scratch_ext2(name: &str) -> Result<ScratchExt2, String> {
+    mount_scratch(dirty_ramdisk(name)?, name)
+}
+
+ktest!(ext2_entry_types, {
+    let ramdisk = dirty_ramdisk("types")?;
+
+    // revision 1 with the filetype feature, the root's entries have their types already
+    let mut superblock = [0u8; 1024];
+    ramdisk
+        .read(1024, superblock.len(), superblock.as_mut_ptr())
+        .map_err(|err| format!("could not read the superblock: {}", err))?;
+    put(&mut superblock, 76, &1u32.to_le_bytes());
+    put(&mut superblock, 84, &11u32.to_le_bytes());
+    put(&mut superblock, 88, &128u16.to_le_bytes());
+    put(&mut superblock, 96, &2u32.to_le_bytes());
+    ramdisk
+        .write(1024, superblock.len(), superblock.as_ptr())
+        .map_err(|err| format!("could not write the superblock: {}", err))?;
+
+    let scratch = mount_scratch(ramdisk, "types")?;
+    scratch.create("file")?;
+    vfs::mkdir("/ktest-types/dir", vfs::Mode::empty())
+        .map_err(|err| format!("could not create /ktest-types/dir: {}", err))?;
+
+    let root = vfs::open("/ktest-types/", vfs::Flags::O_RDONLY, vfs::Mode::empty())
+        .map_err(|err| format!("could not open /ktest-types/: {}", err))?;
+    let entries = vfs::read_dir(&root)
+        .collect::<Result<Vec<vfs::DirEntry>, KError>>()
+        .map_err(|err| format!("readdir failed: {}", err))?;
+    let type_of = |name: &str| {
+        entries.iter().find(|entry| entry.name == name).map(|entry| entry.entry_type)
+    };
+
+    kassert_eq!(type_of("."), Some(vfs::DirEntryType::Directory));
+    kassert_eq!(type_of("file"), Some(vfs::DirEntryType::Normal));
+    kassert_eq!(type_of("dir"), Some(vfs::DirEntryType::Directory));
+});
+
+ktest!(ext2_indirect_blocks_zeroed, {
+    let scratch = scratch_ext2("dirty")?;
+
+    let mut fd = scratch.create("big")?;
+
+    // reaches the second singly indirect block hanging off the doubly indirect one
+    let blocks = 12 + 256 + 256 + 1;
+    let data: Vec<u8> = (0..blocks * 1024).map(|i| (i % 251) as u8).collect();
+    let written = vfs::write(&mut fd, data.as_ptr(), data.len());
+    kassert_eq!(written, Ok(data.len()));
+
+    let mut read_back = alloc::vec![0u8; data.len()];
+    vfs::pread(&fd, read_back.as_mut_ptr(), read_back.len(), 0)
+        .map_err(|err| format!("read failed: {}", err))?;
+    kassert!(read_back == data);
+});
+
+ktest!(ext2_block_map_after_growth, {
+    let scratch = scratch_ext2("map")?;
+
+    let mut fd = scratch.create("grown")?;
+
+    let data: Vec<u8> = (0..(12 + 300) * 1024).map(|i| (i % 241) as u8).collect();
+    let half = (12 + 100) * 1024;
+    kassert_eq!(vfs::write(&mut fd, data.as_ptr(), half), Ok(half));
+
+    // reading block by block caches the singly indirect block while it's half filled
+    let mut block = [0u8; 1024];
+    for offset in (0..half).step_by(1024) {
+        kassert_eq!(vfs::pread(&fd, block.as_mut_ptr(), 1024, offset), Ok(1024));
+        kassert!(block[..] == data[offset..offset + 1024]);
+    }
+
+    // growing the file has to drop the stale copy, or the new blocks would read as holes
+    let rest = data.len() - half;
+    kassert_eq!(vfs::write(&mut fd, unsafe { data.as_ptr().add(half) }, rest), Ok(rest));
+
+    let mut read_back = alloc::vec![0u8; data.len()];
+    kassert_eq!(vfs::pread(&fd, read_back.as_mut_ptr(), data.len(), 0), Ok(data.len()));
+    kassert!(read_back == data);
+});
+
+ktest!(ext2_sparse_file, {
+    let scratch = scratch_ext2("sparse")?;
+
+    let fd = scratch.create("file")?;
+    let before = vfs::statfs("/ktest-sparse/").map_err(|err| format!("statfs: {}", err))?;
+
+    // one block at the start and one behind the singly indirect block, nothing in between
+    let data = b"islands";
+    let far = 100 * 1024 + 10;
+    kassert_eq!(vfs::pwrite(&fd, data.as_ptr(), data.len(), 0), Ok(data.len()));
+    kassert_eq!(vfs::pwrite(&fd, data.as_ptr(), data.len(), far), Ok(data.len()));
+
+    let after = vfs::statfs("/ktest-sparse/").map_err(|err| format!("statfs: {}", err))?;
+    kassert_eq!(before.free_blocks - after.free_blocks, 3);
+
+    let mut read_back = alloc::vec![0xffu8; far + data.len()];
+    kassert_eq!(vfs::pread(&fd, read_back.as_mut_ptr(), read_back.len(), 0), Ok(read_back.len()));
+    kassert!(&read_back[..data.len()] == data);
+    kassert!(read_back[data.len()..far].iter().all(|byte| *byte == 0));
+    kassert!(&read_back[far..] == data);
+});
+
+ktest!(ext2_hard_links, {
+    let scratch = scratch_ext2("link")?;
+
+    let mut fd = scratch.create("original")?;
+    let data = b"one inode, two names";
+    kassert_eq!(vfs::write(&mut fd, data.as_ptr(), data.len()), Ok(data.len()));
+
+    kassert_eq!(vfs::link("/ktest-link/original", "/ktest-link/copy"), Ok(()));
+    kassert_eq!(vfs::link("/ktest-link/original", "/ktest-link/copy"), Err(KError::EEXIST));
+    kassert_eq!(vfs::link("/ktest-link/original", "/proc/copy"), Err(KError::EXDEV));
+    kassert_eq!(vfs::unlink("/ktest-link/original"), Ok(()));
+
+    let missing = vfs::open("/ktest-link/original", vfs::Flags::O_RDONLY, vfs::Mode::empty());
+    kassert_eq!(missing.err(), Some(KError::ENOENT));
+
+    let copy = vfs::open("/ktest-link/copy", vfs::Flags::O_RDONLY, vfs::Mode::empty())
+        .map_err(|err| format!("could not open /ktest-link/copy: {}", err))?;
+    let mut read_back = [0u8; 20];
+    kassert_eq!(vfs::pread(&copy, read_back.as_mut_ptr(), data.len(), 0), Ok(data.len()));
+    kassert!(&read_back == data);
+
+    kassert!(vfs::mkdir("/ktest-link/dir", vfs::Mode::empty()).is_ok());
+    kassert_eq!(vfs::link("/ktest-link/dir", "/ktest-link/dir2"), Err(KError::EPERM));
+    kassert_eq!(vfs::unlink("/ktest-link/dir"), Err(KError::EISDIR));
+});
+
+ktest!(ext2_open_files_shared, {
+    let scratch = scratch_ext2("open")?;
+
+    let mut fd = scratch.create("file")?;
+    let data = b"still here";
+    kassert_eq!(vfs::write(&mut fd, data.as_ptr(), data.len()), Ok(data.len()));
+
+    // a clone keeps the open, another open of the same inode shares its slot
+    let dup = fd.clone();
+    kassert_eq!(fd.open_count(), 2);
+    let again = vfs::open("/ktest-open/file", vfs::Flags::O_RDONLY, vfs::Mode::empty())
+        .map_err(|err| format!("could not open /ktest-open/file: {}", err))?;
+    kassert_eq!(again.file_index, fd.file_index);
+    kassert_eq!(again.open_count(), 1);
+
+    // unlinked, but the data lives until the last close
+    kassert_eq!(vfs::unlink("/ktest-open/file"), Ok(()));
+    drop(fd);
+    drop(again);
+    let mut read_back = [0u8; 10];
+    kassert_eq!(vfs::pread(&dup, read_back.as_mut_ptr(), data.len(), 0), Ok(data.len()));
+    kassert!(&read_back == data);
+
+    let index = dup.file_index;
+    drop(dup);
+    let other = scratch.create("other")?;
+    kassert_eq!(other.file_index, index);
+});
+
+ktest!(ext2_statfs_counts, {
+    let scratch = scratch_ext2("statfs")?;
+
+    let before = vfs::statfs("/ktest-statfs/").map_err(|err| format!("statfs: {}", err))?;
+    kassert_eq!(before.block_size, 1024);
+    kassert_eq!(before.blocks, 2048);
+    kassert!(before.free_blocks < before.blocks && before.free_inodes < before.inodes);
+
+    let mut fd = scratch.create("file")?;
+    let data = [0x5au8; 3000];
+    kassert_eq!(vfs::write(&mut fd, data.as_ptr(), data.len()), Ok(data.len()));
+
+    let after = vfs::statfs("/ktest-statfs/file").map_err(|err| format!("statfs: {}", err))?;
+    kassert_eq!(after.free_inodes, before.free_inodes - 1);
+    kassert_eq!(after.free_blocks, before.free_blocks - 3);
+
+    // the blocks come back with the last close
+    kassert_eq!(vfs::unlink("/ktest-statfs/file"), Ok(()));
+    drop(fd);
+    kassert_eq!(vfs::statfs("/ktest-statfs/"), Ok(before));
+    kassert_eq!(vfs::statfs("/proc/").map(|stats| stats.blocks), Ok(0));
+});
+
+ktest!(ext2_group_descriptors_cached, {
+    let scratch = scratch_ext2("groups")?;
+
+    // the free block and inode counts of the only group, as they are on the disk
+    let on_disk = || {
+        let mut counts = [0u8; 4];
+        scratch.ramdisk.read(2048 + 12, counts.len(), counts.as_mut_ptr()).ok();
+        counts
+    };
+    let before = on_disk();
+
+    let mut fd = scratch.create("file")?;
+    let data = [1u8; 2048];
+    kassert_eq!(vfs::write(&mut fd, data.as_ptr(), data.len()), Ok(data.len()));
+    kassert_eq!(on_disk(), before);
+
+    kassert_eq!(scratch.fs.sync(), Ok(()));
+    let after = on_disk();
+    kassert_eq!(u16::from_le_bytes([after[0], after[1]]), 2048 - 10 - 2);
+    kassert_eq!(u16::from_le_bytes([after[2], after[3]]), 32 - 10 - 1);
+});
+
+ktest!(ext2_mount_state, {
+    let scratch = scratch_ext2("state")?;
+    let (ramdisk, fs) = (scratch.ramdisk.clone(), scratch.fs);
+
+    // the mount count and the state, as they are on the disk
+    let on_disk = |ramdisk: &Ramdisk| {
+        let mut field = [0u8; 2];
+        ramdisk.read(1024 + 52, 2, field.as_mut_ptr()).ok();
+        let mount_cnt = u16::from_le_bytes(field);
+        ramdisk.read(1024 + 58, 2, field.as_mut_ptr()).ok();
+        (mount_cnt, u16::from_le_bytes(field))
+    };
+
+    kassert_eq!(on_disk(&ramdisk), (1, 0));
+    kassert_eq!(vfs::mount(fs, "/ktest-state2"), Err(KError::EBUSY));
+
+    let fd = scratch.create("file")?;
+    kassert_eq!(vfs::unmount("/ktest-state"), Err(KError::EBUSY));
+    drop(fd);
+
+    kassert_eq!(vfs::unmount("/ktest-state"), Ok(()));
+    kassert_eq!(on_disk(&ramdisk), (1, 1));
+    kassert_eq!(vfs::unmount("/ktest-state"), Err(KError::EINVAL));
+    kassert!(vfs::open("/ktest-state/file", vfs::Flags::O_RDONLY, vfs::Mode::empty()).is_err());
+
+    // an unlinked file that's still open when the machine goes away
+    vfs::mount(fs, "/ktest-state").map_err(|err| format!("remount failed: {}", err))?;
+    kassert_eq!(on_disk(&ramdisk), (2, 0));
+    let before = vfs::statfs("/ktest-state/").map_err(|err| format!("statfs: {}", err))?;
+    let mut fd = scratch.create("orphan")?;
+    let data = [7u8; 2048];
+    kassert_eq!(vfs::write(&mut fd, data.as_ptr(), data.len()), Ok(data.len()));
+    kassert_eq!(vfs::unlink("/ktest-state/orphan"), Ok(()));
+    kassert_eq!(fs.unmount(true), Ok(()));
+    kassert_eq!(on_disk(&ramdisk), (2, 0));
+
+    // a fresh instance finds it not clean and frees the orphan
+    let second = probe::probe(ramdisk.clone(), 0).ok_or("the volume wasn't probed again")?;
+    vfs::mount(second, "/ktest-state2").map_err(|err| format!("mount failed: {}", err))?;
+    kassert_eq!(vfs::statfs("/ktest-state2/"), Ok(before));
+    kassert_eq!(vfs::unmount("/ktest-state2"), Ok(()));
+    kassert_eq!(on_disk(&ramdisk), (3, 1));
+    core::mem::forget(fd);
+});
+
+ktest!(ext2_symlinks, {
+    let _scratch = scratch_ext2("symlink")?;
+    let before = vfs::statfs("/ktest-symlink/").map_err(|err| format!("statfs: {}", err))?;
+
+    // the short one fits in the block pointers, the long one needs a block
+    let long: String = (0..20).map(|i| format!("dir{}/", i)).collect();
+    kassert_eq!(vfs::symlink("../short/target", "/ktest-symlink/fast"), Ok(()));
+    kassert_eq!(vfs::symlink(&long, "/ktest-symlink/slow"), Ok(()));
+    kassert_eq!(vfs::symlink("x", "/ktest-symlink/fast"), Err(KError::EEXIST));
+    kassert_eq!(vfs::symlink("", "/ktest-symlink/empty"), Err(KError::ENOENT));
+
+    let after = vfs::statfs("/ktest-symlink/").map_err(|err| format!("statfs: {}", err))?;
+    kassert_eq!(before.free_blocks - after.free_blocks, 1);
+    kassert_eq!(before.free_inodes - after.free_inodes, 2);
+
+    kassert_eq!(vfs::readlink("/ktest-symlink/fast").as_deref(), Ok("../short/target"));
+    kassert_eq!(vfs::readlink("/ktest-symlink/slow").as_deref(), Ok(long.as_str()));
+    kassert_eq!(vfs::readlink("/ktest-symlink/").err(), Some(KError::EINVAL));
+
+    // the fast one's pointers aren't blocks to free
+    kassert_eq!(vfs::unlink("/ktest-symlink/fast"), Ok(()));
+    kassert_eq!(vfs::unlink("/ktest-symlink/slow"), Ok(()));
+    kassert_eq!(vfs::statfs("/ktest-symlink/"), Ok(before));
+});
+
+ktest!(vfs_permission_bits, {
+    let attributes = vfs::Attributes {
+        // rw-r-----
+        permissions: vfs::FilePermissions::from_bits_truncate(0o640),
+        uid: 1000,
+        gid: 100,
+    };
+    let rw = vfs::Access::READ | vfs::Access::WRITE;
+
+    kassert!(vfs::permitted(&attributes, 1000, 100, rw));
+    kassert!(vfs::permitted(&attributes, 1001, 100, vfs::Access::READ));
+    kassert!(!vfs::permitted(&attributes, 1001, 100, vfs::Access::WRITE));
+    kassert!(!vfs::permitted(&attributes, 1001, 101, vfs::Access::READ));
+    kassert!(vfs::permitted(&attributes, 0, 0, rw));
+    // root only runs what someone can run
+    kassert!(!vfs::permitted(&attributes, 0, 0, vfs::Access::EXEC));
+});
+
+ktest!(ext2_chmod_chown, {
+    let scratch = scratch_ext2("chmod")?;
+
+    let fd = scratch.create("file")?;
+
+    let setuid = vfs::FilePermissions::from_bits_truncate(0o4750);
+    kassert_eq!(vfs::chmod("/ktest-chmod/file", setuid), Ok(()));
+    kassert_eq!(vfs::chown("/ktest-chmod/file", Some(1000), None), Ok(()));
+    kassert_eq!(vfs::chown("/ktest-chmod/file", Some(0x10000), None), Err(KError::EINVAL));
+
+    // the open description sees the change, and giving the file away dropped setuid
+    let attributes = fd.fs.attributes(fd.file_index).ok_or("ext2 keeps no owners")?;
+    kassert_eq!(attributes.permissions.bits(), 0o750);
+    kassert_eq!((attributes.uid, attributes.gid), (1000, 0));
+});
+
+ktest!(ext2_rename, {
+    let scratch = scratch_ext2("rename")?;
+
+    let flags = vfs::Flags::O_CREAT | vfs::Flags::O_RDWR;
+    for name in ["/ktest-rename/a", "/ktest-rename/b"] {
+        let mut fd = vfs::open(name, flags, vfs::Mode::empty())
+            .map_err(|err| format!("could not create {}: {}", name, err))?;
+        vfs::write(&mut fd, name.as_ptr(), name.len()).map_err(|_| "write failed")?;
+    }
+    kassert!(vfs::mkdir("/ktest-rename/dir", vfs::Mode::empty()).is_ok());
+
+    let exists = |path: &str| vfs::open(path, vfs::Flags::O_RDONLY, vfs::Mode::empty()).is_ok();
+
+    // in place, then into another directory, then over an existing file
+    kassert_eq!(vfs::rename("/ktest-rename/a", "/ktest-rename/c"), Ok(()));
+    kassert_eq!(vfs::rename("/ktest-rename/c", "/ktest-rename/dir/c"), Ok(()));
+    kassert_eq!(vfs::rename("/ktest-rename/dir/c", "/ktest-rename/b"), Ok(()));
+    kassert!(!exists("/ktest-rename/a") && !exists("/ktest-rename/c"));
+    kassert!(!exists("/ktest-rename/dir/c"));
+
+    let fd = vfs::open("/ktest-rename/b", vfs::Flags::O_RDONLY, vfs::Mode::empty())
+        .map_err(|err| format!("could not open /ktest-rename/b: {}", err))?;
+    let mut content = [0u8; 15];
+    kassert_eq!(vfs::pread(&fd, content.as_mut_ptr(), content.len(), 0), Ok(15));
+    kassert!(&content == b"/ktest-rename/a");
+
+    kassert!(vfs::mkdir("/ktest-rename/dir/sub", vfs::Mode::empty()).is_ok());
+    kassert_eq!(
+        vfs::rename("/ktest-rename/dir", "/ktest-rename/dir/sub/dir"),
+        Err(KError::EINVAL)
+    );
+    kassert_eq!(vfs::rename("/ktest-rename/dir/sub", "/ktest-rename/sub"), Ok(()));
+    kassert!(exists("/ktest-rename/sub/.."));
+    kassert_eq!(vfs::rename("/ktest-rename/b", "/proc/b"), Err(KError::EXDEV));
+});
+
+// appends a newc entry, names and data are padded to 4 bytes
+fn cpio_entry(archive: &mut Vec<u8>, name: &str, ino: u32, mode: u32, nlink: u32, data: &[u8]) {
+    let name_size = name.len() as u32 + 1;
+    let fields = [ino, mode, 0, 0, nlink, 0, data.len() as u32, 0, 0, 0, 0, name_size, 0];
+
+    archive.extend_from_slice(b"070701");
+    for field in fields {
+        archive.extend_from_slice(format!("{:08x}", field).as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize((archive.len() + 3) & !3, 0);
+    archive.extend_from_slice(data);
+    archive.resize((archive.len() + 3) & !3, 0);
+}
+
+ktest!(cpio_newc_unpack, {
+    let mut archive = Vec::new();
+    cpio_entry(&mut archive, ".", 1, 0o40755, 2, &[]);
+    cpio_entry(&mut archive, "etc", 2, 0o40700, 2, &[]);
+    // newc only stores the data of hard links with the last one
+    cpio_entry(&mut archive, "etc/issue", 3, 0o100644, 2, b"");
+    cpio_entry(&mut archive, "etc/motd", 3, 0o100644, 2, b"hello from the initramfs\n");
+    cpio_entry(&mut archive, "usr/bin/true", 4, 0o100755, 1, b"\x7fELF");
+    cpio_entry(&mut archive, "bin", 5, 0o120777, 1, b"usr/bin");
+    cpio_entry(&mut archive, "dev/console", 6, 0o20600, 1, &[]);
+    cpio_entry(&mut archive, "TRAILER!!!", 0, 0, 1, &[]);
+
+    let fs: &'static Ramfs = alloc::boxed::Box::leak(alloc::boxed::Box::new(Ramfs::new()));
+    let entries = cpio::unpack(&archive, fs).map_err(|err| format!("unpack failed: {}", err))?;
+    kassert_eq!(entries, 5);
+
+    let open = |path: &str| {
+        vfs::Filesystem::open(fs, path, vfs::Flags::O_RDONLY, vfs::Mode::empty())
+    };
+
+    let motd = open("/etc/motd").map_err(|err| format!("open failed: {}", err))?;
+    let mut content = alloc::vec![0u8; 64];
+    let read = vfs::pread(&motd, content.as_mut_ptr(), content.len(), 0)
+        .map_err(|err| format!("read failed: {}", err))?;
+    kassert!(&content[..read] == b"hello from the initramfs\n");
+
+    // a hard link is the same node
+    let issue = open("/etc/issue").map_err(|err| format!("open failed: {}", err))?;
+    kassert_eq!(issue.file_index, motd.file_index);
+
+    // through a symlink, and the directories usr/bin was missing were made up
+    let truth = open("/bin/true").map_err(|err| format!("open failed: {}", err))?;
+    kassert_eq!(fs.metadata(truth.file_index).map(|m| m.mode).ok(), Some(0o100755));
+    kassert_eq!(fs.readlink("/bin").ok().as_deref(), Some("usr/bin"));
+
+    let etc = open("/etc").map_err(|err| format!("open failed: {}", err))?;
+    kassert_eq!(fs.metadata(etc.file_index).map(|m| m.mode).ok(), Some(0o40700));
+
+    let root = open("/").map_err(|err| format!("open failed: {}", err))?;
+    let names: Vec<(String, u8)> = vfs::read_dir(&root)
+        .map(|entry| entry.map(|entry| (entry.name, entry.entry_type as u8)))
+        .collect::<Result<_, _>>()
+        .map_err(|err| format!("readdir failed: {}", err))?;
+    kassert!(names.contains(&(String::from("bin"), vfs::DirEntryType::Symlink as u8)));
+    kassert!(names.contains(&(String::from("etc"), vfs::DirEntryType::Directory as u8)));
+
+    // the device node was skipped
+    kassert_eq!(open("/dev/console").err(), Some(KError::ENOENT));
+    kassert!(cpio::unpack(&archive[..archive.len() - 8], &Ramfs::new()).is_err());
+});
+
+// a file that's readable once ready is set, like a device with data waiting
+struct PollTestFs {
+    ready: AtomicBool,
+    hangup: AtomicBool,
+    queue: WaitQueue,
+}
+
+impl vfs::Filesystem for PollTestFs {
+    fn name(&self) -> &'static str {
+        "polltest"
+    }
+
+    fn open(&self, _: &str, _: vfs::Flags, _: vfs::Mode) -> KResult<vfs::FileDescription> {
+        Err(KError::ENOSYS)
+    }
+
+    fn mkdir(&self, _: &str, _: vfs::Mode) -> KResult<vfs::FileDescription> {
+        Err(KError::ENOSYS)
+    }
+
+    fn read(&self, _: usize, _: *mut u8, _: usize, _: usize) -> KResult<usize> {
+        Ok(0)
+    }
+
+    fn write(&self, _: usize, _: *const u8, cnt: usize, _: usize) -> KResult<usize> {
+        Ok(cnt)
+    }
+
+    fn readdir(&self, _: usize, _: usize) -> KResult<Option<vfs::DirEntry>> {
+        Err(KError::ENOTDIR)
+    }
+
+    fn poll(&self, _: usize) -> KResult<vfs::PollEvents> {
+        let mut events = vfs::PollEvents::empty();
+        if self.ready.load(Ordering::SeqCst) {
+            events |= vfs::PollEvents::POLLIN;
+        }
+        if self.hangup.load(Ordering::SeqCst) {
+            events |= vfs::PollEvents::POLLHUP;
+        }
+
+        Ok(events)
+    }
+
+    fn wait_queue(&self, _: usize) -> Option<&WaitQueue> {
+        Some(&self.queue)
+    }
+}
+
+ktest!(vfs_poll_readiness, {
+    let fs: &'static PollTestFs = alloc::boxed::Box::leak(alloc::boxed::Box::new(PollTestFs {
+        ready: AtomicBool::new(false),
+        hangup: AtomicBool::new(false),
+        queue: WaitQueue::new(),
+    }));
+    let fd = vfs::FileDescription::new(0, vfs::Flags::O_RDONLY, fs);
+    let mut fds = [vfs::PollFd::new(&fd, vfs::PollEvents::POLLIN)];
+
+    kassert_eq!(vfs::poll(&mut fds, Some(0)).ok(), Some(0));
+
+    // nothing is going to notify, so this has to time out
+    let start = timer_source::current_ns();
+    kassert_eq!(vfs::poll(&mut fds, Some(5)).ok(), Some(0));
+    kassert!(timer_source::current_ns() - start >= 5_000_000);
+
+    // a notify after the generation was read is seen by the waiter
+    let generation = fs.queue.generation();
+    fs.ready.store(true, Ordering::SeqCst);
+    fs.queue.notify();
+    kassert!(fs.queue.wait(generation, Some(timer_source::current_ns())));
+
+    kassert_eq!(vfs::poll(&mut fds, None).ok(), Some(1));
+    kassert!(fds[0].revents == vfs::PollEvents::POLLIN);
+
+    // hangups are reported even when they weren't asked for
+    fs.ready.store(false, Ordering::SeqCst);
+    fs.hangup.store(true, Ordering::SeqCst);
+    let mut fds = [vfs::PollFd::new(&fd, vfs::PollEvents::POLLOUT)];
+    kassert_eq!(vfs::poll(&mut fds, Some(0)).ok(), Some(1));
+    kassert!(fds[0].revents == vfs::PollEvents::POLLHUP);
+});
+
+// a file of zeroes that remembers what it was asked to read ahead
+struct ReadAheadTestFs {
+    requests: spin::Mutex<Vec<(usize, usize)>>,
+}
+
+impl vfs::Filesystem for ReadAheadTestFs {
+    fn name(&self) -> &'static str {
+        "readaheadtest"
+    }
+
+    fn open(&self, _: &str, _: vfs::Flags, _: vfs::Mode) -> KResult<vfs::FileDescription> {
+        Err(KError::ENOSYS)
+    }
+
+    fn mkdir(&self, _: &str, _: vfs::Mode) -> KResult<vfs::FileDescription> {
+        Err(KError::ENOSYS)
+    }
+
+    fn read(&self, _: usize, buffer: *mut u8, cnt: usize, _: usize) -> KResult<usize> {
+        unsafe { buffer.write_bytes(0, cnt) };
+        Ok(cnt)
+    }
+
+    fn write(&self, _: usize, _: *const u8, cnt: usize, _: usize) -> KResult<usize> {
+        Ok(cnt)
+    }
+
+    fn readdir(&self, _: usize, _: usize) -> KResult<Option<vfs::DirEntry>> {
+        Err(KError::ENOTDIR)
+    }
+
+    fn readahead(&self, _: usize, offset: usize, cnt: usize) {
+        self.requests.lock().push((offset, cnt));
+    }
+}
+
+ktest!(vfs_sequential_readahead, {
+    let fs: &'static ReadAheadTestFs = alloc::boxed::Box::leak(alloc::boxed::Box::new(
+        ReadAheadTestFs {
+            requests: spin::Mutex::new(Vec::new()),
+        },
+    ));
+    let mut fd = vfs::FileDescription::new(0, vfs::Flags::O_RDONLY, fs);
+    let mut page = alloc::vec![0u8; 4096];
+
+    for _ in 0..6 {
+        vfs::read(&mut fd, page.as_mut_ptr(), page.len()).map_err(|err| format!("{}", err))?;
+    }
+
+    // a new window is only asked for once the last one was read, and it keeps growing
+    kassert!(fs.requests.lock()[..] == [(4096, 16 * 1024), (20480, 32 * 1024)][..]);
+
+    // seeking somewhere else starts over
+    fs.requests.lock().clear();
+    vfs::pread(&fd, page.as_mut_ptr(), page.len(), 1 << 20).map_err(|err| format!("{}", err))?;
+    kassert!(fs.requests.lock().is_empty());
+});
+
+ktest!(modfs_lists_boot_modules, {
+    let open = |path: &str, flags| {
+        vfs::Filesystem::open(&modfs::Modfs, path, flags, vfs::Mode::empty())
+    };
+
+    let dir = open("/", vfs::Flags::O_RDONLY).map_err(|err| format!("open failed: {}", err))?;
+    let entries: Vec<vfs::DirEntry> = vfs::read_dir(&dir)
+        .collect::<Result<_, _>>()
+        .map_err(|err| format!("readdir failed: {}", err))?;
+    kassert_eq!(entries.len(), boot::modules().len());
+
+    for (entry, module) in entries.iter().zip(boot::modules()) {
+        let path = format!("/{}", entry.name);
+        let fd = open(&path, vfs::Flags::O_RDONLY)
+            .map_err(|err| format!("open {} failed: {}", path, err))?;
+
+        let mut content = alloc::vec![0u8; module.size];
+        kassert_eq!(vfs::pread(&fd, content.as_mut_ptr(), content.len(), 0), Ok(module.size));
+        kassert!(content.as_slice() == module.data());
+    }
+
+    kassert_eq!(open("/", vfs::Flags::O_RDWR).err(), Some(KError::EROFS));
+});
+
+ktest!(shm_objects, {
+    let open = |name, flags| shmfs::open(name, flags, vfs::Mode::empty());
+    let create = vfs::Flags::O_RDWR | vfs::Flags::O_CREAT;
+    let fd = open("/ktest-shm", create).map_err(|_| "shm open failed")?;
+    kassert_eq!(open("ktest-missing", vfs::Flags::O_RDWR).err(), Some(KError::ENOENT));
+
+    vfs::truncate(&fd, 3 * 4096).map_err(|_| "truncate failed")?;
+    let data = *b"shared";
+    kassert_eq!(vfs::pwrite(&fd, data.as_ptr(), data.len(), 4096 + 10), Ok(data.len()));
+
+    // the same frame every time, it's what the mappings of every process get
+    let page = |offset| fd.fs.page(fd.file_index, offset).ok().flatten().ok_or("no frame");
+    let frame = page(4096)?;
+    kassert_eq!(frame.as_u64(), page(4096 + 100)?.as_u64());
+
+    let mapped = unsafe { frame.higher_half().as_ptr::<u8>().add(10) };
+    kassert_eq!(unsafe { core::slice::from_raw_parts(mapped, 6) }, &data);
+    kassert!(fd.fs.page(fd.file_index, 3 * 4096).is_err());
+
+    // each page() took a reference for a mapping, and the object still holds its own
+    kassert_eq!(frame::refcount(frame), 3);
+    frame::put(frame);
+    frame::put(frame);
+
+    // it's visible through the vfs too, and goes away from there once unlinked
+    let other = vfs::open("/dev/shm/ktest-shm", vfs::Flags::O_RDONLY, vfs::Mode::empty())
+        .map_err(|_| "open through the vfs failed")?;
+    let mut read_back = [0u8; 6];
+    kassert_eq!(vfs::pread(&other, read_back.as_mut_ptr(), 6, 4096 + 10), Ok(6));
+    kassert_eq!(&read_back, &data);
+
+    shmfs::unlink("ktest-shm").map_err(|_| "unlink failed")?;
+    kassert!(vfs::open("/dev/shm/ktest-shm", vfs::Flags::O_RDONLY, vfs::Mode::empty()).is_err());
+});
+
+ktest!(uring_ring_consume, {
+    kassert_eq!(uring::Ring::new(0).err(), Some(KError::EINVAL));
+    kassert_eq!(uring::Ring::new(uring::MAX_ENTRIES + 1).err(), Some(KError::EINVAL));
+
+    let ring = uring::Ring::new(3).map_err(|err| format!("ring failed: {}", err))?;
+    let params = ring.params(0);
+    kassert_eq!((params.sq_entries, params.cq_entries), (4, 8));
+    kassert_eq!(ring.size() % 4096, 0);
+
+    // what userspace would do through its mapping
+    let region = ring.region();
+    let word = |offset: u32| unsafe { (region.add(offset as usize) as *mut u32).read_volatile() };
+    let set = |offset: u32, value: u32| unsafe {
+        (region.add(offset as usize) as *mut u32).write_volatile(value)
+    };
+    let sqes = unsafe { region.add(params.sq_off.sqes as usize) as *mut uring::Sqe };
+    for i in 0..4 {
+        let sqe = uring::Sqe {
+            user_data: 100 + i as u64,
+            ..uring::Sqe::default()
+        };
+        unsafe { sqes.add(i).write(sqe) };
+    }
+
+    // an index past the entries is dropped, the rest are done in the ring's order
+    for (slot, index) in [2u32, 0, 7, 1].iter().enumerate() {
+        set(params.sq_off.array + 4 * slot as u32, *index);
+    }
+    set(params.sq_off.tail, 4);
+
+    let mut seen = Vec::new();
+    let done = ring.consume(|sqe| {
+        seen.push(sqe.user_data);
+        -(sqe.user_data as i32)
+    });
+    kassert_eq!(done, 3);
+    kassert!(seen == [102, 100, 101]);
+    kassert_eq!(word(params.sq_off.head), 4);
+    kassert_eq!(word(params.sq_off.dropped), 1);
+    kassert_eq!((word(params.cq_off.tail), ring.ready()), (3, 3));
+
+    let cqes = unsafe { region.add(params.cq_off.cqes as usize) as *const uring::Cqe };
+    let second = unsafe { cqes.add(1).read() };
+    kassert_eq!((second.user_data, second.res), (100, -100));
+
+    // with nobody taking completions the ring fills up, the rest is counted as overflow
+    for tail in [8, 12] {
+        for slot in 0..4 {
+            set(params.sq_off.array + 4 * slot, slot);
+        }
+        set(params.sq_off.tail, tail);
+        ring.consume(|_| 0);
+    }
+    kassert_eq!(ring.ready(), 8);
+    kassert_eq!(word(params.cq_off.overflow), 3);
+
+    set(params.cq_off.head, 8);
+    kassert_eq!(ring.ready(), 0);
+    kassert!(ring.wait_completions(0));
+});
+
+ktest!(uring_closed_by_last_description, {
+    let (description, ring) = uring::create(2).map_err(|err| format!("create failed: {}", err))?;
+
+    // a dup of it keeps the ring going after the original is closed
+    let dup = description.clone();
+    drop(description);
+    kassert!(!ring.is_closed());
+    kassert!(uring::get(&dup).is_some());
+
+    drop(dup);
+    kassert!(ring.is_closed());
+});
+
+ktest!(procfs_generates_files, {
+    let open = |path: &str, flags| {
+        vfs::Filesystem::open(&procfs::Procfs, path, flags, vfs::Mode::empty())
+    };
+
+    let dir = open("/", vfs::Flags::O_RDONLY).map_err(|err| format!("open failed: {}", err))?;
+    let names: Vec<String> = vfs::read_dir(&dir)
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.name)
+        .collect();
+    kassert!(names.iter().any(|name| name == "meminfo"));
+    kassert!(names.iter().any(|name| name == "uptime"));
+
+    let fd = open("/meminfo", vfs::Flags::O_RDONLY)
+        .map_err(|err| format!("open /meminfo failed: {}", err))?;
+    let mut content = [0u8; 256];
+    let read = vfs::pread(&fd, content.as_mut_ptr(), content.len(), 0)
+        .map_err(|err| format!("read failed: {}", err))?;
+    kassert!(content[..read].starts_with(b"MemTotal:"));
+
+    // reading from the middle gives the rest of the same content
+    let rest = vfs::pread(&fd, content.as_mut_ptr(), content.len(), 3)
+        .map_err(|err| format!("read failed: {}", err))?;
+    kassert_eq!(rest, read - 3);
+    kassert!(content[..rest].starts_with(b"Total:"));
+
+    kassert_eq!(open("/meminfo", vfs::Flags::O_WRONLY).err(), Some(KError::EROFS));
+    kassert_eq!(open("/123456/status", vfs::Flags::O_RDONLY).err(), Some(KError::ENOENT));
+    kassert_eq!(open("/uptime/x", vfs::Flags::O_RDONLY).err(), Some(KError::ENOTDIR));
+});
+
+ktest!(devfs_device_files, {
+    use crate::drivers::input::{self, InputEvent, InputQueue};
+    use alloc::boxed::Box;
+
+    let events: &'static InputQueue = alloc::boxed::Box::leak(Box::new(InputQueue::new()));
+    devfs::register("ktest/events0", events).map_err(|err| format!("register failed: {}", err))?;
+
+    kassert_eq!(devfs::register("ktest/events0", events).err(), Some(KError::EEXIST));
+    kassert_eq!(devfs::register("ktest/events0/x", events).err(), Some(KError::ENOTDIR));
+
+    // the directory on the way was made up
+    let dir = vfs::open("/dev/ktest", vfs::Flags::O_RDONLY, vfs::Mode::empty())
+        .map_err(|err| format!("open /dev/ktest failed: {}", err))?;
+    let names: Vec<String> = vfs::read_dir(&dir)
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.name)
+        .collect();
+    kassert!(names == [String::from("events0")]);
+
+    let fd = vfs::open("/dev/ktest/events0", vfs::Flags::O_RDONLY, vfs::Mode::empty())
+        .map_err(|err| format!("open failed: {}", err))?;
+    events.push(&[InputEvent::new(input::EV_REL, input::REL_X, 3)]);
+
+    let mut buffer = [0u8; 64];
+    let size = core::mem::size_of::<InputEvent>();
+    kassert_eq!(vfs::pread(&fd, buffer.as_mut_ptr(), buffer.len(), 0), Ok(size));
+    let event = unsafe { (buffer.as_ptr() as *const InputEvent).read_unaligned() };
+    kassert_eq!((event.kind, event.code, event.value), (input::EV_REL, input::REL_X, 3));
+});
@@ -0,0 +1,359 @@
+use super::{kassert, kassert_eq, ktest};
+use crate::arch::mm::pmm;
+use crate::error::KError;
+use crate::mm::dma::{DmaBuffer, DmaConstraints};
+use crate::mm::frame::{self, FrameFlags};
+use crate::mm::{aslr, swap, vmalloc};
+use crate::mm::vmm::{self, VirtAddr};
+use alloc::{boxed::Box, vec::Vec};
+
+ktest!(pmm_alloc_free, {
+    let free_before = pmm::get().free_pages();
+
+    let page = pmm::get().alloc(4).map_err(|_| "could not allocate 4 pages")?;
+    kassert_eq!(page.as_u64() % pmm::PAGE_SIZE, 0);
+    kassert_eq!(pmm::get().free_pages(), free_before - 4);
+
+    pmm::get().free(page.higher_half().as_mut_ptr(), 4);
+    kassert_eq!(pmm::get().free_pages(), free_before);
+});
+
+ktest!(pmm_calloc_zeroes, {
+    let page = pmm::get().calloc(1).map_err(|_| "could not allocate a page")?;
+    let bytes: *const u8 = page.higher_half().as_ptr();
+
+    let zeroed = (0..pmm::PAGE_SIZE as usize).all(|i| unsafe { *bytes.add(i) } == 0);
+    pmm::get().free(page.higher_half().as_mut_ptr(), 1);
+
+    kassert!(zeroed);
+});
+
+ktest!(pmm_alloc_aligned, {
+    let pages = (vmm::HUGE_PAGE_SIZE / pmm::PAGE_SIZE) as usize;
+    let block = pmm::get()
+        .alloc_aligned(pages, pages)
+        .map_err(|_| "could not allocate a 2MiB block")?;
+
+    pmm::get().free(block.higher_half().as_mut_ptr(), pages);
+    kassert_eq!(block.as_u64() % vmm::HUGE_PAGE_SIZE, 0);
+});
+
+ktest!(slab_distinct_objects, {
+    let boxes: Vec<Box<[u8; 64]>> = (0..32).map(|i| Box::new([i as u8; 64])).collect();
+
+    for (i, object) in boxes.iter().enumerate() {
+        kassert!(object.iter().all(|byte| *byte == i as u8));
+    }
+
+    for (i, a) in boxes.iter().enumerate() {
+        for b in boxes.iter().skip(i + 1) {
+            kassert!(a.as_ptr() != b.as_ptr());
+        }
+    }
+});
+
+ktest!(slab_growing_vec, {
+    let mut numbers = Vec::new();
+    for i in 0..500u64 {
+        numbers.push(i);
+    }
+
+    kassert_eq!(numbers.iter().sum::<u64>(), 499 * 500 / 2);
+});
+
+ktest!(vmm_translate_direct_map, {
+    let page = pmm::get().alloc(1).map_err(|_| "could not allocate a page")?;
+    let translated = vmm::get().translate(VirtAddr::new(page.higher_half().as_u64()));
+    pmm::get().free(page.higher_half().as_mut_ptr(), 1);
+
+    kassert_eq!(translated.map(|addr| addr.as_u64()), Some(page.as_u64()));
+});
+
+ktest!(vmm_cache_modes, {
+    use crate::arch::cpu::{self, MsrList};
+    use crate::arch::cpuid::{self, Features};
+    use vmm::{CacheMode, PageFlags};
+
+    let flags = PageFlags::PRESENT | PageFlags::WRITABLE;
+    for mode in [
+        CacheMode::WriteBack,
+        CacheMode::WriteThrough,
+        CacheMode::Uncacheable,
+        CacheMode::WriteCombining,
+    ] {
+        let cached = flags.with_cache(mode);
+        kassert_eq!(cached.cache_mode(), mode);
+        kassert!(cached.contains(flags));
+    }
+
+    // the old mode's bits don't stick around
+    let uncached = flags.with_cache(CacheMode::WriteCombining).with_cache(CacheMode::Uncacheable);
+    kassert_eq!(uncached, flags | PageFlags::UNCACHEABLE);
+
+    if cpuid::has(Features::PAT) {
+        kassert_eq!(cpu::rdmsr(MsrList::Pat), vmm::PAT);
+    }
+});
+
+ktest!(dma_buffer_constraints, {
+    let virt = {
+        let constraints = DmaConstraints::BELOW_4G.aligned(0x10000);
+        let mut buffer = DmaBuffer::new(3 * pmm::PAGE_SIZE as usize + 1, constraints)
+            .map_err(|_| "could not allocate a dma buffer")?;
+
+        kassert!(buffer.phys() + 4 * pmm::PAGE_SIZE <= 1 << 32);
+        kassert_eq!(buffer.phys() % 0x10000, 0);
+        kassert_eq!(buffer.as_ptr::<u8>() as u64, buffer.phys() | pmm::PHYS_BASE);
+        kassert!(buffer.as_slice().iter().all(|byte| *byte == 0));
+
+        let mapping = vmm::get().get_mapping(VirtAddr::new(buffer.as_ptr::<u8>() as u64));
+        kassert!(mapping.is_uncacheable());
+
+        buffer.as_mut_slice().fill(0xaa);
+        kassert!(buffer.as_slice().iter().all(|byte| *byte == 0xaa));
+        buffer.as_ptr::<u8>() as u64
+    };
+
+    // the pages go back to the direct map as normal memory
+    kassert!(!vmm::get().get_mapping(VirtAddr::new(virt)).is_uncacheable());
+    kassert!(DmaBuffer::new(0, DmaConstraints::ANY).is_err());
+});
+
+ktest!(vmm_mmap_randomized_layout, {
+    let mut first = vmm::VirtualMemManager::new(true);
+    let mut second = vmm::VirtualMemManager::new(true);
+
+    let anon = vmm::MapFlags::PRIVATE | vmm::MapFlags::ANONYMOUS;
+    let rw = vmm::MapProt::READ | vmm::MapProt::WRITE;
+    let a = first.mmap(None, 3 * 4096, rw, anon, None, 0).map_err(|_| "first mmap failed")?;
+    let b = first.mmap(None, 4096, rw, anon, None, 0).map_err(|_| "second mmap failed")?;
+
+    // handed out top down, without overlapping
+    kassert!(b.as_u64() + 4096 <= a.as_u64());
+    kassert!(a.as_u64() + 3 * 4096 <= aslr::USER_TOP);
+
+    let stack = first.map_stack(64 * 1024).map_err(|_| "map_stack failed")?;
+    kassert!(stack.as_u64() % 4096 == 0 && stack.as_u64() <= aslr::USER_TOP);
+    kassert!(first.get_range(VirtAddr::new(stack.as_u64() - 8)).is_some());
+
+    if aslr::enabled() {
+        kassert!(first.load_base().as_u64() != second.load_base().as_u64());
+    }
+
+    first.destroy();
+    second.destroy();
+});
+
+ktest!(vmm_map_limit, {
+    let mut vmm = vmm::VirtualMemManager::new(true);
+    vmm.set_map_limit(4 * 4096);
+
+    let anon = vmm::MapFlags::PRIVATE | vmm::MapFlags::ANONYMOUS;
+    let rw = vmm::MapProt::READ | vmm::MapProt::WRITE;
+    let base = vmm.mmap(None, 3 * 4096, rw, anon, None, 0).map_err(|_| "mmap failed")?;
+    kassert_eq!(vmm.mapped_bytes(), 3 * 4096);
+    kassert_eq!(vmm.mmap(None, 2 * 4096, rw, anon, None, 0).err(), Some(KError::ENOMEM));
+
+    // unmapping makes room again
+    vmm.munmap(base, 4096);
+    kassert!(vmm.mmap(None, 2 * 4096, rw, anon, None, 0).is_ok());
+
+    vmm.destroy();
+});
+
+ktest!(frame_copy_on_write, {
+    let parent = vmm::VirtualMemManager::new(true);
+    let mut child = vmm::VirtualMemManager::new(true);
+    let mut parent = parent;
+
+    let anon = vmm::MapFlags::PRIVATE | vmm::MapFlags::ANONYMOUS;
+    let rw = vmm::MapProt::READ | vmm::MapProt::WRITE;
+    let base = parent.mmap(None, 4096, rw, anon, None, 0).map_err(|_| "mmap failed")?;
+    child
+        .mmap(Some(base), 4096, rw, anon | vmm::MapFlags::FIXED, None, 0)
+        .map_err(|_| "child mmap failed")?;
+
+    // faulted in by hand, the way the page fault handler does it
+    let page = pmm::get().calloc(1).map_err(|_| "could not allocate a page")?;
+    frame::track(page, FrameFlags::PRIVATE);
+    let flags = vmm::PageFlags::PRESENT | vmm::PageFlags::WRITABLE | vmm::PageFlags::USERMODE;
+    parent.map_page(base, page, flags, true);
+    unsafe { page.higher_half().as_mut_ptr::<u8>().write(0x42) };
+
+    kassert_eq!(parent.share_copy_on_write(base, &child), Ok(()));
+    kassert_eq!(frame::refcount(page), 2);
+    kassert!(!parent.get_mapping(base).is_writable());
+
+    // the first one to write gets a copy
+    kassert_eq!(parent.resolve_copy_on_write(base, || pmm::get().calloc(1).ok()), Ok(true));
+    let copy = parent.translate(base).ok_or("the copy isn't mapped")?;
+    kassert!(copy.as_u64() != page.as_u64());
+    kassert_eq!(unsafe { *copy.higher_half().as_ptr::<u8>() }, 0x42);
+    kassert_eq!(frame::refcount(page), 1);
+
+    // and the last one keeps the frame
+    kassert_eq!(child.resolve_copy_on_write(base, || None), Ok(true));
+    kassert_eq!(child.translate(base).map(|frame| frame.as_u64()), Some(page.as_u64()));
+    kassert!(child.get_mapping(base).is_writable());
+    kassert_eq!(child.resolve_copy_on_write(base, || None), Ok(false));
+
+    parent.destroy();
+    child.destroy();
+});
+
+ktest!(frame_copy_on_write_huge, {
+    let mut parent = vmm::VirtualMemManager::new(true);
+    let mut child = vmm::VirtualMemManager::new(true);
+
+    // twice the size, so a whole aligned block is in it wherever it lands
+    let huge = vmm::HUGE_PAGE_SIZE;
+    let anon = vmm::MapFlags::PRIVATE | vmm::MapFlags::ANONYMOUS;
+    let rw = vmm::MapProt::READ | vmm::MapProt::WRITE;
+    let base = parent
+        .mmap(None, 2 * huge, rw, anon | vmm::MapFlags::HUGE, None, 0)
+        .map_err(|_| "mmap failed")?;
+    child
+        .mmap(Some(base), 2 * huge, rw, anon | vmm::MapFlags::FIXED, None, 0)
+        .map_err(|_| "child mmap failed")?;
+
+    let block = VirtAddr::new((base.as_u64() + huge - 1) & !(huge - 1));
+    kassert_eq!(parent.populate(block, || None), Ok(true));
+    let frame = parent.translate(block).ok_or("the huge page isn't mapped")?;
+    kassert_eq!(frame::refcount(frame), 1);
+
+    // only the page that's shared is, the rest of the block stays the parent's
+    let second = VirtAddr::new(block.as_u64() + 4096);
+    kassert_eq!(parent.share_copy_on_write(second, &child), Ok(()));
+    kassert_eq!(frame::refcount(pmm::PhysAddr::new(frame.as_u64() + 4096)), 2);
+    kassert_eq!(parent.translate(block).map(|frame| frame.as_u64()), Some(frame.as_u64()));
+    kassert!(parent.get_mapping(block).is_writable());
+
+    parent.destroy();
+    child.destroy();
+});
+
+ktest!(vmm_resident_pages, {
+    let mut vmm = vmm::VirtualMemManager::new(true);
+
+    let anon = vmm::MapFlags::PRIVATE | vmm::MapFlags::ANONYMOUS;
+    let rw = vmm::MapProt::READ | vmm::MapProt::WRITE;
+    let base = vmm.mmap(None, 4 * 4096, rw, anon, None, 0).map_err(|_| "mmap failed")?;
+
+    // nothing is backed until it's faulted in, which is what the oom killer goes by
+    kassert_eq!(vmm.resident_pages(), 0);
+
+    let page = pmm::get().calloc(1).map_err(|_| "could not allocate a page")?;
+    let flags = vmm::PageFlags::PRESENT | vmm::PageFlags::WRITABLE | vmm::PageFlags::USERMODE;
+    vmm.map_page(VirtAddr::new(base.as_u64() + 4096), page, flags, true);
+    kassert_eq!(vmm.resident_pages(), 1);
+
+    vmm.destroy();
+});
+
+ktest!(vmm_evict_pages, {
+    let mut vmm = vmm::VirtualMemManager::new(true);
+
+    let anon = vmm::MapFlags::PRIVATE | vmm::MapFlags::ANONYMOUS;
+    let rw = vmm::MapProt::READ | vmm::MapProt::WRITE;
+    let base = vmm.mmap(None, 3 * 4096, rw, anon, None, 0).map_err(|_| "mmap failed")?;
+    let page = |i: u64| VirtAddr::new(base.as_u64() + i * 4096);
+
+    let flags = vmm::PageFlags::PRESENT | vmm::PageFlags::WRITABLE | vmm::PageFlags::USERMODE;
+    let extra = [vmm::PageFlags::empty(), vmm::PageFlags::DIRTY, vmm::PageFlags::ACCESSED];
+    for (i, extra) in extra.iter().enumerate() {
+        let frame = pmm::get().calloc(1).map_err(|_| "could not allocate a page")?;
+        vmm.map_page(page(i as u64), frame, flags | *extra, true);
+    }
+
+    let mut pages = Vec::new();
+    vmm.for_each_page(|address| pages.push(address.as_u64()));
+    kassert_eq!(pages.len(), 3);
+
+    // the clean page is still all zeroes, so it goes without needing swap
+    kassert!(vmm.evict(page(0), false));
+    let clean = vmm.get_mapping(page(0));
+    kassert!(!clean.is_present() && clean.is_mmaped() && !clean.is_swapped());
+
+    // dirty pages need the disk, and there has to be swap to put them in
+    kassert!(!vmm.evict(page(1), false));
+    kassert_eq!(vmm.evict(page(1), true), swap::enabled());
+    kassert_eq!(vmm.get_mapping(page(1)).is_swapped(), swap::enabled());
+
+    // a page that was used gets a second chance
+    kassert_eq!(vmm.test_and_clear_accessed(page(2)), Some(true));
+    kassert_eq!(vmm.test_and_clear_accessed(page(2)), Some(false));
+    kassert_eq!(vmm.test_and_clear_accessed(page(0)), None);
+    kassert!(vmm.evict(page(2), false));
+
+    let entry = vmm::PageMapping::swapped(1234);
+    kassert!(entry.is_swapped() && entry.is_mmaped());
+    kassert_eq!(entry.swap_slot(), 1234);
+
+    vmm.destroy();
+});
+
+ktest!(vmm_madvise, {
+    let mut vmm = vmm::VirtualMemManager::new(true);
+
+    let anon = vmm::MapFlags::PRIVATE | vmm::MapFlags::ANONYMOUS;
+    let rw = vmm::MapProt::READ | vmm::MapProt::WRITE;
+    let base = vmm.mmap(None, 4 * 4096, rw, anon, None, 0).map_err(|_| "mmap failed")?;
+    let page = |i: u64| VirtAddr::new(base.as_u64() + i * 4096);
+    let resident = |vmm: &vmm::VirtualMemManager, i| vmm.get_mapping(page(i)).is_present();
+
+    // everything is faulted in, zeroed
+    kassert_eq!(vmm.madvise(base, 4 * 4096, vmm::Advice::WillNeed), Ok(()));
+    kassert_eq!(vmm.resident_pages(), 4);
+    let frame = vmm.translate(page(1)).ok_or("page 1 isn't mapped")?;
+    kassert_eq!(unsafe { *frame.higher_half().as_ptr::<u8>() }, 0);
+
+    // dropped pages come back as zeroes on the next fault
+    unsafe { frame.higher_half().as_mut_ptr::<u8>().write(0x42) };
+    kassert_eq!(vmm.madvise(page(1), 2 * 4096, vmm::Advice::DontNeed), Ok(()));
+    kassert!(resident(&vmm, 0) && !resident(&vmm, 1) && !resident(&vmm, 2));
+    kassert!(vmm.get_mapping(page(1)).is_mmaped());
+    kassert_eq!(vmm.populate(page(1), || pmm::get().calloc(1).ok()), Ok(true));
+    let frame = vmm.translate(page(1)).ok_or("page 1 wasn't faulted in")?;
+    kassert_eq!(unsafe { *frame.higher_half().as_ptr::<u8>() }, 0);
+
+    // freed pages look clean, so evict can drop them without swap
+    let dirty = vmm::PageFlags::PRESENT | vmm::PageFlags::WRITABLE | vmm::PageFlags::DIRTY;
+    vmm.map_page(page(3), vmm.translate(page(3)).ok_or("page 3 isn't mapped")?, dirty, true);
+    kassert!(!vmm.evict(page(3), false));
+    kassert_eq!(vmm.madvise(page(3), 4096, vmm::Advice::Free), Ok(()));
+    kassert!(vmm.evict(page(3), false));
+
+    // unaligned or partly unmapped ranges are refused
+    let unaligned = VirtAddr::new(base.as_u64() + 1);
+    kassert_eq!(vmm.madvise(unaligned, 4096, vmm::Advice::DontNeed), Err(KError::EINVAL));
+    kassert_eq!(vmm.madvise(page(3), 2 * 4096, vmm::Advice::DontNeed), Err(KError::ENOMEM));
+    kassert_eq!(vmm::Advice::from_u64(4), Some(vmm::Advice::DontNeed));
+
+    vmm.destroy();
+});
+
+ktest!(vmalloc_areas, {
+    let used = vmalloc::used_pages();
+    let len = 3 * 4096 + 1;
+    let buffer = vmalloc::vmalloc(len).map_err(|_| "vmalloc failed")?;
+
+    kassert!(vmalloc::contains(buffer));
+    kassert_eq!(vmalloc::size(buffer), Some(4 * 4096));
+    kassert_eq!(vmalloc::used_pages(), used + 4);
+
+    // zeroed and usable all the way through, then the guard page
+    let bytes = unsafe { core::slice::from_raw_parts_mut(buffer, len) };
+    kassert!(bytes.iter().all(|byte| *byte == 0));
+    bytes.fill(0x5a);
+    let guard = VirtAddr::new(buffer as u64 + 4 * 4096);
+    kassert!(vmm::get().translate(guard).is_none());
+
+    vmalloc::vfree(buffer);
+    kassert!(vmm::get().translate(VirtAddr::new(buffer as u64)).is_none());
+    kassert_eq!(vmalloc::used_pages(), used);
+
+    // what's too big for the slab comes from here
+    let big: Vec<u8> = alloc::vec![7; 64 * 1024];
+    kassert!(vmalloc::contains(big.as_ptr()));
+    kassert_eq!(big[64 * 1024 - 1], 7);
+});
@@ -0,0 +1,138 @@
+/*
+    In-kernel tests, only built with the ktest feature. Every test registered with
+    ktest! ends up in the .ktests section, and they all run once the kernel has booted.
+    The results are reported over serial and qemu is told to exit through the
+    isa-debug-exit device, so a script can check the exit code
+*/
+
+use crate::arch::io;
+use crate::serial;
+use alloc::string::String;
+
+mod arch;
+mod debug;
+mod drivers;
+mod fs;
+mod mm;
+#[cfg(feature = "net")]
+mod net;
+mod proc;
+
+// qemu exits with (code << 1) | 1
+const ISA_DEBUG_EXIT_PORT: u16 = 0xf4;
+
+#[allow(non_upper_case_globals)]
+extern "C" {
+    static ktests_start: KTest;
+    static ktests_end: KTest;
+}
+
+#[repr(u32)]
+pub enum ExitCode {
+    Success = 0x10,
+    Failure = 0x11,
+}
+
+pub struct KTest {
+    pub name: &'static str,
+    pub func: fn() -> Result<(), String>,
+}
+
+macro_rules! ktest {
+    ($name:ident, $body:block) => {
+        #[used]
+        #[allow(non_upper_case_globals)]
+        #[link_section = ".ktests"]
+        static $name: crate::ktest::KTest = crate::ktest::KTest {
+            name: concat!(module_path!(), "::", stringify!($name)),
+            func: {
+                fn test() -> Result<(), alloc::string::String> {
+                    $body
+                    Ok(())
+                }
+                test
+            },
+        };
+    };
+}
+
+macro_rules! kassert {
+    ($cond:expr) => {
+        if !$cond {
+            return Err(alloc::format!(
+                "assertion failed: {} at {}:{}",
+                stringify!($cond),
+                file!(),
+                line!()
+            ));
+        }
+    };
+}
+
+macro_rules! kassert_eq {
+    ($left:expr, $right:expr) => {
+        match (&$left, &$right) {
+            (left, right) => {
+                if left != right {
+                    return Err(alloc::format!(
+                        "{:?} != {:?} ({} == {}) at {}:{}",
+                        left,
+                        right,
+                        stringify!($left),
+                        stringify!($right),
+                        file!(),
+                        line!()
+                    ));
+                }
+            }
+        }
+    };
+}
+
+pub(crate) use {kassert, kassert_eq, ktest};
+
+// only returns if we aren't running under qemu, or the device is missing
+pub fn exit_qemu(code: ExitCode) {
+    unsafe {
+        io::outl(ISA_DEBUG_EXIT_PORT, code as u32);
+    }
+}
+
+fn tests() -> &'static [KTest] {
+    unsafe {
+        let start = &ktests_start as *const KTest;
+        let end = &ktests_end as *const KTest;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+pub fn run() {
+    let tests = tests();
+    let mut failed = 0;
+
+    serial::print!("[KTEST] running {} tests\n", tests.len());
+
+    for test in tests {
+        serial::print!("[KTEST] {} ... ", test.name);
+
+        match (test.func)() {
+            Ok(()) => serial::print!("ok\n"),
+            Err(message) => {
+                serial::print!("FAILED\n[KTEST]     {}\n", message);
+                failed += 1;
+            }
+        }
+    }
+
+    serial::print!(
+        "[KTEST] {} passed, {} failed\n",
+        tests.len() - failed,
+        failed
+    );
+
+    if failed == 0 {
+        exit_qemu(ExitCode::Success);
+    } else {
+        exit_qemu(ExitCode::Failure);
+    }
+}
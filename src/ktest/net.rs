@@ -0,0 +1,247 @@
+use super::{kassert, kassert_eq, ktest};
+use crate::error::KError;
+use crate::fs::vfs::{self, PollEvents, PollFd};
+use crate::net::socket::{self, MsgFlags, SocketType};
+use crate::net::{self, dns, http, icmp, ipv4, Ipv4Addr, SocketAddr};
+use alloc::{format, string::String, vec::Vec};
+
+ktest!(ipv4_header_checksum, {
+    // the header from the wikipedia article, with its checksum
+    let header = [
+        0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0xb8, 0x61, 0xc0, 0xa8, 0x00,
+        0x01, 0xc0, 0xa8, 0x00, 0xc7,
+    ];
+    kassert_eq!(net::checksum(&header), 0);
+
+    let mut zeroed = header;
+    zeroed[10] = 0;
+    zeroed[11] = 0;
+    kassert_eq!(net::checksum(&zeroed), 0xb861);
+
+    // the total length says there's a payload, which isn't there
+    kassert!(ipv4::parse(&header).is_none());
+
+    kassert_eq!(Ipv4Addr::parse("10.0.2.15"), Some(Ipv4Addr([10, 0, 2, 15])));
+    kassert_eq!(Ipv4Addr::parse("10.0.2"), None);
+    kassert_eq!(Ipv4Addr::parse("10.0.2.256"), None);
+    kassert_eq!(format!("{}", SocketAddr::new(Ipv4Addr::LOCALHOST, 53)), "127.0.0.1:53");
+});
+
+ktest!(udp_loopback_sockets, {
+    let new = || socket::socket(SocketType::Datagram).map_err(|err| format!("socket: {}", err));
+    let server = new()?;
+    let client = new()?;
+
+    let any = SocketAddr::new(Ipv4Addr::LOCALHOST, 0);
+    socket::bind(&server, any).map_err(|err| format!("bind: {}", err))?;
+    let address = socket::local_addr(&server).map_err(|_| "no local address")?;
+    kassert!(address.port >= 49152);
+    kassert_eq!(socket::bind(&client, address).err(), Some(KError::EADDRINUSE));
+    kassert_eq!(
+        socket::bind(&client, SocketAddr::new(Ipv4Addr([10, 9, 8, 7]), 0)).err(),
+        Some(KError::EADDRNOTAVAIL)
+    );
+    kassert_eq!(socket::send(&client, b"x").err(), Some(KError::EDESTADDRREQ));
+    kassert_eq!(socket::listen(&server, 1).err(), Some(KError::EOPNOTSUPP));
+
+    let mut buffer = [0u8; 16];
+    let dontwait = MsgFlags::DONTWAIT;
+    kassert_eq!(socket::recv(&server, &mut buffer, dontwait).err(), Some(KError::EAGAIN));
+
+    // the loopback has delivered it by the time send returns, poll sees it right away
+    kassert_eq!(socket::send_to(&client, b"ping", address), Ok(4));
+    let mut fds = [PollFd::new(&server, PollEvents::POLLIN)];
+    kassert_eq!(vfs::poll(&mut fds, Some(0)), Ok(1));
+    kassert_eq!(fds[0].revents, PollEvents::POLLIN);
+
+    let (len, from) = socket::recv_from(&server, &mut buffer, dontwait)
+        .map_err(|err| format!("recv_from: {}", err))?;
+    kassert_eq!(&buffer[..len], b"ping");
+    kassert_eq!(from, socket::local_addr(&client).map_err(|_| "no client address")?);
+
+    // through the vfs, answered to whoever it came from, and cut short to what fits
+    socket::connect(&server, from).map_err(|err| format!("connect: {}", err))?;
+    let reply = b"pong pong pong";
+    kassert_eq!(vfs::pwrite(&server, reply.as_ptr(), reply.len(), 0), Ok(reply.len()));
+    let mut short = [0u8; 4];
+    kassert_eq!(vfs::pread(&client, short.as_mut_ptr(), short.len(), 0), Ok(4));
+    kassert_eq!(&short, b"pong");
+    kassert_eq!(socket::recv(&client, &mut buffer, dontwait).err(), Some(KError::EAGAIN));
+
+    // a connected socket only takes what comes from its peer
+    let stranger = new()?;
+    kassert_eq!(socket::send_to(&stranger, b"hi", address), Ok(2));
+    kassert_eq!(socket::recv(&server, &mut buffer, dontwait).err(), Some(KError::EAGAIN));
+
+    // the port can be bound again once the socket is closed
+    drop(server);
+    let again = new()?;
+    kassert_eq!(socket::bind(&again, address), Ok(()));
+});
+
+ktest!(tcp_loopback_connection, {
+    let new = || socket::socket(SocketType::Stream).map_err(|err| format!("socket: {}", err));
+    let listener = new()?;
+    let client = new()?;
+    let lonely = new()?;
+
+    let any = SocketAddr::new(Ipv4Addr::LOCALHOST, 0);
+    socket::bind(&listener, any).map_err(|err| format!("bind: {}", err))?;
+    socket::listen(&listener, 1).map_err(|err| format!("listen: {}", err))?;
+    let address = socket::local_addr(&listener).map_err(|_| "no local address")?;
+
+    // bound but not listening, the syn is answered with a reset and client can try again
+    socket::bind(&lonely, any).map_err(|err| format!("bind: {}", err))?;
+    let nobody = socket::local_addr(&lonely).map_err(|_| "no local address")?;
+    kassert_eq!(socket::connect(&client, nobody).err(), Some(KError::ECONNREFUSED));
+
+    // the handshake is over the loopback, done before connect returns
+    socket::connect(&client, address).map_err(|err| format!("connect: {}", err))?;
+    kassert_eq!(socket::connect(&client, address).err(), Some(KError::EISCONN));
+    let mut fds = [PollFd::new(&listener, PollEvents::POLLIN)];
+    kassert_eq!(vfs::poll(&mut fds, Some(0)), Ok(1));
+
+    let (server, peer) = socket::accept(&listener).map_err(|err| format!("accept: {}", err))?;
+    kassert_eq!(Ok(peer), socket::local_addr(&client));
+    kassert_eq!(socket::peer_addr(&client), Ok(address));
+
+    let mut buffer = [0u8; 16];
+    let dontwait = MsgFlags::DONTWAIT;
+    kassert_eq!(socket::recv(&server, &mut buffer, dontwait).err(), Some(KError::EAGAIN));
+
+    kassert_eq!(socket::send(&client, b"hello"), Ok(5));
+    kassert_eq!(socket::recv(&server, &mut buffer, dontwait), Ok(5));
+    kassert_eq!(&buffer[..5], b"hello");
+
+    // a stream has no message boundaries, two writes can come out in one read
+    kassert_eq!(vfs::pwrite(&server, b"wor".as_ptr(), 3, 0), Ok(3));
+    kassert_eq!(vfs::pwrite(&server, b"ld".as_ptr(), 2, 0), Ok(2));
+    kassert_eq!(vfs::pread(&client, buffer.as_mut_ptr(), buffer.len(), 0), Ok(5));
+    kassert_eq!(&buffer[..5], b"world");
+
+    // the client's fin, the server reads the end of the stream but can still send
+    drop(client);
+    kassert_eq!(socket::recv(&server, &mut buffer, dontwait), Ok(0));
+    let mut fds = [PollFd::new(&server, PollEvents::POLLIN)];
+    kassert_eq!(vfs::poll(&mut fds, Some(0)), Ok(1));
+    kassert_eq!(socket::send(&server, b"bye"), Ok(3));
+});
+
+ktest!(dns_messages, {
+    let query = dns::encode_query(0x1234, "www.example.com");
+    kassert_eq!(&query[..4], &[0x12, 0x34, 0x01, 0x00]);
+    kassert_eq!(&query[12..29], b"\x03www\x07example\x03com\x00");
+    kassert_eq!(&query[29..], &[0, 1, 0, 1]);
+
+    // the question again, a cname to example.com and its A record, with compressed names
+    let mut response = alloc::vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 0];
+    response.extend_from_slice(&query[12..]);
+    response.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 16]);
+    response.extend_from_slice(&[0xc0, 16, 0, 1, 0, 1, 0, 0, 0x0e, 0x10, 0, 4, 93, 184, 216, 34]);
+    let answer = Ok((Ipv4Addr([93, 184, 216, 34]), 3600));
+    kassert_eq!(dns::parse_response(0x1234, &response), answer);
+    kassert_eq!(dns::parse_response(0x4321, &response), Err(KError::EAGAIN));
+    kassert_eq!(dns::parse_response(0x1234, &response[..40]), Err(KError::EIO));
+
+    response[3] = 0x83;
+    kassert_eq!(dns::parse_response(0x1234, &response), Err(KError::ENOENT));
+
+    // what doesn't need a server
+    let server = dns::server();
+    dns::set_server(None);
+    kassert_eq!(net::resolve("10.0.2.2"), Ok(Ipv4Addr([10, 0, 2, 2])));
+    kassert_eq!(net::resolve("LocalHost."), Ok(Ipv4Addr::LOCALHOST));
+    kassert_eq!(net::resolve("example.com"), Err(KError::EDESTADDRREQ));
+    kassert_eq!(net::resolve("bad..name"), Err(KError::EINVAL));
+    dns::set_server(server);
+});
+
+ktest!(icmp_echo_loopback, {
+    let data = [0x5a; 32];
+    let echo = icmp::echo(Ipv4Addr::LOCALHOST, 7, 1, &data, 0).map_err(|err| format!("{}", err))?;
+    kassert_eq!(echo.from, Ipv4Addr::LOCALHOST);
+    kassert_eq!(echo.len, data.len());
+    kassert_eq!(echo.ttl, 64);
+
+    let nowhere = icmp::echo(Ipv4Addr([10, 9, 8, 7]), 7, 2, &data, 0);
+    kassert_eq!(nowhere.err(), Some(KError::ENETUNREACH));
+
+    #[cfg(feature = "shell")]
+    {
+        let ping = crate::shell::execute("ping localhost 1");
+        kassert!(ping.contains("1 sent, 1 received, 0% lost"));
+        let ifconfig = crate::shell::execute("ifconfig");
+        kassert!(ifconfig.contains("lo: inet 127.0.0.1 netmask 255.0.0.0 mtu 65535"));
+        kassert_eq!(crate::shell::execute("arp"), "the arp cache is empty\n");
+    }
+});
+
+ktest!(http_get_over_loopback, {
+    kassert_eq!(http::Url::parse("https://example.com/").err(), Some(KError::EINVAL));
+    kassert_eq!(http::Url::parse("http://:80/").err(), Some(KError::EINVAL));
+    let url = http::Url::parse("http://localhost:8080/bin/init?v=1").map_err(|_| "bad url")?;
+    kassert_eq!(url.host, "localhost");
+    kassert_eq!(url.port, 8080);
+    kassert_eq!(url.file_name(), Some("init"));
+    kassert_eq!(http::Url::parse("http://localhost").map(|url| url.path), Ok(String::from("/")));
+
+    let new = || socket::socket(SocketType::Stream).map_err(|err| format!("socket: {}", err));
+    let listener = new()?;
+    socket::bind(&listener, SocketAddr::new(Ipv4Addr::LOCALHOST, 0)).map_err(|_| "bind")?;
+    socket::listen(&listener, 2).map_err(|_| "listen")?;
+    let address = socket::local_addr(&listener).map_err(|_| "no local address")?;
+
+    // the server answers before it's asked and closes, it all waits in the client's buffer
+    let respond = |response: &[u8]| -> Result<vfs::FileDescription, String> {
+        let client = new()?;
+        socket::connect(&client, address).map_err(|err| format!("connect: {}", err))?;
+        let (server, _) = socket::accept(&listener).map_err(|err| format!("accept: {}", err))?;
+        kassert_eq!(socket::send(&server, response), Ok(response.len()));
+        Ok(client)
+    };
+
+    let ok = b"HTTP/1.0 200 OK\r\nContent-Length: 11\r\n\r\nhello world";
+    let client = respond(ok)?;
+    let mut body = Vec::new();
+    let len = http::request(&client, &url, &mut |data| {
+        body.extend_from_slice(data);
+        Ok(())
+    });
+    kassert_eq!(len, Ok(11));
+    kassert_eq!(&body[..], b"hello world");
+
+    let client = respond(b"HTTP/1.0 404 Not Found\r\n\r\n")?;
+    kassert_eq!(http::request(&client, &url, &mut |_| Ok(())), Err(KError::ENOENT));
+
+    let short = b"HTTP/1.1 200 OK\r\ncontent-length: 99\r\n\r\ncut";
+    let client = respond(short)?;
+    kassert_eq!(http::request(&client, &url, &mut |_| Ok(())), Err(KError::EIO));
+
+    // a failed fetch leaves what's at the path alone, and no temporary file behind
+    let path = "/tmp/ktest-fetch";
+    let flags = vfs::Flags::O_CREAT | vfs::Flags::O_RDWR;
+    let file = vfs::open(path, flags, vfs::Mode::empty()).map_err(|_| "no /tmp")?;
+    kassert_eq!(vfs::pwrite(&file, b"old".as_ptr(), 3, 0), Ok(3));
+    drop(file);
+
+    let entries = || vfs::open("/tmp", vfs::Flags::O_RDONLY, vfs::Mode::empty()).map(|dir| {
+        vfs::read_dir(&dir).count()
+    });
+    let before = entries();
+    kassert_eq!(http::fetch("http://10.9.8.7/init", path), Err(KError::ENETUNREACH));
+    kassert_eq!(entries(), before);
+
+    let file = vfs::open(path, vfs::Flags::O_RDONLY, vfs::Mode::empty()).map_err(|_| "gone")?;
+    let mut content = [0u8; 8];
+    kassert_eq!(vfs::pread(&file, content.as_mut_ptr(), content.len(), 0), Ok(3));
+    kassert_eq!(&content[..3], b"old");
+    drop(file);
+
+    // what fetch does once it's all in
+    let file = vfs::open("/tmp/.ktest-fetch.part", flags, vfs::Mode::empty());
+    kassert!(file.is_ok());
+    kassert!(vfs::rename("/tmp/.ktest-fetch.part", path).is_ok());
+    kassert_eq!(vfs::unlink("/tmp/.ktest-fetch.part"), Err(KError::ENOENT));
+    kassert!(vfs::unlink(path).is_ok());
+    kassert_eq!(entries(), before.map(|count| count - 1));
+});
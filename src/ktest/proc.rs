@@ -0,0 +1,248 @@
+use super::{kassert, kassert_eq, ktest};
+use crate::arch::cpu;
+use crate::error::KError;
+use crate::fs::vfs;
+use crate::proc::process::{self, CloneFlags, Limits, Process, SelectorValues, Thread};
+use crate::proc::scheduler::{self, Scheduler, SchedulerQueues, PRIORITY_LEVELS};
+use crate::proc::session::{self, Signal, Tty};
+use crate::rcu::Rcu;
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// a process for a test's threads, the pid and tid bitmaps are set up for the first one
+fn ktest_process() -> Rc<RefCell<Process>> {
+    static BITMAPS: AtomicBool = AtomicBool::new(false);
+
+    if !BITMAPS.swap(true, Ordering::SeqCst) {
+        unsafe { process::init_bitmaps() };
+    }
+    Process::new(String::from("ktest"), 0, String::from("/"))
+}
+
+ktest!(scheduler_priorities, {
+    let process = ktest_process();
+    let low = Thread::new(0, SelectorValues::KernelCs, process.clone());
+    let high = Thread::new(0, SelectorValues::KernelCs, process.clone());
+
+    kassert!(low.borrow_mut().set_priority(PRIORITY_LEVELS).is_err());
+    low.borrow_mut().set_priority(PRIORITY_LEVELS - 1).map_err(|_| "set_priority failed")?;
+    high.borrow_mut().set_priority(0).map_err(|_| "set_priority failed")?;
+
+    let mut queues = SchedulerQueues::new();
+    queues.push_runnable(low.clone());
+    queues.push_runnable(high.clone());
+
+    let first = queues.pop_runnable(0).ok_or("no runnable thread")?;
+    kassert!(Rc::ptr_eq(&first, &high));
+
+    // a demoted thread goes back to its base priority when boosted
+    high.borrow_mut().level = PRIORITY_LEVELS - 1;
+    queues.push_runnable(high.clone());
+    queues.boost();
+    kassert_eq!(high.borrow().level, 0);
+
+    let first = queues.pop_runnable(0).ok_or("no runnable thread")?;
+    kassert!(Rc::ptr_eq(&first, &high));
+    let second = queues.pop_runnable(0).ok_or("no runnable thread")?;
+    kassert!(Rc::ptr_eq(&second, &low));
+    kassert!(queues.pop_runnable(0).is_none());
+});
+
+ktest!(scheduler_affinity, {
+    let process = ktest_process();
+    let pinned = Thread::new(0, SelectorValues::KernelCs, process.clone());
+    let free = Thread::new(0, SelectorValues::KernelCs, process.clone());
+
+    kassert!(pinned.borrow_mut().set_affinity(0).is_err());
+    // set directly, set_affinity would refuse cpu 1 on a single cpu machine
+    pinned.borrow_mut().affinity = 1 << 1;
+    pinned.borrow_mut().set_priority(0).map_err(|_| "set_priority failed")?;
+
+    let mut queues = SchedulerQueues::new();
+    queues.push_runnable(pinned.clone());
+    queues.push_runnable(free.clone());
+
+    // the pinned thread has the higher priority, but it can't run on cpu 0
+    let first = queues.pop_runnable(0).ok_or("no runnable thread")?;
+    kassert!(Rc::ptr_eq(&first, &free));
+    kassert!(queues.pop_runnable(0).is_none());
+    kassert!(Rc::ptr_eq(&queues.pop_runnable(1).ok_or("no runnable thread")?, &pinned));
+});
+
+ktest!(scheduler_time_slices, {
+    let process = ktest_process();
+    let idle = Thread::new(0, SelectorValues::KernelCs, process.clone());
+    let busy = Thread::new(0, SelectorValues::KernelCs, process.clone());
+    let other = Thread::new(0, SelectorValues::KernelCs, process.clone());
+
+    // a scheduler of its own, for a cpu 0 that only runs what the test hands it
+    let mut scheduler = Scheduler::new();
+    scheduler.idle_threads.push(idle.clone());
+    scheduler.running_threads.push(Some(idle.clone()));
+    scheduler.enqueue(busy.clone());
+
+    let (previous, next) = scheduler.reschedule(0, 0, true).ok_or("the idle thread kept the cpu")?;
+    kassert!(Rc::ptr_eq(&previous, &idle) && Rc::ptr_eq(&next, &busy));
+
+    // alone it keeps the cpu, and drops a level once its whole timeslice is used up
+    let slice = scheduler::time_slice(scheduler::DEFAULT_PRIORITY);
+    for _ in 0..slice {
+        kassert!(scheduler.reschedule(0, 0, true).is_none());
+    }
+    kassert_eq!(busy.borrow().level, scheduler::DEFAULT_PRIORITY + 1);
+
+    // a thread of a higher priority doesn't wait for the rest of it
+    scheduler.enqueue(other.clone());
+    let (previous, next) = scheduler.reschedule(0, 0, true).ok_or("busy wasn't preempted")?;
+    kassert!(Rc::ptr_eq(&previous, &busy) && Rc::ptr_eq(&next, &other));
+
+    for _ in 0..slice - 1 {
+        kassert!(scheduler.reschedule(0, 0, true).is_none());
+    }
+    let (_, next) = scheduler.reschedule(0, 0, true).ok_or("other kept the cpu")?;
+    kassert!(Rc::ptr_eq(&next, &busy));
+    kassert_eq!(other.borrow().level, scheduler::DEFAULT_PRIORITY + 1);
+
+    // the boost brings other back up, so it gets the cpu back from busy
+    scheduler.ticks = scheduler::BOOST_INTERVAL - 1;
+    let (_, next) = scheduler.reschedule(0, 0, true).ok_or("nothing was boosted")?;
+    kassert!(Rc::ptr_eq(&next, &other));
+    kassert_eq!(other.borrow().level, scheduler::DEFAULT_PRIORITY);
+
+    // giving the cpu up costs no level
+    let (previous, next) = scheduler.reschedule(0, 0, false).ok_or("other didn't yield")?;
+    kassert!(Rc::ptr_eq(&previous, &other) && Rc::ptr_eq(&next, &busy));
+    kassert_eq!(other.borrow().level, scheduler::DEFAULT_PRIORITY);
+});
+
+ktest!(thread_kernel_stacks, {
+    let process = ktest_process();
+    let first = Thread::new(0, SelectorValues::KernelCs, process.clone());
+    let second = Thread::new(0, SelectorValues::KernelCs, process.clone());
+
+    let (first, second) = (first.borrow(), second.borrow());
+    kassert!(first.kernel_stack != 0);
+    kassert!(first.kernel_stack != second.kernel_stack);
+    // kernel threads run on their kernel stack
+    kassert_eq!(first.regs.rsp, first.kernel_stack);
+
+    // a syscall enters on whatever the last switch set
+    let previous = cpu::local().kernel_stack;
+    cpu::set_kernel_stack(second.kernel_stack);
+    kassert_eq!(cpu::local().kernel_stack, second.kernel_stack);
+    cpu::set_kernel_stack(previous);
+});
+
+ktest!(process_limits, {
+    let process = ktest_process();
+    let mut process = process.borrow_mut();
+
+    let too_many = Limits {
+        open_files: process::MAX_FDS_PER_PROCESS + 1,
+        ..Limits::DEFAULT
+    };
+    kassert_eq!(process.set_limits(too_many), Err(KError::EINVAL));
+
+    let limits = Limits {
+        open_files: 2,
+        ..Limits::DEFAULT
+    };
+    kassert_eq!(process.set_limits(limits), Ok(()));
+    kassert_eq!(process.limits(), limits);
+
+    let open = || vfs::open("/proc/uptime", vfs::Flags::O_RDONLY, vfs::Mode::empty());
+    kassert_eq!(process.alloc_fd(open().map_err(|_| "open failed")?), Ok(0));
+    kassert_eq!(process.alloc_fd(open().map_err(|_| "open failed")?), Ok(1));
+    kassert_eq!(process.alloc_fd(open().map_err(|_| "open failed")?), Err(KError::EMFILE));
+
+    // a closed descriptor is handed out again
+    process.file_desc_list[0] = None;
+    kassert_eq!(process.alloc_fd(open().map_err(|_| "open failed")?), Ok(0));
+    process.exit();
+});
+
+ktest!(sessions_and_groups, {
+    static TTY: Tty = Tty::new("ktest");
+
+    let leader = ktest_process();
+    let other = ktest_process();
+    let (mut leader, mut other) = (leader.borrow_mut(), other.borrow_mut());
+
+    // alone in its group, so it can start a new session
+    kassert_eq!(session::setsid(&mut leader), Ok(leader.pid));
+    kassert_eq!(session::setpgid(&mut leader, other.pid), Err(KError::EPERM));
+    // groups can't be joined across sessions
+    kassert_eq!(session::setpgid(&mut other, leader.pid), Err(KError::EPERM));
+
+    kassert_eq!(session::tcgetpgrp(&leader), Err(KError::ENOTTY));
+    session::set_ctty(&mut leader, &TTY).map_err(|_| "set_ctty failed")?;
+    kassert_eq!(TTY.session(), Some(leader.sid));
+    kassert_eq!(session::tcgetpgrp(&leader), Ok(leader.pgid));
+    // a tty controls one session
+    kassert_eq!(session::set_ctty(&mut other, &TTY), Err(KError::EPERM));
+    kassert_eq!(session::tcsetpgrp(&leader, other.pgid), Err(KError::EPERM));
+
+    kassert_eq!(TTY.input(b'a'), None);
+    kassert_eq!(TTY.input(0x03), Some(Signal::SIGINT));
+
+    // the terminal is released with the session leader
+    leader.exit();
+    kassert_eq!(TTY.session(), None);
+    other.exit();
+    kassert_eq!(session::group_session(other.pid), None);
+});
+
+ktest!(tty_winsize, {
+    static TTY: Tty = Tty::new("ktest-winsize");
+
+    kassert_eq!(TTY.winsize(), (0, 0));
+    TTY.resize(25, 80);
+    kassert_eq!(TTY.winsize(), (25, 80));
+});
+
+ktest!(user_threads, {
+    let process = ktest_process();
+
+    let thread = Thread::new_user(0x400000, Some(0x7fff0000), process.clone())
+        .map_err(|_| "new_user failed")?;
+    let thread = thread.borrow();
+    kassert_eq!(thread.regs.rip, 0x400000);
+    kassert_eq!(thread.regs.rsp, 0x7fff0000);
+    kassert_eq!(thread.regs.cs, SelectorValues::UserCs as u64);
+    kassert!(thread.kernel_stack != 0);
+
+    // no address space to map a stack in
+    kassert!(Thread::new_user(0x400000, None, process.clone()).is_err());
+
+    // a thread always shares everything with its process
+    let result = Process::thread_create(&process, 0x400000, None, 0, 0, CloneFlags::CLONE_VM);
+    kassert_eq!(result, Err(KError::EINVAL));
+});
+
+ktest!(rcu_updates, {
+    static VALUES: Rcu<Vec<u32>> = Rcu::new(Vec::new());
+
+    kassert!(VALUES.read().is_empty());
+
+    let pushed: Result<(), ()> = VALUES.update(|values| {
+        values.push(1);
+        Ok(())
+    });
+    kassert!(pushed.is_ok());
+    kassert_eq!(VALUES.read().as_slice(), &[1]);
+
+    // a failed update leaves the current value alone
+    let failed = VALUES.update(|values| {
+        values.push(2);
+        Err(())
+    });
+    kassert!(failed.is_err());
+    kassert_eq!(VALUES.read().as_slice(), &[1]);
+
+    // publish replaces the whole value, the old copy is freed once no one reads it
+    VALUES.publish(alloc::vec![3, 4]);
+    kassert_eq!(VALUES.read().as_slice(), &[3, 4]);
+});
@@ -0,0 +1,449 @@
+/*
+    Every message goes to each sink whose threshold allows its level, so e.g. the screen
+    can show only warnings and errors while the serial port gets everything.
+
+    The kmsg sink keeps the messages as records in a ring, each with a sequence number
+    that counts up from boot. Once the ring is full the oldest records are dropped, a gap
+    in the sequence numbers is how a reader knows it missed some. /dev/kmsg reads as what's
+    in the ring, one record per line, and dmesg in the shell prints the same. The ring
+    starts out in a static buffer, "log.kmsg_size=<KiB>" moves it to a bigger one from
+    vmalloc once that's up
+*/
+
+use crate::drivers::timer_source;
+use crate::error::{KError, KResult};
+use crate::fs::devfs;
+use crate::mm::vmalloc;
+use crate::{cmdline, serial};
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, Ordering};
+
+const KMSG_SIZE: usize = 0x4000;
+const TEXT_MAX: usize = 512; // longer messages are cut
+const RECORD_HEADER: usize = 11; // the text's length, the level and the time
+const LINE_MAX: usize = TEXT_MAX + 64;
+
+static THRESHOLDS: [AtomicU8; 3] = [
+    AtomicU8::new(Level::Debug as u8), // serial
+    AtomicU8::new(Level::Warn as u8),  // screen
+    AtomicU8::new(Level::Info as u8),  // kmsg
+];
+
+static KMSG: spin::Mutex<Kmsg> = spin::Mutex::new(Kmsg::new());
+
+pub static KMSG_DEVICE: KmsgDevice = KmsgDevice;
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+impl Level {
+    pub fn from_str(name: &str) -> Option<Level> {
+        match name {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+
+    // syslog's priorities, what /dev/kmsg has in front of every record
+    pub fn priority(self) -> u8 {
+        match self {
+            Level::Error => 3,
+            Level::Warn => 4,
+            Level::Info => 6,
+            Level::Debug => 7,
+        }
+    }
+
+    fn from_u8(level: u8) -> Level {
+        match level {
+            0 => Level::Error,
+            1 => Level::Warn,
+            2 => Level::Info,
+            _ => Level::Debug,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Sink {
+    Serial = 0,
+    Screen = 1,
+    Kmsg = 2,
+}
+
+impl Sink {
+    pub fn from_str(name: &str) -> Option<Sink> {
+        match name {
+            "serial" => Some(Sink::Serial),
+            "screen" => Some(Sink::Screen),
+            "kmsg" => Some(Sink::Kmsg),
+            _ => None,
+        }
+    }
+}
+
+pub struct Record<'a> {
+    pub seq: u64,
+    pub level: Level,
+    pub time_ns: u64, // 0 if it came before the timer source
+    pub text: &'a str,
+}
+
+impl Record<'_> {
+    // "[    1.234567] text", like dmesg
+    pub fn write_dmesg(&self, w: &mut impl Write) -> fmt::Result {
+        let us = self.time_ns / 1000;
+        writeln!(w, "[{:5}.{:06}] {}", us / 1_000_000, us % 1_000_000, self.text)
+    }
+
+    // "6,42,1234567,-;text", linux's /dev/kmsg format: priority, sequence number, time in us
+    pub fn write_kmsg(&self, w: &mut impl Write) -> fmt::Result {
+        let priority = self.level.priority();
+        writeln!(w, "{},{},{},-;{}", priority, self.seq, self.time_ns / 1000, self.text)
+    }
+}
+
+// a message while it's being formatted, cut at TEXT_MAX on a character boundary
+struct Text {
+    buffer: [u8; TEXT_MAX],
+    len: usize,
+}
+
+impl Write for Text {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut cnt = s.len().min(TEXT_MAX - self.len);
+        while !s.is_char_boundary(cnt) {
+            cnt -= 1;
+        }
+
+        self.buffer[self.len..self.len + cnt].copy_from_slice(&s.as_bytes()[..cnt]);
+        self.len += cnt;
+        Ok(())
+    }
+}
+
+/*
+    The kernel's message buffer. A record is its header (the text's length as a u16, the
+    level and the time in ns as a u64) and then its text, and can wrap around the end
+*/
+pub struct Kmsg {
+    buffer: [u8; KMSG_SIZE],
+    grown: Option<&'static mut [u8]>, // replaces buffer once it's there
+    head: usize, // where the oldest record starts
+    len: usize,
+    first_seq: u64, // the oldest record's
+    next_seq: u64,
+}
+
+impl Kmsg {
+    pub const fn new() -> Self {
+        Kmsg {
+            buffer: [0; KMSG_SIZE],
+            grown: None,
+            head: 0,
+            len: 0,
+            first_seq: 0,
+            next_seq: 0,
+        }
+    }
+
+    fn ring(&self) -> &[u8] {
+        self.grown.as_deref().unwrap_or(&self.buffer)
+    }
+
+    fn ring_mut(&mut self) -> &mut [u8] {
+        match &mut self.grown {
+            Some(grown) => grown,
+            None => &mut self.buffer,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.ring().len()
+    }
+
+    fn read_bytes(&self, offset: usize, buf: &mut [u8]) {
+        let ring = self.ring();
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = ring[(offset + i) % ring.len()];
+        }
+    }
+
+    fn write_bytes(&mut self, offset: usize, bytes: &[u8]) {
+        let ring = self.ring_mut();
+        let size = ring.len();
+        for (i, byte) in bytes.iter().enumerate() {
+            ring[(offset + i) % size] = *byte;
+        }
+    }
+
+    // moves the records to the start of a bigger buffer, which is kept for good
+    pub fn grow(&mut self, buffer: &'static mut [u8]) {
+        if buffer.len() <= self.size() {
+            return;
+        }
+
+        self.read_bytes(self.head, &mut buffer[..self.len]);
+        self.head = 0;
+        self.grown = Some(buffer);
+    }
+
+    fn text_len(&self, offset: usize) -> usize {
+        let mut len = [0; 2];
+        self.read_bytes(offset, &mut len);
+        u16::from_le_bytes(len) as usize
+    }
+
+    fn drop_oldest(&mut self) {
+        let size = RECORD_HEADER + self.text_len(self.head);
+        self.head = (self.head + size) % self.size();
+        self.len -= size;
+        self.first_seq += 1;
+    }
+
+    pub fn push(&mut self, level: Level, time_ns: u64, text: &[u8]) {
+        let size = RECORD_HEADER + text.len();
+        while self.size() - self.len < size {
+            self.drop_oldest();
+        }
+
+        let mut header = [0; RECORD_HEADER];
+        header[..2].copy_from_slice(&(text.len() as u16).to_le_bytes());
+        header[2] = level as u8;
+        header[3..].copy_from_slice(&time_ns.to_le_bytes());
+
+        let tail = (self.head + self.len) % self.size();
+        self.write_bytes(tail, &header);
+        self.write_bytes(tail + RECORD_HEADER, text);
+        self.len += size;
+        self.next_seq += 1;
+    }
+
+    // how many records were dropped since boot, the sequence number of the oldest one
+    pub fn first_seq(&self) -> u64 {
+        self.first_seq
+    }
+
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    // oldest first, until f returns false
+    pub fn for_each(&self, mut f: impl FnMut(&Record) -> bool) {
+        let mut offset = self.head;
+        let mut header = [0; RECORD_HEADER];
+        let mut text = [0; TEXT_MAX];
+
+        for seq in self.first_seq..self.next_seq {
+            self.read_bytes(offset, &mut header);
+            let len = u16::from_le_bytes([header[0], header[1]]) as usize;
+            self.read_bytes(offset + RECORD_HEADER, &mut text[..len]);
+
+            let record = Record {
+                seq,
+                level: Level::from_u8(header[2]),
+                time_ns: u64::from_le_bytes(header[3..].try_into().unwrap()),
+                text: core::str::from_utf8(&text[..len]).unwrap_or(""),
+            };
+            if !f(&record) {
+                return;
+            }
+
+            offset = (offset + RECORD_HEADER + len) % self.size();
+        }
+    }
+}
+
+// a line of /dev/kmsg at a time, on the stack
+struct Line {
+    buffer: [u8; LINE_MAX],
+    len: usize,
+}
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let cnt = s.len().min(LINE_MAX - self.len);
+        self.buffer[self.len..self.len + cnt].copy_from_slice(&s.as_bytes()[..cnt]);
+        self.len += cnt;
+        Ok(())
+    }
+}
+
+/*
+    Reads like a file with what's in the ring, so offsets are from the oldest record. The
+    ring moving between two reads shows as a jump in the sequence numbers
+*/
+pub struct KmsgDevice;
+
+impl devfs::Device for KmsgDevice {
+    fn read(&self, buffer: *mut u8, cnt: usize, offset: usize) -> KResult<usize> {
+        // copied out after the lock is dropped, a fault on buffer could want to log
+        let mut lines = Vec::with_capacity(cnt.min(KMSG_SIZE * 2));
+        let mut position = 0;
+
+        KMSG.lock().for_each(|record| {
+            let mut line = Line {
+                buffer: [0; LINE_MAX],
+                len: 0,
+            };
+            record.write_kmsg(&mut line).ok();
+
+            // the part of the line that's past offset and fits
+            let start = offset.max(position).min(position + line.len);
+            let end = (position + line.len).min(offset + cnt);
+            if start < end {
+                lines.extend_from_slice(&line.buffer[start - position..end - position]);
+            }
+
+            position += line.len;
+            position < offset + cnt
+        });
+
+        unsafe {
+            core::ptr::copy_nonoverlapping(lines.as_ptr(), buffer, lines.len());
+        }
+        Ok(lines.len())
+    }
+
+    fn write(&self, _buffer: *const u8, _cnt: usize, _offset: usize) -> KResult<usize> {
+        Err(KError::EPERM)
+    }
+}
+
+// without the graphics feature the screen sink just drops everything
+struct ScreenWriter;
+
+impl Write for ScreenWriter {
+    fn write_str(&mut self, _s: &str) -> fmt::Result {
+        #[cfg(feature = "graphics")]
+        crate::video::print(_s);
+        Ok(())
+    }
+}
+
+pub fn threshold(sink: Sink) -> Level {
+    Level::from_u8(THRESHOLDS[sink as usize].load(Ordering::Relaxed))
+}
+
+pub fn set_threshold(sink: Sink, level: Level) {
+    THRESHOLDS[sink as usize].store(level as u8, Ordering::Relaxed);
+}
+
+// e.g. "log.serial=debug log.screen=error"
+pub fn init() {
+    for sink_name in ["serial", "screen", "kmsg"] {
+        let key = alloc::format!("log.{}", sink_name);
+
+        if let Some(value) = cmdline::option(&key) {
+            match Level::from_str(value) {
+                Some(level) => set_threshold(Sink::from_str(sink_name).unwrap(), level),
+                None => log(Level::Warn, format_args!("[LOG] Unknown log level {} for {}\n", value, key)),
+            }
+        }
+    }
+}
+
+// after vmalloc, records logged until then are kept
+pub fn grow_kmsg() {
+    let value = match cmdline::option("log.kmsg_size") {
+        Some(value) => value,
+        None => return,
+    };
+    let size = match value.parse::<usize>() {
+        Ok(kib) if kib * 1024 > KMSG_SIZE => kib * 1024,
+        _ => {
+            let min = KMSG_SIZE / 1024;
+            log(Level::Warn, format_args!("[LOG] log.kmsg_size={} isn't above {}\n", value, min));
+            return;
+        }
+    };
+
+    match vmalloc::vmalloc(size) {
+        Ok(buffer) => {
+            KMSG.lock().grow(unsafe { core::slice::from_raw_parts_mut(buffer, size) });
+            log(Level::Info, format_args!("[LOG] The kmsg ring is {} KiB\n", size / 1024));
+        }
+        Err(err) => log(
+            Level::Warn,
+            format_args!("[LOG] Could not allocate a kmsg ring of {} KiB: {}\n", size / 1024, err),
+        ),
+    }
+}
+
+pub fn kmsg() -> spin::MutexGuard<'static, Kmsg> {
+    KMSG.lock()
+}
+
+// for the panic handler, which can't wait for a lock its own cpu might hold
+pub fn try_kmsg() -> Option<spin::MutexGuard<'static, Kmsg>> {
+    KMSG.try_lock()
+}
+
+pub fn log(level: Level, args: fmt::Arguments) {
+    if level <= threshold(Sink::Serial) {
+        serial::print_fmt(args);
+    }
+
+    if level <= threshold(Sink::Screen) {
+        ScreenWriter.write_fmt(args).ok();
+    }
+
+    if level <= threshold(Sink::Kmsg) {
+        let mut text = Text {
+            buffer: [0; TEXT_MAX],
+            len: 0,
+        };
+        text.write_fmt(args).ok();
+
+        // a record is a line, the newline is added back when it's read
+        let bytes = &text.buffer[..text.len];
+        let bytes = bytes.strip_suffix(b"\n").unwrap_or(bytes);
+        let time_ns = if timer_source::is_initialized() { timer_source::current_ns() } else { 0 };
+        KMSG.lock().push(level, time_ns, bytes);
+    }
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        crate::log::log(crate::log::Level::Error, format_args!($($arg)*))
+    };
+}
+
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        crate::log::log(crate::log::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        crate::log::log(crate::log::Level::Info, format_args!($($arg)*))
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        crate::log::log(crate::log::Level::Debug, format_args!($($arg)*))
+    };
+}
+
+pub(crate) use {debug, error, info, warning};
@@ -9,12 +9,34 @@
 extern crate alloc;
 
 pub mod arch;
+pub mod boot;
+pub mod cmdline;
+pub mod crashdump;
 pub mod drivers;
+pub mod error;
 pub mod fs;
+pub mod kprobe;
+#[cfg(feature = "ktest")]
+pub mod ktest;
+pub mod ksym;
+pub mod log;
 pub mod mm;
+#[cfg(feature = "net")]
+pub mod net;
 pub mod proc;
+pub mod rcu;
+pub mod rng;
 pub mod serial;
+#[cfg(feature = "shell")]
+pub mod shell;
+pub mod snapshot;
+pub mod spinlock;
+pub mod syscall;
+pub mod sysctl;
+pub mod trace;
 pub mod utils;
+pub mod vdso;
+#[cfg(feature = "graphics")]
 pub mod video;
 
 use arch::cpu;
@@ -45,55 +67,167 @@ static STIVALE_HEADER: StivaleHeader = StivaleHeader::new()
 
 #[no_mangle]
 unsafe extern "C" fn _start(tags: &'static StivaleStruct) -> ! {
+    #[cfg(feature = "graphics")]
     let framebuffer_tag = tags.framebuffer().unwrap();
     let mmap_tag = tags.memory_map().unwrap();
     let rsdp_tag = tags.rsdp().unwrap();
 
-    serial::SerialWriter::init();
-
-    let mut video = video::Video::new(framebuffer_tag);
-
-    video.print("Hello, world, from Rust!\n");
-    video.print("Is everything fine?");
+    // it's only copied out of memory, nothing is printed before the serial port is set up
+    cmdline::init(tags.command_line());
+    let serial_config = serial::SerialConfig::from_cmdline();
+    if !serial::SerialWriter::init(serial_config)
+        && serial::SerialWriter::init(serial::SerialConfig::default())
+    {
+        let baud_rate = serial_config.baud_rate;
+        serial::print!("Can't use {} baud on the serial port, using the default\n", baud_rate);
+    }
+    vdso::set_epoch(tags.epoch());
 
     arch::mm::pmm::init(
         &mmap_tag.entry_array as *const StivaleMemoryMapEntry,
         mmap_tag.entries_len,
     );
     slab::init();
+    #[cfg(feature = "graphics")]
+    {
+        video::init(framebuffer_tag);
+        video::show_splash(BOOT_STEPS);
+        video::print("Hello, world, from Rust!\n");
+    }
+    log::init();
+    fs::probe::init();
     arch::gdt::init();
     arch::interrupts::init();
-    vmm::init();
+    arch::mce::init();
+    arch::gdbstub::init();
+    cpu::init_msrs();
+    vmm::init(
+        &mmap_tag.entry_array as *const StivaleMemoryMapEntry,
+        mmap_tag.entries_len,
+    );
+    mm::frame::init();
+    mm::vmalloc::init();
+    log::grow_kmsg();
     cpu::start();
+    boot_step();
     arch::acpi::init(rsdp_tag);
+    boot::init(tags.modules());
+    boot_step();
+    #[cfg(feature = "smp")]
+    arch::smp::park(tags.smp());
+    // the stivale2 tags can't be touched after this
+    arch::mm::pmm::reclaim_bootloader_memory();
+    drivers::ramdisk::init();
+    fs::cpio::init();
+    boot_step();
     
-    drivers::hpet::init();
+    drivers::timer_source::init();
    
     arch::apic::init();
-    // arch::apic::get().calibrate_timer(1000);
+    arch::ioapic::init();
+    drivers::timer_source::init_irq();
+    arch::ipi::init();
+    #[cfg(feature = "smp")]
+    arch::smp::init();
+    arch::topology::print_summary();
+    trace::init();
+    rng::init();
+    boot_step();
 
     arch::pci::enumerate_devices();
-    partitions::scan();
-    vfs::mount(fs::ext2::get(), "/");
-    let mut fd = vfs::open("/home/limine.cfg", vfs::Flags::empty(), vfs::Mode::empty()).unwrap();
-    serial::print!("file index: {}\n", fd.file_index);
-
-    let mut content = alloc::vec::Vec::with_capacity(50);
-    vfs::read(fd.fs, fd.file_index, content.as_mut_ptr(), 50, fd.offset);
-    content.set_len(50);
-    serial::print!(
-        "res: {}\n",
-        core::str::from_utf8(content.as_slice()).unwrap()
-    );
+    drivers::ps2::init();
+    // e.g. root=ramdisk to boot from a ramdisk module, or root=initramfs
+    let root_device = cmdline::option("root").unwrap_or("ahci0");
+    for device in drivers::block::devices() {
+        if let Err(err) = partitions::scan(device.clone()) {
+            log::warning!("Could not scan {} for partitions: {}\n", device.name(), err);
+        }
+    }
+    boot_step();
+    crashdump::init();
+    mm::swap::init();
+    let root_fs: &'static dyn vfs::Filesystem = match root_device {
+        "initramfs" => fs::cpio::initramfs().expect("No initramfs was loaded"),
+        _ => partitions::of_device(root_device)
+            .iter()
+            .find_map(|partition| fs::probe::probe_partition(partition))
+            .expect("Could not find a filesystem on the root device"),
+    };
+    vfs::mount(root_fs, "/").expect("Could not mount the root filesystem");
+    fs::modfs::init();
+    fs::shmfs::init();
+    fs::devfs::init();
+    fs::procfs::init();
+    fs::ramfs::init();
+    #[cfg(feature = "net")]
+    net::init();
+    boot_step();
+    if let Ok(mut fd) = vfs::open("/home/limine.cfg", vfs::Flags::empty(), vfs::Mode::empty()) {
+        log::debug!("file index: {}\n", fd.file_index);
+
+        let mut content = alloc::vec::Vec::with_capacity(50);
+        vfs::read(&mut fd, content.as_mut_ptr(), 50).unwrap();
+        content.set_len(50);
+        log::debug!(
+            "res: {}\n",
+            core::str::from_utf8(content.as_slice()).unwrap()
+        );
+    }
     
+    snapshot::run();
+
+    #[cfg(feature = "ktest")]
+    ktest::run();
+    boot_step();
+
+    // after the tests, some of them need the lapic timer for themselves
+    drivers::timer::init();
+    vdso::init();
+    #[cfg(feature = "graphics")]
+    video::start_cursor_blink();
+
     proc::process::init_bitmaps(); 
-    proc::process::Process::new(alloc::string::String::from("crap"), 0, None);
-    serial::print!("hey!\n");
+    proc::scheduler::init();
+    mm::reclaim::init();
+    drivers::aio::init();
+    fs::writeback::init();
+    #[cfg(feature = "net")]
+    net::tcp::init();
+    proc::process::Process::new(
+        alloc::string::String::from("crap"),
+        0,
+        alloc::string::String::from("/"),
+    );
+    boot_step();
+
+    #[cfg(feature = "graphics")]
+    video::hide_splash();
+
+    #[cfg(all(feature = "shell", feature = "net"))]
+    if let Some(port) = cmdline::option("telnet") {
+        shell::serve_remote(port);
+    }
+
+    #[cfg(feature = "shell")]
+    if cmdline::option("shell").is_some() {
+        shell::run();
+    }
+
     cpu::halt();
 }
 
+// how many times boot_step is called, so the splash knows when boot is done
+#[cfg(feature = "graphics")]
+const BOOT_STEPS: usize = 8;
+
+fn boot_step() {
+    #[cfg(feature = "graphics")]
+    video::splash_step();
+}
+
 #[panic_handler]
 fn panic_handler(info: &PanicInfo) -> ! {
+    serial::panic_mode();
     let location = info.location().unwrap();
     serial::print!(
         "PANIC at file {}, line {}: {}\n",
@@ -101,5 +235,12 @@ fn panic_handler(info: &PanicInfo) -> ! {
         location.line(),
         info.message().unwrap()
     );
+    serial::print!("backtrace:\n");
+    ksym::backtrace(|address| serial::print!("    {}\n", ksym::Symbolized(address)));
+    crashdump::dump(info);
+
+    #[cfg(feature = "ktest")]
+    ktest::exit_qemu(ktest::ExitCode::Failure);
+
     cpu::halt();
 }
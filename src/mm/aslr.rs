@@ -0,0 +1,62 @@
+/*
+    Where things go in a user address space. With randomization on, every address space
+    gets its own stack top, mmap base and load base for position independent (ET_DYN)
+    binaries, all page aligned. norandmaps on the command line turns it off, so addresses
+    are the same from one run to the next when debugging
+*/
+
+use crate::arch::mm::pmm::PAGE_SIZE;
+use crate::{cmdline, rng};
+
+// the last page below the non-canonical hole
+pub const USER_TOP: u64 = 0x7fff_ffff_f000;
+// what linux uses for ET_DYN binaries without randomization
+const DEFAULT_LOAD_BASE: u64 = 0x5555_5555_4000;
+// room left for the stack to grow before mmap's area starts
+const STACK_GAP: u64 = 64 << 30;
+
+// how far each one can move, in pages: 16GiB for the stack, 1TiB for the others
+const STACK_RANDOM_PAGES: u64 = 1 << 22;
+const MMAP_RANDOM_PAGES: u64 = 1 << 28;
+const LOAD_RANDOM_PAGES: u64 = 1 << 28;
+
+#[derive(Clone, Copy)]
+pub struct Layout {
+    pub stack_top: u64,
+    pub mmap_base: u64, // mmap hands out addresses downwards from here
+    pub load_base: u64,
+}
+
+pub fn enabled() -> bool {
+    cmdline::option("norandmaps").is_none()
+}
+
+fn random_pages(pages: u64, randomize: bool) -> u64 {
+    if randomize {
+        rng::below(pages) * PAGE_SIZE
+    } else {
+        0
+    }
+}
+
+impl Layout {
+    fn build(randomize: bool) -> Self {
+        let mmap_base = USER_TOP - STACK_RANDOM_PAGES * PAGE_SIZE - STACK_GAP;
+
+        Layout {
+            stack_top: USER_TOP - random_pages(STACK_RANDOM_PAGES, randomize),
+            mmap_base: mmap_base - random_pages(MMAP_RANDOM_PAGES, randomize),
+            load_base: DEFAULT_LOAD_BASE + random_pages(LOAD_RANDOM_PAGES, randomize),
+        }
+    }
+
+    // for a new user address space
+    pub fn new() -> Self {
+        Layout::build(enabled())
+    }
+
+    // doesn't touch the rng, so it's fine before it's seeded or in interrupt handlers
+    pub fn fixed() -> Self {
+        Layout::build(false)
+    }
+}
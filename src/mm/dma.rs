@@ -0,0 +1,111 @@
+/*
+    Memory that devices read and write on their own. It's physically contiguous, satisfies
+    the device's addressing limits and alignment, and is mapped uncached, so neither side
+    ever sees stale data
+*/
+
+use crate::arch::mm::pmm::{self, PhysAddr};
+use crate::error::{KError, KResult};
+use crate::mm::vmm::{self, PageFlags, VirtAddr};
+use crate::utils::math::div_ceil;
+
+// what build_direct_map maps every frame with
+const DIRECT_MAP_FLAGS: PageFlags = PageFlags::from_bits_truncate(
+    PageFlags::PRESENT.bits() | PageFlags::WRITABLE.bits() | PageFlags::NX.bits(),
+);
+
+#[derive(Clone, Copy)]
+pub struct DmaConstraints {
+    pub limit: u64, // every byte of the buffer is below this physical address
+    pub align: u64, // in bytes, a power of two
+}
+
+impl DmaConstraints {
+    pub const ANY: DmaConstraints = DmaConstraints {
+        limit: u64::MAX,
+        align: pmm::PAGE_SIZE,
+    };
+
+    // for devices with 32 bit address registers
+    pub const BELOW_4G: DmaConstraints = DmaConstraints {
+        limit: 1 << 32,
+        align: pmm::PAGE_SIZE,
+    };
+
+    pub const fn aligned(self, align: u64) -> Self {
+        DmaConstraints { align, ..self }
+    }
+}
+
+pub struct DmaBuffer {
+    phys: PhysAddr,
+    len: usize,
+    pages: usize,
+}
+
+impl DmaBuffer {
+    // zero filled
+    pub fn new(len: usize, constraints: DmaConstraints) -> KResult<Self> {
+        if len == 0 || !constraints.align.is_power_of_two() {
+            return Err(KError::EINVAL);
+        }
+
+        let pages = div_ceil(len, pmm::PAGE_SIZE as usize);
+        let align = (constraints.align / pmm::PAGE_SIZE).max(1) as usize;
+        let phys = pmm::get().alloc_below(pages, align, constraints.limit)?;
+
+        let buffer = DmaBuffer { phys, len, pages };
+        buffer.remap(DIRECT_MAP_FLAGS | PageFlags::UNCACHEABLE);
+
+        unsafe {
+            buffer.as_mut_ptr::<u8>().write_bytes(0, pages * pmm::PAGE_SIZE as usize);
+        }
+
+        Ok(buffer)
+    }
+
+    // changes the flags of the buffer's pages in the direct map
+    fn remap(&self, flags: PageFlags) {
+        for page in 0..self.pages as u64 {
+            let phys = PhysAddr::new(self.phys.as_u64() + page * pmm::PAGE_SIZE);
+            vmm::get().map_page(VirtAddr::new(phys.higher_half().as_u64()), phys, flags, true);
+        }
+    }
+
+    // what the device is given
+    pub fn phys(&self) -> u64 {
+        self.phys.as_u64()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_ptr<T>(&self) -> *const T {
+        self.phys.higher_half().as_ptr()
+    }
+
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.phys.higher_half().as_mut_ptr()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.as_mut_ptr(), self.len) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        // back to the direct map's normal flags before anyone else gets the pages
+        self.remap(DIRECT_MAP_FLAGS);
+        pmm::get().free(self.as_mut_ptr(), self.pages);
+    }
+}
@@ -0,0 +1,120 @@
+/*
+    What's known about every physical frame, in an array indexed by frame number that's
+    allocated from the pmm at boot.
+
+    Frames that can have more than one owner are counted. A frame starts being counted
+    when whoever allocated it tracks it, that's its first reference. Everyone else that
+    holds on to it (another address space it's shared with copy on write, a mapping of a
+    shm object's page) takes a reference with get and drops it with put, and the last put
+    frees it. A count of 0 means the frame isn't counted: get and put leave it alone and
+    it's freed by whoever allocated it, the way every frame was before.
+
+    Every page a mapping faults in is counted, a 2MiB one frame by frame so its frames
+    stay counted once it's split, and so are the pages of shm objects and uring rings.
+    The ext2 caches hold heap buffers and not frames, an ext2 file is mapped through a
+    counted copy of its contents
+*/
+
+use crate::arch::mm::pmm::{self, PhysAddr};
+use crate::log;
+use crate::utils::math::div_ceil;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+static mut FRAMES: &[Frame] = &[];
+
+bitflags::bitflags! {
+    pub struct FrameFlags: u32 {
+        const PRIVATE = 1 << 0; // faulted in for a mapping, it only ever has one address space
+        const SHMEM = 1 << 1;   // a page of a shm object
+    }
+}
+
+struct Frame {
+    refcount: AtomicU32,
+    flags: AtomicU32,
+}
+
+// None for frames past the end of memory and before init
+fn frame(addr: PhysAddr) -> Option<&'static Frame> {
+    unsafe { FRAMES.get((addr.as_u64() / pmm::PAGE_SIZE) as usize) }
+}
+
+// after the vmm, the array is reached through the direct map
+pub fn init() {
+    let frames = pmm::get().max_pages();
+    let pages = div_ceil(frames * size_of::<Frame>(), pmm::PAGE_SIZE as usize);
+
+    // zeroed, so nothing is counted
+    match pmm::get().calloc(pages) {
+        Ok(array) => unsafe {
+            FRAMES = core::slice::from_raw_parts(array.higher_half().as_ptr(), frames);
+        },
+        Err(_) => {
+            log::error!("[FRAME] Could not allocate the metadata of {} frames\n", frames);
+            return;
+        }
+    }
+
+    log::info!("[FRAME] {} KiB of metadata for {} frames\n", pages * 4, frames);
+}
+
+// a frame fresh from the pmm, counted from now on with its owner's reference
+pub fn track(addr: PhysAddr, flags: FrameFlags) {
+    if let Some(frame) = frame(addr) {
+        frame.flags.store(flags.bits(), Ordering::Relaxed);
+        frame.refcount.store(1, Ordering::Release);
+    }
+}
+
+pub fn is_counted(addr: PhysAddr) -> bool {
+    refcount(addr) != 0
+}
+
+pub fn refcount(addr: PhysAddr) -> u32 {
+    frame(addr).map_or(0, |frame| frame.refcount.load(Ordering::Acquire))
+}
+
+pub fn flags(addr: PhysAddr) -> FrameFlags {
+    frame(addr).map_or(FrameFlags::empty(), |frame| {
+        FrameFlags::from_bits_truncate(frame.flags.load(Ordering::Relaxed))
+    })
+}
+
+// one more owner, false if the frame isn't counted and so can't be shared this way
+pub fn get(addr: PhysAddr) -> bool {
+    let frame = match frame(addr) {
+        Some(frame) => frame,
+        None => return false,
+    };
+
+    // a frame that isn't counted stays that way
+    frame
+        .refcount
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+            (count != 0).then(|| count + 1)
+        })
+        .is_ok()
+}
+
+// one owner less, the last one frees the frame. Returns whether it was freed
+pub fn put(addr: PhysAddr) -> bool {
+    let frame = match frame(addr) {
+        Some(frame) => frame,
+        None => return false,
+    };
+
+    let previous = frame
+        .refcount
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+            (count != 0).then(|| count - 1)
+        });
+
+    if previous != Ok(1) {
+        return false;
+    }
+
+    frame.flags.store(0, Ordering::Relaxed);
+    pmm::get().free(addr.higher_half().as_mut_ptr(), 1);
+    true
+}
@@ -0,0 +1,208 @@
+/*
+    A small address sanitizer for the slab allocator: every object gets a redzone on
+    each side and freed objects are poisoned and kept in a quarantine for a while,
+    so overruns and use-after-frees are caught when the object is freed, when it
+    leaves the quarantine or during the periodic scans
+*/
+
+use crate::arch::mm::pmm::PHYS_BASE;
+use crate::ksym;
+use crate::log;
+use core::arch::asm;
+
+pub const REDZONE_SIZE: usize = 16;
+
+const REDZONE_BYTE: u8 = 0xfa;
+const FREED_BYTE: u8 = 0xfb;
+
+const MAX_TRACKED: usize = 1024;
+const QUARANTINE_SIZE: usize = 64;
+const SITE_DEPTH: usize = 4;
+
+// how many allocations happen between two full scans
+const SCAN_INTERVAL: usize = 256;
+
+// the slab's biggest cache is 4096 bytes, bigger objects don't get redzones
+const MAX_OBJECT_SIZE: usize = 4096 - 2 * REDZONE_SIZE;
+
+static STATE: spin::Mutex<KasanState> = spin::Mutex::new(KasanState {
+    tracked: [Allocation::EMPTY; MAX_TRACKED],
+    quarantine: [Allocation::EMPTY; QUARANTINE_SIZE],
+    quarantine_head: 0,
+    allocations: 0,
+});
+
+#[derive(Clone, Copy)]
+struct Allocation {
+    object: usize, // 0 if the entry is unused
+    size: usize,
+    site: [u64; SITE_DEPTH], // return addresses of the allocating code
+}
+
+impl Allocation {
+    const EMPTY: Allocation = Allocation {
+        object: 0,
+        size: 0,
+        site: [0; SITE_DEPTH],
+    };
+
+    fn slot(&self) -> *mut u8 {
+        (self.object - REDZONE_SIZE) as *mut u8
+    }
+
+    fn report(&self, what: &str) -> ! {
+        log::error!(
+            "[KASAN] {} (object {:#x}, size {})\n",
+            what,
+            self.object,
+            self.size
+        );
+        log::error!("[KASAN] allocated at:\n");
+        for address in self.site.iter().take_while(|address| **address != 0) {
+            log::error!("[KASAN]     {}\n", ksym::Symbolized(*address));
+        }
+
+        panic!("KASAN detected memory corruption");
+    }
+
+    fn check_redzones(&self) {
+        unsafe {
+            let slot = self.slot();
+
+            for i in 0..REDZONE_SIZE {
+                if *slot.add(i) != REDZONE_BYTE {
+                    self.report("Redzone before the object was overwritten");
+                }
+
+                if *slot.add(REDZONE_SIZE + self.size + i) != REDZONE_BYTE {
+                    self.report("Redzone after the object was overwritten");
+                }
+            }
+        }
+    }
+
+    fn check_poison(&self) {
+        for i in 0..self.size {
+            if unsafe { *(self.object as *const u8).add(i) } != FREED_BYTE {
+                self.report("Object was written to after being freed");
+            }
+        }
+    }
+}
+
+struct KasanState {
+    tracked: [Allocation; MAX_TRACKED],
+    quarantine: [Allocation; QUARANTINE_SIZE],
+    quarantine_head: usize,
+    allocations: usize,
+}
+
+impl KasanState {
+    fn scan(&self) {
+        for allocation in self.tracked.iter().filter(|a| a.object != 0) {
+            allocation.check_redzones();
+        }
+
+        for allocation in self.quarantine.iter().filter(|a| a.object != 0) {
+            allocation.check_redzones();
+            allocation.check_poison();
+        }
+    }
+}
+
+// walks the frame pointers, skipping the allocator's own frames
+fn allocation_site() -> [u64; SITE_DEPTH] {
+    let mut site = [0; SITE_DEPTH];
+    let mut frame: u64;
+
+    unsafe {
+        asm!("mov {}, rbp", out(reg) frame);
+    }
+
+    let mut depth = 0;
+    while frame >= PHYS_BASE && depth < SITE_DEPTH + 2 {
+        let return_address = unsafe { *((frame + 8) as *const u64) };
+
+        if depth >= 2 {
+            site[depth - 2] = return_address;
+        }
+
+        frame = unsafe { *(frame as *const u64) };
+        depth += 1;
+    }
+
+    site
+}
+
+pub fn covers(size: usize) -> bool {
+    size <= MAX_OBJECT_SIZE
+}
+
+// the slab handed out slot, which is size + 2 * REDZONE_SIZE bytes long
+pub unsafe fn on_alloc(slot: *mut u8, size: usize) -> *mut u8 {
+    slot.write_bytes(REDZONE_BYTE, REDZONE_SIZE);
+    slot.add(REDZONE_SIZE + size).write_bytes(REDZONE_BYTE, REDZONE_SIZE);
+
+    let object = slot.add(REDZONE_SIZE);
+    let mut state = STATE.lock();
+
+    // if the table is full, the object still has its redzones, it just isn't scanned
+    if let Some(entry) = state.tracked.iter_mut().find(|a| a.object == 0) {
+        *entry = Allocation {
+            object: object as usize,
+            size,
+            site: allocation_site(),
+        };
+    }
+
+    state.allocations += 1;
+    if state.allocations % SCAN_INTERVAL == 0 {
+        state.scan();
+    }
+
+    object
+}
+
+/*
+    Checks and poisons the object and puts it in the quarantine. Returns the slot
+    and size of the object that was pushed out of the quarantine, which is the
+    one that should actually be given back to the slab
+*/
+pub unsafe fn on_free(object: *mut u8, size: usize) -> Option<(*mut u8, usize)> {
+    let mut state = STATE.lock();
+
+    let allocation = match state.tracked.iter_mut().find(|a| a.object == object as usize) {
+        Some(entry) => core::mem::replace(entry, Allocation::EMPTY),
+        None => {
+            if let Some(freed) = state.quarantine.iter().find(|a| a.object == object as usize) {
+                freed.report("Object was freed twice");
+            }
+
+            Allocation {
+                object: object as usize,
+                size,
+                site: [0; SITE_DEPTH],
+            }
+        }
+    };
+
+    allocation.check_redzones();
+    object.write_bytes(FREED_BYTE, size);
+
+    let head = state.quarantine_head;
+    let evicted = core::mem::replace(&mut state.quarantine[head], allocation);
+    state.quarantine_head = (head + 1) % QUARANTINE_SIZE;
+
+    if evicted.object == 0 {
+        return None;
+    }
+
+    evicted.check_redzones();
+    evicted.check_poison();
+
+    Some((evicted.slot(), evicted.size))
+}
+
+pub fn scan() {
+    STATE.lock().scan();
+}
@@ -1,2 +1,11 @@
+#[cfg(feature = "kasan")]
+pub mod kasan;
+pub mod aslr;
+pub mod dma;
+pub mod frame;
+pub mod oom;
+pub mod reclaim;
 pub mod slab;
+pub mod swap;
+pub mod vmalloc;
 pub mod vmm;
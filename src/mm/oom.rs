@@ -0,0 +1,137 @@
+/*
+    What happens when a user page fault finds no free page. Rather than panicking, cold
+    pages are reclaimed, and if that doesn't free any a user process is killed to get its
+    memory back: the one with the most resident pages, the lowest priority breaking ties.
+    The faulting process is only picked when nothing else is left, and then it's the one
+    that dies
+*/
+
+use crate::arch::mm::pmm::{self, PhysAddr};
+use crate::log;
+use crate::mm::{reclaim, vmm};
+use crate::proc::{process::Process, scheduler};
+use alloc::rc::Rc;
+use core::cell::RefCell;
+
+// how many pages are reclaimed at once before anyone gets killed
+const RECLAIM_BATCH: usize = 32;
+
+struct Victim {
+    process: Rc<RefCell<Process>>,
+    resident: usize, // in pages
+    priority: usize, // the best priority of its threads, 0 is the highest
+}
+
+// every user process except current, they're the only ones with their own address space
+fn pick_victim(current: &Rc<RefCell<Process>>) -> Option<Victim> {
+    // the processes are only known to the scheduler, without it there's nothing to kill
+    let scheduler = match scheduler::try_get() {
+        Some(scheduler) => scheduler,
+        None => panic!("Could not allocate a new page and there's no scheduler to kill for one"),
+    };
+
+    scheduler
+        .processes()
+        .into_iter()
+        .filter(|process| !Rc::ptr_eq(process, current))
+        .filter_map(|process| {
+            let (resident, priority) = {
+                let process = process.borrow();
+                let resident = process.pagemap.as_ref()?.resident_pages();
+                let priority = process
+                    .threads
+                    .iter()
+                    .map(|thread| thread.borrow().priority)
+                    .min()
+                    .unwrap_or(scheduler::PRIORITY_LEVELS);
+
+                (resident, priority)
+            };
+
+            Some(Victim {
+                process,
+                resident,
+                priority,
+            })
+        })
+        .max_by_key(|victim| (victim.resident, victim.priority))
+}
+
+fn announce(process: &Rc<RefCell<Process>>, resident: usize) {
+    let process = process.borrow();
+    log::warning!(
+        "[OOM] Out of memory, killing process {} ({}) to free {} pages\n",
+        process.pid,
+        process.name,
+        resident
+    );
+}
+
+// tears the process down and takes its threads off the scheduler's queues
+pub fn kill(process: &Rc<RefCell<Process>>, resident: usize) {
+    announce(process, resident);
+    terminate(process);
+}
+
+// the same, without saying it was for memory
+pub fn terminate(process: &Rc<RefCell<Process>>) {
+    let threads = process.borrow().threads.clone();
+    process.borrow_mut().exit();
+
+    // none of them can be queued without a scheduler
+    if let Some(mut scheduler) = scheduler::try_get() {
+        for thread in threads.iter() {
+            scheduler.remove(thread);
+        }
+    }
+}
+
+/*
+    A zeroed page for a mapping of current. Pages are swapped out to make room first, once
+    that doesn't help other processes are killed until one is free. None means there was
+    no one left to kill but current
+*/
+pub fn alloc_page(current: &Rc<RefCell<Process>>) -> Option<PhysAddr> {
+    loop {
+        if let Ok(page) = pmm::get().calloc(1) {
+            reclaim::check_pressure();
+            return Some(page);
+        }
+
+        if reclaim::reclaim(RECLAIM_BATCH, true) != 0 {
+            continue;
+        }
+
+        let victim = pick_victim(current)?;
+        kill(&victim.process, victim.resident);
+    }
+}
+
+/*
+    Kills the process of the running thread, for when it's the one that's out of memory.
+    Its threads never get the cpu again, so this doesn't return
+*/
+pub fn kill_current(process: Rc<RefCell<Process>>) -> ! {
+    let resident = process
+        .borrow()
+        .pagemap
+        .as_ref()
+        .map(|pagemap| pagemap.resident_pages())
+        .unwrap_or(0);
+
+    announce(&process, resident);
+    exit_current(process)
+}
+
+// terminates the process of the running thread, which never gets the cpu again
+pub fn exit_current(process: Rc<RefCell<Process>>) -> ! {
+    // its address space is about to go away
+    vmm::get().switch_pagemap();
+    terminate(&process);
+    drop(process);
+
+    loop {
+        scheduler::yield_now();
+        core::hint::spin_loop();
+    }
+}
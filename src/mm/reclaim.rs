@@ -0,0 +1,219 @@
+/*
+    Keeps the mapped pages of user processes on two lists, by how recently they were used.
+    A scan picks up the pages mapped since the last one, then checks the accessed bits:
+    pages used since the last scan move to (or stay on) the active list, the others age onto
+    the inactive one, and the bits are cleared for the next round.
+
+    Pages are freed from the cold end of the inactive list. kreclaimd scans every
+    SCAN_INTERVAL_MS, writes dirty shared file pages that went cold back to their files, so
+    they're cheap to drop, and frees pages once memory runs low. An allocation that fails
+    reclaims right away, without waiting for it
+*/
+
+use super::swap;
+use super::vmm::VirtAddr;
+use crate::arch::mm::pmm;
+use crate::drivers::timer_source;
+use crate::log;
+use crate::proc::process::{Process, SelectorValues, Thread};
+use crate::proc::{scheduler, wait::WaitQueue};
+use alloc::collections::{BTreeSet, VecDeque};
+use alloc::{rc::Rc, string::String, vec::Vec};
+use core::cell::RefCell;
+
+const SCAN_INTERVAL_MS: u64 = 1000;
+// kreclaimd starts freeing below total / LOW_WATERMARK_DIVISOR free pages, up to twice that
+const LOW_WATERMARK_DIVISOR: usize = 64;
+
+static mut LRU: Option<spin::Mutex<Lru>> = None;
+// notified when memory runs low, so kreclaimd doesn't wait for its next scan
+static PRESSURE: WaitQueue = WaitQueue::new();
+
+// a page of the address space of owner, which is the address of its process
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct PageRef {
+    owner: usize,
+    address: u64,
+}
+
+struct Lru {
+    active: VecDeque<PageRef>,
+    inactive: VecDeque<PageRef>, // the coldest pages at the front
+    tracked: BTreeSet<PageRef>,  // everything on either list
+}
+
+fn lru() -> &'static spin::Mutex<Lru> {
+    unsafe {
+        LRU.get_or_insert_with(|| {
+            spin::Mutex::new(Lru {
+                active: VecDeque::new(),
+                inactive: VecDeque::new(),
+                tracked: BTreeSet::new(),
+            })
+        })
+    }
+}
+
+type Owners = Vec<(usize, Rc<RefCell<Process>>)>;
+
+// the user processes, by the owner their pages are tracked with
+fn processes() -> Owners {
+    let processes = match scheduler::try_get() {
+        Some(scheduler) => scheduler.processes(),
+        None => return Vec::new(),
+    };
+
+    processes
+        .into_iter()
+        .filter(|process| process.borrow().pagemap.is_some())
+        .map(|process| (Rc::as_ptr(&process) as usize, process))
+        .collect()
+}
+
+fn find(processes: &Owners, owner: usize) -> Option<&Rc<RefCell<Process>>> {
+    processes.iter().find(|(other, _)| *other == owner).map(|(_, process)| process)
+}
+
+// None if the page (or its process) is gone
+fn test_and_clear_accessed(process: Option<&Rc<RefCell<Process>>>, page: PageRef) -> Option<bool> {
+    let process = process?.borrow();
+    process.pagemap.as_ref()?.test_and_clear_accessed(VirtAddr::new(page.address))
+}
+
+pub fn scan() {
+    let processes = processes();
+    let mut lru = lru().lock();
+
+    for (owner, process) in processes.iter() {
+        if let Some(pagemap) = process.borrow().pagemap.as_ref() {
+            pagemap.for_each_page(|address| {
+                let page = PageRef {
+                    owner: *owner,
+                    address: address.as_u64(),
+                };
+
+                if lru.tracked.insert(page) {
+                    lru.active.push_back(page);
+                }
+            });
+        }
+    }
+
+    let active = core::mem::take(&mut lru.active);
+    let inactive = core::mem::take(&mut lru.inactive);
+
+    for page in inactive.into_iter().chain(active) {
+        match test_and_clear_accessed(find(&processes, page.owner), page) {
+            Some(true) => lru.active.push_back(page),
+            Some(false) => lru.inactive.push_back(page),
+            None => {
+                lru.tracked.remove(&page);
+            }
+        }
+    }
+}
+
+/*
+    Frees up to wanted pages, coldest first. Dirty ones are only written out with io,
+    returns how many were freed
+*/
+pub fn reclaim(wanted: usize, io: bool) -> usize {
+    scan();
+
+    let processes = processes();
+    let candidates = lru().lock().inactive.len();
+    let mut freed = 0;
+
+    // the lock isn't held while evicting, writing a page out can sleep
+    for _ in 0..candidates {
+        if freed == wanted {
+            break;
+        }
+
+        let page = match lru().lock().inactive.pop_front() {
+            Some(page) => page,
+            None => break,
+        };
+
+        let evicted = find(&processes, page.owner).map(|process| {
+            let process = process.borrow();
+            let pagemap = process.pagemap.as_ref();
+            pagemap.map_or(false, |pagemap| pagemap.evict(VirtAddr::new(page.address), io))
+        });
+
+        let mut lru = lru().lock();
+        match evicted {
+            Some(true) => {
+                lru.tracked.remove(&page);
+                freed += 1;
+            }
+            // used again, or dirty without io, it gets another round
+            Some(false) => lru.inactive.push_back(page),
+            None => {
+                lru.tracked.remove(&page);
+            }
+        }
+    }
+
+    if freed != 0 {
+        log::debug!("[RECLAIM] Freed {} pages\n", freed);
+    }
+
+    freed
+}
+
+// the cold dirty shared file pages are written back, so they can be dropped later
+fn writeback() {
+    let processes = processes();
+    let inactive: Vec<PageRef> = lru().lock().inactive.iter().copied().collect();
+
+    for page in inactive {
+        if let Some(process) = find(&processes, page.owner) {
+            if let Some(pagemap) = process.borrow().pagemap.as_ref() {
+                pagemap.msync(VirtAddr::new(page.address), pmm::PAGE_SIZE as usize);
+            }
+        }
+    }
+}
+
+fn low_watermark() -> usize {
+    pmm::get().total_pages() / LOW_WATERMARK_DIVISOR
+}
+
+// wakes kreclaimd up if memory is running low
+pub fn check_pressure() {
+    if pmm::get().free_pages() < low_watermark() {
+        PRESSURE.notify();
+    }
+}
+
+extern "C" fn kreclaimd() -> ! {
+    loop {
+        let generation = PRESSURE.generation();
+        scan();
+        writeback();
+
+        let free = pmm::get().free_pages();
+        if free < low_watermark() {
+            let freed = reclaim(2 * low_watermark() - free, true);
+            if freed == 0 && swap::enabled() {
+                log::warning!("[RECLAIM] Memory is low and nothing could be freed\n");
+            }
+        }
+
+        PRESSURE.wait(generation, Some(timer_source::current_ns() + SCAN_INTERVAL_MS * 1_000_000));
+    }
+}
+
+// starts kreclaimd, without the scheduler pages are only reclaimed when allocations fail
+pub fn init() {
+    if scheduler::try_get().is_none() {
+        log::debug!("[RECLAIM] No scheduler, reclaiming only on failed allocations\n");
+        return;
+    }
+
+    let process = Process::new(String::from("kreclaimd"), 0, String::from("/"));
+    let thread = Thread::new(kreclaimd as u64, SelectorValues::KernelCs, process.clone());
+    process.borrow_mut().threads.push(thread.clone());
+    scheduler::get().enqueue(thread);
+}
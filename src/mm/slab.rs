@@ -3,7 +3,10 @@
 */
 
 use crate::arch::mm::pmm;
-use crate::serial;
+#[cfg(feature = "kasan")]
+use crate::mm::kasan;
+use crate::log;
+use crate::mm::vmalloc;
 use crate::utils::{bitmap, math};
 use core::alloc::GlobalAlloc;
 use core::mem::size_of;
@@ -104,7 +107,7 @@ struct Slab {
 
 impl Slab {
     unsafe fn new(parent: &mut Cache) -> *mut Slab {
-        serial::print!("hi\n");
+        log::debug!("[SLAB] creating a new slab\n");
         let slab_ptr: *mut Slab = pmm::get()
             .calloc(parent.pages_per_slab)
             .expect("Could not allocate pages for the new slab")
@@ -126,7 +129,7 @@ impl Slab {
         parent.slabs = slab_ptr;
         parent.slab_count += 1;
 
-        serial::print!("nah, now\n");
+        log::debug!("[SLAB] created a new slab\n");
         slab_ptr
     }
 
@@ -141,8 +144,8 @@ impl Slab {
             if !bitmap.is_set(i) {
                 bitmap.set(i);
                 self.free_objs -= 1;
-                serial::print!(
-                    "=== slab address:  {:#x}\n",
+                log::debug!(
+                    "[SLAB] slab address:  {:#x}\n",
                     self.data.offset((i * self.object_size) as isize) as u64
                 );
                 return self.data.offset((i * self.object_size) as isize);
@@ -195,7 +198,7 @@ impl<'a> SlabAllocator<'a> {
         let mut curr_cache = self.caches;
 
         while !curr_cache.is_null() {
-            serial::print!(
+            log::info!(
                 "[SLAB DUMP] Found a cache, object size of {}, slab count of {}\n",
                 (*curr_cache).object_size,
                 (*curr_cache).slab_count
@@ -218,22 +221,49 @@ pub unsafe fn init() {
     SLAB_ALLOCATOR.add_cache("8 bytes", 8);
 }
 
-unsafe impl<'a> GlobalAlloc for SlabAllocator<'a> {
-    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        if let Some(cache) = SLAB_ALLOCATOR.cache_for(layout.size()) {
-            serial::print!("alignment: {}\n", layout.align());
+impl<'a> SlabAllocator<'a> {
+    unsafe fn alloc_obj(&self, size: usize) -> *mut u8 {
+        if let Some(cache) = SLAB_ALLOCATOR.cache_for(size) {
             (*cache).alloc_obj()
         } else {
-            serial::print!("size: {}\n", layout.size());
-            panic!("Could not find a cache large enough to suffice the heap allocation");
+            // too big for every cache, it gets pages of its own
+            vmalloc::vmalloc(size).unwrap_or_else(|_| {
+                log::error!("[SLAB] Could not allocate {} bytes\n", size);
+                null_mut()
+            })
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        if let Some(cache) = SLAB_ALLOCATOR.cache_for(layout.size()) {
+    unsafe fn free_obj(&self, ptr: *mut u8, size: usize) {
+        if let Some(cache) = SLAB_ALLOCATOR.cache_for(size) {
             (*cache).free_obj(ptr)
         } else {
-            panic!("Tried do deallocate memory not allocated by the heap");
+            vmalloc::vfree(ptr)
         }
     }
 }
+
+unsafe impl<'a> GlobalAlloc for SlabAllocator<'a> {
+    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        #[cfg(feature = "kasan")]
+        if kasan::covers(layout.size()) {
+            let slot = self.alloc_obj(layout.size() + 2 * kasan::REDZONE_SIZE);
+            return kasan::on_alloc(slot, layout.size());
+        }
+
+        self.alloc_obj(layout.size())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        #[cfg(feature = "kasan")]
+        if kasan::covers(layout.size()) {
+            // objects only go back to the slab once they leave the quarantine
+            if let Some((slot, size)) = kasan::on_free(ptr, layout.size()) {
+                self.free_obj(slot, size + 2 * kasan::REDZONE_SIZE);
+            }
+            return;
+        }
+
+        self.free_obj(ptr, layout.size())
+    }
+}
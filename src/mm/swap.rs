@@ -0,0 +1,135 @@
+/*
+    Swapping anonymous pages out to disk, on the first partition the scan found with the
+    "Linux swap" type. The partition is split into page sized slots, slot 0 is left alone
+    since that's where mkswap puts its header.
+
+    A swapped out page keeps MMAPED in its pte, with SWAPPED set and the slot where the
+    physical address would be, so the page fault handler knows to read it back in. Which
+    pages go is decided by the lists in mm::reclaim
+*/
+
+use crate::arch::mm::pmm::{self, PhysAddr};
+use crate::error::{KError, KResult};
+use crate::fs::partitions::{self, Guid, Partition};
+use crate::log;
+use crate::utils::{bitmap::Bitmap, math::div_ceil};
+use alloc::sync::Arc;
+
+// where mkswap's "SWAPSPACE2" ends up in the first page
+const SIGNATURE_OFFSET: usize = 4086;
+const SIGNATURE: &[u8; 10] = b"SWAPSPACE2";
+
+static mut SWAP: Option<SwapArea> = None;
+
+struct SwapArea {
+    partition: Arc<Partition>,
+    slots: spin::Mutex<Bitmap>, // a set bit is a slot in use
+    slot_count: usize,
+}
+
+impl SwapArea {
+    fn offset(&self, slot: u64) -> u64 {
+        self.partition.start_lba * 512 + slot * pmm::PAGE_SIZE
+    }
+
+    fn alloc_slot(&self) -> Option<u64> {
+        let mut slots = self.slots.lock();
+        let slot = (1..self.slot_count).find(|slot| !slots.is_set(*slot))?;
+
+        slots.set(slot);
+        Some(slot as u64)
+    }
+
+    fn free_slot(&self, slot: u64) {
+        self.slots.lock().clear(slot as usize);
+    }
+}
+
+fn area() -> Option<&'static SwapArea> {
+    unsafe { SWAP.as_ref() }
+}
+
+pub fn enabled() -> bool {
+    area().is_some()
+}
+
+/*
+    Writes page out to a new slot, returning it. The caller has to make sure nothing
+    writes to the page until this is done
+*/
+pub fn write_page(page: PhysAddr) -> KResult<u64> {
+    let area = area().ok_or(KError::ENOSPC)?;
+    let slot = area.alloc_slot().ok_or(KError::ENOSPC)?;
+    let buffer = page.higher_half().as_ptr::<u8>();
+
+    match area.partition.device.write(area.offset(slot), pmm::PAGE_SIZE as usize, buffer) {
+        Ok(_) => Ok(slot),
+        Err(err) => {
+            area.free_slot(slot);
+            Err(err)
+        }
+    }
+}
+
+// reads slot back into page, the slot is free after this
+pub fn read_page(slot: u64, page: PhysAddr) -> KResult<()> {
+    let area = area().ok_or(KError::EINVAL)?;
+    let buffer = page.higher_half().as_mut_ptr::<u8>();
+
+    let result = area.partition.device.read(area.offset(slot), pmm::PAGE_SIZE as usize, buffer);
+    area.free_slot(slot);
+    result.map(|_| ())
+}
+
+// for pages that are unmapped while swapped out
+pub fn free(slot: u64) {
+    if let Some(area) = area() {
+        area.free_slot(slot);
+    }
+}
+
+pub fn free_slots() -> usize {
+    area().map_or(0, |area| {
+        let slots = area.slots.lock();
+        (1..area.slot_count).filter(|slot| !slots.is_set(*slot)).count()
+    })
+}
+
+pub fn init() {
+    let partition = match partitions::partitions()
+        .iter()
+        .find(|partition| partition.type_guid == Guid::LINUX_SWAP)
+    {
+        Some(partition) => partition.clone(),
+        None => return,
+    };
+
+    let slot_count = (partition.size() / pmm::PAGE_SIZE) as usize;
+    if slot_count < 2 {
+        log::warning!("[SWAP] {} is too small to swap to\n", partition.name);
+        return;
+    }
+
+    let mut header = alloc::vec![0u8; pmm::PAGE_SIZE as usize];
+    let read = partition.device.read(partition.start_lba * 512, header.len(), header.as_mut_ptr());
+    if read.is_err() || &header[SIGNATURE_OFFSET..] != SIGNATURE {
+        log::warning!("[SWAP] {} has no swap signature, using it anyway\n", partition.name);
+    }
+
+    let mut slots = Bitmap::new(div_ceil(slot_count, 8));
+    slots.set(0);
+
+    log::info!(
+        "[SWAP] Swapping to {}, {} KiB\n",
+        partition.name,
+        (slot_count - 1) * pmm::PAGE_SIZE as usize / 1024
+    );
+
+    unsafe {
+        SWAP = Some(SwapArea {
+            partition,
+            slots: spin::Mutex::new(slots),
+            slot_count,
+        });
+    }
+}
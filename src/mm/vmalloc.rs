@@ -0,0 +1,178 @@
+/*
+    Kernel memory that's contiguous in virtual memory but not in physical memory, for big
+    buffers nothing has to do dma to. The pages come from the pmm one at a time and are
+    mapped next to each other in a region of the higher half of its own, so a buffer never
+    needs a run of free frames. Every area is followed by a guard page that's never mapped,
+    running off its end faults instead of trampling the next one.
+
+    The areas are kept in a fixed table sorted by address, the heap hands out what's too
+    big for the slab from here so nothing here can use the heap
+*/
+
+use crate::arch::mm::pmm::{self, PhysAddr};
+use crate::error::{KError, KResult};
+use crate::log;
+use crate::mm::vmm::{self, PageFlags, VirtAddr};
+use crate::utils::math::div_ceil;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+// one pml4 entry, which is set up at boot so every address space shares the region
+const VMALLOC_BASE: u64 = 0xffffc90000000000;
+const VMALLOC_END: u64 = VMALLOC_BASE + (1 << 39);
+const MAX_AREAS: usize = 512;
+
+static AREAS: spin::Mutex<Areas> = spin::Mutex::new(Areas {
+    list: [Area { start: 0, pages: 0 }; MAX_AREAS],
+    len: 0,
+});
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy)]
+struct Area {
+    start: u64,
+    pages: usize, // without the guard page
+}
+
+impl Area {
+    // past the guard page
+    fn end(&self) -> u64 {
+        self.start + (self.pages as u64 + 1) * pmm::PAGE_SIZE
+    }
+}
+
+struct Areas {
+    list: [Area; MAX_AREAS],
+    len: usize,
+}
+
+impl Areas {
+    // the lowest gap that fits size bytes, with the index an area there is inserted at
+    fn find_gap(&self, size: u64) -> Option<(usize, u64)> {
+        let mut start = VMALLOC_BASE;
+
+        for (i, area) in self.list[..self.len].iter().enumerate() {
+            if area.start - start >= size {
+                return Some((i, start));
+            }
+            start = area.end();
+        }
+
+        (VMALLOC_END - start >= size).then(|| (self.len, start))
+    }
+
+    fn insert(&mut self, index: usize, area: Area) {
+        self.list.copy_within(index..self.len, index + 1);
+        self.list[index] = area;
+        self.len += 1;
+    }
+
+    fn position(&self, start: u64) -> Option<usize> {
+        self.list[..self.len].iter().position(|area| area.start == start)
+    }
+
+    fn remove(&mut self, index: usize) -> Area {
+        let area = self.list[index];
+        self.list.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+        area
+    }
+}
+
+// after the vmm
+pub fn init() {
+    // creates the pdp the region lives under, new address spaces copy the pml4 entry
+    vmm::get().map_page(
+        VirtAddr::new(VMALLOC_BASE),
+        PhysAddr::new(0),
+        PageFlags::empty(),
+        false,
+    );
+
+    INITIALIZED.store(true, Ordering::Release);
+    log::info!(
+        "[VMALLOC] {} GiB of address space at {:#x}\n",
+        (VMALLOC_END - VMALLOC_BASE) >> 30,
+        VMALLOC_BASE
+    );
+}
+
+// unmaps the first pages of an area and frees their frames
+fn unmap(start: u64, pages: usize) {
+    for page in 0..pages as u64 {
+        let address = VirtAddr::new(start + page * pmm::PAGE_SIZE);
+
+        if let Some(frame) = vmm::get().unmap_page(address) {
+            pmm::get().free(frame.higher_half().as_mut_ptr(), 1);
+        }
+    }
+
+    vmm::get().shootdown(VirtAddr::new(start), pages);
+}
+
+// zero filled, ENOMEM before init and once the region or the table is full
+pub fn vmalloc(len: usize) -> KResult<*mut u8> {
+    if len == 0 {
+        return Err(KError::EINVAL);
+    }
+    if !INITIALIZED.load(Ordering::Acquire) {
+        return Err(KError::ENOMEM);
+    }
+
+    let pages = div_ceil(len, pmm::PAGE_SIZE as usize);
+    let mut areas = AREAS.lock();
+    if areas.len == MAX_AREAS {
+        return Err(KError::ENOMEM);
+    }
+
+    let (index, start) = areas
+        .find_gap((pages as u64 + 1) * pmm::PAGE_SIZE)
+        .ok_or(KError::ENOMEM)?;
+    let flags = PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::NX;
+
+    for page in 0..pages {
+        let frame = match pmm::get().calloc(1) {
+            Ok(frame) => frame,
+            Err(err) => {
+                unmap(start, page);
+                return Err(err);
+            }
+        };
+
+        let address = VirtAddr::new(start + page as u64 * pmm::PAGE_SIZE);
+        vmm::get().map_page(address, frame, flags, false);
+    }
+
+    areas.insert(index, Area { start, pages });
+    Ok(start as *mut u8)
+}
+
+// addr must be what vmalloc returned
+pub fn vfree(addr: *mut u8) {
+    let mut areas = AREAS.lock();
+    let area = match areas.position(addr as u64) {
+        Some(index) => areas.remove(index),
+        None => {
+            log::error!("[VMALLOC] Tried to free {:#x}, which isn't an area\n", addr as u64);
+            return;
+        }
+    };
+
+    unmap(area.start, area.pages);
+}
+
+pub fn contains(addr: *const u8) -> bool {
+    (VMALLOC_BASE..VMALLOC_END).contains(&(addr as u64))
+}
+
+// the size of the area starting at addr, in bytes
+pub fn size(addr: *const u8) -> Option<usize> {
+    let areas = AREAS.lock();
+    let index = areas.position(addr as u64)?;
+    Some(areas.list[index].pages * pmm::PAGE_SIZE as usize)
+}
+
+// how many pages all the areas have mapped
+pub fn used_pages() -> usize {
+    let areas = AREAS.lock();
+    areas.list[..areas.len].iter().map(|area| area.pages).sum()
+}
@@ -1,15 +1,33 @@
 use core::ops::RangeBounds;
 
 use crate::arch::mm::pmm::{self, PhysAddr};
-use crate::arch::{cpu, interrupts};
+use crate::arch::cpuid::{self, Features};
+use crate::arch::{cpu, interrupts, ipi};
 use crate::proc::scheduler;
-use crate::utils::math::div_ceil;
-use crate::{serial, vfs};
+use crate::error::{KError, KResult};
+use crate::mm::frame::{self, FrameFlags};
+use crate::mm::{aslr, oom, swap};
+use crate::{syscall, vdso};
+use crate::utils::math::{div_ceil, round_up};
+use crate::{crashdump, log, trace, vfs};
 use core::arch::asm;
 use alloc::vec::Vec;
+use stivale_boot::v2::{StivaleMemoryMapEntry, StivaleMemoryMapEntryType};
 
 static mut VIRTUAL_MEMORY_MANAGER: Option<VirtualMemManager> = None;
 pub const KERNEL_BASE: u64 = 0xffffffff80000000;
+pub const HUGE_PAGE_SIZE: u64 = 0x200000;
+
+// defined in linker.ld, all of them are page aligned
+#[allow(non_upper_case_globals)]
+extern "C" {
+    static text_start: u8;
+    static text_end: u8;
+    static rodata_start: u8;
+    static rodata_end: u8;
+    static data_start: u8;
+    static data_end: u8;
+}
 
 bitflags::bitflags! {
     pub struct PageFlags: u64 {
@@ -18,9 +36,20 @@ bitflags::bitflags! {
         const USERMODE    = 1 << 2;
         const WT          = 1 << 3;
         const UNCACHEABLE = 1 << 4;
+        const ACCESSED    = 1 << 5;
+        const DIRTY       = 1 << 6;
+        const HUGE        = 1 << 7;
 
         // bits that are ignored by the cpu but used by griffin's vmm
         const MMAPED = 1 << 9;
+        const SWAPPED = 1 << 10; // not present, the address bits hold a swap slot
+        /*
+            The frame isn't the address space's, e.g. a shm object's. It's only freed with
+            it if it's counted and this was the last reference (see mm::frame)
+        */
+        const SHARED_FRAME = 1 << 11;
+        // read only until it's written, then it's copied if anyone else still has it
+        const COPY_ON_WRITE = 1 << 52;
         // ==========================
 
         const NX          = 1 << 63;
@@ -38,6 +67,71 @@ bitflags::bitflags! {
         const PRIVATE   = 0x0002;
         const FIXED     = 0x0010;
         const ANONYMOUS = 0x1000;
+        // back anonymous mappings with 2MiB pages wherever they cover a whole aligned block
+        const HUGE      = 0x40000;
+    }
+}
+
+/*
+    How the cpu caches a page, picked by the pat entry that the page's WT and UNCACHEABLE
+    bits select. The pat is programmed so that all four modes are reachable without its
+    own bit, which is HUGE's bit in a pte. Without a pat, WRITE_COMBINING ends up UC
+*/
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CacheMode {
+    WriteBack,
+    WriteThrough,
+    Uncacheable,
+    WriteCombining, // writes are buffered and sent in bursts, for framebuffers
+}
+
+// what madvise is told about a range, numbered like linux's MADV_*
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Advice {
+    Normal,
+    WillNeed, // fault it all in now
+    DontNeed, // drop it, what's faulted in again is zeroes or the file's contents
+    Free,     // private anonymous pages may be dropped unless they're written again first
+}
+
+impl Advice {
+    pub fn from_u64(advice: u64) -> Option<Advice> {
+        match advice {
+            0 => Some(Advice::Normal),
+            3 => Some(Advice::WillNeed),
+            4 => Some(Advice::DontNeed),
+            8 => Some(Advice::Free),
+            _ => None,
+        }
+    }
+}
+
+// IA32_PAT, one memory type per byte: WB, WT, UC, WC, and the same again for the pat bit
+pub const PAT: u64 = 0x0100_0406_0100_0406;
+
+impl PageFlags {
+    const CACHE_BITS: PageFlags = PageFlags::from_bits_truncate(
+        PageFlags::WT.bits() | PageFlags::UNCACHEABLE.bits(),
+    );
+
+    pub fn with_cache(self, mode: CacheMode) -> Self {
+        let bits = match mode {
+            CacheMode::WriteBack => PageFlags::empty(),
+            CacheMode::WriteThrough => PageFlags::WT,
+            CacheMode::Uncacheable => PageFlags::UNCACHEABLE,
+            CacheMode::WriteCombining => PageFlags::CACHE_BITS,
+        };
+
+        (self - PageFlags::CACHE_BITS) | bits
+    }
+
+    pub fn cache_mode(self) -> CacheMode {
+        match (self.contains(PageFlags::WT), self.contains(PageFlags::UNCACHEABLE)) {
+            (false, false) => CacheMode::WriteBack,
+            (true, false) => CacheMode::WriteThrough,
+            (false, true) => CacheMode::Uncacheable,
+            (true, true) => CacheMode::WriteCombining,
+        }
     }
 }
 
@@ -135,6 +229,44 @@ impl PageMapping {
     pub fn is_non_exec(&self) -> bool {
         self.0 & PageFlags::NX.bits() != 0
     }
+
+    pub fn is_accessed(&self) -> bool {
+        self.0 & PageFlags::ACCESSED.bits() != 0
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.0 & PageFlags::DIRTY.bits() != 0
+    }
+
+    pub fn is_huge(&self) -> bool {
+        self.0 & PageFlags::HUGE.bits() != 0
+    }
+
+    pub fn is_shared_frame(&self) -> bool {
+        self.0 & PageFlags::SHARED_FRAME.bits() != 0
+    }
+
+    pub fn is_copy_on_write(&self) -> bool {
+        self.0 & PageFlags::COPY_ON_WRITE.bits() != 0
+    }
+
+    // everything but the address
+    pub fn flags(&self) -> PageFlags {
+        PageFlags::from_bits_truncate(self.0 & !0x000ffffffffff000)
+    }
+
+    pub fn is_swapped(&self) -> bool {
+        !self.is_present() && self.0 & PageFlags::SWAPPED.bits() != 0
+    }
+
+    pub fn swap_slot(&self) -> u64 {
+        self.phys_addr().as_u64() / pmm::PAGE_SIZE
+    }
+
+    // what a page swapped out to slot gets in its pte
+    pub fn swapped(slot: u64) -> Self {
+        PageMapping(slot * pmm::PAGE_SIZE | (PageFlags::MMAPED | PageFlags::SWAPPED).bits())
+    }
 }
 
 pub struct VirtMemoryRange {
@@ -173,6 +305,19 @@ impl VirtMemoryRange {
         self.base.as_u64() + self.length as u64
     }
 
+    pub fn prot(&self) -> MapProt {
+        self.prot
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    // the file it maps, None for anonymous mappings
+    pub fn fd(&self) -> Option<&vfs::FileDescription> {
+        self.fd.as_ref()
+    }
+
     pub fn is_anon_map(&self) -> bool {
         self.flags.contains(MapFlags::ANONYMOUS)
     }
@@ -184,11 +329,46 @@ impl VirtMemoryRange {
     pub fn is_shared_map(&self) -> bool {
         self.flags.contains(MapFlags::SHARED)
     }
+
+    // whether the 2MiB block containing address can be mapped with a single huge page
+    pub fn fits_huge_page(&self, address: VirtAddr) -> bool {
+        let block = address.as_u64() & !(HUGE_PAGE_SIZE - 1);
+
+        self.is_anon_map()
+            && self.flags.contains(MapFlags::HUGE)
+            && block >= self.start()
+            && block + HUGE_PAGE_SIZE <= self.end()
+    }
+
+    // for shared mappings of files that live in memory, the frame to map at address
+    pub fn backing_frame(&self, address: VirtAddr) -> Option<PhysAddr> {
+        if !self.is_shared_map() {
+            return None;
+        }
+
+        let fd = self.fd.as_ref()?;
+        let offset = self.offset + (address.as_u64() - self.start()) as usize;
+        fd.fs.page(fd.file_index, offset).ok().flatten()
+    }
+
+    // the part of this range between start and end, with the file offset adjusted
+    fn slice(&self, start: u64, end: u64) -> Self {
+        VirtMemoryRange::new(
+            VirtAddr::new(start),
+            (end - start) as usize,
+            self.prot,
+            self.flags,
+            self.offset + (start - self.start()) as usize,
+            self.fd.clone(),
+        )
+    }
 }
 
 pub struct VirtualMemManager {
     pub pagemap: PhysAddr,
     ranges: Vec<VirtMemoryRange>,
+    layout: aslr::Layout,
+    map_limit: u64, // how many bytes the ranges may add up to, the owner's limit
 }
 
 impl VirtualMemManager {
@@ -197,22 +377,72 @@ impl VirtualMemManager {
             return VirtualMemManager {
                 pagemap: PhysAddr::new(0),
                 ranges: alloc::vec![],
+                layout: aslr::Layout::fixed(),
+                map_limit: u64::MAX,
             };
         }
 
         let pml4 = pmm::get().calloc(1).expect("Could not allocate a new pml4");
         let pml4_ptr: *mut u64 = pml4.higher_half().as_mut_ptr();
 
+        // the higher half (direct map and kernel) is the same in every address space
         unsafe {
-            let kernel_vmm_ptr = get().pagemap.as_mut_ptr::<u64>();
-            *pml4_ptr.offset(256) = *kernel_vmm_ptr.offset(256);
-            *pml4_ptr.offset(511) = *kernel_vmm_ptr.offset(511);
+            let kernel_vmm_ptr = get().pagemap.higher_half().as_mut_ptr::<u64>();
+            for i in 256..512 {
+                *pml4_ptr.offset(i) = *kernel_vmm_ptr.offset(i);
+            }
         }
 
-        VirtualMemManager {
+        let vmm = VirtualMemManager {
             pagemap: pml4,
             ranges: alloc::vec![],
+            layout: aslr::Layout::new(),
+            map_limit: u64::MAX,
+        };
+
+        // the clock data, it's not in a range so munmap can't take it away
+        if let Some(page) = vdso::data_page() {
+            let flags = PageFlags::PRESENT
+                | PageFlags::USERMODE
+                | PageFlags::NX
+                | PageFlags::SHARED_FRAME;
+            vmm.map_page(VirtAddr::new(vdso::DATA_ADDRESS), page, flags, false);
         }
+
+        vmm
+    }
+
+    // what's mapped already stays when the limit goes below it, only new ranges are refused
+    pub fn set_map_limit(&mut self, bytes: u64) {
+        self.map_limit = bytes;
+    }
+
+    pub fn mapped_bytes(&self) -> u64 {
+        self.ranges.iter().map(|range| range.length as u64).sum()
+    }
+
+    // where a position independent executable should be loaded
+    pub fn load_base(&self) -> VirtAddr {
+        VirtAddr::new(self.layout.load_base)
+    }
+
+    // maps the initial stack of a thread below the stack top, returns the stack pointer
+    pub fn map_stack(&mut self, size: usize) -> KResult<VirtAddr> {
+        let size = round_up(size, pmm::PAGE_SIZE as usize) as u64;
+        let bottom = self.layout.stack_top - size;
+
+        let stack = self.mmap(
+            Some(VirtAddr::new(bottom)),
+            size,
+            MapProt::READ | MapProt::WRITE,
+            MapFlags::PRIVATE | MapFlags::ANONYMOUS | MapFlags::FIXED,
+            None,
+            0,
+        )?;
+
+        // the next thread's stack goes below this one, with a guard page in between
+        self.layout.stack_top = bottom - pmm::PAGE_SIZE;
+        Ok(VirtAddr::new(stack.as_u64() + size))
     }
 
     pub fn mmap(
@@ -223,9 +453,12 @@ impl VirtualMemManager {
         flags: MapFlags,
         fd: Option<vfs::FileDescription>,
         offset: usize,
-    ) {
+    ) -> KResult<VirtAddr> {
         if address.is_none() && flags.contains(MapFlags::FIXED) {
-            return; // TODO: hard error
+            return Err(KError::EINVAL);
+        }
+        if self.mapped_bytes().saturating_add(length) > self.map_limit {
+            return Err(KError::ENOMEM);
         }
 
         let mut range_address: VirtAddr;
@@ -241,12 +474,14 @@ impl VirtualMemManager {
                     if (new_range_start > entry.start() && new_range_start < entry.end())
                         || (new_range_end > entry.start() && new_range_end < entry.end())
                     {
-                        range_address = self.get_free_range(length as usize);
+                        range_address = self
+                            .get_free_range(length as usize)
+                            .ok_or(KError::ENOMEM)?;
                     }
                 }
             }
         } else {
-            range_address = self.get_free_range(length as usize);
+            range_address = self.get_free_range(length as usize).ok_or(KError::ENOMEM)?;
         }
 
         let new_range_start = range_address.as_u64();
@@ -265,11 +500,13 @@ impl VirtualMemManager {
         let new_entry =
             VirtMemoryRange::new(range_address, length as usize, prot, flags, offset, fd);
         self.ranges.push(new_entry);
+
+        Ok(range_address)
     }
 
     pub fn get_range(&self, address: VirtAddr) -> Option<&VirtMemoryRange> {
         for entry in self.ranges.iter() {
-            if address.as_u64() > entry.start() && address.as_u64() < entry.end() {
+            if address.as_u64() >= entry.start() && address.as_u64() < entry.end() {
                 return Some(entry);
             }
         }
@@ -277,8 +514,662 @@ impl VirtualMemManager {
         None
     }
 
-    pub fn get_free_range(&self, length: usize) -> VirtAddr {
-        todo!()
+    // whether userspace may access address, through a mmaped range or a page mapped for it
+    pub fn user_accessible(&self, address: VirtAddr, write: bool) -> bool {
+        if let Some(range) = self.get_range(address) {
+            let needed = if write { MapProt::WRITE } else { MapProt::READ };
+            return range.prot.contains(needed);
+        }
+
+        match self.get_pte(address) {
+            Some(pte) => {
+                let mapping = PageMapping::new(unsafe { *pte });
+                mapping.is_present() && mapping.is_usermode() && (!write || mapping.is_writable())
+            }
+            None => false,
+        }
+    }
+
+    // writes the dirty pages of shared file mappings in the given range back to their files
+    pub fn msync(&self, address: VirtAddr, length: usize) {
+        let start = address.as_u64() & !(pmm::PAGE_SIZE - 1);
+        let end = address.as_u64() + length as u64;
+
+        for range in self.ranges.iter() {
+            if !range.is_shared_map() || range.fd.is_none() {
+                continue;
+            }
+
+            let sync_start = start.max(range.start());
+            let sync_end = end.min(range.end());
+
+            for page in (sync_start..sync_end).step_by(pmm::PAGE_SIZE as usize) {
+                if let Some(pte) = self.get_pte(VirtAddr::new(page)) {
+                    self.writeback_page(range, page, pte);
+                }
+            }
+        }
+    }
+
+    // writes the frame mapped at page of a shared file mapping to its place in the file
+    fn write_to_file(&self, range: &VirtMemoryRange, page: u64, frame: PhysAddr) -> KResult<()> {
+        let fd = range.fd.as_ref().ok_or(KError::EINVAL)?;
+        let page_offset = (page - range.start()) as usize;
+        let cnt = (pmm::PAGE_SIZE as usize).min(range.length - page_offset);
+
+        let result = vfs::pwrite(
+            fd,
+            frame.higher_half().as_ptr::<u8>(),
+            cnt,
+            range.offset + page_offset,
+        );
+
+        if let Err(err) = result {
+            log::error!("[VMM] Could not write back the page at {:#x}: {}\n", page, err);
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    fn writeback_page(&self, range: &VirtMemoryRange, page: u64, pte: *mut u64) {
+        let mapping = PageMapping::new(unsafe { *pte });
+
+        // a frame of the file itself has nothing to be written back to
+        if !mapping.is_present() || !mapping.is_dirty() || mapping.is_shared_frame() {
+            return;
+        }
+
+        // the page stays dirty, so the next msync tries again
+        if self.write_to_file(range, page, mapping.phys_addr()).is_err() {
+            return;
+        }
+
+        unsafe {
+            *pte = mapping.as_u64() & !PageFlags::DIRTY.bits();
+        }
+        self.shootdown(VirtAddr::new(page), 1);
+    }
+
+    // shared file mappings are written back before their pages are freed
+    pub fn munmap(&mut self, address: VirtAddr, length: usize) {
+        let start = address.as_u64() & !(pmm::PAGE_SIZE - 1);
+        let end = round_up((address.as_u64() + length as u64) as usize, pmm::PAGE_SIZE as usize) as u64;
+
+        self.msync(VirtAddr::new(start), (end - start) as usize);
+
+        let ranges = core::mem::take(&mut self.ranges);
+        for range in ranges {
+            if range.end() <= start || range.start() >= end {
+                self.ranges.push(range);
+                continue;
+            }
+
+            let unmap_start = start.max(range.start());
+            let unmap_end = end.min(range.end());
+
+            let mut block = unmap_start & !(HUGE_PAGE_SIZE - 1);
+            while block < unmap_end {
+                if let Some(pde) = self.get_huge_pde(VirtAddr::new(block)) {
+                    if block >= unmap_start && block + HUGE_PAGE_SIZE <= unmap_end {
+                        release_huge_page(PageMapping::new(unsafe { *pde }));
+
+                        unsafe {
+                            *pde = 0;
+                        }
+                    } else {
+                        // only part of it goes away, so the rest is kept as 4KiB pages
+                        self.split_huge_page(pde);
+                    }
+                }
+
+                block += HUGE_PAGE_SIZE;
+            }
+
+            for page in (unmap_start..unmap_end).step_by(pmm::PAGE_SIZE as usize) {
+                if let Some(pte) = self.get_pte(VirtAddr::new(page)) {
+                    let mapping = PageMapping::new(unsafe { *pte });
+
+                    if mapping.is_present() {
+                        release_frame(mapping);
+                    } else if mapping.is_swapped() {
+                        swap::free(mapping.swap_slot());
+                    }
+
+                    unsafe {
+                        *pte = 0;
+                    }
+                }
+            }
+
+            self.shootdown(
+                VirtAddr::new(unmap_start),
+                ((unmap_end - unmap_start) / pmm::PAGE_SIZE) as usize,
+            );
+
+            // keep whatever is left on each side
+            if range.start() < unmap_start {
+                self.ranges.push(range.slice(range.start(), unmap_start));
+            }
+
+            if range.end() > unmap_end {
+                self.ranges.push(range.slice(unmap_end, range.end()));
+            }
+        }
+    }
+
+    /*
+        Tells the vmm how a page aligned part of the mapped ranges is going to be used, see
+        Advice. Nothing is done and ENOMEM is returned if some of it isn't mapped, EINVAL if
+        the advice doesn't apply to one of the ranges
+    */
+    pub fn madvise(&self, address: VirtAddr, length: usize, advice: Advice) -> KResult<()> {
+        let start = address.as_u64();
+        let end = round_up((start + length as u64) as usize, pmm::PAGE_SIZE as usize) as u64;
+
+        if start % pmm::PAGE_SIZE != 0 || end < start {
+            return Err(KError::EINVAL);
+        }
+
+        let mut ranges: Vec<&VirtMemoryRange> = self
+            .ranges
+            .iter()
+            .filter(|range| range.start() < end && range.end() > start)
+            .collect();
+        ranges.sort_by_key(|range| range.start());
+
+        // no holes, from start to end
+        let mut covered = start;
+        for range in ranges.iter() {
+            if range.start() > covered {
+                return Err(KError::ENOMEM);
+            }
+            covered = covered.max(range.end());
+        }
+        if covered < end {
+            return Err(KError::ENOMEM);
+        }
+
+        if advice == Advice::Free
+            && ranges.iter().any(|range| !range.is_anon_map() || !range.is_private_map())
+        {
+            return Err(KError::EINVAL);
+        }
+
+        for range in ranges {
+            let (first, last) = (start.max(range.start()), end.min(range.end()));
+
+            match advice {
+                Advice::Normal => {}
+                Advice::WillNeed => {
+                    for page in (first..last).step_by(pmm::PAGE_SIZE as usize) {
+                        // out of memory it stops there, what's faulted in stays
+                        self.populate(VirtAddr::new(page), || pmm::get().calloc(1).ok())
+                            .map_err(|_| KError::EAGAIN)?;
+                    }
+                }
+                Advice::DontNeed => self.drop_pages(range, first, last),
+                Advice::Free => self.mark_free(range, first, last),
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+        For DONTNEED: unmaps the pages of range between start and end, so they're faulted
+        in again. Shared file mappings are written back first. Shared anonymous ones have
+        nothing to come back from, so they're left alone. A huge page that's only partly
+        inside is zeroed there instead, faulting it in again would map a huge page over the
+        rest of it
+    */
+    fn drop_pages(&self, range: &VirtMemoryRange, start: u64, end: u64) {
+        if range.is_anon_map() && range.is_shared_map() {
+            return;
+        }
+
+        self.msync(VirtAddr::new(start), (end - start) as usize);
+
+        let mut block = start & !(HUGE_PAGE_SIZE - 1);
+        while block < end {
+            if let Some(pde) = self.get_huge_pde(VirtAddr::new(block)) {
+                if block >= start && block + HUGE_PAGE_SIZE <= end {
+                    self.split_huge_page(pde);
+                } else {
+                    let (first, last) = (start.max(block), end.min(block + HUGE_PAGE_SIZE));
+                    let frame = PageMapping::new(unsafe { *pde }).phys_addr();
+                    unsafe {
+                        frame
+                            .higher_half()
+                            .as_mut_ptr::<u8>()
+                            .add((first - block) as usize)
+                            .write_bytes(0, (last - first) as usize);
+                    }
+                }
+            }
+
+            block += HUGE_PAGE_SIZE;
+        }
+
+        for page in (start..end).step_by(pmm::PAGE_SIZE as usize) {
+            let pte = match self.get_pte(VirtAddr::new(page)) {
+                Some(pte) => pte,
+                None => continue,
+            };
+            let mapping = PageMapping::new(unsafe { *pte });
+
+            if mapping.is_present() {
+                release_frame(mapping);
+            } else if mapping.is_swapped() {
+                swap::free(mapping.swap_slot());
+            } else {
+                continue;
+            }
+
+            unsafe {
+                *pte = (PageFlags::from(range.prot) | PageFlags::MMAPED).bits();
+            }
+        }
+
+        self.shootdown(VirtAddr::new(start), ((end - start) / pmm::PAGE_SIZE) as usize);
+    }
+
+    /*
+        For FREE: the pages between start and end look clean and unused, so evict drops
+        them as if they were still all zeroes, unless a write makes them dirty again first.
+        Swapped out ones are dropped right away. Huge pages are never evicted, they stay
+    */
+    fn mark_free(&self, range: &VirtMemoryRange, start: u64, end: u64) {
+        for page in (start..end).step_by(pmm::PAGE_SIZE as usize) {
+            let pte = match self.get_pte(VirtAddr::new(page)) {
+                Some(pte) => pte,
+                None => continue,
+            };
+            let mapping = PageMapping::new(unsafe { *pte });
+
+            if mapping.is_swapped() {
+                swap::free(mapping.swap_slot());
+                unsafe {
+                    *pte = (PageFlags::from(range.prot) | PageFlags::MMAPED).bits();
+                }
+            } else if mapping.is_present() && mapping.is_dirty() {
+                let cleared = PageFlags::DIRTY | PageFlags::ACCESSED;
+                unsafe {
+                    *pte = mapping.as_u64() & !cleared.bits();
+                }
+            }
+        }
+
+        self.shootdown(VirtAddr::new(start), ((end - start) / pmm::PAGE_SIZE) as usize);
+    }
+
+    /*
+        Frees the whole lower half: the frames backing the mapped ranges (shared file
+        mappings are written back first) and every page table, including the pml4.
+        Pages that don't belong to any range are not owned by the address space,
+        so they are left alone. Must not be called on the active pagemap
+    */
+    pub fn destroy(&mut self) {
+        if self.pagemap.as_u64() == get().pagemap.as_u64() {
+            panic!("Tried to destroy the kernel's address space");
+        }
+
+        for range in self.ranges.iter() {
+            self.msync(VirtAddr::new(range.start()), range.length);
+        }
+
+        let entries = |table: PhysAddr| -> *mut u64 { table.higher_half().as_mut_ptr() };
+        let pml4 = entries(self.pagemap);
+
+        // the higher half is shared with the kernel
+        for i in 0..256u64 {
+            let pml4e = unsafe { *pml4.offset(i as isize) };
+            if pml4e & PageFlags::PRESENT.bits() == 0 {
+                continue;
+            }
+
+            let pdp = PhysAddr::new(pml4e).remove_flags();
+            for j in 0..512u64 {
+                let pdpe = unsafe { *entries(pdp).offset(j as isize) };
+                if pdpe & PageFlags::PRESENT.bits() == 0 {
+                    continue;
+                }
+
+                let pd = PhysAddr::new(pdpe).remove_flags();
+                for k in 0..512u64 {
+                    let pde = unsafe { *entries(pd).offset(k as isize) };
+                    if pde & PageFlags::PRESENT.bits() == 0 {
+                        continue;
+                    }
+
+                    let pt = PhysAddr::new(pde).remove_flags();
+
+                    if pde & PageFlags::HUGE.bits() != 0 {
+                        if self.get_range(VirtAddr::new(i << 39 | j << 30 | k << 21)).is_some() {
+                            release_huge_page(PageMapping::new(pde));
+                        }
+
+                        continue;
+                    }
+
+                    for l in 0..512u64 {
+                        let pte = PageMapping::new(unsafe { *entries(pt).offset(l as isize) });
+                        let virt_addr = VirtAddr::new(i << 39 | j << 30 | k << 21 | l << 12);
+
+                        if pte.is_present() && self.get_range(virt_addr).is_some() {
+                            release_frame(pte);
+                        } else if pte.is_swapped() {
+                            swap::free(pte.swap_slot());
+                        }
+                    }
+
+                    pmm::get().free(pt.higher_half().as_mut_ptr(), 1);
+                }
+
+                pmm::get().free(pd.higher_half().as_mut_ptr(), 1);
+            }
+
+            pmm::get().free(pdp.higher_half().as_mut_ptr(), 1);
+        }
+
+        pmm::get().free(self.pagemap.higher_half().as_mut_ptr(), 1);
+
+        self.ranges.clear();
+        self.pagemap = PhysAddr::new(0);
+    }
+
+    /*
+        Unmaps the page at virt_addr and frees its frame, if it can be brought back later.
+        Clean pages are just dropped: anonymous ones are still all zeroes and file ones are
+        read again. Dirty shared file pages are written back to their file, the other dirty
+        ones go to swap. Both need the disk, so without io they're kept. Pages used since
+        their accessed bit was last cleared are kept too. Returns whether it was freed
+    */
+    pub fn evict(&self, virt_addr: VirtAddr, io: bool) -> bool {
+        let range = match self.get_range(virt_addr) {
+            // huge page ranges would get a huge page mapped over the evicted ones
+            Some(range) if !range.flags.contains(MapFlags::HUGE) => range,
+            _ => return false,
+        };
+
+        // shared anonymous pages have no file to go back to
+        if range.is_anon_map() && range.is_shared_map() {
+            return false;
+        }
+
+        // huge pages don't have a pte
+        let pte = match self.get_pte(virt_addr) {
+            Some(pte) => pte,
+            None => return false,
+        };
+        let mapping = PageMapping::new(unsafe { *pte });
+
+        if !mapping.is_present() || mapping.is_shared_frame() || mapping.is_accessed() {
+            return false;
+        }
+
+        // another address space still has it copy on write
+        if frame::refcount(mapping.phys_addr()) > 1 {
+            return false;
+        }
+
+        if mapping.is_dirty() && !io {
+            return false;
+        }
+
+        // unmapped first, so nothing changes it while it's being written out
+        unsafe {
+            *pte = (PageFlags::from(range.prot) | PageFlags::MMAPED).bits();
+        }
+        self.shootdown(virt_addr, 1);
+
+        if mapping.is_dirty() {
+            let result = if range.is_shared_map() {
+                self.write_to_file(range, virt_addr.as_u64(), mapping.phys_addr())
+            } else {
+                swap::write_page(mapping.phys_addr())
+                    .map(|slot| unsafe { *pte = PageMapping::swapped(slot).as_u64() })
+            };
+
+            // out of swap or the write failed, put it back
+            if result.is_err() {
+                unsafe {
+                    *pte = mapping.as_u64();
+                }
+                return false;
+            }
+        }
+
+        release_frame(mapping);
+        true
+    }
+
+    /*
+        For fork: maps the private page at virt_addr into other at the same address, and
+        makes both read only until one of them writes to it. Only counted frames can be
+        shared like this, shared mappings are shared anyway
+    */
+    pub fn share_copy_on_write(
+        &self,
+        virt_addr: VirtAddr,
+        other: &VirtualMemManager,
+    ) -> KResult<()> {
+        // only the 4KiB page at virt_addr is shared, so a huge page is split around it
+        if let Some(pde) = self.get_huge_pde(virt_addr) {
+            self.split_huge_page(pde);
+        }
+
+        let pte = self.get_pte(virt_addr).ok_or(KError::EFAULT)?;
+        let mapping = PageMapping::new(unsafe { *pte });
+
+        if !mapping.is_present() {
+            return Err(KError::EFAULT);
+        }
+        if mapping.is_shared_frame() || !frame::get(mapping.phys_addr()) {
+            return Err(KError::EINVAL);
+        }
+
+        let flags = (mapping.flags() - PageFlags::WRITABLE) | PageFlags::COPY_ON_WRITE;
+        unsafe {
+            *pte = mapping.phys_addr().as_u64() | flags.bits();
+        }
+        self.shootdown(virt_addr, 1);
+
+        other.map_page(virt_addr, mapping.phys_addr(), flags, true);
+        Ok(())
+    }
+
+    /*
+        After a write fault on a copy on write page: the last one that still has the frame
+        just gets it writable again, everyone else gets their own copy in a page from
+        alloc. Returns false if the page isn't copy on write
+    */
+    pub fn resolve_copy_on_write(
+        &self,
+        virt_addr: VirtAddr,
+        alloc: impl FnOnce() -> Option<PhysAddr>,
+    ) -> KResult<bool> {
+        let pte = match self.get_pte(virt_addr) {
+            Some(pte) => pte,
+            None => return Ok(false),
+        };
+        let mapping = PageMapping::new(unsafe { *pte });
+
+        if !mapping.is_present() || !mapping.is_copy_on_write() {
+            return Ok(false);
+        }
+
+        let old = mapping.phys_addr();
+        let flags = (mapping.flags() - PageFlags::COPY_ON_WRITE) | PageFlags::WRITABLE;
+
+        if frame::refcount(old) > 1 {
+            let copy = alloc().ok_or(KError::ENOMEM)?;
+            unsafe {
+                copy.higher_half()
+                    .as_mut_ptr::<u8>()
+                    .copy_from(old.higher_half().as_ptr::<u8>(), pmm::PAGE_SIZE as usize);
+                *pte = copy.as_u64() | flags.bits();
+            }
+            frame::track(copy, FrameFlags::PRIVATE);
+            self.shootdown(virt_addr, 1);
+
+            // the others might have dropped it in the meantime, then it's freed here
+            frame::put(old);
+        } else {
+            unsafe {
+                *pte = old.as_u64() | flags.bits();
+            }
+            self.shootdown(virt_addr, 1);
+        }
+
+        Ok(true)
+    }
+
+    /*
+        Brings in the page at virt_addr if it's one of a range's that hasn't been faulted
+        in yet or was swapped out. Anonymous huge page ranges get a whole huge page if
+        there's contiguous memory for it, shared mappings of files that live in memory get
+        the file's frame, so every process mapping it sees the same memory. Everything else
+        gets a page from alloc, read back from swap or the file or left zeroed. Returns
+        false if there was nothing to bring in, ENOMEM if alloc came back empty
+    */
+    pub fn populate(
+        &self,
+        virt_addr: VirtAddr,
+        alloc: impl FnOnce() -> Option<PhysAddr>,
+    ) -> KResult<bool> {
+        let mapping = self.get_mapping(virt_addr);
+        let range = match self.get_range(virt_addr) {
+            Some(range) if mapping.is_mmaped() => range,
+            _ => return Ok(false),
+        };
+
+        if range.fits_huge_page(virt_addr) {
+            let block = VirtAddr::new(virt_addr.as_u64() & !(HUGE_PAGE_SIZE - 1));
+            let pages = (HUGE_PAGE_SIZE / pmm::PAGE_SIZE) as usize;
+
+            // if there isn't enough contiguous memory, just fall back to 4KiB pages
+            if let Ok(page) = pmm::get().alloc_aligned(pages, pages) {
+                unsafe {
+                    page.higher_half()
+                        .as_mut_ptr::<u8>()
+                        .write_bytes(0, HUGE_PAGE_SIZE as usize);
+                }
+                // counted frame by frame, so they're still counted once it's split
+                for frame in huge_frames(page) {
+                    frame::track(frame, FrameFlags::PRIVATE);
+                }
+
+                self.map_page(
+                    block,
+                    page,
+                    PageFlags::from(range.prot) | PageFlags::PRESENT | PageFlags::HUGE,
+                    true,
+                );
+                return Ok(true);
+            }
+        }
+
+        // the reference is ours
+        if let Some(frame) = range.backing_frame(virt_addr) {
+            let flags = PageFlags::PRESENT | PageFlags::SHARED_FRAME;
+            self.map_page(virt_addr, frame, PageFlags::from(range.prot) | flags, true);
+            return Ok(true);
+        }
+
+        let page = alloc().ok_or(KError::ENOMEM)?;
+        let mut flags = PageFlags::from(range.prot) | PageFlags::PRESENT;
+
+        if mapping.is_swapped() {
+            // the slot is gone now, so the page has to be written out again next time
+            if let Err(err) = swap::read_page(mapping.swap_slot(), page) {
+                log::error!("[VMM] Could not read a page back from swap: {}\n", err);
+            }
+            flags |= PageFlags::DIRTY;
+        } else if !range.is_anon_map() {
+            /*
+                Both private and shared file mappings start with the file's contents,
+                writes to shared mappings are tracked through the dirty bit and
+                written back by msync/munmap
+            */
+            read_mapping_page(range, virt_addr, page);
+        }
+
+        frame::track(page, FrameFlags::PRIVATE);
+        self.map_page(virt_addr, page, flags, true);
+        Ok(true)
+    }
+
+    // calls f with every page that's mapped with a pte, huge pages are left out
+    pub fn for_each_page(&self, mut f: impl FnMut(VirtAddr)) {
+        for range in self.ranges.iter() {
+            for page in (range.start()..range.end()).step_by(pmm::PAGE_SIZE as usize) {
+                let virt_addr = VirtAddr::new(page);
+
+                match self.get_pte(virt_addr) {
+                    Some(pte) if PageMapping::new(unsafe { *pte }).is_present() => f(virt_addr),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    // whether the page was used since the last call, None if it isn't mapped anymore
+    pub fn test_and_clear_accessed(&self, virt_addr: VirtAddr) -> Option<bool> {
+        let pte = self.get_pte(virt_addr)?;
+        let mapping = PageMapping::new(unsafe { *pte });
+
+        if !mapping.is_present() {
+            return None;
+        }
+
+        if mapping.is_accessed() {
+            unsafe {
+                *pte = mapping.as_u64() & !PageFlags::ACCESSED.bits();
+            }
+            self.shootdown(virt_addr, 1);
+        }
+
+        Some(mapping.is_accessed())
+    }
+
+    pub fn ranges(&self) -> &[VirtMemoryRange] {
+        &self.ranges
+    }
+
+    // how many frames back the ranges, huge pages count as all of their 4KiB pages
+    pub fn resident_pages(&self) -> usize {
+        self.ranges
+            .iter()
+            .map(|range| {
+                (range.start()..range.end())
+                    .step_by(pmm::PAGE_SIZE as usize)
+                    .filter(|page| self.translate(VirtAddr::new(*page)).is_some())
+                    .count()
+            })
+            .sum()
+    }
+
+    // the highest gap below the mmap base that fits length, None if the address space is full
+    pub fn get_free_range(&self, length: usize) -> Option<VirtAddr> {
+        let length = round_up(length, pmm::PAGE_SIZE as usize) as u64;
+        let mut end = self.layout.mmap_base;
+
+        // every time a range is in the way, try again right below it
+        loop {
+            let start = end.checked_sub(length).filter(|start| *start >= pmm::PAGE_SIZE)?;
+
+            match self
+                .ranges
+                .iter()
+                .filter(|range| range.start() < end && range.end() > start)
+                .map(|range| range.start())
+                .min()
+            {
+                Some(blocker) => end = blocker & !(pmm::PAGE_SIZE - 1),
+                None => return Some(VirtAddr::new(start)),
+            }
+        }
     }
 
     fn get_next_level(&self, curr: PhysAddr, index: isize) -> PhysAddr {
@@ -301,6 +1192,7 @@ impl VirtualMemManager {
         }
     }
 
+    // maps a 2MiB page instead if flags contains HUGE, both addresses must then be 2MiB aligned
     pub fn map_page(
         &self,
         virtual_addr: VirtAddr,
@@ -308,8 +1200,21 @@ impl VirtualMemManager {
         flags: PageFlags,
         flush_prev: bool,
     ) {
-        if flush_prev {
-            self.invlpg(virtual_addr);
+        let huge = flags.contains(PageFlags::HUGE);
+        let flags = if cpuid::has(Features::NX) {
+            flags
+        } else {
+            flags - PageFlags::NX
+        };
+
+        if huge
+            && (virtual_addr.as_u64() % HUGE_PAGE_SIZE != 0 || phys_addr.as_u64() % HUGE_PAGE_SIZE != 0)
+        {
+            panic!(
+                "[VMM] Tried to map a huge page at unaligned addresses {:#x} -> {:#x}",
+                virtual_addr.as_u64(),
+                phys_addr.as_u64()
+            );
         }
 
         let pml4e = virtual_addr.pml4();
@@ -319,13 +1224,78 @@ impl VirtualMemManager {
 
         let pdp = self.get_next_level(self.pagemap, pml4e as isize);
         let pd = self.get_next_level(pdp, pdpe as isize);
-        let page_table: *mut u64 = self.get_next_level(pd, pde as isize).as_mut_ptr();
+        let pd_entry: *mut u64 = unsafe { pd.higher_half().as_mut_ptr::<u64>().offset(pde as isize) };
+
+        if huge {
+            unsafe {
+                let old = PageMapping::new(*pd_entry);
+
+                // the page table that was here is replaced by the huge page
+                if old.is_present() && !old.is_huge() {
+                    pmm::get().free(old.phys_addr().higher_half().as_mut_ptr(), 1);
+                }
+
+                *pd_entry = phys_addr.as_u64() | flags.bits();
+            }
+
+            if flush_prev {
+                self.shootdown(virtual_addr, (HUGE_PAGE_SIZE / pmm::PAGE_SIZE) as usize);
+            }
+            return;
+        }
+
+        if PageMapping::new(unsafe { *pd_entry }).is_huge() {
+            self.split_huge_page(pd_entry);
+        }
+
+        let page_table: *mut u64 = self.get_next_level(pd, pde as isize).higher_half().as_mut_ptr();
 
         unsafe {
             *page_table.offset(pte as isize) = phys_addr.as_u64() | flags.bits();
         }
+
+        if flush_prev {
+            self.invlpg(virtual_addr);
+        }
     }
 
+    // maps a physically contiguous region, with 2MiB pages wherever the alignment allows it
+    pub fn map_range(&self, virtual_addr: VirtAddr, phys_addr: PhysAddr, length: u64, flags: PageFlags) {
+        let mut offset = 0;
+
+        while offset < length {
+            let virt = virtual_addr.as_u64() + offset;
+            let phys = phys_addr.as_u64() + offset;
+
+            if virt % HUGE_PAGE_SIZE == 0 && phys % HUGE_PAGE_SIZE == 0 && length - offset >= HUGE_PAGE_SIZE {
+                self.map_page(VirtAddr::new(virt), PhysAddr::new(phys), flags | PageFlags::HUGE, false);
+                offset += HUGE_PAGE_SIZE;
+            } else {
+                self.map_page(VirtAddr::new(virt), PhysAddr::new(phys), flags - PageFlags::HUGE, false);
+                offset += pmm::PAGE_SIZE;
+            }
+        }
+    }
+
+    /*
+        Maps device memory where the direct map would have it, with mode instead of the
+        direct map's write back, and returns its address. Also for memory that's already in
+        the direct map, so every page is flushed
+    */
+    pub fn map_mmio(&self, phys_addr: PhysAddr, length: u64, mode: CacheMode) -> *mut u8 {
+        let flags = (PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::NX).with_cache(mode);
+        let start = phys_addr.as_u64() & !(pmm::PAGE_SIZE - 1);
+        let end = phys_addr.as_u64() + length.max(1);
+
+        for page in (start..end).step_by(pmm::PAGE_SIZE as usize) {
+            let phys = PhysAddr::new(page);
+            self.map_page(VirtAddr::new(phys.higher_half().as_u64()), phys, flags, true);
+        }
+
+        phys_addr.higher_half().as_mut_ptr()
+    }
+
+    // for addresses inside a huge page, the pd entry is returned
     pub fn get_mapping(&self, virtual_addr: VirtAddr) -> PageMapping {
         let pml4e = virtual_addr.pml4();
         let pdpe = virtual_addr.pdp();
@@ -334,11 +1304,133 @@ impl VirtualMemManager {
 
         let pdp = self.get_next_level(self.pagemap, pml4e as isize);
         let pd = self.get_next_level(pdp, pdpe as isize);
-        let page_table: *mut u64 = self.get_next_level(pd, pde as isize).as_mut_ptr();
+
+        let pd_entry = unsafe { PageMapping::new(*pd.higher_half().as_ptr::<u64>().offset(pde as isize)) };
+        if pd_entry.is_present() && pd_entry.is_huge() {
+            return pd_entry;
+        }
+
+        let page_table: *mut u64 = self.get_next_level(pd, pde as isize).higher_half().as_mut_ptr();
 
         unsafe { PageMapping::new(*page_table.offset(pte as isize)) }
     }
 
+    // returns a pointer to the pd entry if virtual_addr is inside a 2MiB page
+    fn get_huge_pde(&self, virtual_addr: VirtAddr) -> Option<*mut u64> {
+        let mut table = self.pagemap;
+
+        for index in [virtual_addr.pml4(), virtual_addr.pdp()] {
+            let entry = unsafe { *table.higher_half().as_ptr::<u64>().offset(index as isize) };
+
+            if entry & PageFlags::PRESENT.bits() == 0 {
+                return None;
+            }
+
+            table = PhysAddr::new(entry).remove_flags();
+        }
+
+        let pde = unsafe { table.higher_half().as_mut_ptr::<u64>().offset(virtual_addr.pd() as isize) };
+        let mapping = PageMapping::new(unsafe { *pde });
+
+        if mapping.is_present() && mapping.is_huge() {
+            Some(pde)
+        } else {
+            None
+        }
+    }
+
+    // replaces a 2MiB page with a page table mapping the same frames
+    fn split_huge_page(&self, pde: *mut u64) {
+        let mapping = PageMapping::new(unsafe { *pde });
+        let flags = mapping.as_u64() & !0x000ffffffffff000 & !PageFlags::HUGE.bits();
+
+        let page_table = pmm::get()
+            .calloc(1)
+            .expect("Could not allocate a page table to split a huge page");
+        let entries: *mut u64 = page_table.higher_half().as_mut_ptr();
+
+        for i in 0..512 {
+            unsafe {
+                *entries.offset(i) = (mapping.phys_addr().as_u64() + i as u64 * pmm::PAGE_SIZE) | flags;
+            }
+        }
+
+        let table_flags = PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::USERMODE;
+        unsafe {
+            *pde = page_table.as_u64() | table_flags.bits();
+        }
+    }
+
+    // returns a pointer to the entry of a 4KiB page, without allocating missing tables
+    fn get_pte(&self, virtual_addr: VirtAddr) -> Option<*mut u64> {
+        let mut table = self.pagemap;
+
+        for index in [virtual_addr.pml4(), virtual_addr.pdp(), virtual_addr.pd()] {
+            let entry = unsafe { *table.higher_half().as_ptr::<u64>().offset(index as isize) };
+
+            if entry & PageFlags::PRESENT.bits() == 0 || entry & PageFlags::HUGE.bits() != 0 {
+                return None;
+            }
+
+            table = PhysAddr::new(entry).remove_flags();
+        }
+
+        unsafe {
+            Some(
+                table
+                    .higher_half()
+                    .as_mut_ptr::<u64>()
+                    .offset(virtual_addr.pt() as isize),
+            )
+        }
+    }
+
+    // walks the page tables without allocating anything, unlike get_mapping
+    pub fn translate(&self, virtual_addr: VirtAddr) -> Option<PhysAddr> {
+        let indexes = [
+            virtual_addr.pml4(),
+            virtual_addr.pdp(),
+            virtual_addr.pd(),
+            virtual_addr.pt(),
+        ];
+
+        let mut table = self.pagemap;
+
+        for (level, index) in indexes.iter().enumerate() {
+            let entry = unsafe { *table.higher_half().as_ptr::<u64>().offset(*index as isize) };
+
+            if entry & PageFlags::PRESENT.bits() == 0 {
+                return None;
+            }
+
+            let next = PhysAddr::new(entry).remove_flags();
+
+            // 1GiB and 2MiB pages
+            if (level == 1 || level == 2) && entry & PageFlags::HUGE.bits() != 0 {
+                let page_size = if level == 1 { 1 << 30 } else { 1 << 21 };
+                let base = next.as_u64() & !(page_size - 1);
+                return Some(PhysAddr::new(base + (virtual_addr.as_u64() & (page_size - 1))));
+            }
+
+            table = next;
+        }
+
+        Some(PhysAddr::new(
+            table.as_u64() + (virtual_addr.as_u64() & (pmm::PAGE_SIZE - 1)),
+        ))
+    }
+
+    // clears the entry of a 4KiB page and returns the frame it had, without any flushing
+    pub fn unmap_page(&self, virtual_addr: VirtAddr) -> Option<PhysAddr> {
+        let pte = self.get_pte(virtual_addr)?;
+        let mapping = PageMapping::new(unsafe { *pte });
+
+        unsafe {
+            *pte = 0;
+        }
+        mapping.is_present().then(|| mapping.phys_addr())
+    }
+
     pub fn switch_pagemap(&self) {
         unsafe {
             asm!("mov cr3, {}", in(reg) self.pagemap.as_u64());
@@ -350,18 +1442,142 @@ impl VirtualMemManager {
             asm!("invlpg [{}]", in(reg) virtual_addr.as_u64());
         }
     }
+
+    // unlike invlpg, this also invalidates the pages on every other cpu
+    pub fn shootdown(&self, virtual_addr: VirtAddr, pages: usize) {
+        ipi::tlb_shootdown(virtual_addr, pages);
+    }
 }
 
-pub fn init() {
+fn map_kernel_section(
+    kernel_vmm: &VirtualMemManager,
+    bootloader_vmm: &VirtualMemManager,
+    start: u64,
+    end: u64,
+    flags: PageFlags,
+) {
+    for page in (start..end).step_by(pmm::PAGE_SIZE as usize) {
+        let phys_addr = bootloader_vmm
+            .translate(VirtAddr::new(page))
+            .expect("The kernel is not fully mapped by the bootloader");
+
+        kernel_vmm.map_page(VirtAddr::new(page), phys_addr, flags, false);
+    }
+}
+
+/*
+    The direct map covers the first 4GiB (where most MMIO lives) and everything in the
+    memory map, so that any frame the pmm gives out can be reached through PHYS_BASE
+*/
+unsafe fn build_direct_map(kernel_vmm: &VirtualMemManager, entries: *const StivaleMemoryMapEntry, entries_num: u64) {
+    let flags = PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::NX;
+    let low_memory = 4 << 30;
+
+    // the identity map is still used by code that hasn't moved to the higher half
+    kernel_vmm.map_range(VirtAddr::new(0), PhysAddr::new(0), low_memory, flags);
+    kernel_vmm.map_range(VirtAddr::new(pmm::PHYS_BASE), PhysAddr::new(0), low_memory, flags);
+
+    for i in 0..entries_num {
+        let entry = &*entries.offset(i as isize);
+
+        if matches!(entry.entry_type, StivaleMemoryMapEntryType::BadMemory) {
+            continue;
+        }
+
+        let start = (entry.base & !(pmm::PAGE_SIZE - 1)).max(low_memory);
+        let end = round_up((entry.base + entry.length) as usize, pmm::PAGE_SIZE as usize) as u64;
+
+        if end <= start {
+            continue;
+        }
+
+        kernel_vmm.map_range(
+            VirtAddr::new(start + pmm::PHYS_BASE),
+            PhysAddr::new(start),
+            end - start,
+            flags,
+        );
+    }
+
+    // what the bootloader says is the framebuffer, which is usually below 4GiB
+    for i in 0..entries_num {
+        let entry = &*entries.offset(i as isize);
+
+        if !matches!(entry.entry_type, StivaleMemoryMapEntryType::Framebuffer) {
+            continue;
+        }
+
+        let start = entry.base & !(pmm::PAGE_SIZE - 1);
+        let end = round_up((entry.base + entry.length) as usize, pmm::PAGE_SIZE as usize) as u64;
+        kernel_vmm.map_range(
+            VirtAddr::new(start + pmm::PHYS_BASE),
+            PhysAddr::new(start),
+            end - start,
+            flags.with_cache(CacheMode::WriteCombining),
+        );
+    }
+}
+
+/*
+    We don't trust the permissions the bootloader gave to the kernel, so we build our own
+    mappings for it: .text is RX, .rodata is R and .data/.bss are RW, and only .text is executable.
+    The direct map is built by us as well, so nothing references the bootloader's tables afterwards
+*/
+pub fn init(entries: *const StivaleMemoryMapEntry, entries_num: u64) {
     let pml4: u64;
 
     unsafe {
         asm!("mov {}, cr3", out(reg) pml4);
-        let mut kernel_vmm = VirtualMemManager::new(false);
-        kernel_vmm.pagemap = PhysAddr::new(pml4);
+    }
+
+    let mut bootloader_vmm = VirtualMemManager::new(false);
+    bootloader_vmm.pagemap = PhysAddr::new(pml4).remove_flags();
+
+    // cpu::init_msrs set NXE already, without NX map_page drops the bit
+    if !cpuid::has(Features::NX) {
+        log::warning!("[VMM] The cpu doesn't support NX, data will be executable\n");
+    }
+
+    let mut kernel_vmm = VirtualMemManager::new(false);
+    kernel_vmm.pagemap = pmm::get()
+        .calloc(1)
+        .expect("Could not allocate the kernel's pml4");
+
+    unsafe {
+        build_direct_map(&kernel_vmm, entries, entries_num);
+
+        map_kernel_section(
+            &kernel_vmm,
+            &bootloader_vmm,
+            &text_start as *const u8 as u64,
+            &text_end as *const u8 as u64,
+            PageFlags::PRESENT,
+        );
+        map_kernel_section(
+            &kernel_vmm,
+            &bootloader_vmm,
+            &rodata_start as *const u8 as u64,
+            &rodata_end as *const u8 as u64,
+            PageFlags::PRESENT | PageFlags::NX,
+        );
+        map_kernel_section(
+            &kernel_vmm,
+            &bootloader_vmm,
+            &data_start as *const u8 as u64,
+            &data_end as *const u8 as u64,
+            PageFlags::PRESENT | PageFlags::WRITABLE | PageFlags::NX,
+        );
+    }
+
+    kernel_vmm.switch_pagemap();
+
+    // make read only pages read only for the kernel too
+    unsafe {
+        asm!("mov {tmp}, cr0", "or {tmp}, {wp}", "mov cr0, {tmp}", tmp = out(reg) _, wp = in(reg) 1u64 << 16);
 
         VIRTUAL_MEMORY_MANAGER = Some(kernel_vmm);
-        // interrupts::register_isr(0xe, page_fault as u64, cpu::Ists::PageFault as u8, 0x8e);
+        // not on an ist, the handler can block reading a page in and another #PF would reuse it
+        interrupts::register_isr(0xe, page_fault as u64, 0, 0x8e, "page fault");
     }
 }
 
@@ -373,96 +1589,184 @@ pub fn get() -> &'static mut VirtualMemManager {
     }
 }
 
-// NOTE: SMAP is enabled, so all of this wont work rn
-// TODO: handle MAP_SHARED
-// interrupts::isr_err!(page_fault, |_stack, error_code| {
-//     serial::print!("Page fault handler\n");
-//     let mut cr2: u64;
-//     asm!("mov {}, cr2", out(reg) cr2);
-
-//     let virt_cr2 = VirtAddr::new(cr2);
-
-//     let curr_thread = scheduler::get()
-//         .running_thread
-//         .as_ref()
-//         .expect("Page fault: no running thread")
-//         .borrow();
-
-//     let curr_process = curr_thread.parent.borrow();
-
-//     let vmm = &curr_process.pagemap;
-//     let mapping = vmm.get_mapping(virt_cr2);
-
-//     if mapping.is_mmaped() {
-//         serial::print!("is mmaped\n");
-//         // demand paging
-//         interrupts::enable();
-
-//         let range = vmm
-//             .get_range(virt_cr2)
-//             .expect("Page is marked as mmaped but doesn't belong to any range");
-
-//         if range.is_anon_map() {
-//             serial::print!("anon map\n");
-//             let page = pmm::get()
-//                 .calloc(1)
-//                 .expect("Could not allocate new page for anonymous map");
-//             serial::print!("allocated page: {:#x}\n", page.as_u64());
-//             vmm.map_page(
-//                 virt_cr2,
-//                 page,
-//                 PageFlags::from(range.prot) | PageFlags::PRESENT,
-//                 true,
-//             );
-//             serial::print!("continue");
-//             return;
-//         }
-
-//         // TODO: test this
-//         if range.is_private_map() {
-//             let page = pmm::get()
-//                 .calloc(1)
-//                 .expect("Could not allocate new page for private map")
-//                 .higher_half();
-
-//             let this_page_number = cr2 / pmm::PAGE_SIZE - range.start() / pmm::PAGE_SIZE;
-//             // TODO: add range offset to the calculation
-//             let offset = this_page_number * pmm::PAGE_SIZE;
-//             let cnt = if (this_page_number + 1) * pmm::PAGE_SIZE <= range.length as u64 {
-//                 pmm::PAGE_SIZE
-//             } else {
-//                 range.length as u64 % pmm::PAGE_SIZE
-//             };
-
-//             let fd = range
-//                 .fd
-//                 .as_ref()
-//                 .expect("Private mapping not backed by a file");
-
-//             vfs::read(
-//                 fd.fs,
-//                 fd.file_index,
-//                 page.as_mut_ptr::<u8>(),
-//                 cnt as usize,
-//                 offset as usize + range.offset,
-//             );
-
-//             vmm.map_page(
-//                 virt_cr2,
-//                 page.lower_half(),
-//                 PageFlags::from(range.prot),
-//                 true,
-//             );
-//             return;
-//         }
-
-//         serial::print!("Page fault says: crap\n");
-//         return;
-//     }
-
-//     serial::print!("Page fault\n");
-//     serial::print!("Error code: {}\n", error_code);
-//     serial::print!("CR2: {:#x}\n", cr2);
-
-//     cpu::halt();
-// });
+/*
+    Drops an address space's hold on a 4KiB frame it had mapped. A counted frame goes once
+    nothing references it anymore, one that isn't counted is only the address space's if
+    it's not a shared frame
+*/
+fn release_frame(mapping: PageMapping) {
+    let frame = mapping.phys_addr();
+
+    if frame::is_counted(frame) {
+        frame::put(frame);
+    } else if !mapping.is_shared_frame() {
+        pmm::get().free(frame.higher_half().as_mut_ptr(), 1);
+    }
+}
+
+// the 4KiB frames a 2MiB page at base is made of
+fn huge_frames(base: PhysAddr) -> impl Iterator<Item = PhysAddr> {
+    let frames = HUGE_PAGE_SIZE / pmm::PAGE_SIZE;
+    (0..frames).map(move |i| PhysAddr::new(base.as_u64() + i * pmm::PAGE_SIZE))
+}
+
+// the same as release_frame for a 2MiB page, whose frames are counted one by one
+fn release_huge_page(mapping: PageMapping) {
+    for frame in huge_frames(mapping.phys_addr()) {
+        if frame::is_counted(frame) {
+            frame::put(frame);
+        } else {
+            pmm::get().free(frame.higher_half().as_mut_ptr(), 1);
+        }
+    }
+}
+
+// walks whatever page tables are loaded, which may be the interrupted process' and not the kernel's
+pub fn translate_active(virtual_addr: VirtAddr) -> Option<PhysAddr> {
+    let cr3: u64;
+    unsafe {
+        asm!("mov {}, cr3", out(reg) cr3);
+    }
+
+    let mut vmm = VirtualMemManager::new(false);
+    vmm.pagemap = PhysAddr::new(cr3).remove_flags();
+    vmm.translate(virtual_addr)
+}
+
+// writes through the direct map, so read only pages like the kernel's text can be patched
+pub fn patch_byte(virtual_addr: VirtAddr, value: u8) -> bool {
+    match translate_active(virtual_addr) {
+        Some(phys) => {
+            unsafe { phys.higher_half().as_mut_ptr::<u8>().write_volatile(value) };
+            true
+        }
+        None => false,
+    }
+}
+
+// reads the page of a file mapping that contains virt_addr into page
+fn read_mapping_page(range: &VirtMemoryRange, virt_addr: VirtAddr, page: PhysAddr) {
+    let page = page.higher_half();
+
+    let this_page_number = virt_addr.as_u64() / pmm::PAGE_SIZE - range.start() / pmm::PAGE_SIZE;
+    let offset = this_page_number * pmm::PAGE_SIZE;
+    let cnt = if (this_page_number + 1) * pmm::PAGE_SIZE <= range.length as u64 {
+        pmm::PAGE_SIZE
+    } else {
+        range.length as u64 % pmm::PAGE_SIZE
+    };
+
+    let fd = range.fd.as_ref().expect("File mapping not backed by a file");
+
+    // the page is still mapped if this fails, the process just sees zeroes
+    let result = vfs::pread(
+        fd,
+        page.as_mut_ptr::<u8>(),
+        cnt as usize,
+        offset as usize + range.offset,
+    );
+
+    if let Err(err) = result {
+        log::error!("[VMM] Could not read a page of a file mapping: {}\n", err);
+    }
+}
+
+// the page fault error code's bits
+const FAULT_PRESENT: u64 = 1 << 0;
+const FAULT_WRITE: u64 = 1 << 1;
+const FAULT_USER: u64 = 1 << 2;
+const FAULT_FETCH: u64 = 1 << 4;
+
+// whether the range at address allows the access that faulted
+fn fault_allowed(vmm: &VirtualMemManager, address: VirtAddr, error_code: u64) -> bool {
+    let needed = if error_code & FAULT_WRITE != 0 {
+        MapProt::WRITE
+    } else if error_code & FAULT_FETCH != 0 {
+        MapProt::EXEC
+    } else {
+        MapProt::READ
+    };
+
+    vmm.get_range(address).map_or(false, |range| range.prot.contains(needed))
+}
+
+interrupts::isr_err!(page_fault, |stack, error_code| {
+    let cr2: u64;
+    asm!("mov {}, cr2", out(reg) cr2);
+    trace::trace!(page_fault, cr2, error_code);
+
+    let virt_cr2 = VirtAddr::new(cr2 & !(pmm::PAGE_SIZE - 1));
+
+    if let Some(curr_thread) = scheduler::running_thread() {
+        let curr_thread = curr_thread.borrow();
+        let curr_process = curr_thread.parent.borrow();
+
+        if let Some(vmm) = curr_process.pagemap.as_ref() {
+            let mapping = vmm.get_mapping(virt_cr2);
+
+            // a write to a present page
+            if error_code & (FAULT_PRESENT | FAULT_WRITE) == FAULT_PRESENT | FAULT_WRITE {
+                interrupts::enable();
+
+                match vmm.resolve_copy_on_write(virt_cr2, || oom::alloc_page(&curr_thread.parent)) {
+                    Ok(true) => return,
+                    Ok(false) => {}
+                    Err(_) => {
+                        let process = curr_thread.parent.clone();
+                        drop(curr_process);
+                        drop(curr_thread);
+                        oom::kill_current(process);
+                    }
+                }
+            }
+
+            // demand paging, a page that isn't there yet and that its range lets be accessed so
+            let missing = error_code & FAULT_PRESENT == 0;
+            if missing && mapping.is_mmaped() && fault_allowed(vmm, virt_cr2, error_code) {
+                interrupts::enable();
+
+                match vmm.populate(virt_cr2, || oom::alloc_page(&curr_thread.parent)) {
+                    Ok(true) => return,
+                    Ok(false) => {
+                        panic!("Page is marked as mmaped but doesn't belong to any range")
+                    }
+                    Err(_) => {
+                        let process = curr_thread.parent.clone();
+                        drop(curr_process);
+                        drop(curr_thread);
+                        oom::kill_current(process);
+                    }
+                }
+            }
+        }
+
+        // a bad access by userspace takes down its process, not the kernel
+        if error_code & FAULT_USER != 0 {
+            log::warning!(
+                "[VMM] Segmentation fault at {:#x} (error code {:#x}) in process {} ({})\n",
+                cr2,
+                error_code,
+                curr_process.pid,
+                curr_process.name
+            );
+
+            let process = curr_thread.parent.clone();
+            drop(curr_process);
+            drop(curr_thread);
+            oom::exit_current(process);
+        }
+    }
+
+    // a bad pointer handed to a syscall, the copy bails out with EFAULT
+    if let Some(fixup) = syscall::user::fault_fixup(stack.rip) {
+        stack.rip = fixup;
+        return;
+    }
+
+    log::error!("[VMM] Page fault\n");
+    log::error!("Error code: {}\n", error_code);
+    log::error!("CR2: {:#x}\n", cr2);
+
+    crashdump::set_context(stack);
+    panic!("Page fault at {:#x}, error code {:#x}", cr2, error_code);
+});
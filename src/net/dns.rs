@@ -0,0 +1,245 @@
+/*
+    A stub resolver: A records are asked of one server, over udp, which does the recursion.
+    The server is what a dhcp client would set, and there's no dhcp client yet, so for now
+    it comes from "dns=<address>" on the command line or the net.dns sysctl.
+
+    A query is sent again if no answer comes in time, and answers are cached for as long as
+    their ttl says, names that don't exist too but for a shorter while
+*/
+
+use super::{socket, Ipv4Addr, SocketAddr};
+use crate::drivers::timer_source;
+use crate::error::{KError, KResult};
+use crate::{cmdline, log, rng};
+use alloc::collections::BTreeMap;
+use alloc::{string::String, vec::Vec};
+
+const PORT: u16 = 53;
+const HEADER_SIZE: usize = 12;
+const MAX_MESSAGE: usize = 512; // over udp, without edns
+const RECURSION_DESIRED: u16 = 0x0100;
+const RESPONSE: u16 = 0x8000;
+const TRUNCATED: u16 = 0x0200;
+const RCODE: u16 = 0x000f;
+const RCODE_NXDOMAIN: u16 = 3;
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+
+const TIMEOUT_MS: u64 = 2000;
+const ATTEMPTS: usize = 3;
+const MAX_TTL: u32 = 24 * 60 * 60;
+const NEGATIVE_TTL: u32 = 60;
+const MAX_CACHED: usize = 64;
+
+static SERVER: spin::Mutex<Option<Ipv4Addr>> = spin::Mutex::new(None);
+static CACHE: spin::Mutex<BTreeMap<String, Cached>> = spin::Mutex::new(BTreeMap::new());
+
+struct Cached {
+    address: Option<Ipv4Addr>, // None if the name doesn't exist
+    expires_ms: u64,
+}
+
+pub fn server() -> Option<Ipv4Addr> {
+    *SERVER.lock()
+}
+
+// what was cached came from the old server, it's forgotten
+pub fn set_server(server: Option<Ipv4Addr>) {
+    *SERVER.lock() = server;
+    CACHE.lock().clear();
+}
+
+// lowercase and without the trailing dot, EINVAL if it can't be a name
+fn normalize(hostname: &str) -> KResult<String> {
+    let name = hostname.strip_suffix('.').unwrap_or(hostname);
+    let valid_byte = |byte: u8| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_';
+    let valid_label = |label: &str| {
+        !label.is_empty() && label.len() <= 63 && label.bytes().all(valid_byte)
+    };
+
+    if name.is_empty() || name.len() > 253 || !name.split('.').all(valid_label) {
+        return Err(KError::EINVAL);
+    }
+
+    Ok(name.to_ascii_lowercase())
+}
+
+// a query for the A records of name, which has to be normalized
+pub fn encode_query(id: u16, name: &str) -> Vec<u8> {
+    let mut query = Vec::with_capacity(HEADER_SIZE + name.len() + 6);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&RECURSION_DESIRED.to_be_bytes());
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]); // one question
+
+    for label in name.split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+
+    query.extend_from_slice(&TYPE_A.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    query
+}
+
+// where the name at offset ends, compressed names end at their first pointer
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(offset)? as usize;
+        match len {
+            0 => return Some(offset + 1),
+            len if len & 0xc0 == 0xc0 => return Some(offset + 2),
+            len if len & 0xc0 == 0 => offset += 1 + len,
+            _ => return None,
+        }
+    }
+}
+
+fn u16_at(message: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(message.get(offset..offset + 2)?.try_into().ok()?))
+}
+
+fn u32_at(message: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(message.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+/*
+    The first A record in the answer to query id, and its ttl. The records are taken in the
+    order they come, a server that follows a cname puts the target's records after it.
+    ENOENT if the name doesn't exist or has no A record, EIO if the answer is broken or the
+    server failed, EAGAIN if it isn't the answer to id
+*/
+pub fn parse_response(id: u16, message: &[u8]) -> KResult<(Ipv4Addr, u32)> {
+    let broken = KError::EIO;
+    if message.len() < HEADER_SIZE {
+        return Err(broken);
+    }
+
+    let flags = u16_at(message, 2).ok_or(broken)?;
+    if u16_at(message, 0) != Some(id) || flags & RESPONSE == 0 {
+        return Err(KError::EAGAIN);
+    }
+    match flags & RCODE {
+        0 => {}
+        RCODE_NXDOMAIN => return Err(KError::ENOENT),
+        _ => return Err(broken),
+    }
+    if flags & TRUNCATED != 0 {
+        return Err(broken);
+    }
+
+    let questions = u16_at(message, 4).ok_or(broken)?;
+    let answers = u16_at(message, 6).ok_or(broken)?;
+
+    let mut offset = HEADER_SIZE;
+    for _ in 0..questions {
+        offset = skip_name(message, offset).ok_or(broken)? + 4;
+    }
+
+    for _ in 0..answers {
+        offset = skip_name(message, offset).ok_or(broken)?;
+        let kind = u16_at(message, offset).ok_or(broken)?;
+        let class = u16_at(message, offset + 2).ok_or(broken)?;
+        let ttl = u32_at(message, offset + 4).ok_or(broken)?;
+        let len = u16_at(message, offset + 8).ok_or(broken)? as usize;
+        let data = message.get(offset + 10..offset + 10 + len).ok_or(broken)?;
+
+        if kind == TYPE_A && class == CLASS_IN && len == 4 {
+            return Ok((Ipv4Addr([data[0], data[1], data[2], data[3]]), ttl));
+        }
+        offset += 10 + len;
+    }
+
+    Err(KError::ENOENT)
+}
+
+// asks the server, up to ATTEMPTS times
+fn query(server: Ipv4Addr, name: &str) -> KResult<(Ipv4Addr, u32)> {
+    let fd = socket::socket(socket::SocketType::Datagram)?;
+    socket::connect(&fd, SocketAddr::new(server, PORT))?;
+    socket::set_recv_timeout(&fd, Some(TIMEOUT_MS))?;
+
+    let mut buffer = [0u8; MAX_MESSAGE];
+    for _ in 0..ATTEMPTS {
+        let id = rng::next_u64() as u16;
+        socket::send(&fd, &encode_query(id, name))?;
+
+        // what isn't the answer, like a late one to an earlier attempt, is skipped
+        loop {
+            let len = match socket::recv(&fd, &mut buffer, socket::MsgFlags::empty()) {
+                Ok(len) => len,
+                Err(KError::EAGAIN) => break,
+                Err(err) => return Err(err),
+            };
+
+            match parse_response(id, &buffer[..len]) {
+                Err(KError::EAGAIN) => continue,
+                result => return result,
+            }
+        }
+    }
+
+    Err(KError::ETIMEDOUT)
+}
+
+fn cache(name: String, address: Option<Ipv4Addr>, ttl: u32, now: u64) {
+    let mut cache = CACHE.lock();
+    cache.retain(|_, cached| cached.expires_ms > now);
+
+    // the one that expires first makes room
+    if cache.len() >= MAX_CACHED && !cache.contains_key(&name) {
+        let first = cache.iter().min_by_key(|(_, cached)| cached.expires_ms);
+        if let Some(first) = first.map(|(name, _)| name.clone()) {
+            cache.remove(&first);
+        }
+    }
+
+    let expires_ms = now + ttl.min(MAX_TTL) as u64 * 1000;
+    cache.insert(name, Cached { address, expires_ms });
+}
+
+/*
+    The address of hostname, which can also be a dotted address. Blocks while the server is
+    asked, ENOENT if the name doesn't exist, ETIMEDOUT if the server didn't answer and
+    EDESTADDRREQ if there's no server
+*/
+pub fn resolve(hostname: &str) -> KResult<Ipv4Addr> {
+    if let Some(address) = Ipv4Addr::parse(hostname) {
+        return Ok(address);
+    }
+
+    let name = normalize(hostname)?;
+    if name == "localhost" {
+        return Ok(Ipv4Addr::LOCALHOST);
+    }
+
+    let now = timer_source::current_ms();
+    if let Some(cached) = CACHE.lock().get(&name).filter(|cached| cached.expires_ms > now) {
+        return cached.address.ok_or(KError::ENOENT);
+    }
+
+    let server = server().ok_or(KError::EDESTADDRREQ)?;
+    match query(server, &name) {
+        Ok((address, ttl)) => {
+            cache(name, Some(address), ttl, now);
+            Ok(address)
+        }
+        Err(KError::ENOENT) => {
+            cache(name, None, NEGATIVE_TTL, now);
+            Err(KError::ENOENT)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+pub fn init() {
+    let address = match cmdline::option("dns") {
+        Some(address) => address,
+        None => return,
+    };
+
+    match Ipv4Addr::parse(address) {
+        Some(server) => set_server(Some(server)),
+        None => log::error!("[DNS] {} isn't an address\n", address),
+    }
+}
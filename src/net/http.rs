@@ -0,0 +1,209 @@
+/*
+    Fetching files with http/1.0 GET over tcp, so what's needed at runtime, like userland
+    binaries, can come from a server on the host instead of the boot image. There's no tls,
+    redirects aren't followed and the body ends where the server closes the connection,
+    checked against its content-length if it sent one.
+
+    Fetched files usually go to /tmp, which is a ramfs (see fs/ramfs.rs)
+*/
+
+use super::{socket, SocketAddr};
+use crate::error::{KError, KResult};
+use crate::fs::vfs;
+use crate::rng;
+use alloc::{format, string::String, vec::Vec};
+
+const DEFAULT_PORT: u16 = 80;
+const MAX_HEADER: usize = 8192;
+const TIMEOUT_MS: u64 = 30_000;
+const CHUNK: usize = 4096;
+
+#[derive(PartialEq, Debug)]
+pub struct Url {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl Url {
+    // only http://host[:port][/path], EINVAL for anything else
+    pub fn parse(url: &str) -> KResult<Self> {
+        let rest = url.strip_prefix("http://").ok_or(KError::EINVAL)?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+
+        let (host, port) = match authority.split_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| KError::EINVAL)?),
+            None => (authority, DEFAULT_PORT),
+        };
+        if host.is_empty() || port == 0 || path.contains(char::is_whitespace) {
+            return Err(KError::EINVAL);
+        }
+
+        Ok(Url {
+            host: String::from(host),
+            port,
+            path: String::from(path),
+        })
+    }
+
+    // the last part of the path, what a fetched file is named after by default
+    pub fn file_name(&self) -> Option<&str> {
+        let path = self.path.split(|c| c == '?' || c == '#').next().unwrap_or("");
+        path.rsplit('/').next().filter(|name| !name.is_empty())
+    }
+}
+
+// the status line's code as an error, 2xx is fine
+fn status_error(code: u16) -> Option<KError> {
+    match code {
+        200..=299 => None,
+        401 | 403 => Some(KError::EACCES),
+        404 | 410 => Some(KError::ENOENT),
+        _ => Some(KError::EIO),
+    }
+}
+
+// the status code and content-length, if there was one
+fn parse_header(header: &str) -> KResult<(u16, Option<usize>)> {
+    let mut lines = header.split("\r\n");
+    let status = lines.next().ok_or(KError::EIO)?;
+
+    let mut parts = status.split(' ');
+    let version = parts.next().unwrap_or("");
+    let code = parts.next().and_then(|code| code.parse().ok());
+    let code = match code {
+        Some(code) if version.starts_with("HTTP/1.") => code,
+        _ => return Err(KError::EIO),
+    };
+
+    let mut len = None;
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                len = Some(value.trim().parse().map_err(|_| KError::EIO)?);
+            }
+        }
+    }
+
+    Ok((code, len))
+}
+
+fn receive(fd: &vfs::FileDescription, buffer: &mut [u8]) -> KResult<usize> {
+    match socket::recv(fd, buffer, socket::MsgFlags::empty()) {
+        Err(KError::EAGAIN) => Err(KError::ETIMEDOUT),
+        result => result,
+    }
+}
+
+/*
+    Asks for url over fd, a stream socket that's already connected to its host, and hands
+    the body to sink as it comes in. Returns how long the body was
+*/
+pub fn request(
+    fd: &vfs::FileDescription,
+    url: &Url,
+    sink: &mut dyn FnMut(&[u8]) -> KResult<()>,
+) -> KResult<usize> {
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: griffin\r\nConnection: close\r\n\r\n",
+        url.path, url.host
+    );
+    socket::send(fd, request.as_bytes())?;
+
+    // the header, and whatever of the body came in with it
+    let mut received = Vec::new();
+    let mut buffer = [0u8; CHUNK];
+    let header_end = loop {
+        if let Some(end) = received.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+        if received.len() > MAX_HEADER {
+            return Err(KError::EIO);
+        }
+
+        match receive(fd, &mut buffer)? {
+            0 => return Err(KError::EIO),
+            len => received.extend_from_slice(&buffer[..len]),
+        }
+    };
+
+    let header = core::str::from_utf8(&received[..header_end]).map_err(|_| KError::EIO)?;
+    let (code, expected) = parse_header(header)?;
+    if let Some(err) = status_error(code) {
+        return Err(err);
+    }
+
+    let mut body = &received[header_end + 4..];
+    let mut total = 0;
+    loop {
+        total += body.len();
+        sink(body)?;
+
+        let len = receive(fd, &mut buffer)?;
+        if len == 0 {
+            break;
+        }
+        body = &buffer[..len];
+    }
+
+    // cut short, the server or the connection gave up
+    if expected.map_or(false, |expected| expected != total) {
+        return Err(KError::EIO);
+    }
+    Ok(total)
+}
+
+// the body of url, handed to sink as it comes in
+pub fn get(url: &Url, sink: &mut dyn FnMut(&[u8]) -> KResult<()>) -> KResult<usize> {
+    let address = super::resolve(&url.host)?;
+
+    let fd = socket::socket(socket::SocketType::Stream)?;
+    socket::set_recv_timeout(&fd, Some(TIMEOUT_MS))?;
+    socket::connect(&fd, SocketAddr::new(address, url.port))?;
+    request(&fd, url, sink)
+}
+
+// a hidden name next to path, so renaming it to path stays on the same filesystem
+fn temporary_path(path: &str) -> String {
+    let suffix = rng::next_u64() as u32;
+
+    match path.rsplit_once('/') {
+        Some((directory, name)) => format!("{}/.{}.{:08x}", directory, name, suffix),
+        None => format!(".{}.{:08x}", path, suffix),
+    }
+}
+
+/*
+    Downloads url into the file at path, which is made executable, it's most likely a
+    binary. It goes to a temporary file first, which replaces path only once all of it is
+    in, so a file that's already at path is left as it is if the download fails
+*/
+pub fn fetch(url: &str, path: &str) -> KResult<usize> {
+    let url = Url::parse(url)?;
+    let temporary = temporary_path(path);
+    let flags = vfs::Flags::O_CREAT | vfs::Flags::O_WRONLY | vfs::Flags::O_TRUNC;
+    let mode = vfs::Mode::from_bits_truncate(0o755);
+    let mut file = vfs::open(&temporary, flags, mode)?;
+
+    let result = get(&url, &mut |mut data| {
+        while !data.is_empty() {
+            match vfs::write(&mut file, data.as_ptr(), data.len())? {
+                0 => return Err(KError::ENOSPC),
+                written => data = &data[written..],
+            }
+        }
+        Ok(())
+    });
+    drop(file);
+
+    match result.and_then(|len| vfs::rename(&temporary, path).map(|_| len)) {
+        Ok(len) => Ok(len),
+        Err(err) => {
+            vfs::unlink(&temporary).ok();
+            Err(err)
+        }
+    }
+}
@@ -0,0 +1,133 @@
+/*
+    Icmp, only echo. Requests are answered with the same identifier, sequence and data, and
+    a reply goes to whoever's waiting in echo with its identifier
+*/
+
+use super::ipv4::{self, Header};
+use super::Ipv4Addr;
+use crate::drivers::timer_source;
+use crate::error::{KError, KResult};
+use crate::proc::wait::WaitQueue;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+pub const HEADER_SIZE: usize = 8;
+const ECHO_REPLY: u8 = 0;
+const ECHO_REQUEST: u8 = 8;
+
+// the replies that came in, by identifier, for the echoes that wait for them
+static REPLIES: spin::Mutex<BTreeMap<u16, Option<Reply>>> = spin::Mutex::new(BTreeMap::new());
+static REPLIED: WaitQueue = WaitQueue::new();
+
+#[derive(Clone, Copy)]
+struct Reply {
+    sequence: u16,
+    ttl: u8,
+    len: usize,
+    received_ns: u64,
+}
+
+pub struct Echo {
+    pub from: Ipv4Addr,
+    pub len: usize, // of the data
+    pub ttl: u8,
+    pub rtt_ns: u64,
+}
+
+fn message(kind: u8, identifier: u16, sequence: u16, data: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(HEADER_SIZE + data.len());
+    message.extend_from_slice(&[kind, 0, 0, 0]);
+    message.extend_from_slice(&identifier.to_be_bytes());
+    message.extend_from_slice(&sequence.to_be_bytes());
+    message.extend_from_slice(data);
+
+    let checksum = super::checksum(&message);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+    message
+}
+
+/*
+    Sends an echo request to destination and waits at most timeout_ms for its reply,
+    ETIMEDOUT if none came. EBUSY if someone else is waiting with identifier
+*/
+pub fn echo(
+    destination: Ipv4Addr,
+    identifier: u16,
+    sequence: u16,
+    data: &[u8],
+    timeout_ms: u64,
+) -> KResult<Echo> {
+    let source = ipv4::source_for(destination)?;
+    if REPLIES.lock().insert(identifier, None).is_some() {
+        return Err(KError::EBUSY);
+    }
+
+    let request = message(ECHO_REQUEST, identifier, sequence, data);
+    let sent_ns = timer_source::current_ns();
+    let deadline = sent_ns + timeout_ms * 1_000_000;
+
+    // the loopback answers before send returns, so it's waited for after
+    let mut result = ipv4::send(source, destination, ipv4::PROTOCOL_ICMP, &request);
+    let reply = loop {
+        let generation = REPLIED.generation();
+        let reply = REPLIES.lock().get(&identifier).copied().flatten();
+
+        match reply {
+            Some(reply) if reply.sequence == sequence => break Some(reply),
+            _ if result.is_err() => break None,
+            _ if !REPLIED.wait(generation, Some(deadline)) => {
+                result = Err(KError::ETIMEDOUT);
+                break None;
+            }
+            _ => {}
+        }
+    };
+    REPLIES.lock().remove(&identifier);
+
+    result?;
+    let reply = reply.ok_or(KError::ETIMEDOUT)?;
+    Ok(Echo {
+        from: destination,
+        len: reply.len,
+        ttl: reply.ttl,
+        rtt_ns: reply.received_ns - sent_ns,
+    })
+}
+
+// false if it was dropped
+pub fn receive(header: &Header, message: &[u8]) -> bool {
+    if message.len() < HEADER_SIZE || super::checksum(message) != 0 {
+        return false;
+    }
+
+    let identifier = u16::from_be_bytes([message[4], message[5]]);
+    let sequence = u16::from_be_bytes([message[6], message[7]]);
+    let data = &message[HEADER_SIZE..];
+
+    match message[0] {
+        ECHO_REQUEST => {
+            let reply = self::message(ECHO_REPLY, identifier, sequence, data);
+            let protocol = ipv4::PROTOCOL_ICMP;
+            ipv4::send(header.destination, header.source, protocol, &reply).is_ok()
+        }
+        ECHO_REPLY => {
+            let mut replies = REPLIES.lock();
+            let waiting = match replies.get_mut(&identifier) {
+                Some(waiting) => waiting,
+                None => return false,
+            };
+
+            *waiting = Some(Reply {
+                sequence,
+                ttl: header.ttl,
+                len: data.len(),
+                received_ns: timer_source::current_ns(),
+            });
+            drop(replies);
+
+            REPLIED.notify();
+            true
+        }
+        _ => false,
+    }
+}
@@ -0,0 +1,119 @@
+/*
+    Ipv4 packets, without options or fragments. Nothing that's sent needs to be split up, the
+    protocols above keep under the interface's mtu, and received fragments are dropped
+*/
+
+use super::{icmp, tcp, udp, Interface, Ipv4Addr};
+use crate::error::{KError, KResult};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+pub const HEADER_SIZE: usize = 20;
+const VERSION_IHL: u8 = 0x45; // version 4, a 5 word header
+const DEFAULT_TTL: u8 = 64;
+const MORE_FRAGMENTS: u16 = 0x2000;
+const FRAGMENT_OFFSET: u16 = 0x1fff;
+
+static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+
+pub struct Header {
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub protocol: u8,
+    pub ttl: u8,
+}
+
+// the header's part of a tcp or udp checksum, which covers both addresses too
+pub fn pseudo_header_sum(source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, len: usize) -> u32 {
+    let mut sum = super::checksum_add(0, &source.0);
+    sum = super::checksum_add(sum, &destination.0);
+    super::checksum_add(sum, &[0, protocol, (len >> 8) as u8, len as u8])
+}
+
+// the address packets to destination are sent from, one of ours is talked to from itself
+pub fn source_for(destination: Ipv4Addr) -> KResult<Ipv4Addr> {
+    let interface = super::route(destination)?;
+
+    if interface.is_loopback() {
+        Ok(destination)
+    } else {
+        Ok(interface.address)
+    }
+}
+
+// the biggest payload a packet to destination can have
+pub fn max_payload(destination: Ipv4Addr) -> KResult<usize> {
+    Ok(super::route(destination)?.mtu() - HEADER_SIZE)
+}
+
+pub fn send(source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, payload: &[u8]) -> KResult<()> {
+    let interface = super::route(destination)?;
+    let len = HEADER_SIZE + payload.len();
+    if len > interface.mtu() {
+        return Err(KError::EMSGSIZE);
+    }
+
+    let mut packet = Vec::with_capacity(len);
+    packet.extend_from_slice(&[VERSION_IHL, 0]);
+    packet.extend_from_slice(&(len as u16).to_be_bytes());
+    packet.extend_from_slice(&NEXT_ID.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    packet.extend_from_slice(&[0, 0, DEFAULT_TTL, protocol, 0, 0]);
+    packet.extend_from_slice(&source.0);
+    packet.extend_from_slice(&destination.0);
+
+    let checksum = super::checksum(&packet);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    packet.extend_from_slice(payload);
+    interface.transmit(packet)
+}
+
+// the header and the payload, None if it's malformed or a fragment
+pub fn parse(packet: &[u8]) -> Option<(Header, &[u8])> {
+    if packet.len() < HEADER_SIZE || packet[0] >> 4 != 4 {
+        return None;
+    }
+
+    let header_len = (packet[0] & 0xf) as usize * 4;
+    let total_len = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+    if header_len < HEADER_SIZE || total_len < header_len || total_len > packet.len() {
+        return None;
+    }
+    if super::checksum(&packet[..header_len]) != 0 {
+        return None;
+    }
+
+    let fragment = u16::from_be_bytes([packet[6], packet[7]]);
+    if fragment & (MORE_FRAGMENTS | FRAGMENT_OFFSET) != 0 {
+        return None;
+    }
+
+    let header = Header {
+        source: Ipv4Addr([packet[12], packet[13], packet[14], packet[15]]),
+        destination: Ipv4Addr([packet[16], packet[17], packet[18], packet[19]]),
+        protocol: packet[9],
+        ttl: packet[8],
+    };
+    Some((header, &packet[header_len..total_len]))
+}
+
+// hands the payload to its protocol, what isn't for this machine is dropped, there's no forwarding
+pub fn receive(interface: &'static Interface, packet: &[u8]) {
+    let delivered = match parse(packet) {
+        Some((header, payload)) if super::is_local(header.destination) => match header.protocol {
+            PROTOCOL_ICMP => icmp::receive(&header, payload),
+            PROTOCOL_TCP => tcp::receive(&header, payload),
+            PROTOCOL_UDP => udp::receive(&header, payload),
+            _ => false,
+        },
+        _ => false,
+    };
+
+    if !delivered {
+        interface.stats.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
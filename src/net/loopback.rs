@@ -0,0 +1,60 @@
+/*
+    The loopback interface, 127.0.0.1/8. What's sent is received right away, but not from
+    inside of the send: the stack can answer a packet while it's handling one, so packets are
+    queued and whoever sends first hands them to the stack until the queue is empty
+*/
+
+use super::{Interface, Ipv4Addr, NetDevice};
+use crate::error::KResult;
+use crate::log;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const MTU: usize = 65535;
+
+static LOOPBACK: Loopback = Loopback {
+    queue: spin::Mutex::new(VecDeque::new()),
+    draining: AtomicBool::new(false),
+};
+
+struct Loopback {
+    queue: spin::Mutex<VecDeque<Vec<u8>>>,
+    draining: AtomicBool,
+}
+
+impl NetDevice for Loopback {
+    fn mtu(&self) -> usize {
+        MTU
+    }
+
+    fn transmit(&self, interface: &'static Interface, packet: Vec<u8>) -> KResult<()> {
+        self.queue.lock().push_back(packet);
+
+        // whoever's draining could have just seen an empty queue, so it's checked again after
+        while !self.queue.lock().is_empty() {
+            if self.draining.swap(true, Ordering::Acquire) {
+                return Ok(());
+            }
+
+            loop {
+                let packet = self.queue.lock().pop_front();
+                match packet {
+                    Some(packet) => interface.receive(&packet),
+                    None => break,
+                }
+            }
+
+            self.draining.store(false, Ordering::Release);
+        }
+
+        Ok(())
+    }
+}
+
+pub fn init() {
+    let netmask = Ipv4Addr([255, 0, 0, 0]);
+    if let Err(err) = super::add_interface("lo", &LOOPBACK, Ipv4Addr::LOCALHOST, netmask) {
+        log::error!("[NET] Could not add the loopback interface: {}\n", err);
+    }
+}
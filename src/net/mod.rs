@@ -0,0 +1,239 @@
+/*
+    The network stack. An interface is a device that takes and hands over ipv4 packets with
+    an address of its own, there's only loopback until there's a nic driver. Packets are
+    routed to the interface whose subnet has the destination, or else to the first one that
+    isn't loopback.
+
+    Interfaces are never removed, so they're leaked and handed out as &'static
+*/
+
+use crate::error::{KError, KResult};
+use crate::log;
+use alloc::{boxed::Box, vec::Vec};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+pub mod dns;
+pub mod http;
+pub mod icmp;
+pub mod ipv4;
+pub mod loopback;
+pub mod socket;
+pub mod tcp;
+pub mod udp;
+
+pub use dns::resolve;
+
+static INTERFACES: spin::Mutex<Vec<&'static Interface>> = spin::Mutex::new(Vec::new());
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub struct Ipv4Addr(pub [u8; 4]);
+
+impl Ipv4Addr {
+    pub const UNSPECIFIED: Ipv4Addr = Ipv4Addr([0, 0, 0, 0]);
+    pub const LOCALHOST: Ipv4Addr = Ipv4Addr([127, 0, 0, 1]);
+    pub const BROADCAST: Ipv4Addr = Ipv4Addr([255, 255, 255, 255]);
+
+    pub fn from_u32(value: u32) -> Self {
+        Ipv4Addr(value.to_be_bytes())
+    }
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes(self.0)
+    }
+
+    pub fn is_unspecified(self) -> bool {
+        self == Ipv4Addr::UNSPECIFIED
+    }
+
+    // all of 127.0.0.0/8
+    pub fn is_loopback(self) -> bool {
+        self.0[0] == 127
+    }
+
+    // dotted decimal, None if it isn't exactly 4 numbers under 256
+    pub fn parse(text: &str) -> Option<Self> {
+        let mut bytes = [0u8; 4];
+        let mut parts = text.split('.');
+
+        for byte in bytes.iter_mut() {
+            *byte = parts.next()?.parse().ok()?;
+        }
+
+        parts.next().is_none().then(|| Ipv4Addr(bytes))
+    }
+}
+
+impl fmt::Display for Ipv4Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}.{}", self.0[0], self.0[1], self.0[2], self.0[3])
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SocketAddr {
+    pub ip: Ipv4Addr,
+    pub port: u16,
+}
+
+impl SocketAddr {
+    pub fn new(ip: Ipv4Addr, port: u16) -> Self {
+        SocketAddr { ip, port }
+    }
+}
+
+impl fmt::Display for SocketAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.ip, self.port)
+    }
+}
+
+pub trait NetDevice: Sync {
+    // the biggest packet it can take, with the ipv4 header
+    fn mtu(&self) -> usize;
+    // hands over a whole ipv4 packet, the interface is the one the device is under
+    fn transmit(&self, interface: &'static Interface, packet: Vec<u8>) -> KResult<()>;
+    // the addresses it found the hardware addresses of, only a device with a link layer has any
+    fn neighbors(&self) -> Vec<(Ipv4Addr, [u8; 6])> {
+        Vec::new()
+    }
+}
+
+#[derive(Default)]
+pub struct Stats {
+    pub rx_packets: AtomicU64,
+    pub rx_bytes: AtomicU64,
+    pub tx_packets: AtomicU64,
+    pub tx_bytes: AtomicU64,
+    pub dropped: AtomicU64, // received but thrown away, malformed or for nobody
+}
+
+pub struct Interface {
+    pub name: &'static str,
+    pub address: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub stats: Stats,
+    device: &'static dyn NetDevice,
+}
+
+impl Interface {
+    pub fn mtu(&self) -> usize {
+        self.device.mtu()
+    }
+
+    // whether ip is in the interface's subnet
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        let mask = self.netmask.to_u32();
+        ip.to_u32() & mask == self.address.to_u32() & mask
+    }
+
+    pub fn neighbors(&self) -> Vec<(Ipv4Addr, [u8; 6])> {
+        self.device.neighbors()
+    }
+
+    pub fn is_loopback(&self) -> bool {
+        self.address.is_loopback()
+    }
+
+    pub fn transmit(&'static self, packet: Vec<u8>) -> KResult<()> {
+        self.stats.tx_packets.fetch_add(1, Ordering::Relaxed);
+        self.stats.tx_bytes.fetch_add(packet.len() as u64, Ordering::Relaxed);
+        self.device.transmit(self, packet)
+    }
+
+    // a packet the device received, can't be called with any of the stack's locks held
+    pub fn receive(&'static self, packet: &[u8]) {
+        self.stats.rx_packets.fetch_add(1, Ordering::Relaxed);
+        self.stats.rx_bytes.fetch_add(packet.len() as u64, Ordering::Relaxed);
+        ipv4::receive(self, packet);
+    }
+}
+
+pub fn add_interface(
+    name: &'static str,
+    device: &'static dyn NetDevice,
+    address: Ipv4Addr,
+    netmask: Ipv4Addr,
+) -> KResult<&'static Interface> {
+    let mut interfaces = INTERFACES.lock();
+    if interfaces.iter().any(|interface| interface.name == name) {
+        return Err(KError::EEXIST);
+    }
+
+    let interface: &'static Interface = Box::leak(Box::new(Interface {
+        name,
+        address,
+        netmask,
+        stats: Stats::default(),
+        device,
+    }));
+    interfaces.push(interface);
+
+    log::info!("[NET] {} is up with {}/{}\n", name, address, netmask);
+    Ok(interface)
+}
+
+pub fn interfaces() -> Vec<&'static Interface> {
+    INTERFACES.lock().clone()
+}
+
+// the interface a packet to destination goes out of
+pub fn route(destination: Ipv4Addr) -> KResult<&'static Interface> {
+    let interfaces = INTERFACES.lock();
+
+    if destination.is_loopback() || is_own(&interfaces, destination) {
+        return interfaces
+            .iter()
+            .find(|interface| interface.is_loopback())
+            .copied()
+            .ok_or(KError::ENETUNREACH);
+    }
+
+    interfaces
+        .iter()
+        .find(|interface| !interface.is_loopback() && interface.contains(destination))
+        .or_else(|| interfaces.iter().find(|interface| !interface.is_loopback()))
+        .copied()
+        .ok_or(KError::ENETUNREACH)
+}
+
+fn is_own(interfaces: &[&'static Interface], ip: Ipv4Addr) -> bool {
+    interfaces.iter().any(|interface| interface.address == ip)
+}
+
+// whether packets to ip are for this machine
+pub fn is_local(ip: Ipv4Addr) -> bool {
+    ip.is_loopback() || ip == Ipv4Addr::BROADCAST || is_own(&INTERFACES.lock(), ip)
+}
+
+// adds data to a ones' complement sum, as 16 bit big endian words
+pub fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += u16::from_be_bytes([word[0], word[1]]) as u32;
+    }
+    if let [last] = words.remainder() {
+        sum += (*last as u32) << 8;
+    }
+
+    // folded as it goes, so it never overflows however much is added
+    (sum & 0xffff) + (sum >> 16)
+}
+
+// the internet checksum of what was summed, 0 once it's summed with its own checksum
+pub fn checksum_finish(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+pub fn checksum(data: &[u8]) -> u16 {
+    checksum_finish(checksum_add(0, data))
+}
+
+pub fn init() {
+    loopback::init();
+    dns::init();
+}
@@ -0,0 +1,571 @@
+/*
+    Sockets, which are files like any other: reading one receives, writing one sends and poll
+    waits on it, so a socket goes wherever a descriptor does. They live in an anonymous
+    filesystem of their own, and what only sockets can do takes their description.
+
+    A socket gets a port before it first sends, an ephemeral one if it wasn't bound to one,
+    and udp and tcp ports are handed out apart. What comes in for a datagram (udp) socket's
+    port is queued on it. A stream (tcp) socket either listens, and accept makes a socket for
+    each connection that comes in, or is connected, and then it only passes bytes to and from
+    its connection (see tcp.rs), which wakes it up when something changes
+*/
+
+use super::{ipv4, tcp, udp, Ipv4Addr, SocketAddr};
+use crate::drivers::timer_source;
+use crate::error::{KError, KResult};
+use crate::fs::vfs::{self, PollEvents};
+use crate::proc::wait::WaitQueue;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicU16, Ordering};
+
+const MAX_SOCKETS: usize = 256;
+const MAX_QUEUED: usize = 64; // datagrams, the ones that come in after that are dropped
+const EPHEMERAL_FIRST: u16 = 49152;
+
+// one wait queue per slot, a queue has to outlive the socket it's for
+const QUEUE: WaitQueue = WaitQueue::new();
+static SOCKFS: Sockfs = Sockfs {
+    queues: [QUEUE; MAX_SOCKETS],
+};
+static SOCKETS: spin::Mutex<Vec<Option<Arc<Socket>>>> = spin::Mutex::new(Vec::new());
+// who has which udp port, locked before any socket's state
+static UDP_PORTS: spin::Mutex<BTreeMap<u16, Arc<Socket>>> = spin::Mutex::new(BTreeMap::new());
+// an accepted socket shares the listener's port, so it's never here
+static TCP_PORTS: spin::Mutex<BTreeMap<u16, Arc<Socket>>> = spin::Mutex::new(BTreeMap::new());
+static NEXT_EPHEMERAL: AtomicU16 = AtomicU16::new(EPHEMERAL_FIRST);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SocketType {
+    Datagram,
+    Stream,
+}
+
+bitflags::bitflags! {
+    // the same bits as linux's
+    pub struct MsgFlags: u32 {
+        const DONTWAIT = 0x40;
+    }
+}
+
+struct Datagram {
+    from: SocketAddr,
+    data: Vec<u8>,
+}
+
+enum Stream {
+    Idle,
+    Listening(Arc<tcp::Listener>),
+    Connected(Arc<tcp::Connection>),
+}
+
+struct State {
+    local: Option<SocketAddr>,  // once it's bound
+    remote: Option<SocketAddr>, // once it's connected, then only what it sends comes in
+    received: VecDeque<Datagram>,
+    recv_timeout_ms: Option<u64>,
+    stream: Stream, // always Idle for a datagram socket
+}
+
+impl State {
+    fn new(local: Option<SocketAddr>, remote: Option<SocketAddr>, stream: Stream) -> Self {
+        State {
+            local,
+            remote,
+            received: VecDeque::new(),
+            recv_timeout_ms: None,
+            stream,
+        }
+    }
+}
+
+pub struct Socket {
+    index: usize,
+    kind: SocketType,
+    state: spin::Mutex<State>,
+}
+
+impl Socket {
+    // notified when something comes in
+    fn queue(&self) -> &'static WaitQueue {
+        &SOCKFS.queues[self.index]
+    }
+}
+
+fn get(fd: &vfs::FileDescription) -> KResult<Arc<Socket>> {
+    if fd.fs.name() != vfs::Filesystem::name(&SOCKFS) {
+        return Err(KError::ENOTSOCK);
+    }
+
+    by_index(fd.file_index)
+}
+
+fn by_index(index: usize) -> KResult<Arc<Socket>> {
+    SOCKETS.lock().get(index).cloned().flatten().ok_or(KError::EBADF)
+}
+
+// what tcp wakes a socket up with
+pub(super) fn wake(index: usize) {
+    if let Some(queue) = SOCKFS.queues.get(index) {
+        queue.notify();
+    }
+}
+
+fn ports(kind: SocketType) -> &'static spin::Mutex<BTreeMap<u16, Arc<Socket>>> {
+    match kind {
+        SocketType::Datagram => &UDP_PORTS,
+        SocketType::Stream => &TCP_PORTS,
+    }
+}
+
+fn connection(socket: &Socket) -> KResult<Arc<tcp::Connection>> {
+    match &socket.state.lock().stream {
+        Stream::Connected(connection) => Ok(connection.clone()),
+        _ => Err(KError::ENOTCONN),
+    }
+}
+
+pub fn socket(kind: SocketType) -> KResult<vfs::FileDescription> {
+    let index = add(kind, State::new(None, None, Stream::Idle))?;
+    Ok(vfs::FileDescription::new(index, vfs::Flags::O_RDWR, &SOCKFS))
+}
+
+fn add(kind: SocketType, state: State) -> KResult<usize> {
+    let mut sockets = SOCKETS.lock();
+    let index = match sockets.iter().position(|slot| slot.is_none()) {
+        Some(index) => index,
+        None if sockets.len() < MAX_SOCKETS => {
+            sockets.push(None);
+            sockets.len() - 1
+        }
+        None => return Err(KError::ENFILE),
+    };
+
+    sockets[index] = Some(Arc::new(Socket {
+        index,
+        kind,
+        state: spin::Mutex::new(state),
+    }));
+    Ok(index)
+}
+
+// the first free ephemeral port, going around from the one after the last that was handed out
+fn ephemeral_port(ports: &BTreeMap<u16, Arc<Socket>>) -> KResult<u16> {
+    let count = (u16::MAX - EPHEMERAL_FIRST) as u32 + 1;
+    let start = NEXT_EPHEMERAL.load(Ordering::Relaxed);
+
+    for i in 0..count {
+        let offset = ((start - EPHEMERAL_FIRST) as u32 + i) % count;
+        let port = EPHEMERAL_FIRST + offset as u16;
+        if !ports.contains_key(&port) {
+            let next = if port == u16::MAX { EPHEMERAL_FIRST } else { port + 1 };
+            NEXT_EPHEMERAL.store(next, Ordering::Relaxed);
+            return Ok(port);
+        }
+    }
+
+    Err(KError::EADDRINUSE)
+}
+
+fn bind_locked(
+    ports: &mut BTreeMap<u16, Arc<Socket>>,
+    socket: &Arc<Socket>,
+    addr: SocketAddr,
+) -> KResult<SocketAddr> {
+    let port = match addr.port {
+        0 => ephemeral_port(ports)?,
+        port if ports.contains_key(&port) => return Err(KError::EADDRINUSE),
+        port => port,
+    };
+
+    let local = SocketAddr::new(addr.ip, port);
+    ports.insert(port, socket.clone());
+    socket.state.lock().local = Some(local);
+    Ok(local)
+}
+
+// a port of 0 picks an ephemeral one, an address of 0.0.0.0 takes what comes in for any of ours
+pub fn bind(fd: &vfs::FileDescription, addr: SocketAddr) -> KResult<()> {
+    let socket = get(fd)?;
+    if !addr.ip.is_unspecified() && !super::is_local(addr.ip) {
+        return Err(KError::EADDRNOTAVAIL);
+    }
+
+    let mut ports = ports(socket.kind).lock();
+    if socket.state.lock().local.is_some() {
+        return Err(KError::EINVAL);
+    }
+
+    bind_locked(&mut ports, &socket, addr).map(|_| ())
+}
+
+// where the socket is bound, to any address and an ephemeral port if it wasn't yet
+fn local_or_bind(socket: &Arc<Socket>) -> KResult<SocketAddr> {
+    let mut ports = ports(socket.kind).lock();
+    if let Some(local) = socket.state.lock().local {
+        return Ok(local);
+    }
+
+    bind_locked(&mut ports, socket, SocketAddr::new(Ipv4Addr::UNSPECIFIED, 0))
+}
+
+/*
+    Sets where send sends to, and only what comes from there is received from then on. A
+    stream socket makes a connection, and waits until the handshake is done
+*/
+pub fn connect(fd: &vfs::FileDescription, addr: SocketAddr) -> KResult<()> {
+    let socket = get(fd)?;
+    if addr.port == 0 {
+        return Err(KError::EINVAL);
+    }
+
+    // fails now if there's no way to get there
+    let source = ipv4::source_for(addr.ip)?;
+    if socket.kind == SocketType::Stream {
+        return connect_stream(&socket, addr, source);
+    }
+
+    local_or_bind(&socket)?;
+
+    let mut state = socket.state.lock();
+    state.remote = Some(addr);
+    // what came from anyone else before isn't received anymore either
+    state.received.retain(|datagram| datagram.from == addr);
+    Ok(())
+}
+
+fn connect_stream(socket: &Arc<Socket>, addr: SocketAddr, source: Ipv4Addr) -> KResult<()> {
+    if !matches!(socket.state.lock().stream, Stream::Idle) {
+        return Err(KError::EISCONN);
+    }
+
+    let local = local_or_bind(socket)?;
+    let ip = if local.ip.is_unspecified() { source } else { local.ip };
+    let connection = tcp::connect(SocketAddr::new(ip, local.port), addr, socket.index)?;
+
+    let mut state = socket.state.lock();
+    state.remote = Some(addr);
+    state.stream = Stream::Connected(connection.clone());
+    drop(state);
+
+    loop {
+        let generation = socket.queue().generation();
+        match connection.state() {
+            tcp::State::SynSent | tcp::State::SynReceived => {}
+            tcp::State::Closed => break,
+            _ => return Ok(()),
+        }
+
+        socket.queue().wait(generation, None);
+    }
+
+    // it can be connected again
+    let mut state = socket.state.lock();
+    state.remote = None;
+    state.stream = Stream::Idle;
+    Err(connection.error().unwrap_or(KError::ECONNREFUSED))
+}
+
+// connections for the socket's port are taken, at most backlog of them waiting for accept
+pub fn listen(fd: &vfs::FileDescription, backlog: usize) -> KResult<()> {
+    let socket = get(fd)?;
+    if socket.kind != SocketType::Stream {
+        return Err(KError::EOPNOTSUPP);
+    }
+
+    let local = local_or_bind(&socket)?;
+    let mut state = socket.state.lock();
+    match state.stream {
+        Stream::Idle => {}
+        Stream::Listening(_) => return Ok(()),
+        Stream::Connected(_) => return Err(KError::EINVAL),
+    }
+
+    state.stream = Stream::Listening(tcp::listen(local, backlog, socket.index)?);
+    Ok(())
+}
+
+/*
+    A connection that came in on a listening socket, as a socket of its own, and where it's
+    from. Waits for one at most the receive timeout, EAGAIN if none came
+*/
+pub fn accept(fd: &vfs::FileDescription) -> KResult<(vfs::FileDescription, SocketAddr)> {
+    let socket = get(fd)?;
+    let state = socket.state.lock();
+    let (listener, timeout) = match &state.stream {
+        Stream::Listening(listener) => (listener.clone(), state.recv_timeout_ms),
+        _ => return Err(KError::EINVAL),
+    };
+    drop(state);
+    let deadline = timeout.map(|ms| timer_source::current_ns() + ms * 1_000_000);
+
+    let connection = loop {
+        let generation = socket.queue().generation();
+        if let Some(connection) = listener.accept() {
+            break connection;
+        }
+
+        if !socket.queue().wait(generation, deadline) {
+            return Err(KError::EAGAIN);
+        }
+    };
+
+    let (local, remote) = (connection.local, connection.remote);
+    let state = State::new(Some(local), Some(remote), Stream::Connected(connection.clone()));
+    let index = match add(SocketType::Stream, state) {
+        Ok(index) => index,
+        Err(err) => {
+            connection.abort();
+            return Err(err);
+        }
+    };
+
+    connection.set_socket(index);
+    Ok((vfs::FileDescription::new(index, vfs::Flags::O_RDWR, &SOCKFS), remote))
+}
+
+// to where the socket is connected, EDESTADDRREQ if it isn't
+pub fn send(fd: &vfs::FileDescription, data: &[u8]) -> KResult<usize> {
+    send_connected(&get(fd)?, data)
+}
+
+fn send_connected(socket: &Arc<Socket>, data: &[u8]) -> KResult<usize> {
+    if socket.kind == SocketType::Stream {
+        return send_stream(socket, data);
+    }
+
+    let remote = socket.state.lock().remote;
+    send_socket(socket, data, remote.ok_or(KError::EDESTADDRREQ)?)
+}
+
+/*
+    The whole of data in one datagram, EMSGSIZE if it doesn't fit. A stream socket sends to
+    where it's connected whatever addr is
+*/
+pub fn send_to(fd: &vfs::FileDescription, data: &[u8], addr: SocketAddr) -> KResult<usize> {
+    let socket = get(fd)?;
+    if socket.kind == SocketType::Stream {
+        return send_stream(&socket, data);
+    }
+
+    send_socket(&socket, data, addr)
+}
+
+// waits for room in the send buffer until all of data is in, or the connection fails
+fn send_stream(socket: &Arc<Socket>, data: &[u8]) -> KResult<usize> {
+    let connection = connection(socket)?;
+    let mut sent = 0;
+
+    while sent < data.len() {
+        let generation = socket.queue().generation();
+        match connection.send(&data[sent..]) {
+            Ok(len) => sent += len,
+            Err(KError::EAGAIN) => {
+                socket.queue().wait(generation, None);
+            }
+            Err(err) if sent == 0 => return Err(err),
+            Err(_) => break,
+        }
+    }
+
+    Ok(sent)
+}
+
+fn send_socket(socket: &Arc<Socket>, data: &[u8], addr: SocketAddr) -> KResult<usize> {
+    let local = local_or_bind(socket)?;
+
+    let source = if local.ip.is_unspecified() {
+        ipv4::source_for(addr.ip)?
+    } else {
+        local.ip
+    };
+
+    // nothing of the socket is locked, the loopback delivers before this returns
+    udp::send(SocketAddr::new(source, local.port), addr, data)?;
+    Ok(data.len())
+}
+
+pub fn recv(fd: &vfs::FileDescription, buffer: &mut [u8], flags: MsgFlags) -> KResult<usize> {
+    recv_from(fd, buffer, flags).map(|(len, _)| len)
+}
+
+/*
+    The next datagram and who sent it, waiting for one unless it's DONTWAIT, and then at most
+    the receive timeout. A datagram too big for buffer is cut short, the rest is lost. EAGAIN
+    if there was nothing. A stream socket gets what came in on its connection so far, 0 once
+    the peer closed it
+*/
+pub fn recv_from(
+    fd: &vfs::FileDescription,
+    buffer: &mut [u8],
+    flags: MsgFlags,
+) -> KResult<(usize, SocketAddr)> {
+    recv_socket(&get(fd)?, buffer, flags)
+}
+
+fn recv_socket(
+    socket: &Arc<Socket>,
+    buffer: &mut [u8],
+    flags: MsgFlags,
+) -> KResult<(usize, SocketAddr)> {
+    let timeout = socket.state.lock().recv_timeout_ms;
+    let deadline = timeout.map(|ms| timer_source::current_ns() + ms * 1_000_000);
+    let connection = match socket.kind {
+        SocketType::Stream => Some(connection(socket)?),
+        SocketType::Datagram => None,
+    };
+
+    loop {
+        let generation = socket.queue().generation();
+        if let Some(connection) = &connection {
+            match connection.recv(buffer) {
+                Err(KError::EAGAIN) => {}
+                result => return result.map(|len| (len, connection.remote)),
+            }
+        } else if let Some(datagram) = socket.state.lock().received.pop_front() {
+            let len = datagram.data.len().min(buffer.len());
+            buffer[..len].copy_from_slice(&datagram.data[..len]);
+            return Ok((len, datagram.from));
+        }
+
+        if flags.contains(MsgFlags::DONTWAIT) || !socket.queue().wait(generation, deadline) {
+            return Err(KError::EAGAIN);
+        }
+    }
+}
+
+// how long recv waits for something to come in, None is forever
+pub fn set_recv_timeout(fd: &vfs::FileDescription, timeout_ms: Option<u64>) -> KResult<()> {
+    get(fd)?.state.lock().recv_timeout_ms = timeout_ms;
+    Ok(())
+}
+
+// 0.0.0.0:0 until it's bound, a connected stream socket has its connection's address
+pub fn local_addr(fd: &vfs::FileDescription) -> KResult<SocketAddr> {
+    let socket = get(fd)?;
+    let state = socket.state.lock();
+    if let Stream::Connected(connection) = &state.stream {
+        return Ok(connection.local);
+    }
+
+    Ok(state.local.unwrap_or(SocketAddr::new(Ipv4Addr::UNSPECIFIED, 0)))
+}
+
+pub fn peer_addr(fd: &vfs::FileDescription) -> KResult<SocketAddr> {
+    get(fd)?.state.lock().remote.ok_or(KError::ENOTCONN)
+}
+
+// queues a datagram on whoever has its port, false if nobody takes it
+pub fn deliver_datagram(from: SocketAddr, to: SocketAddr, data: &[u8]) -> bool {
+    let socket = match UDP_PORTS.lock().get(&to.port) {
+        Some(socket) => socket.clone(),
+        None => return false,
+    };
+
+    let mut state = socket.state.lock();
+    let for_us = match state.local {
+        Some(local) => local.ip.is_unspecified() || local.ip == to.ip,
+        None => false,
+    };
+    let from_peer = state.remote.map_or(true, |remote| remote == from);
+    if !for_us || !from_peer || state.received.len() == MAX_QUEUED {
+        return false;
+    }
+
+    state.received.push_back(Datagram {
+        from,
+        data: Vec::from(data),
+    });
+    drop(state);
+
+    socket.queue().notify();
+    true
+}
+
+struct Sockfs {
+    queues: [WaitQueue; MAX_SOCKETS],
+}
+
+impl vfs::Filesystem for Sockfs {
+    fn name(&self) -> &'static str {
+        "sockfs"
+    }
+
+    // sockets are only made by socket, they have no names
+    fn open(
+        &self,
+        _path: &str,
+        _flags: vfs::Flags,
+        _mode: vfs::Mode,
+    ) -> KResult<vfs::FileDescription> {
+        Err(KError::ENOENT)
+    }
+
+    fn mkdir(&self, _path: &str, _mode: vfs::Mode) -> KResult<vfs::FileDescription> {
+        Err(KError::EPERM)
+    }
+
+    // a datagram at a time, or what came in on the connection, there's no offset in a socket
+    fn read(&self, index: usize, buffer: *mut u8, cnt: usize, _offset: usize) -> KResult<usize> {
+        let buffer = unsafe { core::slice::from_raw_parts_mut(buffer, cnt) };
+        recv_socket(&by_index(index)?, buffer, MsgFlags::empty()).map(|(len, _)| len)
+    }
+
+    // to where the socket is connected
+    fn write(&self, index: usize, buffer: *const u8, cnt: usize, _offset: usize) -> KResult<usize> {
+        let data = unsafe { core::slice::from_raw_parts(buffer, cnt) };
+        send_connected(&by_index(index)?, data)
+    }
+
+    fn readdir(&self, _index: usize, _offset: usize) -> KResult<Option<vfs::DirEntry>> {
+        Err(KError::ENOTDIR)
+    }
+
+    fn poll(&self, index: usize) -> KResult<PollEvents> {
+        let socket = by_index(index)?;
+        let state = socket.state.lock();
+
+        match &state.stream {
+            Stream::Connected(connection) => return Ok(connection.poll()),
+            Stream::Listening(listener) if listener.has_pending() => return Ok(PollEvents::POLLIN),
+            Stream::Listening(_) => return Ok(PollEvents::empty()),
+            Stream::Idle => {}
+        }
+
+        if state.received.is_empty() {
+            Ok(PollEvents::POLLOUT)
+        } else {
+            Ok(PollEvents::POLLIN | PollEvents::POLLOUT)
+        }
+    }
+
+    fn wait_queue(&self, index: usize) -> Option<&WaitQueue> {
+        self.queues.get(index)
+    }
+
+    // the port is free again once the last description is gone, a connection closes on its own
+    fn close(&self, index: usize) {
+        let socket = match SOCKETS.lock().get_mut(index).and_then(|slot| slot.take()) {
+            Some(socket) => socket,
+            None => return,
+        };
+
+        let mut state = socket.state.lock();
+        let stream = core::mem::replace(&mut state.stream, Stream::Idle);
+        let local = state.local;
+        drop(state);
+
+        match stream {
+            Stream::Listening(listener) => listener.close(),
+            Stream::Connected(connection) => connection.close(),
+            Stream::Idle => {}
+        }
+
+        if let Some(local) = local {
+            let mut ports = ports(socket.kind).lock();
+            if ports.get(&local.port).map_or(false, |owner| Arc::ptr_eq(owner, &socket)) {
+                ports.remove(&local.port);
+            }
+        }
+    }
+}
@@ -0,0 +1,988 @@
+/*
+    Tcp. A connection is found by both of its ends, and a syn for a port that's listened on
+    starts a new one, which the listener hands to accept once the handshake is done.
+
+    Everything happens as segments come in: what they ack leaves the send buffer, what they
+    carry goes to the receive buffer, and then whatever the peer's window has room for is
+    sent. Only what comes in order is taken, a segment after a gap is dropped and the last
+    byte in order is acked again, the peer will send it again. Segments are made with the
+    connection locked and only sent once it isn't, the loopback hands them right back.
+
+    When the retransmission timeout runs out the first segment that wasn't acked is sent
+    again, and the timeout doubles. The timeout comes from the round trip times measured on
+    segments that weren't sent again. ktcpd checks the timers on every tick of the timer, and
+    ends connections that wait out TIME-WAIT
+*/
+
+use super::ipv4::{self, Header};
+use super::{socket, SocketAddr};
+use crate::drivers::{timer, timer_source};
+use crate::error::{KError, KResult};
+use crate::fs::vfs::PollEvents;
+use crate::log;
+use crate::proc::process::{Process, SelectorValues, Thread};
+use crate::proc::{scheduler, wait::WaitQueue};
+use crate::rng;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+const HEADER_SIZE: usize = 20;
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+const DEFAULT_MSS: usize = 536; // when the peer doesn't say
+const SEND_BUFFER: usize = 64 * 1024;
+const RECV_BUFFER: usize = 65535; // also the biggest window, there's no window scaling
+const RTO_INITIAL_MS: u64 = 1000;
+const RTO_MIN_MS: u64 = 200;
+const RTO_MAX_MS: u64 = 60_000;
+const SYN_RETRIES: u32 = 5;
+const RETRIES: u32 = 12;
+const TIME_WAIT_MS: u64 = 30_000;
+const TICK_MS: u64 = 100;
+const NO_SOCKET: usize = usize::MAX;
+
+static CONNECTIONS: spin::Mutex<Vec<Arc<Connection>>> = spin::Mutex::new(Vec::new());
+static LISTENERS: spin::Mutex<BTreeMap<u16, Arc<Listener>>> = spin::Mutex::new(BTreeMap::new());
+// notified by the timer, ktcpd checks the timers every time
+static TICK: WaitQueue = WaitQueue::new();
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum State {
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+    Closed,
+}
+
+// sequence numbers wrap around, a is before b if it's less than half the space behind it
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+fn seq_le(a: u32, b: u32) -> bool {
+    !seq_lt(b, a)
+}
+
+struct Segment {
+    local: SocketAddr,
+    remote: SocketAddr,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>, // only on a syn
+    data: Vec<u8>,
+}
+
+struct Incoming<'a> {
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    window: u16,
+    mss: Option<u16>,
+    data: &'a [u8],
+}
+
+impl Incoming<'_> {
+    fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+}
+
+struct Tcb {
+    state: State,
+    iss: u32,
+    snd_una: u32, // the first byte that wasn't acked
+    snd_nxt: u32,
+    snd_wnd: u32,
+    rcv_nxt: u32,
+    mss: usize,                // the peer's
+    send_buffer: VecDeque<u8>, // what wasn't acked, from snd_una on
+    recv_buffer: VecDeque<u8>,
+    closing: bool, // closed on this end, a fin goes after what's in the send buffer
+    fin_sent: bool,
+    rto_ms: u64,
+    srtt_ms: Option<u64>,
+    rttvar_ms: u64,
+    timing: Option<(u32, u64)>, // the end of a segment that's timed and when it was sent
+    retransmit_at: Option<u64>,
+    retries: u32,
+    time_wait_until: Option<u64>,
+    error: Option<KError>,
+    listener: Option<Arc<Listener>>, // while it's a handshake for a listener
+}
+
+impl Tcb {
+    fn new(state: State, iss: u32) -> Self {
+        Tcb {
+            state,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss.wrapping_add(1), // the syn
+            snd_wnd: 0,
+            rcv_nxt: 0,
+            mss: DEFAULT_MSS,
+            send_buffer: VecDeque::new(),
+            recv_buffer: VecDeque::new(),
+            closing: false,
+            fin_sent: false,
+            rto_ms: RTO_INITIAL_MS,
+            srtt_ms: None,
+            rttvar_ms: 0,
+            timing: None,
+            retransmit_at: None,
+            retries: 0,
+            time_wait_until: None,
+            error: None,
+            listener: None,
+        }
+    }
+
+    fn window(&self) -> u16 {
+        (RECV_BUFFER - self.recv_buffer.len()) as u16
+    }
+
+    fn fin_acked(&self) -> bool {
+        self.fin_sent && self.snd_una == self.snd_nxt
+    }
+
+    // whether the peer sent everything it's going to
+    fn peer_closed(&self) -> bool {
+        matches!(
+            self.state,
+            State::CloseWait | State::Closing | State::LastAck | State::TimeWait | State::Closed
+        )
+    }
+
+    fn can_send(&self) -> bool {
+        matches!(self.state, State::Established | State::CloseWait) && !self.closing
+    }
+
+    // a round trip time of rtt, like rfc 6298 says
+    fn sample(&mut self, rtt: u64) {
+        match self.srtt_ms {
+            None => {
+                self.srtt_ms = Some(rtt);
+                self.rttvar_ms = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+                self.rttvar_ms = (3 * self.rttvar_ms + delta) / 4;
+                self.srtt_ms = Some((7 * srtt + rtt) / 8);
+            }
+        }
+
+        let srtt = self.srtt_ms.unwrap();
+        let variance = (4 * self.rttvar_ms).max(timer::TICK_MS);
+        self.rto_ms = (srtt + variance).clamp(RTO_MIN_MS, RTO_MAX_MS);
+    }
+
+    fn fail(&mut self, err: KError) {
+        self.error = Some(err);
+        self.state = State::Closed;
+        self.retransmit_at = None;
+        self.send_buffer.clear();
+    }
+
+    fn enter_time_wait(&mut self, now: u64) {
+        self.state = State::TimeWait;
+        self.retransmit_at = None;
+        self.time_wait_until = Some(now + TIME_WAIT_MS);
+    }
+}
+
+pub struct Connection {
+    pub local: SocketAddr,
+    pub remote: SocketAddr,
+    socket: AtomicUsize, // woken up by changes, the listener's until it's accepted
+    tcb: spin::Mutex<Tcb>,
+}
+
+impl Connection {
+    fn new(local: SocketAddr, remote: SocketAddr, socket: usize, tcb: Tcb) -> Arc<Self> {
+        Arc::new(Connection {
+            local,
+            remote,
+            socket: AtomicUsize::new(socket),
+            tcb: spin::Mutex::new(tcb),
+        })
+    }
+
+    fn wake(&self) {
+        let socket = self.socket.load(Ordering::Acquire);
+        if socket != NO_SOCKET {
+            socket::wake(socket);
+        }
+    }
+
+    // once it's accepted
+    pub fn set_socket(&self, socket: usize) {
+        self.socket.store(socket, Ordering::Release);
+    }
+
+    pub fn state(&self) -> State {
+        self.tcb.lock().state
+    }
+
+    pub fn error(&self) -> Option<KError> {
+        self.tcb.lock().error
+    }
+
+    fn segment(&self, tcb: &Tcb, seq: u32, flags: u8, data: Vec<u8>) -> Segment {
+        let syn = flags & SYN != 0;
+        let mss = ipv4::max_payload(self.remote.ip).unwrap_or(DEFAULT_MSS) - HEADER_SIZE;
+
+        Segment {
+            local: self.local,
+            remote: self.remote,
+            seq,
+            ack: if flags & ACK != 0 { tcb.rcv_nxt } else { 0 },
+            flags,
+            window: tcb.window(),
+            mss: syn.then(|| mss.min(u16::MAX as usize) as u16),
+            data,
+        }
+    }
+
+    fn ack(&self, tcb: &Tcb) -> Segment {
+        self.segment(tcb, tcb.snd_nxt, ACK, Vec::new())
+    }
+
+    // new data as far as the peer's window goes, and the fin once all of it was sent
+    fn output(&self, tcb: &mut Tcb, now: u64, out: &mut Vec<Segment>) {
+        if !matches!(tcb.state, State::Established | State::CloseWait) {
+            return;
+        }
+
+        loop {
+            let sent = tcb.snd_nxt.wrapping_sub(tcb.snd_una) as usize;
+            let unsent = tcb.send_buffer.len() - sent;
+            let room = (tcb.snd_wnd as usize).saturating_sub(sent);
+            let len = unsent.min(room).min(tcb.mss);
+            if len == 0 {
+                break;
+            }
+
+            let data: Vec<u8> = tcb.send_buffer.range(sent..sent + len).copied().collect();
+            let flags = if len == unsent { ACK | PSH } else { ACK };
+            out.push(self.segment(tcb, tcb.snd_nxt, flags, data));
+
+            tcb.snd_nxt = tcb.snd_nxt.wrapping_add(len as u32);
+            if tcb.timing.is_none() {
+                tcb.timing = Some((tcb.snd_nxt, now));
+            }
+        }
+
+        let all_sent = tcb.snd_nxt.wrapping_sub(tcb.snd_una) as usize == tcb.send_buffer.len();
+        if tcb.closing && !tcb.fin_sent && all_sent {
+            out.push(self.segment(tcb, tcb.snd_nxt, FIN | ACK, Vec::new()));
+            tcb.snd_nxt = tcb.snd_nxt.wrapping_add(1);
+            tcb.fin_sent = true;
+            tcb.state = match tcb.state {
+                State::CloseWait => State::LastAck,
+                _ => State::FinWait1,
+            };
+        }
+
+        // also when the window is shut with something left to send, to probe it
+        let waiting = tcb.snd_nxt != tcb.snd_una || !tcb.send_buffer.is_empty();
+        if waiting && tcb.retransmit_at.is_none() {
+            tcb.retransmit_at = Some(now + tcb.rto_ms);
+        }
+    }
+
+    // the retransmission timeout ran out
+    fn retransmit(&self, tcb: &mut Tcb, now: u64, out: &mut Vec<Segment>) {
+        let handshake = matches!(tcb.state, State::SynSent | State::SynReceived);
+        tcb.retries += 1;
+
+        if tcb.retries > if handshake { SYN_RETRIES } else { RETRIES } {
+            if !handshake {
+                out.push(self.segment(tcb, tcb.snd_nxt, RST, Vec::new()));
+            }
+            tcb.fail(KError::ETIMEDOUT);
+            return;
+        }
+
+        tcb.rto_ms = (tcb.rto_ms * 2).min(RTO_MAX_MS);
+        // karn's algorithm, what's sent again can't tell how long a round trip takes
+        tcb.timing = None;
+
+        let in_flight = tcb.snd_nxt.wrapping_sub(tcb.snd_una) as usize;
+        let data_in_flight = in_flight - (tcb.fin_sent && !tcb.fin_acked()) as usize;
+
+        match tcb.state {
+            State::SynSent => out.push(self.segment(tcb, tcb.iss, SYN, Vec::new())),
+            State::SynReceived => out.push(self.segment(tcb, tcb.iss, SYN | ACK, Vec::new())),
+            _ if data_in_flight > 0 => {
+                let len = data_in_flight.min(tcb.mss);
+                let data: Vec<u8> = tcb.send_buffer.range(..len).copied().collect();
+                out.push(self.segment(tcb, tcb.snd_una, ACK | PSH, data));
+            }
+            _ if in_flight > 0 => out.push(self.segment(tcb, tcb.snd_una, FIN | ACK, Vec::new())),
+            // the window is shut, a byte past it makes the peer say when it opens
+            _ if !tcb.send_buffer.is_empty() => {
+                let data = alloc::vec![tcb.send_buffer[0]];
+                out.push(self.segment(tcb, tcb.snd_nxt, ACK, data));
+                tcb.snd_nxt = tcb.snd_nxt.wrapping_add(1);
+            }
+            _ => {}
+        }
+
+        tcb.retransmit_at = Some(now + tcb.rto_ms);
+    }
+
+    // the answer to a syn-ack, the handshake is done on this end
+    fn syn_sent(&self, tcb: &mut Tcb, segment: &Incoming, now: u64, out: &mut Vec<Segment>) {
+        if segment.has(ACK) && (seq_le(segment.ack, tcb.iss) || seq_lt(tcb.snd_nxt, segment.ack)) {
+            if !segment.has(RST) {
+                out.push(reset_for(self.local, self.remote, segment));
+            }
+            return;
+        }
+
+        if segment.has(RST) {
+            if segment.has(ACK) {
+                tcb.fail(KError::ECONNREFUSED);
+            }
+            return;
+        }
+
+        if !segment.has(SYN) || !segment.has(ACK) {
+            return;
+        }
+
+        tcb.rcv_nxt = segment.seq.wrapping_add(1);
+        tcb.snd_una = segment.ack;
+        tcb.snd_wnd = segment.window as u32;
+        tcb.mss = segment.mss.map_or(DEFAULT_MSS, |mss| mss as usize);
+        tcb.state = State::Established;
+        tcb.retransmit_at = None;
+        tcb.retries = 0;
+        if let Some((_, sent)) = tcb.timing.take() {
+            tcb.sample(now - sent);
+        }
+
+        out.push(self.ack(tcb));
+        self.output(tcb, now, out);
+    }
+
+    // everything after syn-sent, returns whether the connection is gone
+    fn process(&self, segment: &Incoming, now: u64, out: &mut Vec<Segment>) -> bool {
+        let mut tcb = self.tcb.lock();
+        match tcb.state {
+            State::Closed => return true,
+            State::SynSent => {
+                self.syn_sent(&mut tcb, segment, now, out);
+                return tcb.state == State::Closed;
+            }
+            _ => {}
+        }
+
+        // the peer didn't get the syn-ack
+        if tcb.state == State::SynReceived && segment.has(SYN) && !segment.has(ACK) {
+            out.push(self.segment(&tcb, tcb.iss, SYN | ACK, Vec::new()));
+            return false;
+        }
+
+        // what was already received is cut off, and what comes after a gap is dropped
+        let mut data = segment.data;
+        let mut seq = segment.seq;
+        if seq_lt(seq, tcb.rcv_nxt) {
+            let old = tcb.rcv_nxt.wrapping_sub(seq) as usize;
+            if old > data.len() || (old == data.len() && !segment.has(FIN)) {
+                if !segment.has(RST) {
+                    out.push(self.ack(&tcb));
+                }
+                return false;
+            }
+
+            data = &data[old..];
+            seq = tcb.rcv_nxt;
+        }
+        if seq != tcb.rcv_nxt {
+            if !segment.has(RST) {
+                out.push(self.ack(&tcb));
+            }
+            return false;
+        }
+
+        if segment.has(RST) {
+            if tcb.state == State::SynReceived {
+                tcb.state = State::Closed;
+            } else {
+                tcb.fail(KError::ECONNRESET);
+            }
+            return true;
+        }
+
+        // a syn in the middle of a connection is only acked, the peer sorts it out
+        if segment.has(SYN) {
+            out.push(self.ack(&tcb));
+            return false;
+        }
+        if !segment.has(ACK) {
+            return false;
+        }
+
+        let acceptable = seq_lt(tcb.snd_una, segment.ack) && seq_le(segment.ack, tcb.snd_nxt);
+        if tcb.state == State::SynReceived {
+            if !acceptable {
+                out.push(reset_for(self.local, self.remote, segment));
+                return false;
+            }
+
+            tcb.state = State::Established;
+            if let Some(listener) = tcb.listener.take() {
+                if !listener.established(self) {
+                    out.push(self.segment(&tcb, segment.ack, RST, Vec::new()));
+                    tcb.state = State::Closed;
+                    return true;
+                }
+            }
+        }
+
+        if acceptable {
+            let acked = segment.ack.wrapping_sub(tcb.snd_una) as usize;
+            let data_acked = acked.min(tcb.send_buffer.len());
+            tcb.send_buffer.drain(..data_acked);
+            tcb.snd_una = segment.ack;
+            tcb.retries = 0;
+
+            if let Some((end, sent)) = tcb.timing {
+                if seq_le(end, segment.ack) {
+                    tcb.timing = None;
+                    tcb.sample(now - sent);
+                }
+            }
+
+            let waiting = tcb.snd_una != tcb.snd_nxt || !tcb.send_buffer.is_empty();
+            tcb.retransmit_at = waiting.then(|| now + tcb.rto_ms);
+        } else if seq_lt(tcb.snd_nxt, segment.ack) {
+            // acks what was never sent
+            out.push(self.ack(&tcb));
+            return false;
+        }
+        tcb.snd_wnd = segment.window as u32;
+
+        if tcb.fin_acked() {
+            match tcb.state {
+                State::FinWait1 => tcb.state = State::FinWait2,
+                State::Closing => tcb.enter_time_wait(now),
+                State::LastAck => {
+                    tcb.state = State::Closed;
+                    return true;
+                }
+                _ => {}
+            }
+        }
+
+        let mut need_ack = false;
+        let mut fin = segment.has(FIN);
+        let receiving = matches!(tcb.state, State::Established | State::FinWait1 | State::FinWait2);
+        if !data.is_empty() && receiving {
+            let room = RECV_BUFFER - tcb.recv_buffer.len();
+            let taken = data.len().min(room);
+            tcb.recv_buffer.extend(&data[..taken]);
+            tcb.rcv_nxt = tcb.rcv_nxt.wrapping_add(taken as u32);
+
+            // the fin only counts once everything before it is in
+            fin &= taken == data.len();
+            need_ack = true;
+        }
+
+        if fin {
+            tcb.rcv_nxt = tcb.rcv_nxt.wrapping_add(1);
+            need_ack = true;
+
+            match tcb.state {
+                State::Established => tcb.state = State::CloseWait,
+                State::FinWait1 if tcb.fin_acked() => tcb.enter_time_wait(now),
+                State::FinWait1 => tcb.state = State::Closing,
+                State::FinWait2 | State::TimeWait => tcb.enter_time_wait(now),
+                _ => {}
+            }
+        }
+
+        if need_ack {
+            out.push(self.ack(&tcb));
+        }
+        self.output(&mut tcb, now, out);
+        false
+    }
+
+    /*
+        Queues as much of data as fits in the send buffer and sends what it can, EAGAIN if
+        nothing fits or the handshake isn't done, EPIPE once this end is closed
+    */
+    pub fn send(&self, data: &[u8]) -> KResult<usize> {
+        let mut out = Vec::new();
+        let result = {
+            let mut tcb = self.tcb.lock();
+
+            if let Some(err) = tcb.error {
+                Err(err)
+            } else if matches!(tcb.state, State::SynSent | State::SynReceived) {
+                Err(KError::EAGAIN)
+            } else if !tcb.can_send() {
+                Err(KError::EPIPE)
+            } else {
+                let taken = data.len().min(SEND_BUFFER - tcb.send_buffer.len());
+                tcb.send_buffer.extend(&data[..taken]);
+                self.output(&mut tcb, timer_source::current_ms(), &mut out);
+
+                if taken == 0 {
+                    Err(KError::EAGAIN)
+                } else {
+                    Ok(taken)
+                }
+            }
+        };
+
+        transmit(out);
+        result
+    }
+
+    // Ok(0) once the peer closed its end, EAGAIN if there's nothing yet
+    pub fn recv(&self, buffer: &mut [u8]) -> KResult<usize> {
+        let mut out = Vec::new();
+        let result = {
+            let mut tcb = self.tcb.lock();
+
+            if !tcb.recv_buffer.is_empty() {
+                let len = buffer.len().min(tcb.recv_buffer.len());
+                for (byte, received) in buffer.iter_mut().zip(tcb.recv_buffer.drain(..len)) {
+                    *byte = received;
+                }
+
+                // the peer may be waiting for the window to open up again
+                let window = tcb.window() as usize;
+                if window - len < RECV_BUFFER / 2 && window >= RECV_BUFFER / 2 {
+                    out.push(self.ack(&tcb));
+                }
+                Ok(len)
+            } else if let Some(err) = tcb.error {
+                Err(err)
+            } else if tcb.peer_closed() {
+                Ok(0)
+            } else {
+                Err(KError::EAGAIN)
+            }
+        };
+
+        transmit(out);
+        result
+    }
+
+    pub fn poll(&self) -> PollEvents {
+        let tcb = self.tcb.lock();
+        let mut events = PollEvents::empty();
+
+        if !tcb.recv_buffer.is_empty() || tcb.peer_closed() || tcb.error.is_some() {
+            events |= PollEvents::POLLIN;
+        }
+        if tcb.can_send() && tcb.send_buffer.len() < SEND_BUFFER {
+            events |= PollEvents::POLLOUT;
+        }
+        if tcb.error.is_some() {
+            events |= PollEvents::POLLERR;
+        }
+        if tcb.state == State::Closed {
+            events |= PollEvents::POLLHUP;
+        }
+
+        events
+    }
+
+    // the socket is gone, the fin goes after what's left to send and the rest goes on alone
+    pub fn close(self: &Arc<Self>) {
+        self.set_socket(NO_SOCKET);
+
+        let mut out = Vec::new();
+        let closed = {
+            let mut tcb = self.tcb.lock();
+            match tcb.state {
+                State::SynSent | State::SynReceived => tcb.state = State::Closed,
+                State::Established | State::CloseWait => {
+                    tcb.closing = true;
+                    self.output(&mut tcb, timer_source::current_ms(), &mut out);
+                }
+                _ => {}
+            }
+            tcb.state == State::Closed
+        };
+
+        transmit(out);
+        if closed {
+            remove(self);
+        }
+    }
+
+    // ends it right away, for one that's never going to be accepted
+    pub fn abort(self: &Arc<Self>) {
+        let segment = {
+            let mut tcb = self.tcb.lock();
+            let segment = self.segment(&tcb, tcb.snd_nxt, RST, Vec::new());
+            tcb.fail(KError::ECONNRESET);
+            segment
+        };
+
+        transmit(alloc::vec![segment]);
+        remove(self);
+    }
+}
+
+pub struct Listener {
+    local: SocketAddr,
+    socket: usize,
+    backlog: usize,
+    accepted: spin::Mutex<VecDeque<Arc<Connection>>>, // done with the handshake
+    closed: AtomicBool,
+}
+
+impl Listener {
+    // the next connection that's done with its handshake, if there's one
+    pub fn accept(&self) -> Option<Arc<Connection>> {
+        self.accepted.lock().pop_front()
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.accepted.lock().is_empty()
+    }
+
+    // false if there's no room for it, then it's reset
+    fn established(&self, connection: &Connection) -> bool {
+        let mut accepted = self.accepted.lock();
+        if self.closed.load(Ordering::Acquire) || accepted.len() >= self.backlog {
+            return false;
+        }
+
+        let connection = CONNECTIONS
+            .lock()
+            .iter()
+            .find(|other| core::ptr::eq(other.as_ref(), connection))
+            .cloned();
+        match connection {
+            Some(connection) => accepted.push_back(connection),
+            None => return false,
+        }
+        drop(accepted);
+
+        socket::wake(self.socket);
+        true
+    }
+
+    // a syn for the port, the handshake starts in a connection of its own
+    fn syn(self: &Arc<Self>, local: SocketAddr, remote: SocketAddr, segment: &Incoming) {
+        let ip_matches = self.local.ip.is_unspecified() || self.local.ip == local.ip;
+        if self.closed.load(Ordering::Acquire) || !ip_matches {
+            transmit(alloc::vec![reset_for(local, remote, segment)]);
+            return;
+        }
+
+        // the peer tries again later
+        if self.accepted.lock().len() >= self.backlog {
+            return;
+        }
+
+        let mut tcb = Tcb::new(State::SynReceived, rng::next_u64() as u32);
+        tcb.rcv_nxt = segment.seq.wrapping_add(1);
+        tcb.snd_wnd = segment.window as u32;
+        tcb.mss = segment.mss.map_or(DEFAULT_MSS, |mss| mss as usize);
+        tcb.listener = Some(self.clone());
+        tcb.retransmit_at = Some(timer_source::current_ms() + tcb.rto_ms);
+
+        let connection = Connection::new(local, remote, self.socket, tcb);
+        let segment = {
+            let tcb = connection.tcb.lock();
+            connection.segment(&tcb, tcb.iss, SYN | ACK, Vec::new())
+        };
+
+        CONNECTIONS.lock().push(connection);
+        transmit(alloc::vec![segment]);
+    }
+
+    // what wasn't accepted yet is reset
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+
+        let mut listeners = LISTENERS.lock();
+        let ours = listeners
+            .get(&self.local.port)
+            .map_or(false, |listener| core::ptr::eq(listener.as_ref(), self));
+        if ours {
+            listeners.remove(&self.local.port);
+        }
+        drop(listeners);
+
+        let accepted: Vec<Arc<Connection>> = self.accepted.lock().drain(..).collect();
+        for connection in accepted {
+            connection.abort();
+        }
+    }
+}
+
+// starts the handshake, the connection is established once its state says so
+pub fn connect(local: SocketAddr, remote: SocketAddr, socket: usize) -> KResult<Arc<Connection>> {
+    let mut connections = CONNECTIONS.lock();
+    let taken = connections.iter().any(|other| other.local == local && other.remote == remote);
+    if taken {
+        return Err(KError::EADDRINUSE);
+    }
+
+    let now = timer_source::current_ms();
+    let mut tcb = Tcb::new(State::SynSent, rng::next_u64() as u32);
+    tcb.retransmit_at = Some(now + tcb.rto_ms);
+    tcb.timing = Some((tcb.snd_nxt, now));
+
+    let connection = Connection::new(local, remote, socket, tcb);
+    connections.push(connection.clone());
+    drop(connections);
+
+    let syn = {
+        let tcb = connection.tcb.lock();
+        connection.segment(&tcb, tcb.iss, SYN, Vec::new())
+    };
+    if let Err(err) = send_segment(&syn) {
+        remove(&connection);
+        return Err(err);
+    }
+
+    Ok(connection)
+}
+
+pub fn listen(local: SocketAddr, backlog: usize, socket: usize) -> KResult<Arc<Listener>> {
+    let mut listeners = LISTENERS.lock();
+    if listeners.contains_key(&local.port) {
+        return Err(KError::EADDRINUSE);
+    }
+
+    let listener = Arc::new(Listener {
+        local,
+        socket,
+        backlog: backlog.max(1),
+        accepted: spin::Mutex::new(VecDeque::new()),
+        closed: AtomicBool::new(false),
+    });
+    listeners.insert(local.port, listener.clone());
+    Ok(listener)
+}
+
+fn remove(connection: &Arc<Connection>) {
+    CONNECTIONS.lock().retain(|other| !Arc::ptr_eq(other, connection));
+}
+
+fn find(local: SocketAddr, remote: SocketAddr) -> Option<Arc<Connection>> {
+    let connections = CONNECTIONS.lock();
+    connections
+        .iter()
+        .find(|connection| connection.local == local && connection.remote == remote)
+        .cloned()
+}
+
+// what a segment for nobody gets back
+fn reset_for(local: SocketAddr, remote: SocketAddr, segment: &Incoming) -> Segment {
+    let (seq, ack, flags) = if segment.has(ACK) {
+        (segment.ack, 0, RST)
+    } else {
+        let len = segment.data.len() + segment.has(SYN) as usize + segment.has(FIN) as usize;
+        (0, segment.seq.wrapping_add(len as u32), RST | ACK)
+    };
+
+    Segment {
+        local,
+        remote,
+        seq,
+        ack,
+        flags,
+        window: 0,
+        mss: None,
+        data: Vec::new(),
+    }
+}
+
+fn send_segment(segment: &Segment) -> KResult<()> {
+    let options = if segment.mss.is_some() { 4 } else { 0 };
+    let len = HEADER_SIZE + options + segment.data.len();
+
+    let mut bytes = Vec::with_capacity(len);
+    bytes.extend_from_slice(&segment.local.port.to_be_bytes());
+    bytes.extend_from_slice(&segment.remote.port.to_be_bytes());
+    bytes.extend_from_slice(&segment.seq.to_be_bytes());
+    bytes.extend_from_slice(&segment.ack.to_be_bytes());
+    bytes.extend_from_slice(&[(((HEADER_SIZE + options) / 4) << 4) as u8, segment.flags]);
+    bytes.extend_from_slice(&segment.window.to_be_bytes());
+    bytes.extend_from_slice(&[0, 0, 0, 0]); // the checksum and the urgent pointer
+    if let Some(mss) = segment.mss {
+        bytes.extend_from_slice(&[OPTION_MSS, 4]);
+        bytes.extend_from_slice(&mss.to_be_bytes());
+    }
+    bytes.extend_from_slice(&segment.data);
+
+    let (source, destination) = (segment.local.ip, segment.remote.ip);
+    let sum = ipv4::pseudo_header_sum(source, destination, ipv4::PROTOCOL_TCP, len);
+    let checksum = super::checksum_finish(super::checksum_add(sum, &bytes));
+    bytes[16..18].copy_from_slice(&checksum.to_be_bytes());
+
+    ipv4::send(source, destination, ipv4::PROTOCOL_TCP, &bytes)
+}
+
+// what's sent because of something else can get lost like anything on the wire
+fn transmit(segments: Vec<Segment>) {
+    for segment in segments {
+        if let Err(err) = send_segment(&segment) {
+            log::debug!("[TCP] Could not send a segment to {}: {}\n", segment.remote, err);
+        }
+    }
+}
+
+// the mss option, the only one that's looked at
+fn parse_mss(mut options: &[u8]) -> Option<u16> {
+    while let [kind, rest @ ..] = options {
+        match *kind {
+            OPTION_END => break,
+            OPTION_NOP => options = rest,
+            _ => {
+                let len = *rest.first()? as usize;
+                if len < 2 || len > options.len() {
+                    break;
+                }
+                if *kind == OPTION_MSS && len == 4 {
+                    return Some(u16::from_be_bytes([options[2], options[3]]));
+                }
+                options = &options[len..];
+            }
+        }
+    }
+
+    None
+}
+
+// false if it was dropped
+pub fn receive(header: &Header, bytes: &[u8]) -> bool {
+    if bytes.len() < HEADER_SIZE {
+        return false;
+    }
+
+    let offset = (bytes[12] >> 4) as usize * 4;
+    if offset < HEADER_SIZE || offset > bytes.len() {
+        return false;
+    }
+
+    let sum = ipv4::pseudo_header_sum(
+        header.source,
+        header.destination,
+        ipv4::PROTOCOL_TCP,
+        bytes.len(),
+    );
+    if super::checksum_finish(super::checksum_add(sum, bytes)) != 0 {
+        return false;
+    }
+
+    let word = |at: usize| u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap());
+    let local = SocketAddr::new(header.destination, u16::from_be_bytes([bytes[2], bytes[3]]));
+    let remote = SocketAddr::new(header.source, u16::from_be_bytes([bytes[0], bytes[1]]));
+    let segment = Incoming {
+        seq: word(4),
+        ack: word(8),
+        flags: bytes[13],
+        window: u16::from_be_bytes([bytes[14], bytes[15]]),
+        mss: parse_mss(&bytes[HEADER_SIZE..offset]),
+        data: &bytes[offset..],
+    };
+
+    if let Some(connection) = find(local, remote) {
+        let mut out = Vec::new();
+        let gone = connection.process(&segment, timer_source::current_ms(), &mut out);
+        transmit(out);
+        if gone {
+            remove(&connection);
+        }
+
+        connection.wake();
+        return true;
+    }
+
+    if segment.has(SYN) && !segment.has(ACK) && !segment.has(RST) {
+        let listener = LISTENERS.lock().get(&local.port).cloned();
+        if let Some(listener) = listener {
+            listener.syn(local, remote, &segment);
+            return true;
+        }
+    }
+
+    // nobody's there
+    if !segment.has(RST) {
+        transmit(alloc::vec![reset_for(local, remote, &segment)]);
+    }
+    false
+}
+
+// retransmits what timed out and ends the connections done with TIME-WAIT
+pub fn tick(now: u64) {
+    let connections = CONNECTIONS.lock().clone();
+
+    for connection in connections {
+        let mut out = Vec::new();
+        let (gone, changed) = {
+            let mut tcb = connection.tcb.lock();
+            let mut changed = false;
+
+            if matches!(tcb.time_wait_until, Some(until) if until <= now) {
+                tcb.state = State::Closed;
+                changed = true;
+            }
+            if matches!(tcb.retransmit_at, Some(at) if at <= now) {
+                connection.retransmit(&mut tcb, now, &mut out);
+                changed = true;
+            }
+
+            (tcb.state == State::Closed, changed)
+        };
+
+        transmit(out);
+        if gone {
+            remove(&connection);
+        }
+        if changed {
+            connection.wake();
+        }
+    }
+}
+
+extern "C" fn ktcpd() -> ! {
+    loop {
+        let generation = TICK.generation();
+        tick(timer_source::current_ms());
+        TICK.wait(generation, None);
+    }
+}
+
+// starts ktcpd, without it nothing is ever sent again and TIME-WAIT never ends
+pub fn init() {
+    if scheduler::try_get().is_none() {
+        log::debug!("[TCP] No scheduler, lost segments won't be sent again\n");
+        return;
+    }
+
+    let process = Process::new(String::from("ktcpd"), 0, String::from("/"));
+    let thread = Thread::new(ktcpd as u64, SelectorValues::KernelCs, process.clone());
+    process.borrow_mut().threads.push(thread.clone());
+    scheduler::get().enqueue(thread);
+
+    timer::every(TICK_MS, || TICK.notify());
+}
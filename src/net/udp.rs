@@ -0,0 +1,65 @@
+/*
+    Udp datagrams, what's received goes to the socket bound to its port (see socket.rs)
+*/
+
+use super::ipv4::{self, Header};
+use super::{socket, SocketAddr};
+use crate::error::{KError, KResult};
+use alloc::vec::Vec;
+
+pub const HEADER_SIZE: usize = 8;
+
+// source has to have an address, not just a port
+pub fn send(source: SocketAddr, destination: SocketAddr, data: &[u8]) -> KResult<()> {
+    let len = HEADER_SIZE + data.len();
+    if len > ipv4::max_payload(destination.ip)? {
+        return Err(KError::EMSGSIZE);
+    }
+
+    let mut datagram = Vec::with_capacity(len);
+    datagram.extend_from_slice(&source.port.to_be_bytes());
+    datagram.extend_from_slice(&destination.port.to_be_bytes());
+    datagram.extend_from_slice(&(len as u16).to_be_bytes());
+    datagram.extend_from_slice(&[0, 0]);
+    datagram.extend_from_slice(data);
+
+    let sum = ipv4::pseudo_header_sum(source.ip, destination.ip, ipv4::PROTOCOL_UDP, len);
+    // 0 means there's no checksum, so one that comes out as 0 is sent as all ones
+    let checksum = match super::checksum_finish(super::checksum_add(sum, &datagram)) {
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+
+    ipv4::send(source.ip, destination.ip, ipv4::PROTOCOL_UDP, &datagram)
+}
+
+// false if it was dropped
+pub fn receive(header: &Header, datagram: &[u8]) -> bool {
+    if datagram.len() < HEADER_SIZE {
+        return false;
+    }
+
+    let len = u16::from_be_bytes([datagram[4], datagram[5]]) as usize;
+    if len < HEADER_SIZE || len > datagram.len() {
+        return false;
+    }
+
+    let datagram = &datagram[..len];
+    let checksum = u16::from_be_bytes([datagram[6], datagram[7]]);
+    if checksum != 0 {
+        let protocol = ipv4::PROTOCOL_UDP;
+        let sum = ipv4::pseudo_header_sum(header.source, header.destination, protocol, len);
+        if super::checksum_finish(super::checksum_add(sum, datagram)) != 0 {
+            return false;
+        }
+    }
+
+    let source_port = u16::from_be_bytes([datagram[0], datagram[1]]);
+    let destination_port = u16::from_be_bytes([datagram[2], datagram[3]]);
+    socket::deliver_datagram(
+        SocketAddr::new(header.source, source_port),
+        SocketAddr::new(header.destination, destination_port),
+        &datagram[HEADER_SIZE..],
+    )
+}
@@ -1,2 +1,4 @@
 pub mod process;
 pub mod scheduler;
+pub mod session;
+pub mod wait;
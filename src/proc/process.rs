@@ -1,17 +1,54 @@
-use crate::arch::{cpu, mm::pmm};
+use super::{scheduler, session};
+use crate::arch::{cpu, fpu, mm::pmm};
+use crate::error::{KError, KResult};
 use crate::fs::vfs;
 use crate::mm::vmm;
-use crate::serial;
+use crate::log;
 use crate::utils::bitmap;
 use alloc::{rc::Rc, string::String, vec::Vec};
 use core::cell::RefCell;
 use core::arch::asm;
 
 pub const MAX_FDS_PER_PROCESS: usize = 128;
+const USER_STACK_SIZE: usize = 8 << 20;
+// every thread enters the kernel on its own stack, kernel threads also run on it
+const KERNEL_STACK_PAGES: usize = 4;
 
 static mut PID_BITMAP: Option<bitmap::Bitmap> = None;
 static mut TID_BITMAP: Option<bitmap::Bitmap> = None;
 
+bitflags::bitflags! {
+    // the subset of linux's clone flags that makes sense for a new thread
+    pub struct CloneFlags: u64 {
+        const CLONE_VM = 0x100;
+        const CLONE_FS = 0x200;
+        const CLONE_FILES = 0x400;
+        const CLONE_SIGHAND = 0x800;
+        const CLONE_THREAD = 0x10000;
+        const CLONE_SETTLS = 0x80000;
+    }
+}
+
+/*
+    What a process may use, each checked where it's allocated: the total length of its
+    mapped ranges in mmap, its descriptors in alloc_fd and its threads in thread_create.
+    Lowering one below what's in use only refuses more, nothing is taken away
+*/
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Limits {
+    pub mapped_bytes: u64,
+    pub open_files: usize, // at most MAX_FDS_PER_PROCESS
+    pub threads: usize,
+}
+
+impl Limits {
+    pub const DEFAULT: Limits = Limits {
+        mapped_bytes: u64::MAX,
+        open_files: MAX_FDS_PER_PROCESS,
+        threads: usize::MAX,
+    };
+}
+
 #[derive(PartialEq, Clone, Copy)]
 pub enum Status {
     Running,
@@ -26,8 +63,8 @@ pub enum SelectorValues {
     KernelDs = 0x10,
 
     // the RPL for the following selectors is 0x3
-    UserCs = 0x1b,
-    UserDs = 0x23,
+    UserDs = 0x1b,
+    UserCs = 0x23,
 }
 
 pub struct Process {
@@ -37,35 +74,160 @@ pub struct Process {
     pub pagemap: Option<vmm::VirtualMemManager>,
     pub threads: Vec<Rc<RefCell<Thread>>>,
     pub file_desc_list: [Option<vfs::FileDescription>; MAX_FDS_PER_PROCESS],
-    pub working_dir: Option<vfs::FileDescription>,
+    pub working_dir: String, // always absolute and normalized
+    pub pgid: usize,
+    pub sid: usize,
+    pub ctty: Option<&'static session::Tty>, // only while it's the session's terminal
+    pub pending_signals: u64, // bit n set means signal n is pending
+    pub uid: u32, // 0 is root, which every process is until something changes it
+    pub gid: u32,
+    limits: Limits,
 }
 
 impl Process {
-    pub fn new(name: String, rip: u64, working_dir: Option<vfs::FileDescription>) -> Rc<RefCell<Self>> {
+    pub fn new(name: String, rip: u64, working_dir: String) -> Rc<RefCell<Self>> {
         // serial::print!("hey!\n");
         // let pagemap = vmm::VirtualMemManager::new(true);
         // serial::print!("pagemap: {:#x}\n", pagemap.pagemap.as_u64());
-        // let pid = Process::alloc_pid().unwrap();
-        // serial::print!("pid: {}\n", pid);
+        let pid = Process::alloc_pid().expect("Could not allocate a new pid");
         const NO_FD: Option<vfs::FileDescription> = None;
         // serial::print!("uh here\n");
         let new_proc = Process {
-            pid: 0,
+            pid,
             status: Status::Running,
             name,
             pagemap: None,
             threads: Vec::new(),
             file_desc_list: [NO_FD; MAX_FDS_PER_PROCESS],
             working_dir,
+            pgid: pid,
+            sid: pid,
+            ctty: None,
+            pending_signals: 0,
+            uid: 0,
+            gid: 0,
+            limits: Limits::DEFAULT,
         };
+        session::create(pid);
 
         // serial::print!("ok thread now\n");
         // let main_thread = Thread::new(rip, SelectorValues::UserCs, new_proc.clone());
         // new_proc.borrow_mut().threads.push(main_thread);
-        serial::print!("a\n");
         Rc::new(RefCell::new(new_proc))
     }
 
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /*
+        For whoever creates a process, before its first thread runs, or for the process to
+        lower its own. The address space takes its limit along, so one given to the process
+        afterwards needs them set again
+    */
+    pub fn set_limits(&mut self, limits: Limits) -> KResult<()> {
+        if limits.open_files > MAX_FDS_PER_PROCESS || limits.threads == 0 {
+            return Err(KError::EINVAL);
+        }
+
+        if let Some(pagemap) = self.pagemap.as_mut() {
+            pagemap.set_map_limit(limits.mapped_bytes);
+        }
+        self.limits = limits;
+        Ok(())
+    }
+
+    // puts description in the lowest free descriptor under the limit and returns it
+    pub fn alloc_fd(&mut self, description: vfs::FileDescription) -> KResult<usize> {
+        let fd = self.file_desc_list[..self.limits.open_files]
+            .iter()
+            .position(|slot| slot.is_none())
+            .ok_or(KError::EMFILE)?;
+
+        self.file_desc_list[fd] = Some(description);
+        Ok(fd)
+    }
+
+    // tears down everything the process owns, its threads must not run anymore
+    pub fn exit(&mut self) {
+        self.status = Status::Dying;
+
+        for fd in self.file_desc_list.iter_mut() {
+            *fd = None;
+        }
+
+        if let Some(mut pagemap) = self.pagemap.take() {
+            pagemap.destroy();
+        }
+
+        for thread in self.threads.iter() {
+            let mut thread = thread.borrow_mut();
+            thread.status = Status::Dying;
+            Thread::free_tid(thread.tid);
+        }
+        self.threads.clear();
+
+        session::exit(self);
+        Process::free_pid(self.pid);
+    }
+
+    /*
+        Starts a new userspace thread at entry, with arg in rdi. It shares everything of
+        the process, so the flags have to ask for at least the address space, the fd table
+        and the thread group, there's no fork-like clone. Without a stack one is mapped for
+        it. Returns the new tid
+    */
+    pub fn thread_create(
+        process: &Rc<RefCell<Process>>,
+        entry: u64,
+        stack: Option<u64>,
+        arg: u64,
+        tls: u64,
+        flags: CloneFlags,
+    ) -> KResult<usize> {
+        let shared = CloneFlags::CLONE_VM | CloneFlags::CLONE_FILES | CloneFlags::CLONE_THREAD;
+        if !flags.contains(shared) {
+            return Err(KError::EINVAL);
+        }
+
+        if process.borrow().status == Status::Dying {
+            return Err(KError::EINVAL);
+        }
+        if process.borrow().threads.len() >= process.borrow().limits.threads {
+            return Err(KError::EAGAIN);
+        }
+        // nothing would ever run it
+        if scheduler::try_get().is_none() {
+            return Err(KError::EAGAIN);
+        }
+
+        let thread = Thread::new_user(entry, stack, process.clone())?;
+        let tid = {
+            let mut thread = thread.borrow_mut();
+            thread.regs.rdi = arg;
+            if flags.contains(CloneFlags::CLONE_SETTLS) {
+                thread.fs_base = tls;
+            }
+
+            thread.tid
+        };
+
+        process.borrow_mut().threads.push(thread.clone());
+        scheduler::get().enqueue(thread);
+
+        Ok(tid)
+    }
+
+    pub fn free_pid(pid: usize) {
+        let bitmap = unsafe {
+            PID_BITMAP
+                .as_mut()
+                .expect("Pid bitmap hasn't been initialized")
+        };
+
+        bitmap.clear(pid);
+    }
+
     pub fn alloc_pid() -> Option<usize> {
         let bitmap = unsafe {
             PID_BITMAP
@@ -84,37 +246,101 @@ impl Process {
     }
 }
 
+#[derive(Default, Clone, Copy)]
+pub struct ThreadStats {
+    pub context_switches: u64,
+    pub cpu_time_ns: u64,
+    pub scheduled_at_ns: u64, // when it last got the cpu
+}
+
 pub struct Thread {
     pub tid: usize,
     pub status: Status,
     pub parent: Rc<RefCell<Process>>,
-    pub kernel_stack: u64,
+    pub kernel_stack: u64, // the top of kernel_stack_pages
+    kernel_stack_pages: pmm::PmmBox<u8>,
+    pub priority: usize, // 0 is the highest
+    pub level: usize,    // the queue it's in, which drops as it burns whole timeslices
+    pub slice_left: u64, // timer ticks of its timeslice, refilled whenever it gets the cpu
+    pub affinity: u64,   // bit n set means it may run on cpu n
+    pub last_cpu: Option<usize>,
+    pub stats: ThreadStats,
+    pub wake_at: Option<u64>, // when a waiting thread times out, in timer source ns
     pub regs: cpu::InterruptContext,
+    pub fpu_state: fpu::FpuState,
+    pub fs_base: u64, // thread-local storage pointer
+    pub gs_base: u64,
 }
 
 impl Thread {
     pub fn new(rip: u64, cs: SelectorValues, parent: Rc<RefCell<Process>>) -> Rc<RefCell<Self>> {
-        serial::print!("thread new\n");
+        Thread::create(rip, cs, None, parent)
+    }
+
+    // a userspace thread, on stack if it's given or on a newly mapped one
+    pub fn new_user(
+        rip: u64,
+        stack: Option<u64>,
+        parent: Rc<RefCell<Process>>,
+    ) -> KResult<Rc<RefCell<Self>>> {
+        let thread = Thread::create(rip, SelectorValues::UserCs, stack, parent);
+
+        if thread.borrow().regs.rsp == 0 {
+            Thread::free_tid(thread.borrow().tid);
+            return Err(KError::ENOMEM);
+        }
+
+        Ok(thread)
+    }
+
+    fn create(
+        rip: u64,
+        cs: SelectorValues,
+        user_stack: Option<u64>,
+        parent: Rc<RefCell<Process>>,
+    ) -> Rc<RefCell<Self>> {
+        let kernel_stack_size = KERNEL_STACK_PAGES * pmm::PAGE_SIZE as usize;
+        let kernel_stack_pages: pmm::PmmBox<u8> = pmm::PmmBox::new(kernel_stack_size);
+
         let mut new_thread = Thread {
             tid: Self::alloc_tid().expect("Could not allocate a new tid"),
             status: Status::Running,
             parent,
-            kernel_stack: 0,
+            kernel_stack: kernel_stack_pages.as_ptr() as u64 + kernel_stack_size as u64,
+            kernel_stack_pages,
+            priority: scheduler::DEFAULT_PRIORITY,
+            level: scheduler::DEFAULT_PRIORITY,
+            slice_left: 0,
+            affinity: u64::MAX,
+            last_cpu: None,
+            stats: ThreadStats::default(),
+            wake_at: None,
             regs: cpu::InterruptContext::default(),
+            fpu_state: fpu::FpuState::new(),
+            fs_base: 0,
+            gs_base: 0,
         };
 
         if cs as u64 & 0x3 != 0 {
-            // userspace thread
-            // TODO: allocate the stack and mmap it
+            // userspace thread, its stack goes wherever the address space's layout says
+            if let Some(stack) = user_stack {
+                new_thread.regs.rsp = stack;
+            } else if let Some(pagemap) = new_thread.parent.borrow_mut().pagemap.as_mut() {
+                match pagemap.map_stack(USER_STACK_SIZE) {
+                    Ok(stack) => new_thread.regs.rsp = stack.as_u64(),
+                    Err(err) => log::error!("Could not map the stack of a new thread: {}\n", err),
+                }
+            }
+
             new_thread.regs.ss = SelectorValues::UserDs as u64;
         } else {
+            new_thread.regs.rsp = new_thread.kernel_stack;
             new_thread.regs.ss = SelectorValues::KernelDs as u64;
         }
 
         new_thread.regs.rflags = 0x202;
         new_thread.regs.cs = cs as u64;
         new_thread.regs.rip = rip;
-        serial::print!("all good at new thread\n");
         Rc::new(RefCell::new(new_thread))
     }
 
@@ -135,29 +361,93 @@ impl Thread {
         None
     }
 
-    // #[naked]
-    // pub unsafe extern "C" fn switch(regs: &cpu::InterruptContext) {
-    //     asm!(
-    //         "mov rsp, rdi",
-    //         "pop rax",
-    //         "pop rbx",
-    //         "pop rcx",
-    //         "pop rdx",
-    //         "pop rsi",
-    //         "pop rdi",
-    //         "pop rbp",
-    //         "pop r8",
-    //         "pop r9",
-    //         "pop r10",
-    //         "pop r11",
-    //         "pop r12",
-    //         "pop r13",
-    //         "pop r14",
-    //         "pop r15",
-    //         "iretq",
-    //         options(noreturn)
-    //     )
-    // }
+    pub fn free_tid(tid: usize) {
+        let bitmap = unsafe {
+            TID_BITMAP
+                .as_mut()
+                .expect("Tid bitmap hasn't been initialized")
+        };
+
+        bitmap.clear(tid);
+    }
+
+    // the new priority is picked up the next time the thread is queued
+    pub fn set_priority(&mut self, priority: usize) -> KResult<()> {
+        if priority >= scheduler::PRIORITY_LEVELS {
+            return Err(KError::EINVAL);
+        }
+
+        self.priority = priority;
+        self.level = priority;
+        Ok(())
+    }
+
+    // like set_priority, a running thread only moves away at the next reschedule
+    pub fn set_affinity(&mut self, affinity: u64) -> KResult<()> {
+        let cpus = cpu::online_cpus().max(1);
+        let online = if cpus >= 64 { u64::MAX } else { (1 << cpus) - 1 };
+
+        // it has to be able to run somewhere
+        if affinity & online == 0 {
+            return Err(KError::EINVAL);
+        }
+
+        self.affinity = affinity;
+        Ok(())
+    }
+
+    pub fn can_run_on(&self, cpu: usize) -> bool {
+        cpu < 64 && self.affinity & (1 << cpu) != 0
+    }
+
+    // sets the tls pointer of this thread, takes effect immediately if it's the running thread
+    pub fn set_fs_base(&mut self, fs_base: u64, running: bool) {
+        self.fs_base = fs_base;
+
+        if running {
+            cpu::set_fs_base(fs_base);
+        }
+    }
+
+    pub fn save_segment_bases(&mut self) {
+        self.fs_base = cpu::get_fs_base();
+        self.gs_base = cpu::get_user_gs_base();
+    }
+
+    pub fn restore_segment_bases(&self) {
+        cpu::set_fs_base(self.fs_base);
+        cpu::set_user_gs_base(self.gs_base);
+    }
+
+    #[naked]
+    pub unsafe extern "C" fn switch(regs: *const cpu::InterruptContext) -> ! {
+        asm!(
+            "mov rsp, rdi",
+            "pop rax",
+            "pop rbx",
+            "pop rcx",
+            "pop rdx",
+            "pop rsi",
+            "pop rdi",
+            "pop rbp",
+            "pop r8",
+            "pop r9",
+            "pop r10",
+            "pop r11",
+            "pop r12",
+            "pop r13",
+            "pop r14",
+            "pop r15",
+
+            // going back to userspace, so give it its gs base back
+            "test qword ptr [rsp + 8], 3",
+            "jz 2f",
+            "swapgs",
+            "2:",
+            "iretq",
+            options(noreturn)
+        )
+    }
 
     // pub fn block(&self) {
     //     // if self.status == Status::Waiting {
@@ -199,7 +489,6 @@ impl Thread {
 pub unsafe fn init_bitmaps() {
     let a = bitmap::Bitmap::new(pmm::PAGE_SIZE as usize);
     let b = bitmap::Bitmap::new(pmm::PAGE_SIZE as usize);
-    serial::print!("a: {:p}, b: {:p}\n", &a, &b);
     PID_BITMAP = Some(a);
     TID_BITMAP = Some(b);
 }
@@ -1,95 +1,559 @@
-// use super::process::{self, Process, Thread};
-// use crate::arch::{apic, cpu, interrupts};
-// use crate::fs::vfs;
-// use crate::serial;
-// use alloc::collections::VecDeque;
-// use alloc::{rc::Rc, string::String};
-// use core::cell::RefCell;
-
-// static mut SCHEDULER: Option<Scheduler> = None;
-
-// pub struct SchedulerQueues {
-//     pub runnable: VecDeque<Rc<RefCell<Thread>>>,
-//     pub waiting: VecDeque<Rc<RefCell<Thread>>>,
-// }
-
-// impl SchedulerQueues {
-//     pub fn new() -> Self {
-//         SchedulerQueues {
-//             runnable: VecDeque::new(),
-//             waiting: VecDeque::new(),
-//         }
-//     }
-// }
-
-// pub struct Scheduler {
-//     pub queues: SchedulerQueues,
-//     pub running_thread: Option<Rc<RefCell<Thread>>>,
-// }
-
-// impl Scheduler {
-//     pub fn new() -> Self {
-//         Scheduler {
-//             queues: SchedulerQueues::new(),
-//             running_thread: None,
-//         }
-//     }
-// }
-
-// interrupts::isr!(reschedule, |regs| {
-//     let scheduler = get();
-
-//     if let Some(thread) = scheduler.queues.runnable.pop_front() {
-//         if let Some(previous_thread) = scheduler.running_thread.clone() {
-//             previous_thread.borrow_mut().regs = *regs;
-//             scheduler.queues.runnable.push_back(previous_thread);
-//         }
-
-//         scheduler.running_thread = Some(thread);
-//         let running_thread = scheduler.running_thread.as_ref().unwrap().borrow();
-
-//         // running_thread.parent.borrow().pagemap.switch_pagemap();
-
-//         apic::get().eoi();
-//         Thread::switch(&running_thread.regs);
-
-//         unreachable!();
-//     } else {
-//         if let Some(_) = scheduler.running_thread.as_ref() {
-//             apic::get().eoi();
-//             cpu::halt();
-//         }
-
-//         unreachable!();
-//     }
-// });
-
-// pub fn init() {
-//     serial::print!("at scheduler init\n");
-//     unsafe {
-//         process::init_bitmaps();
-//         SCHEDULER = Some(Scheduler::new());
-//         // serial::print!("opening the file\n");
-//         // let fd = vfs::open("/home/limine.cfg", vfs::Flags::empty(), vfs::Mode::empty()).unwrap();
-//         // serial::print!("done\n");
-//         // let new_proc = Process::new(String::from("init"), 0, fd);
-//         // serial::print!("here?\n");
-//         // SCHEDULER.as_mut().unwrap().running_thread = Some(new_proc.borrow().threads[0].clone());
-//         // serial::print!("gg\n");
-//     }
-
-//     let vector = interrupts::alloc_vector()
-//         .expect("Could not allocate an interrupt vector for the scheduler");
-//     unsafe {
-//         interrupts::register_isr(vector, reschedule as u64, 0, 0x8e);
-//     }
-//     // apic::get().calibrate_timer(30, vector);
-// }
-
-// pub fn get() -> &'static mut Scheduler {
-//     unsafe {
-//         SCHEDULER
-//             .as_mut()
-//             .expect("The scheduler hasn't been initialized")
-//     }
-// }
+use super::process::{Process, SelectorValues, Status, Thread};
+use crate::arch::{apic, cpu, interrupts, ipi, topology};
+use crate::drivers::timer_source;
+use crate::spinlock::{Spinlock, SpinlockGuard};
+use crate::trace;
+use alloc::collections::VecDeque;
+use alloc::{rc::Rc, string::String, vec::Vec};
+use core::arch::asm;
+use core::cell::RefCell;
+use core::fmt::Write;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU8, Ordering};
+
+// every thread is moved back to its base priority this often, in timer ticks, so nothing starves
+pub const BOOST_INTERVAL: u64 = 100;
+
+pub const PRIORITY_LEVELS: usize = 4;
+pub const DEFAULT_PRIORITY: usize = 1;
+
+// how many timer ticks a thread gets at level, the lower ones get longer
+pub const fn time_slice(level: usize) -> u64 {
+    1 << level
+}
+
+// the reschedule isr takes it too, so it's only ever held with interrupts off
+static SCHEDULER: Spinlock<Option<Scheduler>> = Spinlock::new(None);
+// 0 until the scheduler has a vector, yield_now sends it to this cpu
+static RESCHEDULE_VECTOR: AtomicU8 = AtomicU8::new(0);
+
+// a multilevel feedback queue, runnable[0] is the highest priority
+pub struct SchedulerQueues {
+    pub runnable: [VecDeque<Rc<RefCell<Thread>>>; PRIORITY_LEVELS],
+    pub waiting: VecDeque<Rc<RefCell<Thread>>>,
+}
+
+impl SchedulerQueues {
+    pub fn new() -> Self {
+        const EMPTY: VecDeque<Rc<RefCell<Thread>>> = VecDeque::new();
+
+        SchedulerQueues {
+            runnable: [EMPTY; PRIORITY_LEVELS],
+            waiting: VecDeque::new(),
+        }
+    }
+
+    pub fn push_runnable(&mut self, thread: Rc<RefCell<Thread>>) {
+        let level = thread.borrow().level;
+        self.runnable[level].push_back(thread);
+    }
+
+    /*
+        A thread of the highest priority that is allowed to run on cpu. Within a level the
+        one that last ran closest to cpu is picked, its caches may still be warm there. One
+        that's borrowed is in the middle of being changed by whatever the tick interrupted,
+        so it's left for the next time
+    */
+    pub fn pop_runnable(&mut self, cpu: usize) -> Option<Rc<RefCell<Thread>>> {
+        self.runnable.iter_mut().find_map(|queue| {
+            let index = queue
+                .iter()
+                .enumerate()
+                .filter_map(|(index, thread)| {
+                    let thread = thread.try_borrow_mut().ok()?;
+                    let distance = match thread.last_cpu {
+                        Some(last_cpu) => topology::distance(cpu, last_cpu),
+                        None => topology::Distance::Remote,
+                    };
+
+                    thread.can_run_on(cpu).then(|| (index, distance))
+                })
+                .min_by_key(|(_, distance)| *distance)
+                .map(|(index, _)| index)?;
+            queue.remove(index)
+        })
+    }
+
+    // whether a thread of a higher priority than level is waiting to run on cpu
+    pub fn has_runnable_above(&self, level: usize, cpu: usize) -> bool {
+        self.runnable[..level].iter().flatten().any(|thread| {
+            thread.try_borrow().map_or(false, |thread| thread.can_run_on(cpu))
+        })
+    }
+
+    // puts every runnable thread back at its base priority
+    pub fn boost(&mut self) {
+        for level in 0..PRIORITY_LEVELS {
+            let mut kept = VecDeque::new();
+
+            while let Some(thread) = self.runnable[level].pop_front() {
+                let priority = thread.borrow().priority;
+
+                if priority == level {
+                    kept.push_back(thread);
+                } else {
+                    thread.borrow_mut().level = priority;
+                    self.runnable[priority].push_back(thread);
+                }
+            }
+
+            self.runnable[level].append(&mut kept);
+        }
+    }
+}
+
+pub struct Scheduler {
+    pub queues: SchedulerQueues,
+    // one per cpu, indexed by cpu id. They never go into the queues
+    pub idle_threads: Vec<Rc<RefCell<Thread>>>,
+    // what each cpu runs, indexed by cpu id like the idle threads
+    pub running_threads: Vec<Option<Rc<RefCell<Thread>>>>,
+    pub ticks: u64,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler {
+            queues: SchedulerQueues::new(),
+            idle_threads: Vec::new(),
+            running_threads: Vec::new(),
+            ticks: 0,
+        }
+    }
+
+    // makes a new thread runnable
+    pub fn enqueue(&mut self, thread: Rc<RefCell<Thread>>) {
+        {
+            let mut thread = thread.borrow_mut();
+            thread.status = Status::Running;
+            thread.level = thread.priority;
+        }
+
+        self.kick_idle_cpu(&thread);
+        self.queues.push_runnable(thread);
+    }
+
+    pub fn block(&mut self, thread: Rc<RefCell<Thread>>) {
+        thread.borrow_mut().status = Status::Waiting;
+
+        for queue in self.queues.runnable.iter_mut() {
+            queue.retain(|other| !Rc::ptr_eq(other, &thread));
+        }
+        self.queues.waiting.push_back(thread);
+    }
+
+    /*
+        threads that slept on I/O get to skip ahead of the ones that burned their timeslice,
+        they go to the front of their base priority
+    */
+    pub fn wake(&mut self, thread: &Rc<RefCell<Thread>>) {
+        let index = match self.queues.waiting.iter().position(|other| Rc::ptr_eq(other, thread)) {
+            Some(index) => index,
+            None => return,
+        };
+        let thread = self.queues.waiting.remove(index).unwrap();
+        trace::trace!(sched_wake, thread.borrow().tid);
+
+        let level = {
+            let mut thread = thread.borrow_mut();
+            thread.status = Status::Running;
+            thread.wake_at = None;
+            thread.level = thread.priority;
+            thread.level
+        };
+
+        self.kick_idle_cpu(&thread);
+        self.queues.runnable[level].push_front(thread);
+    }
+
+    /*
+        Sends a reschedule ipi to another cpu that's idle and that thread can run on. It
+        would only look at the queues on its next tick otherwise, and the aps don't tick
+    */
+    fn kick_idle_cpu(&self, thread: &Rc<RefCell<Thread>>) {
+        let this_cpu = cpu::local().cpu_id;
+        let thread = thread.borrow();
+
+        let idle_cpu = self.running_threads.iter().enumerate().position(|(cpu_id, running)| {
+            let idle = running.as_ref().map_or(false, |running| self.is_idle(running));
+            idle && cpu_id != this_cpu && thread.can_run_on(cpu_id)
+        });
+
+        if let Some(cpu_id) = idle_cpu {
+            ipi::send_reschedule(cpu_id);
+        }
+    }
+
+    // takes a thread that will never run again out of every queue
+    pub fn remove(&mut self, thread: &Rc<RefCell<Thread>>) {
+        for queue in self.queues.runnable.iter_mut() {
+            queue.retain(|other| !Rc::ptr_eq(other, thread));
+        }
+        self.queues.waiting.retain(|other| !Rc::ptr_eq(other, thread));
+    }
+
+    pub fn wake_tid(&mut self, tid: usize) {
+        let thread = self.queues.waiting.iter().find(|thread| thread.borrow().tid == tid).cloned();
+
+        if let Some(thread) = thread {
+            self.wake(&thread);
+        }
+    }
+
+    // blocks thread until it's woken up, or until deadline if there's one
+    pub fn sleep(&mut self, thread: Rc<RefCell<Thread>>, deadline: Option<u64>) {
+        thread.borrow_mut().wake_at = deadline;
+        self.block(thread);
+    }
+
+    // wakes the waiting threads whose deadline has passed
+    fn wake_expired(&mut self, now: u64) {
+        let expired: Vec<Rc<RefCell<Thread>>> = self
+            .queues
+            .waiting
+            .iter()
+            .filter(|thread| matches!(thread.borrow().wake_at, Some(deadline) if deadline <= now))
+            .cloned()
+            .collect();
+
+        for thread in expired.iter() {
+            self.wake(thread);
+        }
+    }
+
+    // every thread, running, queued or idle, each one once
+    pub fn threads(&self) -> Vec<&Rc<RefCell<Thread>>> {
+        self.running_threads
+            .iter()
+            .flatten()
+            .chain(self.queues.runnable.iter().flatten())
+            .chain(self.queues.waiting.iter())
+            .chain(self.idle_threads.iter())
+            .fold(Vec::new(), |mut threads: Vec<&Rc<RefCell<Thread>>>, thread| {
+                // a running thread can be an idle thread too
+                if !threads.iter().any(|other| Rc::ptr_eq(other, thread)) {
+                    threads.push(thread);
+                }
+                threads
+            })
+    }
+
+    // the processes the threads belong to, each one once
+    pub fn processes(&self) -> Vec<Rc<RefCell<Process>>> {
+        let mut processes: Vec<Rc<RefCell<Process>>> = Vec::new();
+
+        for thread in self.threads() {
+            let parent = &thread.borrow().parent;
+            if !processes.iter().any(|other| Rc::ptr_eq(other, parent)) {
+                processes.push(parent.clone());
+            }
+        }
+
+        processes
+    }
+
+    pub fn idle_thread(&self) -> Rc<RefCell<Thread>> {
+        self.idle_threads[cpu::local().cpu_id].clone()
+    }
+
+    // the thread this cpu is running
+    pub fn running_thread(&self) -> Option<&Rc<RefCell<Thread>>> {
+        self.running_threads.get(cpu::local().cpu_id)?.as_ref()
+    }
+
+    pub fn is_idle(&self, thread: &Rc<RefCell<Thread>>) -> bool {
+        self.idle_threads.iter().any(|idle| Rc::ptr_eq(idle, thread))
+    }
+
+    /*
+        Picks what cpu runs next, at now. A timer tick takes a tick off the running thread's
+        timeslice, and it only loses the cpu before using all of it to a thread of a higher
+        priority. Once it's all used up it drops a level. Without a tick the running thread
+        gave the cpu up itself and keeps its level. Returns the previous and the next thread
+        if they're not the same one, the previous one's registers are left for the caller
+    */
+    pub fn reschedule(
+        &mut self,
+        cpu_id: usize,
+        now: u64,
+        tick: bool,
+    ) -> Option<(Rc<RefCell<Thread>>, Rc<RefCell<Thread>>)> {
+        let previous_thread = self.running_threads[cpu_id]
+            .clone()
+            .expect("The scheduler has no running thread");
+        let previous_is_idle = self.is_idle(&previous_thread);
+
+        if tick {
+            self.ticks += 1;
+            if self.ticks % BOOST_INTERVAL == 0 {
+                self.queues.boost();
+            }
+        }
+        self.wake_expired(now);
+
+        // the tick came in while it was using itself, it keeps the cpu until the next one
+        let mut previous = previous_thread.try_borrow_mut().ok()?;
+        let previous_runnable = previous.status == Status::Running;
+        // its affinity may have changed since it got the cpu
+        let previous_can_stay = previous_runnable && previous.can_run_on(cpu_id);
+
+        if tick && !previous_is_idle {
+            let preempted = self.queues.has_runnable_above(previous.level, cpu_id);
+            previous.slice_left = previous.slice_left.saturating_sub(1);
+
+            if previous.slice_left == 0 {
+                previous.level = (previous.level + 1).min(PRIORITY_LEVELS - 1);
+            } else if previous_can_stay && !preempted {
+                return None;
+            }
+        }
+
+        let thread = match self.queues.pop_runnable(cpu_id) {
+            Some(thread) => thread,
+            // nothing else wants the cpu, so the current thread keeps it
+            None if previous_can_stay => {
+                if previous.slice_left == 0 {
+                    previous.slice_left = time_slice(previous.level);
+                }
+                return None;
+            }
+            None => self.idle_threads[cpu_id].clone(),
+        };
+
+        previous.stats.cpu_time_ns += now.saturating_sub(previous.stats.scheduled_at_ns);
+        drop(previous);
+        {
+            let mut next = thread.borrow_mut();
+            next.stats.context_switches += 1;
+            next.stats.scheduled_at_ns = now;
+            next.last_cpu = Some(cpu_id);
+            next.slice_left = time_slice(next.level);
+        }
+
+        // the idle threads never go into the queues
+        if !previous_is_idle && previous_runnable {
+            self.queues.push_runnable(previous_thread.clone());
+        }
+
+        self.running_threads[cpu_id] = Some(thread.clone());
+        Some((previous_thread, thread))
+    }
+}
+
+// what the idle threads run, interrupts have to be enabled or we would never wake up
+extern "C" fn idle() -> ! {
+    loop {
+        unsafe {
+            asm!("sti", "hlt");
+        }
+    }
+}
+
+fn new_idle_thread(process: Rc<RefCell<Process>>) -> Rc<RefCell<Thread>> {
+    let thread = Thread::new(idle as u64, SelectorValues::KernelCs, process.clone());
+    process.borrow_mut().threads.push(thread.clone());
+    thread
+}
+
+/*
+    Switches this cpu to what it runs next, from an interrupt that came in while regs was
+    running, tick says it's the timer's. The interrupt is acknowledged here, and this only
+    returns if the thread that was running keeps the cpu
+*/
+pub fn reschedule_from(regs: &mut cpu::InterruptContext, tick: bool) {
+    let mut scheduler = match try_get() {
+        Some(scheduler) => scheduler,
+        None => {
+            apic::get().eoi();
+            return;
+        }
+    };
+
+    let cpu_id = cpu::local().cpu_id;
+    let now = timer_source::current_ns();
+    let (previous_thread, thread) = match scheduler.reschedule(cpu_id, now, tick) {
+        Some(switch) => switch,
+        None => {
+            apic::get().eoi();
+            return;
+        }
+    };
+
+    trace::trace!(sched_switch, previous_thread.borrow().tid, thread.borrow().tid);
+
+    // the idle thread always starts over from the top of its loop, there's nothing to save
+    if !scheduler.is_idle(&previous_thread) {
+        let mut previous = previous_thread.borrow_mut();
+        previous.regs = *regs;
+        previous.fpu_state.save();
+        previous.save_segment_bases();
+    }
+
+    // a dying thread may have nothing else keeping it alive, and we're still on its stack
+    if previous_thread.borrow().status == Status::Dying {
+        core::mem::forget(previous_thread);
+    } else {
+        drop(previous_thread);
+    }
+
+    /*
+        Thread::switch never returns, so we can't keep the RefCell borrowed or the
+        scheduler locked while switching. The thread itself is kept alive by running_threads
+    */
+    let regs_ptr = {
+        let running_thread = thread.borrow();
+        running_thread.fpu_state.restore();
+        running_thread.restore_segment_bases();
+        cpu::set_kernel_stack(running_thread.kernel_stack);
+
+        /*
+            A user process's threads run in its address space, its kernel ones like a ring's
+            worker too. Kernel threads of kernel processes run in whatever is loaded, the
+            kernel is mapped in all of them
+        */
+        if let Some(pagemap) = running_thread.parent.borrow().pagemap.as_ref() {
+            pagemap.switch_pagemap();
+        }
+
+        &running_thread.regs as *const cpu::InterruptContext
+    };
+    drop(thread);
+    drop(scheduler);
+
+    apic::get().eoi();
+    unsafe {
+        Thread::switch(regs_ptr);
+    }
+}
+
+interrupts::isr!(reschedule, |regs| {
+    reschedule_from(regs, false);
+});
+
+// the pid and tid bitmaps have to be initialized already
+pub fn init() {
+    let idle_process = Process::new(String::from("idle"), 0, String::from("/"));
+    let idle_threads: Vec<_> = (0..cpu::online_cpus().max(1))
+        .map(|_| new_idle_thread(idle_process.clone()))
+        .collect();
+
+    /*
+        What booted us goes on as a thread of its own, its registers are saved the first
+        time it's switched away from like any other thread's
+    */
+    let kernel_process = Process::new(String::from("kernel"), 0, String::from("/"));
+    let boot_thread = Thread::new(0, SelectorValues::KernelCs, kernel_process.clone());
+    kernel_process.borrow_mut().threads.push(boot_thread.clone());
+
+    /*
+        There is always a running thread on each cpu. The other cpus are halted until
+        their first reschedule, which is what their idle threads do anyway
+    */
+    let mut scheduler = Scheduler::new();
+    scheduler.running_threads = idle_threads.iter().cloned().map(Some).collect();
+    scheduler.running_threads[cpu::local().cpu_id] = Some(boot_thread);
+    scheduler.idle_threads = idle_threads;
+    *SCHEDULER.lock_irqsave() = Some(scheduler);
+
+    let vector = interrupts::alloc_vector()
+        .expect("Could not allocate an interrupt vector for the scheduler");
+    unsafe {
+        interrupts::register_isr(vector, reschedule as u64, 0, 0x8e, "reschedule");
+    }
+    RESCHEDULE_VECTOR.store(vector as u8, Ordering::SeqCst);
+}
+
+// a ps-style table of every thread the scheduler knows about
+pub fn ps() -> String {
+    let scheduler = get();
+    let mut table = String::new();
+
+    writeln!(
+        table,
+        "{:>5} {:>5} {:<16} {:<8} {:>4} {:>10} {:>10} {:>18}",
+        "TID", "PID", "NAME", "STATE", "PRIO", "SWITCHES", "CPU(ms)", "AFFINITY"
+    )
+    .ok();
+
+    for thread in scheduler.threads() {
+        let thread = thread.borrow();
+        let process = thread.parent.borrow();
+        let state = match thread.status {
+            Status::Running => "running",
+            Status::Waiting => "waiting",
+            Status::Dying => "dying",
+        };
+
+        writeln!(
+            table,
+            "{:>5} {:>5} {:<16} {:<8} {:>4} {:>10} {:>10} {:#018x}",
+            thread.tid,
+            process.pid,
+            process.name,
+            state,
+            thread.priority,
+            thread.stats.context_switches,
+            thread.stats.cpu_time_ns / 1_000_000,
+            thread.affinity
+        )
+        .ok();
+    }
+
+    table
+}
+
+// gives up the cpu right away instead of waiting for the next tick
+pub fn yield_now() {
+    let vector = RESCHEDULE_VECTOR.load(Ordering::SeqCst);
+
+    if vector != 0 {
+        apic::get().send_ipi(0, vector, apic::IpiDestination::Myself);
+    }
+}
+
+// the running thread if it can be put to sleep, the idle threads (and boot) can't
+pub fn sleepable_thread() -> Option<Rc<RefCell<Thread>>> {
+    let scheduler = try_get()?;
+    let thread = scheduler.running_thread().cloned()?;
+
+    if scheduler.is_idle(&thread) {
+        return None;
+    }
+
+    Some(thread)
+}
+
+// unlike get(), this can be used before the scheduler has been initialized
+pub fn running_thread() -> Option<Rc<RefCell<Thread>>> {
+    try_get()?.running_thread().cloned()
+}
+
+// the scheduler, locked until this is dropped, so nothing that takes it again can run meanwhile
+pub struct Locked(SpinlockGuard<'static, Option<Scheduler>>);
+
+impl Deref for Locked {
+    type Target = Scheduler;
+
+    fn deref(&self) -> &Scheduler {
+        self.0.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for Locked {
+    fn deref_mut(&mut self) -> &mut Scheduler {
+        self.0.as_mut().unwrap()
+    }
+}
+
+pub fn try_get() -> Option<Locked> {
+    let scheduler = SCHEDULER.lock_irqsave();
+
+    if scheduler.is_none() {
+        return None;
+    }
+    Some(Locked(scheduler))
+}
+
+pub fn get() -> Locked {
+    try_get().expect("The scheduler hasn't been initialized")
+}
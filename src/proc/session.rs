@@ -0,0 +1,288 @@
+/*
+    Sessions, process groups and controlling terminals, what job control is built on. A
+    process starts out leading its own group and session, like init does. A session leader
+    can take a tty as its controlling terminal, and the tty then remembers which of the
+    session's groups is in the foreground, the one keyboard generated signals go to.
+
+    Groups are kept by id, with how many processes are in them, so checking that a group
+    exists doesn't need every process to be walked
+*/
+
+use super::process::Process;
+use super::scheduler;
+use crate::error::{KError, KResult};
+use alloc::collections::BTreeMap;
+
+static GROUPS: spin::Mutex<BTreeMap<usize, Group>> = spin::Mutex::new(BTreeMap::new());
+
+// the serial console, the only terminal there is for now
+pub static CONSOLE: Tty = Tty::new("ttyS0");
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Signal {
+    SIGHUP = 1,
+    SIGINT = 2,
+    SIGQUIT = 3,
+    SIGTSTP = 20,
+    SIGWINCH = 28,
+}
+
+impl Signal {
+    pub fn mask(self) -> u64 {
+        1 << self as u8
+    }
+
+    // the control characters a terminal turns into signals, like ^C for SIGINT
+    pub fn from_key(c: u8) -> Option<Signal> {
+        match c {
+            0x03 => Some(Signal::SIGINT),
+            0x1c => Some(Signal::SIGQUIT),
+            0x1a => Some(Signal::SIGTSTP),
+            _ => None,
+        }
+    }
+}
+
+struct Group {
+    sid: usize,
+    members: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Control {
+    sid: usize,
+    foreground: usize, // pgid
+}
+
+pub struct Tty {
+    pub name: &'static str,
+    // None while no session has it as its controlling terminal
+    control: spin::Mutex<Option<Control>>,
+    winsize: spin::Mutex<(u16, u16)>, // rows and columns, 0 while unknown
+}
+
+impl Tty {
+    pub const fn new(name: &'static str) -> Self {
+        Tty {
+            name,
+            control: spin::Mutex::new(None),
+            winsize: spin::Mutex::new((0, 0)),
+        }
+    }
+
+    pub fn winsize(&self) -> (u16, u16) {
+        *self.winsize.lock()
+    }
+
+    // what a driver calls when the screen behind the terminal changed size
+    pub fn resize(&self, rows: u16, cols: u16) {
+        let old = core::mem::replace(&mut *self.winsize.lock(), (rows, cols));
+        if old == (rows, cols) {
+            return;
+        }
+
+        if let Some(foreground) = self.foreground() {
+            signal_group(foreground, Signal::SIGWINCH);
+        }
+    }
+
+    pub fn session(&self) -> Option<usize> {
+        self.control.lock().map(|control| control.sid)
+    }
+
+    pub fn foreground(&self) -> Option<usize> {
+        self.control.lock().map(|control| control.foreground)
+    }
+
+    /*
+        What a tty driver calls with every byte typed. Returns the signal it generated, which
+        was sent to the foreground group, the byte is not input then
+    */
+    pub fn input(&self, c: u8) -> Option<Signal> {
+        let signal = Signal::from_key(c)?;
+
+        if let Some(foreground) = self.foreground() {
+            signal_group(foreground, signal);
+        }
+
+        Some(signal)
+    }
+}
+
+fn join(pgid: usize, sid: usize) {
+    GROUPS
+        .lock()
+        .entry(pgid)
+        .or_insert(Group { sid, members: 0 })
+        .members += 1;
+}
+
+fn leave(pgid: usize) {
+    let mut groups = GROUPS.lock();
+
+    if let Some(group) = groups.get_mut(&pgid) {
+        group.members -= 1;
+        if group.members == 0 {
+            groups.remove(&pgid);
+        }
+    }
+}
+
+// a new process leading its own group and session
+pub(super) fn create(pid: usize) {
+    // a stale group can be left behind if its pid was freed without an exit
+    GROUPS.lock().insert(pid, Group { sid: pid, members: 1 });
+}
+
+// the session of pgid, if there is such a group
+pub fn group_session(pgid: usize) -> Option<usize> {
+    GROUPS.lock().get(&pgid).map(|group| group.sid)
+}
+
+/*
+    Makes process the leader of a new session and of a new group in it, without a
+    controlling terminal. A group has to stay in one session, so this fails if the
+    process's pid is the id of a group with other processes in it
+*/
+pub fn setsid(process: &mut Process) -> KResult<usize> {
+    let others = GROUPS
+        .lock()
+        .get(&process.pid)
+        .map(|group| group.members - (process.pgid == process.pid) as usize)
+        .unwrap_or(0);
+
+    if others > 0 {
+        return Err(KError::EPERM);
+    }
+
+    leave(process.pgid);
+    join(process.pid, process.pid);
+    process.pgid = process.pid;
+    process.sid = process.pid;
+    process.ctty = None;
+
+    Ok(process.sid)
+}
+
+/*
+    Moves process to the group pgid, 0 meaning its own pid. Only an existing group of the
+    same session can be joined, unless it's a new group led by the process. Session leaders
+    can't move
+*/
+pub fn setpgid(process: &mut Process, pgid: usize) -> KResult<()> {
+    let pgid = if pgid == 0 { process.pid } else { pgid };
+
+    if pgid == process.pgid {
+        return Ok(());
+    }
+
+    if process.sid == process.pid {
+        return Err(KError::EPERM);
+    }
+
+    if pgid != process.pid && group_session(pgid) != Some(process.sid) {
+        return Err(KError::EPERM);
+    }
+
+    leave(process.pgid);
+    join(pgid, process.sid);
+    process.pgid = pgid;
+
+    Ok(())
+}
+
+/*
+    Makes tty the controlling terminal of process's session, which it has to lead. The
+    leader's group starts in the foreground
+*/
+pub fn set_ctty(process: &mut Process, tty: &'static Tty) -> KResult<()> {
+    if process.sid != process.pid || process.ctty.is_some() {
+        return Err(KError::EPERM);
+    }
+
+    let mut control = tty.control.lock();
+    if control.is_some() {
+        return Err(KError::EPERM);
+    }
+
+    *control = Some(Control {
+        sid: process.sid,
+        foreground: process.pgid,
+    });
+    process.ctty = Some(tty);
+
+    Ok(())
+}
+
+// the foreground group of process's controlling terminal
+pub fn tcgetpgrp(process: &Process) -> KResult<usize> {
+    let tty = process.ctty.ok_or(KError::ENOTTY)?;
+
+    match *tty.control.lock() {
+        Some(control) if control.sid == process.sid => Ok(control.foreground),
+        _ => Err(KError::ENOTTY),
+    }
+}
+
+// puts pgid, a group of the same session, in the foreground of process's controlling terminal
+pub fn tcsetpgrp(process: &Process, pgid: usize) -> KResult<()> {
+    let tty = process.ctty.ok_or(KError::ENOTTY)?;
+    let mut control = tty.control.lock();
+
+    let control = match control.as_mut() {
+        Some(control) if control.sid == process.sid => control,
+        _ => return Err(KError::ENOTTY),
+    };
+
+    if group_session(pgid) != Some(process.sid) {
+        return Err(KError::EPERM);
+    }
+
+    control.foreground = pgid;
+    Ok(())
+}
+
+/*
+    Marks signal pending for every process in the group, returns how many got it. There's
+    no delivery yet, whoever returns to userspace next will have to look at it
+*/
+pub fn signal_group(pgid: usize, signal: Signal) -> usize {
+    let scheduler = match scheduler::try_get() {
+        Some(scheduler) => scheduler,
+        None => return 0,
+    };
+
+    let mut signaled = 0;
+    for process in scheduler.processes() {
+        // one that's already borrowed is the one doing the signaling, it's skipped
+        if let Ok(mut process) = process.try_borrow_mut() {
+            if process.pgid == pgid {
+                process.pending_signals |= signal.mask();
+                signaled += 1;
+            }
+        }
+    }
+
+    signaled
+}
+
+/*
+    Takes an exiting process out of its group. When a session leader goes, its terminal
+    is released and its foreground group gets a SIGHUP
+*/
+pub(super) fn exit(process: &mut Process) {
+    leave(process.pgid);
+
+    if process.sid != process.pid {
+        return;
+    }
+
+    if let Some(tty) = process.ctty.take() {
+        let control = tty.control.lock().take();
+
+        if let Some(control) = control {
+            signal_group(control.foreground, Signal::SIGHUP);
+        }
+    }
+}
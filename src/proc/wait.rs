@@ -0,0 +1,97 @@
+/*
+    Wait queues, for sleeping until something happens, e.g. a device having data to read.
+    Every notify bumps a generation counter: a waiter reads it before checking its
+    condition and only sleeps if it hasn't changed, so a notify in between isn't missed.
+
+    Without a thread to put to sleep (before the scheduler runs, in the idle thread or with
+    interrupts disabled) waiting just spins
+*/
+
+use super::scheduler;
+use crate::arch::cpu;
+use crate::drivers::timer_source;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+pub struct WaitQueue {
+    generation: AtomicU64,
+    // tids, they're only touched with interrupts off since notify can run in an isr
+    waiters: spin::Mutex<Vec<usize>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            generation: AtomicU64::new(0),
+            waiters: spin::Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    // wakes up everyone waiting, can be called from interrupt handlers
+    pub fn notify(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+
+        cpu::without_interrupts(|| {
+            for tid in self.waiters.lock().iter() {
+                if let Some(mut scheduler) = scheduler::try_get() {
+                    scheduler.wake_tid(*tid);
+                }
+            }
+        });
+    }
+
+    // returns false if deadline passed before a notify
+    pub fn wait(&self, generation: u64, deadline: Option<u64>) -> bool {
+        wait_any(&[(self, generation)], deadline)
+    }
+}
+
+/*
+    Sleeps until any of the queues is notified after its generation was read, or until
+    deadline (in timer source ns). Returns false on a timeout
+*/
+pub fn wait_any(queues: &[(&WaitQueue, u64)], deadline: Option<u64>) -> bool {
+    let notified = || queues.iter().any(|(queue, generation)| queue.generation() != *generation);
+
+    loop {
+        if notified() {
+            return true;
+        }
+
+        if matches!(deadline, Some(deadline) if timer_source::current_ns() >= deadline) {
+            return false;
+        }
+
+        // the reschedule ipi couldn't be taken with interrupts off
+        let thread = match scheduler::sleepable_thread() {
+            Some(thread) if cpu::interrupts_enabled() => thread,
+            _ => {
+                core::hint::spin_loop();
+                continue;
+            }
+        };
+        let tid = thread.borrow().tid;
+
+        // the reschedule ipi is only taken once interrupts are back on, after we're blocked
+        cpu::without_interrupts(|| {
+            for (queue, _) in queues {
+                queue.waiters.lock().push(tid);
+            }
+
+            if !notified() {
+                scheduler::get().sleep(thread, deadline);
+                scheduler::yield_now();
+            }
+        });
+
+        cpu::without_interrupts(|| {
+            for (queue, _) in queues {
+                queue.waiters.lock().retain(|waiter| *waiter != tid);
+            }
+        });
+    }
+}
@@ -0,0 +1,136 @@
+/*
+    Read-mostly data that readers get at without taking a lock. Writers make a changed
+    copy and publish it with a single pointer swap, so a reader sees either the old value
+    or the new one, never something in between.
+
+    The old copy can only be freed once no reader is looking at it. Readers count
+    themselves in one of two counters, picked by the current epoch. After a swap the
+    writer moves everyone to the other counter and waits for the old one to drain, whoever
+    is still counted there may have the old pointer, anyone who came after can't
+*/
+
+use crate::spinlock::Spinlock;
+use alloc::boxed::Box;
+use core::hint::spin_loop;
+use core::ops::Deref;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+static EPOCH: AtomicUsize = AtomicUsize::new(0);
+static READERS: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+// one grace period at a time, shared by every Rcu
+static SYNCHRONIZE: Spinlock<()> = Spinlock::new(());
+
+pub struct Rcu<T> {
+    initial: T,             // what's read until the first update, it's never freed
+    current: AtomicPtr<T>,  // null while initial is current
+    writer: Spinlock<()>,
+}
+
+pub struct RcuGuard<'a, T> {
+    value: &'a T,
+    epoch: usize,
+}
+
+impl<T> Rcu<T> {
+    pub const fn new(initial: T) -> Self {
+        Rcu {
+            initial,
+            current: AtomicPtr::new(null_mut()),
+            writer: Spinlock::new(()),
+        }
+    }
+
+    /*
+        The value as of now, it stays valid until the guard is dropped even if it's replaced
+        meanwhile. Writers wait for the guard, so it shouldn't be kept across a sleep
+    */
+    pub fn read(&self) -> RcuGuard<T> {
+        let epoch = enter();
+        let current = self.current.load(Ordering::SeqCst);
+
+        RcuGuard {
+            value: if current.is_null() {
+                &self.initial
+            } else {
+                unsafe { &*current }
+            },
+            epoch,
+        }
+    }
+
+    // makes value current, the old one is freed once its readers are gone
+    pub fn publish(&self, value: T) {
+        let _writer = self.writer.lock();
+        self.replace(value);
+    }
+
+    /*
+        Lets f change a copy of the current value, which is published if it returns Ok.
+        Writers are serialized, so f sees every earlier update
+    */
+    pub fn update<E>(&self, f: impl FnOnce(&mut T) -> Result<(), E>) -> Result<(), E>
+    where
+        T: Clone,
+    {
+        let _writer = self.writer.lock();
+
+        let mut copy = (*self.read()).clone();
+        f(&mut copy)?;
+
+        self.replace(copy);
+        Ok(())
+    }
+
+    // with the writer lock held
+    fn replace(&self, value: T) {
+        let new = Box::into_raw(Box::new(value));
+        let old = self.current.swap(new, Ordering::SeqCst);
+
+        if !old.is_null() {
+            synchronize();
+            unsafe { drop(Box::from_raw(old)) };
+        }
+    }
+}
+
+unsafe impl<T> Sync for Rcu<T> {}
+
+impl<T> Deref for RcuGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T> Drop for RcuGuard<'_, T> {
+    fn drop(&mut self) {
+        READERS[self.epoch % 2].fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// counts a reader in the current epoch, returns it
+fn enter() -> usize {
+    loop {
+        let epoch = EPOCH.load(Ordering::SeqCst);
+        READERS[epoch % 2].fetch_add(1, Ordering::SeqCst);
+
+        // the writer may have flipped the epoch and found the counter empty in between
+        if EPOCH.load(Ordering::SeqCst) == epoch {
+            return epoch;
+        }
+
+        READERS[epoch % 2].fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// waits until every reader that could have seen what was just unpublished is gone
+pub fn synchronize() {
+    let _sync = SYNCHRONIZE.lock();
+
+    let old = EPOCH.fetch_add(1, Ordering::SeqCst);
+    while READERS[old % 2].load(Ordering::SeqCst) != 0 {
+        spin_loop();
+    }
+}
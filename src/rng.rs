@@ -0,0 +1,211 @@
+/*
+    Random numbers for the kernel: a ChaCha20 keystream whose key comes from RDSEED or
+    RDRAND, or from the jitter of the tsc against the clock when the cpu has neither.
+    The key is replaced with fresh keystream after every request (fast key erasure), so
+    what was handed out before can't be recovered from the state
+*/
+
+use crate::arch::cpu;
+use crate::arch::cpuid::{self, Features};
+use crate::drivers::timer_source;
+use crate::log;
+use core::arch::asm;
+
+// "expand 32-byte k"
+const CONSTANTS: [u32; 4] = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574];
+const BLOCK_SIZE: usize = 64;
+// the instructions can fail transiently when the entropy source is drained
+const HW_RETRIES: usize = 10;
+
+struct ChaCha {
+    key: [u32; 8],
+    seeded: bool,
+}
+
+static STATE: spin::Mutex<ChaCha> = spin::Mutex::new(ChaCha {
+    key: [0; 8],
+    seeded: false,
+});
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+// the block function from rfc 8439
+pub fn block(key: &[u32; 8], counter: u32, nonce: &[u32; 3]) -> [u32; 16] {
+    let mut initial = [0u32; 16];
+    initial[..4].copy_from_slice(&CONSTANTS);
+    initial[4..12].copy_from_slice(key);
+    initial[12] = counter;
+    initial[13..].copy_from_slice(nonce);
+
+    let mut state = initial;
+    for _ in 0..10 {
+        // columns, then diagonals
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+
+    for (word, initial) in state.iter_mut().zip(initial.iter()) {
+        *word = word.wrapping_add(*initial);
+    }
+
+    state
+}
+
+fn rdseed() -> Option<u64> {
+    for _ in 0..HW_RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) ok);
+        }
+
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+fn rdrand() -> Option<u64> {
+    for _ in 0..HW_RETRIES {
+        let value: u64;
+        let ok: u8;
+        unsafe {
+            asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok);
+        }
+
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+
+    None
+}
+
+/*
+    How long reading the clock takes, in tsc cycles, wobbles a little every time. The low
+    bits of many of those readings are folded together, which is slow but needs nothing
+*/
+fn tsc_jitter() -> u64 {
+    let mut value = 0u64;
+
+    for _ in 0..64 {
+        let start = cpu::rdtsc();
+        timer_source::current_ns();
+        let delta = cpu::rdtsc() - start;
+
+        value = value.rotate_left(7) ^ delta;
+    }
+
+    value
+}
+
+fn entropy() -> u64 {
+    if cpuid::has(Features::RDSEED) {
+        if let Some(value) = rdseed() {
+            return value;
+        }
+    }
+
+    if cpuid::has(Features::RDRAND) {
+        if let Some(value) = rdrand() {
+            return value;
+        }
+    }
+
+    tsc_jitter()
+}
+
+impl ChaCha {
+    // mixes new entropy into the key, so a bad source can only make it better
+    fn reseed(&mut self) {
+        let mut seed = [0u32; 8];
+        for pair in seed.chunks_mut(2) {
+            let value = entropy();
+            pair[0] = value as u32;
+            pair[1] = (value >> 32) as u32;
+        }
+
+        let mixed = block(&self.key, 0, &[seed[0], seed[1], seed[2]]);
+        for (i, word) in self.key.iter_mut().enumerate() {
+            *word = mixed[i] ^ seed[i];
+        }
+
+        self.seeded = true;
+    }
+
+    fn fill(&mut self, buffer: &mut [u8]) {
+        if !self.seeded {
+            self.reseed();
+        }
+
+        // block 0 becomes the next key, the output starts at block 1
+        let mut counter = 1;
+        for chunk in buffer.chunks_mut(BLOCK_SIZE) {
+            let words = block(&self.key, counter, &[0; 3]);
+            for (bytes, word) in chunk.chunks_mut(4).zip(words.iter()) {
+                bytes.copy_from_slice(&word.to_le_bytes()[..bytes.len()]);
+            }
+
+            counter += 1;
+        }
+
+        let next = block(&self.key, 0, &[0; 3]);
+        self.key.copy_from_slice(&next[..8]);
+    }
+}
+
+pub fn fill(buffer: &mut [u8]) {
+    STATE.lock().fill(buffer);
+}
+
+pub fn next_u64() -> u64 {
+    let mut bytes = [0u8; 8];
+    fill(&mut bytes);
+    u64::from_le_bytes(bytes)
+}
+
+// uniform in 0..bound, without the bias of a plain modulo
+pub fn below(bound: u64) -> u64 {
+    if bound == 0 {
+        return 0;
+    }
+
+    let limit = u64::MAX - u64::MAX % bound;
+    loop {
+        let value = next_u64();
+        if value < limit {
+            return value % bound;
+        }
+    }
+}
+
+// can be called again later to mix in more entropy
+pub fn init() {
+    let source = if cpuid::has(Features::RDSEED) {
+        "rdseed"
+    } else if cpuid::has(Features::RDRAND) {
+        "rdrand"
+    } else {
+        "tsc jitter"
+    };
+
+    STATE.lock().reseed();
+    log::info!("[RNG] Seeded from {}\n", source);
+}
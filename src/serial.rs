@@ -1,56 +1,307 @@
+/*
+    print! is called from threads and from interrupt handlers on every cpu, so each message
+    is written whole under a lock, held with interrupts disabled so an isr can't come in on
+    top of its own cpu's holder. A caller that already runs with interrupts disabled, like
+    an isr, doesn't wait for the lock: its message goes to a small buffer that whoever holds
+    the lock empties before letting go.
+
+    A panic waits for the lock only for a while and then writes anyway, the holder may be
+    the cpu that panicked, or one that will never let go
+*/
+
+use crate::arch::cpu;
 use crate::arch::io::{inb, outb};
-use core::fmt::Write;
+use crate::cmdline;
+use crate::spinlock::{Spinlock, SpinlockGuard};
+use core::fmt::{self, Write};
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
 const COM1: u16 = 0x3f8;
+const UART_CLOCK: u32 = 115200; // the divisor latch counts in units of this frequency
+
+// how long we wait for the other end to assert CTS before dropping the character
+const CTS_TIMEOUT: usize = 100000;
+
+// how many times a panic spins on the lock before ignoring it
+const PANIC_WAIT: usize = 10_000_000;
+const PENDING_SIZE: usize = 0x1000;
+// bytes taken out of the pending buffer at a time, so it isn't locked while they're sent
+const FLUSH_CHUNK: usize = 64;
+
+static PRESENT: AtomicBool = AtomicBool::new(false);
+static FLOW_CONTROL: AtomicBool = AtomicBool::new(false);
+
+static PORT: Spinlock<SerialWriter> = Spinlock::new(SerialWriter);
+static PANICKING: AtomicBool = AtomicBool::new(false);
+static PENDING: Spinlock<Pending> = Spinlock::new(Pending {
+    buffer: [0; PENDING_SIZE],
+    head: 0,
+    len: 0,
+});
+// messages that didn't make it into the pending buffer, whole or in part
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Clone, Copy)]
+pub struct SerialConfig {
+    pub baud_rate: u32,
+    pub flow_control: bool, // RTS/CTS
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        SerialConfig {
+            baud_rate: 115200,
+            flow_control: false,
+        }
+    }
+}
+
+impl SerialConfig {
+    /*
+        "baud=<rate>" and "rtscts" on the command line, the defaults for what isn't there.
+        A rate that isn't a number is 0, which init refuses like any it can't program
+    */
+    pub fn from_cmdline() -> Self {
+        let baud_rate = match cmdline::option("baud") {
+            Some(rate) => rate.parse().unwrap_or(0),
+            None => SerialConfig::default().baud_rate,
+        };
+
+        SerialConfig {
+            baud_rate,
+            flow_control: cmdline::option("rtscts").is_some(),
+        }
+    }
+}
 
 pub struct SerialWriter;
 
 impl SerialWriter {
-    pub fn init() {
+    // returns false if there is no uart at COM1 or the baud rate can't be programmed
+    pub fn init(config: SerialConfig) -> bool {
+        // the divisor latch is 16 bits, and a rate it can't divide exactly would be off
+        let divisor = UART_CLOCK.checked_div(config.baud_rate).unwrap_or(0);
+        if divisor == 0 || divisor > u16::MAX as u32 || divisor * config.baud_rate != UART_CLOCK {
+            return false;
+        }
+
         unsafe {
+            // if the scratch register doesn't hold a value, there is nothing behind the port
+            outb(COM1 + 7, 0xae);
+            if inb(COM1 + 7) != 0xae {
+                PRESENT.store(false, Ordering::SeqCst);
+                return false;
+            }
+
             outb(COM1 + 1, 0x00);
-            outb(COM1 + 3, 0x80);
-            outb(COM1 + 0, 0x03);
-            outb(COM1 + 1, 0x00);
+            outb(COM1 + 3, 0x80); // DLAB on, so we can set the divisor
+            outb(COM1 + 0, divisor as u8);
+            outb(COM1 + 1, (divisor >> 8) as u8);
             outb(COM1 + 3, 0x03);
             outb(COM1 + 2, 0xC7);
             outb(COM1 + 4, 0x0B);
         }
+
+        FLOW_CONTROL.store(config.flow_control, Ordering::SeqCst);
+        PRESENT.store(true, Ordering::SeqCst);
+        true
     }
 
     fn is_transmit_empty() -> u8 {
         unsafe { inb(COM1 + 5) & 0x20 }
     }
 
+    fn clear_to_send() -> bool {
+        unsafe { inb(COM1 + 6) & 0x10 != 0 }
+    }
+
     pub fn send_char(c: char) {
+        if !PRESENT.load(Ordering::Relaxed) {
+            return;
+        }
+
         while SerialWriter::is_transmit_empty() == 0 {}
 
+        if FLOW_CONTROL.load(Ordering::Relaxed) {
+            let mut tries = 0;
+            while !SerialWriter::clear_to_send() {
+                tries += 1;
+                if tries == CTS_TIMEOUT {
+                    return;
+                }
+                core::hint::spin_loop();
+            }
+        }
+
         unsafe {
             outb(COM1, c as u8);
         }
     }
 
+    // doesn't wait, None if nothing has been received
+    pub fn read_char() -> Option<char> {
+        if !PRESENT.load(Ordering::Relaxed) {
+            return None;
+        }
+
+        unsafe {
+            if inb(COM1 + 5) & 0x01 == 0 {
+                return None;
+            }
+
+            Some(inb(COM1) as char)
+        }
+    }
+
     pub fn print(msg: &str) {
-        for c in msg.chars() {
-            SerialWriter::send_char(c);
+        print_fmt(format_args!("{}", msg));
+    }
+
+    pub fn write_bytes(bytes: &[u8]) {
+        let port = acquire();
+        for byte in bytes {
+            SerialWriter::send_char(*byte as char);
         }
+        release(port);
     }
 }
 
+// straight to the uart, whoever writes through it has to hold the lock
 impl Write for SerialWriter {
-    fn write_str(&mut self, s: &str) -> core::fmt::Result {
-        SerialWriter::print(s);
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            SerialWriter::send_char(c);
+        }
         Ok(())
     }
 }
 
+// what isrs couldn't write because someone else had the port
+struct Pending {
+    buffer: [u8; PENDING_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl Pending {
+    fn pop(&mut self, out: &mut [u8]) -> usize {
+        let cnt = out.len().min(self.len);
+
+        for byte in out.iter_mut().take(cnt) {
+            *byte = self.buffer[self.head];
+            self.head = (self.head + 1) % PENDING_SIZE;
+        }
+        self.len -= cnt;
+
+        cnt
+    }
+}
+
+impl Write for Pending {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if self.len == PENDING_SIZE {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+                break;
+            }
+
+            self.buffer[(self.head + self.len) % PENDING_SIZE] = byte;
+            self.len += 1;
+        }
+
+        Ok(())
+    }
+}
+
+fn acquire() -> SpinlockGuard<'static, SerialWriter> {
+    if !PANICKING.load(Ordering::Relaxed) {
+        return PORT.lock_irqsave();
+    }
+
+    for _ in 0..PANIC_WAIT {
+        if let Some(port) = PORT.try_lock_irqsave() {
+            return port;
+        }
+        spin_loop();
+    }
+
+    unsafe { PORT.force_unlock() };
+    PORT.lock_irqsave()
+}
+
+fn flush_pending(port: &mut SerialWriter) {
+    let mut chunk = [0; FLUSH_CHUNK];
+
+    loop {
+        let cnt = PENDING.lock_irqsave().pop(&mut chunk);
+        if cnt == 0 {
+            break;
+        }
+
+        for byte in &chunk[..cnt] {
+            SerialWriter::send_char(*byte as char);
+        }
+    }
+
+    let dropped = DROPPED.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+        write!(port, "[SERIAL] Lost output from {} messages\n", dropped).ok();
+    }
+}
+
+fn release(mut port: SpinlockGuard<'static, SerialWriter>) {
+    loop {
+        flush_pending(&mut port);
+        drop(port);
+
+        // an isr on another cpu may have buffered something after the flush
+        if PENDING.lock_irqsave().len == 0 {
+            break;
+        }
+        port = match PORT.try_lock_irqsave() {
+            Some(port) => port,
+            None => break,
+        };
+    }
+}
+
+/*
+    Writes the whole message at once. Without interrupts it can't wait for the lock, if
+    it's taken the message is buffered for the holder to write
+*/
+pub fn print_fmt(args: fmt::Arguments) {
+    let can_wait = cpu::interrupts_enabled() || PANICKING.load(Ordering::Relaxed);
+
+    let mut port = match PORT.try_lock_irqsave() {
+        Some(port) => port,
+        None if can_wait => acquire(),
+        None => {
+            // an nmi could have come in on top of this cpu's own writer
+            match PENDING.try_lock() {
+                Some(mut pending) => {
+                    pending.write_fmt(args).ok();
+                }
+                None => {
+                    DROPPED.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            return;
+        }
+    };
+
+    port.write_fmt(args).ok();
+    release(port);
+}
+
+// from the panic handler, from here on the lock is only waited for so long
+pub fn panic_mode() {
+    PANICKING.store(true, Ordering::SeqCst);
+}
+
 macro_rules! print {
     ($($arg:tt)*) => {
-        {
-            use crate::serial::SerialWriter;
-            use core::fmt::Write;
-            write!(&mut SerialWriter {}, $($arg)*).unwrap();
-        }
+        crate::serial::print_fmt(format_args!($($arg)*))
     };
 }
 
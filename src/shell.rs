@@ -0,0 +1,624 @@
+/*
+    A debugging shell on the serial port, started instead of halting when the kernel is
+    booted with "shell" on its command line. Commands get their arguments split on
+    whitespace and return what to print. With "telnet" on the command line the same commands
+    are also served over tcp, to one client at a time
+*/
+
+use crate::arch::{interrupts, power};
+use crate::fs::{vfs, writeback};
+#[cfg(feature = "net")]
+use crate::net::{self, http, icmp};
+use crate::{kprobe, ksym, log, trace};
+use crate::proc::scheduler;
+use crate::serial::{self, SerialWriter};
+use alloc::{format, string::String, vec::Vec};
+use core::fmt::Write;
+
+const PROMPT: &str = "griffin> ";
+const LINE_MAX: usize = 256;
+#[cfg(feature = "net")]
+const TELNET_PORT: u16 = 23;
+#[cfg(feature = "net")]
+const PING_DATA: usize = 56;
+#[cfg(feature = "net")]
+const PING_INTERVAL_MS: u64 = 1000;
+#[cfg(feature = "net")]
+const PING_TIMEOUT_MS: u64 = 2000;
+const KPROBE_USAGE: &str = "kprobe list | arm <address | symbol> | disarm <address | symbol>";
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    run: fn(&[&str]) -> String,
+}
+
+static COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "help",
+        run: help,
+    },
+    Command {
+        name: "sym",
+        usage: "sym <address | symbol>",
+        run: sym,
+    },
+    Command {
+        name: "kprobe",
+        usage: KPROBE_USAGE,
+        run: kprobes,
+    },
+    Command {
+        name: "trace",
+        usage: "trace on | off | clear | dump",
+        run: tracing,
+    },
+    Command {
+        name: "ps",
+        usage: "ps",
+        run: ps,
+    },
+    Command {
+        name: "dmesg",
+        usage: "dmesg [level]",
+        run: dmesg,
+    },
+    Command {
+        name: "interrupts",
+        usage: "interrupts",
+        run: interrupts,
+    },
+    Command {
+        name: "df",
+        usage: "df [path]",
+        run: df,
+    },
+    Command {
+        name: "sync",
+        usage: "sync",
+        run: sync,
+    },
+    Command {
+        name: "reboot",
+        usage: "reboot",
+        run: |_| power::reboot(),
+    },
+    Command {
+        name: "poweroff",
+        usage: "poweroff",
+        run: |_| power::power_off(),
+    },
+    #[cfg(feature = "audio")]
+    Command {
+        name: "beep",
+        usage: "beep [hz] [ms]",
+        run: beep,
+    },
+    #[cfg(feature = "net")]
+    Command {
+        name: "ping",
+        usage: "ping <host> [count]",
+        run: ping,
+    },
+    #[cfg(feature = "net")]
+    Command {
+        name: "arp",
+        usage: "arp",
+        run: arp,
+    },
+    #[cfg(feature = "net")]
+    Command {
+        name: "ifconfig",
+        usage: "ifconfig",
+        run: ifconfig,
+    },
+    #[cfg(feature = "net")]
+    Command {
+        name: "fetch",
+        usage: "fetch <url> [path]",
+        run: fetch,
+    },
+];
+
+fn help(_: &[&str]) -> String {
+    let mut output = String::new();
+
+    for command in COMMANDS {
+        writeln!(output, "{}", command.usage).ok();
+    }
+
+    output
+}
+
+fn sym(args: &[&str]) -> String {
+    let arg = match args {
+        [arg] => *arg,
+        _ => return String::from("usage: sym <address | symbol>\n"),
+    };
+
+    let address = arg.strip_prefix("0x").and_then(|hex| u64::from_str_radix(hex, 16).ok());
+
+    match address {
+        Some(address) => match ksym::resolve(address) {
+            Some(_) => format!("{}\n", ksym::Symbolized(address)),
+            None => format!("{:#x} is not in the kernel's code\n", address),
+        },
+        None => match ksym::lookup(arg) {
+            Some(address) => format!("{:#x}\n", address),
+            None => format!("no symbol named {}\n", arg),
+        },
+    }
+}
+
+// either hex with a 0x prefix or the name of a symbol
+fn parse_address(arg: &str) -> Option<u64> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => ksym::lookup(arg),
+    }
+}
+
+fn kprobes(args: &[&str]) -> String {
+    match args {
+        ["list"] => {
+            let mut output = String::new();
+
+            for (address, hits) in kprobe::probes() {
+                writeln!(output, "{} hits {}", ksym::Symbolized(address), hits).ok();
+            }
+
+            output
+        }
+        [action @ ("arm" | "disarm"), target] => {
+            let address = match parse_address(target) {
+                Some(address) => address,
+                None => return format!("no symbol named {}\n", target),
+            };
+
+            let result = if *action == "arm" {
+                kprobe::arm(address)
+            } else {
+                kprobe::disarm(address)
+            };
+
+            match result {
+                Ok(()) => String::new(),
+                Err(err) => format!("kprobe {} {}: {}\n", action, target, err),
+            }
+        }
+        _ => format!("usage: {}\n", KPROBE_USAGE),
+    }
+}
+
+fn tracing(args: &[&str]) -> String {
+    match args {
+        ["on"] => trace::enable(true),
+        ["off"] => trace::enable(false),
+        ["clear"] => trace::clear(),
+        ["dump"] => return trace::dump(),
+        _ => return String::from("usage: trace on | off | clear | dump\n"),
+    }
+
+    format!("tracing is {}\n", if trace::is_enabled() { "on" } else { "off" })
+}
+
+fn ps(_: &[&str]) -> String {
+    if scheduler::running_thread().is_none() {
+        return String::from("the scheduler hasn't been started\n");
+    }
+
+    scheduler::ps()
+}
+
+// the kernel's message buffer, only what's at least as important as level if it's given
+fn dmesg(args: &[&str]) -> String {
+    let max_level = match args {
+        [] => log::Level::Debug,
+        [level] => match log::Level::from_str(level) {
+            Some(level) => level,
+            None => return String::from("usage: dmesg [error | warn | info | debug]\n"),
+        },
+        _ => return String::from("usage: dmesg [level]\n"),
+    };
+
+    let mut output = String::new();
+    let kmsg = log::kmsg();
+
+    if kmsg.first_seq() > 0 {
+        writeln!(output, "({} older messages were dropped)", kmsg.first_seq()).ok();
+    }
+    kmsg.for_each(|record| {
+        if record.level <= max_level {
+            record.write_dmesg(&mut output).ok();
+        }
+        true
+    });
+
+    output
+}
+
+// how often each vector's handler ran on each cpu, like /proc/interrupts
+fn interrupts(_: &[&str]) -> String {
+    let mut output = String::new();
+    interrupts::write_stats(&mut output).ok();
+    output
+}
+
+fn write_usage(output: &mut String, name: &str, fs_name: &str, stats: vfs::StatFs) {
+    let kib = |blocks: u64| blocks * stats.block_size as u64 / 1024;
+
+    writeln!(
+        output,
+        "{:<16} {:<8} {:>10} {:>10} {:>10} {:>8} {:>8}",
+        name,
+        fs_name,
+        kib(stats.blocks),
+        kib(stats.blocks.saturating_sub(stats.free_blocks)),
+        kib(stats.free_blocks),
+        stats.inodes,
+        stats.free_inodes
+    )
+    .ok();
+}
+
+// in KiB, every mount point or the one path is on
+fn df(args: &[&str]) -> String {
+    let mut output = format!(
+        "{:<16} {:<8} {:>10} {:>10} {:>10} {:>8} {:>8}\n",
+        "mounted on", "type", "size", "used", "free", "inodes", "ifree"
+    );
+
+    match args {
+        [] => {
+            for mount_point in vfs::mount_points().iter() {
+                if let Some(fs) = mount_point.fs() {
+                    let stats = fs.statfs().unwrap_or_default();
+                    write_usage(&mut output, mount_point.name(), fs.name(), stats);
+                }
+            }
+        }
+        [path] => {
+            let path = vfs::resolve_path(path);
+            let stats = match vfs::statfs(&path) {
+                Ok(stats) => stats,
+                Err(err) => return format!("df: {}: {}\n", path, err),
+            };
+
+            if let Some(mount_point) = vfs::get_mount_point(&path) {
+                let fs_name = mount_point.fs().map_or("", |fs| fs.name());
+                write_usage(&mut output, mount_point.name(), fs_name, stats);
+            }
+        }
+        _ => return String::from("usage: df [path]\n"),
+    }
+
+    output
+}
+
+fn sync(_: &[&str]) -> String {
+    match writeback::sync() {
+        Ok(()) => String::new(),
+        Err(err) => format!("sync: {}\n", err),
+    }
+}
+
+#[cfg(feature = "audio")]
+fn beep(args: &[&str]) -> String {
+    let (frequency, ms) = match args {
+        [] => (Some(440), Some(200)),
+        [hz] => (hz.parse().ok(), Some(200)),
+        [hz, ms] => (hz.parse().ok(), ms.parse().ok()),
+        _ => (None, None),
+    };
+
+    let (frequency, ms) = match (frequency, ms) {
+        (Some(frequency), Some(ms)) => (frequency, ms),
+        _ => return String::from("usage: beep [hz] [ms]\n"),
+    };
+
+    match crate::drivers::audio::tone(frequency, ms) {
+        Ok(()) => String::new(),
+        Err(err) => format!("beep: {}\n", err),
+    }
+}
+
+// ns as ms with 3 decimals
+#[cfg(feature = "net")]
+fn as_ms(ns: u64) -> String {
+    format!("{}.{:03}", ns / 1_000_000, ns / 1000 % 1000)
+}
+
+// count echo requests a second apart, 4 if it's not given
+#[cfg(feature = "net")]
+fn ping(args: &[&str]) -> String {
+    let (host, count) = match args {
+        [host] => (*host, 4),
+        [host, count] => match count.parse::<u16>() {
+            Ok(count) => (*host, count),
+            Err(_) => return String::from("usage: ping <host> [count]\n"),
+        },
+        _ => return String::from("usage: ping <host> [count]\n"),
+    };
+
+    let destination = match net::resolve(host) {
+        Ok(destination) => destination,
+        Err(err) => return format!("ping: {}: {}\n", host, err),
+    };
+
+    let mut output = format!("PING {} ({}) {} data bytes\n", host, destination, PING_DATA);
+    let identifier = crate::rng::next_u64() as u16;
+    let mut data = [0u8; PING_DATA];
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+
+    let mut rtts = Vec::new();
+    for sequence in 0..count {
+        if sequence > 0 {
+            crate::drivers::timer_source::sleep(PING_INTERVAL_MS);
+        }
+
+        match icmp::echo(destination, identifier, sequence, &data, PING_TIMEOUT_MS) {
+            Ok(echo) => {
+                let len = echo.len + icmp::HEADER_SIZE;
+                let time = as_ms(echo.rtt_ns);
+                writeln!(
+                    output,
+                    "{} bytes from {}: icmp_seq={} ttl={} time={} ms",
+                    len, echo.from, sequence, echo.ttl, time
+                )
+                .ok();
+                rtts.push(echo.rtt_ns);
+            }
+            Err(err) => {
+                writeln!(output, "icmp_seq={}: {}", sequence, err).ok();
+            }
+        }
+    }
+
+    let lost = (count as usize - rtts.len()) * 100 / (count as usize).max(1);
+    writeln!(output, "--- {} ping statistics ---", host).ok();
+    writeln!(output, "{} sent, {} received, {}% lost", count, rtts.len(), lost).ok();
+    if let (Some(min), Some(max)) = (rtts.iter().min(), rtts.iter().max()) {
+        let average = rtts.iter().sum::<u64>() / rtts.len() as u64;
+        let (min, average, max) = (as_ms(*min), as_ms(average), as_ms(*max));
+        writeln!(output, "rtt min/avg/max = {}/{}/{} ms", min, average, max).ok();
+    }
+
+    output
+}
+
+// what each interface's link layer knows of its neighbors, loopback doesn't have one
+#[cfg(feature = "net")]
+fn arp(_: &[&str]) -> String {
+    let mut output = String::new();
+
+    for interface in net::interfaces() {
+        for (address, hardware) in interface.neighbors() {
+            let bytes: Vec<String> = hardware.iter().map(|byte| format!("{:02x}", byte)).collect();
+            writeln!(output, "{:<16} {} {}", address, bytes.join(":"), interface.name).ok();
+        }
+    }
+
+    if output.is_empty() {
+        return String::from("the arp cache is empty\n");
+    }
+    output
+}
+
+#[cfg(feature = "net")]
+fn ifconfig(_: &[&str]) -> String {
+    use core::sync::atomic::Ordering;
+
+    let mut output = String::new();
+    for interface in net::interfaces() {
+        let stats = &interface.stats;
+        let count = |counter: &core::sync::atomic::AtomicU64| counter.load(Ordering::Relaxed);
+
+        writeln!(
+            output,
+            "{}: inet {} netmask {} mtu {}",
+            interface.name,
+            interface.address,
+            interface.netmask,
+            interface.mtu()
+        )
+        .ok();
+        writeln!(
+            output,
+            "    rx {} packets {} bytes, tx {} packets {} bytes, {} dropped",
+            count(&stats.rx_packets),
+            count(&stats.rx_bytes),
+            count(&stats.tx_packets),
+            count(&stats.tx_bytes),
+            count(&stats.dropped)
+        )
+        .ok();
+    }
+
+    output
+}
+
+// into /tmp under the url's file name if there's no path
+#[cfg(feature = "net")]
+fn fetch(args: &[&str]) -> String {
+    let (url, path) = match args {
+        [url] => {
+            let name = http::Url::parse(url)
+                .ok()
+                .and_then(|url| url.file_name().map(String::from))
+                .unwrap_or_else(|| String::from("index.html"));
+            (*url, format!("/tmp/{}", name))
+        }
+        [url, path] => (*url, String::from(*path)),
+        _ => return String::from("usage: fetch <url> [path]\n"),
+    };
+
+    match http::fetch(url, &path) {
+        Ok(len) => format!("{} bytes saved to {}\n", len, path),
+        Err(err) => format!("fetch: {}: {}\n", url, err),
+    }
+}
+
+pub fn execute(line: &str) -> String {
+    let args: Vec<&str> = line.split_whitespace().collect();
+
+    let (name, args) = match args.split_first() {
+        Some((name, args)) => (*name, args),
+        None => return String::new(),
+    };
+
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => (command.run)(args),
+        None => format!("unknown command {}, try help\n", name),
+    }
+}
+
+// blocks until a whole line has been typed, echoing it back
+fn read_line() -> String {
+    let mut line = String::new();
+
+    loop {
+        let c = match SerialWriter::read_char() {
+            Some(c) => c,
+            None => {
+                core::hint::spin_loop();
+                continue;
+            }
+        };
+
+        match c {
+            '\r' | '\n' => {
+                serial::print!("\n");
+                return line;
+            }
+            // backspace and delete
+            '\x08' | '\x7f' => {
+                if line.pop().is_some() {
+                    serial::print!("\x08 \x08");
+                }
+            }
+            c if !c.is_control() && line.len() < LINE_MAX => {
+                line.push(c);
+                SerialWriter::send_char(c);
+            }
+            _ => {}
+        }
+    }
+}
+
+pub fn run() -> ! {
+    loop {
+        serial::print!("{}", PROMPT);
+        let line = read_line();
+        SerialWriter::print(&execute(&line));
+    }
+}
+
+// strips telnet's option negotiation, an IAC and the two bytes after it
+#[cfg(feature = "net")]
+fn remote_line(pending: &mut Vec<u8>) -> Option<String> {
+    let end = pending.iter().position(|&byte| byte == b'\n')?;
+    let raw: Vec<u8> = pending.drain(..=end).collect();
+
+    let mut line = String::new();
+    let mut skip = 0;
+    for &byte in &raw {
+        match byte {
+            _ if skip > 0 => skip -= 1,
+            0xff => skip = 2,
+            byte if byte.is_ascii() && !byte.is_ascii_control() => line.push(byte as char),
+            _ => {}
+        }
+    }
+    Some(line)
+}
+
+// a client at a time, until it closes the connection or types exit
+#[cfg(feature = "net")]
+fn serve(connection: &vfs::FileDescription) -> crate::error::KResult<()> {
+    use crate::net::socket::{self, MsgFlags};
+
+    let mut pending = Vec::new();
+    let mut buffer = [0u8; 128];
+    socket::send(connection, PROMPT.as_bytes())?;
+
+    loop {
+        while let Some(line) = remote_line(&mut pending) {
+            if line.trim() == "exit" {
+                return Ok(());
+            }
+
+            // telnet wants crlf
+            let output = execute(&line).replace('\n', "\r\n");
+            socket::send(connection, output.as_bytes())?;
+            socket::send(connection, PROMPT.as_bytes())?;
+        }
+
+        match socket::recv(connection, &mut buffer, MsgFlags::empty())? {
+            0 => return Ok(()),
+            len if pending.len() + len > LINE_MAX * 4 => pending.clear(),
+            len => pending.extend_from_slice(&buffer[..len]),
+        }
+    }
+}
+
+#[cfg(feature = "net")]
+extern "C" fn telnetd(port: u64) -> ! {
+    use crate::net::{socket, Ipv4Addr, SocketAddr};
+
+    let listening = socket::socket(socket::SocketType::Stream).and_then(|fd| {
+        socket::bind(&fd, SocketAddr::new(Ipv4Addr::UNSPECIFIED, port as u16))?;
+        socket::listen(&fd, 1)?;
+        Ok(fd)
+    });
+
+    match &listening {
+        Ok(_) => log::info!("[SHELL] Serving the shell on port {}\n", port),
+        Err(err) => log::error!("[SHELL] Could not listen on port {}: {}\n", port, err),
+    }
+
+    if let Ok(fd) = listening {
+        loop {
+            if let Ok((connection, peer)) = socket::accept(&fd) {
+                log::info!("[SHELL] {} connected\n", peer);
+                if let Err(err) = serve(&connection) {
+                    log::debug!("[SHELL] Lost {}: {}\n", peer, err);
+                }
+            }
+        }
+    }
+
+    loop {
+        scheduler::yield_now();
+    }
+}
+
+// port is the value of "telnet" on the command line, 23 if it's empty
+#[cfg(feature = "net")]
+pub fn serve_remote(port: &str) {
+    use crate::proc::process::{Process, SelectorValues, Thread};
+
+    let port = match port {
+        "" => TELNET_PORT,
+        port => match port.parse() {
+            Ok(port) => port,
+            Err(_) => {
+                log::error!("[SHELL] {} isn't a port\n", port);
+                return;
+            }
+        },
+    };
+
+    if scheduler::try_get().is_none() {
+        log::error!("[SHELL] No scheduler to serve the shell on port {} with\n", port);
+        return;
+    }
+
+    let process = Process::new(String::from("telnetd"), 0, String::from("/"));
+    let thread = Thread::new(telnetd as u64, SelectorValues::KernelCs, process.clone());
+    thread.borrow_mut().regs.rdi = port as u64;
+    process.borrow_mut().threads.push(thread.clone());
+    scheduler::get().enqueue(thread);
+}
@@ -0,0 +1,79 @@
+/*
+    With "snapshot" on the command line, the state the kernel ended up in after booting
+    is dumped in a stable text format, so that two builds can be compared with a plain diff.
+    Nothing that changes from boot to boot (addresses, timestamps) goes in here.
+    "snapshot=/some/path" also writes it to that file
+*/
+
+use crate::arch::{interrupts, mm::pmm, pci};
+use crate::fs::{partitions, vfs};
+use crate::{cmdline, log, serial};
+use alloc::string::String;
+use core::fmt::Write;
+
+const VERSION: usize = 2;
+
+fn build() -> String {
+    let mut snapshot = String::new();
+
+    writeln!(snapshot, "griffin boot snapshot v{}", VERSION).ok();
+
+    writeln!(snapshot, "[memory]").ok();
+    writeln!(snapshot, "total_pages {}", pmm::get().total_pages()).ok();
+    writeln!(snapshot, "free_pages {}", pmm::get().free_pages()).ok();
+
+    writeln!(snapshot, "[mounts]").ok();
+    for mount_point in vfs::mount_points().iter() {
+        let fs_name = mount_point.fs().map(|fs| fs.name()).unwrap_or("none");
+        writeln!(snapshot, "{} {}", mount_point.name(), fs_name).ok();
+    }
+
+    writeln!(snapshot, "[partitions]").ok();
+    for partition in partitions::partitions() {
+        writeln!(
+            snapshot,
+            "{} {} {}-{} \"{}\"",
+            partition.name,
+            partition.type_guid,
+            partition.start_lba,
+            partition.end_lba,
+            partition.label
+        )
+        .ok();
+    }
+
+    writeln!(snapshot, "[pci]").ok();
+    for device in pci::devices().iter() {
+        writeln!(snapshot, "{}", device).ok();
+    }
+
+    writeln!(snapshot, "[interrupts]").ok();
+    for (vector, ist, gate_type) in interrupts::used_vectors() {
+        writeln!(snapshot, "{:#04x} ist {} type {:#04x}", vector, ist, gate_type).ok();
+    }
+
+    snapshot
+}
+
+pub fn run() {
+    let path = match cmdline::option("snapshot") {
+        Some(path) => path,
+        None => return,
+    };
+
+    let snapshot = build();
+
+    // printed straight to the serial port, so log levels can't change it
+    serial::print!("=== BOOT SNAPSHOT BEGIN ===\n{}=== BOOT SNAPSHOT END ===\n", snapshot);
+
+    if path.is_empty() {
+        return;
+    }
+
+    let result = vfs::open(path, vfs::Flags::O_CREAT | vfs::Flags::O_WRONLY, vfs::Mode::empty())
+        .and_then(|mut fd| vfs::write(&mut fd, snapshot.as_ptr(), snapshot.len()));
+
+    if let Err(err) = result {
+        log::error!("[SNAPSHOT] Could not write {}: {}\n", path, err);
+    }
+}
@@ -1,27 +1,133 @@
+/*
+    A lock that spins, for data shared between cpus. The guard unlocks when it's dropped.
+    lock_irqsave also disables interrupts until then, which anything an isr takes as well
+    has to use, otherwise an isr can come in on top of its own cpu's holder and spin
+    forever
+*/
+
+use crate::arch::cpu;
+use core::cell::UnsafeCell;
 use core::hint::spin_loop;
-use core::sync::atomic::{self, Ordering};
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
 
-pub struct Spinlock<T>(atomic::AtomicBool, T);
+pub struct Spinlock<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+pub struct SpinlockGuard<'a, T> {
+    lock: &'a Spinlock<T>,
+    restore_interrupts: bool, // whether they were enabled before lock_irqsave
+}
 
 impl<T> Spinlock<T> {
     pub const fn new(value: T) -> Self {
-        Spinlock(atomic::AtomicBool::new(false), value)
+        Spinlock {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    fn acquire(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    fn guard(&self, restore_interrupts: bool) -> SpinlockGuard<T> {
+        SpinlockGuard {
+            lock: self,
+            restore_interrupts,
+        }
+    }
+
+    pub fn lock(&self) -> SpinlockGuard<T> {
+        while !self.acquire() {
+            // only read while it's taken, so the cache line isn't bounced around
+            while self.locked.load(Ordering::Relaxed) {
+                spin_loop();
+            }
+        }
+
+        self.guard(false)
+    }
+
+    pub fn try_lock(&self) -> Option<SpinlockGuard<T>> {
+        if self.acquire() {
+            Some(self.guard(false))
+        } else {
+            None
+        }
+    }
+
+    pub fn lock_irqsave(&self) -> SpinlockGuard<T> {
+        let enabled = cpu::interrupts_enabled();
+        cpu::cli();
+
+        while !self.acquire() {
+            // the wait can be long, so interrupts are let in meanwhile if they were on
+            if enabled {
+                cpu::sti();
+            }
+            while self.locked.load(Ordering::Relaxed) {
+                spin_loop();
+            }
+            cpu::cli();
+        }
+
+        self.guard(enabled)
     }
 
-    pub fn lock(&mut self) -> &mut T {
-        while let Err(_) =
-            self.0
-                .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
-        {
-            spin_loop();
+    pub fn try_lock_irqsave(&self) -> Option<SpinlockGuard<T>> {
+        let enabled = cpu::interrupts_enabled();
+        cpu::cli();
+
+        if self.acquire() {
+            return Some(self.guard(enabled));
+        }
+
+        if enabled {
+            cpu::sti();
         }
+        None
+    }
 
-        &mut self.1
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
     }
 
-    pub fn unlock(&self) {
-        self.0.store(false, Ordering::Release);
+    /*
+        Unlocks it without a guard, for when the holder will never give it back, like a
+        cpu that panicked. Whoever still has a guard must not use it anymore
+    */
+    pub unsafe fn force_unlock(&self) {
+        self.locked.store(false, Ordering::Release);
     }
 }
 
 unsafe impl<T> Sync for Spinlock<T> {}
+
+impl<T> Deref for SpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for SpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for SpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.locked.store(false, Ordering::Release);
+
+        if self.restore_interrupts {
+            cpu::sti();
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use super::copy_path_from_user;
+use super::user::UserSlice;
+use crate::error::{KError, KResult};
+use crate::fs::vfs;
+use crate::proc::scheduler;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+// the fixed part of a linux_dirent64, the name follows it
+#[repr(C, packed)]
+struct Dirent64 {
+    inode: u64,
+    offset: i64,
+    record_length: u16,
+    entry_type: u8,
+}
+
+/*
+    Fills buffer with as many directory entries as fit, each one padded to 8 bytes and
+    with a null terminated name. Returns the amount of bytes used, 0 at the end of the
+    directory, or an error if not even the first entry fits
+*/
+pub fn getdents64(fd: u64, buffer: u64, len: u64) -> KResult<u64> {
+    let buffer = UserSlice::new(buffer, len as usize);
+    // reading moves the directory offset, so a bad buffer has to be caught before that
+    buffer.check_writable()?;
+
+    let records = read_records(fd, len as usize)?;
+    buffer.write(&records)?;
+
+    Ok(records.len() as u64)
+}
+
+// the process stays borrowed while reading, so nothing is copied to userspace in here
+fn read_records(fd: u64, len: usize) -> KResult<Vec<u8>> {
+    let thread = scheduler::running_thread().ok_or(KError::EINVAL)?;
+    let thread = thread.borrow();
+    let mut process = thread.parent.borrow_mut();
+
+    let description = match process.file_desc_list.get_mut(fd as usize) {
+        Some(Some(description)) => description,
+        _ => return Err(KError::EBADF),
+    };
+
+    let mut records: Vec<u8> = Vec::new();
+    let mut entries = vfs::read_dir(description);
+    let mut consumed = description.offset;
+
+    while let Some(entry) = entries.next() {
+        let entry = entry?;
+        let record_length = (size_of::<Dirent64>() + entry.name.len() + 1 + 7) & !7;
+
+        if records.len() + record_length > len {
+            if records.is_empty() {
+                return Err(KError::EINVAL);
+            }
+
+            // this one is returned by the next call
+            break;
+        }
+
+        let header = Dirent64 {
+            inode: entry.inode,
+            offset: entry.next_offset as i64,
+            record_length: record_length as u16,
+            entry_type: entry.entry_type as u8,
+        };
+
+        let start = records.len();
+        records.resize(start + record_length, 0);
+
+        unsafe {
+            records
+                .as_mut_ptr()
+                .add(start)
+                .copy_from(&header as *const Dirent64 as *const u8, size_of::<Dirent64>());
+        }
+
+        let name_start = start + size_of::<Dirent64>();
+        records[name_start..name_start + entry.name.len()].copy_from_slice(entry.name.as_bytes());
+
+        consumed = entries.offset();
+    }
+
+    description.offset = consumed;
+    Ok(records)
+}
+
+pub fn chdir(path: u64) -> KResult<u64> {
+    let path = copy_path_from_user(path)?;
+    vfs::chdir(&path)?;
+    Ok(0)
+}
+
+pub fn chmod(path: u64, mode: u64) -> KResult<u64> {
+    let path = copy_path_from_user(path)?;
+    vfs::chmod(&path, vfs::FilePermissions::from_bits_truncate(mode as u16))?;
+    Ok(0)
+}
+
+// an id of -1 (as a u32, like linux) leaves it unchanged
+pub fn chown(path: u64, uid: u64, gid: u64) -> KResult<u64> {
+    let path = copy_path_from_user(path)?;
+    let id = |id: u64| (id as u32 != u32::MAX).then(|| id as u32);
+    vfs::chown(&path, id(uid), id(gid))?;
+    Ok(0)
+}
+
+// copies the null terminated working directory into buffer, returns its length with the null
+pub fn getcwd(buffer: u64, len: u64) -> KResult<u64> {
+    let mut cwd = vfs::getcwd().into_bytes();
+    cwd.push(0);
+
+    UserSlice::new(buffer, len as usize).write(&cwd)?;
+    Ok(cwd.len() as u64)
+}
@@ -0,0 +1,97 @@
+use crate::drivers::timer_source;
+use crate::serial;
+use crate::error::{KError, KResult};
+use crate::{log, trace};
+use alloc::string::String;
+use user::UserSlice;
+
+mod fs;
+mod proc;
+mod uring;
+pub mod user;
+
+pub const SYS_DEBUG_WRITE: u64 = 0;
+pub const SYS_GETDENTS64: u64 = 1;
+pub const SYS_CHDIR: u64 = 2;
+pub const SYS_GETCWD: u64 = 3;
+pub const SYS_THREAD_CREATE: u64 = 4;
+pub const SYS_CHMOD: u64 = 5;
+pub const SYS_CHOWN: u64 = 6;
+pub const SYS_IO_URING_SETUP: u64 = 7;
+pub const SYS_IO_URING_ENTER: u64 = 8;
+
+const PATH_MAX: usize = 4096;
+
+// debug_write may burst up to this many bytes, then it's refilled at this rate per second
+const DEBUG_WRITE_BUDGET: u64 = 4096;
+
+static DEBUG_WRITE_LIMITER: spin::Mutex<RateLimiter> = spin::Mutex::new(RateLimiter {
+    tokens: DEBUG_WRITE_BUDGET,
+    last_refill: 0,
+});
+
+struct RateLimiter {
+    tokens: u64,
+    last_refill: u64,
+}
+
+impl RateLimiter {
+    // returns how many of the requested bytes can be written right now
+    fn take(&mut self, wanted: u64) -> u64 {
+        let now = timer_source::current_ms();
+        let refill = (now - self.last_refill) * DEBUG_WRITE_BUDGET / 1000;
+
+        if refill > 0 {
+            self.tokens = (self.tokens + refill).min(DEBUG_WRITE_BUDGET);
+            self.last_refill = now;
+        }
+
+        let granted = wanted.min(self.tokens);
+        self.tokens -= granted;
+        granted
+    }
+}
+
+pub fn dispatch(number: u64, arg0: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64, _arg5: u64) -> u64 {
+    trace::trace!(syscall_enter, number, arg0);
+
+    let result = match number {
+        SYS_DEBUG_WRITE => debug_write(arg0, arg1),
+        SYS_GETDENTS64 => fs::getdents64(arg0, arg1, arg2),
+        SYS_CHDIR => fs::chdir(arg0),
+        SYS_GETCWD => fs::getcwd(arg0, arg1),
+        SYS_THREAD_CREATE => proc::thread_create(arg0, arg1, arg2, arg3, arg4),
+        SYS_CHMOD => fs::chmod(arg0, arg1),
+        SYS_CHOWN => fs::chown(arg0, arg1, arg2),
+        SYS_IO_URING_SETUP => uring::io_uring_setup(arg0, arg1),
+        SYS_IO_URING_ENTER => uring::io_uring_enter(arg0, arg1, arg2, arg3),
+        _ => {
+            log::warning!("[SYSCALL] Unknown syscall {}\n", number);
+            Err(KError::ENOSYS)
+        }
+    };
+
+    // errors are returned as -errno
+    let result = result.unwrap_or_else(|err| err.as_syscall_return());
+    trace::trace!(syscall_exit, number, result);
+    result
+}
+
+// copies a null terminated path out of user memory
+fn copy_path_from_user(address: u64) -> KResult<String> {
+    user::read_cstr(address, PATH_MAX)
+}
+
+// prints a string straight to the kernel console, until there's a proper tty
+fn debug_write(buffer: u64, len: u64) -> KResult<u64> {
+    if !user::is_user_range(buffer, len) {
+        return Err(KError::EFAULT);
+    }
+
+    let len = DEBUG_WRITE_LIMITER.lock().take(len);
+
+    let bytes = UserSlice::new(buffer, len as usize).read_to_vec()?;
+    serial::SerialWriter::write_bytes(&bytes);
+
+    Ok(len)
+}
@@ -0,0 +1,30 @@
+use super::user;
+use crate::error::{KError, KResult};
+use crate::proc::process::{CloneFlags, Process};
+use crate::proc::scheduler;
+
+/*
+    Starts a thread in the calling process at entry, with arg as its first argument. A
+    stack of 0 gets a new one mapped, otherwise it's the top of one userspace set up, like
+    what pthread_create hands to clone. Returns the new tid
+*/
+pub fn thread_create(entry: u64, stack: u64, flags: u64, tls: u64, arg: u64) -> KResult<u64> {
+    let flags = CloneFlags::from_bits(flags).ok_or(KError::EINVAL)?;
+
+    if !user::is_user_range(entry, 1) || !user::is_user_range(stack, 0) {
+        return Err(KError::EFAULT);
+    }
+
+    // fs_base has to be canonical, or restoring it would fault in the kernel
+    if flags.contains(CloneFlags::CLONE_SETTLS) && !user::is_user_range(tls, 0) {
+        return Err(KError::EINVAL);
+    }
+
+    let thread = scheduler::running_thread().ok_or(KError::EINVAL)?;
+    let process = thread.borrow().parent.clone();
+
+    let stack = if stack == 0 { None } else { Some(stack) };
+    let tid = Process::thread_create(&process, entry, stack, arg, tls, flags)?;
+
+    Ok(tid as u64)
+}
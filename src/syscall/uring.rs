@@ -0,0 +1,205 @@
+/*
+    io_uring_setup and io_uring_enter. Setting a ring up maps it into the process and starts
+    a kernel thread in it, the ring's worker, which does what's submitted until the ring is
+    closed. Enter only wakes the worker and waits for completions if asked to, a process that
+    keeps the worker busy can do its i/o without entering at all
+*/
+
+use super::copy_path_from_user;
+use super::user::{UserPtr, UserSlice};
+use crate::error::{KError, KResult};
+use crate::fs::uring::{self, Ring, Sqe};
+use crate::fs::vfs;
+use crate::mm::vmm::{MapFlags, MapProt};
+use crate::proc::process::{Process, SelectorValues, Status, Thread};
+use crate::proc::scheduler;
+use alloc::rc::Rc;
+use alloc::sync::Arc;
+use core::cell::RefCell;
+
+pub const IORING_ENTER_GETEVENTS: u64 = 1;
+const AT_FDCWD: i32 = -100;
+
+/*
+    Makes a ring of at least entries submission entries, maps it and fills in params with
+    what it looks like and where it is. Returns the ring's fd
+*/
+pub fn io_uring_setup(entries: u64, params: u64) -> KResult<u64> {
+    let params = UserPtr::<uring::Params>::new(params);
+    // nothing of the setup is undone, so a bad pointer has to be caught before it
+    params.read()?;
+    // the worker would never run
+    if scheduler::try_get().is_none() {
+        return Err(KError::EAGAIN);
+    }
+
+    let entries = u32::try_from(entries).map_err(|_| KError::EINVAL)?;
+    let (description, ring) = uring::create(entries)?;
+
+    let thread = scheduler::running_thread().ok_or(KError::EINVAL)?;
+    let process = thread.borrow().parent.clone();
+
+    let (fd, address) = {
+        let mut process = process.borrow_mut();
+        let fd = process.alloc_fd(description.clone())?;
+
+        let pagemap = process.pagemap.as_mut().ok_or(KError::EINVAL)?;
+        let prot = MapProt::READ | MapProt::WRITE;
+        let mapped = pagemap.mmap(
+            None,
+            ring.size() as u64,
+            prot,
+            MapFlags::SHARED,
+            Some(description),
+            0,
+        );
+
+        match mapped {
+            Ok(address) => (fd, address),
+            Err(err) => {
+                process.file_desc_list[fd] = None;
+                return Err(err);
+            }
+        }
+    };
+
+    let worker = Thread::new(worker as u64, SelectorValues::KernelCs, process.clone());
+    worker.borrow_mut().regs.rdi = Arc::into_raw(ring.clone()) as u64;
+    process.borrow_mut().threads.push(worker.clone());
+    scheduler::get().enqueue(worker);
+
+    params.write(ring.params(address.as_u64()))?;
+    Ok(fd as u64)
+}
+
+/*
+    Wakes fd's worker for to_submit new entries and with IORING_ENTER_GETEVENTS sleeps until
+    there are at least min_complete completions. Returns to_submit, the worker takes them
+    whenever it gets to them
+*/
+pub fn io_uring_enter(fd: u64, to_submit: u64, min_complete: u64, flags: u64) -> KResult<u64> {
+    let ring = {
+        let thread = scheduler::running_thread().ok_or(KError::EINVAL)?;
+        let thread = thread.borrow();
+        let process = thread.parent.borrow();
+
+        match process.file_desc_list.get(fd as usize) {
+            Some(Some(description)) => uring::get(description).ok_or(KError::EBADF)?,
+            _ => return Err(KError::EBADF),
+        }
+    };
+
+    if to_submit > 0 {
+        ring.submitted.notify();
+    }
+
+    if flags & IORING_ENTER_GETEVENTS != 0 && !ring.wait_completions(min_complete as u32) {
+        return Err(KError::EBADF);
+    }
+
+    Ok(to_submit)
+}
+
+extern "C" fn worker(ring: *const Ring) -> ! {
+    let ring = unsafe { Arc::from_raw(ring) };
+    let thread = scheduler::running_thread().expect("A ring's worker runs without a thread");
+    let process = thread.borrow().parent.clone();
+
+    while !ring.is_closed() {
+        let generation = ring.submitted.generation();
+        if ring.consume(|sqe| execute(&process, sqe)) == 0 {
+            ring.submitted.wait(generation, None);
+        }
+    }
+
+    drop(ring);
+    exit(&process, thread)
+}
+
+// there's no exit for kernel threads, the worker takes itself out and never runs again
+fn exit(process: &Rc<RefCell<Process>>, thread: Rc<RefCell<Thread>>) -> ! {
+    let tid = thread.borrow().tid;
+    process.borrow_mut().threads.retain(|other| !Rc::ptr_eq(other, &thread));
+
+    thread.borrow_mut().status = Status::Dying;
+    Thread::free_tid(tid);
+    drop(thread);
+
+    loop {
+        scheduler::yield_now();
+    }
+}
+
+// what the syscall would have returned, errors as -errno
+fn execute(process: &Rc<RefCell<Process>>, sqe: &Sqe) -> i32 {
+    let result = match sqe.opcode {
+        uring::IORING_OP_NOP => Ok(0),
+        uring::IORING_OP_READ => read(process, sqe),
+        uring::IORING_OP_WRITE => write(process, sqe),
+        uring::IORING_OP_OPENAT => openat(process, sqe),
+        _ => Err(KError::EINVAL),
+    };
+
+    match result {
+        Ok(value) => value as i32,
+        Err(err) => err.as_syscall_return() as i32,
+    }
+}
+
+// the process is only borrowed for the i/o itself, like the syscalls do
+fn with_description<T>(
+    process: &Rc<RefCell<Process>>,
+    fd: i32,
+    f: impl FnOnce(&mut vfs::FileDescription) -> KResult<T>,
+) -> KResult<T> {
+    let mut process = process.borrow_mut();
+
+    match process.file_desc_list.get_mut(fd as usize) {
+        Some(Some(description)) if fd >= 0 => f(description),
+        _ => Err(KError::EBADF),
+    }
+}
+
+fn read(process: &Rc<RefCell<Process>>, sqe: &Sqe) -> KResult<usize> {
+    let buffer = UserSlice::new(sqe.addr, sqe.len as usize);
+    // reading can move the offset, so a bad buffer has to be caught before that
+    buffer.check_writable()?;
+
+    let mut data = alloc::vec![0u8; sqe.len as usize];
+    let read = with_description(process, sqe.fd, |description| {
+        if sqe.off == u64::MAX {
+            vfs::read(description, data.as_mut_ptr(), data.len())
+        } else {
+            vfs::pread(description, data.as_mut_ptr(), data.len(), sqe.off as usize)
+        }
+    })?;
+
+    buffer.write(&data[..read])?;
+    Ok(read)
+}
+
+fn write(process: &Rc<RefCell<Process>>, sqe: &Sqe) -> KResult<usize> {
+    let data = UserSlice::new(sqe.addr, sqe.len as usize).read_to_vec()?;
+
+    with_description(process, sqe.fd, |description| {
+        if sqe.off == u64::MAX {
+            vfs::write(description, data.as_ptr(), data.len())
+        } else {
+            vfs::pwrite(description, data.as_ptr(), data.len(), sqe.off as usize)
+        }
+    })
+}
+
+// only absolute paths or ones relative to the working directory, there are no dirfds yet
+fn openat(process: &Rc<RefCell<Process>>, sqe: &Sqe) -> KResult<usize> {
+    let path = copy_path_from_user(sqe.addr)?;
+    if sqe.fd != AT_FDCWD && !path.starts_with('/') {
+        return Err(KError::EINVAL);
+    }
+
+    let flags = vfs::Flags::from_bits_truncate(sqe.op_flags);
+    let mode = vfs::Mode::from_bits_truncate(sqe.len);
+    let description = vfs::open(&path, flags, mode)?;
+
+    process.borrow_mut().alloc_fd(description)
+}
@@ -0,0 +1,201 @@
+/*
+    Every pointer a syscall gets from userspace goes through UserPtr or UserSlice. The range
+    is checked against the calling process' mappings before it's touched, and the copy itself
+    is done by copy_user_bytes, whose faults are turned into EFAULT by the page fault handler
+    instead of taking the kernel down
+*/
+
+use crate::arch::{cpu, mm::pmm};
+use crate::error::{KError, KResult};
+use crate::mm::vmm::VirtAddr;
+use crate::proc::scheduler;
+use alloc::{string::String, vec::Vec};
+use core::arch::global_asm;
+use core::marker::PhantomData;
+use core::mem::{size_of, MaybeUninit};
+
+const USER_SPACE_END: u64 = 0x0000800000000000;
+
+// rcx holds the bytes left to copy, so when rep movsb faults the fixup just returns it
+global_asm!(
+    ".global copy_user_bytes",
+    ".global copy_user_fault_ip",
+    ".global copy_user_fixup",
+    "copy_user_bytes:",
+    "mov rcx, rdx",
+    "copy_user_fault_ip:",
+    "rep movsb",
+    "copy_user_fixup:",
+    "mov rax, rcx",
+    "ret",
+);
+
+extern "C" {
+    // returns how many bytes were not copied
+    fn copy_user_bytes(destination: *mut u8, source: *const u8, len: usize) -> usize;
+    static copy_user_fault_ip: u8;
+    static copy_user_fixup: u8;
+}
+
+// called by the page fault handler, returns where to resume if the fault happened mid copy
+pub fn fault_fixup(rip: u64) -> Option<u64> {
+    unsafe {
+        if rip == &copy_user_fault_ip as *const u8 as u64 {
+            Some(&copy_user_fixup as *const u8 as u64)
+        } else {
+            None
+        }
+    }
+}
+
+pub fn is_user_range(address: u64, len: u64) -> bool {
+    address < USER_SPACE_END && len <= USER_SPACE_END - address
+}
+
+// checks that every page of the range is mapped for userspace in the running process
+fn validate(address: u64, len: u64, write: bool) -> KResult<()> {
+    if !is_user_range(address, len) {
+        return Err(KError::EFAULT);
+    }
+
+    if len == 0 {
+        return Ok(());
+    }
+
+    let thread = scheduler::running_thread().ok_or(KError::EFAULT)?;
+    let thread = thread.borrow();
+    let process = thread.parent.borrow();
+    let pagemap = process.pagemap.as_ref().ok_or(KError::EFAULT)?;
+
+    let first_page = address & !(pmm::PAGE_SIZE - 1);
+    let last_page = (address + len - 1) & !(pmm::PAGE_SIZE - 1);
+
+    for page in (first_page..=last_page).step_by(pmm::PAGE_SIZE as usize) {
+        if !pagemap.user_accessible(VirtAddr::new(page), write) {
+            return Err(KError::EFAULT);
+        }
+    }
+
+    Ok(())
+}
+
+fn copy(destination: *mut u8, source: *const u8, len: usize) -> KResult<()> {
+    cpu::stac();
+    let left = unsafe { copy_user_bytes(destination, source, len) };
+    cpu::clac();
+
+    if left != 0 {
+        return Err(KError::EFAULT);
+    }
+
+    Ok(())
+}
+
+pub struct UserPtr<T> {
+    address: u64,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Copy> UserPtr<T> {
+    pub fn new(address: u64) -> Self {
+        UserPtr {
+            address,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn read(&self) -> KResult<T> {
+        validate(self.address, size_of::<T>() as u64, false)?;
+
+        let mut value = MaybeUninit::<T>::uninit();
+        copy(
+            value.as_mut_ptr() as *mut u8,
+            self.address as *const u8,
+            size_of::<T>(),
+        )?;
+
+        Ok(unsafe { value.assume_init() })
+    }
+
+    pub fn write(&self, value: T) -> KResult<()> {
+        validate(self.address, size_of::<T>() as u64, true)?;
+        copy(
+            self.address as *mut u8,
+            &value as *const T as *const u8,
+            size_of::<T>(),
+        )
+    }
+}
+
+pub struct UserSlice {
+    address: u64,
+    len: usize,
+}
+
+impl UserSlice {
+    pub fn new(address: u64, len: usize) -> Self {
+        UserSlice { address, len }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // for callers that have side effects to avoid when the copy would fail anyway
+    pub fn check_writable(&self) -> KResult<()> {
+        validate(self.address, self.len as u64, true)
+    }
+
+    pub fn read_to_vec(&self) -> KResult<Vec<u8>> {
+        let mut buffer = alloc::vec![0u8; self.len];
+        self.read(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    // fills buffer from the start of the slice
+    pub fn read(&self, buffer: &mut [u8]) -> KResult<()> {
+        if buffer.len() > self.len {
+            return Err(KError::EINVAL);
+        }
+
+        validate(self.address, buffer.len() as u64, false)?;
+        copy(buffer.as_mut_ptr(), self.address as *const u8, buffer.len())
+    }
+
+    // writes data to the start of the slice, ERANGE if it doesn't fit
+    pub fn write(&self, data: &[u8]) -> KResult<()> {
+        if data.len() > self.len {
+            return Err(KError::ERANGE);
+        }
+
+        validate(self.address, data.len() as u64, true)?;
+        copy(self.address as *mut u8, data.as_ptr(), data.len())
+    }
+}
+
+// copies a null terminated string of less than max bytes out of user memory
+pub fn read_cstr(address: u64, max: usize) -> KResult<String> {
+    let mut string = Vec::new();
+    let mut current = address;
+
+    // a page at a time, the string may end right before an unmapped page
+    while string.len() < max {
+        let page_end = (current & !(pmm::PAGE_SIZE - 1)) + pmm::PAGE_SIZE;
+        let chunk_len = ((page_end - current) as usize).min(max - string.len());
+
+        let chunk = UserSlice::new(current, chunk_len).read_to_vec()?;
+        if let Some(end) = chunk.iter().position(|byte| *byte == 0) {
+            string.extend_from_slice(&chunk[..end]);
+            return String::from_utf8(string).map_err(|_| KError::EINVAL);
+        }
+
+        string.extend_from_slice(&chunk);
+        current += chunk_len as u64;
+    }
+
+    Err(KError::ENAMETOOLONG)
+}
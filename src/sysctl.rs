@@ -0,0 +1,70 @@
+/*
+    Kernel tunables that can be changed at runtime, by name
+*/
+
+use crate::log;
+use alloc::string::{String, ToString};
+
+pub struct Sysctl {
+    pub name: &'static str,
+    pub read: fn() -> String,
+    pub write: fn(&str) -> Result<(), ()>,
+}
+
+static SYSCTLS: &[Sysctl] = &[
+    Sysctl {
+        name: "log.serial",
+        read: || log::threshold(log::Sink::Serial).as_str().to_string(),
+        write: |value| set_log_threshold(log::Sink::Serial, value),
+    },
+    Sysctl {
+        name: "log.screen",
+        read: || log::threshold(log::Sink::Screen).as_str().to_string(),
+        write: |value| set_log_threshold(log::Sink::Screen, value),
+    },
+    Sysctl {
+        name: "log.kmsg",
+        read: || log::threshold(log::Sink::Kmsg).as_str().to_string(),
+        write: |value| set_log_threshold(log::Sink::Kmsg, value),
+    },
+    #[cfg(feature = "net")]
+    Sysctl {
+        name: "net.dns",
+        read: || crate::net::dns::server().map_or(String::from("none"), |ip| ip.to_string()),
+        write: set_dns_server,
+    },
+];
+
+fn set_log_threshold(sink: log::Sink, value: &str) -> Result<(), ()> {
+    let level = log::Level::from_str(value).ok_or(())?;
+    log::set_threshold(sink, level);
+    Ok(())
+}
+
+// "none" for no server
+#[cfg(feature = "net")]
+fn set_dns_server(value: &str) -> Result<(), ()> {
+    let server = match value {
+        "none" => None,
+        address => Some(crate::net::Ipv4Addr::parse(address).ok_or(())?),
+    };
+
+    crate::net::dns::set_server(server);
+    Ok(())
+}
+
+pub fn all() -> &'static [Sysctl] {
+    SYSCTLS
+}
+
+pub fn read(name: &str) -> Option<String> {
+    SYSCTLS
+        .iter()
+        .find(|sysctl| sysctl.name == name)
+        .map(|sysctl| (sysctl.read)())
+}
+
+pub fn write(name: &str, value: &str) -> Result<(), ()> {
+    let sysctl = SYSCTLS.iter().find(|sysctl| sysctl.name == name).ok_or(())?;
+    (sysctl.write)(value)
+}
@@ -0,0 +1,195 @@
+/*
+    Cheap event tracing: trace!(sched_switch, old_tid, new_tid) stores a timestamped record
+    in the ring of the cpu it runs on. Nothing is locked or printed on the way, a slot is
+    claimed with an atomic increment, so trace points can be put in interrupt handlers and
+    hot paths. The rings are merged by timestamp when they are dumped.
+
+    Tracing is off until trace::init, and can be switched on and off from the shell
+*/
+
+use crate::arch::{cpu, topology};
+use crate::cmdline;
+use crate::drivers::timer_source;
+use alloc::{string::String, vec::Vec};
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// per cpu, the oldest records get overwritten
+const RING_SIZE: usize = 4096;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+// filled once by init, the rings themselves are only touched through atomics and raw writes
+static mut RINGS: Vec<Ring> = Vec::new();
+
+macro_rules! events {
+    ($($name:ident),* $(,)?) => {
+        #[allow(non_camel_case_types)]
+        #[derive(Clone, Copy)]
+        pub enum Event {
+            $($name),*
+        }
+
+        impl Event {
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Event::$name => stringify!($name)),*
+                }
+            }
+        }
+    };
+}
+
+// every trace point, the names are what trace! takes
+events! {
+    sched_switch,  // old tid, new tid
+    sched_wake,    // tid
+    timer_tick,    // ticks
+    page_fault,    // address, error code
+    syscall_enter, // number, first argument
+    syscall_exit,  // number, return value
+    ahci_command,  // lba, sectors | write << 16
+}
+
+#[derive(Clone, Copy)]
+pub struct Record {
+    pub timestamp: u64, // ns, from the timer source
+    pub cpu: usize,
+    pub event: Event,
+    pub args: [u64; 2],
+}
+
+struct Ring {
+    // every record ever claimed, the slot is next % RING_SIZE
+    next: AtomicUsize,
+    records: Vec<Record>,
+}
+
+impl Ring {
+    fn new(cpu: usize) -> Self {
+        let empty = Record {
+            timestamp: 0,
+            cpu,
+            event: Event::timer_tick,
+            args: [0; 2],
+        };
+
+        Ring {
+            next: AtomicUsize::new(0),
+            records: alloc::vec![empty; RING_SIZE],
+        }
+    }
+
+    // an interrupt on this cpu can record in the middle of it, it just claims the next slot
+    fn push(&self, record: Record) {
+        let slot = self.next.fetch_add(1, Ordering::Relaxed) % RING_SIZE;
+
+        unsafe {
+            (self.records.as_ptr() as *mut Record).add(slot).write_volatile(record);
+        }
+    }
+
+    fn records(&self) -> &[Record] {
+        let claimed = self.next.load(Ordering::Relaxed);
+        &self.records[..claimed.min(RING_SIZE)]
+    }
+}
+
+macro_rules! trace {
+    ($event:ident) => {
+        crate::trace::record(crate::trace::Event::$event, 0, 0)
+    };
+    ($event:ident, $arg0:expr) => {
+        crate::trace::record(crate::trace::Event::$event, $arg0 as u64, 0)
+    };
+    ($event:ident, $arg0:expr, $arg1:expr) => {
+        crate::trace::record(crate::trace::Event::$event, $arg0 as u64, $arg1 as u64)
+    };
+}
+
+pub(crate) use trace;
+
+pub fn record(event: Event, arg0: u64, arg1: u64) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let cpu = cpu::local().cpu_id;
+    let ring = match unsafe { RINGS.get(cpu) } {
+        Some(ring) => ring,
+        None => return,
+    };
+
+    ring.push(Record {
+        timestamp: timer_source::current_ns(),
+        cpu,
+        event,
+        args: [arg0, arg1],
+    });
+}
+
+pub fn enable(enabled: bool) {
+    // there's nowhere to record to before init
+    if unsafe { RINGS.is_empty() } {
+        return;
+    }
+
+    ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// the records of every cpu, oldest first
+pub fn records() -> Vec<Record> {
+    let mut records: Vec<Record> = unsafe { RINGS.iter() }
+        .flat_map(|ring| ring.records().iter().copied())
+        .collect();
+
+    records.sort_by_key(|record| record.timestamp);
+    records
+}
+
+pub fn clear() {
+    for ring in unsafe { RINGS.iter() } {
+        ring.next.store(0, Ordering::SeqCst);
+    }
+}
+
+// the merged timeline, times are relative to the first record
+pub fn dump() -> String {
+    let records = records();
+    let start = records.first().map(|record| record.timestamp).unwrap_or(0);
+    let mut output = String::new();
+
+    for record in records {
+        let elapsed = record.timestamp - start;
+
+        writeln!(
+            output,
+            "{:>10}.{:03}us cpu{} {:<14} {:#x} {:#x}",
+            elapsed / 1000,
+            elapsed % 1000,
+            record.cpu,
+            record.event.name(),
+            record.args[0],
+            record.args[1]
+        )
+        .ok();
+    }
+
+    output
+}
+
+// needs the heap, the cpu locals and the clock. trace on the command line starts tracing
+pub fn init() {
+    unsafe {
+        for cpu in 0..topology::possible_cpus().max(1) {
+            RINGS.push(Ring::new(cpu));
+        }
+    }
+
+    if cmdline::option("trace").is_some() {
+        enable(true);
+    }
+}
@@ -0,0 +1,18 @@
+// standard base64 (rfc 4648) with padding, for binary data that has to go over text
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// calls out with every character, nothing is allocated
+pub fn encode(data: &[u8], mut out: impl FnMut(u8)) {
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let group = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out(ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize]);
+            } else {
+                out(b'=');
+            }
+        }
+    }
+}
@@ -1,2 +1,4 @@
+pub mod base64;
 pub mod bitmap;
+pub mod crc32;
 pub mod math;
@@ -0,0 +1,158 @@
+/*
+    A page of clock data the kernel keeps up to date and maps read-only at the same address
+    in every user address space, so userspace can implement clock_gettime without a
+    syscall. The timer tick stores the tsc and the clock's time together, readers take the
+    tsc themselves and extrapolate from there with the tsc frequency.
+
+    Updates are guarded by a sequence counter, odd while one is in progress. A reader
+    retries until it saw the same even value before and after reading the fields
+*/
+
+use crate::arch::cpuid::{self, Features};
+use crate::arch::{cpu, mm::pmm};
+use crate::drivers::{timer, timer_source};
+use crate::log;
+use crate::mm::aslr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use stivale_boot::v2::StivaleEpochTag;
+
+// the last user page, above where any stack can start
+pub const DATA_ADDRESS: u64 = aslr::USER_TOP;
+const CALIBRATION_MS: u64 = 10;
+const NS_PER_SEC: u128 = 1_000_000_000;
+
+// unix time when the bootloader handed over, in seconds
+static BOOT_EPOCH: AtomicU64 = AtomicU64::new(0);
+static mut DATA_PAGE: Option<pmm::PhysAddr> = None;
+
+#[repr(C)]
+pub struct VdsoData {
+    pub sequence: AtomicU64,
+    pub tsc_frequency: u64, // hz
+    pub tsc_base: AtomicU64, // the tsc at the last update
+    pub monotonic_base: AtomicU64, // ns since boot at tsc_base
+    pub boot_time: u64, // unix time when the monotonic clock was 0, in ns
+}
+
+impl VdsoData {
+    pub const fn new(tsc_frequency: u64, boot_time: u64) -> Self {
+        VdsoData {
+            sequence: AtomicU64::new(0),
+            tsc_frequency,
+            tsc_base: AtomicU64::new(0),
+            monotonic_base: AtomicU64::new(0),
+            boot_time,
+        }
+    }
+
+    // returns false if someone else is in the middle of an update, theirs is as good
+    pub fn update(&self, tsc: u64, monotonic: u64) -> bool {
+        let sequence = self.sequence.load(Ordering::Relaxed);
+        if sequence % 2 != 0
+            || self
+                .sequence
+                .compare_exchange(sequence, sequence + 1, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+        {
+            return false;
+        }
+
+        self.tsc_base.store(tsc, Ordering::Relaxed);
+        self.monotonic_base.store(monotonic, Ordering::Relaxed);
+
+        self.sequence.store(sequence + 2, Ordering::Release);
+        true
+    }
+
+    // what a reader in userspace does, ns since boot for the given tsc
+    pub fn monotonic_ns(&self, tsc: u64) -> u64 {
+        loop {
+            let sequence = self.sequence.load(Ordering::Acquire);
+            let tsc_base = self.tsc_base.load(Ordering::Relaxed);
+            let monotonic_base = self.monotonic_base.load(Ordering::Relaxed);
+
+            if sequence % 2 == 0 && self.sequence.load(Ordering::Acquire) == sequence {
+                let elapsed = tsc.saturating_sub(tsc_base) as u128 * NS_PER_SEC
+                    / self.tsc_frequency.max(1) as u128;
+                return monotonic_base + elapsed as u64;
+            }
+
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn realtime_ns(&self, tsc: u64) -> u64 {
+        self.boot_time + self.monotonic_ns(tsc)
+    }
+}
+
+// has to be called before the bootloader's memory is reclaimed
+pub fn set_epoch(tag: Option<&StivaleEpochTag>) {
+    if let Some(tag) = tag {
+        BOOT_EPOCH.store(tag.epoch, Ordering::SeqCst);
+    }
+}
+
+// unix time in seconds, for the timestamps the kernel writes itself
+pub fn unix_time() -> u64 {
+    BOOT_EPOCH.load(Ordering::SeqCst) + timer_source::current_ns() / NS_PER_SEC as u64
+}
+
+// the frame to map at DATA_ADDRESS in new address spaces, once init ran
+pub fn data_page() -> Option<pmm::PhysAddr> {
+    unsafe { DATA_PAGE }
+}
+
+fn data() -> Option<&'static VdsoData> {
+    data_page().map(|page| unsafe { &*page.higher_half().as_mut_ptr::<VdsoData>() })
+}
+
+// counts tsc cycles while the timer source says CALIBRATION_MS went by
+fn calibrate_tsc() -> u64 {
+    let start = cpu::rdtsc();
+    timer_source::sleep(CALIBRATION_MS);
+    let cycles = cpu::rdtsc() - start;
+
+    cycles * 1000 / CALIBRATION_MS
+}
+
+fn update() {
+    if let Some(data) = data() {
+        data.update(cpu::rdtsc(), timer_source::current_ns());
+    }
+}
+
+// needs the timer source, the pmm and the timer
+pub fn init() {
+    if !cpuid::has(Features::INVARIANT_TSC) {
+        log::warning!("[VDSO] The tsc isn't invariant, the clock can drift between ticks\n");
+    }
+
+    let page = match pmm::get().calloc(1) {
+        Ok(page) => page,
+        Err(err) => {
+            log::error!("[VDSO] Could not allocate the data page: {}\n", err);
+            return;
+        }
+    };
+
+    let tsc_frequency = calibrate_tsc();
+    // the timer source was started right after the bootloader read the clock
+    let boot_time = BOOT_EPOCH.load(Ordering::SeqCst) * NS_PER_SEC as u64;
+
+    unsafe {
+        page.higher_half()
+            .as_mut_ptr::<VdsoData>()
+            .write(VdsoData::new(tsc_frequency, boot_time));
+        DATA_PAGE = Some(page);
+    }
+
+    update();
+    timer::every(timer::TICK_MS, update);
+
+    log::info!(
+        "[VDSO] Clock data at {:#x}, tsc running at {} MHz\n",
+        DATA_ADDRESS,
+        tsc_frequency / 1_000_000
+    );
+}
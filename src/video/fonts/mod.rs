@@ -1,3 +1,6 @@
+use crate::log;
+use alloc::collections::BTreeMap;
+
 #[repr(C, packed)]
 struct PsfHeader {
     magic: u32,
@@ -11,28 +14,107 @@ struct PsfHeader {
 }
 
 const PSF_MAGIC: u32 = 0x864ab572;
+const PSF_HAS_UNICODE_TABLE: u32 = 0x1;
+// in the unicode table, every glyph's entry ends with this byte
+const PSF_SEPARATOR: u8 = 0xff;
+// and this one starts its multi-codepoint sequences, which we don't render
+const PSF_START_SEQUENCE: u8 = 0xfe;
+
+pub const DEFAULT_FONT: &str = "terminus";
+
+// the fonts that can be picked with font= on the command line, bigger sizes are scaled up
+static FONTS: &[(&str, &[u8], usize)] = &[
+    ("terminus", include_bytes!("terminus.psf"), 1),
+    ("terminus-2x", include_bytes!("terminus.psf"), 2),
+];
 
 pub struct Font {
-    pub bitmap: &'static [u8],
-    pub height: u32,
+    glyphs: &'static [u8],
+    glyph_count: u32,
+    glyph_size: usize,
+    bytes_per_row: usize,
+    scale: usize,
+    unicode: BTreeMap<char, u32>,
+    replacement: u32, // drawn for the characters that have no glyph
+    pub height: u32, // in pixels, after scaling
     pub width: u32,
 }
 
 impl Font {
-    pub fn new() -> Self {
-        let bytes = include_bytes!("terminus.psf");
-        let header;
+    pub fn new(name: &str) -> Option<Self> {
+        let (_, bytes, scale) = FONTS.iter().find(|(font_name, _, _)| *font_name == name)?;
+        let header = unsafe { (bytes.as_ptr() as *const PsfHeader).read_unaligned() };
+
+        assert!(header.magic == PSF_MAGIC);
 
-        unsafe {
-            header = &*(bytes as *const u8 as *const PsfHeader);
+        let glyphs_start = header.hdr_size as usize;
+        let glyphs_end = glyphs_start + (header.glyph_count * header.glyph_size) as usize;
+
+        let mut font = Font {
+            glyphs: &bytes[glyphs_start..glyphs_end],
+            glyph_count: header.glyph_count,
+            glyph_size: header.glyph_size as usize,
+            bytes_per_row: (header.width as usize + 7) / 8,
+            scale: *scale,
+            unicode: BTreeMap::new(),
+            replacement: 0,
+            height: header.height * *scale as u32,
+            width: header.width * *scale as u32,
+        };
+
+        if header.flags & PSF_HAS_UNICODE_TABLE != 0 {
+            font.parse_unicode_table(&bytes[glyphs_end..]);
         }
 
-        assert!(header.magic == PSF_MAGIC);
+        font.replacement = ['\u{fffd}', '?']
+            .iter()
+            .find_map(|c| font.lookup(*c))
+            .unwrap_or(0);
+
+        Some(font)
+    }
 
-        Font {
-            bitmap: &bytes[header.hdr_size as usize..],
-            height: header.height,
-            width: header.width,
+    /*
+        Each glyph has an entry with the utf-8 encoded codepoints it's used for, ended by
+        PSF_SEPARATOR. Only the single codepoints before PSF_START_SEQUENCE are kept
+    */
+    fn parse_unicode_table(&mut self, table: &[u8]) {
+        for (glyph, entry) in table.split(|byte| *byte == PSF_SEPARATOR).enumerate() {
+            if glyph as u32 >= self.glyph_count {
+                break;
+            }
+
+            let singles = entry.split(|byte| *byte == PSF_START_SEQUENCE).next().unwrap_or(&[]);
+
+            match core::str::from_utf8(singles) {
+                Ok(codepoints) => {
+                    for codepoint in codepoints.chars() {
+                        self.unicode.entry(codepoint).or_insert(glyph as u32);
+                    }
+                }
+                Err(_) => log::warning!("[FONT] Bad unicode table entry for glyph {}\n", glyph),
+            }
+        }
+    }
+
+    fn lookup(&self, character: char) -> Option<u32> {
+        if self.unicode.is_empty() {
+            // no table, so the glyphs are in codepoint order
+            return Some(character as u32).filter(|glyph| *glyph < self.glyph_count);
         }
+
+        self.unicode.get(&character).copied()
+    }
+
+    pub fn glyph(&self, character: char) -> u32 {
+        self.lookup(character).unwrap_or(self.replacement)
+    }
+
+    // x and y are in scaled pixels
+    pub fn pixel(&self, glyph: u32, x: usize, y: usize) -> bool {
+        let (x, y) = (x / self.scale, y / self.scale);
+        let row = glyph as usize * self.glyph_size + y * self.bytes_per_row;
+
+        (self.glyphs[row + x / 8] >> (7 - x % 8)) & 1 == 1
     }
 }
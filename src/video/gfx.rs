@@ -0,0 +1,304 @@
+/*
+    Drawing on the framebuffer, for what isn't text. Everything is clipped to the screen,
+    so a shape can be drawn partly outside of it. Colors are 0xrrggbb, and the framebuffer
+    is assumed to have 32 bits per pixel like the console does.
+
+    What was drawn on since the last take_dirty is tracked as a single rectangle, for
+    displays that have to be told which part of the screen to update
+*/
+
+use alloc::vec::Vec;
+use stivale_boot::v2::StivaleFramebufferTag;
+
+pub struct Framebuffer {
+    addr: *mut u32,
+    width: usize,
+    height: usize,
+    stride: usize, // pixels from one line to the next
+    dirty: Option<Rect>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    // the smallest rectangle with both in it
+    pub fn union(self, other: Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+
+        Rect {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        }
+    }
+}
+
+// a picture with no transparency, one color per pixel, line by line
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pixels: Vec<u32>,
+}
+
+impl Image {
+    /*
+        From a binary ppm (P6) with 8 bit channels, which is easy to embed and to produce
+        with any image editor. Comments in the header aren't supported
+    */
+    pub fn from_ppm(data: &[u8]) -> Option<Image> {
+        let mut fields = [0; 3];
+        let mut offset = 2;
+
+        if !data.starts_with(b"P6") {
+            return None;
+        }
+
+        for field in fields.iter_mut() {
+            while data.get(offset)?.is_ascii_whitespace() {
+                offset += 1;
+            }
+
+            let start = offset;
+            while data.get(offset)?.is_ascii_digit() {
+                offset += 1;
+            }
+            *field = core::str::from_utf8(&data[start..offset]).ok()?.parse().ok()?;
+        }
+
+        // a single whitespace character separates the header from the pixels
+        let [width, height, max_value] = fields;
+        let pixels = data.get(offset + 1..offset + 1 + width * height * 3)?;
+        if max_value != 255 {
+            return None;
+        }
+
+        Some(Image {
+            width,
+            height,
+            pixels: pixels
+                .chunks_exact(3)
+                .map(|rgb| (rgb[0] as u32) << 16 | (rgb[1] as u32) << 8 | rgb[2] as u32)
+                .collect(),
+        })
+    }
+}
+
+/*
+    A small picture with see-through pixels, drawn as ascii art: '#' is the outline, '.'
+    the fill and anything else is left alone
+*/
+pub struct Sprite {
+    rows: &'static [&'static str],
+    outline: u32,
+    fill: u32,
+}
+
+impl Sprite {
+    pub const fn new(rows: &'static [&'static str], outline: u32, fill: u32) -> Self {
+        Sprite {
+            rows,
+            outline,
+            fill,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.rows.iter().map(|row| row.len()).max().unwrap_or(0)
+    }
+
+    pub fn height(&self) -> usize {
+        self.rows.len()
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> Option<u32> {
+        match self.rows[y].as_bytes().get(x) {
+            Some(b'#') => Some(self.outline),
+            Some(b'.') => Some(self.fill),
+            _ => None,
+        }
+    }
+}
+
+impl Framebuffer {
+    pub fn new(fb_tag: &StivaleFramebufferTag) -> Self {
+        Framebuffer {
+            addr: fb_tag.framebuffer_addr as *mut u32,
+            width: fb_tag.framebuffer_width as usize,
+            height: fb_tag.framebuffer_height as usize,
+            stride: fb_tag.framebuffer_pitch as usize / 4,
+            dirty: None,
+        }
+    }
+
+    // addr has to be mapped, for at least stride * height pixels
+    pub fn from_raw(addr: *mut u32, width: usize, height: usize, stride: usize) -> Self {
+        Framebuffer {
+            addr,
+            width,
+            height,
+            stride,
+            dirty: None,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // clipped to the screen
+    fn mark_dirty(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        let width = width.min(self.width.saturating_sub(x));
+        let height = height.min(self.height.saturating_sub(y));
+        if width == 0 || height == 0 {
+            return;
+        }
+
+        let rect = Rect {
+            x,
+            y,
+            width,
+            height,
+        };
+        self.dirty = Some(self.dirty.map_or(rect, |dirty| dirty.union(rect)));
+    }
+
+    pub fn take_dirty(&mut self) -> Option<Rect> {
+        self.dirty.take()
+    }
+
+    pub fn put_pixel(&mut self, x: usize, y: usize, color: u32) {
+        if x < self.width && y < self.height {
+            self.mark_dirty(x, y, 1, 1);
+            unsafe {
+                *self.addr.add(x + y * self.stride) = color;
+            }
+        }
+    }
+
+    // inverting a pixel twice gives it back, like for a cursor
+    pub fn invert_pixel(&mut self, x: usize, y: usize) {
+        if x < self.width && y < self.height {
+            self.mark_dirty(x, y, 1, 1);
+            unsafe {
+                *self.addr.add(x + y * self.stride) ^= 0xffffff;
+            }
+        }
+    }
+
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: u32) {
+        let right = x.saturating_add(width).min(self.width);
+        let bottom = y.saturating_add(height).min(self.height);
+        self.mark_dirty(x, y, width, height);
+
+        for line in y..bottom {
+            for column in x..right {
+                unsafe {
+                    *self.addr.add(column + line * self.stride) = color;
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self, color: u32) {
+        self.fill_rect(0, 0, self.width, self.height, color);
+    }
+
+    // bresenham's, both ends are drawn
+    pub fn line(&mut self, from: (isize, isize), to: (isize, isize), color: u32) {
+        let (mut x, mut y) = from;
+        let dx = (to.0 - x).abs();
+        let dy = -(to.1 - y).abs();
+        let step_x = if x < to.0 { 1 } else { -1 };
+        let step_y = if y < to.1 { 1 } else { -1 };
+        let mut error = dx + dy;
+
+        loop {
+            if x >= 0 && y >= 0 {
+                self.put_pixel(x as usize, y as usize, color);
+            }
+
+            if (x, y) == to {
+                break;
+            }
+
+            let doubled = 2 * error;
+            if doubled >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+    }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> Option<u32> {
+        if x < self.width && y < self.height {
+            Some(unsafe { *self.addr.add(x + y * self.stride) })
+        } else {
+            None
+        }
+    }
+
+    /*
+        Draws sprite with its top left corner at x, y. What was under the whole rectangle
+        is saved in under first, line by line, for restore to put back. under has to hold
+        width * height pixels
+    */
+    pub fn draw_sprite(&mut self, x: usize, y: usize, sprite: &Sprite, under: &mut [u32]) {
+        let width = sprite.width();
+
+        for line in 0..sprite.height() {
+            for column in 0..width {
+                let (px, py) = (x + column, y + line);
+                under[column + line * width] = self.get_pixel(px, py).unwrap_or(0);
+
+                if let Some(color) = sprite.pixel(column, line) {
+                    self.put_pixel(px, py, color);
+                }
+            }
+        }
+    }
+
+    pub fn restore(&mut self, x: usize, y: usize, sprite: &Sprite, under: &[u32]) {
+        let width = sprite.width();
+
+        for line in 0..sprite.height() {
+            for column in 0..width {
+                if sprite.pixel(column, line).is_some() {
+                    self.put_pixel(x + column, y + line, under[column + line * width]);
+                }
+            }
+        }
+    }
+
+    pub fn blit(&mut self, x: usize, y: usize, image: &Image) {
+        let width = image.width.min(self.width.saturating_sub(x));
+        let height = image.height.min(self.height.saturating_sub(y));
+        self.mark_dirty(x, y, width, height);
+
+        for line in 0..height {
+            let source = &image.pixels[line * image.width..line * image.width + width];
+
+            unsafe {
+                let destination = self.addr.add(x + (y + line) * self.stride);
+                core::ptr::copy_nonoverlapping(source.as_ptr(), destination, width);
+            }
+        }
+    }
+}
@@ -1,67 +1,432 @@
+use crate::drivers::timer;
+use crate::proc::session;
+use crate::{cmdline, log};
+use core::sync::atomic::{AtomicU64, Ordering};
 use stivale_boot::v2::StivaleFramebufferTag;
 
 mod fonts;
+pub mod gfx;
+mod splash;
 
+// space around the text area, and between two cells
+const MARGIN: usize = 10;
+const CELL_SPACING: usize = 2;
+const CURSOR_HEIGHT: usize = 2;
+const CURSOR_BLINK_MS: u64 = 500;
+
+const FOREGROUND: u32 = 0xffffff;
+const BACKGROUND: u32 = 0x000000;
+
+const POINTER_WIDTH: usize = 11;
+const POINTER_HEIGHT: usize = 16;
+const NO_POINTER: u64 = u64::MAX;
+
+static CONSOLE: spin::Mutex<Option<Video>> = spin::Mutex::new(None);
+// packed as x << 32 | y, they're kept outside of CONSOLE for isrs that can't wait for it
+static POINTER: AtomicU64 = AtomicU64::new(NO_POINTER);
+static SCREEN_SIZE: AtomicU64 = AtomicU64::new(0);
+
+static POINTER_SPRITE: gfx::Sprite = gfx::Sprite::new(
+    &[
+        "#",
+        "##",
+        "#.#",
+        "#..#",
+        "#...#",
+        "#....#",
+        "#.....#",
+        "#......#",
+        "#.......#",
+        "#........#",
+        "#.....#####",
+        "#..#..#",
+        "#.# #..#",
+        "##  #..#",
+        "#    #..#",
+        "      ##",
+    ],
+    0x000000,
+    0xffffff,
+);
+// for displays that only show what they're told changed, it's set once by their driver
+static mut FLUSH: Option<fn(gfx::Rect)> = None;
+
+/*
+    The console is a grid of rows and columns of character cells, which is what
+    escape sequences like \x1b[H address
+*/
 pub struct Video {
-    cursor_x: usize,
-    cursor_y: usize,
-    fb_addr: *mut u32,
-    height: u16,
-    width: u16,
-    pitch: u16,
+    row: usize,
+    col: usize,
+    cursor_enabled: bool,
+    cursor_shown: bool, // whether the cursor is drawn right now
+    fb: gfx::Framebuffer,
     font: fonts::Font,
+    splash: Option<splash::Splash>, // the console stays off the screen while there's one
+    pointer: Option<(usize, usize)>, // where the mouse pointer is drawn right now
+    under_pointer: [u32; POINTER_WIDTH * POINTER_HEIGHT],
 }
 
+// the framebuffer is only ever accessed through CONSOLE's lock
+unsafe impl Send for Video {}
+
 impl Video {
-    pub fn new(fb_tag: &StivaleFramebufferTag) -> Self {
+    pub fn new(fb_tag: &StivaleFramebufferTag, font: fonts::Font) -> Self {
         Video {
-            cursor_x: 10,
-            cursor_y: 10,
-            fb_addr: fb_tag.framebuffer_addr as *mut u32,
-            height: fb_tag.framebuffer_height,
-            width: fb_tag.framebuffer_width,
-            pitch: fb_tag.framebuffer_pitch,
-            font: fonts::Font::new(),
+            row: 0,
+            col: 0,
+            cursor_enabled: true,
+            cursor_shown: false,
+            fb: gfx::Framebuffer::new(fb_tag),
+            font,
+            splash: None,
+            pointer: None,
+            under_pointer: [0; POINTER_WIDTH * POINTER_HEIGHT],
         }
     }
 
-    pub fn putc(&mut self, character: char, color: u32) {
-        match character {
-            '\n' => {
-                self.cursor_y += self.font.height as usize + 2;
-                self.cursor_x = 10;
-                return;
+    fn cell_width(&self) -> usize {
+        self.font.width as usize + CELL_SPACING
+    }
+
+    fn cell_height(&self) -> usize {
+        self.font.height as usize + CELL_SPACING
+    }
+
+    pub fn rows(&self) -> usize {
+        (self.fb.height() - 2 * MARGIN) / self.cell_height()
+    }
+
+    pub fn cols(&self) -> usize {
+        (self.fb.width() - 2 * MARGIN) / self.cell_width()
+    }
+
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.row, self.col)
+    }
+
+    // positions out of the screen are clamped to its last row or column
+    pub fn set_cursor(&mut self, row: usize, col: usize) {
+        self.hide_cursor();
+        self.row = row.min(self.rows() - 1);
+        self.col = col.min(self.cols() - 1);
+        self.show_cursor();
+    }
+
+    pub fn enable_cursor(&mut self, enabled: bool) {
+        if !enabled {
+            self.hide_cursor();
+        }
+
+        self.cursor_enabled = enabled;
+        self.show_cursor();
+    }
+
+    fn cell_origin(&self, row: usize, col: usize) -> (usize, usize) {
+        (
+            MARGIN + col * self.cell_width(),
+            MARGIN + row * self.cell_height(),
+        )
+    }
+
+    // draws character over the whole cell, so whatever was there before is gone
+    pub fn draw_cell(&mut self, row: usize, col: usize, character: char, fg: u32, bg: u32) {
+        let (x, y) = self.cell_origin(row, col);
+        let glyph = self.font.glyph(character);
+
+        for line in 0..self.cell_height() {
+            for column in 0..self.cell_width() {
+                let set = line < self.font.height as usize
+                    && column < self.font.width as usize
+                    && self.font.pixel(glyph, column, line);
+
+                self.fb.put_pixel(x + column, y + line, if set { fg } else { bg });
             }
+        }
+    }
+
+    pub fn clear_cell(&mut self, row: usize, col: usize) {
+        self.draw_cell(row, col, ' ', FOREGROUND, BACKGROUND);
+    }
 
-            _ => {}
+    pub fn clear(&mut self) {
+        for row in 0..self.rows() {
+            for col in 0..self.cols() {
+                self.clear_cell(row, col);
+            }
         }
 
-        let index = character as u32 * self.font.height;
-        for col in 0..self.font.height {
-            for row in 0..self.font.width {
-                if (self.font.bitmap[(index + col) as usize] >> (7 - row)) & 1 == 1 {
-                    let offset = self.cursor_x
-                        + row as usize
-                        + (self.cursor_y + col as usize) * self.pitch as usize / 4;
+        self.cursor_shown = false;
+        self.set_cursor(0, 0);
+    }
 
-                    unsafe {
-                        (*self.fb_addr.offset(offset as isize)) = color;
-                    }
-                }
+    // the cursor is an underline, inverting it twice gives the cell back
+    fn invert_cursor(&mut self) {
+        let (x, y) = self.cell_origin(self.row, self.col);
+        let y = y + self.cell_height() - CURSOR_HEIGHT;
+
+        for line in 0..CURSOR_HEIGHT {
+            for column in 0..self.cell_width() {
+                self.fb.invert_pixel(x + column, y + line);
             }
         }
 
-        let char_width = self.font.width as usize + 2;
-        self.cursor_x += char_width;
-        if self.cursor_x + char_width >= self.width as usize {
-            self.cursor_x = 10;
-            self.cursor_y += self.font.height as usize + 2;
+        self.cursor_shown = !self.cursor_shown;
+    }
+
+    fn hide_cursor(&mut self) {
+        if self.cursor_shown {
+            self.invert_cursor();
+        }
+    }
+
+    fn show_cursor(&mut self) {
+        if self.cursor_enabled && !self.cursor_shown {
+            self.invert_cursor();
+        }
+    }
+
+    pub fn blink_cursor(&mut self) {
+        if self.cursor_enabled && self.splash.is_none() {
+            self.invert_cursor();
+        }
+    }
+
+    fn newline(&mut self) {
+        self.col = 0;
+        self.row += 1;
+
+        // there's no scrolling yet, so just start over from the top
+        if self.row >= self.rows() {
+            self.row = 0;
+        }
+    }
+
+    pub fn putc(&mut self, character: char, color: u32) {
+        self.hide_cursor();
+
+        match character {
+            '\n' => self.newline(),
+            '\r' => self.col = 0,
+            '\x08' => self.col = self.col.saturating_sub(1),
+            _ => {
+                self.draw_cell(self.row, self.col, character, color, BACKGROUND);
+
+                self.col += 1;
+                if self.col >= self.cols() {
+                    self.newline();
+                }
+            }
         }
+
+        self.show_cursor();
     }
 
     pub fn print(&mut self, msg: &str) {
+        if self.splash.is_some() {
+            return;
+        }
+
         for c in msg.chars() {
-            self.putc(c, 0xffffff);
+            self.putc(c, FOREGROUND);
+        }
+    }
+
+    // puts back what the pointer was drawn over, anything else drawing has to do this first
+    fn hide_pointer(&mut self) {
+        if let Some((x, y)) = self.pointer.take() {
+            self.fb.restore(x, y, &POINTER_SPRITE, &self.under_pointer);
         }
     }
+
+    fn show_pointer(&mut self, position: Option<(usize, usize)>) {
+        if let (Some((x, y)), None) = (position, &self.splash) {
+            self.fb.draw_sprite(x, y, &POINTER_SPRITE, &mut self.under_pointer);
+            self.pointer = Some((x, y));
+        }
+    }
+
+    // for a new mode, the console starts over on an empty screen
+    pub fn set_framebuffer(&mut self, fb: gfx::Framebuffer) {
+        self.hide_pointer();
+        self.fb = fb;
+        self.splash = None;
+        self.fb.clear(BACKGROUND);
+        self.cursor_shown = false;
+        self.set_cursor(0, 0);
+    }
+
+    pub fn show_splash(&mut self, steps: usize) {
+        self.splash = Some(splash::Splash::new(&mut self.fb, steps));
+    }
+
+    pub fn splash_step(&mut self) {
+        if let Some(splash) = self.splash.as_mut() {
+            splash.step(&mut self.fb);
+        }
+    }
+
+    // gives the screen back to the console, which starts over from the top
+    pub fn hide_splash(&mut self) {
+        if self.splash.take().is_some() {
+            self.fb.clear(BACKGROUND);
+            self.cursor_shown = false;
+            self.set_cursor(0, 0);
+        }
+    }
+}
+
+// needs the heap for the font's unicode table
+pub fn init(fb_tag: &StivaleFramebufferTag) {
+    let name = cmdline::option("font").unwrap_or(fonts::DEFAULT_FONT);
+    let font = fonts::Font::new(name).unwrap_or_else(|| {
+        log::warning!("[VIDEO] Unknown font {}, using {}\n", name, fonts::DEFAULT_FONT);
+        fonts::Font::new(fonts::DEFAULT_FONT).unwrap()
+    });
+
+    let mut video = Video::new(fb_tag, font);
+    video.clear();
+    // the console is the only terminal, its size is the screen's
+    session::CONSOLE.resize(video.rows() as u16, video.cols() as u16);
+    SCREEN_SIZE.store(pack(video.fb.width(), video.fb.height()), Ordering::SeqCst);
+    *CONSOLE.lock() = Some(video);
+}
+
+fn pack(x: usize, y: usize) -> u64 {
+    (x as u64) << 32 | y as u64 & 0xffffffff
+}
+
+fn unpack(packed: u64) -> (usize, usize) {
+    ((packed >> 32) as usize, (packed & 0xffffffff) as usize)
+}
+
+/*
+    flush is called with what was drawn on after every change to the screen, without the
+    console's lock held, so it can take its own locks and even set a new framebuffer. It
+    can also be called from the cursor's timer interrupt
+*/
+pub fn set_flush(flush: fn(gfx::Rect)) {
+    unsafe {
+        FLUSH = Some(flush);
+    }
+}
+
+fn flush(dirty: Option<gfx::Rect>) {
+    if let (Some(flush), Some(dirty)) = (unsafe { FLUSH }, dirty) {
+        flush(dirty);
+    }
+}
+
+/*
+    Runs f on the console if it's initialized, with the pointer out of the way, and flushes
+    what it drew. The pointer is drawn back where it is now, it may have moved meanwhile
+*/
+fn draw<R>(
+    mut console: spin::MutexGuard<Option<Video>>,
+    f: impl FnOnce(&mut Video) -> R,
+) -> Option<R> {
+    let video = console.as_mut()?;
+
+    video.hide_pointer();
+    let result = f(video);
+    video.show_pointer(pointer());
+
+    let dirty = video.fb.take_dirty();
+    drop(console);
+
+    flush(dirty);
+    Some(result)
+}
+
+fn with_console<R>(f: impl FnOnce(&mut Video) -> R) -> Option<R> {
+    draw(CONSOLE.lock(), f)
+}
+
+// in pixels, (width, height), it can be read from an isr
+pub fn screen_size() -> Option<(usize, usize)> {
+    match SCREEN_SIZE.load(Ordering::SeqCst) {
+        0 => None,
+        size => Some(unpack(size)),
+    }
+}
+
+pub fn pointer() -> Option<(usize, usize)> {
+    match POINTER.load(Ordering::SeqCst) {
+        NO_POINTER => None,
+        position => Some(unpack(position)),
+    }
+}
+
+/*
+    Draws the mouse pointer with its tip at x, y, on top of the console. It's called from
+    the mouse's isr, so if someone is drawing the pointer is left for them to move
+*/
+pub fn move_pointer(x: usize, y: usize) {
+    POINTER.store(pack(x, y), Ordering::SeqCst);
+
+    if let Some(console) = CONSOLE.try_lock() {
+        draw(console, |_| {});
+    }
+}
+
+// what a display driver calls after changing the mode
+pub fn set_framebuffer(fb: gfx::Framebuffer) {
+    let size = with_console(|video| {
+        video.set_framebuffer(fb);
+        SCREEN_SIZE.store(pack(video.fb.width(), video.fb.height()), Ordering::SeqCst);
+        (video.rows() as u16, video.cols() as u16)
+    });
+
+    // the foreground group gets a SIGWINCH, which looks at the processes
+    if let Some((rows, cols)) = size {
+        session::CONSOLE.resize(rows, cols);
+    }
+}
+
+/*
+    Shows the boot splash instead of the console with splash on the command line, the
+    progress bar is full after steps calls to splash_step
+*/
+pub fn show_splash(steps: usize) {
+    if cmdline::option("splash").is_none() {
+        return;
+    }
+
+    with_console(|video| video.show_splash(steps));
+}
+
+pub fn splash_step() {
+    with_console(|video| video.splash_step());
+}
+
+pub fn hide_splash() {
+    with_console(|video| video.hide_splash());
+}
+
+// the timer has to be running for this to do anything
+pub fn start_cursor_blink() {
+    timer::every(CURSOR_BLINK_MS, || {
+        // this runs in an interrupt, so it can't wait for whoever is printing
+        if let Some(console) = CONSOLE.try_lock() {
+            draw(console, |video| video.blink_cursor());
+        }
+    });
+}
+
+// does nothing until the console has been initialized
+pub fn print(msg: &str) {
+    with_console(|video| video.print(msg));
+}
+
+pub fn set_cursor(row: usize, col: usize) {
+    with_console(|video| video.set_cursor(row, col));
+}
+
+pub fn cursor() -> Option<(usize, usize)> {
+    CONSOLE.lock().as_ref().map(|video| video.cursor())
+}
+
+// in cells, as (rows, columns)
+pub fn size() -> Option<(usize, usize)> {
+    CONSOLE.lock().as_ref().map(|video| (video.rows(), video.cols()))
 }
@@ -0,0 +1,62 @@
+/*
+    The boot splash, a logo with a progress bar under it, shown instead of the console
+    with splash on the command line. Boot moves the bar along as each step completes, and
+    the console gets the screen back once it's done. Whatever was printed meanwhile went
+    to the other log sinks, the screen doesn't show it
+*/
+
+use super::gfx::{Framebuffer, Image};
+
+const BACKGROUND: u32 = 0x1d2330; // the logo's
+const BAR_COLOR: u32 = 0xe0a030;
+const BAR_WIDTH: usize = 240;
+const BAR_HEIGHT: usize = 8;
+const BAR_GAP: usize = 24; // between the logo and the bar
+
+static LOGO: &[u8] = include_bytes!("logo.ppm");
+
+pub struct Splash {
+    steps: usize,
+    done: usize,
+    bar: (usize, usize), // the top left corner of the bar's inside
+}
+
+impl Splash {
+    pub fn new(fb: &mut Framebuffer, steps: usize) -> Self {
+        fb.clear(BACKGROUND);
+
+        let logo_height = match Image::from_ppm(LOGO) {
+            Some(logo) => {
+                let x = fb.width().saturating_sub(logo.width) / 2;
+                let y = (fb.height() / 2).saturating_sub(logo.height);
+                fb.blit(x, y, &logo);
+                logo.height
+            }
+            None => 0,
+        };
+
+        let x = fb.width().saturating_sub(BAR_WIDTH) / 2;
+        let y = (fb.height() / 2).saturating_sub(logo_height) + logo_height + BAR_GAP;
+
+        // the outline sits one pixel around the inside
+        let (left, top) = (x as isize - 1, y as isize - 1);
+        let (right, bottom) = ((x + BAR_WIDTH) as isize, (y + BAR_HEIGHT) as isize);
+        fb.line((left, top), (right, top), BAR_COLOR);
+        fb.line((right, top), (right, bottom), BAR_COLOR);
+        fb.line((right, bottom), (left, bottom), BAR_COLOR);
+        fb.line((left, bottom), (left, top), BAR_COLOR);
+
+        Splash {
+            steps: steps.max(1),
+            done: 0,
+            bar: (x, y),
+        }
+    }
+
+    pub fn step(&mut self, fb: &mut Framebuffer) {
+        self.done = (self.done + 1).min(self.steps);
+
+        let (x, y) = self.bar;
+        fb.fill_rect(x, y, BAR_WIDTH * self.done / self.steps, BAR_HEIGHT, BAR_COLOR);
+    }
+}